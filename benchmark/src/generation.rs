@@ -128,12 +128,15 @@ async fn prefill(
                 seed: 0,
                 repetition_penalty: 1.0,
                 watermark: false,
+                allowed_tokens: vec![],
             }),
             stopping_parameters: Some(StoppingCriteriaParameters {
                 max_new_tokens: decode_length,
                 stop_sequences: vec![],
                 ignore_eos_token: true, // Will not stop even if a eos token is generated
             }),
+            prefix_cache: None,
+            prefill_logprobs: true,
         })
         .collect();
 
@@ -145,7 +148,7 @@ async fn prefill(
 
     // Run prefill
     let start_time = Instant::now();
-    let (_, decode_batch) = client.prefill(batch.clone()).await?;
+    let (_, decode_batch, _) = client.prefill(batch.clone(), None).await?;
 
     // Get latency
     let latency = start_time.elapsed();
@@ -174,7 +177,7 @@ async fn decode(batch: Batch, client: &mut ShardedClient) -> Result<Decode, Clie
     // Full decode over decode length
     let mut next_batch = Some(batch);
     while let Some(batch) = next_batch {
-        let result = client.decode(vec![batch]).await?;
+        let result = client.decode(vec![batch], None).await?;
         next_batch = result.1;
         decode_length += 1;
     }
@@ -33,6 +33,19 @@ def test_validation_error():
     assert isinstance(parse_error(400, payload), ValidationError)
 
 
+def test_input_length_error():
+    payload = {
+        "error_type": "validation",
+        "error": "test",
+        "input_length": 1200,
+        "max_input_length": 1000,
+    }
+    error = parse_error(422, payload)
+    assert isinstance(error, ValidationError)
+    assert error.input_length == 1200
+    assert error.max_input_length == 1000
+
+
 def test_bad_request_error():
     payload = {"error": "test"}
     assert isinstance(parse_error(400, payload), BadRequestError)
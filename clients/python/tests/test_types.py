@@ -38,8 +38,7 @@ def test_parameters_validation():
 
     # Test top_k
     Parameters(top_k=1)
-    with pytest.raises(ValidationError):
-        Parameters(top_k=0)
+    Parameters(top_k=0)
     with pytest.raises(ValidationError):
         Parameters(top_k=-1)
 
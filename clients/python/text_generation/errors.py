@@ -1,10 +1,18 @@
-from typing import Dict
+from typing import Dict, Optional
 
 
 # Text Generation Inference Errors
 class ValidationError(Exception):
-    def __init__(self, message: str):
+    def __init__(
+        self,
+        message: str,
+        input_length: Optional[int] = None,
+        max_input_length: Optional[int] = None,
+    ):
         super().__init__(message)
+        # Set when the inputs are too long
+        self.input_length = input_length
+        self.max_input_length = max_input_length
 
 
 class GenerationError(Exception):
@@ -88,7 +96,11 @@ def parse_error(status_code: int, payload: Dict[str, str]) -> Exception:
         if error_type == "overloaded":
             return OverloadedError(message)
         if error_type == "validation":
-            return ValidationError(message)
+            return ValidationError(
+                message,
+                payload.get("input_length"),
+                payload.get("max_input_length"),
+            )
 
     # Try to parse a APIInference error
     if status_code == 400:
@@ -77,8 +77,8 @@ class Parameters(BaseModel):
 
     @validator("top_k")
     def valid_top_k(cls, v):
-        if v is not None and v <= 0:
-            raise ValidationError("`top_k` must be strictly positive")
+        if v is not None and v < 0:
+            raise ValidationError("`top_k` must be positive, or 0 to disable it")
         return v
 
     @validator("top_p")
@@ -161,6 +161,10 @@ class FinishReason(Enum):
     EndOfSequenceToken = "eos_token"
     # the model generated a text included in `stop_sequences`
     StopSequence = "stop_sequence"
+    # the generated text is empty and retrying with greedy decoding would not change it
+    EmptyGeneration = "empty_generation"
+    # the generated text was rejected by the post-generation hook
+    ContentFilter = "content_filter"
 
 
 # Additional sequences when using the `best_of` parameter
@@ -179,6 +183,38 @@ class BestOfSequence(BaseModel):
     tokens: List[Token]
 
 
+# Parameters used by the backend after defaulting and validation
+class ValidParameters(BaseModel):
+    # The value used to module the logits distribution
+    temperature: float
+    # Number of highest probability tokens kept for top-k filtering, 0 if disabled
+    top_k: int
+    # Nucleus sampling probability
+    top_p: float
+    # Typical decoding mass
+    typical_p: float
+    # Repetition penalty
+    repetition_penalty: float
+    # Activate logits sampling
+    do_sample: bool
+    # Maximum number of generated tokens
+    max_new_tokens: int
+    # Stop generating tokens if a member of `stop_sequences` is generated
+    stop: List[str]
+    # Sampling seed
+    seed: int
+    # Watermarking of the generated text, forced by some deployments
+    watermark: bool
+
+
+# Stop sequence that ended the generation
+class MatchedStop(BaseModel):
+    # Stop sequence
+    sequence: str
+    # Position of the stop sequence in `stop` followed by `stop_config`
+    index: int
+
+
 # `generate` details
 class Details(BaseModel):
     # Generation finish reason
@@ -193,6 +229,12 @@ class Details(BaseModel):
     tokens: List[Token]
     # Additional sequences when using the `best_of` parameter
     best_of_sequences: Optional[List[BestOfSequence]]
+    # Number of generations when using the `retry_on_empty` parameter
+    attempts: Optional[int]
+    # Stop sequence that ended the generation
+    matched_stop: Optional[MatchedStop]
+    # Parameters used by the backend
+    parameters: Optional[ValidParameters]
 
 
 # `generate` return value
@@ -201,6 +243,8 @@ class Response(BaseModel):
     generated_text: str
     # Generation details
     details: Details
+    # Id of the continued request, its generated text being prepended to `generated_text`
+    continued_from: Optional[int]
 
 
 # `generate_stream` details
@@ -211,6 +255,8 @@ class StreamDetails(BaseModel):
     generated_tokens: int
     # Sampling seed if sampling was activated
     seed: Optional[int]
+    # Stop sequence that ended the generation
+    matched_stop: Optional[MatchedStop]
 
 
 # `generate_stream` return value
@@ -223,3 +269,7 @@ class StreamResponse(BaseModel):
     # Generation details
     # Only available when the generation is finished
     details: Optional[StreamDetails]
+    # Text of all the tokens streamed so far, if `stream_full_text` is set
+    generated_text_so_far: Optional[str]
+    # The optional fields were dropped to keep the event under the size limit of the server
+    truncated: bool = False
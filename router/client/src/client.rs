@@ -1,8 +1,9 @@
 /// Single shard Client
 use crate::pb::generate::v1::text_generation_service_client::TextGenerationServiceClient;
 use crate::pb::generate::v1::*;
-use crate::Result;
+use crate::{CacheUsage, Result};
 use grpc_metadata::InjectTelemetryContext;
+use std::time::Duration;
 use tonic::transport::{Channel, Uri};
 use tracing::instrument;
 
@@ -54,6 +55,14 @@ impl Client {
         Ok(urls)
     }
 
+    /// Vocabulary of the tokenizer of the shard
+    #[instrument(skip(self))]
+    pub async fn info(&mut self) -> Result<InfoResponse> {
+        let request = tonic::Request::new(InfoRequest {}).inject_context();
+        let response = self.stub.info(request).await?.into_inner();
+        Ok(response)
+    }
+
     /// Clear the past generations cache
     #[instrument(skip(self))]
     pub async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
@@ -62,15 +71,36 @@ impl Client {
         Ok(())
     }
 
+    /// Remove requests from a cached batch, freeing their resources on the shard
+    ///
+    /// Returns the cached batch without these requests
+    #[instrument(skip(self))]
+    pub async fn cancel(&mut self, batch_id: u64, request_ids: Vec<u64>) -> Result<Option<Batch>> {
+        let request = tonic::Request::new(CancelRequest {
+            batch_id,
+            request_ids,
+        })
+        .inject_context();
+        let response = self.stub.cancel(request).await?.into_inner();
+        Ok(response.batch)
+    }
+
     /// Generate one token for each request in the given batch
     ///
     /// Returns Generation for each request in batch
     /// and the next cached batch
     #[instrument(skip_all, fields(id = &batch.id, size = &batch.size))]
-    pub async fn prefill(&mut self, batch: Batch) -> Result<(Vec<Generation>, Option<Batch>)> {
-        let request = tonic::Request::new(PrefillRequest { batch: Some(batch) }).inject_context();
+    pub async fn prefill(
+        &mut self,
+        batch: Batch,
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>)> {
+        let mut request =
+            tonic::Request::new(PrefillRequest { batch: Some(batch) }).inject_context();
+        inject_deadline(&mut request, deadline);
         let response = self.stub.prefill(request).await?.into_inner();
-        Ok((response.generations, response.batch))
+        let cache_usage = CacheUsage::new(response.cache_blocks_used, response.cache_blocks_total);
+        Ok((response.generations, response.batch, cache_usage))
     }
 
     /// Generate one token for each request in the given cached batches
@@ -81,9 +111,67 @@ impl Client {
     pub async fn decode(
         &mut self,
         batches: Vec<Batch>,
-    ) -> Result<(Vec<Generation>, Option<Batch>)> {
-        let request = tonic::Request::new(DecodeRequest { batches }).inject_context();
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>)> {
+        let mut request = tonic::Request::new(DecodeRequest { batches }).inject_context();
+        inject_deadline(&mut request, deadline);
         let response = self.stub.decode(request).await?.into_inner();
-        Ok((response.generations, response.batch))
+        let cache_usage = CacheUsage::new(response.cache_blocks_used, response.cache_blocks_total);
+        Ok((response.generations, response.batch, cache_usage))
+    }
+
+    /// Generate `tokens` draft tokens for each request in the given cached batch, after
+    /// replacing its last generated tokens by the `accepted` ones
+    ///
+    /// Returns the draft tokens of each request and the next cached batch
+    #[instrument(skip_all, fields(id = &batch.id, size = &batch.size, tokens))]
+    pub async fn draft(
+        &mut self,
+        batch: Batch,
+        tokens: u32,
+        accepted: Vec<RequestTokens>,
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<RequestTokens>, Option<Batch>)> {
+        let mut request = tonic::Request::new(DraftRequest {
+            batch: Some(batch),
+            tokens,
+            accepted,
+        })
+        .inject_context();
+        inject_deadline(&mut request, deadline);
+        let response = self.stub.draft(request).await?.into_inner();
+        Ok((response.tokens, response.batch))
+    }
+
+    /// Check the draft tokens of each request in the given cached batch
+    ///
+    /// Returns Generation for each accepted token and for the token following them,
+    /// and the next cached batch
+    #[instrument(skip_all, fields(id = &batch.id, size = &batch.size))]
+    pub async fn verify(
+        &mut self,
+        batch: Batch,
+        tokens: Vec<RequestTokens>,
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>)> {
+        let mut request = tonic::Request::new(VerifyRequest {
+            batch: Some(batch),
+            tokens,
+        })
+        .inject_context();
+        inject_deadline(&mut request, deadline);
+        let response = self.stub.verify(request).await?.into_inner();
+        let cache_usage = CacheUsage::new(response.cache_blocks_used, response.cache_blocks_total);
+        Ok((response.generations, response.batch, cache_usage))
+    }
+}
+
+/// Forward the earliest deadline of the requests in the batch as gRPC metadata
+fn inject_deadline<T>(request: &mut tonic::Request<T>, deadline: Option<Duration>) {
+    if let Some(deadline) = deadline {
+        request.metadata_mut().insert(
+            "x-request-deadline-ms",
+            (deadline.as_millis() as u64).into(),
+        );
     }
 }
@@ -1,19 +1,23 @@
 //! Text Generation gRPC client library
 
 mod client;
+mod mock;
 #[allow(clippy::derive_partial_eq_without_eq)]
 mod pb;
+mod shard_stats;
 mod sharded_client;
 
 pub use client::Client;
+pub use mock::{MockCall, MockConfig};
 pub use pb::generate::v1::{
-    Batch, FinishReason, GeneratedText, Generation, NextTokenChooserParameters, PrefillTokens,
-    Request, StoppingCriteriaParameters,
+    Batch, FinishReason, GeneratedText, Generation, InfoResponse, NextTokenChooserParameters,
+    PrefillTokens, PrefixCache, Request, RequestTokens, StoppingCriteriaParameters,
 };
+pub use shard_stats::{ShardSnapshot, ShardStats, DEFAULT_SLOW_SHARD_FACTOR};
 pub use sharded_client::ShardedClient;
 use thiserror::Error;
 use tonic::transport;
-use tonic::Status;
+use tonic::{Code, Status};
 
 #[derive(Error, Debug, Clone)]
 pub enum ClientError {
@@ -21,11 +25,29 @@ pub enum ClientError {
     Connection(String),
     #[error("Server error: {0}")]
     Generation(String),
+    #[error("Server is overloaded: {0}")]
+    Overloaded(String),
+    #[error("Server ran out of memory: {0}")]
+    OutOfMemory(String),
+    #[error("Server rejected an invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Server is unavailable: {0}")]
+    Unavailable(String),
 }
 
 impl From<Status> for ClientError {
     fn from(err: Status) -> Self {
-        let err = Self::Generation(err.message().to_string());
+        let message = err.message().to_string();
+        // See the error codes documented in `generate.proto`
+        let err = match err.code() {
+            Code::ResourceExhausted => Self::OutOfMemory(message),
+            Code::Aborted => Self::Overloaded(message),
+            Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+                Self::InvalidArgument(message)
+            }
+            Code::Unavailable => Self::Unavailable(message),
+            _ => Self::Generation(message),
+        };
         tracing::error!("{err}");
         err
     }
@@ -40,3 +62,28 @@ impl From<transport::Error> for ClientError {
 }
 
 pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// KV-cache blocks of a server, reported by its prefill and decode responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheUsage {
+    pub blocks_used: u32,
+    pub blocks_total: u32,
+}
+
+impl CacheUsage {
+    /// None unless the server reports both fields and has blocks
+    fn new(blocks_used: Option<u32>, blocks_total: Option<u32>) -> Option<Self> {
+        match (blocks_used, blocks_total) {
+            (Some(blocks_used), Some(blocks_total)) if blocks_total > 0 => Some(Self {
+                blocks_used: blocks_used.min(blocks_total),
+                blocks_total,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Fraction of the blocks in use
+    pub fn utilization(&self) -> f64 {
+        self.blocks_used as f64 / self.blocks_total as f64
+    }
+}
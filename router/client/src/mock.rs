@@ -0,0 +1,477 @@
+/// In-process backend generating deterministic tokens
+use crate::{
+    Batch, CacheUsage, ClientError, FinishReason, GeneratedText, Generation, InfoResponse,
+    PrefillTokens, Request, RequestTokens, Result,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Tokens generated by the mock backend, in order
+const VOCABULARY: [&str; 8] = [
+    " the", " quick", " brown", " fox", " jumps", " over", " lazy", " dog",
+];
+
+/// Mock backend configuration
+#[derive(Debug, Clone, Default)]
+pub struct MockConfig {
+    /// Time needed to generate one token for all the requests of a batch
+    pub token_delay: Duration,
+    /// Time added to `token_delay` for each request of the batch
+    pub request_delay: Duration,
+    /// Time added to the prefill for each byte of the inputs of the batch, so that long prompts
+    /// stall the batch as they do on a real backend
+    pub prefill_byte_delay: Duration,
+    /// Ids of the requests failing the prefill or decode of their batch
+    pub fail_requests: HashSet<u64>,
+    /// Inputs of the requests failing the prefill or decode of their batch
+    pub fail_inputs: HashSet<String>,
+    /// Inputs of the requests panicking the decode of their batch after its token delay, as a
+    /// crash of the batching task
+    pub panic_inputs: HashSet<String>,
+    /// Ids of the batches failing their prefill or decode
+    pub fail_batches: HashSet<u64>,
+    /// Ids of the batches failing their prefill or decode as if the backend was unavailable
+    pub unavailable_batches: HashSet<u64>,
+    /// Report zero generated tokens in the generated texts
+    pub zero_generated_tokens: bool,
+    /// KV-cache blocks reported in the responses, one per token of the cached requests
+    /// The KV-cache usage is not reported if None
+    pub cache_blocks_total: Option<u32>,
+    /// Draft tokens at the positions multiple of this interval differ from the generated tokens,
+    /// 0 if all the draft tokens are right
+    pub draft_miss_interval: u32,
+    /// Vocabulary reported by the info call, the mock vocabulary without special tokens if None
+    pub info: Option<InfoResponse>,
+    /// Receives the prefill and decode calls, in order
+    pub calls: Option<Arc<Mutex<Vec<MockCall>>>>,
+}
+
+/// Call received by the mock backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockCall {
+    /// Ids of the requests of the prefilled batch
+    Prefill(Vec<u64>),
+    /// Ids of the requests of the decoded batches
+    Decode(Vec<u64>),
+}
+
+/// Request cached by the mock backend
+#[derive(Debug, Clone)]
+struct MockRequest {
+    request: Request,
+    /// Generated token ids
+    tokens: Vec<u32>,
+    /// Number of tokens generated by the last call, replaced by the accepted tokens of a draft call
+    last_generated: usize,
+}
+
+impl MockRequest {
+    fn new(request: Request) -> Self {
+        Self {
+            request,
+            tokens: Vec::new(),
+            last_generated: 0,
+        }
+    }
+
+    /// Token at the next position
+    /// Sampled requests start at a position given by their seed, greedy requests at the first token
+    /// Requests with allowed tokens cycle through them instead of the vocabulary
+    fn expected_token(&self) -> u32 {
+        let parameters = self.request.parameters.clone().unwrap_or_default();
+        let offset = match parameters.do_sample {
+            true => parameters.seed,
+            false => 0,
+        };
+        let position = offset + self.tokens.len() as u64;
+        let allowed_tokens = &parameters.allowed_tokens;
+        match allowed_tokens.is_empty() {
+            true => (position % VOCABULARY.len() as u64) as u32,
+            false => allowed_tokens[(position % allowed_tokens.len() as u64) as usize],
+        }
+    }
+
+    /// Draft the next token, wrong at the positions multiple of `miss_interval`
+    fn draft_token(&mut self, miss_interval: u32) -> u32 {
+        let mut token_id = self.expected_token();
+        if miss_interval > 0 && (self.tokens.len() as u32 + 1) % miss_interval == 0 {
+            token_id = (token_id + 1) % VOCABULARY.len() as u32;
+        }
+        self.tokens.push(token_id);
+        token_id
+    }
+
+    fn text(&self) -> String {
+        self.tokens
+            .iter()
+            .map(|id| VOCABULARY[*id as usize])
+            .collect()
+    }
+
+    /// Generate the next token
+    fn next_token(&mut self, prefill: bool) -> Generation {
+        let parameters = self.request.parameters.clone().unwrap_or_default();
+        let stopping_parameters = self.request.stopping_parameters.clone().unwrap_or_default();
+        let token_id = self.expected_token();
+        let token_text = VOCABULARY[token_id as usize];
+        self.tokens.push(token_id);
+        let text = self.text();
+        let generated_tokens = self.tokens.len() as u32;
+
+        let finish_reason = if stopping_parameters
+            .stop_sequences
+            .iter()
+            .any(|stop| text.ends_with(stop.as_str()))
+        {
+            Some(FinishReason::StopSequence)
+        } else if generated_tokens >= stopping_parameters.max_new_tokens {
+            Some(FinishReason::Length)
+        } else {
+            None
+        };
+
+        // One prefill token per word of the inputs
+        let prefill_tokens = (prefill && self.request.prefill_logprobs).then(|| {
+            let texts: Vec<String> = self
+                .request
+                .inputs
+                .split_inclusive(' ')
+                .map(String::from)
+                .collect();
+            let mut logprobs = vec![-1.0; texts.len()];
+            if let Some(first) = logprobs.first_mut() {
+                *first = f32::NAN;
+            }
+            PrefillTokens {
+                ids: (0..texts.len() as u32).collect(),
+                logprobs,
+                texts,
+            }
+        });
+
+        Generation {
+            request_id: self.request.id,
+            prefill_tokens,
+            token_id,
+            token_logprob: -1.0,
+            token_text: token_text.to_string(),
+            token_is_special: false,
+            generated_text: finish_reason.map(|finish_reason| GeneratedText {
+                text,
+                generated_tokens,
+                finish_reason: finish_reason as i32,
+                seed: parameters.do_sample.then_some(parameters.seed),
+            }),
+        }
+    }
+}
+
+/// Backend generating tokens from a fixed vocabulary, for tests and local development
+#[derive(Debug, Clone)]
+pub(crate) struct MockClient {
+    config: MockConfig,
+    /// Cached batches
+    batches: HashMap<u64, Vec<MockRequest>>,
+}
+
+impl MockClient {
+    pub(crate) fn new(config: MockConfig) -> Self {
+        Self {
+            config,
+            batches: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn info(&self) -> InfoResponse {
+        self.config.info.clone().unwrap_or(InfoResponse {
+            vocab_size: VOCABULARY.len() as u32,
+            special_tokens: HashMap::new(),
+            speculation: true,
+            prefix_cache: true,
+            cancel: true,
+        })
+    }
+
+    /// Clear the past generations cache
+    pub(crate) fn clear_cache(&mut self, batch_id: Option<u64>) {
+        match batch_id {
+            None => self.batches.clear(),
+            Some(batch_id) => {
+                self.batches.remove(&batch_id);
+            }
+        }
+    }
+
+    /// Remove requests from a cached batch
+    pub(crate) fn cancel(&mut self, batch_id: u64, request_ids: &[u64]) -> Result<Option<Batch>> {
+        let requests = self.batches.get_mut(&batch_id).ok_or_else(|| {
+            ClientError::InvalidArgument(format!("batch {batch_id} not found in cache"))
+        })?;
+        requests.retain(|request| !request_ids.contains(&request.request.id));
+        if requests.is_empty() {
+            self.batches.remove(&batch_id);
+            return Ok(None);
+        }
+        Ok(Some(Batch {
+            id: batch_id,
+            requests: requests
+                .iter()
+                .map(|request| request.request.clone())
+                .collect(),
+            size: requests.len() as u32,
+        }))
+    }
+
+    fn record(&self, call: MockCall) {
+        if let Some(calls) = &self.config.calls {
+            calls.lock().unwrap().push(call);
+        }
+    }
+
+    /// Time needed to generate one token for all the requests of a batch of `size` requests
+    fn step_delay(&self, size: usize) -> Duration {
+        self.config.token_delay + self.config.request_delay * size as u32
+    }
+
+    /// Generate one token for each request in the given batch
+    pub(crate) async fn prefill(
+        &mut self,
+        batch: Batch,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>)> {
+        self.check(&batch)?;
+        self.record(MockCall::Prefill(
+            batch.requests.iter().map(|request| request.id).collect(),
+        ));
+        let requests: Vec<MockRequest> = batch.requests.into_iter().map(MockRequest::new).collect();
+
+        let input_bytes: usize = requests
+            .iter()
+            .map(|request| request.request.inputs.len())
+            .sum();
+        let prefill_delay = self.config.prefill_byte_delay * input_bytes as u32;
+        tokio::time::sleep(self.step_delay(requests.len()) + prefill_delay).await;
+        let (generations, batch) = self.generate(batch.id, requests, true);
+        Ok((generations, batch, self.cache_usage()))
+    }
+
+    /// Generate one token for each request in the given cached batches, concatenated in the
+    /// first batch
+    pub(crate) async fn decode(
+        &mut self,
+        batches: Vec<Batch>,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>)> {
+        let batch_id = match batches.first() {
+            Some(batch) => batch.id,
+            None => return Err(ClientError::InvalidArgument("no batches".to_string())),
+        };
+
+        let mut requests = Vec::new();
+        for batch in &batches {
+            let cached = self.batches.remove(&batch.id).ok_or_else(|| {
+                ClientError::InvalidArgument(format!("batch {} not found in cache", batch.id))
+            })?;
+            requests.extend(cached);
+        }
+        self.record(MockCall::Decode(
+            requests.iter().map(|request| request.request.id).collect(),
+        ));
+        // The cached batches are lost on failure
+        for batch in &batches {
+            self.check(batch)?;
+        }
+
+        tokio::time::sleep(self.step_delay(requests.len())).await;
+        if let Some(request) = requests
+            .iter()
+            .find(|request| self.config.panic_inputs.contains(&request.request.inputs))
+        {
+            panic!("mock panic on request {}", request.request.id);
+        }
+        let (generations, batch) = self.generate(batch_id, requests, false);
+        Ok((generations, batch, self.cache_usage()))
+    }
+
+    /// Generate draft tokens for each request in the given cached batch, after replacing the
+    /// tokens generated by the last call by the accepted ones
+    pub(crate) async fn draft(
+        &mut self,
+        batch: Batch,
+        tokens: u32,
+        accepted: &[RequestTokens],
+    ) -> Result<(Vec<RequestTokens>, Option<Batch>)> {
+        self.check(&batch)?;
+        let miss_interval = self.config.draft_miss_interval;
+        let requests = self.batches.get_mut(&batch.id).ok_or_else(|| {
+            ClientError::InvalidArgument(format!("batch {} not found in cache", batch.id))
+        })?;
+
+        let mut drafts = Vec::with_capacity(requests.len());
+        for request in requests.iter_mut() {
+            if let Some(accepted) = accepted
+                .iter()
+                .find(|accepted| accepted.request_id == request.request.id)
+            {
+                let confirmed = request.tokens.len() - request.last_generated;
+                request.tokens.truncate(confirmed);
+                request.tokens.extend(&accepted.ids);
+            }
+            let ids: Vec<u32> = (0..tokens)
+                .map(|_| request.draft_token(miss_interval))
+                .collect();
+            request.last_generated = ids.len();
+            drafts.push(RequestTokens {
+                request_id: request.request.id,
+                ids,
+            });
+        }
+
+        tokio::time::sleep(self.config.token_delay * tokens).await;
+        Ok((drafts, Some(batch)))
+    }
+
+    /// Generate the tokens of each request in the given cached batch up to its first wrong
+    /// draft token
+    pub(crate) async fn verify(
+        &mut self,
+        batch: Batch,
+        tokens: &[RequestTokens],
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>)> {
+        let requests = self.batches.remove(&batch.id).ok_or_else(|| {
+            ClientError::InvalidArgument(format!("batch {} not found in cache", batch.id))
+        })?;
+        // The cached batch is lost on failure
+        self.check(&batch)?;
+
+        tokio::time::sleep(self.config.token_delay).await;
+        let mut generations = Vec::new();
+        let mut remaining = Vec::with_capacity(requests.len());
+        for mut request in requests {
+            let draft = tokens
+                .iter()
+                .find(|tokens| tokens.request_id == request.request.id)
+                .map_or(&[][..], |tokens| tokens.ids.as_slice());
+            for position in 0.. {
+                let generation = self.next_token(&mut request, false);
+                let finished = generation.generated_text.is_some();
+                let accepted = draft.get(position) == Some(&generation.token_id);
+                generations.push(generation);
+                if finished {
+                    break;
+                }
+                if !accepted {
+                    remaining.push(request);
+                    break;
+                }
+            }
+        }
+        let batch = self.cache(batch.id, remaining);
+        Ok((generations, batch, self.cache_usage()))
+    }
+
+    fn generate(
+        &mut self,
+        batch_id: u64,
+        requests: Vec<MockRequest>,
+        prefill: bool,
+    ) -> (Vec<Generation>, Option<Batch>) {
+        let mut generations = Vec::with_capacity(requests.len());
+        let mut remaining = Vec::with_capacity(requests.len());
+        for mut request in requests {
+            let generation = self.next_token(&mut request, prefill);
+            request.last_generated = 1;
+            if generation.generated_text.is_none() {
+                remaining.push(request);
+            }
+            generations.push(generation);
+        }
+        (generations, self.cache(batch_id, remaining))
+    }
+
+    fn next_token(&self, request: &mut MockRequest, prefill: bool) -> Generation {
+        let mut generation = request.next_token(prefill);
+        if self.config.zero_generated_tokens {
+            if let Some(generated_text) = generation.generated_text.as_mut() {
+                generated_text.generated_tokens = 0;
+            }
+        }
+        generation
+    }
+
+    /// Cache the requests that are not finished
+    fn cache(&mut self, batch_id: u64, remaining: Vec<MockRequest>) -> Option<Batch> {
+        if remaining.is_empty() {
+            return None;
+        }
+        let batch = Batch {
+            id: batch_id,
+            requests: remaining
+                .iter()
+                .map(|request| request.request.clone())
+                .collect(),
+            size: remaining.len() as u32,
+        };
+        self.batches.insert(batch_id, remaining);
+        Some(batch)
+    }
+
+    /// Blocks of the input words and generated tokens of the cached requests
+    fn cache_usage(&self) -> Option<CacheUsage> {
+        let blocks_total = self.config.cache_blocks_total?;
+        let blocks_used: usize = self
+            .batches
+            .values()
+            .flatten()
+            .map(|request| request.request.inputs.split_whitespace().count() + request.tokens.len())
+            .sum();
+        CacheUsage::new(Some(blocks_used as u32), Some(blocks_total))
+    }
+
+    /// Fail the scripted batches and requests
+    fn check(&self, batch: &Batch) -> Result<()> {
+        if self.config.fail_batches.contains(&batch.id) {
+            return Err(ClientError::Generation(format!(
+                "mock failure of batch {}",
+                batch.id
+            )));
+        }
+        if self.config.unavailable_batches.contains(&batch.id) {
+            return Err(ClientError::Unavailable(format!(
+                "mock unavailability of batch {}",
+                batch.id
+            )));
+        }
+        let unknown_token = batch.requests.iter().find_map(|request| {
+            let parameters = request.parameters.as_ref()?;
+            parameters
+                .allowed_tokens
+                .iter()
+                .find(|id| **id as usize >= VOCABULARY.len())
+        });
+        if let Some(id) = unknown_token {
+            return Err(ClientError::Generation(format!(
+                "mock vocabulary has no token {id}"
+            )));
+        }
+        // As the backends not clamping `top_k` to their vocabulary
+        let top_k = batch.requests.iter().find_map(|request| {
+            let parameters = request.parameters.as_ref()?;
+            (parameters.top_k as usize >= VOCABULARY.len()).then_some(parameters.top_k)
+        });
+        if let Some(top_k) = top_k {
+            return Err(ClientError::Generation(format!(
+                "mock vocabulary has fewer than {top_k} tokens"
+            )));
+        }
+        match batch.requests.iter().find(|request| {
+            self.config.fail_requests.contains(&request.id)
+                || self.config.fail_inputs.contains(&request.inputs)
+        }) {
+            Some(request) => Err(ClientError::Generation(format!(
+                "mock failure of request {}",
+                request.id
+            ))),
+            None => Ok(()),
+        }
+    }
+}
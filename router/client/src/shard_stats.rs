@@ -0,0 +1,181 @@
+/// Response times and errors of each shard of a backend
+use crate::{ClientError, Result};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Number of recent calls whose response times are kept for each shard
+const WINDOW: usize = 100;
+/// The shards are not compared until each of them responded this many times
+const MIN_SAMPLES: usize = 10;
+/// Default ratio between the median response time of a shard and the median of the other shards
+/// above which the shard is reported as slow
+pub const DEFAULT_SLOW_SHARD_FACTOR: f64 = 2.0;
+
+/// Statistics of the shards of a backend, clones share the same statistics
+#[derive(Debug, Clone)]
+pub struct ShardStats {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    slow_factor: f64,
+    shards: Vec<ShardState>,
+}
+
+#[derive(Debug)]
+struct ShardState {
+    uri: String,
+    /// Response times of the recent calls, oldest first
+    latencies: VecDeque<Duration>,
+    /// Error of the last failed call
+    last_error: Option<String>,
+    /// False if the last call failed
+    healthy: bool,
+    /// Set while the shard is slower than the others by `slow_factor`
+    slow: bool,
+}
+
+/// Statistics of a shard
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShardSnapshot {
+    pub uri: String,
+    pub healthy: bool,
+    pub last_error: Option<String>,
+    /// None until the shard responded to a call
+    pub p50: Option<Duration>,
+    pub p95: Option<Duration>,
+    pub slow: bool,
+}
+
+impl ShardStats {
+    pub fn new(uris: Vec<String>, slow_factor: f64) -> Self {
+        let shards = uris
+            .into_iter()
+            .map(|uri| ShardState {
+                uri,
+                latencies: VecDeque::with_capacity(WINDOW),
+                last_error: None,
+                healthy: true,
+                slow: false,
+            })
+            .collect();
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                slow_factor,
+                shards,
+            })),
+        }
+    }
+
+    pub(crate) fn set_slow_factor(&self, slow_factor: f64) {
+        self.inner.lock().unwrap().slow_factor = slow_factor;
+    }
+
+    /// Record the response time or the error of each shard for a `method` call sent to all of
+    /// them, in the order of the shards
+    /// Only the response times of the successful calls are kept
+    pub fn record<T>(&self, method: &'static str, results: &[(Duration, Result<T>)]) {
+        let mut inner = self.inner.lock().unwrap();
+        for (shard, (latency, result)) in inner.shards.iter_mut().zip(results) {
+            match result {
+                Ok(_) => {
+                    metrics::histogram!("tgi_shard_request_duration", *latency, "shard" => shard.uri.clone(), "method" => method);
+                    shard.healthy = true;
+                    if shard.latencies.len() == WINDOW {
+                        shard.latencies.pop_front();
+                    }
+                    shard.latencies.push_back(*latency);
+                }
+                Err(err) => {
+                    metrics::increment_counter!("tgi_shard_request_failure", "shard" => shard.uri.clone(), "method" => method);
+                    shard.healthy = false;
+                    shard.last_error = Some(err.to_string());
+                }
+            }
+        }
+        inner.check_slow_shards();
+    }
+
+    /// Statistics of each shard, in the order of the shards
+    pub fn snapshot(&self) -> Vec<ShardSnapshot> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .shards
+            .iter()
+            .map(|shard| ShardSnapshot {
+                uri: shard.uri.clone(),
+                healthy: shard.healthy,
+                last_error: shard.last_error.clone(),
+                p50: quantile(shard.latencies.iter().copied(), 0.5),
+                p95: quantile(shard.latencies.iter().copied(), 0.95),
+                slow: shard.slow,
+            })
+            .collect()
+    }
+}
+
+impl Inner {
+    /// Warn once when a shard becomes slower than the median of the other shards
+    fn check_slow_shards(&mut self) {
+        if self.shards.len() < 2
+            || self
+                .shards
+                .iter()
+                .any(|shard| shard.latencies.len() < MIN_SAMPLES)
+        {
+            return;
+        }
+        let medians: Vec<Duration> = self
+            .shards
+            .iter()
+            .map(|shard| quantile(shard.latencies.iter().copied(), 0.5).unwrap_or_default())
+            .collect();
+        for (i, shard) in self.shards.iter_mut().enumerate() {
+            let others = medians
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, median)| *median);
+            let others = quantile(others, 0.5).unwrap_or_default();
+            let slow = medians[i].as_secs_f64() > others.as_secs_f64() * self.slow_factor;
+            if slow && !shard.slow {
+                tracing::warn!(
+                    "Shard {} is slow: median response time of {:?}, {:?} for the other shards",
+                    shard.uri,
+                    medians[i],
+                    others
+                );
+            } else if !slow && shard.slow {
+                tracing::info!("Shard {} is not slow anymore", shard.uri);
+            }
+            shard.slow = slow;
+        }
+    }
+}
+
+/// None if there are no samples
+fn quantile(samples: impl Iterator<Item = Duration>, quantile: f64) -> Option<Duration> {
+    let mut samples: Vec<Duration> = samples.collect();
+    samples.sort();
+    let index = (quantile * samples.len() as f64) as usize;
+    samples
+        .get(index.min(samples.len().checked_sub(1)?))
+        .copied()
+}
+
+/// Result of a call sent to all the shards
+/// All the shards return the same response, the first error is returned if any of them failed
+pub(crate) fn merge<T>(results: Vec<(Duration, Result<T>)>) -> Result<T> {
+    let mut first = None;
+    for (_, result) in results {
+        match result {
+            Err(err) => return Err(err),
+            Ok(response) => {
+                first.get_or_insert(response);
+            }
+        }
+    }
+    first.ok_or_else(|| ClientError::Connection("no shards".to_string()))
+}
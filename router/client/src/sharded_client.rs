@@ -1,19 +1,45 @@
 /// Multi shard Client
+use crate::mock::MockClient;
+use crate::shard_stats::{merge, DEFAULT_SLOW_SHARD_FACTOR};
 use crate::Result;
-use crate::{Batch, Client, Generation};
+use crate::{
+    Batch, CacheUsage, Client, ClientError, Generation, InfoResponse, MockConfig, RequestTokens,
+    ShardStats,
+};
 use futures::future::join_all;
-use futures::future::select_all;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
 use tonic::transport::Uri;
 use tracing::instrument;
 
 /// Text Generation Inference gRPC multi client
+/// Its clones share the connections and statistics of the shards
+#[derive(Clone)]
 pub struct ShardedClient {
     clients: Vec<Client>,
+    /// Response times and errors of `clients`, in the same order
+    stats: ShardStats,
+    /// Replaces the shards if set
+    mock: Option<MockClient>,
 }
 
 impl ShardedClient {
-    fn new(clients: Vec<Client>) -> Self {
-        Self { clients }
+    fn new(clients: Vec<Client>, uris: Vec<String>) -> Self {
+        Self {
+            clients,
+            stats: ShardStats::new(uris, DEFAULT_SLOW_SHARD_FACTOR),
+            mock: None,
+        }
+    }
+
+    /// Returns a client generating tokens in process instead of calling shards
+    pub fn mock(config: MockConfig) -> Self {
+        Self {
+            clients: Vec::new(),
+            stats: ShardStats::new(Vec::new(), DEFAULT_SLOW_SHARD_FACTOR),
+            mock: Some(MockClient::new(config)),
+        }
     }
 
     /// Create a new ShardedClient from a master client. The master client will communicate with
@@ -21,9 +47,9 @@ impl ShardedClient {
     async fn from_master_client(mut master_client: Client) -> Result<Self> {
         // Get all uris/unix sockets from the master client
         let uris = master_client.service_discovery().await.unwrap();
-        let futures = uris.into_iter().map(Client::connect_uds);
+        let futures = uris.clone().into_iter().map(Client::connect_uds);
         let clients: Result<Vec<Client>> = join_all(futures).await.into_iter().collect();
-        Ok(Self::new(clients?))
+        Ok(Self::new(clients?, uris))
     }
 
     /// Returns a client connected to the given uri
@@ -38,9 +64,36 @@ impl ShardedClient {
         Self::from_master_client(master_client).await
     }
 
+    /// Statistics of the shards, updated by the prefill and decode calls
+    pub fn stats(&self) -> ShardStats {
+        self.stats.clone()
+    }
+
+    /// Ratio between the median response time of a shard and the median of the other shards
+    /// above which a warning names the shard
+    pub fn set_slow_shard_factor(&self, slow_shard_factor: f64) {
+        self.stats.set_slow_factor(slow_shard_factor);
+    }
+
+    /// Vocabulary of the tokenizer of the master shard, all the shards loading the same model
+    #[instrument(skip(self))]
+    pub async fn info(&mut self) -> Result<InfoResponse> {
+        if let Some(mock) = &self.mock {
+            return Ok(mock.info());
+        }
+        match self.clients.first_mut() {
+            Some(client) => client.info().await,
+            None => Err(ClientError::Connection("no shard".to_string())),
+        }
+    }
+
     /// Clear the past generations cache
     #[instrument(skip(self))]
     pub async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
+        if let Some(mock) = &mut self.mock {
+            mock.clear_cache(batch_id);
+            return Ok(());
+        }
         let futures: Vec<_> = self
             .clients
             .iter_mut()
@@ -49,38 +102,131 @@ impl ShardedClient {
         join_all(futures).await.into_iter().collect()
     }
 
+    /// Remove requests from a cached batch on all the shards
+    ///
+    /// Returns the cached batch without these requests
+    #[instrument(skip(self))]
+    pub async fn cancel(&mut self, batch_id: u64, request_ids: Vec<u64>) -> Result<Option<Batch>> {
+        if let Some(mock) = &mut self.mock {
+            return mock.cancel(batch_id, &request_ids);
+        }
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.cancel(batch_id, request_ids.clone()))
+            .collect();
+        // All the shards must drop the requests, then they return the same batch
+        let results: Result<Vec<Option<Batch>>> = join_all(futures).await.into_iter().collect();
+        Ok(results?.into_iter().next().flatten())
+    }
+
     /// Generate one token for each request in the given batch
     ///
-    /// Returns Generation for each request in batch
-    /// and the next cached batch
+    /// Returns Generation for each request in batch,
+    /// the next cached batch and the KV-cache usage if the shards report it
     #[instrument(skip_all, fields(id = &batch.id, size = &batch.size))]
-    pub async fn prefill(&mut self, batch: Batch) -> Result<(Vec<Generation>, Option<Batch>)> {
+    pub async fn prefill(
+        &mut self,
+        batch: Batch,
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>)> {
+        if let Some(mock) = &mut self.mock {
+            return mock.prefill(batch).await;
+        }
         let futures: Vec<_> = self
             .clients
             .iter_mut()
-            .map(|client| Box::pin(client.prefill(batch.clone())))
+            .map(|client| timed(client.prefill(batch.clone(), deadline)))
             .collect();
-        // As soon as we receive one response, we can return as all shards will return the same
-        let (result, _, _) = select_all(futures).await;
-        result
+        // The shards synchronize at each step, so waiting for all of them to measure each one
+        // barely delays the response
+        let results = join_all(futures).await;
+        self.stats.record("prefill", &results);
+        merge(results)
     }
 
     /// Generate one token for each request in the given cached batches
     ///
-    /// Returns Generation for each request in batches
-    /// and the next cached batch
+    /// Returns Generation for each request in batches,
+    /// the next cached batch and the KV-cache usage if the shards report it
     #[instrument(skip_all, fields(size = batches.iter().map(|batch|{batch.size}).sum::<u32>()))]
     pub async fn decode(
         &mut self,
         batches: Vec<Batch>,
-    ) -> Result<(Vec<Generation>, Option<Batch>)> {
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>)> {
+        if let Some(mock) = &mut self.mock {
+            return mock.decode(batches).await;
+        }
         let futures: Vec<_> = self
             .clients
             .iter_mut()
-            .map(|client| Box::pin(client.decode(batches.clone())))
+            .map(|client| timed(client.decode(batches.clone(), deadline)))
             .collect();
-        // As soon as we receive one response, we can return as all shards will return the same
-        let (result, _, _) = select_all(futures).await;
-        result
+        // The shards synchronize at each step, so waiting for all of them to measure each one
+        // barely delays the response
+        let results = join_all(futures).await;
+        self.stats.record("decode", &results);
+        merge(results)
     }
+
+    /// Generate `tokens` draft tokens for each request in the given cached batch, after
+    /// replacing its last generated tokens by the `accepted` ones
+    ///
+    /// Returns the draft tokens of each request and the next cached batch
+    #[instrument(skip_all, fields(id = &batch.id, size = &batch.size, tokens))]
+    pub async fn draft(
+        &mut self,
+        batch: Batch,
+        tokens: u32,
+        accepted: Vec<RequestTokens>,
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<RequestTokens>, Option<Batch>)> {
+        if let Some(mock) = &mut self.mock {
+            return mock.draft(batch, tokens, &accepted).await;
+        }
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| timed(client.draft(batch.clone(), tokens, accepted.clone(), deadline)))
+            .collect();
+        // The shards synchronize at each step, so waiting for all of them to measure each one
+        // barely delays the response
+        let results = join_all(futures).await;
+        self.stats.record("draft", &results);
+        merge(results)
+    }
+
+    /// Check the draft tokens of each request in the given cached batch
+    ///
+    /// Returns Generation for each accepted token and for the token following them,
+    /// the next cached batch and the KV-cache usage if the shards report it
+    #[instrument(skip_all, fields(id = &batch.id, size = &batch.size))]
+    pub async fn verify(
+        &mut self,
+        batch: Batch,
+        tokens: Vec<RequestTokens>,
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>)> {
+        if let Some(mock) = &mut self.mock {
+            return mock.verify(batch, &tokens).await;
+        }
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| timed(client.verify(batch.clone(), tokens.clone(), deadline)))
+            .collect();
+        // The shards synchronize at each step, so waiting for all of them to measure each one
+        // barely delays the response
+        let results = join_all(futures).await;
+        self.stats.record("verify", &results);
+        merge(results)
+    }
+}
+
+/// Response time of a shard with its response
+async fn timed<T>(call: impl Future<Output = Result<T>>) -> (Duration, Result<T>) {
+    let start = Instant::now();
+    let result = call.await;
+    (start.elapsed(), result)
 }
@@ -0,0 +1,70 @@
+/// Router embedded in another axum service, behind the authentication of the service
+///
+/// Serves the mock backend with the tokenizer given as first argument:
+///   EMBEDDED_TOKEN=secret cargo run --example embedded -- path/to/tokenizer.json
+///   curl -H "Authorization: Bearer secret" localhost:3000/generate \
+///     -H "Content-Type: application/json" -d '{"inputs": "Hello"}'
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use std::net::SocketAddr;
+use text_generation_client::{MockConfig, ShardedClient};
+use text_generation_router::server::{RouterApp, ServerOptions};
+use tokenizers::Tokenizer;
+
+/// Token expected by the authentication layer
+#[derive(Clone)]
+struct AuthToken(String);
+
+/// Reject the requests without the `AuthToken` bearer token
+async fn authenticate<B>(request: Request<B>, next: Next<B>) -> Response {
+    let expected = request
+        .extensions()
+        .get::<AuthToken>()
+        .map(|token| format!("Bearer {}", token.0));
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    match (authorization, expected) {
+        (Some(authorization), Some(expected)) if authorization == expected => {
+            next.run(request).await
+        }
+        _ => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+fn main() {
+    let tokenizer_path = std::env::args()
+        .nth(1)
+        .expect("usage: embedded <tokenizer.json>");
+    let tokenizer = Tokenizer::from_file(tokenizer_path).expect("Could not load the tokenizer");
+    let token = std::env::var("EMBEDDED_TOKEN").unwrap_or_else(|_| "secret".to_string());
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let client = ShardedClient::mock(MockConfig::default()).into();
+            let mut options = ServerOptions::new("mock".to_string(), tokenizer, client);
+            options.max_concurrent_requests = 16;
+            let router = RouterApp::new(options).into_router();
+
+            // Routes of the service next to the routes of the router, all authenticated
+            let app = Router::new()
+                .route("/service/status", get(|| async { "ok" }))
+                .merge(router)
+                .layer(middleware::from_fn(authenticate))
+                .layer(axum::Extension(AuthToken(token)));
+
+            let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+            axum::Server::bind(&addr)
+                // The connection limits of the router need the client addresses
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+                .unwrap();
+        });
+}
@@ -0,0 +1,172 @@
+/// Sampled access logs and request counters
+use crate::usage::{api_key, api_key_id};
+use axum::extract::{MatchedPath, State};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Route of the requests matching no route
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+/// Access log configuration
+#[derive(Debug, Clone)]
+pub(crate) struct AccessLog {
+    /// Share of the successful requests that are logged
+    sample_rate: f64,
+    /// Requests slower than this are always logged
+    slow_threshold: Duration,
+    /// Label of the request counters
+    model_id: String,
+}
+
+impl AccessLog {
+    pub(crate) fn new(sample_rate: f64, slow_threshold: Duration, model_id: String) -> Self {
+        Self {
+            sample_rate,
+            slow_threshold,
+            model_id,
+        }
+    }
+}
+
+/// Access log record of a request, filled by the handlers
+///
+/// The record is logged and counted once all its clones are dropped, so that streaming requests
+/// are logged when their stream completes
+#[derive(Clone)]
+pub(crate) struct RequestLog {
+    inner: Arc<Record>,
+}
+
+struct Record {
+    config: AccessLog,
+    start_time: Instant,
+    method: String,
+    route: String,
+    api_key_id: Option<String>,
+    state: Mutex<RecordState>,
+}
+
+#[derive(Default)]
+struct RecordState {
+    status: u16,
+    prompt_tokens: u32,
+    generated_tokens: u32,
+    error_type: Option<String>,
+    /// None if the request is not streamed, else true if the stream reached its end
+    stream_end: Option<bool>,
+}
+
+impl RequestLog {
+    /// Set the number of tokens of the request
+    pub(crate) fn tokens(&self, prompt_tokens: u32, generated_tokens: u32) {
+        let mut state = self.inner.state.lock();
+        state.prompt_tokens = prompt_tokens;
+        state.generated_tokens = generated_tokens;
+    }
+
+    /// Mark a request as failed
+    pub(crate) fn error(&self, error_type: &str) {
+        self.inner.state.lock().error_type = Some(error_type.to_string());
+    }
+
+    /// Mark a request as streamed
+    pub(crate) fn stream(&self) {
+        self.inner.state.lock().stream_end = Some(false);
+    }
+
+    /// Mark the stream of a request as ended without errors
+    pub(crate) fn stream_end(&self) {
+        self.inner.state.lock().stream_end = Some(true);
+    }
+
+    fn status(&self, status: u16) {
+        self.inner.state.lock().status = status;
+    }
+}
+
+impl Drop for Record {
+    fn drop(&mut self) {
+        let latency = self.start_time.elapsed();
+        let state = self.state.get_mut();
+
+        // Counters
+        let error_type = state
+            .error_type
+            .clone()
+            .unwrap_or_else(|| "none".to_string());
+        metrics::increment_counter!(
+            "tgi_request_count",
+            "route" => self.route.clone(),
+            "status" => format!("{}xx", state.status / 100),
+            "error" => error_type,
+            "model_id" => self.config.model_id.clone(),
+        );
+        if let Some(stream_end) = state.stream_end {
+            let outcome = match (&state.error_type, stream_end) {
+                (Some(_), _) => "error",
+                (None, true) => "end",
+                // The client disconnected
+                (None, false) => "disconnected",
+            };
+            metrics::increment_counter!(
+                "tgi_stream_count",
+                "outcome" => outcome,
+                "model_id" => self.config.model_id.clone(),
+            );
+        }
+
+        let failed = state.status >= 400 || state.error_type.is_some();
+        let slow = latency >= self.config.slow_threshold;
+        if !failed && !slow && !rand::thread_rng().gen_bool(self.config.sample_rate) {
+            return;
+        }
+
+        tracing::info!(
+            target: "access_log",
+            method = self.method.as_str(),
+            route = self.route.as_str(),
+            status = state.status,
+            latency_ms = latency.as_millis() as u64,
+            prompt_tokens = state.prompt_tokens,
+            generated_tokens = state.generated_tokens,
+            api_key_id = self.api_key_id.as_deref(),
+            error_type = state.error_type.as_deref(),
+            "request"
+        );
+    }
+}
+
+/// Middleware adding a `RequestLog` to the request extensions
+pub(crate) async fn access_log_middleware<B>(
+    State(config): State<AccessLog>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // The paths of the unmatched requests are not logged nor used as labels, any client can send
+    // as many as it wants
+    let route = match request.extensions().get::<MatchedPath>() {
+        Some(path) => path.as_str().to_string(),
+        None => UNMATCHED_ROUTE.to_string(),
+    };
+    let log = RequestLog {
+        inner: Arc::new(Record {
+            config,
+            start_time: Instant::now(),
+            method: request.method().to_string(),
+            route,
+            api_key_id: api_key(request.headers()).map(|api_key| api_key_id(&api_key)),
+            state: Mutex::new(RecordState::default()),
+        }),
+    };
+    request.extensions_mut().insert(log.clone());
+
+    let response = next.run(request).await;
+    log.status(response.status().as_u16());
+    response
+}
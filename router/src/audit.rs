@@ -0,0 +1,406 @@
+/// Audit log of the generations sent to the audited API keys
+use crate::infer::InferError;
+use crate::limits::LimitProfiles;
+use crate::{FinishReason, GenerateResponse, Token, ValidParameters};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Audit log configuration
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    /// JSONL file the records are appended to, the rotated files get the `.1`, `.2`... suffixes
+    pub path: PathBuf,
+    /// The file is rotated when a record would make it exceed this size
+    pub max_file_bytes: u64,
+    /// Number of rotated files kept, the oldest one being removed
+    pub max_files: usize,
+}
+
+/// Generation of an audited request, as received by the client
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct AuditRecord {
+    /// None for the responses of the response cache
+    pub request_id: Option<u64>,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Keyed hash of the API key
+    pub api_key_id: String,
+    /// The tokens were streamed
+    pub stream: bool,
+    /// The response was served from the response cache
+    pub cached: bool,
+    /// None if the generation did not end
+    pub parameters: Option<ValidParameters>,
+    /// Tokens sent to the client, in order
+    pub tokens: Vec<AuditToken>,
+    /// Text sent to the client at the end of the generation
+    pub generated_text: Option<String>,
+    pub finish_reason: Option<FinishReason>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct AuditToken {
+    pub id: u32,
+    pub text: String,
+}
+
+/// Writes the records of the audited API keys
+/// The requests of the other API keys never allocate a record
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuditLog {
+    /// None if the audit is disabled
+    state: Option<Arc<AuditState>>,
+}
+
+#[derive(Debug)]
+struct AuditState {
+    limit_profiles: LimitProfiles,
+    file: Mutex<AuditFile>,
+}
+
+impl AuditLog {
+    pub(crate) fn new(
+        config: Option<AuditConfig>,
+        limit_profiles: LimitProfiles,
+    ) -> std::io::Result<Self> {
+        let config = match config {
+            None => {
+                let audited = limit_profiles.audited_count();
+                if audited > 0 {
+                    tracing::warn!(
+                        "{audited} API keys are audited but the audit log is disabled, set `audit_log_path`"
+                    );
+                }
+                return Ok(Self::default());
+            }
+            Some(config) => config,
+        };
+        let file = AuditFile::open(config)?;
+        Ok(Self {
+            state: Some(Arc::new(AuditState {
+                limit_profiles,
+                file: Mutex::new(file),
+            })),
+        })
+    }
+
+    /// Start the record of a request sent with the API key with the id `api_key_id`, None if the
+    /// key is not audited
+    /// The record is written when the returned entry is dropped
+    pub(crate) fn start(&self, api_key_id: Option<&str>, stream: bool) -> Option<AuditEntry> {
+        let state = self.state.as_ref()?;
+        let api_key_id = api_key_id?;
+        if !state.limit_profiles.audited(api_key_id) {
+            return None;
+        }
+        Some(AuditEntry {
+            state: state.clone(),
+            record: AuditRecord {
+                request_id: None,
+                timestamp: now_ms(),
+                api_key_id: api_key_id.to_string(),
+                stream,
+                cached: false,
+                parameters: None,
+                tokens: vec![],
+                generated_text: None,
+                finish_reason: None,
+                error: None,
+            },
+            ended: false,
+        })
+    }
+}
+
+/// Record of an audited request being generated
+#[derive(Debug)]
+pub(crate) struct AuditEntry {
+    state: Arc<AuditState>,
+    record: AuditRecord,
+    /// The generation succeeded or failed
+    ended: bool,
+}
+
+impl AuditEntry {
+    pub(crate) fn set_request_id(&mut self, request_id: u64) {
+        self.record.request_id = Some(request_id);
+    }
+
+    /// Token sent to the client
+    pub(crate) fn push(&mut self, token: &Token) {
+        self.record.tokens.push(AuditToken {
+            id: token.id,
+            text: token.text.clone(),
+        });
+    }
+
+    pub(crate) fn succeed(
+        &mut self,
+        parameters: ValidParameters,
+        generated_text: String,
+        finish_reason: FinishReason,
+    ) {
+        self.record.parameters = Some(parameters);
+        self.record.generated_text = Some(generated_text);
+        self.record.finish_reason = Some(finish_reason);
+        self.ended = true;
+    }
+
+    /// Response served from the response cache, its tokens are only known with `details`
+    pub(crate) fn cached(&mut self, response: &GenerateResponse) {
+        self.record.cached = true;
+        if let Some(details) = &response.details {
+            details.tokens.iter().for_each(|token| self.push(token));
+            self.record.parameters = Some(details.parameters.clone());
+            self.record.finish_reason = Some(details.finish_reason.clone());
+        }
+        self.record.generated_text = Some(response.generated_text.clone());
+        self.ended = true;
+    }
+
+    pub(crate) fn fail(&mut self, err: &InferError) {
+        self.record.error = Some(err.to_string());
+        self.ended = true;
+    }
+}
+
+impl Drop for AuditEntry {
+    fn drop(&mut self) {
+        // The client went away before the end of the generation
+        if !self.ended {
+            self.record.error = Some("Request cancelled by the client".to_string());
+        }
+        self.state.file.lock().write(&self.record);
+    }
+}
+
+/// Append-only audit file rotated by size
+#[derive(Debug)]
+struct AuditFile {
+    config: AuditConfig,
+    writer: BufWriter<File>,
+    /// Size of the current file
+    bytes: u64,
+}
+
+impl AuditFile {
+    fn open(config: AuditConfig) -> std::io::Result<Self> {
+        if let Some(dir) = config.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let (writer, bytes) = open(&config.path)?;
+        Ok(Self {
+            config,
+            writer,
+            bytes,
+        })
+    }
+
+    fn write(&mut self, record: &AuditRecord) {
+        match self.try_write(record) {
+            Ok(()) => metrics::increment_counter!("tgi_audit_record"),
+            Err(err) => {
+                tracing::error!(
+                    "Could not write the audit record of {}: {err}",
+                    record.api_key_id
+                );
+                metrics::increment_counter!("tgi_audit_record_dropped");
+            }
+        }
+    }
+
+    /// The record is flushed before returning: a crash only loses the records being generated
+    fn try_write(&mut self, record: &AuditRecord) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        if self.bytes > 0 && self.bytes + line.len() as u64 > self.config.max_file_bytes {
+            self.rotate()?;
+        }
+        self.writer.write_all(&line)?;
+        self.writer.flush()?;
+        self.bytes += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift the rotated files and start a new file, the oldest rotated file being removed
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        let path = &self.config.path;
+        for index in (1..self.config.max_files).rev() {
+            let rotated = rotated_path(path, index);
+            if rotated.exists() {
+                std::fs::rename(&rotated, rotated_path(path, index + 1))?;
+            }
+        }
+        std::fs::rename(path, rotated_path(path, 1))?;
+        (self.writer, self.bytes) = open(path)?;
+        Ok(())
+    }
+}
+
+/// Open the file in append mode with its current size
+fn open(path: &Path) -> std::io::Result<(BufWriter<File>, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let bytes = file.metadata()?.len();
+    Ok((BufWriter::new(file), bytes))
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{index}"));
+    PathBuf::from(rotated)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |timestamp| timestamp.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::LimitsError;
+    use crate::usage::api_key_id;
+
+    fn config(name: &str, max_files: usize, max_file_bytes: u64) -> AuditConfig {
+        let dir = std::env::temp_dir().join(format!("tgi-audit-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).unwrap_or(());
+        AuditConfig {
+            path: dir.join("audit.jsonl"),
+            max_files,
+            max_file_bytes,
+        }
+    }
+
+    fn limit_profiles(name: &str) -> Result<LimitProfiles, LimitsError> {
+        let path = std::env::temp_dir().join(format!(
+            "tgi-audit-profiles-{}-{name}.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            r#"{"api_keys": {"audited-key": {"audit": true}, "other-key": {}}}"#,
+        )?;
+        LimitProfiles::load(Some(path), 1000, 1512)
+    }
+
+    fn token(id: u32, text: &str) -> Token {
+        Token {
+            id,
+            text: text.to_string(),
+            logprob: -0.5,
+            special: false,
+        }
+    }
+
+    fn records(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_audited_keys() {
+        let config = config("keys", 2, 1 << 20);
+        let audit_log =
+            AuditLog::new(Some(config.clone()), limit_profiles("keys").unwrap()).unwrap();
+        assert!(audit_log.start(None, false).is_none());
+        assert!(audit_log
+            .start(Some(&api_key_id("other-key")), false)
+            .is_none());
+        assert!(audit_log
+            .start(Some(&api_key_id("unknown-key")), false)
+            .is_none());
+
+        let mut entry = audit_log
+            .start(Some(&api_key_id("audited-key")), true)
+            .unwrap();
+        entry.set_request_id(7);
+        entry.push(&token(1, "Hello"));
+        entry.push(&token(2, " world"));
+        entry.fail(&InferError::IncompleteGeneration);
+        drop(entry);
+
+        // Written when the entry is dropped, whether the generation ended or not
+        let entry = audit_log
+            .start(Some(&api_key_id("audited-key")), true)
+            .unwrap();
+        drop(entry);
+
+        let records = records(&config.path);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["request_id"], 7);
+        assert_eq!(records[0]["api_key_id"], api_key_id("audited-key"));
+        assert_eq!(records[0]["stream"], true);
+        assert_eq!(
+            records[0]["tokens"],
+            serde_json::json!([{"id": 1, "text": "Hello"}, {"id": 2, "text": " world"}])
+        );
+        assert_eq!(
+            records[0]["error"],
+            InferError::IncompleteGeneration.to_string()
+        );
+        assert_eq!(records[1]["error"], "Request cancelled by the client");
+        assert!(records[1]["request_id"].is_null());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let audit_log = AuditLog::new(None, limit_profiles("disabled").unwrap()).unwrap();
+        assert!(audit_log
+            .start(Some(&api_key_id("audited-key")), false)
+            .is_none());
+    }
+
+    #[test]
+    fn test_rotation() {
+        // Each record is larger than a file: the files have one record each
+        let config = config("rotation", 2, 300);
+        let audit_log =
+            AuditLog::new(Some(config.clone()), limit_profiles("rotation").unwrap()).unwrap();
+        for request_id in 0..5 {
+            let mut entry = audit_log
+                .start(Some(&api_key_id("audited-key")), false)
+                .unwrap();
+            entry.set_request_id(request_id);
+            entry.push(&token(1, &"a".repeat(100)));
+            drop(entry);
+        }
+
+        let request_ids = |path: &Path| -> Vec<u64> {
+            records(path)
+                .iter()
+                .map(|record| record["request_id"].as_u64().unwrap())
+                .collect()
+        };
+        assert_eq!(request_ids(&config.path), vec![4]);
+        assert_eq!(request_ids(&rotated_path(&config.path, 1)), vec![3]);
+        assert_eq!(request_ids(&rotated_path(&config.path, 2)), vec![2]);
+        assert!(!rotated_path(&config.path, 3).exists());
+    }
+
+    #[test]
+    fn test_append() {
+        let config = config("append", 2, 1 << 20);
+        for _ in 0..2 {
+            let audit_log =
+                AuditLog::new(Some(config.clone()), limit_profiles("append").unwrap()).unwrap();
+            drop(
+                audit_log
+                    .start(Some(&api_key_id("audited-key")), false)
+                    .unwrap(),
+            );
+        }
+        // The records of a previous run are kept
+        assert_eq!(records(&config.path).len(), 2);
+    }
+}
@@ -0,0 +1,486 @@
+/// Open-loop load generator driving the inference pipeline
+use crate::breaker::CircuitBreakerConfig;
+use crate::infer::{dedicated_runtime, BatchingPolicy, InferStreamResponse};
+use crate::stop::{NaiveStopBuffer, StopBuffer, StopMatcher};
+use crate::{
+    default_parameters, GenerateParameters, GenerateRequest, Infer, RequestContext, StopConfig,
+    Validation,
+};
+use metrics_exporter_prometheus::PrometheusHandle;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+use text_generation_client::ShardedClient;
+use tokenizers::Tokenizer;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_stream::StreamExt;
+
+/// Benchmark load and router configuration
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Requests sent per second, whether the previous requests are done or not
+    pub requests_per_second: f64,
+    /// Time during which requests are sent
+    pub duration: Duration,
+    /// Number of words of the prompts, drawn uniformly
+    pub prompt_length: RangeInclusive<usize>,
+    /// `max_new_tokens` of the requests, drawn uniformly
+    pub max_new_tokens: RangeInclusive<u32>,
+    /// Share of the requests that are streamed
+    pub stream_ratio: f64,
+    pub max_concurrent_requests: usize,
+    pub max_input_length: usize,
+    pub max_total_tokens: usize,
+    pub max_batch_size: usize,
+    pub max_waiting_tokens: usize,
+    pub prefill_chunk_tokens: Option<u32>,
+    pub batching_policy: BatchingPolicy,
+    pub validation_workers: usize,
+    /// Worker threads of a dedicated runtime of the batching task, 0 to share the runtime of the
+    /// load generator
+    pub batching_threads: usize,
+}
+
+/// Benchmark results
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub requests: usize,
+    pub failed_requests: usize,
+    pub duration: Duration,
+    /// Generated tokens per second
+    pub throughput: f64,
+    /// Time between the validation and the batching of the requests
+    pub queue_time: Percentiles,
+    /// Time between two tokens of the streamed requests
+    pub inter_token_latency: Percentiles,
+    /// Mean share of `max_batch_size` used by the decode calls
+    pub batch_occupancy: Option<f64>,
+    /// Mean time between the end of a decode call and the start of the next one of the same batch
+    pub decode_gap: Option<Duration>,
+}
+
+/// Percentiles of a latency distribution
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+impl Percentiles {
+    fn new(mut values: Vec<Duration>) -> Self {
+        values.sort();
+        let percentile = |p: f64| match values.len() {
+            0 => Duration::ZERO,
+            len => values[((p * len as f64).ceil() as usize).clamp(1, len) - 1],
+        };
+        Self {
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+        }
+    }
+}
+
+/// Timings of one request
+struct Sample {
+    generated_tokens: u32,
+    queue_time: Duration,
+    /// Only measured for streamed requests
+    inter_token_latencies: Vec<Duration>,
+}
+
+/// Send the load of `config` to `client` through the validation, queue and batching task of the
+/// router
+/// `prometheus` is the handle of the installed metrics recorder, used to read the batch occupancy
+/// and the decode gaps
+pub async fn run(
+    config: BenchConfig,
+    tokenizer: Tokenizer,
+    client: ShardedClient,
+    prometheus: Option<PrometheusHandle>,
+) -> Report {
+    let validation = Validation::basic(
+        tokenizer,
+        config.validation_workers,
+        1,
+        4,
+        config.max_input_length,
+        config.max_total_tokens,
+    );
+    let infer = Infer::builder(client.into(), validation)
+        .max_batch_size(config.max_batch_size)
+        .max_waiting_tokens(config.max_waiting_tokens)
+        .prefill_chunk_tokens(config.prefill_chunk_tokens)
+        .min_downgraded_new_tokens(1)
+        .batching_policy(config.batching_policy)
+        .max_concurrent_requests(config.max_concurrent_requests)
+        .circuit_breaker(CircuitBreakerConfig {
+            threshold: 0,
+            probe_interval: Duration::from_secs(5),
+        })
+        .batching_runtime(
+            (config.batching_threads > 0).then(|| dedicated_runtime(config.batching_threads)),
+        )
+        .build();
+
+    // Open-loop load
+    let start_time = Instant::now();
+    let mut interval =
+        tokio::time::interval(Duration::from_secs_f64(1.0 / config.requests_per_second));
+    let mut requests = JoinSet::new();
+    while start_time.elapsed() < config.duration {
+        interval.tick().await;
+        let (request, stream) = {
+            let mut rng = rand::thread_rng();
+            let prompt_length = rng.gen_range(config.prompt_length.clone());
+            let request = GenerateRequest {
+                inputs: "hello ".repeat(prompt_length),
+                parameters: GenerateParameters {
+                    max_new_tokens: rng.gen_range(config.max_new_tokens.clone()),
+                    ..default_parameters()
+                },
+                template: None,
+                template_vars: None,
+                preset: None,
+                model: None,
+            };
+            (request, rng.gen_bool(config.stream_ratio))
+        };
+        let infer = infer.clone();
+        requests.spawn(async move {
+            match stream {
+                true => stream_request(&infer, request).await,
+                false => generate_request(&infer, request).await,
+            }
+        });
+    }
+
+    let mut samples = Vec::new();
+    let mut failed_requests = 0;
+    while let Some(sample) = requests.join_next().await {
+        match sample.expect("benchmark request panicked") {
+            Some(sample) => samples.push(sample),
+            None => failed_requests += 1,
+        }
+    }
+    let duration = start_time.elapsed();
+
+    let generated_tokens: u32 = samples.iter().map(|sample| sample.generated_tokens).sum();
+    let metrics = prometheus.map(|handle| handle.render());
+    Report {
+        requests: samples.len() + failed_requests,
+        failed_requests,
+        duration,
+        throughput: generated_tokens as f64 / duration.as_secs_f64(),
+        queue_time: Percentiles::new(samples.iter().map(|sample| sample.queue_time).collect()),
+        inter_token_latency: Percentiles::new(
+            samples
+                .iter()
+                .flat_map(|sample| sample.inter_token_latencies.iter().copied())
+                .collect(),
+        ),
+        batch_occupancy: metrics
+            .as_deref()
+            .and_then(|metrics| summary_mean(metrics, "tgi_batch_efficiency")),
+        decode_gap: metrics
+            .as_deref()
+            .and_then(|metrics| summary_mean(metrics, "tgi_batch_decode_gap_duration"))
+            .map(Duration::from_secs_f64),
+    }
+}
+
+async fn generate_request(infer: &Infer, request: GenerateRequest) -> Option<Sample> {
+    let response = infer
+        .generate(request, RequestContext::default())
+        .await
+        .ok()?;
+    Some(Sample {
+        generated_tokens: response.generated_text.generated_tokens,
+        // The queue and the batching task may not agree on their order, as `RequestTimings`
+        queue_time: response.start.saturating_duration_since(response.queued),
+        inter_token_latencies: Vec::new(),
+    })
+}
+
+async fn stream_request(infer: &Infer, request: GenerateRequest) -> Option<Sample> {
+    let mut stream = infer
+        .generate_stream(request, RequestContext::default(), infer.register())
+        .await
+        .ok()?;
+    let mut inter_token_latencies = Vec::new();
+    let mut last_token = None;
+    while let Some(response) = stream.next().await {
+        let now = Instant::now();
+        match response.ok()? {
+            InferStreamResponse::Token(_) => {
+                if let Some(last_token) = last_token {
+                    inter_token_latencies.push(now - last_token);
+                }
+                last_token = Some(now);
+            }
+            InferStreamResponse::End {
+                generated_text,
+                start,
+                queued,
+                ..
+            } => {
+                if let Some(last_token) = last_token {
+                    inter_token_latencies.push(now - last_token);
+                }
+                return Some(Sample {
+                    generated_tokens: generated_text.generated_tokens,
+                    queue_time: start.saturating_duration_since(queued),
+                    inter_token_latencies,
+                });
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Mean of the `name` summary of a Prometheus report, over all its labels
+fn summary_mean(metrics: &str, name: &str) -> Option<f64> {
+    let value = |name: &str| -> f64 {
+        metrics
+            .lines()
+            .filter(|line| line.starts_with(name))
+            .filter_map(|line| line.rsplit(' ').next()?.parse::<f64>().ok())
+            .sum()
+    };
+    let count = value(&format!("{name}_count"));
+    (count > 0.0).then(|| value(&format!("{name}_sum")) / count)
+}
+
+impl Report {
+    pub fn csv_header() -> &'static str {
+        "requests,failed_requests,duration_s,throughput_tokens_s,queue_time_p50_ms,queue_time_p90_ms,queue_time_p99_ms,inter_token_latency_p50_ms,inter_token_latency_p90_ms,inter_token_latency_p99_ms,batch_occupancy,decode_gap_ms"
+    }
+
+    pub fn csv_row(&self) -> String {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        format!(
+            "{},{},{:.3},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{:.2},{},{}",
+            self.requests,
+            self.failed_requests,
+            self.duration.as_secs_f64(),
+            self.throughput,
+            ms(self.queue_time.p50),
+            ms(self.queue_time.p90),
+            ms(self.queue_time.p99),
+            ms(self.inter_token_latency.p50),
+            ms(self.inter_token_latency.p90),
+            ms(self.inter_token_latency.p99),
+            self.batch_occupancy
+                .map(|occupancy| format!("{occupancy:.3}"))
+                .unwrap_or_default(),
+            self.decode_gap
+                .map(|decode_gap| format!("{:.3}", ms(decode_gap)))
+                .unwrap_or_default(),
+        )
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "| {:<24} | {:>16} |",
+            "Requests",
+            format!("{} ({} failed)", self.requests, self.failed_requests)
+        )?;
+        writeln!(
+            f,
+            "| {:<24} | {:>16} |",
+            "Duration",
+            format!("{:.2?}", self.duration)
+        )?;
+        writeln!(
+            f,
+            "| {:<24} | {:>16} |",
+            "Throughput (tokens/s)",
+            format!("{:.2}", self.throughput)
+        )?;
+        for (name, percentiles) in [
+            ("Queue time", self.queue_time),
+            ("Inter-token latency", self.inter_token_latency),
+        ] {
+            for (percentile, value) in [
+                ("p50", percentiles.p50),
+                ("p90", percentiles.p90),
+                ("p99", percentiles.p99),
+            ] {
+                writeln!(
+                    f,
+                    "| {:<24} | {:>16} |",
+                    format!("{name} {percentile}"),
+                    format!("{value:.2?}")
+                )?;
+            }
+        }
+        let occupancy = match self.batch_occupancy {
+            Some(occupancy) => format!("{:.1}%", occupancy * 100.0),
+            None => "-".to_string(),
+        };
+        writeln!(f, "| {:<24} | {:>16} |", "Batch occupancy", occupancy)?;
+        let decode_gap = match self.decode_gap {
+            Some(decode_gap) => format!("{decode_gap:.2?}"),
+            None => "-".to_string(),
+        };
+        write!(f, "| {:<24} | {:>16} |", "Decode gap (mean)", decode_gap)
+    }
+}
+
+/// Generations scanned by `stop_scan`, the timings are their mean
+const STOP_SCAN_RUNS: u32 = 100;
+
+/// Time to scan a generation for its stop sequences
+#[derive(Debug, Clone)]
+pub struct StopScanReport {
+    pub tokens: usize,
+    pub stop_sequences: usize,
+    /// Stop sequences found in the generation, the scan starts again after each of them
+    pub matches: usize,
+    /// Rescanning the held back text for each token
+    pub naive: Duration,
+    /// Automaton of the stop sequences of the request
+    pub incremental: Duration,
+}
+
+/// Scan a generation of `tokens` random tokens for 4 stop sequences with the naive scan and with
+/// the automaton of the router
+/// The tokens are often prefixes of the stop sequences, whose text is held back
+pub fn stop_scan(tokens: usize) -> StopScanReport {
+    let separator = "=".repeat(64);
+    let stop_sequences: Vec<StopConfig> = ["\n\nUser:", "</s>", "###", separator.as_str()]
+        .iter()
+        .map(|sequence| StopConfig {
+            sequence: sequence.to_string(),
+            keep_text: false,
+        })
+        .collect();
+    let pieces = [
+        " the", " quick", " fox", "\n", "\n\n", "User", ":", "#", "##", "</", "s", ">", "=",
+        "====", "========",
+    ];
+    let mut rng = StdRng::seed_from_u64(0);
+    let generation: Vec<&str> = (0..tokens)
+        .map(|_| *pieces.choose(&mut rng).unwrap())
+        .collect();
+
+    let start = std::time::Instant::now();
+    let mut naive_released = 0;
+    let mut matches = 0;
+    for _ in 0..STOP_SCAN_RUNS {
+        let mut buffer = NaiveStopBuffer::new(&stop_sequences);
+        matches = 0;
+        for token in &generation {
+            naive_released += buffer.push(token).len();
+            if buffer.matched().is_some() {
+                matches += 1;
+                buffer = NaiveStopBuffer::new(&stop_sequences);
+            }
+        }
+    }
+    let naive = start.elapsed() / STOP_SCAN_RUNS;
+
+    // The automaton is built once per request, as in the validation
+    let start = std::time::Instant::now();
+    let mut released = 0;
+    for _ in 0..STOP_SCAN_RUNS {
+        let matcher = || Arc::new(StopMatcher::new(&stop_sequences));
+        let mut buffer = StopBuffer::new(matcher());
+        for token in &generation {
+            released += buffer.push(token).len();
+            if buffer.matched().is_some() {
+                buffer = StopBuffer::new(matcher());
+            }
+        }
+    }
+    let incremental = start.elapsed() / STOP_SCAN_RUNS;
+    assert_eq!(
+        released, naive_released,
+        "the scans released different texts"
+    );
+
+    StopScanReport {
+        tokens,
+        stop_sequences: stop_sequences.len(),
+        matches,
+        naive,
+        incremental,
+    }
+}
+
+impl fmt::Display for StopScanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "| {:<24} | {:>16} |",
+            "Tokens",
+            format!("{} ({} stops)", self.tokens, self.stop_sequences)
+        )?;
+        writeln!(f, "| {:<24} | {:>16} |", "Matches", self.matches)?;
+        writeln!(
+            f,
+            "| {:<24} | {:>16} |",
+            "Naive scan",
+            format!("{:.2?}", self.naive)
+        )?;
+        write!(
+            f,
+            "| {:<24} | {:>16} |",
+            "Incremental scan",
+            format!("{:.2?}", self.incremental)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles() {
+        let values = (1..=100).map(Duration::from_millis).collect();
+        let percentiles = Percentiles::new(values);
+        assert_eq!(percentiles.p50, Duration::from_millis(50));
+        assert_eq!(percentiles.p90, Duration::from_millis(90));
+        assert_eq!(percentiles.p99, Duration::from_millis(99));
+
+        assert_eq!(Percentiles::new(Vec::new()), Percentiles::default());
+    }
+
+    #[test]
+    fn test_summary_mean() {
+        let metrics = "tgi_batch_efficiency{backend=\"stable\",quantile=\"0.5\"} 0.5\n\
+                       tgi_batch_efficiency_sum{backend=\"stable\"} 3\n\
+                       tgi_batch_efficiency_count{backend=\"stable\"} 4\n";
+        assert_eq!(summary_mean(metrics, "tgi_batch_efficiency"), Some(0.75));
+        assert_eq!(summary_mean("", "tgi_batch_efficiency"), None);
+
+        // Summed over the labels
+        let metrics = "tgi_batch_decode_gap_duration_sum{batch_size=\"1\"} 0.5\n\
+                       tgi_batch_decode_gap_duration_count{batch_size=\"1\"} 2\n\
+                       tgi_batch_decode_gap_duration_sum{batch_size=\"2\"} 0.25\n\
+                       tgi_batch_decode_gap_duration_count{batch_size=\"2\"} 1\n";
+        assert_eq!(
+            summary_mean(metrics, "tgi_batch_decode_gap_duration"),
+            Some(0.25)
+        );
+    }
+
+    #[test]
+    fn test_stop_scan() {
+        let report = stop_scan(256);
+        assert_eq!(report.tokens, 256);
+        assert_eq!(report.stop_sequences, 4);
+        assert!(report.matches > 0);
+    }
+}
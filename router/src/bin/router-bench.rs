@@ -0,0 +1,158 @@
+/// Benchmark of the router inference pipeline
+use clap::Parser;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use text_generation_client::{MockConfig, ShardedClient};
+use text_generation_router::bench::{self, BenchConfig, Report};
+use text_generation_router::server::BatchingPolicy;
+use tokenizers::Tokenizer;
+use tracing_subscriber::EnvFilter;
+
+/// Benchmark Configuration
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(default_value = "10", long, env)]
+    requests_per_second: f64,
+    #[clap(default_value = "30", long, env)]
+    duration_secs: u64,
+    #[clap(default_value = "10", long, env)]
+    min_prompt_length: usize,
+    #[clap(default_value = "200", long, env)]
+    max_prompt_length: usize,
+    #[clap(default_value = "10", long, env)]
+    min_new_tokens: u32,
+    #[clap(default_value = "100", long, env)]
+    max_new_tokens: u32,
+    #[clap(default_value = "0.5", long, env)]
+    stream_ratio: f64,
+    #[clap(default_value = "128", long, env)]
+    max_concurrent_requests: usize,
+    #[clap(default_value = "1000", long, env)]
+    max_input_length: usize,
+    #[clap(default_value = "1512", long, env)]
+    max_total_tokens: usize,
+    #[clap(default_value = "32", long, env)]
+    max_batch_size: usize,
+    #[clap(default_value = "20", long, env)]
+    max_waiting_tokens: usize,
+    #[clap(long, env)]
+    prefill_chunk_tokens: Option<u32>,
+    #[clap(default_value = "throughput", long, env)]
+    batching_policy: BatchingPolicy,
+    #[clap(default_value = "2", long, env)]
+    validation_workers: usize,
+    /// Run the batching task on a dedicated runtime with this many worker threads, to compare its
+    /// decode gaps with the default shared runtime (0)
+    #[clap(default_value = "0", long, env)]
+    batching_threads: usize,
+    #[clap(default_value = "bigscience/bloom", long, env)]
+    tokenizer_name: String,
+    /// Benchmark a real backend instead of the mock backend
+    #[clap(long, env)]
+    master_shard_uds_path: Option<String>,
+    #[clap(default_value = "20", long, env)]
+    mock_token_delay_ms: u64,
+    /// Prefill time of each byte of the inputs on the mock backend, e.g. to compare the
+    /// inter-token latency percentiles with and without `prefill_chunk_tokens`
+    #[clap(default_value = "0", long, env)]
+    mock_prefill_byte_delay_us: u64,
+    /// Append the results to a CSV file
+    #[clap(long, env)]
+    csv: Option<PathBuf>,
+    /// Only compare the stop sequence scans of a generation of this many tokens
+    #[clap(long, env)]
+    stop_scan_tokens: Option<usize>,
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let args = Args::parse();
+    if let Some(tokens) = args.stop_scan_tokens {
+        println!("{}", bench::stop_scan(tokens));
+        return Ok(());
+    }
+    if args.requests_per_second <= 0.0 {
+        panic!("requests_per_second must be > 0");
+    }
+    if !(0.0..=1.0).contains(&args.stream_ratio) {
+        panic!("stream_ratio must be in [0, 1]");
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_env("LOG_LEVEL").unwrap_or_else(|_| "warn".into()))
+        .init();
+
+    // Tokenizer instance
+    let local_path = Path::new(&args.tokenizer_name);
+    let tokenizer =
+        if local_path.exists() && local_path.is_dir() && local_path.join("tokenizer.json").exists()
+        {
+            Tokenizer::from_file(local_path.join("tokenizer.json")).unwrap()
+        } else {
+            // We need to download it outside of the Tokio runtime
+            Tokenizer::from_pretrained(args.tokenizer_name.clone(), None).unwrap()
+        };
+
+    let config = BenchConfig {
+        requests_per_second: args.requests_per_second,
+        duration: Duration::from_secs(args.duration_secs),
+        prompt_length: args.min_prompt_length..=args.max_prompt_length,
+        max_new_tokens: args.min_new_tokens..=args.max_new_tokens,
+        stream_ratio: args.stream_ratio,
+        max_concurrent_requests: args.max_concurrent_requests,
+        max_input_length: args.max_input_length,
+        max_total_tokens: args.max_total_tokens,
+        max_batch_size: args.max_batch_size,
+        max_waiting_tokens: args.max_waiting_tokens,
+        prefill_chunk_tokens: args.prefill_chunk_tokens,
+        batching_policy: args.batching_policy,
+        validation_workers: args.validation_workers,
+        batching_threads: args.batching_threads,
+    };
+
+    let report = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let client = match args.master_shard_uds_path {
+                Some(uds_path) => {
+                    let mut client = ShardedClient::connect_uds(uds_path)
+                        .await
+                        .expect("Could not connect to server");
+                    client
+                        .clear_cache(None)
+                        .await
+                        .expect("Unable to clear cache");
+                    client
+                }
+                None => ShardedClient::mock(MockConfig {
+                    token_delay: Duration::from_millis(args.mock_token_delay_ms),
+                    prefill_byte_delay: Duration::from_micros(args.mock_prefill_byte_delay_us),
+                    ..MockConfig::default()
+                }),
+            };
+            let prometheus = PrometheusBuilder::new()
+                .install_recorder()
+                .expect("failed to install metrics recorder");
+
+            bench::run(config, tokenizer, client, Some(prometheus)).await
+        });
+
+    println!("{report}");
+
+    if let Some(path) = args.csv {
+        let exists = path.exists();
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        if !exists {
+            writeln!(file, "{}", Report::csv_header())?;
+        }
+        writeln!(file, "{}", report.csv_row())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,238 @@
+/// Circuit breaker failing requests fast while a backend cannot be reached
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::time::Instant;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive unreachable batches opening the circuit, 0 to disable the breaker
+    pub threshold: u32,
+    /// Delay between two probe requests while the circuit is open
+    pub probe_interval: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CircuitState {
+    /// Requests are sent to the backend
+    Closed,
+    /// Requests are rejected
+    Open,
+    /// A probe request was let through to check if the backend recovered
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub(crate) fn as_f64(&self) -> f64 {
+        match self {
+            CircuitState::Closed => 0.0,
+            CircuitState::HalfOpen => 1.0,
+            CircuitState::Open => 2.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct CircuitBreakerStatus {
+    #[schema(example = "stable")]
+    pub backend: &'static str,
+    pub state: CircuitState,
+    #[schema(example = 0)]
+    pub consecutive_failures: u32,
+}
+
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    /// Set while the circuit is open: instant from which the next probe request is let through
+    next_probe: Option<Instant>,
+    /// A probe request was let through and its batch did not finish yet
+    probing: bool,
+    /// Instant from which the next probe request is let through to an unhealthy backend while the
+    /// circuit is closed
+    next_unhealthy_probe: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Check if a request can be sent to the backend
+    /// While the circuit is open, one probe request is let through every `probe_interval` and the
+    /// others are rejected with the delay before the next probe
+    pub(crate) fn admit(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock();
+        let next_probe = match state.next_probe {
+            None => return Ok(()),
+            Some(next_probe) => next_probe,
+        };
+        let now = Instant::now();
+        if now >= next_probe {
+            state.next_probe = Some(now + self.config.probe_interval);
+            state.probing = true;
+            return Ok(());
+        }
+        Err(next_probe - now)
+    }
+
+    /// Check if a probe request can be sent to a backend that is not sent requests because it was
+    /// reported unhealthy
+    /// One probe is let through every `probe_interval`: while the circuit is open it is the probe
+    /// of `admit`, the circuit being half-open, and it must not be admitted again
+    pub(crate) fn admit_probe(&self) -> bool {
+        let mut state = self.state.lock();
+        let now = Instant::now();
+        match state.next_probe {
+            Some(next_probe) if now < next_probe => false,
+            Some(_) => {
+                state.next_probe = Some(now + self.config.probe_interval);
+                state.probing = true;
+                true
+            }
+            // The backend is unhealthy without opening the circuit, e.g. if the breaker is
+            // disabled or below its threshold
+            None => {
+                if state
+                    .next_unhealthy_probe
+                    .map_or(false, |next_probe| now < next_probe)
+                {
+                    return false;
+                }
+                state.next_unhealthy_probe = Some(now + self.config.probe_interval);
+                true
+            }
+        }
+    }
+
+    /// A batch reached the backend
+    /// Returns true if the circuit closed
+    pub(crate) fn record_success(&self) -> bool {
+        let mut state = self.state.lock();
+        state.consecutive_failures = 0;
+        state.probing = false;
+        state.next_probe.take().is_some()
+    }
+
+    /// A batch could not reach the backend
+    /// Returns true if the circuit opened
+    pub(crate) fn record_failure(&self) -> bool {
+        if self.config.threshold == 0 {
+            return false;
+        }
+        let mut state = self.state.lock();
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        let closed = state.next_probe.is_none();
+        if (closed && state.consecutive_failures >= self.config.threshold) || state.probing {
+            state.next_probe = Some(Instant::now() + self.config.probe_interval);
+            state.probing = false;
+        }
+        closed && state.next_probe.is_some()
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        let state = self.state.lock();
+        match (state.next_probe, state.probing) {
+            (None, _) => CircuitState::Closed,
+            (Some(_), true) => CircuitState::HalfOpen,
+            (Some(_), false) => CircuitState::Open,
+        }
+    }
+
+    pub(crate) fn consecutive_failures(&self) -> u32 {
+        self.state.lock().consecutive_failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(threshold: u32, probe_interval: Duration) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            threshold,
+            probe_interval,
+        })
+    }
+
+    #[test]
+    fn test_open_after_threshold() {
+        let breaker = breaker(2, Duration::from_secs(60));
+        assert!(!breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.admit().is_ok());
+
+        assert!(breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(breaker.consecutive_failures(), 2);
+        assert!(breaker.admit().is_err());
+
+        assert!(breaker.record_success());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert_eq!(breaker.consecutive_failures(), 0);
+        assert!(breaker.admit().is_ok());
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let breaker = breaker(2, Duration::from_secs(60));
+        breaker.record_failure();
+        assert!(!breaker.record_success());
+        assert!(!breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_probe() {
+        let breaker = breaker(1, Duration::ZERO);
+        assert!(breaker.record_failure());
+
+        // One probe is let through and fails
+        assert!(breaker.admit().is_ok());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.record_failure());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // The next probe succeeds
+        assert!(breaker.admit().is_ok());
+        assert!(breaker.record_success());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_admit_probe() {
+        // Paced while the circuit is closed
+        let closed = breaker(0, Duration::from_secs(60));
+        assert!(closed.admit_probe());
+        assert!(!closed.admit_probe());
+        assert_eq!(closed.state(), CircuitState::Closed);
+
+        // The probe of an open circuit makes it half-open
+        let open = breaker(1, Duration::ZERO);
+        assert!(open.record_failure());
+        assert!(open.admit_probe());
+        assert_eq!(open.state(), CircuitState::HalfOpen);
+        assert!(open.record_success());
+        assert_eq!(open.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let breaker = breaker(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            assert!(!breaker.record_failure());
+        }
+        assert!(breaker.admit().is_ok());
+    }
+}
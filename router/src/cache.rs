@@ -0,0 +1,291 @@
+/// Response cache for deterministic requests
+use crate::infer::Backend;
+use crate::{GenerateRequest, GenerateResponse, RequestContext};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Parameters that do not change the generated response
+const IGNORED_PARAMETERS: [&str; 9] = [
+    "no_cache",
+    "deadline_ms",
+    "response_timeout_ms",
+    "heartbeat",
+    "force_queue",
+    "auto_requeue",
+    "stream_rate_limit",
+    "progress_interval_tokens",
+    "progress_only",
+];
+
+/// LRU cache of `GenerateResponse` bounded in number of entries and in bytes
+#[derive(Clone)]
+pub(crate) struct ResponseCache {
+    /// None if the cache is disabled
+    state: Option<Arc<Mutex<CacheState>>>,
+}
+
+struct CacheState {
+    /// Maximum number of entries
+    max_entries: usize,
+    /// Maximum total size of the entries
+    max_bytes: usize,
+    /// Current total size of the entries
+    bytes: usize,
+    /// Logical clock used to order entries by last access
+    tick: u64,
+    entries: HashMap<String, CacheEntry>,
+    /// Last access tick -> key
+    lru: BTreeMap<u64, String>,
+}
+
+struct CacheEntry {
+    response: GenerateResponse,
+    size: usize,
+    tick: u64,
+}
+
+impl ResponseCache {
+    /// Create a new cache. The cache is disabled if any of the limits is 0
+    pub(crate) fn new(max_entries: usize, max_bytes: usize) -> Self {
+        let state = (max_entries > 0 && max_bytes > 0).then(|| {
+            Arc::new(Mutex::new(CacheState {
+                max_entries,
+                max_bytes,
+                bytes: 0,
+                tick: 0,
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+            }))
+        });
+        Self { state }
+    }
+
+    /// Cache key of a request, or None if the request must not use the cache
+    pub(crate) fn key(
+        &self,
+        request: &GenerateRequest,
+        context: &RequestContext,
+    ) -> Option<String> {
+        self.state.as_ref()?;
+        request_key(request, context)
+    }
+
+    /// Get a cached response
+    pub(crate) fn get(&self, key: &str) -> Option<GenerateResponse> {
+        let mut state = self.state.as_ref()?.lock();
+        state.tick += 1;
+        let tick = state.tick;
+
+        let entry = state.entries.get_mut(key)?;
+        let previous_tick = entry.tick;
+        entry.tick = tick;
+        let response = entry.response.clone();
+
+        let key = state.lru.remove(&previous_tick)?;
+        state.lru.insert(tick, key);
+        Some(response)
+    }
+
+    /// Cache a response, evicting the least recently used entries if needed
+    pub(crate) fn insert(&self, key: String, response: &GenerateResponse) {
+        let mut state = match &self.state {
+            None => return,
+            Some(state) => state.lock(),
+        };
+
+        let size = key.len() + serde_json::to_vec(response).map_or(0, |bytes| bytes.len());
+        if size > state.max_bytes || state.entries.contains_key(&key) {
+            return;
+        }
+
+        // Evict
+        while state.entries.len() >= state.max_entries || state.bytes + size > state.max_bytes {
+            let (_, evicted_key) = match state.lru.pop_first() {
+                None => break,
+                Some(evicted) => evicted,
+            };
+            if let Some(evicted) = state.entries.remove(&evicted_key) {
+                state.bytes -= evicted.size;
+            }
+            metrics::increment_counter!("tgi_cache_eviction");
+        }
+
+        state.tick += 1;
+        let tick = state.tick;
+        state.bytes += size;
+        state.lru.insert(tick, key.clone());
+        state.entries.insert(
+            key,
+            CacheEntry {
+                response: response.clone(),
+                size,
+                tick,
+            },
+        );
+        metrics::gauge!("tgi_cache_size", state.entries.len() as f64);
+        metrics::gauge!("tgi_cache_bytes", state.bytes as f64);
+    }
+}
+
+/// Key of the response of a request, or None if the response is not deterministic
+pub(crate) fn request_key(request: &GenerateRequest, context: &RequestContext) -> Option<String> {
+    let parameters = &request.parameters;
+
+    // Only deterministic requests can be cached
+    // Retries on empty generations use a new random seed
+    let random_seed = parameters.seed.is_none() || parameters.retry_on_empty > 0;
+    if parameters.no_cache
+        || parameters.do_sample.unwrap_or(false)
+        || (parameters.sampling() && random_seed)
+    {
+        return None;
+    }
+    // The probes of an unhealthy backend must reach it
+    if context.admitted {
+        return None;
+    }
+
+    let limits = context.limits;
+    let mut parameters = serde_json::to_value(parameters).ok()?;
+    if let Some(parameters) = parameters.as_object_mut() {
+        for name in IGNORED_PARAMETERS {
+            parameters.remove(name);
+        }
+        // A response cached for an API key with higher limits must not be returned to others
+        if let Some(limits) = limits {
+            parameters.insert("limits".to_string(), serde_json::to_value(limits).ok()?);
+        }
+    }
+    // serde_json maps are sorted so the key is stable
+    // The backends may serve different versions of the model
+    let model = request.model.as_deref().unwrap_or_default();
+    let backend = context.backend.unwrap_or(Backend::Stable).as_str();
+    Some(format!("{model}:{backend}:{parameters}{}", request.inputs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_parameters;
+    use crate::limits::Limits;
+
+    fn request(inputs: &str) -> GenerateRequest {
+        GenerateRequest {
+            inputs: inputs.to_string(),
+            parameters: default_parameters(),
+            template: None,
+            template_vars: None,
+            preset: None,
+            model: None,
+        }
+    }
+
+    fn response(text: &str) -> GenerateResponse {
+        GenerateResponse {
+            generated_text: text.to_string(),
+            details: None,
+            continued_from: None,
+            warning: None,
+        }
+    }
+
+    #[test]
+    fn test_key() {
+        let cache = ResponseCache::new(10, 1000);
+        let context = RequestContext::default();
+        assert!(cache.key(&request("test"), &context).is_some());
+        assert_ne!(
+            cache.key(&request("test"), &context),
+            cache.key(&request("other"), &context)
+        );
+
+        let mut deadline = request("test");
+        deadline.parameters.deadline_ms = Some(10);
+        assert_eq!(
+            cache.key(&deadline, &context),
+            cache.key(&request("test"), &context)
+        );
+
+        let limits = RequestContext {
+            limits: Some(Limits {
+                max_input_length: 6000,
+                max_new_tokens: None,
+            }),
+            ..RequestContext::default()
+        };
+        assert_ne!(
+            cache.key(&request("test"), &limits),
+            cache.key(&request("test"), &context)
+        );
+
+        let canary = RequestContext {
+            backend: Some(Backend::Canary),
+            ..RequestContext::default()
+        };
+        assert_ne!(
+            cache.key(&request("test"), &canary),
+            cache.key(&request("test"), &context)
+        );
+        let probe = RequestContext {
+            admitted: true,
+            ..canary
+        };
+        assert!(cache.key(&request("test"), &probe).is_none());
+
+        let mut draft = request("test");
+        draft.model = Some("draft".to_string());
+        assert_ne!(
+            cache.key(&draft, &context),
+            cache.key(&request("test"), &context)
+        );
+
+        let mut no_cache = request("test");
+        no_cache.parameters.no_cache = true;
+        assert!(cache.key(&no_cache, &context).is_none());
+
+        let mut sampling = request("test");
+        sampling.parameters.do_sample = Some(true);
+        assert!(cache.key(&sampling, &context).is_none());
+
+        let mut seedless = request("test");
+        seedless.parameters.temperature = Some(0.5);
+        assert!(cache.key(&seedless, &context).is_none());
+        seedless.parameters.seed = Some(42);
+        assert!(cache.key(&seedless, &context).is_some());
+        seedless.parameters.retry_on_empty = 1;
+        assert!(cache.key(&seedless, &context).is_none());
+
+        assert!(ResponseCache::new(0, 1000)
+            .key(&request("test"), &context)
+            .is_none());
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = ResponseCache::new(2, 1000);
+        cache.insert("a".to_string(), &response("a"));
+        cache.insert("b".to_string(), &response("b"));
+        // Access "a" so "b" is the least recently used entry
+        assert!(cache.get("a").is_some());
+        cache.insert("c".to_string(), &response("c"));
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("c").unwrap().generated_text, "c");
+    }
+
+    #[test]
+    fn test_bytes_limit() {
+        let size = "a".len() + serde_json::to_vec(&response("a")).unwrap().len();
+        let cache = ResponseCache::new(10, size);
+        cache.insert("a".to_string(), &response("a"));
+        cache.insert("b".to_string(), &response("b"));
+
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+
+        cache.insert("c".to_string(), &response(&"c".repeat(size)));
+        assert!(cache.get("c").is_none());
+    }
+}
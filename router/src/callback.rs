@@ -0,0 +1,386 @@
+/// Delivery of the job results to callback URLs
+use crate::jobs::Jobs;
+use crate::validation::ValidationError;
+use crate::JobStatus;
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use sha2::Sha256;
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+
+/// Maximum number of deliveries waiting for a permit, the results of the next jobs are dropped
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Entry of `allowed_hosts` allowing any host that only resolves to public addresses
+const ANY_PUBLIC_HOST: &str = "*";
+
+/// Callback delivery configuration
+#[derive(Debug, Clone)]
+pub struct CallbackConfig {
+    /// Key of the HMAC-SHA256 signature of the payloads, sent in the `x-signature-256` header
+    pub secret: Option<String>,
+    /// Hosts the callback URLs can point to, callbacks are disabled if empty
+    ///
+    /// `*` allows any host that only resolves to public addresses
+    pub allowed_hosts: Vec<String>,
+    /// Number of delivery attempts before a result is marked as not delivered
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after each attempt
+    pub initial_backoff: Duration,
+    /// Timeout of each attempt
+    pub timeout: Duration,
+    /// Maximum number of deliveries in flight
+    pub max_concurrent: usize,
+}
+
+/// Sends the results of the jobs to their callback URLs
+///
+/// Deliveries run on their own task so that slow callback endpoints cannot slow down generation
+#[derive(Clone)]
+pub(crate) struct Callbacks {
+    allowed_hosts: Arc<HashSet<String>>,
+    sender: mpsc::Sender<Delivery>,
+    jobs: Jobs,
+}
+
+#[derive(Debug)]
+struct Delivery {
+    job_id: u64,
+    url: Url,
+    body: Vec<u8>,
+}
+
+impl Callbacks {
+    pub(crate) fn new(config: CallbackConfig, jobs: Jobs) -> Self {
+        let allowed_hosts: Arc<HashSet<String>> =
+            Arc::new(config.allowed_hosts.iter().cloned().collect());
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(delivery_task(
+            config,
+            allowed_hosts.clone(),
+            jobs.clone(),
+            receiver,
+        ));
+        Self {
+            allowed_hosts,
+            sender,
+            jobs,
+        }
+    }
+
+    /// Parse a callback URL, rejecting the URLs of the hosts that are not allowed
+    pub(crate) fn check(&self, url: &str) -> Result<Url, ValidationError> {
+        check_url(&self.allowed_hosts, url)
+    }
+
+    /// Queue the delivery of the final status of a job
+    pub(crate) fn send(&self, url: Url, status: &JobStatus) {
+        let body = match serde_json::to_vec(status) {
+            Ok(body) => body,
+            Err(err) => {
+                tracing::error!("Could not serialize the result of job {}: {err}", status.id);
+                return;
+            }
+        };
+        let delivery = Delivery {
+            job_id: status.id,
+            url,
+            body,
+        };
+        // The delivery task only stops with the router, so the send only fails if the queue is full
+        if let Err(err) = self.sender.try_send(delivery) {
+            let job_id = err.into_inner().job_id;
+            tracing::warn!("Callback queue is full, the result of job {job_id} is not delivered");
+            metrics::increment_counter!("tgi_callback_delivery", "result" => "dropped");
+            self.jobs.set_delivered(job_id, false);
+        }
+    }
+}
+
+fn check_url(allowed_hosts: &HashSet<String>, url: &str) -> Result<Url, ValidationError> {
+    let url = Url::parse(url)
+        .map_err(|err| ValidationError::CallbackUrl(format!("is not a valid URL: {err}")))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ValidationError::CallbackUrl(
+            "must use http or https".to_string(),
+        ));
+    }
+    if allowed_hosts.is_empty() {
+        return Err(ValidationError::CallbackUrl(
+            "is not allowed: callbacks are disabled".to_string(),
+        ));
+    }
+    let host = url.host_str().unwrap_or_default();
+    // The listed hosts are trusted, the other ones must be public
+    let allowed = allowed_hosts.contains(host)
+        || (allowed_hosts.contains(ANY_PUBLIC_HOST) && literal_ip(&url).map_or(true, is_public));
+    if !allowed {
+        return Err(ValidationError::CallbackUrl(format!(
+            "host `{host}` is not allowed"
+        )));
+    }
+    Ok(url)
+}
+
+/// Address of a URL whose host is an IP literal
+fn literal_ip(url: &Url) -> Option<IpAddr> {
+    // The IPv6 literals are bracketed
+    let host = url.host_str()?;
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+/// The address is reachable from the internet: not loopback, link-local, private, shared or
+/// reserved
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // 0.0.0.0/8 and the shared address space 100.64.0.0/10
+                || first == 0
+                || (first == 100 && (second & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local fc00::/7 and link-local fe80::/10
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolve the host of a callback URL that is not listed and check that all its addresses are
+/// public, so that a public name cannot point to an internal service
+/// Returns the address the delivery is pinned to, None if the URL host is listed or an IP
+async fn resolve(allowed_hosts: &HashSet<String>, url: &Url) -> Result<Option<SocketAddr>, String> {
+    let host = url.host_str().unwrap_or_default();
+    if allowed_hosts.contains(host) || literal_ip(url).is_some() {
+        return Ok(None);
+    }
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|err| format!("could not resolve `{host}`: {err}"))?
+        .collect();
+    if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(format!(
+            "`{host}` resolves to the non public address {}",
+            addr.ip()
+        ));
+    }
+    addrs
+        .first()
+        .map(|addr| Some(*addr))
+        .ok_or_else(|| format!("`{host}` has no address"))
+}
+
+/// Client of the deliveries
+fn client_builder(timeout: Duration) -> reqwest::ClientBuilder {
+    // Redirects are not followed: they could point to a host that is not allowed
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(timeout)
+}
+
+/// Deliver the queued results, at most `max_concurrent` at a time
+async fn delivery_task(
+    config: CallbackConfig,
+    allowed_hosts: Arc<HashSet<String>>,
+    jobs: Jobs,
+    mut receiver: mpsc::Receiver<Delivery>,
+) {
+    let client = client_builder(config.timeout)
+        .build()
+        .expect("Could not create the callback client");
+    let config = Arc::new(config);
+    let permits = Arc::new(Semaphore::new(config.max_concurrent));
+
+    while let Some(delivery) = receiver.recv().await {
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore is never closed");
+        let client = client.clone();
+        let config = config.clone();
+        let allowed_hosts = allowed_hosts.clone();
+        let jobs = jobs.clone();
+        tokio::spawn(async move {
+            let delivered = match resolve(&allowed_hosts, &delivery.url).await {
+                Ok(None) => deliver(&client, &config, &delivery).await,
+                // The address is pinned so that the name cannot be resolved again to another one
+                Ok(Some(addr)) => match client_builder(config.timeout)
+                    .resolve(delivery.url.host_str().unwrap_or_default(), addr)
+                    .build()
+                {
+                    Ok(client) => deliver(&client, &config, &delivery).await,
+                    Err(err) => {
+                        tracing::error!("Could not create the callback client: {err}");
+                        false
+                    }
+                },
+                Err(err) => {
+                    tracing::warn!("Callback of job {} is not allowed: {err}", delivery.job_id);
+                    metrics::increment_counter!("tgi_callback_delivery", "result" => "rejected");
+                    false
+                }
+            };
+            jobs.set_delivered(delivery.job_id, delivered);
+            drop(permit);
+        });
+    }
+}
+
+/// POST a result to its callback URL, retrying with an exponential backoff
+/// Returns false if all the attempts failed
+async fn deliver(client: &reqwest::Client, config: &CallbackConfig, delivery: &Delivery) -> bool {
+    let signature = config
+        .secret
+        .as_ref()
+        .map(|secret| format!("sha256={}", sign(secret.as_bytes(), &delivery.body)));
+
+    for attempt in 0..config.max_attempts {
+        if attempt > 0 {
+            tokio::time::sleep(backoff(config.initial_backoff, attempt)).await;
+        }
+        let mut request = client
+            .post(delivery.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("x-job-id", delivery.job_id.to_string())
+            .body(delivery.body.clone());
+        if let Some(signature) = &signature {
+            request = request.header("x-signature-256", signature);
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                metrics::increment_counter!("tgi_callback_delivery", "result" => "delivered");
+                return true;
+            }
+            Ok(response) => tracing::warn!(
+                "Callback of job {} returned {}",
+                delivery.job_id,
+                response.status()
+            ),
+            Err(err) => tracing::warn!(
+                "Could not send the callback of job {}: {err}",
+                delivery.job_id
+            ),
+        }
+        metrics::increment_counter!("tgi_callback_attempt_failure");
+    }
+
+    tracing::error!(
+        "Could not deliver the result of job {} after {} attempts",
+        delivery.job_id,
+        config.max_attempts
+    );
+    metrics::increment_counter!("tgi_callback_delivery", "result" => "failed");
+    false
+}
+
+/// Delay before the given retry
+fn backoff(initial_backoff: Duration, attempt: u32) -> Duration {
+    initial_backoff.saturating_mul(1 << (attempt - 1).min(16))
+}
+
+/// Hex encoded HMAC-SHA256 of a payload
+fn sign(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_url() {
+        let disabled = HashSet::new();
+        assert_eq!(
+            check_url(&disabled, "https://example.com/callback")
+                .unwrap_err()
+                .to_string(),
+            "`callback_url` is not allowed: callbacks are disabled"
+        );
+
+        let any = HashSet::from(["*".to_string()]);
+        assert!(check_url(&any, "https://example.com/callback").is_ok());
+        assert!(check_url(&any, "http://93.184.216.34/callback").is_ok());
+        assert!(check_url(&any, "file:///etc/passwd").is_err());
+        assert!(check_url(&any, "not a url").is_err());
+        for url in [
+            "http://127.0.0.1:8080/",
+            "http://169.254.169.254/latest",
+            "http://10.0.0.1/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:192.168.0.1]/",
+        ] {
+            assert!(check_url(&any, url).is_err(), "{url}");
+        }
+
+        let allowed = HashSet::from(["hooks.example.com".to_string()]);
+        assert!(check_url(&allowed, "https://hooks.example.com/job").is_ok());
+        assert_eq!(
+            check_url(&allowed, "http://169.254.169.254/latest")
+                .unwrap_err()
+                .to_string(),
+            "`callback_url` host `169.254.169.254` is not allowed"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let any = HashSet::from(["*".to_string(), "localhost".to_string()]);
+        let url = Url::parse("http://localhost:8080/").unwrap();
+        assert_eq!(resolve(&any, &url).await, Ok(None));
+        let any = HashSet::from(["*".to_string()]);
+        assert!(resolve(&any, &url)
+            .await
+            .unwrap_err()
+            .contains("resolves to the non public address"));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let initial = Duration::from_millis(100);
+        assert_eq!(backoff(initial, 1), initial);
+        assert_eq!(backoff(initial, 3), Duration::from_millis(400));
+        assert_eq!(backoff(initial, u32::MAX), initial * (1 << 16));
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -0,0 +1,524 @@
+//! Client of the router for the Rust services embedding it, enabled by the `client` feature
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! use text_generation_router::client::{GenerateParameters, GenerateRequest, RouterClient};
+//!
+//! let client = RouterClient::new("http://localhost:3000");
+//! let parameters = GenerateParameters::builder()
+//!     .max_new_tokens(64)
+//!     .temperature(0.7)
+//!     .stop("\n")
+//!     .build()?;
+//! let response = client
+//!     .generate(&GenerateRequest::new("My name is Olivier and I", parameters))
+//!     .await?;
+//! println!("{}", response.generated_text);
+//! # Ok(())
+//! # }
+//! ```
+use crate::default_parameters;
+use crate::validation::check_parameters;
+pub use crate::validation::ValidationError;
+pub use crate::{
+    BestOfSequence, Details, ErrorResponse, FinishReason, GenerateParameters, GenerateRequest,
+    GenerateResponse, MatchedStop, PrefillToken, StopConfig, StreamDetails, StreamResponse, Token,
+    ValidParameters,
+};
+use futures::{Stream, StreamExt};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+impl GenerateParameters {
+    /// Builder starting from the default parameters of the router
+    pub fn builder() -> GenerateParametersBuilder {
+        GenerateParametersBuilder {
+            parameters: default_parameters(),
+        }
+    }
+}
+
+impl GenerateRequest {
+    pub fn new(inputs: impl Into<String>, parameters: GenerateParameters) -> Self {
+        Self {
+            inputs: inputs.into(),
+            parameters,
+            template: None,
+            template_vars: None,
+            preset: None,
+            model: None,
+        }
+    }
+}
+
+/// Builder of [`GenerateParameters`]
+/// `build` fails on the parameters the router would reject whatever its configuration, the limits
+/// of the router (`max_best_of`, `max_stop_sequences`, `max_input_length` and `max_total_tokens`)
+/// being checked by the router only
+#[derive(Clone, Debug)]
+pub struct GenerateParametersBuilder {
+    parameters: GenerateParameters,
+}
+
+impl GenerateParametersBuilder {
+    pub fn best_of(mut self, best_of: usize) -> Self {
+        self.parameters.best_of = Some(best_of);
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.parameters.temperature = Some(temperature);
+        self
+    }
+
+    pub fn repetition_penalty(mut self, repetition_penalty: f32) -> Self {
+        self.parameters.repetition_penalty = Some(repetition_penalty);
+        self
+    }
+
+    pub fn top_k(mut self, top_k: i32) -> Self {
+        self.parameters.top_k = Some(top_k);
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.parameters.top_p = Some(top_p);
+        self
+    }
+
+    pub fn typical_p(mut self, typical_p: f32) -> Self {
+        self.parameters.typical_p = Some(typical_p);
+        self
+    }
+
+    pub fn do_sample(mut self, do_sample: bool) -> Self {
+        self.parameters.do_sample = Some(do_sample);
+        self
+    }
+
+    pub fn max_new_tokens(mut self, max_new_tokens: u32) -> Self {
+        self.parameters.max_new_tokens = max_new_tokens;
+        self
+    }
+
+    pub fn return_full_text(mut self, return_full_text: bool) -> Self {
+        self.parameters.return_full_text = Some(return_full_text);
+        self
+    }
+
+    /// Add a stop sequence removed from the generated text
+    pub fn stop(mut self, sequence: impl Into<String>) -> Self {
+        self.parameters.stop.push(sequence.into());
+        self
+    }
+
+    /// Add a stop sequence, sent with the generated text if `keep_text` is set
+    pub fn stop_config(mut self, sequence: impl Into<String>, keep_text: bool) -> Self {
+        self.parameters.stop_config.push(StopConfig {
+            sequence: sequence.into(),
+            keep_text,
+        });
+        self
+    }
+
+    pub fn truncate(mut self, truncate: usize) -> Self {
+        self.parameters.truncate = Some(truncate);
+        self
+    }
+
+    pub fn watermark(mut self, watermark: bool) -> Self {
+        self.parameters.watermark = Some(watermark);
+        self
+    }
+
+    pub fn details(mut self, details: bool) -> Self {
+        self.parameters.details = details;
+        self
+    }
+
+    pub fn decoder_input_details(mut self, decoder_input_details: bool) -> Self {
+        self.parameters.decoder_input_details = decoder_input_details;
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.parameters.seed = Some(seed);
+        self
+    }
+
+    pub fn deadline_ms(mut self, deadline_ms: u64) -> Self {
+        self.parameters.deadline_ms = Some(deadline_ms);
+        self
+    }
+
+    pub fn no_cache(mut self, no_cache: bool) -> Self {
+        self.parameters.no_cache = no_cache;
+        self
+    }
+
+    pub fn heartbeat(mut self, heartbeat: bool) -> Self {
+        self.parameters.heartbeat = heartbeat;
+        self
+    }
+
+    pub fn retry_on_empty(mut self, retry_on_empty: u8) -> Self {
+        self.parameters.retry_on_empty = retry_on_empty;
+        self
+    }
+
+    pub fn session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.parameters.session_id = Some(session_id.into());
+        self
+    }
+
+    pub fn latency_sensitive(mut self, latency_sensitive: bool) -> Self {
+        self.parameters.latency_sensitive = latency_sensitive;
+        self
+    }
+
+    pub fn stream_full_text(mut self, stream_full_text: bool) -> Self {
+        self.parameters.stream_full_text = stream_full_text;
+        self
+    }
+
+    pub fn force_queue(mut self, force_queue: bool) -> Self {
+        self.parameters.force_queue = force_queue;
+        self
+    }
+
+    pub fn strict_n(mut self, strict_n: bool) -> Self {
+        self.parameters.strict_n = strict_n;
+        self
+    }
+
+    pub fn auto_requeue(mut self, auto_requeue: bool) -> Self {
+        self.parameters.auto_requeue = Some(auto_requeue);
+        self
+    }
+
+    pub fn allow_downgrade(mut self, allow_downgrade: bool) -> Self {
+        self.parameters.allow_downgrade = allow_downgrade;
+        self
+    }
+
+    pub fn normalize_input(mut self, normalize_input: bool) -> Self {
+        self.parameters.normalize_input = Some(normalize_input);
+        self
+    }
+
+    pub fn clean_up_tokenization_spaces(mut self, clean_up_tokenization_spaces: bool) -> Self {
+        self.parameters.clean_up_tokenization_spaces = clean_up_tokenization_spaces;
+        self
+    }
+
+    pub fn raw_token_text(mut self, raw_token_text: bool) -> Self {
+        self.parameters.raw_token_text = raw_token_text;
+        self
+    }
+
+    pub fn stream_rate_limit(mut self, tokens_per_second: f32) -> Self {
+        self.parameters.stream_rate_limit = Some(tokens_per_second);
+        self
+    }
+
+    pub fn progress_interval_tokens(mut self, progress_interval_tokens: u32) -> Self {
+        self.parameters.progress_interval_tokens = Some(progress_interval_tokens);
+        self
+    }
+
+    pub fn progress_only(mut self, progress_only: bool) -> Self {
+        self.parameters.progress_only = progress_only;
+        self
+    }
+
+    pub fn allowed_tokens(mut self, allowed_tokens: Vec<u32>) -> Self {
+        self.parameters.allowed_tokens = Some(allowed_tokens);
+        self
+    }
+
+    pub fn build(self) -> Result<GenerateParameters, ValidationError> {
+        check_parameters(&self.parameters)?;
+        Ok(self.parameters)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RouterClientError {
+    #[error("could not serialize the request: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    /// Error returned by the router, match on `error_type` to handle it
+    #[error("router error ({0}): {}", .1.error)]
+    Router(StatusCode, ErrorResponse),
+    /// Error sent by the router in the stream, after the HTTP status
+    #[error("stream error: {}", .0.error)]
+    Stream(ErrorResponse),
+    #[error("invalid response ({0}): {1}")]
+    InvalidResponse(StatusCode, String),
+}
+
+/// Thin client of the `/generate` and `/generate_stream` routes
+#[derive(Clone, Debug)]
+pub struct RouterClient {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl RouterClient {
+    /// `base_url` is the address of the router, e.g. `http://localhost:3000`
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_client(reqwest::Client::new(), base_url)
+    }
+
+    /// Use a configured `reqwest` client, e.g. with timeouts
+    pub fn with_client(client: reqwest::Client, base_url: impl Into<String>) -> Self {
+        Self {
+            client,
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+        }
+    }
+
+    /// Send the requests with `Authorization: Bearer <api_key>`
+    pub fn api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub async fn generate(
+        &self,
+        request: &GenerateRequest,
+    ) -> Result<GenerateResponse, RouterClientError> {
+        let response = self.post("/generate", request).await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        parse(status, &body)
+    }
+
+    /// Stream the generated tokens, the last response having the generated text
+    /// The queue notifications of the router are skipped. The stream must be pinned to be polled,
+    /// e.g. with `futures::pin_mut!`
+    pub async fn generate_stream(
+        &self,
+        request: &GenerateRequest,
+    ) -> Result<impl Stream<Item = Result<StreamResponse, RouterClientError>>, RouterClientError>
+    {
+        let response = self.post("/generate_stream", request).await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.bytes().await?;
+            return Err(router_error(status, &body));
+        }
+
+        let mut chunks = response.bytes_stream();
+        Ok(async_stream::stream! {
+            let mut decoder = EventDecoder::default();
+            'chunks: while let Some(chunk) = chunks.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(err) => {
+                        yield Err(RouterClientError::from(err));
+                        break;
+                    }
+                };
+                for data in decoder.push(&chunk) {
+                    let response = parse_event(status, &data);
+                    let failed = response.is_err();
+                    yield response;
+                    // The router ends the stream after an error
+                    if failed {
+                        break 'chunks;
+                    }
+                }
+            }
+        })
+    }
+
+    async fn post(
+        &self,
+        route: &str,
+        request: &GenerateRequest,
+    ) -> Result<reqwest::Response, RouterClientError> {
+        let body = serde_json::to_vec(request)?;
+        let mut builder = self
+            .client
+            .post(format!("{}{route}", self.base_url))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {api_key}"));
+        }
+        Ok(builder.send().await?)
+    }
+}
+
+fn parse<T: DeserializeOwned>(status: StatusCode, body: &[u8]) -> Result<T, RouterClientError> {
+    if !status.is_success() {
+        return Err(router_error(status, body));
+    }
+    serde_json::from_slice(body)
+        .map_err(|err| RouterClientError::InvalidResponse(status, err.to_string()))
+}
+
+fn router_error(status: StatusCode, body: &[u8]) -> RouterClientError {
+    match serde_json::from_slice::<ErrorResponse>(body) {
+        Ok(error) => RouterClientError::Router(status, error),
+        Err(_) => {
+            RouterClientError::InvalidResponse(status, String::from_utf8_lossy(body).into_owned())
+        }
+    }
+}
+
+/// Data of an event: a streamed response or the error that ended the stream
+fn parse_event(status: StatusCode, data: &str) -> Result<StreamResponse, RouterClientError> {
+    serde_json::from_str(data).map_err(|err| match serde_json::from_str(data) {
+        Ok(error) => RouterClientError::Stream(error),
+        Err(_) => RouterClientError::InvalidResponse(status, err.to_string()),
+    })
+}
+
+/// Decoder of the server-sent events split across chunks
+#[derive(Debug, Default)]
+struct EventDecoder {
+    buffer: Vec<u8>,
+}
+
+impl EventDecoder {
+    /// Data of the events completed by `chunk`
+    /// The named events, i.e. the queue notifications, and the keep-alive comments are skipped
+    fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.windows(2).position(|window| window == b"\n\n") {
+            let event: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            let event = String::from_utf8_lossy(&event);
+            let mut data = Vec::new();
+            let mut named = false;
+            for line in event.lines() {
+                if let Some(value) = line.strip_prefix("data:") {
+                    data.push(value.strip_prefix(' ').unwrap_or(value));
+                } else if line.starts_with("event:") {
+                    named = true;
+                }
+            }
+            if !named && !data.is_empty() {
+                events.push(data.join("\n"));
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let parameters = GenerateParameters::builder()
+            .max_new_tokens(10)
+            .temperature(0.5)
+            .stop("\n")
+            .stop_config("###", true)
+            .build()
+            .unwrap();
+        assert_eq!(parameters.max_new_tokens, 10);
+        assert_eq!(parameters.temperature, Some(0.5));
+        assert_eq!(parameters.stop, vec!["\n".to_string()]);
+        assert!(parameters.stop_config[0].keep_text);
+
+        assert!(matches!(
+            GenerateParameters::builder().top_p(1.0).build(),
+            Err(ValidationError::TopP)
+        ));
+        assert!(matches!(
+            GenerateParameters::builder().best_of(2).build(),
+            Err(ValidationError::BestOfSampling)
+        ));
+        assert!(matches!(
+            GenerateParameters::builder()
+                .best_of(2)
+                .do_sample(true)
+                .seed(42)
+                .build(),
+            Err(ValidationError::BestOfSeed)
+        ));
+        assert!(matches!(
+            GenerateParameters::builder()
+                .stop("\n")
+                .stop_config("\n", true)
+                .build(),
+            Err(ValidationError::DuplicateStopSequence(_))
+        ));
+        assert!(matches!(
+            GenerateParameters::builder()
+                .max_new_tokens(1200)
+                .stream_rate_limit(1.0)
+                .build(),
+            Err(ValidationError::StreamRateLimit(_))
+        ));
+        assert!(GenerateParameters::builder()
+            .max_new_tokens(1200)
+            .stream_rate_limit(2.0)
+            .build()
+            .is_ok());
+        assert!(matches!(
+            GenerateParameters::builder()
+                .progress_interval_tokens(0)
+                .build(),
+            Err(ValidationError::ProgressInterval)
+        ));
+        assert!(matches!(
+            GenerateParameters::builder().progress_only(true).build(),
+            Err(ValidationError::ProgressOnly)
+        ));
+        assert!(GenerateParameters::builder()
+            .progress_interval_tokens(16)
+            .progress_only(true)
+            .build()
+            .is_ok());
+        assert!(matches!(
+            GenerateParameters::builder().allowed_tokens(vec![]).build(),
+            Err(ValidationError::AllowedTokens(4096, 0))
+        ));
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let parameters = GenerateParameters::builder().seed(42).build().unwrap();
+        let request = GenerateRequest::new("Hello", parameters);
+        let request: GenerateRequest =
+            serde_json::from_slice(&serde_json::to_vec(&request).unwrap()).unwrap();
+        assert_eq!(request.inputs, "Hello");
+        assert_eq!(request.parameters.seed, Some(42));
+    }
+
+    #[test]
+    fn test_event_decoder() {
+        let mut decoder = EventDecoder::default();
+        assert!(decoder
+            .push(b"event: status\ndata: {\"status\":\"queued\"}\n\n:\n\n")
+            .is_empty());
+
+        let token = r#"{"token":{"id":0,"text":"a","logprob":-0.1,"special":false},"generated_text":null,"details":null}"#;
+        let event = format!("data: {token}\n\n");
+        let (start, end) = event.as_bytes().split_at(10);
+        assert!(decoder.push(start).is_empty());
+        let events = decoder.push(end);
+        assert_eq!(events, vec![token.to_string()]);
+
+        let response = parse_event(StatusCode::OK, &events[0]).unwrap();
+        assert_eq!(response.token.text, "a");
+        assert!(!response.truncated);
+
+        let data = r#"{"error":"Request failed during generation","error_type":"generation"}"#;
+        assert!(matches!(
+            parse_event(StatusCode::OK, data),
+            Err(RouterClientError::Stream(error)) if error.error_type == "generation"
+        ));
+    }
+}
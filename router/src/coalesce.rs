@@ -0,0 +1,387 @@
+/// Coalescing of identical concurrent requests
+use crate::cache::request_key;
+use crate::infer::{InferError, InferStreamResponse};
+use crate::registry::RequestHandle;
+use crate::{GenerateRequest, RequestContext, RequestStatus};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+type Message = Result<InferStreamResponse, InferError>;
+
+/// Identical deterministic requests waiting for the generation of the first one
+///
+/// Only the first request of a group is queued and batched. The others join it until it finishes
+/// and receive a copy of its messages
+#[derive(Clone)]
+pub(crate) struct Coalescer {
+    /// Key -> group of the request being generated. None if coalescing is disabled
+    groups: Option<Arc<Mutex<HashMap<String, Weak<Mutex<Group>>>>>>,
+}
+
+#[derive(Debug)]
+struct Group {
+    /// Request of the queued entry
+    leader: Arc<RequestHandle>,
+    /// The leader was cancelled while other requests were still waiting for the generation
+    leader_detached: bool,
+    followers: Vec<Follower>,
+    /// Messages sent so far, replayed to the requests joining the group
+    history: Vec<InferStreamResponse>,
+    /// Set once the last message was sent
+    finished: bool,
+}
+
+#[derive(Debug)]
+struct Follower {
+    response_tx: UnboundedSender<Message>,
+    handle: Arc<RequestHandle>,
+}
+
+/// Response sender of an entry
+///
+/// Sends the messages of the entry to its client and to the clients of the requests that joined it
+#[derive(Debug)]
+pub(crate) struct ResponseSender {
+    response_tx: UnboundedSender<Message>,
+    /// None if the request cannot be coalesced
+    group: Option<Arc<Mutex<Group>>>,
+}
+
+impl Coalescer {
+    pub(crate) fn new(enabled: bool) -> Self {
+        let groups = enabled.then(|| Arc::new(Mutex::new(HashMap::new())));
+        Self { groups }
+    }
+
+    /// Coalescing key of a request sent to `backend`, or None if the request must not be coalesced
+    pub(crate) fn key(
+        &self,
+        request: &GenerateRequest,
+        context: &RequestContext,
+        backend: &str,
+    ) -> Option<String> {
+        self.groups.as_ref()?;
+        let parameters = &request.parameters;
+
+        // The entry is queued with the deadline, the heartbeats and the session of the first request
+        // Health probes must reach the backend
+        if context.probe
+            || parameters.heartbeat
+            || parameters.deadline_ms.is_some()
+            || context.session.is_some()
+            || context.continued.is_some()
+            || context.conversation.is_some()
+        {
+            return None;
+        }
+        // Same rules as the response cache: sampled requests without a seed are never coalesced
+        Some(format!("{backend}:{}", request_key(request, context)?))
+    }
+
+    /// Join the group of an identical request being generated
+    ///
+    /// Returns the stream of the messages of the group, starting with the ones already sent
+    pub(crate) fn join(
+        &self,
+        key: &str,
+        handle: &Arc<RequestHandle>,
+    ) -> Option<UnboundedReceiverStream<Message>> {
+        let group = self.groups.as_ref()?.lock().get(key)?.upgrade()?;
+        let mut group = group.lock();
+        if group.finished || group.leader_detached {
+            return None;
+        }
+
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        handle.set_input_length(group.leader.input_length());
+        for message in &group.history {
+            let message = Ok(message.clone());
+            observe(handle, &message);
+            // unwrap is valid here as the receiver is not dropped yet
+            response_tx.send(message).unwrap();
+        }
+        group.followers.push(Follower {
+            response_tx,
+            handle: handle.clone(),
+        });
+        metrics::increment_counter!("tgi_request_coalesced");
+        Some(UnboundedReceiverStream::new(response_rx))
+    }
+
+    /// Response sender of a new entry, that identical requests can join if `key` is set
+    pub(crate) fn sender(
+        &self,
+        key: Option<String>,
+        response_tx: UnboundedSender<Message>,
+        handle: &Arc<RequestHandle>,
+    ) -> ResponseSender {
+        let (groups, key) = match (&self.groups, key) {
+            (Some(groups), Some(key)) => (groups, key),
+            _ => return ResponseSender::from(response_tx),
+        };
+
+        let group = Arc::new(Mutex::new(Group {
+            leader: handle.clone(),
+            leader_detached: false,
+            followers: vec![],
+            history: vec![],
+            finished: false,
+        }));
+        let mut groups = groups.lock();
+        // Forget the groups of the dropped entries
+        groups.retain(|_, group| group.strong_count() > 0);
+        groups.insert(key, Arc::downgrade(&group));
+        ResponseSender {
+            response_tx,
+            group: Some(group),
+        }
+    }
+
+    /// Other requests are waiting for the generation of request `request_id`
+    pub(crate) fn has_followers(&self, request_id: u64) -> bool {
+        let groups = match &self.groups {
+            None => return false,
+            Some(groups) => groups.lock(),
+        };
+        groups.values().filter_map(Weak::upgrade).any(|group| {
+            let group = group.lock();
+            group.leader.id == request_id && !group.followers.is_empty()
+        })
+    }
+}
+
+impl From<UnboundedSender<Message>> for ResponseSender {
+    fn from(response_tx: UnboundedSender<Message>) -> Self {
+        Self {
+            response_tx,
+            group: None,
+        }
+    }
+}
+
+impl ResponseSender {
+    /// Send a message to the client of the entry and to the requests that joined it
+    ///
+    /// Fails if the client of the entry is gone
+    pub(crate) fn send(&self, message: Message) -> Result<(), SendError<Message>> {
+        let mut group = match &self.group {
+            None => return self.response_tx.send(message),
+            Some(group) => group.lock(),
+        };
+
+        let last = matches!(message, Ok(InferStreamResponse::End { .. }) | Err(_));
+        group.finished |= last;
+        group.followers.retain(|follower| {
+            if follower.handle.cancel_requested() || follower.response_tx.is_closed() {
+                if follower.handle.finish(RequestStatus::Cancelled, None) {
+                    follower
+                        .response_tx
+                        .send(Err(InferError::Cancelled))
+                        .unwrap_or(());
+                }
+                return false;
+            }
+            observe(&follower.handle, &message);
+            follower.response_tx.send(message.clone()).is_ok()
+        });
+        if last {
+            let coalesced = group.followers.len() as u32 + 1;
+            metrics::histogram!("tgi_request_coalesced_group_size", coalesced as f64);
+            group.leader.set_coalesced(coalesced);
+            for follower in &group.followers {
+                follower.handle.set_coalesced(coalesced);
+            }
+        }
+        if let Ok(sent @ (InferStreamResponse::Prefill { .. } | InferStreamResponse::Token(_))) =
+            &message
+        {
+            group.history.push(sent.clone());
+        }
+
+        // The generation goes on for the other requests without the cancelled leader
+        if !group.leader_detached && group.leader.cancel_requested() && !group.followers.is_empty()
+        {
+            group.leader_detached = true;
+            group.leader.finish(RequestStatus::Cancelled, None);
+            return self.response_tx.send(Err(InferError::Cancelled));
+        }
+        match group.leader_detached {
+            true => Ok(()),
+            false => self.response_tx.send(message),
+        }
+    }
+
+    /// The clients of the entry and of the requests that joined it are all gone
+    pub(crate) fn is_closed(&self) -> bool {
+        self.response_tx.is_closed()
+            && self.group.as_ref().map_or(true, |group| {
+                let group = group.lock();
+                group
+                    .followers
+                    .iter()
+                    .all(|follower| follower.response_tx.is_closed())
+            })
+    }
+
+    /// Nobody waits for the generation of the entry of `handle` anymore
+    pub(crate) fn abandoned(&self, handle: &RequestHandle) -> bool {
+        let gone = handle.cancel_requested() || self.response_tx.is_closed();
+        gone && self.group.as_ref().map_or(true, |group| {
+            let group = group.lock();
+            group.followers.iter().all(|follower| {
+                follower.handle.cancel_requested() || follower.response_tx.is_closed()
+            })
+        })
+    }
+}
+
+/// Report a message sent to a request that joined a group on its handle
+fn observe(handle: &RequestHandle, message: &Message) {
+    match message {
+        Ok(InferStreamResponse::Queued) => {}
+        Ok(InferStreamResponse::Started | InferStreamResponse::Prefill { .. }) => {
+            handle.set_running()
+        }
+        Ok(InferStreamResponse::Token(_)) => {
+            handle.set_running();
+            handle.add_token();
+        }
+        Ok(InferStreamResponse::End { .. }) => {
+            handle.add_token();
+            handle.finish(RequestStatus::Completed, None);
+        }
+        Err(InferError::Cancelled) => {
+            handle.finish(RequestStatus::Cancelled, None);
+        }
+        Err(err) => {
+            handle.finish(RequestStatus::Failed, Some(err.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Registry;
+    use crate::{default_parameters, GenerateParameters, Token};
+    use tokio_stream::StreamExt;
+
+    fn request(do_sample: bool, seed: Option<u64>) -> GenerateRequest {
+        GenerateRequest {
+            inputs: "test".to_string(),
+            parameters: GenerateParameters {
+                do_sample: Some(do_sample),
+                seed,
+                ..default_parameters()
+            },
+            template: None,
+            template_vars: None,
+            preset: None,
+            model: None,
+        }
+    }
+
+    fn token(id: u32) -> Message {
+        Ok(InferStreamResponse::Token(Token {
+            id,
+            text: id.to_string(),
+            logprob: 0.0,
+            special: false,
+        }))
+    }
+
+    #[test]
+    fn test_key() {
+        let coalescer = Coalescer::new(true);
+        let context = RequestContext::default();
+        assert!(coalescer
+            .key(&request(false, None), &context, "default")
+            .is_some());
+        assert_ne!(
+            coalescer.key(&request(false, None), &context, "default"),
+            coalescer.key(&request(false, None), &context, "canary")
+        );
+        // Sampled requests without a fixed seed are never coalesced
+        assert!(coalescer
+            .key(&request(true, None), &context, "default")
+            .is_none());
+        assert!(coalescer
+            .key(&request(true, Some(1)), &context, "default")
+            .is_none());
+
+        let mut deadline = request(false, None);
+        deadline.parameters.deadline_ms = Some(1000);
+        assert!(coalescer.key(&deadline, &context, "default").is_none());
+        assert!(Coalescer::new(false)
+            .key(&request(false, None), &context, "default")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_join() {
+        let registry = Registry::new(false);
+        let coalescer = Coalescer::new(true);
+        let leader = registry.register();
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        let sender = coalescer.sender(Some("key".to_string()), response_tx, &leader);
+        assert!(coalescer.join("other", &registry.register()).is_none());
+
+        sender.send(token(1)).unwrap();
+        let follower = registry.register();
+        let mut stream = coalescer.join("key", &follower).unwrap();
+        assert!(coalescer.has_followers(leader.id));
+        sender.send(token(2)).unwrap();
+        sender.send(Err(InferError::IncompleteGeneration)).unwrap();
+
+        // The follower gets the tokens sent before it joined
+        for id in [1, 2] {
+            let message = stream.next().await.unwrap().unwrap();
+            assert!(matches!(message, InferStreamResponse::Token(token) if token.id == id));
+            let message = response_rx.recv().await.unwrap().unwrap();
+            assert!(matches!(message, InferStreamResponse::Token(token) if token.id == id));
+        }
+        assert!(stream.next().await.unwrap().is_err());
+        assert_eq!(follower.generated_tokens(), 2);
+        assert_eq!(follower.status(), RequestStatus::Failed);
+        assert_eq!(leader.coalesced(), 2);
+
+        // Finished groups cannot be joined
+        assert!(coalescer.join("key", &registry.register()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_leader() {
+        let registry = Registry::new(false);
+        let coalescer = Coalescer::new(true);
+        let leader = registry.register();
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        let sender = coalescer.sender(Some("key".to_string()), response_tx, &leader);
+        let follower = registry.register();
+        let mut stream = coalescer.join("key", &follower).unwrap();
+
+        leader.request_cancel();
+        assert!(!sender.abandoned(&leader));
+        sender.send(token(1)).unwrap();
+        assert!(matches!(
+            response_rx.recv().await,
+            Some(Err(InferError::Cancelled))
+        ));
+        assert_eq!(leader.status(), RequestStatus::Cancelled);
+
+        // The generation goes on for the follower
+        sender.send(token(2)).unwrap();
+        for id in [1, 2] {
+            let message = stream.next().await.unwrap().unwrap();
+            assert!(matches!(message, InferStreamResponse::Token(token) if token.id == id));
+        }
+        assert!(response_rx.try_recv().is_err());
+
+        drop(stream);
+        assert!(sender.abandoned(&leader));
+    }
+}
@@ -0,0 +1,242 @@
+/// Streaming connections of each client
+use crate::infer::InferError;
+use crate::usage::api_key_id;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+/// Number of clients listed by `GET /admin/connections`
+const MAX_LISTED_CLIENTS: usize = 20;
+
+/// Concurrent streams of each client IP and API key
+/// They are counted before the requests wait for a permit, so that a broken client holding many
+/// streams open is rejected instead of using the permits of the other clients
+#[derive(Clone, Debug, Default)]
+pub(crate) struct StreamConnections {
+    /// Unlimited if None
+    max_per_ip: Option<usize>,
+    max_per_api_key: Option<usize>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    ips: HashMap<IpAddr, usize>,
+    api_keys: HashMap<String, usize>,
+}
+
+/// Stream counted by `StreamConnections` until it is dropped, whether the stream ended, was
+/// cancelled or panicked
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard {
+    connections: StreamConnections,
+    ip: IpAddr,
+    api_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct ConnectionStatus {
+    /// Streams open on the router
+    #[schema(example = 3)]
+    pub total: usize,
+    #[schema(nullable = true, example = 8)]
+    pub max_per_ip: Option<usize>,
+    #[schema(nullable = true, example = "null")]
+    pub max_per_api_key: Option<usize>,
+    /// Clients with the most streams first
+    pub ips: Vec<ClientConnections>,
+    pub api_keys: Vec<ClientConnections>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct ClientConnections {
+    /// IP address, or id of the API key
+    #[schema(example = "203.0.113.7")]
+    pub client: String,
+    #[schema(example = 2)]
+    pub connections: usize,
+}
+
+impl StreamConnections {
+    pub(crate) fn new(max_per_ip: Option<usize>, max_per_api_key: Option<usize>) -> Self {
+        Self {
+            max_per_ip,
+            max_per_api_key,
+            counts: Arc::new(Mutex::new(Counts::default())),
+        }
+    }
+
+    /// Count a new stream of the client, unless it already has the maximum number of streams
+    pub(crate) fn acquire(
+        &self,
+        ip: IpAddr,
+        api_key: Option<&str>,
+    ) -> Result<ConnectionGuard, InferError> {
+        let mut counts = self.counts.lock();
+        let ip_count = counts.ips.get(&ip).copied().unwrap_or(0);
+        if let Some(limit) = self.max_per_ip {
+            if ip_count >= limit {
+                return Err(rejected("client IP", limit, ip_count));
+            }
+        }
+        if let (Some(limit), Some(api_key)) = (self.max_per_api_key, api_key) {
+            let api_key_count = counts.api_keys.get(api_key).copied().unwrap_or(0);
+            if api_key_count >= limit {
+                return Err(rejected("API key", limit, api_key_count));
+            }
+        }
+
+        counts.total += 1;
+        *counts.ips.entry(ip).or_default() += 1;
+        if let Some(api_key) = api_key {
+            *counts.api_keys.entry(api_key.to_string()).or_default() += 1;
+        }
+        metrics::gauge!("tgi_stream_connections", counts.total as f64);
+        Ok(ConnectionGuard {
+            connections: self.clone(),
+            ip,
+            api_key: api_key.map(str::to_string),
+        })
+    }
+
+    pub(crate) fn status(&self) -> ConnectionStatus {
+        let counts = self.counts.lock();
+        ConnectionStatus {
+            total: counts.total,
+            max_per_ip: self.max_per_ip,
+            max_per_api_key: self.max_per_api_key,
+            ips: busiest(
+                counts
+                    .ips
+                    .iter()
+                    .map(|(ip, count)| (ip.to_string(), *count)),
+            ),
+            api_keys: busiest(
+                counts
+                    .api_keys
+                    .iter()
+                    .map(|(api_key, count)| (api_key_id(api_key), *count)),
+            ),
+        }
+    }
+
+    fn release(&self, ip: &IpAddr, api_key: Option<&str>) {
+        let mut counts = self.counts.lock();
+        counts.total -= 1;
+        decrement(&mut counts.ips, ip);
+        if let Some(api_key) = api_key {
+            decrement(&mut counts.api_keys, api_key);
+        }
+        metrics::gauge!("tgi_stream_connections", counts.total as f64);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.release(&self.ip, self.api_key.as_deref());
+    }
+}
+
+fn rejected(scope: &'static str, limit: usize, current: usize) -> InferError {
+    metrics::increment_counter!("tgi_request_failure", "err" => "connection_limit");
+    metrics::increment_counter!("tgi_stream_connection_rejected", "scope" => scope);
+    InferError::ConnectionLimit {
+        scope,
+        limit,
+        current,
+    }
+}
+
+/// Clients without streams are removed so that the maps do not grow with every client seen
+fn decrement<K, Q>(counts: &mut HashMap<K, usize>, key: &Q)
+where
+    K: std::borrow::Borrow<Q> + std::hash::Hash + Eq,
+    Q: std::hash::Hash + Eq + ?Sized,
+{
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+fn busiest(counts: impl Iterator<Item = (String, usize)>) -> Vec<ClientConnections> {
+    let mut clients: Vec<ClientConnections> = counts
+        .map(|(client, connections)| ClientConnections {
+            client,
+            connections,
+        })
+        .collect();
+    clients.sort_by(|a, b| {
+        b.connections
+            .cmp(&a.connections)
+            .then_with(|| a.client.cmp(&b.client))
+    });
+    clients.truncate(MAX_LISTED_CLIENTS);
+    clients
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_connection_limits() {
+        let connections = StreamConnections::new(Some(2), Some(3));
+        let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        let other_ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 8));
+
+        let first = connections.acquire(ip, Some("secret-key")).unwrap();
+        let _second = connections.acquire(ip, None).unwrap();
+        let err = connections.acquire(ip, None).unwrap_err();
+        assert!(matches!(
+            err,
+            InferError::ConnectionLimit {
+                scope: "client IP",
+                limit: 2,
+                current: 2
+            }
+        ));
+
+        let _third = connections.acquire(other_ip, Some("secret-key")).unwrap();
+        let _fourth = connections.acquire(other_ip, Some("secret-key")).unwrap();
+        assert!(matches!(
+            connections.acquire(IpAddr::V4(Ipv4Addr::LOCALHOST), Some("secret-key")),
+            Err(InferError::ConnectionLimit {
+                scope: "API key",
+                ..
+            })
+        ));
+
+        let status = connections.status();
+        assert_eq!(status.total, 4);
+        assert_eq!(status.ips.len(), 2);
+        assert_eq!(status.api_keys[0].client, api_key_id("secret-key"));
+        assert_eq!(status.api_keys[0].connections, 3);
+
+        // Dropped streams free their connection
+        drop(first);
+        let _fifth = connections.acquire(ip, None).unwrap();
+        assert_eq!(connections.status().total, 4);
+    }
+
+    #[test]
+    fn test_released_clients_are_removed() {
+        let connections = StreamConnections::default();
+        let guard = connections
+            .acquire(IpAddr::V4(Ipv4Addr::LOCALHOST), Some("secret-key"))
+            .unwrap();
+        drop(guard);
+
+        let status = connections.status();
+        assert_eq!(status.total, 0);
+        assert!(status.ips.is_empty());
+        assert!(status.api_keys.is_empty());
+    }
+}
@@ -0,0 +1,418 @@
+/// Server-side history of multi-turn conversations
+use axum::async_trait;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::OwnedMutexGuard;
+use tokio::time::Instant;
+use utoipa::ToSchema;
+
+/// Minimum delay between two sweeps of the expired conversations
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Stop sequence of the generated replies when the request does not set any
+pub(crate) const USER_STOP_SEQUENCE: &str = "\nUser:";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Role {
+    User,
+    Assistant,
+}
+
+impl Role {
+    fn prefix(&self) -> &'static str {
+        match self {
+            Role::User => "User:",
+            Role::Assistant => "Assistant:",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub(crate) struct Message {
+    #[schema(example = "user")]
+    pub role: Role,
+    #[schema(example = "What is Deep Learning?")]
+    pub content: String,
+    /// Number of tokens of the message in the rendered history
+    #[schema(example = 7)]
+    pub tokens: u32,
+}
+
+/// Storage of the conversation histories
+/// The in-memory store can be replaced by a shared store to serve the conversations from several
+/// routers
+#[async_trait]
+pub(crate) trait ConversationStore: Debug + Send + Sync {
+    async fn get(&self, id: &str) -> Option<Vec<Message>>;
+    async fn set(&self, id: String, messages: Vec<Message>);
+    /// Returns false if the conversation does not exist
+    async fn remove(&self, id: &str) -> bool;
+}
+
+/// Conversations kept in memory until they are not updated for `ttl`
+#[derive(Debug)]
+pub(crate) struct MemoryStore {
+    ttl: Duration,
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Debug)]
+struct MemoryState {
+    /// Conversation id -> messages and instant of the last update
+    conversations: HashMap<String, (Vec<Message>, Instant)>,
+    /// Instant of the last sweep
+    last_sweep: Instant,
+}
+
+impl MemoryStore {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            state: Mutex::new(MemoryState {
+                conversations: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl ConversationStore for MemoryStore {
+    async fn get(&self, id: &str) -> Option<Vec<Message>> {
+        let mut state = self.state.lock();
+        // Remove expired conversations
+        if state.last_sweep.elapsed() >= SWEEP_INTERVAL {
+            let ttl = self.ttl;
+            state
+                .conversations
+                .retain(|_, (_, updated)| updated.elapsed() < ttl);
+            state.last_sweep = Instant::now();
+        }
+
+        match state.conversations.get(id) {
+            Some((messages, updated)) if updated.elapsed() < self.ttl => Some(messages.clone()),
+            _ => None,
+        }
+    }
+
+    async fn set(&self, id: String, messages: Vec<Message>) {
+        let mut state = self.state.lock();
+        state.conversations.insert(id, (messages, Instant::now()));
+        metrics::gauge!("tgi_conversations", state.conversations.len() as f64);
+    }
+
+    async fn remove(&self, id: &str) -> bool {
+        let mut state = self.state.lock();
+        let removed = state.conversations.remove(id);
+        metrics::gauge!("tgi_conversations", state.conversations.len() as f64);
+        removed.map_or(false, |(_, updated)| updated.elapsed() < self.ttl)
+    }
+}
+
+/// Locks of the conversations with a running turn
+type TurnLocks = Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// Conversations bounded in number of tokens
+///
+/// The conversations of an API key are only visible to that key: the same conversation id sent
+/// with two keys gives two conversations
+#[derive(Debug, Clone)]
+pub(crate) struct Conversations {
+    store: Arc<dyn ConversationStore>,
+    /// The oldest exchanges are evicted from the longer histories
+    max_tokens: u32,
+    /// The turns of a conversation run one after the other, so that they all see the reply of the
+    /// previous turn instead of overwriting it
+    locks: TurnLocks,
+}
+
+impl Conversations {
+    pub(crate) fn new(store: Arc<dyn ConversationStore>, max_tokens: u32) -> Self {
+        Self {
+            store,
+            max_tokens,
+            locks: Arc::default(),
+        }
+    }
+
+    pub(crate) async fn history(&self, api_key_id: Option<&str>, id: &str) -> Option<Vec<Message>> {
+        self.store.get(&store_key(api_key_id, id)).await
+    }
+
+    /// Returns false if the conversation does not exist
+    pub(crate) async fn clear(&self, api_key_id: Option<&str>, id: &str) -> bool {
+        self.store.remove(&store_key(api_key_id, id)).await
+    }
+
+    /// Start a new turn with a user message, the conversation being created if needed
+    /// Waits for the end of the running turn of the conversation
+    pub(crate) async fn start(
+        &self,
+        api_key_id: Option<&str>,
+        id: String,
+        message: String,
+    ) -> ConversationTurn {
+        let key = store_key(api_key_id, &id);
+        let lock = self.locks.lock().entry(key.clone()).or_default().clone();
+        let lock = TurnLock {
+            locks: self.locks.clone(),
+            key: key.clone(),
+            guard: Some(lock.lock_owned().await),
+        };
+        let history = self.store.get(&key).await.unwrap_or_default();
+        ConversationTurn {
+            conversations: self.clone(),
+            key,
+            history,
+            message,
+            _lock: Arc::new(lock),
+        }
+    }
+}
+
+/// Key of a conversation in the store
+/// The API key ids are hexadecimal, so the keys of two API keys never collide
+fn store_key(api_key_id: Option<&str>, id: &str) -> String {
+    format!("{}/{id}", api_key_id.unwrap_or("-"))
+}
+
+/// Lock of a conversation held by its running turn
+#[derive(Debug)]
+struct TurnLock {
+    locks: TurnLocks,
+    key: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for TurnLock {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self.locks.lock();
+        // No other turn of the conversation is waiting
+        if locks
+            .get(&self.key)
+            .map_or(false, |lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+/// User message waiting for its reply
+/// The next turn of the conversation starts once the turn and its clones are dropped
+#[derive(Debug, Clone)]
+pub(crate) struct ConversationTurn {
+    conversations: Conversations,
+    /// Key of the conversation in the store, scoped by API key
+    key: String,
+    history: Vec<Message>,
+    message: String,
+    _lock: Arc<TurnLock>,
+}
+
+impl ConversationTurn {
+    /// Conversation id scoped by API key
+    pub(crate) fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// History and user message rendered as a prompt for the reply
+    pub(crate) fn prompt(&self) -> String {
+        let mut prompt = String::new();
+        for message in &self.history {
+            prompt.push_str(message.role.prefix());
+            prompt.push(' ');
+            prompt.push_str(&message.content);
+            prompt.push('\n');
+        }
+        prompt.push_str(Role::User.prefix());
+        prompt.push(' ');
+        prompt.push_str(&self.message);
+        prompt.push('\n');
+        prompt.push_str(Role::Assistant.prefix());
+        prompt
+    }
+
+    /// Store the user message and its reply
+    ///
+    /// The user message is counted as the tokens the prompt adds to the history
+    pub(crate) async fn finish(self, reply: &str, prompt_tokens: u32, generated_tokens: u32) {
+        let mut history = self.history;
+        let history_tokens: u32 = history.iter().map(|message| message.tokens).sum();
+        history.push(Message {
+            role: Role::User,
+            content: self.message,
+            tokens: prompt_tokens.saturating_sub(history_tokens),
+        });
+        history.push(Message {
+            role: Role::Assistant,
+            content: reply.trim().to_string(),
+            tokens: generated_tokens,
+        });
+        evict(&mut history, self.conversations.max_tokens);
+        self.conversations.store.set(self.key, history).await;
+    }
+}
+
+/// Remove the oldest exchanges until the history fits in `max_tokens`
+/// The last exchange is always kept
+fn evict(history: &mut Vec<Message>, max_tokens: u32) {
+    let mut tokens: u32 = history.iter().map(|message| message.tokens).sum();
+    let mut evicted = 0;
+    while tokens > max_tokens && history.len() - evicted > 2 {
+        tokens -= history[evicted..evicted + 2]
+            .iter()
+            .map(|message| message.tokens)
+            .sum::<u32>();
+        evicted += 2;
+    }
+    if evicted > 0 {
+        history.drain(..evicted);
+        metrics::increment_counter!("tgi_conversation_eviction");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversations(max_tokens: u32) -> Conversations {
+        Conversations::new(
+            Arc::new(MemoryStore::new(Duration::from_secs(60))),
+            max_tokens,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_turns() {
+        let conversations = conversations(100);
+        let turn = conversations
+            .start(None, "chat".to_string(), "Hello".to_string())
+            .await;
+        assert_eq!(turn.prompt(), "User: Hello\nAssistant:");
+        turn.finish(" Hi!", 6, 2).await;
+
+        let turn = conversations
+            .start(None, "chat".to_string(), "How are you?".to_string())
+            .await;
+        assert_eq!(
+            turn.prompt(),
+            "User: Hello\nAssistant: Hi!\nUser: How are you?\nAssistant:"
+        );
+        turn.finish("Fine", 15, 1).await;
+
+        let history = conversations.history(None, "chat").await.unwrap();
+        let tokens: Vec<u32> = history.iter().map(|message| message.tokens).collect();
+        assert_eq!(tokens, vec![6, 2, 7, 1]);
+        assert_eq!(history[3].role, Role::Assistant);
+        assert_eq!(history[3].content, "Fine");
+
+        assert!(conversations.clear(None, "chat").await);
+        assert!(conversations.history(None, "chat").await.is_none());
+        assert!(!conversations.clear(None, "chat").await);
+    }
+
+    #[tokio::test]
+    async fn test_eviction() {
+        let conversations = conversations(15);
+        for (message, prompt_tokens) in [("first", 8), ("second", 18), ("third", 18)] {
+            let turn = conversations
+                .start(None, "chat".to_string(), message.to_string())
+                .await;
+            turn.finish("ok", prompt_tokens, 2).await;
+        }
+
+        // Each exchange adds 10 tokens: only the last one fits
+        let history = conversations.history(None, "chat").await.unwrap();
+        let contents: Vec<&str> = history
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["third", "ok"]);
+    }
+
+    #[tokio::test]
+    async fn test_api_key_scope() {
+        let conversations = conversations(100);
+        let turn = conversations
+            .start(Some("first"), "chat".to_string(), "Hello".to_string())
+            .await;
+        turn.finish(" Hi!", 6, 2).await;
+
+        // Another API key, or no API key, does not see the conversation
+        assert!(conversations
+            .history(Some("second"), "chat")
+            .await
+            .is_none());
+        assert!(conversations.history(None, "chat").await.is_none());
+        assert!(!conversations.clear(Some("second"), "chat").await);
+        let turn = conversations
+            .start(Some("second"), "chat".to_string(), "Hello".to_string())
+            .await;
+        assert_eq!(turn.prompt(), "User: Hello\nAssistant:");
+        drop(turn);
+
+        assert_eq!(
+            conversations
+                .history(Some("first"), "chat")
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(conversations.clear(Some("first"), "chat").await);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_turns() {
+        let conversations = conversations(100);
+        let first = conversations
+            .start(None, "chat".to_string(), "Hello".to_string())
+            .await;
+
+        // The second turn waits for the reply of the first one
+        let second = tokio::spawn({
+            let conversations = conversations.clone();
+            async move {
+                let turn = conversations
+                    .start(None, "chat".to_string(), "How are you?".to_string())
+                    .await;
+                let prompt = turn.prompt();
+                turn.finish("Fine", 15, 1).await;
+                prompt
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+        first.finish(" Hi!", 6, 2).await;
+        assert_eq!(
+            second.await.unwrap(),
+            "User: Hello\nAssistant: Hi!\nUser: How are you?\nAssistant:"
+        );
+
+        // Both replies are kept
+        let history = conversations.history(None, "chat").await.unwrap();
+        let contents: Vec<&str> = history
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["Hello", "Hi!", "How are you?", "Fine"]);
+        assert!(conversations.locks.lock().is_empty());
+    }
+
+    #[test]
+    fn test_evict_keeps_last_exchange() {
+        let message = |tokens| Message {
+            role: Role::User,
+            content: String::new(),
+            tokens,
+        };
+        let mut history = vec![message(10), message(10)];
+        evict(&mut history, 5);
+        assert_eq!(history.len(), 2);
+    }
+}
@@ -0,0 +1,77 @@
+/// Draining of the router before it is stopped
+use crate::ErrorResponse;
+use axum::extract::State;
+use axum::http::{header, HeaderValue, Method, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Delay in seconds after which the rejected clients can retry, on another replica
+const RETRY_AFTER: &str = "5";
+
+/// Set while the router drains, on graceful shutdown or when asked by the admin API
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Draining(Arc<AtomicBool>);
+
+impl Draining {
+    pub(crate) fn get(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Returns false if the router was already draining
+    pub(crate) fn start(&self) -> bool {
+        !self.0.swap(true, Ordering::SeqCst)
+    }
+
+    /// Returns false if the router was not draining
+    pub(crate) fn stop(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// Middleware rejecting new generation requests while the router drains
+/// The generation routes are the only `POST` routes outside of the admin API
+pub(crate) async fn drain_middleware<B>(
+    State(draining): State<Draining>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let admin = request.uri().path().starts_with("/admin/");
+    if draining.get() && request.method() == Method::POST && !admin {
+        metrics::increment_counter!("tgi_request_failure", "err" => "draining");
+        let mut response = (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorResponse::new(
+                "Router is draining".to_string(),
+                "draining",
+            )),
+        )
+            .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from_static(RETRY_AFTER));
+        return response;
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_draining() {
+        let draining = Draining::default();
+        assert!(!draining.get());
+        assert!(!draining.stop());
+
+        assert!(draining.start());
+        assert!(!draining.clone().start());
+        assert!(draining.get());
+
+        assert!(draining.stop());
+        assert!(!draining.get());
+    }
+}
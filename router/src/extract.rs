@@ -0,0 +1,149 @@
+/// Request extractors
+use crate::{default_parameters, ErrorResponse, RequestContext};
+use axum::body::HttpBody;
+use axum::extract::{FromRequest, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{Request, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{async_trait, BoxError, Json};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::convert::Infallible;
+
+/// Maximum edit distance for a known parameter to be suggested
+const MAX_SUGGESTION_DISTANCE: usize = 3;
+
+/// Accept unknown parameters instead of rejecting the request
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LenientJson(pub bool);
+
+/// JSON extractor rejecting unknown fields in the request `parameters`
+///
+/// The check is skipped if the `LenientJson(true)` extension is set
+#[derive(Debug)]
+pub(crate) struct StrictJson<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for StrictJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let lenient = req
+            .extensions()
+            .get::<LenientJson>()
+            .map_or(false, |lenient| lenient.0);
+
+        // Syntax errors keep the default axum rejection
+        let Json(value) = Json::<Value>::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        if !lenient {
+            if let Some(err) = unknown_parameter(&value) {
+                return Err(unprocessable(err));
+            }
+        }
+
+        serde_json::from_value(value)
+            .map(StrictJson)
+            .map_err(|err| unprocessable(err.to_string()))
+    }
+}
+
+/// The requests received by a handler start with an empty context, the handlers calling another
+/// one pass the context they set
+#[async_trait]
+impl<S> FromRequestParts<S> for RequestContext
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(_parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(RequestContext::default())
+    }
+}
+
+fn unprocessable(error: String) -> Response {
+    metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(ErrorResponse::new(error, "validation")),
+    )
+        .into_response()
+}
+
+/// Error message for the first unknown field in `parameters`, if any
+fn unknown_parameter(value: &Value) -> Option<String> {
+    let parameters = value.get("parameters")?.as_object()?;
+    let known = serde_json::to_value(default_parameters()).ok()?;
+    let known = known.as_object()?;
+
+    let unknown = parameters.keys().find(|name| !known.contains_key(*name))?;
+    let suggestion = known
+        .keys()
+        .map(|name| (edit_distance(unknown, name), name))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min_by_key(|(distance, _)| *distance);
+
+    Some(match suggestion {
+        Some((_, name)) => format!("Unknown parameter `{unknown}`. Did you mean `{name}`?"),
+        None => format!("Unknown parameter `{unknown}`"),
+    })
+}
+
+/// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("max_new_tokens", "max_new_tokens"), 0);
+        assert_eq!(edit_distance("max_new_token", "max_new_tokens"), 1);
+        assert_eq!(edit_distance("top_q", "top_p"), 1);
+        assert_eq!(edit_distance("", "seed"), 4);
+    }
+
+    #[test]
+    fn test_unknown_parameter() {
+        let valid = json!({"inputs": "test", "parameters": {"max_new_tokens": 10}});
+        assert!(unknown_parameter(&valid).is_none());
+        assert!(unknown_parameter(&json!({"inputs": "test"})).is_none());
+
+        let typo = json!({"inputs": "test", "parameters": {"max_new_token": 10}});
+        assert_eq!(
+            unknown_parameter(&typo).unwrap(),
+            "Unknown parameter `max_new_token`. Did you mean `max_new_tokens`?"
+        );
+
+        let unknown = json!({"inputs": "test", "parameters": {"presence_penalty": 1.0}});
+        assert_eq!(
+            unknown_parameter(&unknown).unwrap(),
+            "Unknown parameter `presence_penalty`"
+        );
+    }
+}
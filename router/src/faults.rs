@@ -0,0 +1,181 @@
+/// Fault injection in the calls to the backend, to exercise the error paths of the router
+use rand::Rng;
+use std::time::Duration;
+use text_generation_client::{
+    Batch, CacheUsage, ClientError, Generation, RequestTokens, ShardedClient,
+};
+
+/// Probabilities of the injected faults
+#[derive(Debug, Clone, Default)]
+pub struct FaultConfig {
+    /// Probability of failing a prefill call
+    pub prefill_failure: f64,
+    /// Probability of failing a decode call
+    pub decode_failure: f64,
+    /// Probability of delaying a prefill or decode call by `latency`
+    pub latency_probability: f64,
+    pub latency: Duration,
+    /// Probability of failing a prefill or decode call as if the backend could not be reached
+    pub connection_drop: f64,
+}
+
+/// Type of an injected fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fault {
+    Failure,
+    Latency,
+    ConnectionDrop,
+}
+
+impl Fault {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Fault::Failure => "failure",
+            Fault::Latency => "latency",
+            Fault::ConnectionDrop => "connection_drop",
+        }
+    }
+}
+
+/// Client of a backend
+/// The faults are only injected by the `Faulty` variant, picked once at construction
+pub(crate) enum BackendClient {
+    Sharded(ShardedClient),
+    Faulty(ShardedClient, FaultConfig),
+}
+
+impl BackendClient {
+    pub(crate) fn new(client: ShardedClient, faults: Option<FaultConfig>) -> Self {
+        match faults {
+            None => BackendClient::Sharded(client),
+            Some(faults) => BackendClient::Faulty(client, faults),
+        }
+    }
+
+    /// Clear the past generations cache
+    pub(crate) async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<(), ClientError> {
+        match self {
+            BackendClient::Sharded(client) | BackendClient::Faulty(client, _) => {
+                client.clear_cache(batch_id).await
+            }
+        }
+    }
+
+    /// Remove requests from a cached batch
+    pub(crate) async fn cancel(
+        &mut self,
+        batch_id: u64,
+        request_ids: Vec<u64>,
+    ) -> Result<Option<Batch>, ClientError> {
+        match self {
+            BackendClient::Sharded(client) | BackendClient::Faulty(client, _) => {
+                client.cancel(batch_id, request_ids).await
+            }
+        }
+    }
+
+    /// Generate one token for each request in the given batch
+    pub(crate) async fn prefill(
+        &mut self,
+        batch: Batch,
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>), ClientError> {
+        match self {
+            BackendClient::Sharded(client) => client.prefill(batch, deadline).await,
+            BackendClient::Faulty(client, faults) => {
+                inject("prefill", faults.prefill_failure, faults).await?;
+                client.prefill(batch, deadline).await
+            }
+        }
+    }
+
+    /// Generate one token for each request in the given cached batches
+    pub(crate) async fn decode(
+        &mut self,
+        batches: Vec<Batch>,
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>), ClientError> {
+        match self {
+            BackendClient::Sharded(client) => client.decode(batches, deadline).await,
+            BackendClient::Faulty(client, faults) => {
+                inject("decode", faults.decode_failure, faults).await?;
+                client.decode(batches, deadline).await
+            }
+        }
+    }
+
+    /// Generate draft tokens for each request in the given cached batch
+    pub(crate) async fn draft(
+        &mut self,
+        batch: Batch,
+        tokens: u32,
+        accepted: Vec<RequestTokens>,
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<RequestTokens>, Option<Batch>), ClientError> {
+        match self {
+            BackendClient::Sharded(client) => client.draft(batch, tokens, accepted, deadline).await,
+            BackendClient::Faulty(client, faults) => {
+                inject("draft", faults.decode_failure, faults).await?;
+                client.draft(batch, tokens, accepted, deadline).await
+            }
+        }
+    }
+
+    /// Check the draft tokens of each request in the given cached batch
+    pub(crate) async fn verify(
+        &mut self,
+        batch: Batch,
+        tokens: Vec<RequestTokens>,
+        deadline: Option<Duration>,
+    ) -> Result<(Vec<Generation>, Option<Batch>, Option<CacheUsage>), ClientError> {
+        match self {
+            BackendClient::Sharded(client) => client.verify(batch, tokens, deadline).await,
+            BackendClient::Faulty(client, faults) => {
+                inject("verify", faults.decode_failure, faults).await?;
+                client.verify(batch, tokens, deadline).await
+            }
+        }
+    }
+}
+
+/// Draw the faults of one `method` call
+/// The backend is not called if the call fails
+async fn inject(
+    method: &'static str,
+    failure: f64,
+    faults: &FaultConfig,
+) -> Result<(), ClientError> {
+    let (latency, failure) = {
+        let mut rng = rand::thread_rng();
+        let latency = rng.gen_bool(faults.latency_probability);
+        let failure = if rng.gen_bool(faults.connection_drop) {
+            Some(Fault::ConnectionDrop)
+        } else if rng.gen_bool(failure) {
+            Some(Fault::Failure)
+        } else {
+            None
+        };
+        (latency, failure)
+    };
+
+    if latency {
+        record(method, Fault::Latency);
+        tokio::time::sleep(faults.latency).await;
+    }
+    match failure {
+        None => Ok(()),
+        Some(fault) => {
+            record(method, fault);
+            let message = format!("injected {} in {method}", fault.as_str());
+            Err(match fault {
+                Fault::ConnectionDrop => ClientError::Connection(message),
+                _ => ClientError::Generation(message),
+            })
+        }
+    }
+}
+
+fn record(method: &'static str, fault: Fault) {
+    tracing::warn!(fault = fault.as_str(), "Injecting fault in {method}");
+    metrics::increment_counter!("tgi_faults_injected", "method" => method, "fault" => fault.as_str());
+}
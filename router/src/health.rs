@@ -0,0 +1,76 @@
+/// Generation based health check
+use crate::infer::{Backend, InferError};
+use crate::{default_parameters, GenerateParameters, GenerateRequest, Infer, RequestContext};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Health check sending a one token generation to the backends
+///
+/// A successful check is reused for `cache_duration`, and concurrent checks wait for the running
+/// one instead of sending their own generation
+#[derive(Clone)]
+pub(crate) struct HealthCheck {
+    infer: Infer,
+    cache_duration: Duration,
+    /// Instant of the last successful check
+    last_success: Arc<Mutex<Option<Instant>>>,
+}
+
+impl HealthCheck {
+    pub(crate) fn new(infer: Infer, cache_duration: Duration) -> Self {
+        Self {
+            infer,
+            cache_duration,
+            last_success: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub(crate) async fn check(&self) -> Result<(), InferError> {
+        let mut last_success = self.last_success.lock().await;
+        if last_success.map_or(false, |instant| instant.elapsed() < self.cache_duration) {
+            metrics::increment_counter!("tgi_health_check_cached");
+            return Ok(());
+        }
+
+        // Send a small inference request to the canary backend to track its health
+        // Its requests spill over to the stable backend when it is down, so it does not fail the
+        // check
+        if self.infer.has_canary() {
+            let _ = self
+                .infer
+                .generate(probe_request(), probe_context(Backend::Canary))
+                .await;
+        }
+
+        self.infer
+            .generate(probe_request(), probe_context(Backend::Stable))
+            .await?;
+        *last_success = Some(Instant::now());
+        Ok(())
+    }
+}
+
+fn probe_request() -> GenerateRequest {
+    GenerateRequest {
+        inputs: "liveness".to_string(),
+        parameters: GenerateParameters {
+            max_new_tokens: 1,
+            ..default_parameters()
+        },
+        template: None,
+        template_vars: None,
+        preset: None,
+        model: None,
+    }
+}
+
+/// Health probes are pinned to `backend`
+fn probe_context(backend: Backend) -> RequestContext {
+    RequestContext {
+        backend: Some(backend),
+        probe: true,
+        ..RequestContext::default()
+    }
+}
@@ -0,0 +1,452 @@
+/// Moderation hooks
+use crate::infer::InferError;
+use crate::validation::ValidGenerateRequest;
+use crate::Token;
+use axum::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Decision of a pre-generation hook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HookDecision {
+    Allow,
+    Deny(String),
+    /// Generate from new inputs instead
+    Modify(String),
+}
+
+/// Reason of the requests blocked because the pre-generation hook failed
+const HOOK_UNAVAILABLE: &str = "the moderation hook is unavailable";
+
+#[derive(Debug, Error)]
+pub(crate) enum HookError {
+    #[error("Hook timed out")]
+    Timeout,
+    #[error("Hook request failed: {0}")]
+    Request(String),
+}
+
+/// Hook called on validated requests before they are queued
+#[async_trait]
+pub(crate) trait PreGenerationHook: Send + Sync {
+    async fn check(&self, request: &ValidGenerateRequest) -> Result<HookDecision, HookError>;
+}
+
+/// Pre-generation hook with its timeout policy
+#[derive(Clone)]
+pub(crate) struct InputHook {
+    hook: Arc<dyn PreGenerationHook>,
+    timeout: Duration,
+    /// Allow requests when the hook fails or times out
+    fail_open: bool,
+}
+
+impl InputHook {
+    pub(crate) fn new(
+        hook: Arc<dyn PreGenerationHook>,
+        timeout: Duration,
+        fail_open: bool,
+    ) -> Self {
+        Self {
+            hook,
+            timeout,
+            fail_open,
+        }
+    }
+
+    /// Run the hook
+    /// Hook failures allow the request if `fail_open` is set and block it otherwise. Their cause is
+    /// only logged, it can disclose the hook URL or its internal errors
+    pub(crate) async fn check(
+        &self,
+        request: &ValidGenerateRequest,
+    ) -> Result<HookDecision, InferError> {
+        let decision = match tokio::time::timeout(self.timeout, self.hook.check(request)).await {
+            Ok(decision) => decision,
+            Err(_) => Err(HookError::Timeout),
+        };
+
+        match decision {
+            Ok(decision) => Ok(decision),
+            Err(err) => {
+                metrics::increment_counter!("tgi_hook_failure", "hook" => "pre_generation");
+                tracing::error!("Pre-generation hook: {err}");
+                match self.fail_open {
+                    true => Ok(HookDecision::Allow),
+                    false => Err(InferError::Blocked(HOOK_UNAVAILABLE.to_string())),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookRequest<'a> {
+    inputs: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum WebhookResponse {
+    Allow,
+    Deny {
+        #[serde(default)]
+        reason: String,
+    },
+    Modify {
+        inputs: String,
+    },
+}
+
+/// Pre-generation hook calling an HTTP webhook
+///
+/// The webhook receives `{"inputs": "..."}` and answers with `{"action": "allow"}`,
+/// `{"action": "deny", "reason": "..."}` or `{"action": "modify", "inputs": "..."}`
+pub(crate) struct WebhookHook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl WebhookHook {
+    pub(crate) fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+#[async_trait]
+impl PreGenerationHook for WebhookHook {
+    async fn check(&self, request: &ValidGenerateRequest) -> Result<HookDecision, HookError> {
+        let body = serde_json::to_vec(&WebhookRequest {
+            inputs: &request.inputs,
+        })
+        .map_err(|err| HookError::Request(err.to_string()))?;
+
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| HookError::Request(err.to_string()))?;
+        let body = response
+            .bytes()
+            .await
+            .map_err(|err| HookError::Request(err.to_string()))?;
+
+        let response: WebhookResponse =
+            serde_json::from_slice(&body).map_err(|err| HookError::Request(err.to_string()))?;
+        Ok(match response {
+            WebhookResponse::Allow => HookDecision::Allow,
+            WebhookResponse::Deny { reason } => HookDecision::Deny(reason),
+            WebhookResponse::Modify { inputs } => HookDecision::Modify(inputs),
+        })
+    }
+}
+
+/// Decision of a post-generation hook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum OutputDecision {
+    Allow,
+    /// Replace the text
+    Redact(String),
+    Reject(String),
+}
+
+/// Hook called on generated text before it is sent to the client
+#[async_trait]
+pub(crate) trait PostGenerationHook: Send + Sync {
+    async fn check(&self, text: &str) -> OutputDecision;
+}
+
+/// Post-generation hook and its streaming window
+#[derive(Clone)]
+pub(crate) struct OutputHook {
+    /// None if the hook is disabled
+    hook: Option<Arc<dyn PostGenerationHook>>,
+    /// Number of tokens held back while streaming
+    window: usize,
+}
+
+impl OutputHook {
+    pub(crate) fn new(hook: Option<Arc<dyn PostGenerationHook>>, window: usize) -> Self {
+        Self { hook, window }
+    }
+
+    /// Run the hook on a generated text and return the text to send
+    pub(crate) async fn check(&self, text: String) -> Result<String, InferError> {
+        let hook = match &self.hook {
+            None => return Ok(text),
+            Some(hook) => hook,
+        };
+        match hook.check(&text).await {
+            OutputDecision::Allow => Ok(text),
+            OutputDecision::Redact(text) => {
+                metrics::increment_counter!("tgi_request_redacted");
+                Ok(text)
+            }
+            OutputDecision::Reject(reason) => {
+                metrics::increment_counter!("tgi_request_failure", "err" => "content_filter");
+                Err(InferError::ContentFiltered(reason))
+            }
+        }
+    }
+
+    /// Filter already generated tokens through a window
+    pub(crate) async fn filter(&self, tokens: Vec<Token>) -> Result<Vec<Token>, InferError> {
+        let mut window = self.stream();
+        let mut filtered = Vec::with_capacity(tokens.len());
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(token) = tokens.next() {
+            match tokens.peek() {
+                Some(_) => filtered.extend(window.push(token).await?),
+                None => filtered.extend(window.finish(token).await?),
+            }
+        }
+        Ok(filtered)
+    }
+
+    /// Create the window used to filter a stream of tokens
+    pub(crate) fn stream(&self) -> OutputWindow {
+        OutputWindow {
+            hook: self.clone(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+/// Holds back the last generated tokens until the hook approves them
+pub(crate) struct OutputWindow {
+    hook: OutputHook,
+    pending: VecDeque<Token>,
+}
+
+impl OutputWindow {
+    /// Add a token and return the tokens that can be sent to the client
+    pub(crate) async fn push(&mut self, token: Token) -> Result<Vec<Token>, InferError> {
+        if self.hook.hook.is_none() {
+            return Ok(vec![token]);
+        }
+        self.pending.push_back(token);
+        self.check().await?;
+
+        let released = self.pending.len().saturating_sub(self.hook.window);
+        Ok(self.pending.drain(..released).collect())
+    }
+
+    /// Add the last token and return all the remaining tokens
+    pub(crate) async fn finish(&mut self, token: Token) -> Result<Vec<Token>, InferError> {
+        if self.hook.hook.is_none() {
+            return Ok(vec![token]);
+        }
+        self.pending.push_back(token);
+        self.check().await?;
+        Ok(self.pending.drain(..).collect())
+    }
+
+    /// Run the hook on the pending tokens
+    /// A redacted window is merged into a single token
+    async fn check(&mut self) -> Result<(), InferError> {
+        let text: String = self
+            .pending
+            .iter()
+            .map(|token| token.text.as_str())
+            .collect();
+        let checked = self.hook.check(text.clone()).await?;
+        if checked != text {
+            let last = self
+                .pending
+                .back()
+                .expect("pending is empty. This is a bug.");
+            let token = Token {
+                id: last.id,
+                text: checked,
+                logprob: self.pending.iter().map(|token| token.logprob).sum(),
+                special: false,
+            };
+            self.pending.clear();
+            self.pending.push_back(token);
+        }
+        Ok(())
+    }
+}
+
+/// Post-generation hook redacting or rejecting texts matching regular expressions
+pub(crate) struct RegexHook {
+    redact: Vec<Regex>,
+    reject: Vec<Regex>,
+}
+
+/// Replacement of the redacted matches
+const REDACTED: &str = "[REDACTED]";
+
+impl RegexHook {
+    pub(crate) fn new(redact: &[String], reject: &[String]) -> Result<Self, regex::Error> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            redact: compile(redact)?,
+            reject: compile(reject)?,
+        })
+    }
+}
+
+#[async_trait]
+impl PostGenerationHook for RegexHook {
+    async fn check(&self, text: &str) -> OutputDecision {
+        if let Some(pattern) = self.reject.iter().find(|pattern| pattern.is_match(text)) {
+            return OutputDecision::Reject(format!("text matches `{pattern}`"));
+        }
+
+        let mut redacted = text.to_string();
+        for pattern in &self.redact {
+            redacted = pattern.replace_all(&redacted, REDACTED).into_owned();
+        }
+        match redacted == text {
+            true => OutputDecision::Allow,
+            false => OutputDecision::Redact(redacted),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::ValidationTimings;
+    use crate::InputSource;
+    use text_generation_client::{NextTokenChooserParameters, StoppingCriteriaParameters};
+
+    struct SlowHook;
+
+    #[async_trait]
+    impl PreGenerationHook for SlowHook {
+        async fn check(&self, _: &ValidGenerateRequest) -> Result<HookDecision, HookError> {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok(HookDecision::Deny("slow".to_string()))
+        }
+    }
+
+    fn request() -> ValidGenerateRequest {
+        ValidGenerateRequest {
+            inputs: "test".to_string(),
+            input_length: 1,
+            parameters: NextTokenChooserParameters {
+                temperature: 0.0,
+                top_k: 0,
+                top_p: 0.0,
+                typical_p: 0.0,
+                do_sample: false,
+                seed: 0,
+                repetition_penalty: 0.0,
+                watermark: false,
+                allowed_tokens: vec![],
+            },
+            stopping_parameters: StoppingCriteriaParameters {
+                ignore_eos_token: false,
+                max_new_tokens: 0,
+                stop_sequences: vec![],
+            },
+            stop_matcher: Arc::default(),
+            prefill_tokens: false,
+            tokenization: None,
+            clean_up_tokenization_spaces: false,
+            raw_token_text: false,
+            normalized_chars: None,
+            input_source: InputSource::Text,
+            timings: ValidationTimings::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout_policy() {
+        let timeout = Duration::from_millis(1);
+
+        let fail_open = InputHook::new(Arc::new(SlowHook), timeout, true);
+        assert_eq!(
+            fail_open.check(&request()).await.unwrap(),
+            HookDecision::Allow
+        );
+
+        let fail_closed = InputHook::new(Arc::new(SlowHook), timeout, false);
+        assert!(matches!(
+            fail_closed.check(&request()).await,
+            Err(InferError::Blocked(reason)) if reason == HOOK_UNAVAILABLE
+        ));
+    }
+
+    #[test]
+    fn test_webhook_response() {
+        let response: WebhookResponse =
+            serde_json::from_str(r#"{"action": "deny", "reason": "flagged"}"#).unwrap();
+        assert!(matches!(response, WebhookResponse::Deny { reason } if reason == "flagged"));
+
+        let response: WebhookResponse =
+            serde_json::from_str(r#"{"action": "modify", "inputs": "clean"}"#).unwrap();
+        assert!(matches!(response, WebhookResponse::Modify { inputs } if inputs == "clean"));
+    }
+
+    fn token(text: &str) -> Token {
+        Token {
+            id: 0,
+            text: text.to_string(),
+            logprob: 0.0,
+            special: false,
+        }
+    }
+
+    fn texts(tokens: Vec<Token>) -> Vec<String> {
+        tokens.into_iter().map(|token| token.text).collect()
+    }
+
+    #[tokio::test]
+    async fn test_regex_hook() {
+        let hook = RegexHook::new(&["[0-9]{4}".to_string()], &["forbidden".to_string()]).unwrap();
+        assert_eq!(hook.check("hello").await, OutputDecision::Allow);
+        assert_eq!(
+            hook.check("pin 1234").await,
+            OutputDecision::Redact("pin [REDACTED]".to_string())
+        );
+        assert!(matches!(
+            hook.check("forbidden 1234").await,
+            OutputDecision::Reject(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_output_window() {
+        let hook = RegexHook::new(&["secret".to_string()], &["forbidden".to_string()]).unwrap();
+        let hook = OutputHook::new(Some(Arc::new(hook)), 2);
+
+        let mut window = hook.stream();
+        assert!(window.push(token("a")).await.unwrap().is_empty());
+        assert!(window.push(token(" sec")).await.unwrap().is_empty());
+        // The redacted window is merged into a single token
+        assert!(window.push(token("ret")).await.unwrap().is_empty());
+        assert_eq!(
+            texts(window.finish(token("!")).await.unwrap()),
+            vec!["a [REDACTED]", "!"]
+        );
+
+        let mut window = hook.stream();
+        assert!(window.push(token("for")).await.unwrap().is_empty());
+        assert!(matches!(
+            window.push(token("bidden")).await,
+            Err(InferError::ContentFiltered(_))
+        ));
+
+        let mut disabled = OutputHook::new(None, 2).stream();
+        assert_eq!(texts(disabled.push(token("a")).await.unwrap()), vec!["a"]);
+    }
+}
@@ -1,498 +1,5800 @@
 /// Batching and inference logic
-use crate::validation::{Validation, ValidationError};
+use crate::breaker::{CircuitBreaker, CircuitBreakerConfig, CircuitBreakerStatus};
+use crate::coalesce::Coalescer;
+use crate::faults::{BackendClient, FaultConfig};
+use crate::hook::{HookDecision, InputHook};
+use crate::latency::{LatencyController, LatencyTarget};
+use crate::limits::Limits;
+use crate::queue::{Permit, QueuedProbe, TokenBudget, STALE_ENTRY_INTERVAL};
+use crate::registry::{Continuation, Registry, RequestHandle};
+use crate::replay::ReplayLog;
+use crate::session::Sessions;
+use crate::stop::StopBuffer;
+use crate::validation::{
+    InputTokenization, ValidGenerateRequest, Validation, ValidationError, ValidationTimings,
+};
+use crate::{
+    AbortReason, CacheUtilization, FinishReason, GenerateParameters, GenerateRequest,
+    GenerationStatus, MatchedStop, OverloadReason, PrefillToken, QueueStatus, RequestContext,
+    RequestStatus, ShardStatus, ValidParameters,
+};
 use crate::{Entry, Queue, Token};
-use crate::{GenerateRequest, PrefillToken};
-use futures::future::try_join_all;
+use futures::future::join_all;
 use nohash_hasher::IntMap;
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use text_generation_client::{
-    Batch, ClientError, GeneratedText, Generation, PrefillTokens, ShardedClient,
+    Batch, CacheUsage, ClientError, GeneratedText, Generation, PrefillTokens, Request,
+    RequestTokens, ShardSnapshot, ShardStats, ShardedClient, StoppingCriteriaParameters,
 };
 use thiserror::Error;
-use tokio::sync::{mpsc, Notify, Semaphore, TryAcquireError};
+use tokenizers::Tokenizer;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc::WeakUnboundedSender;
+use tokio::sync::{mpsc, oneshot, Notify, OwnedSemaphorePermit, Semaphore};
 use tokio::time::Instant;
 use tokio_stream::wrappers::UnboundedReceiverStream;
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
 use tracing::{info_span, instrument, Instrument, Span};
+use utoipa::ToSchema;
+
+/// Number of generated tokens between two `decoding` transitions of a request
+const TRACE_TOKEN_INTERVAL: u32 = 16;
+
+/// Weight of the last decode step in the rolling throughput estimate
+const THROUGHPUT_SMOOTHING: f64 = 0.1;
+
+/// Number of times a request can be requeued after the failure of its batch
+const MAX_REQUEUES: u32 = 2;
+
+/// Interval between two checks of the readiness of a loading backend
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Delay before respawning a batching task that panicked, so that a task panicking on each batch
+/// does not spin
+const BATCHING_RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// With deterministic batching, a batch smaller than `max_batch_size` is only sent once no
+/// request was queued for this long
+const DETERMINISTIC_FILL_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Inference struct
 #[derive(Clone)]
 pub struct Infer {
     /// Validation
     validation: Validation,
+    /// Stable backend
+    stable: BackendQueue,
+    /// Canary backend
+    canary: Option<BackendQueue>,
+    /// Share of the requests sent to the canary backend
+    canary_ratio: f32,
+    /// Live requests registry
+    registry: Registry,
+    /// Sessions of the previous requests
+    sessions: Sessions,
+    /// Inference limit
+    limit_concurrent_requests: Arc<Semaphore>,
+    max_concurrent_requests: usize,
+    /// Permits reserved for the health and self-test probes
+    probe_permits: Arc<Semaphore>,
+    /// Interval between two heartbeats sent to queued streaming clients
+    heartbeat_interval: Option<Duration>,
+    /// Hook called before queuing requests
+    input_hook: Option<InputHook>,
+    /// Treat all requests as latency sensitive
+    all_latency_sensitive: bool,
+    /// Watermark the generated text of all requests
+    force_watermark: bool,
+    /// Requests are rejected when their estimated time in the queue is longer
+    max_queue_wait: Option<Duration>,
+    /// API keys allowed to bypass the estimated wait limit with `force_queue`
+    force_queue_api_keys: Arc<HashSet<String>>,
+    /// Requeue the requests of failed batches unless they override it
+    auto_requeue: bool,
+    /// Handling of the requests sent while a backend is loading
+    loading_policy: LoadingPolicy,
+    /// Identical concurrent requests waiting for the same generation
+    coalescer: Coalescer,
+    /// Tokens a backend can send past `max_new_tokens` before the router ends the generation
+    max_excess_tokens: u32,
+    /// The requests are batched in arrival order, the probes included
+    deterministic_batching: bool,
+    /// Name of the model, matched by the `model` of the requests
+    model_name: Arc<str>,
+    /// Other models served by the router, by name
+    models: Arc<BTreeMap<String, Infer>>,
+}
+
+/// Client of a backend, which may still be connecting to its shards
+pub enum BackendConnection {
+    Connected(ShardedClient),
+    /// Receives the client once the shards have loaded the model
+    Connecting(oneshot::Receiver<ShardedClient>),
+}
+
+impl From<ShardedClient> for BackendConnection {
+    fn from(client: ShardedClient) -> Self {
+        BackendConnection::Connected(client)
+    }
+}
+
+impl BackendConnection {
+    /// None if the router shut down before the backend connected
+    async fn connected(self) -> Option<ShardedClient> {
+        match self {
+            BackendConnection::Connected(client) => Some(client),
+            BackendConnection::Connecting(receiver) => receiver.await.ok(),
+        }
+    }
+}
+
+/// Speculative decoding of the requests of a backend with the draft tokens of another backend
+pub(crate) struct Speculation {
+    /// Backend of the draft model, sharing the tokenizer of the target model
+    pub draft: BackendConnection,
+    /// Number of draft tokens checked by each call to the target backend
+    pub tokens: u32,
+}
+
+/// Handling of the requests sent while a backend connects to its shards and loads its model
+/// Health probes are always rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadingPolicy {
+    /// Requests fail with a `model_loading` error
+    Reject,
+    /// Requests wait in the queue, within the limit of concurrent requests and their deadline
+    Queue,
+}
+
+impl FromStr for LoadingPolicy {
+    type Err = String;
+
+    /// Parse `reject` or `queue`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(LoadingPolicy::Reject),
+            "queue" => Ok(LoadingPolicy::Queue),
+            _ => Err(format!(
+                "unknown loading policy `{value}`, expected `reject` or `queue`"
+            )),
+        }
+    }
+}
+
+/// Backend serving a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Backend {
+    Stable,
+    Canary,
+    /// Stable backend of a model served next to the model of the router
+    Model(&'static str),
+}
+
+impl Backend {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Backend::Stable => "stable",
+            Backend::Canary => "canary",
+            Backend::Model(name) => name,
+        }
+    }
+}
+
+/// When the batching task pauses the running batch to prefill new requests
+/// Only batches with latency sensitive requests are affected, the others always accept new requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchingPolicy {
+    /// New requests are added whenever the running batch is small enough
+    Throughput,
+    /// New requests wait until the latency sensitive requests are finished
+    Latency,
+    /// New requests wait until the batch has at most `max_size` requests
+    Hybrid { max_size: u32 },
+}
+
+impl BatchingPolicy {
+    /// Whether new requests can be prefilled while a batch of `batch_size` requests is running
+    fn allows_prefill(&self, batch_size: u32, latency_sensitive: bool) -> bool {
+        match self {
+            _ if !latency_sensitive => true,
+            BatchingPolicy::Throughput => true,
+            BatchingPolicy::Latency => false,
+            BatchingPolicy::Hybrid { max_size } => batch_size <= *max_size,
+        }
+    }
+}
+
+impl FromStr for BatchingPolicy {
+    type Err = String;
+
+    /// Parse `throughput`, `latency` or `hybrid:<max_size>`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            None if value == "throughput" => Ok(BatchingPolicy::Throughput),
+            None if value == "latency" => Ok(BatchingPolicy::Latency),
+            Some(("hybrid", max_size)) => max_size
+                .parse()
+                .map(|max_size| BatchingPolicy::Hybrid { max_size })
+                .map_err(|err| format!("invalid hybrid batch size `{max_size}`: {err}")),
+            _ => Err(format!(
+                "unknown batching policy `{value}`, expected `throughput`, `latency` or `hybrid:<max_size>`"
+            )),
+        }
+    }
+}
+
+/// Queue and batching task of a backend
+#[derive(Clone)]
+struct BackendQueue {
     /// Request queue
     queue: Queue,
     /// Shared state
     shared: Arc<Shared>,
-    /// Inference limit
-    limit_concurrent_requests: Arc<Semaphore>,
 }
 
 /// Infer shared state
 struct Shared {
     /// Batching background Tokio task notifier
     batching_task: Notify,
+    /// Backend served by the batching task
+    backend: Backend,
+    /// Set to false when the backend cannot be reached
+    healthy: AtomicBool,
+    /// Set once the backend is connected and has loaded its model
+    ready: AtomicBool,
+    /// Set if the batching task stopped without being respawned: the queued requests are never
+    /// batched
+    stopped: AtomicBool,
+    /// Set once the backend is connected if it implements `Cancel`
+    cancel: AtomicBool,
+    /// Number of health probes waiting in the queue
+    queued_probes: Arc<AtomicUsize>,
+    /// Rolling estimate of the decoded tokens per second, stored as the bits of a f64
+    /// 0 until the first decode
+    throughput: AtomicU64,
+    /// Maximum number of tokens the running batch will still generate, set before each decode
+    /// step
+    running_token_debt: AtomicU64,
+    /// Latest KV-cache usage reported by the backend, the used blocks in the high 32 bits and the
+    /// total in the low 32 bits. 0 if the backend does not report it
+    cache_blocks: AtomicU64,
+    /// Fails requests fast while the backend cannot be reached
+    breaker: CircuitBreaker,
+    /// Captures the requests failed by a generation error
+    replay_log: ReplayLog,
+    /// Log the timings of each decode step
+    debug_batching: bool,
+    /// Fraction of the batch allowed by the inter-token latency controller, stored as the bits of
+    /// a f64. NaN without latency target
+    latency_budget: AtomicU64,
+    /// Set once the backend is connected
+    shard_stats: Mutex<Option<ShardStats>>,
 }
 
-impl Infer {
-    pub(crate) fn new(
-        client: ShardedClient,
-        validation: Validation,
+impl Shared {
+    fn set_healthy(&self, healthy: bool) {
+        if self.healthy.swap(healthy, Ordering::SeqCst) != healthy {
+            tracing::warn!("{} backend healthy: {healthy}", self.backend.as_str());
+            metrics::gauge!("tgi_backend_healthy", healthy as u8 as f64, "backend" => self.backend.as_str());
+        }
+    }
+
+    /// A batch reached the backend
+    fn batch_succeeded(&self) {
+        self.set_healthy(true);
+        if self.breaker.record_success() {
+            tracing::warn!("{} backend circuit breaker closed", self.backend.as_str());
+            self.update_breaker_gauge();
+        }
+    }
+
+    /// A batch failed, the circuit breaker only counting the failures to reach the backend
+    fn batch_failed(&self, err: &ClientError) {
+        let unreachable = backend_unreachable(err);
+        self.set_healthy(!unreachable);
+        let changed = match unreachable {
+            true => self.breaker.record_failure(),
+            false => self.breaker.record_success(),
+        };
+        if changed {
+            tracing::warn!(
+                "{} backend circuit breaker {:?}",
+                self.backend.as_str(),
+                self.breaker.state()
+            );
+        }
+        self.update_breaker_gauge();
+    }
+
+    /// Let a probe request through to the backend if it can be reached again, i.e. if it is not
+    /// loading or stopped
+    fn admit_probe(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+            && !self.stopped.load(Ordering::SeqCst)
+            && self.breaker.admit_probe()
+    }
+
+    fn update_breaker_gauge(&self) {
+        metrics::gauge!("tgi_circuit_breaker_state", self.breaker.state().as_f64(), "backend" => self.backend.as_str());
+    }
+
+    /// Decoded tokens per second, or None if nothing was decoded yet
+    fn throughput(&self) -> Option<f64> {
+        let throughput = f64::from_bits(self.throughput.load(Ordering::Relaxed));
+        (throughput > 0.0).then_some(throughput)
+    }
+
+    /// None without latency target
+    fn latency_budget(&self) -> Option<f64> {
+        let budget = f64::from_bits(self.latency_budget.load(Ordering::Relaxed));
+        (!budget.is_nan()).then_some(budget)
+    }
+
+    fn set_latency_budget(&self, budget: f64) {
+        self.latency_budget
+            .store(budget.to_bits(), Ordering::Relaxed);
+        metrics::gauge!("tgi_batch_latency_budget", budget, "backend" => self.backend.as_str());
+    }
+
+    /// Update the throughput estimate with a decode step
+    fn record_decode(&self, tokens: usize, duration: Duration) {
+        if tokens == 0 || duration.is_zero() {
+            return;
+        }
+        let rate = tokens as f64 / duration.as_secs_f64();
+        let throughput = match self.throughput() {
+            None => rate,
+            Some(throughput) => throughput + THROUGHPUT_SMOOTHING * (rate - throughput),
+        };
+        // Only the batching task writes the estimate
+        self.throughput
+            .store(throughput.to_bits(), Ordering::Relaxed);
+        metrics::gauge!("tgi_backend_throughput", throughput, "backend" => self.backend.as_str());
+    }
+
+    /// Set the tokens left to generate by `entries`, the entries of the running batch
+    fn set_running_token_debt(&self, entries: &IntMap<u64, Entry>) {
+        let token_debt = entries
+            .values()
+            .map(|entry| {
+                entry
+                    .request
+                    .stopping_parameters
+                    .max_new_tokens
+                    .saturating_sub(entry.handle.generated_tokens()) as u64
+            })
+            .sum();
+        self.running_token_debt.store(token_debt, Ordering::Relaxed);
+    }
+
+    /// Latest KV-cache usage of the backend, or None if it does not report it
+    fn cache_usage(&self) -> Option<CacheUsage> {
+        let cache_blocks = self.cache_blocks.load(Ordering::Relaxed);
+        let blocks_total = cache_blocks as u32;
+        (blocks_total > 0).then_some(CacheUsage {
+            blocks_used: (cache_blocks >> 32) as u32,
+            blocks_total,
+        })
+    }
+
+    /// Store the KV-cache usage reported by a prefill or decode response
+    fn record_cache_usage(&self, cache_usage: Option<CacheUsage>) {
+        let cache_usage = match cache_usage {
+            Some(cache_usage) => cache_usage,
+            None => return,
+        };
+        let cache_blocks = (cache_usage.blocks_used as u64) << 32 | cache_usage.blocks_total as u64;
+        self.cache_blocks.store(cache_blocks, Ordering::Relaxed);
+        let backend = self.backend.as_str();
+        metrics::gauge!("tgi_backend_cache_blocks_used", cache_usage.blocks_used as f64, "backend" => backend);
+        metrics::gauge!("tgi_backend_cache_blocks_total", cache_usage.blocks_total as f64, "backend" => backend);
+        metrics::gauge!("tgi_backend_cache_utilization", cache_usage.utilization(), "backend" => backend);
+    }
+
+    fn cache_utilization(&self) -> Option<CacheUtilization> {
+        self.cache_usage().map(|cache_usage| CacheUtilization {
+            backend: self.backend.as_str(),
+            blocks_used: cache_usage.blocks_used,
+            blocks_total: cache_usage.blocks_total,
+            utilization: cache_usage.utilization(),
+        })
+    }
+}
+
+impl BackendQueue {
+    /// Time needed to generate the tokens of the running and queued requests at the current
+    /// throughput
+    /// None if the throughput is not known yet
+    async fn estimated_wait(&self) -> Option<Duration> {
+        let throughput = self.shared.throughput()?;
+        let token_debt =
+            self.queue.token_debt().await + self.shared.running_token_debt.load(Ordering::Relaxed);
+        Some(Duration::from_secs_f64(token_debt as f64 / throughput))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        client: BackendConnection,
+        faults: Option<FaultConfig>,
+        backend: Backend,
         max_batch_size: usize,
         max_waiting_tokens: usize,
-        max_concurrent_requests: usize,
+        prefill_chunk_tokens: Option<u32>,
+        max_batch_total_tokens: Option<u32>,
+        min_downgraded_new_tokens: u32,
+        cache_utilization_threshold: f64,
+        batching_policy: BatchingPolicy,
+        circuit_breaker: CircuitBreakerConfig,
+        replay_log: ReplayLog,
+        debug_batching: bool,
+        speculation: Option<Speculation>,
+        latency_target: Option<LatencyTarget>,
+        deterministic_batching: bool,
+        runtime: Option<&Handle>,
     ) -> Self {
         // Infer shared state
-        let queue = Queue::new();
+        let queue = Queue::new(backend.as_str());
+        let ready = matches!(client, BackendConnection::Connected(_));
         let shared = Arc::new(Shared {
             batching_task: Notify::new(),
+            backend,
+            healthy: AtomicBool::new(true),
+            ready: AtomicBool::new(ready),
+            stopped: AtomicBool::new(false),
+            cancel: AtomicBool::new(false),
+            queued_probes: Arc::new(AtomicUsize::new(0)),
+            throughput: AtomicU64::new(0),
+            running_token_debt: AtomicU64::new(0),
+            cache_blocks: AtomicU64::new(0),
+            breaker: CircuitBreaker::new(circuit_breaker),
+            replay_log,
+            debug_batching,
+            latency_budget: AtomicU64::new(f64::NAN.to_bits()),
+            shard_stats: Mutex::new(None),
         });
 
         // Spawn batching background task that contains all the inference logic
-        tokio::spawn(batching_task(
-            client,
-            max_batch_size,
-            max_waiting_tokens,
-            queue.clone(),
-            shared.clone(),
-        ));
+        // Its supervisor respawns it if it panics
+        let task_queue = queue.clone();
+        let task_shared = shared.clone();
+        match speculation {
+            None => spawn_batching(
+                runtime,
+                supervise(client.connected(), shared.clone(), move |client| {
+                    batching_task(
+                        client,
+                        faults.clone(),
+                        max_batch_size,
+                        max_waiting_tokens,
+                        prefill_chunk_tokens,
+                        max_batch_total_tokens,
+                        min_downgraded_new_tokens,
+                        cache_utilization_threshold,
+                        batching_policy,
+                        latency_target,
+                        deterministic_batching,
+                        task_queue.clone(),
+                        task_shared.clone(),
+                    )
+                }),
+            ),
+            Some(speculation) => {
+                let draft_tokens = speculation.tokens;
+                // The backend is ready once both backends are connected
+                let connect = async move {
+                    let draft = speculation.draft.connected().await?;
+                    let client = client.connected().await?;
+                    Some((client, draft))
+                };
+                spawn_batching(
+                    runtime,
+                    supervise(connect, shared.clone(), move |(client, draft)| {
+                        speculative_task(
+                            client,
+                            draft,
+                            draft_tokens,
+                            faults.clone(),
+                            task_queue.clone(),
+                            task_shared.clone(),
+                        )
+                    }),
+                )
+            }
+        }
 
-        // Inference limit with a semaphore
-        let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
+        Self { queue, shared }
+    }
+}
 
-        Self {
-            validation,
-            queue,
-            shared,
-            limit_concurrent_requests: semaphore,
+/// Spawn a batching task on `runtime`, or on the current runtime if None
+fn spawn_batching<F>(runtime: Option<&Handle>, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    match runtime {
+        Some(runtime) => runtime.spawn(task),
+        None => tokio::spawn(task),
+    };
+}
+
+/// Run `task` once `connect` returns the client of the backend, respawning it if it panics
+///
+/// This needs panics to unwind: the workspace release profile must not set `panic = "abort"`,
+/// which would stop the router and leave the restart to the launcher
+///
+/// The requests of the batch running when it panicked are dropped with it, their clients
+/// receiving an error. The respawned task clears the cache of the backend before batching the
+/// queued requests. The router is shutting down if `connect` returns None
+async fn supervise<C, T, F>(connect: impl Future<Output = Option<C>>, shared: Arc<Shared>, task: T)
+where
+    C: Clone,
+    T: Fn(C) -> F,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let client = match connect.await {
+        Some(client) => client,
+        None => return,
+    };
+    let backend = shared.backend.as_str();
+    loop {
+        match tokio::spawn(task(client.clone())).await {
+            Err(err) if err.is_panic() => {
+                metrics::increment_counter!("tgi_batching_task_restarts", "backend" => backend);
+                tracing::error!("{backend} batching task panicked, respawning it");
+                tokio::time::sleep(BATCHING_RESTART_DELAY).await;
+                // Batch the requests queued while it was down
+                shared.batching_task.notify_one();
+            }
+            // The task never returns while the router is running, it was cancelled otherwise
+            _ => {
+                tracing::error!("{backend} batching task stopped");
+                shared.stopped.store(true, Ordering::SeqCst);
+                metrics::gauge!("tgi_backend_healthy", 0.0, "backend" => backend);
+                return;
+            }
         }
     }
+}
 
-    /// Add a new request to the queue and return a stream of InferStreamResponse
-    #[instrument(skip(self))]
-    pub(crate) async fn generate_stream(
-        &self,
-        request: GenerateRequest,
-    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
-        // Limit concurrent requests by acquiring a permit from the semaphore
-        // This permit will live as long as Entry
-        let permit = self
-            .clone()
-            .limit_concurrent_requests
-            .try_acquire_owned()
-            .map_err(|err| {
-                metrics::increment_counter!("tgi_request_failure", "err" => "overloaded");
-                tracing::error!("{err}");
-                err
-            })?;
+/// Runtime with `threads` worker threads, driven by a dedicated thread for the lifetime of the
+/// router
+///
+/// The batching tasks spawned on it do not wait behind the request handlers of the main runtime
+/// to send the next decode call. A single thread runs them on a current-thread runtime
+pub(crate) fn dedicated_runtime(threads: usize) -> Handle {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name("batching".to_string())
+        .spawn(move || {
+            let runtime = match threads {
+                1 => tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build(),
+                threads => tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(threads)
+                    .thread_name("batching-worker")
+                    .enable_all()
+                    .build(),
+            }
+            .expect("Could not build the batching runtime");
+            sender.send(runtime.handle().clone()).unwrap();
+            // The tasks of a current-thread runtime only run while it is blocked on
+            runtime.block_on(std::future::pending::<()>())
+        })
+        .expect("Could not spawn the batching thread");
+    receiver.recv().expect("The batching thread stopped")
+}
 
-        // Validate request
-        let valid_request = self.validation.validate(request).await?;
+/// Builder of an [`Infer`], with the defaults of the router command line
+pub struct InferBuilder {
+    client: BackendConnection,
+    backend: Backend,
+    canary_client: Option<BackendConnection>,
+    canary_ratio: f32,
+    validation: Validation,
+    max_batch_size: usize,
+    max_waiting_tokens: usize,
+    prefill_chunk_tokens: Option<u32>,
+    max_batch_total_tokens: Option<u32>,
+    min_downgraded_new_tokens: u32,
+    cache_utilization_threshold: f64,
+    batching_policy: BatchingPolicy,
+    all_latency_sensitive: bool,
+    force_watermark: bool,
+    max_concurrent_requests: usize,
+    heartbeat_interval: Option<Duration>,
+    input_hook: Option<InputHook>,
+    trace_requests: bool,
+    faults: Option<FaultConfig>,
+    max_queue_wait: Option<Duration>,
+    force_queue_api_keys: HashSet<String>,
+    circuit_breaker: CircuitBreakerConfig,
+    auto_requeue: bool,
+    replay_log: ReplayLog,
+    loading_policy: LoadingPolicy,
+    debug_batching: bool,
+    coalesce_requests: bool,
+    speculation: Option<Speculation>,
+    latency_target: Option<LatencyTarget>,
+    reserved_probe_permits: usize,
+    max_excess_tokens: u32,
+    batching_runtime: Option<Handle>,
+    deterministic_batching: bool,
+    max_sessions: usize,
+    max_session_bytes: usize,
+}
 
-        // MPSC channel to communicate with the background batching task
-        let (response_tx, response_rx) = mpsc::unbounded_channel();
+impl InferBuilder {
+    /// Send a share of the requests to a canary backend
+    pub fn canary(mut self, client: BackendConnection, ratio: f32) -> Self {
+        self.canary_client = Some(client);
+        self.canary_ratio = ratio;
+        self
+    }
 
-        // Append the request to the queue
-        self.queue.append(Entry {
-            request: valid_request,
-            response_tx,
-            span: Span::current(),
-            temp_span: None,
-            queue_time: Instant::now(),
-            batch_time: None,
-            _permit: permit,
-        });
+    pub fn max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
 
-        // Notify the background task that we have a new entry in the queue that needs
-        // to be batched
-        self.shared.batching_task.notify_one();
+    pub fn max_waiting_tokens(mut self, max_waiting_tokens: usize) -> Self {
+        self.max_waiting_tokens = max_waiting_tokens;
+        self
+    }
 
-        // Return stream
-        Ok(UnboundedReceiverStream::new(response_rx))
+    /// Maximum input tokens of a batch added to a running batch
+    /// A request longer than this is prefilled alone, its prompt is not split
+    pub fn prefill_chunk_tokens(mut self, prefill_chunk_tokens: Option<u32>) -> Self {
+        self.prefill_chunk_tokens = prefill_chunk_tokens;
+        self
     }
 
-    /// Add a new request to the queue and return a InferResponse
-    #[instrument(skip(self))]
-    pub(crate) async fn generate(
-        &self,
-        request: GenerateRequest,
-    ) -> Result<InferResponse, InferError> {
-        // Create stream
-        let mut stream = self.generate_stream(request).await?;
+    pub fn max_batch_total_tokens(mut self, max_batch_total_tokens: Option<u32>) -> Self {
+        self.max_batch_total_tokens = max_batch_total_tokens;
+        self
+    }
 
-        // Return values
-        let mut result_prefill = Vec::new();
-        let mut result_tokens = Vec::new();
-        let mut result_generated_text = None;
-        let mut result_start = None;
-        let mut result_queued = None;
+    pub fn min_downgraded_new_tokens(mut self, min_downgraded_new_tokens: u32) -> Self {
+        self.min_downgraded_new_tokens = min_downgraded_new_tokens;
+        self
+    }
 
-        // Iterate on stream
-        while let Some(response) = stream.next().await {
-            match response? {
-                // Add prefill tokens
-                InferStreamResponse::Prefill(tokens) => {
-                    // Create Token objects
-                    // We do that here instead of in the Python code as Rust for loops are faster
-                    result_prefill = tokens
-                        .ids
-                        .into_iter()
-                        .zip(tokens.logprobs.into_iter())
-                        .zip(tokens.texts.into_iter())
-                        .map(|((id, logprob), text)| PrefillToken { id, text, logprob })
-                        .collect();
-                }
-                // Push last token
-                InferStreamResponse::Token(token) => result_tokens.push(token),
-                // Final message
-                // Set return values
-                InferStreamResponse::End {
-                    token,
-                    generated_text,
-                    start,
-                    queued,
-                } => {
-                    result_tokens.push(token);
-                    result_generated_text = Some(generated_text);
-                    result_start = Some(start);
-                    result_queued = Some(queued)
-                }
-            }
-        }
+    pub fn cache_utilization_threshold(mut self, cache_utilization_threshold: f64) -> Self {
+        self.cache_utilization_threshold = cache_utilization_threshold;
+        self
+    }
 
-        // Check that we received a `InferStreamResponse::End` message
-        if let (Some(generated_text), Some(queued), Some(start)) =
-            (result_generated_text, result_queued, result_start)
-        {
-            Ok(InferResponse {
-                prefill: result_prefill,
-                tokens: result_tokens,
-                generated_text,
-                queued,
-                start,
-            })
-        } else {
-            let err = InferError::IncompleteGeneration;
-            metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
-            tracing::error!("{err}");
-            Err(err)
-        }
+    pub fn batching_policy(mut self, batching_policy: BatchingPolicy) -> Self {
+        self.batching_policy = batching_policy;
+        self
     }
-    /// Add best_of new requests to the queue and return a InferResponse of the sequence with
-    /// the highest log probability per token
-    #[instrument(skip(self))]
-    pub(crate) async fn generate_best_of(
-        &self,
-        request: GenerateRequest,
-        best_of: usize,
-    ) -> Result<(InferResponse, Vec<InferResponse>), InferError> {
-        // validate  best_of parameter separately
-        let best_of = self.validation.validate_best_of(best_of)?;
 
-        // create multiple generate requests
-        let mut infer_responses: Vec<InferResponse> =
-            try_join_all((0..best_of).map(|_| self.generate(request.clone()))).await?;
+    pub fn all_latency_sensitive(mut self, all_latency_sensitive: bool) -> Self {
+        self.all_latency_sensitive = all_latency_sensitive;
+        self
+    }
 
-        // get the sequence with the highest log probability per token
-        let mut max_index = 0;
-        let mut max_logprob: f32 = f32::MIN;
+    pub fn force_watermark(mut self, force_watermark: bool) -> Self {
+        self.force_watermark = force_watermark;
+        self
+    }
 
-        for (i, response) in infer_responses.iter().enumerate() {
-            // mean logprobs of the generated tokens
-            let sequence_logprob = response
-                .tokens
-                .iter()
-                .map(|token| token.logprob)
-                .sum::<f32>()
-                / response.tokens.len() as f32;
+    pub fn max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = max_concurrent_requests;
+        self
+    }
 
-            // set best sequence
-            if sequence_logprob > max_logprob {
-                max_index = i;
-                max_logprob = sequence_logprob;
-            }
-        }
-        let best_response = infer_responses.remove(max_index);
-        Ok((best_response, infer_responses))
+    pub fn heartbeat_interval(mut self, heartbeat_interval: Option<Duration>) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
     }
-}
 
-/// Batching logic
-/// Will be launched in a background Tokio task
-///
-/// Batches requests and sends them to the inference server
-async fn batching_task(
-    mut client: ShardedClient,
-    max_batch_size: usize,
-    max_waiting_tokens: usize,
-    queue: Queue,
-    shared: Arc<Shared>,
-) {
-    // Minimum batch size after which we try to add more requests
-    let limit_min_batch_size = if max_batch_size > 1 {
-        (max_batch_size / 2) as u32
-    } else {
-        0
-    };
+    pub(crate) fn input_hook(mut self, input_hook: Option<InputHook>) -> Self {
+        self.input_hook = input_hook;
+        self
+    }
 
-    // Infinite loop
-    loop {
-        // Wait for a notification from the Infer struct
-        shared.batching_task.notified().await;
+    pub fn trace_requests(mut self, trace_requests: bool) -> Self {
+        self.trace_requests = trace_requests;
+        self
+    }
 
-        // Get the next batch from the queue
-        // This batch might be smaller than the maximum batch size if there are not enough requests
-        // waiting in the queue
-        while let Some((mut entries, batch, span)) = queue.next_batch(None, max_batch_size).await {
-            let mut cached_batch = prefill(&mut client, batch, &mut entries)
-                .instrument(span)
-                .await;
-            let mut waiting_tokens = 1;
+    pub fn faults(mut self, faults: Option<FaultConfig>) -> Self {
+        self.faults = faults;
+        self
+    }
 
-            // We loop until we do not receive any cached batch from the inference server (== until
-            // all requests have met their stopping criteria)
-            while let Some(batch) = cached_batch {
-                // Get current batch info
-                let batch_size = batch.size;
-                let mut batches = vec![batch];
-                metrics::gauge!("tgi_batch_current_size", batch_size as f64);
+    pub fn max_queue_wait(mut self, max_queue_wait: Option<Duration>) -> Self {
+        self.max_queue_wait = max_queue_wait;
+        self
+    }
 
-                // If the current batch is too small, we try to add more requests to it
-                if batch_size <= limit_min_batch_size {
+    pub fn force_queue_api_keys(mut self, force_queue_api_keys: HashSet<String>) -> Self {
+        self.force_queue_api_keys = force_queue_api_keys;
+        self
+    }
+
+    pub fn circuit_breaker(mut self, circuit_breaker: CircuitBreakerConfig) -> Self {
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    pub fn auto_requeue(mut self, auto_requeue: bool) -> Self {
+        self.auto_requeue = auto_requeue;
+        self
+    }
+
+    pub(crate) fn replay_log(mut self, replay_log: ReplayLog) -> Self {
+        self.replay_log = replay_log;
+        self
+    }
+
+    pub fn loading_policy(mut self, loading_policy: LoadingPolicy) -> Self {
+        self.loading_policy = loading_policy;
+        self
+    }
+
+    pub fn debug_batching(mut self, debug_batching: bool) -> Self {
+        self.debug_batching = debug_batching;
+        self
+    }
+
+    pub fn coalesce_requests(mut self, coalesce_requests: bool) -> Self {
+        self.coalesce_requests = coalesce_requests;
+        self
+    }
+
+    pub(crate) fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub(crate) fn speculation(mut self, speculation: Option<Speculation>) -> Self {
+        self.speculation = speculation;
+        self
+    }
+
+    pub fn latency_target(mut self, latency_target: Option<LatencyTarget>) -> Self {
+        self.latency_target = latency_target;
+        self
+    }
+
+    pub fn reserved_probe_permits(mut self, reserved_probe_permits: usize) -> Self {
+        self.reserved_probe_permits = reserved_probe_permits;
+        self
+    }
+
+    pub fn max_excess_tokens(mut self, max_excess_tokens: u32) -> Self {
+        self.max_excess_tokens = max_excess_tokens;
+        self
+    }
+
+    /// Limits of the sessions of the requests with a `session_id`, 0 disables them
+    pub fn session_limits(mut self, max_sessions: usize, max_session_bytes: usize) -> Self {
+        self.max_sessions = max_sessions;
+        self.max_session_bytes = max_session_bytes;
+        self
+    }
+
+    /// Run the batching tasks on `runtime` instead of the current runtime
+    pub fn batching_runtime(mut self, runtime: Option<Handle>) -> Self {
+        self.batching_runtime = runtime;
+        self
+    }
+
+    /// Batch the requests in arrival order into batches of `max_batch_size`, without adding
+    /// requests to the running batch nor adapting the batches to the backend
+    pub fn deterministic_batching(mut self, deterministic_batching: bool) -> Self {
+        self.deterministic_batching = deterministic_batching;
+        self
+    }
+
+    /// Start the batching tasks of the backends
+    /// Must be called from a Tokio runtime
+    pub fn build(self) -> Infer {
+        Infer::new(
+            self.client,
+            self.backend,
+            self.canary_client,
+            self.canary_ratio,
+            self.validation,
+            self.max_batch_size,
+            self.max_waiting_tokens,
+            self.prefill_chunk_tokens,
+            self.max_batch_total_tokens,
+            self.min_downgraded_new_tokens,
+            self.cache_utilization_threshold,
+            self.batching_policy,
+            self.all_latency_sensitive,
+            self.force_watermark,
+            self.max_concurrent_requests,
+            self.heartbeat_interval,
+            self.input_hook,
+            self.trace_requests,
+            self.faults,
+            self.max_queue_wait,
+            self.force_queue_api_keys,
+            self.circuit_breaker,
+            self.auto_requeue,
+            self.replay_log,
+            self.loading_policy,
+            self.debug_batching,
+            self.coalesce_requests,
+            self.speculation,
+            self.latency_target,
+            self.reserved_probe_permits,
+            self.max_excess_tokens,
+            self.batching_runtime,
+            self.deterministic_batching,
+            self.max_sessions,
+            self.max_session_bytes,
+        )
+    }
+}
+
+impl Infer {
+    /// Builder of an `Infer` generating with `client` the requests checked by `validation`
+    pub fn builder(client: BackendConnection, validation: Validation) -> InferBuilder {
+        InferBuilder {
+            client,
+            backend: Backend::Stable,
+            canary_client: None,
+            canary_ratio: 0.0,
+            validation,
+            max_batch_size: 32,
+            max_waiting_tokens: 20,
+            prefill_chunk_tokens: None,
+            max_batch_total_tokens: None,
+            min_downgraded_new_tokens: 16,
+            cache_utilization_threshold: 0.9,
+            batching_policy: BatchingPolicy::Throughput,
+            all_latency_sensitive: false,
+            force_watermark: false,
+            max_concurrent_requests: 128,
+            heartbeat_interval: None,
+            input_hook: None,
+            trace_requests: false,
+            faults: None,
+            max_queue_wait: None,
+            force_queue_api_keys: HashSet::new(),
+            circuit_breaker: CircuitBreakerConfig {
+                threshold: 5,
+                probe_interval: Duration::from_secs(5),
+            },
+            auto_requeue: false,
+            replay_log: ReplayLog::default(),
+            loading_policy: LoadingPolicy::Reject,
+            debug_batching: false,
+            coalesce_requests: false,
+            speculation: None,
+            latency_target: None,
+            reserved_probe_permits: 1,
+            max_excess_tokens: 16,
+            batching_runtime: None,
+            deterministic_batching: false,
+            max_sessions: 10000,
+            max_session_bytes: 64 * 1024 * 1024,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        client: BackendConnection,
+        backend: Backend,
+        canary_client: Option<BackendConnection>,
+        canary_ratio: f32,
+        validation: Validation,
+        max_batch_size: usize,
+        max_waiting_tokens: usize,
+        prefill_chunk_tokens: Option<u32>,
+        max_batch_total_tokens: Option<u32>,
+        min_downgraded_new_tokens: u32,
+        cache_utilization_threshold: f64,
+        batching_policy: BatchingPolicy,
+        all_latency_sensitive: bool,
+        force_watermark: bool,
+        max_concurrent_requests: usize,
+        heartbeat_interval: Option<Duration>,
+        input_hook: Option<InputHook>,
+        trace_requests: bool,
+        faults: Option<FaultConfig>,
+        max_queue_wait: Option<Duration>,
+        force_queue_api_keys: HashSet<String>,
+        circuit_breaker: CircuitBreakerConfig,
+        auto_requeue: bool,
+        replay_log: ReplayLog,
+        loading_policy: LoadingPolicy,
+        debug_batching: bool,
+        coalesce_requests: bool,
+        speculation: Option<Speculation>,
+        latency_target: Option<LatencyTarget>,
+        reserved_probe_permits: usize,
+        max_excess_tokens: u32,
+        batching_runtime: Option<Handle>,
+        deterministic_batching: bool,
+        max_sessions: usize,
+        max_session_bytes: usize,
+    ) -> Self {
+        let stable = BackendQueue::new(
+            client,
+            faults.clone(),
+            backend,
+            max_batch_size,
+            max_waiting_tokens,
+            prefill_chunk_tokens,
+            max_batch_total_tokens,
+            min_downgraded_new_tokens,
+            cache_utilization_threshold,
+            batching_policy,
+            circuit_breaker,
+            replay_log.clone(),
+            debug_batching,
+            speculation,
+            latency_target,
+            deterministic_batching,
+            batching_runtime.as_ref(),
+        );
+        let canary = canary_client.map(|client| {
+            BackendQueue::new(
+                client,
+                faults,
+                Backend::Canary,
+                max_batch_size,
+                max_waiting_tokens,
+                prefill_chunk_tokens,
+                max_batch_total_tokens,
+                min_downgraded_new_tokens,
+                cache_utilization_threshold,
+                batching_policy,
+                circuit_breaker,
+                replay_log,
+                debug_batching,
+                None,
+                latency_target,
+                deterministic_batching,
+                batching_runtime.as_ref(),
+            )
+        });
+
+        // Inference limit with a semaphore
+        let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
+
+        Self {
+            validation,
+            stable,
+            canary,
+            canary_ratio,
+            registry: Registry::new(trace_requests),
+            sessions: Sessions::new(max_sessions, max_session_bytes),
+            limit_concurrent_requests: semaphore,
+            max_concurrent_requests,
+            probe_permits: Arc::new(Semaphore::new(reserved_probe_permits)),
+            heartbeat_interval,
+            input_hook,
+            all_latency_sensitive,
+            force_watermark,
+            max_queue_wait,
+            force_queue_api_keys: Arc::new(force_queue_api_keys),
+            auto_requeue,
+            loading_policy,
+            coalescer: Coalescer::new(coalesce_requests),
+            max_excess_tokens,
+            deterministic_batching,
+            model_name: Arc::from(""),
+            models: Arc::new(BTreeMap::new()),
+        }
+    }
+
+    /// Serve the other `models` next to the model `model_name`, selected by the `model` of the
+    /// requests
+    /// The requests of all the models share the registry of this one
+    pub(crate) fn with_models(
+        mut self,
+        model_name: &str,
+        models: impl IntoIterator<Item = (String, Infer)>,
+    ) -> Self {
+        let models = models
+            .into_iter()
+            .map(|(name, mut infer)| {
+                infer.model_name = Arc::from(name.as_str());
+                infer.registry = self.registry.clone();
+                (name, infer)
+            })
+            .collect();
+        self.model_name = Arc::from(model_name);
+        self.models = Arc::new(models);
+        self
+    }
+
+    /// Infer of the model `name`, this one if it is not set
+    fn model(&self, name: Option<&str>) -> Result<&Infer, InferError> {
+        match name {
+            None => Ok(self),
+            Some(name) if name == &*self.model_name => Ok(self),
+            Some(name) => self.models.get(name).ok_or_else(|| {
+                let err = InferError::UnknownModel {
+                    model: name.to_string(),
+                    available: self.model_names().into_iter().map(String::from).collect(),
+                };
+                metrics::increment_counter!("tgi_request_failure", "err" => "unknown_model");
+                tracing::error!("{err}");
+                err
+            }),
+        }
+    }
+
+    /// Names of the served models, starting with the model of the router
+    pub(crate) fn model_names(&self) -> Vec<&str> {
+        std::iter::once(&*self.model_name)
+            .chain(self.models.keys().map(String::as_str))
+            .collect()
+    }
+
+    /// This Infer and the ones of the other served models
+    fn all_models(&self) -> impl Iterator<Item = &Infer> {
+        std::iter::once(self).chain(self.models.values())
+    }
+
+    /// Pick the backend of a request
+    /// `requested` overrides the split ratio. Requests are sent to the stable backend if the
+    /// canary backend is not configured or unhealthy
+    pub(crate) fn route(&self, requested: Option<&str>) -> Backend {
+        match self.backend_healthy(Backend::Canary) && self.canary_requested(requested) {
+            true => Backend::Canary,
+            false => Backend::Stable,
+        }
+    }
+
+    /// Pick the backend of a request as `route` does and pin the request to it
+    /// The requests for an unhealthy canary backend spill over to the stable backend, except one
+    /// every probe interval of its circuit breaker that checks if it recovered
+    pub(crate) fn route_request(
+        &self,
+        requested: Option<&str>,
+        context: &mut RequestContext,
+    ) -> Backend {
+        let backend = match &self.canary {
+            Some(canary) if self.canary_requested(requested) => {
+                if self.backend_healthy(Backend::Canary) {
+                    Backend::Canary
+                } else if canary.shared.admit_probe() {
+                    metrics::increment_counter!("tgi_backend_probe", "backend" => Backend::Canary.as_str());
+                    context.admitted = true;
+                    Backend::Canary
+                } else {
+                    Backend::Stable
+                }
+            }
+            _ => Backend::Stable,
+        };
+        context.backend = Some(backend);
+        backend
+    }
+
+    /// `requested` overrides the split ratio
+    fn canary_requested(&self, requested: Option<&str>) -> bool {
+        match requested {
+            Some("canary") => true,
+            Some("stable") => false,
+            _ => rand::thread_rng().gen::<f32>() < self.canary_ratio,
+        }
+    }
+
+    /// All the concurrent requests are running
+    fn overloaded(&self) -> InferError {
+        let running = self
+            .max_concurrent_requests
+            .saturating_sub(self.limit_concurrent_requests.available_permits());
+        metrics::increment_counter!("tgi_request_failure", "err" => "overloaded", "reason" => "concurrency");
+        let err = InferError::Overloaded {
+            limit: self.max_concurrent_requests,
+            running,
+        };
+        tracing::error!("{err}");
+        err
+    }
+
+    pub(crate) fn has_canary(&self) -> bool {
+        self.canary.is_some()
+    }
+
+    /// Wait until the stable backend has loaded its model
+    pub async fn wait_ready(&self) {
+        while !self.stable.shared.ready.load(Ordering::SeqCst) {
+            tokio::time::sleep(READY_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Returns false if the backend is not configured, cannot be reached, is still loading or its
+    /// batching task stopped
+    pub(crate) fn backend_healthy(&self, backend: Backend) -> bool {
+        let backend = match backend {
+            Backend::Stable => Some(&self.stable),
+            Backend::Canary => self.canary.as_ref(),
+        };
+        backend.map_or(false, |backend| {
+            backend.shared.healthy.load(Ordering::SeqCst)
+                && backend.shared.ready.load(Ordering::SeqCst)
+                && !backend.shared.stopped.load(Ordering::SeqCst)
+        })
+    }
+
+    /// State of the circuit breakers of the configured backends
+    pub(crate) fn circuit_breakers(&self) -> Vec<CircuitBreakerStatus> {
+        self.backends()
+            .map(|queue| CircuitBreakerStatus {
+                backend: queue.shared.backend.as_str(),
+                state: queue.shared.breaker.state(),
+                consecutive_failures: queue.shared.breaker.consecutive_failures(),
+            })
+            .collect()
+    }
+
+    /// Latest KV-cache usage of the backends that report it
+    pub(crate) fn cache_utilization(&self) -> Vec<CacheUtilization> {
+        self.backends()
+            .filter_map(|queue| queue.shared.cache_utilization())
+            .collect()
+    }
+
+    fn backend_queue(&self, backend: Backend) -> &BackendQueue {
+        match (backend, &self.canary) {
+            (Backend::Canary, Some(canary)) => canary,
+            _ => &self.stable,
+        }
+    }
+
+    /// Backends of all the served models
+    fn backends(&self) -> impl Iterator<Item = &BackendQueue> {
+        self.all_models()
+            .flat_map(|infer| std::iter::once(&infer.stable).chain(infer.canary.as_ref()))
+    }
+
+    /// Queues of all the backends
+    fn queues(&self) -> impl Iterator<Item = &Queue> {
+        self.backends().map(|backend| &backend.queue)
+    }
+
+    /// Register a new request
+    /// The returned handle id can be used to poll the request status or to cancel it
+    pub(crate) fn register(&self) -> Arc<RequestHandle> {
+        self.registry.register()
+    }
+
+    /// Start the ids of the new requests at `next_id` or after
+    pub(crate) fn reserve_ids(&self, next_id: u64) {
+        self.registry.reserve(next_id)
+    }
+
+    /// Register a request restored with its id, reserved with `reserve_ids`
+    pub(crate) fn register_id(&self, id: u64) -> Arc<RequestHandle> {
+        self.registry.register_id(id)
+    }
+
+    /// Text of a recently completed request, to continue it
+    /// None if the request was sent with another API key than `api_key_id`
+    pub(crate) fn continuation(
+        &self,
+        request_id: u64,
+        api_key_id: Option<&str>,
+    ) -> Option<Continuation> {
+        let handle = self.registry.get(request_id)?;
+        if handle.owner().as_deref() != api_key_id {
+            return None;
+        }
+        handle.continuation()
+    }
+
+    /// The requests with a `session_id` are started in their session
+    pub(crate) fn sessions_enabled(&self) -> bool {
+        self.sessions.enabled()
+    }
+
+    /// Start the request in its session if it has a `session_id`
+    /// The backend is told the length of the prefix shared with the previous request of the
+    /// session, it still receives the full inputs
+    pub(crate) fn start_session(&self, request: &GenerateRequest, context: &mut RequestContext) {
+        let session_id = match request.parameters.session_id.clone() {
+            Some(session_id) => session_id,
+            None => return,
+        };
+        // Unknown models are rejected by `prepare`
+        let infer = self.model(request.model.as_deref()).unwrap_or(self);
+        let session = infer.sessions.start(session_id, &request.inputs);
+        metrics::increment_counter!("tgi_session_prefix", "hit" => session.hit().to_string());
+        context.session = Some(session);
+    }
+
+    /// Number of queued and running requests
+    pub(crate) fn request_counts(&self) -> (usize, usize) {
+        self.registry.counts()
+    }
+
+    /// Snapshot of the queue of each backend
+    pub(crate) async fn queue_status(&self) -> Vec<QueueStatus> {
+        let mut statuses = Vec::new();
+        for backend in self.backends() {
+            let mut status = backend.queue.snapshot().await;
+            status.backend = backend.shared.backend.as_str();
+            status.cache_utilization = backend.shared.cache_utilization();
+            status.latency_budget = backend.shared.latency_budget();
+            statuses.push(status);
+        }
+        statuses
+    }
+
+    /// Statistics of the shards of each connected backend
+    pub(crate) fn shard_status(&self) -> Vec<ShardStatus> {
+        let mut statuses = Vec::new();
+        for backend in self.backends() {
+            if let Some(stats) = backend.shared.shard_stats.lock().as_ref() {
+                let backend = backend.shared.backend.as_str();
+                statuses.extend(shard_status(backend, stats.snapshot()));
+            }
+        }
+        statuses
+    }
+
+    /// Ignore `force_queue` unless the request uses one of the allowed API keys
+    pub(crate) fn authorize_force_queue(
+        &self,
+        api_key: Option<&str>,
+        parameters: &mut GenerateParameters,
+    ) {
+        let allowed = api_key.map_or(false, |api_key| self.force_queue_api_keys.contains(api_key));
+        if parameters.force_queue && !allowed {
+            tracing::warn!("Ignoring `force_queue`: the API key is not allowed to force queuing");
+            parameters.force_queue = false;
+        }
+    }
+
+    /// Apply the limit profile of the API key of a request sent to `model`
+    pub(crate) fn apply_limits(
+        &self,
+        api_key: Option<&str>,
+        model: Option<&str>,
+        context: &mut RequestContext,
+    ) {
+        // Unknown models are rejected by `prepare`
+        let infer = self.model(model).unwrap_or(self);
+        context.limits = Some(infer.validation.limits(api_key));
+    }
+
+    /// Limits of the requests sent with `api_key`
+    pub(crate) fn limits(&self, api_key: Option<&str>) -> Limits {
+        self.validation.limits(api_key)
+    }
+
+    /// Check the model of a request, then resolve its parameter preset and its inputs
+    pub(crate) fn prepare(
+        &self,
+        request: &mut GenerateRequest,
+        context: &mut RequestContext,
+    ) -> Result<(), InferError> {
+        let infer = self.model(request.model.as_deref())?;
+        self.validation.resolve_preset(request)?;
+        infer.validation.normalize_top_k(&mut request.parameters);
+        Ok(self.validation.resolve_inputs(request, context)?)
+    }
+
+    /// Validate a request prepared by `prepare` and pick its backend as `generate` would, without
+    /// queuing it
+    /// It does not take a concurrency permit and the pre-generation hook is not run
+    #[instrument(skip_all)]
+    pub(crate) async fn dry_run(
+        &self,
+        mut request: GenerateRequest,
+        mut context: RequestContext,
+    ) -> Result<DryRun, InferError> {
+        let infer = self.model(request.model.as_deref())?;
+        if let Some(best_of) = request.parameters.best_of {
+            infer.validation.validate_best_of(best_of)?;
+        }
+        if infer.force_watermark {
+            request.parameters.watermark = Some(true);
+        }
+        infer
+            .validation
+            .validate_params(&mut request, &mut context)?;
+
+        let backend = infer.backend_queue(context.backend.unwrap_or_else(|| infer.route(None)));
+        let estimated_wait = backend.estimated_wait().await;
+        let request = infer.validation.validate_input(request, &context).await?;
+        Ok(DryRun {
+            model: infer.model_name.to_string(),
+            backend: backend.shared.backend.as_str(),
+            estimated_wait,
+            request,
+        })
+    }
+
+    /// Add a new request to the queue and return a stream of InferStreamResponse
+    #[instrument(skip(self, handle), fields(request_id = handle.id))]
+    pub(crate) async fn generate_stream(
+        &self,
+        request: GenerateRequest,
+        context: RequestContext,
+        handle: Arc<RequestHandle>,
+    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        let result = match self.model(request.model.as_deref()) {
+            Ok(infer) => {
+                if let Some(model) = &request.model {
+                    handle.set_model(model.clone());
+                }
+                infer.enqueue(request, context, handle.clone()).await
+            }
+            Err(err) => Err(err),
+        };
+        result.map_err(|err| {
+            transition!(handle, "failed", error_type = err.error_code());
+            match err {
+                InferError::Cancelled => handle.finish(RequestStatus::Cancelled, None),
+                _ => handle.finish(RequestStatus::Failed, Some(err.to_string())),
+            };
+            err
+        })
+    }
+
+    async fn enqueue(
+        &self,
+        mut request: GenerateRequest,
+        mut context: RequestContext,
+        handle: Arc<RequestHandle>,
+    ) -> Result<UnboundedReceiverStream<Result<InferStreamResponse, InferError>>, InferError> {
+        transition!(
+            handle,
+            "received",
+            inputs_length = request.inputs.len(),
+            max_new_tokens = request.parameters.max_new_tokens
+        );
+        // A deadline too far to be represented is no deadline
+        let deadline = request
+            .parameters
+            .deadline_ms
+            .and_then(|deadline_ms| Instant::now().checked_add(Duration::from_millis(deadline_ms)));
+        let heartbeat_interval = self
+            .heartbeat_interval
+            .filter(|_| request.parameters.heartbeat);
+
+        // Limit concurrent requests by acquiring a permit from the semaphore
+        // Health probes use the reserved permits so that they are not rejected under load
+        // This permit will live as long as Entry
+        let probe = context.probe;
+        // Health probes report the current state of the backend
+        let auto_requeue = !probe && request.parameters.auto_requeue.unwrap_or(self.auto_requeue);
+        let latency_sensitive = request.parameters.latency_sensitive || self.all_latency_sensitive;
+        let allow_downgrade = request.parameters.allow_downgrade;
+        if self.force_watermark {
+            request.parameters.watermark = Some(true);
+        }
+
+        // Reject invalid parameters before taking a permit, the inputs are tokenized once the
+        // request holds one
+        self.validation
+            .validate_params(&mut request, &mut context)?;
+
+        let backend = self.backend_queue(context.backend.unwrap_or_else(|| self.route(None)));
+
+        // Fail fast without taking a permit while the backend cannot be reached
+        // Health probes go through the breaker too so that the router reports it is not ready
+        let admitted = match context.admitted {
+            true => Ok(()),
+            false => backend.shared.breaker.admit(),
+        };
+        if let Err(retry_after) = admitted {
+            metrics::increment_counter!("tgi_request_failure", "err" => "circuit_open");
+            let err = InferError::CircuitOpen(retry_after);
+            tracing::error!("{err}");
+            return Err(err);
+        }
+
+        // The health probes fail too, the router cannot recover without a restart
+        if backend.shared.stopped.load(Ordering::SeqCst) {
+            metrics::increment_counter!("tgi_request_failure", "err" => "batching_stopped");
+            let err = InferError::BackendUnavailable("the batching task stopped".to_string());
+            tracing::error!("{err}");
+            return Err(err);
+        }
+
+        // The backend is still connecting or loading its model
+        // Queued requests are batched in arrival order once it is ready
+        let loading = !backend.shared.ready.load(Ordering::SeqCst);
+        if loading && (probe || self.loading_policy == LoadingPolicy::Reject) {
+            metrics::increment_counter!("tgi_request_failure", "err" => "model_loading");
+            let err = InferError::ModelLoading;
+            tracing::error!("{err}");
+            return Err(err);
+        }
+
+        // Identical deterministic requests share the generation of the first one
+        // The requests joining it do not take a permit
+        let coalesce_key = self
+            .coalescer
+            .key(&request, &context, backend.shared.backend.as_str());
+        if let Some(key) = &coalesce_key {
+            if let Some(stream) = self.coalescer.join(key, &handle) {
+                transition!(
+                    handle,
+                    "coalesced",
+                    backend = backend.shared.backend.as_str()
+                );
+                return Ok(stream);
+            }
+        }
+
+        let permit = match probe {
+            true => self
+                .clone()
+                .probe_permits
+                .acquire_owned()
+                .await
+                .expect("probe semaphore is closed. This is a bug."),
+            false => match context.permit.take().and_then(|permit| permit.take()) {
+                Some(permit) => permit,
+                None => self
+                    .clone()
+                    .limit_concurrent_requests
+                    .try_acquire_owned()
+                    .map_err(|_| self.overloaded())?,
+            },
+        };
+
+        let mut session = context.session.take();
+        let api_key_id = context.api_key_id.take();
+        handle.set_owner(api_key_id.clone());
+        if let Some(sampled) = context.trace_sampled {
+            handle.set_sampled(sampled);
+        }
+        if let Some(continued) = context.continued.take() {
+            handle.set_continued(continued);
+        }
+        let inputs_length = request.inputs.len();
+
+        // Reject the request if it would wait too long in the queue
+        if let Some(max_queue_wait) = self.max_queue_wait {
+            if !probe && !request.parameters.force_queue {
+                if let Some(estimated_wait) = backend.estimated_wait().await {
+                    if estimated_wait > max_queue_wait {
+                        metrics::increment_counter!("tgi_request_failure", "err" => "queue_wait", "reason" => "admission");
+                        let err = InferError::QueueWait {
+                            estimated_wait,
+                            max_queue_wait,
+                        };
+                        tracing::error!("{err}");
+                        return Err(err);
+                    }
+                }
+            }
+        }
+
+        // Keep the request to validate it again if the hook modifies its inputs
+        // Health probes are not moderated
+        let input_hook = self.input_hook.as_ref().filter(|_| !probe);
+        let hooked_request = input_hook.map(|_| request.clone());
+
+        // Validate request
+        let mut valid_request = self.validation.validate_input(request, &context).await?;
+
+        // Run the pre-generation hook
+        if let (Some(input_hook), Some(mut hooked_request)) = (input_hook, hooked_request) {
+            let start_time = Instant::now();
+            let decision = input_hook.check(&valid_request).await;
+            let hook_time = start_time.elapsed();
+            handle.set_hook_time(hook_time);
+            metrics::histogram!("tgi_request_hook_duration", hook_time, "hook" => "pre_generation");
+
+            match decision? {
+                HookDecision::Allow => {}
+                HookDecision::Deny(reason) => {
+                    metrics::increment_counter!("tgi_request_failure", "err" => "blocked");
+                    return Err(InferError::Blocked(reason));
+                }
+                HookDecision::Modify(inputs) => {
+                    hooked_request.inputs = inputs;
+                    valid_request = self
+                        .validation
+                        .validate_input(hooked_request, &context)
+                        .await?;
+                }
+            }
+        }
+
+        handle.set_validation_timings(valid_request.timings);
+        handle.set_input_length(valid_request.input_length);
+
+        // Truncation removed the start of the inputs
+        if let Some(session) = &mut session {
+            if valid_request.inputs.len() != inputs_length {
+                session.truncated();
+            }
+        }
+
+        // The request was cancelled during validation
+        if handle.cancel_requested() {
+            return Err(InferError::Cancelled);
+        }
+        transition!(
+            handle,
+            "validated",
+            inputs_length = valid_request.inputs.len()
+        );
+
+        // MPSC channel to communicate with the background batching task
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+
+        // Periodically tell the client that the request is still waiting in the queue
+        if let Some(interval) = heartbeat_interval {
+            tokio::spawn(queue_heartbeat(
+                interval,
+                handle.clone(),
+                response_tx.downgrade(),
+            ));
+        }
+        let response_tx = self.coalescer.sender(coalesce_key, response_tx, &handle);
+
+        // Append the request to the queue
+        // Deterministic batching keeps the probes in arrival order
+        let priority = probe && !self.deterministic_batching;
+        let stop_buffer = StopBuffer::new(valid_request.stop_matcher.clone());
+        let token_pieces = valid_request
+            .raw_token_text
+            .then(|| self.validation.tokenizer());
+        let token_limit = valid_request
+            .stopping_parameters
+            .max_new_tokens
+            .saturating_add(self.max_excess_tokens);
+        transition!(handle, "queued", backend = backend.shared.backend.as_str());
+        backend.queue.append(Entry {
+            request: valid_request,
+            response_tx,
+            handle,
+            span: Span::current(),
+            temp_span: None,
+            queue_time: Instant::now(),
+            batch_time: None,
+            deadline,
+            heartbeat: heartbeat_interval.is_some(),
+            session,
+            stop_buffer,
+            priority,
+            probe: priority.then(|| QueuedProbe::new(backend.shared.queued_probes.clone())),
+            latency_sensitive,
+            auto_requeue,
+            allow_downgrade,
+            requested_max_new_tokens: None,
+            api_key_id,
+            token_pieces,
+            token_limit,
+            sent_text: String::new(),
+            permit: Permit::new(permit),
+        });
+
+        // Notify the background task that we have a new entry in the queue that needs
+        // to be batched
+        backend.shared.batching_task.notify_one();
+
+        // Return stream
+        Ok(UnboundedReceiverStream::new(response_rx))
+    }
+
+    /// Add a new request to the queue and return a InferResponse
+    /// Sampled requests with an empty generated text are retried up to `retry_on_empty` times
+    #[instrument(skip(self))]
+    pub(crate) async fn generate(
+        &self,
+        request: GenerateRequest,
+        context: RequestContext,
+    ) -> Result<InferResponse, InferError> {
+        let retries = request.parameters.retry_on_empty as u32;
+        let sampling = request.parameters.sampling();
+        // The retries must answer before the same timeout. A zero timeout fails validation and a
+        // timeout too far to be represented is no timeout
+        let response_timeout = request
+            .parameters
+            .response_timeout_ms
+            .filter(|timeout_ms| *timeout_ms > 0)
+            .and_then(|timeout_ms| Instant::now().checked_add(Duration::from_millis(timeout_ms)));
+
+        let mut response = self
+            .generate_once(request.clone(), context.clone(), response_timeout)
+            .await?;
+        while response.generated_text.text.trim().is_empty() {
+            // Greedy decoding would generate the same text again
+            if !sampling {
+                response.empty = retries > 0;
+                break;
+            }
+            if response.attempts > retries {
+                break;
+            }
+            metrics::increment_counter!("tgi_request_empty_retry");
+
+            // Retry with a new random seed
+            let mut retry_request = request.clone();
+            retry_request.parameters.seed = None;
+            let mut retry = self
+                .generate_once(retry_request, context.clone(), response_timeout)
+                .await?;
+            retry.attempts = response.attempts + 1;
+            retry.total_generated_tokens += response.total_generated_tokens;
+            response = retry;
+        }
+        Ok(response)
+    }
+
+    /// The request is cancelled if it is not done before `response_timeout`
+    async fn generate_once(
+        &self,
+        mut request: GenerateRequest,
+        context: RequestContext,
+        response_timeout: Option<Instant>,
+    ) -> Result<InferResponse, InferError> {
+        // Heartbeats are only useful to streaming clients
+        request.parameters.heartbeat = false;
+
+        // Create stream
+        let handle = self.register();
+        let generation = async {
+            let stream = self
+                .generate_stream(request, context, handle.clone())
+                .await?;
+            accumulate(stream, &handle).await
+        };
+        let response_timeout = match response_timeout {
+            None => return generation.await,
+            Some(response_timeout) => response_timeout,
+        };
+
+        match tokio::time::timeout_at(response_timeout, generation).await {
+            Ok(response) => response,
+            Err(_) => {
+                // Dropping the stream already stops the generation, cancelling the request also
+                // removes it from the queue so that its permit is released right away
+                self.cancel(handle.id).await;
+                let err = InferError::ResponseTimeout {
+                    input_length: handle.input_length(),
+                    generated_tokens: handle.generated_tokens(),
+                };
+                handle.finish(RequestStatus::Failed, Some(err.to_string()));
+                transition!(handle, "failed", error_type = err.error_code());
+                metrics::increment_counter!("tgi_request_failure", "err" => "response_timeout");
+                tracing::error!("{err}");
+                Err(err)
+            }
+        }
+    }
+    /// Add best_of new requests to the queue and return a InferResponse of the sequence with
+    /// the highest log probability per token
+    ///
+    /// Each sequence takes a permit, acquired before the sequences are enqueued: unless `strict_n`
+    /// is set, fewer sequences are generated when fewer permits are available. Each sequence is
+    /// validated with the limits of the API key of the request
+    #[instrument(skip(self))]
+    pub(crate) async fn generate_best_of(
+        &self,
+        request: GenerateRequest,
+        context: RequestContext,
+        best_of: usize,
+    ) -> Result<(InferResponse, Vec<InferResponse>), InferError> {
+        // The sequences take the permits of the model of the request
+        let infer = self.model(request.model.as_deref())?;
+        // validate  best_of parameter separately
+        let best_of = infer.validation.validate_best_of(best_of)?;
+
+        let strict = request.parameters.strict_n;
+        // Held by the sequences: concurrent requests cannot take them once counted
+        let permits: Vec<OwnedSemaphorePermit> = (0..best_of)
+            .map_while(|_| {
+                infer
+                    .limit_concurrent_requests
+                    .clone()
+                    .try_acquire_owned()
+                    .ok()
+            })
+            .collect();
+        let available = permits.len();
+        if available < best_of {
+            if available == 0 || strict {
+                drop(permits);
+                return Err(infer.overloaded());
+            }
+            tracing::warn!("Only {available} permits available for `best_of` {best_of}");
+            metrics::increment_counter!("tgi_request_best_of_degraded");
+        }
+
+        // create multiple generate requests
+        let mut infer_responses = Vec::with_capacity(available);
+        let generations = permits.into_iter().map(|permit| {
+            let context = RequestContext {
+                permit: Some(ReservedPermit(Arc::new(Mutex::new(Some(permit))))),
+                ..context.clone()
+            };
+            self.generate(request.clone(), context)
+        });
+        for result in join_all(generations).await {
+            match result {
+                Ok(response) => infer_responses.push(response),
+                // The retry of an empty sequence found no permit
+                Err(InferError::Overloaded { .. }) if !strict => {}
+                Err(err) => return Err(err),
+            }
+        }
+        if infer_responses.is_empty() {
+            return Err(infer.overloaded());
+        }
+
+        // get the sequence with the highest log probability per token
+        let mut max_index = 0;
+        let mut max_logprob: f32 = f32::MIN;
+
+        for (i, response) in infer_responses.iter().enumerate() {
+            // mean logprobs of the generated tokens
+            let sequence_logprob = response
+                .tokens
+                .iter()
+                .map(|token| token.logprob)
+                .sum::<f32>()
+                / response.tokens.len() as f32;
+
+            // set best sequence
+            if sequence_logprob > max_logprob {
+                max_index = i;
+                max_logprob = sequence_logprob;
+            }
+        }
+        let best_response = infer_responses.remove(max_index);
+        Ok((best_response, infer_responses))
+    }
+
+    /// Get the status of a live or recently finished request
+    #[instrument(skip(self))]
+    pub(crate) async fn status(&self, request_id: u64) -> Option<GenerationStatus> {
+        let handle = self.registry.get(request_id)?;
+        let status = handle.status();
+
+        let mut queue_position = None;
+        if status == RequestStatus::Queued {
+            for queue in self.queues() {
+                queue_position = queue.position(request_id).await;
+                if queue_position.is_some() {
+                    break;
+                }
+            }
+        }
+
+        Some(GenerationStatus {
+            id: request_id,
+            status,
+            queue_position,
+            generated_tokens: handle.generated_tokens(),
+            error: handle.error(),
+        })
+    }
+
+    /// Cancel a request
+    /// Queued requests are removed from the queue; running requests are cancelled on the
+    /// backend by the batching task after the current decode step
+    #[instrument(skip(self))]
+    pub(crate) async fn cancel(&self, request_id: u64) -> Option<GenerationStatus> {
+        let handle = self.registry.get(request_id)?;
+        handle.request_cancel();
+
+        for queue in self.queues() {
+            // The batching task detaches the request from the requests waiting for its generation
+            if handle.status() != RequestStatus::Queued || self.coalescer.has_followers(request_id)
+            {
+                break;
+            }
+            if let Some(entry) = queue.remove(request_id).await {
+                handle.finish(RequestStatus::Cancelled, None);
+                transition!(handle, "cancelled");
+                metrics::increment_counter!("tgi_request_failure", "err" => "cancelled");
+                // unwrap_or is valid here as we don't care if the receiver is gone.
+                entry
+                    .response_tx
+                    .send(Err(InferError::Cancelled))
+                    .unwrap_or(());
+                // `entry` is dropped here, releasing its permit
+            }
+        }
+
+        self.status(request_id).await
+    }
+}
+
+/// Accumulate the messages of a request stream in an InferResponse
+///
+/// The generation is complete once the `End` message is received: the messages received after it,
+/// errors included, are logged and ignored
+pub(crate) async fn accumulate(
+    mut stream: impl Stream<Item = Result<InferStreamResponse, InferError>> + Unpin,
+    handle: &RequestHandle,
+) -> Result<InferResponse, InferError> {
+    // Return values
+    let mut result_prefill = Vec::new();
+    let mut result_tokenization = None;
+    let mut result_prefill_mismatch = false;
+    let mut result_tokens = Vec::new();
+    let mut result = None;
+
+    // Iterate on stream
+    while let Some(response) = stream.next().await {
+        if result.is_some() {
+            tracing::warn!(
+                "Ignoring a message received after the end of the generation: {response:?}"
+            );
+            continue;
+        }
+        match response? {
+            // Add prefill tokens
+            InferStreamResponse::Prefill {
+                tokens,
+                tokenization,
+                mismatch,
+            } => {
+                // The offsets are only added if the backend tokenized the inputs as the router
+                let offsets = tokenization
+                    .as_ref()
+                    .map(|tokenization| tokenization.offsets.as_slice())
+                    .filter(|offsets| {
+                        let aligned = offsets.len() == tokens.ids.len();
+                        if !aligned {
+                            tracing::warn!(
+                                "The backend returned {} prefill tokens for {} input tokens, the offsets are not sent",
+                                tokens.ids.len(),
+                                offsets.len()
+                            );
+                        }
+                        aligned
+                    });
+                // Create Token objects
+                // We do that here instead of in the Python code as Rust for loops are faster
+                result_prefill = tokens
+                    .ids
+                    .into_iter()
+                    .zip(tokens.logprobs.into_iter())
+                    .zip(tokens.texts.into_iter())
+                    .enumerate()
+                    .map(|(i, ((id, logprob), text))| {
+                        let offsets = offsets.map(|offsets| offsets[i]);
+                        PrefillToken {
+                            id,
+                            text,
+                            logprob,
+                            start: offsets.map(|(start, _)| start),
+                            end: offsets.map(|(_, end)| end),
+                        }
+                    })
+                    .collect();
+                result_tokenization = tokenization;
+                result_prefill_mismatch = mismatch;
+            }
+            // Push last token
+            InferStreamResponse::Token(token) => result_tokens.push(token),
+            // Queue notifications
+            InferStreamResponse::Queued | InferStreamResponse::Started => {}
+            // Final message
+            // Set return values
+            InferStreamResponse::End {
+                token,
+                generated_text,
+                start,
+                queued,
+                parameters,
+                matched_stop,
+                token_count_mismatch,
+                router_limit,
+            } => {
+                result_tokens.push(token);
+                result = Some((
+                    generated_text,
+                    start,
+                    queued,
+                    parameters,
+                    matched_stop,
+                    token_count_mismatch,
+                    router_limit,
+                ));
+            }
+        }
+    }
+
+    // Check that we received a `InferStreamResponse::End` message
+    match result {
+        Some((
+            generated_text,
+            start,
+            queued,
+            parameters,
+            matched_stop,
+            token_count_mismatch,
+            router_limit,
+        )) => Ok(InferResponse {
+            request_id: handle.id,
+            hook_time: handle.hook_time(),
+            validation_timings: handle.validation_timings(),
+            input_length: handle.input_length(),
+            prefill: result_prefill,
+            prefill_mismatch: result_prefill_mismatch,
+            tokenization: result_tokenization,
+            tokens: result_tokens,
+            attempts: 1,
+            total_generated_tokens: generated_text.generated_tokens,
+            requeues: handle.requeues(),
+            empty: false,
+            generated_text,
+            queued,
+            start,
+            parameters,
+            matched_stop,
+            token_count_mismatch,
+            coalesced: handle.coalesced(),
+            router_limit,
+        }),
+        None => {
+            let err = InferError::IncompleteGeneration;
+            metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
+            tracing::error!("{err}");
+            Err(err)
+        }
+    }
+}
+
+/// Tokens reserved by the entries of a batch: their inputs and all the tokens they can generate
+fn batch_tokens(entries: &IntMap<u64, Entry>) -> u32 {
+    entries
+        .values()
+        .map(|entry| entry.request.input_length + entry.request.stopping_parameters.max_new_tokens)
+        .sum()
+}
+
+/// Token budget of the requests added to a running batch, shrunk linearly to 0 as the KV-cache
+/// utilization goes from `threshold` to 1
+fn cache_budget(tokens: u32, cache_usage: Option<CacheUsage>, threshold: f64) -> u32 {
+    let utilization = match cache_usage {
+        Some(cache_usage) => cache_usage.utilization(),
+        None => return tokens,
+    };
+    if utilization <= threshold {
+        return tokens;
+    }
+    let room = (1.0 - utilization) / (1.0 - threshold);
+    (tokens as f64 * room) as u32
+}
+
+/// Send a heartbeat every `interval` until the request leaves the queue
+///
+/// Only holds a weak sender so that it never keeps the response stream open
+async fn queue_heartbeat(
+    interval: Duration,
+    handle: Arc<RequestHandle>,
+    response_tx: WeakUnboundedSender<Result<InferStreamResponse, InferError>>,
+) {
+    let mut interval = tokio::time::interval_at(Instant::now() + interval, interval);
+    loop {
+        interval.tick().await;
+        if handle.status() != RequestStatus::Queued {
+            return;
+        }
+        match response_tx.upgrade() {
+            Some(response_tx) if response_tx.send(Ok(InferStreamResponse::Queued)).is_ok() => {}
+            // The client is gone or the request is finished
+            _ => return,
+        }
+    }
+}
+
+/// Mean time per generated token
+/// Zero if the backend reports no generated tokens
+pub(crate) fn mean_time_per_token(inference_time: Duration, generated_tokens: u32) -> Duration {
+    inference_time
+        .checked_div(generated_tokens)
+        .unwrap_or_default()
+}
+
+/// Statuses of the shards of `backend`
+pub(crate) fn shard_status(backend: &'static str, shards: Vec<ShardSnapshot>) -> Vec<ShardStatus> {
+    let millis = |duration: Duration| u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+    shards
+        .into_iter()
+        .map(|shard| ShardStatus {
+            backend,
+            uri: shard.uri,
+            healthy: shard.healthy,
+            last_error: shard.last_error,
+            p50_ms: shard.p50.map(millis),
+            p95_ms: shard.p95.map(millis),
+            slow: shard.slow,
+        })
+        .collect()
+}
+
+/// Minimum batch size after which we try to add more requests
+pub(crate) fn limit_min_batch_size(max_batch_size: usize) -> u32 {
+    if max_batch_size > 1 {
+        (max_batch_size / 2) as u32
+    } else {
+        0
+    }
+}
+
+/// Batching logic
+/// Will be launched in a background Tokio task
+///
+/// Batches requests and sends them to the inference server
+#[allow(clippy::too_many_arguments)]
+async fn batching_task(
+    client: ShardedClient,
+    faults: Option<FaultConfig>,
+    max_batch_size: usize,
+    max_waiting_tokens: usize,
+    prefill_chunk_tokens: Option<u32>,
+    max_batch_total_tokens: Option<u32>,
+    min_downgraded_new_tokens: u32,
+    cache_utilization_threshold: f64,
+    batching_policy: BatchingPolicy,
+    latency_target: Option<LatencyTarget>,
+    deterministic: bool,
+    queue: Queue,
+    shared: Arc<Shared>,
+) {
+    let mut client = connected(&shared, client, faults).await;
+    set_ready(&shared);
+
+    let limit_min_batch_size = limit_min_batch_size(max_batch_size);
+    let mut last_stale_check = Instant::now();
+    // Deterministic batching sends batches of `max_batch_size` in arrival order: the batches do
+    // not depend on the timings of the backend, and the prefill chunks are not added to a
+    // running batch
+    let latency_target = latency_target.filter(|_| !deterministic);
+    let prefill_chunk_tokens = prefill_chunk_tokens.filter(|_| !deterministic);
+    let full_batch = deterministic.then_some(max_batch_size);
+    // The batches are only limited by the static configuration without latency target
+    let mut latency_controller = latency_target.map(LatencyController::new);
+    if let Some(controller) = &latency_controller {
+        shared.set_latency_budget(controller.budget());
+    }
+    let token_budget = |entries: &IntMap<u64, Entry>, controller: Option<&LatencyController>| {
+        max_batch_total_tokens.map(|max_batch_total_tokens| {
+            let mut tokens = max_batch_total_tokens.saturating_sub(batch_tokens(entries));
+            // The backend frees the KV-cache of a batch once it finishes
+            // A new batch is not limited by the latency controller so that the queue never stalls
+            if !entries.is_empty() {
+                tokens = cache_budget(tokens, shared.cache_usage(), cache_utilization_threshold);
+                if let Some(controller) = controller {
+                    tokens = controller.token_budget(tokens);
+                }
+            }
+            TokenBudget {
+                tokens,
+                min_new_tokens: min_downgraded_new_tokens,
+            }
+        })
+    };
+
+    // Infinite loop
+    loop {
+        // Wait for a notification from the Infer struct
+        // A deterministic batch is sent before it is full once no request was queued for
+        // `DETERMINISTIC_FILL_TIMEOUT`
+        let mut min_size = match deterministic {
+            false => {
+                shared.batching_task.notified().await;
+                None
+            }
+            true => {
+                tokio::time::timeout(DETERMINISTIC_FILL_TIMEOUT, shared.batching_task.notified())
+                    .await
+                    .ok()
+                    .and(full_batch)
+            }
+        };
+
+        // Get the next batch from the queue
+        // This batch might be smaller than the maximum batch size if there are not enough requests
+        // waiting in the queue
+        while let Some((mut entries, batch, span)) = queue
+            .next_batch(
+                min_size,
+                allowed_batch_size(latency_controller.as_ref(), max_batch_size),
+                None,
+                token_budget(&IntMap::default(), latency_controller.as_ref()),
+            )
+            .await
+        {
+            // Only the first batch after the fill timeout can be smaller
+            min_size = full_batch;
+            let mut cached_batch = prefill(&mut client, batch, &mut entries, &queue, &shared)
+                .instrument(span)
+                .await;
+            let mut waiting_tokens = 1;
+            // The last batch added to the running batch may have been cut by
+            // `prefill_chunk_tokens`: its next chunk is prefilled after one decode
+            let mut chunking = false;
+            let mut cancel_supported = shared.cancel.load(Ordering::SeqCst);
+            // End of the previous decode step of the running batch
+            let mut previous_decode = None;
+
+            // We loop until we do not receive any cached batch from the inference server (== until
+            // all requests have met their stopping criteria)
+            while let Some(batch) = cached_batch {
+                // Get current batch info
+                let batch_size = batch.size;
+                let mut batches = vec![batch];
+                metrics::gauge!("tgi_batch_current_size", batch_size as f64, "backend" => shared.backend.as_str());
+
+                // If the current batch is too small, we try to add more requests to it
+                // The batching policy can keep latency sensitive requests from being paused
+                // Health probes are added even to a full batch. Each of them holds one of the
+                // reserved permits, so the batch exceeds `max_batch_size` by at most their number
+                // The latency controller stops adding requests once the batch has its allowed size
+                let latency_sensitive = entries.values().any(|entry| entry.latency_sensitive);
+                let probe_waiting = shared.queued_probes.load(Ordering::SeqCst) > 0;
+                let allowed_size = allowed_batch_size(latency_controller.as_ref(), max_batch_size);
+                let room = (batch_size as usize) < allowed_size;
+                let add_requests = room
+                    && (batch_size <= limit_min_batch_size || chunking)
+                    && batching_policy.allows_prefill(batch_size, latency_sensitive);
+                if !deterministic && (add_requests || probe_waiting) {
                     let min_size = match waiting_tokens {
                         // If we didn't onboard any new requests since >= max_waiting_tokens, we try
                         // to add a new batch even though its size might be small
                         _ if waiting_tokens >= max_waiting_tokens => None,
+                        // The next chunk is added even though its size might be small
+                        _ if chunking => None,
                         // Minimum size criteria
                         _ => Some(limit_min_batch_size as usize),
                     };
 
-                    // Try to get a new batch
-                    if let Some((mut new_entries, new_batch, span)) = queue
-                        .next_batch(min_size, max_batch_size - batch_size as usize)
-                        .await
-                    {
-                        let new_batch_size = new_batch.size;
-                        entries.iter_mut().for_each(|(_, entry)| {
-                            // Create a new span to add the info that this entry is waiting
-                            // because a new batch is being computed
-                            let entry_waiting_span =
-                                info_span!(parent: &entry.span, "waiting", batch_size = new_batch_size);
-                            // Add relationships
-                            span.follows_from(&entry_waiting_span);
-                            entry_waiting_span.follows_from(&span);
-                            // Update entry
-                            entry.temp_span = Some(entry_waiting_span);
-                        });
+                    // Try to get a new batch
+                    // Its prefill is limited to `prefill_chunk_tokens` so that it does not stall
+                    // the running batch for too long
+                    // Probes are batched before the other requests. Only the probes are added
+                    // when no other request can be
+                    let max_size = allowed_size.saturating_sub(batch_size as usize);
+                    chunking = false;
+                    let next_batch = match add_requests {
+                        true => {
+                            queue
+                                .next_batch(
+                                    min_size,
+                                    max_size,
+                                    prefill_chunk_tokens,
+                                    token_budget(&entries, latency_controller.as_ref()),
+                                )
+                                .await
+                        }
+                        false => queue.next_probes().await,
+                    };
+                    if let Some((mut new_entries, new_batch, span)) = next_batch {
+                        let new_batch_size = new_batch.size;
+                        chunking = add_requests
+                            && prefill_chunk_tokens.is_some()
+                            && (new_batch_size as usize) < max_size;
+                        entries.iter_mut().for_each(|(_, entry)| {
+                            // Create a new span to add the info that this entry is waiting
+                            // because a new batch is being computed
+                            let entry_waiting_span = entry.sampled_span(|| {
+                                info_span!(parent: &entry.span, "waiting", batch_size = new_batch_size)
+                            });
+                            // Add relationships
+                            span.follows_from(&entry_waiting_span);
+                            entry_waiting_span.follows_from(&span);
+                            // Update entry
+                            entry.temp_span = Some(entry_waiting_span);
+                        });
+
+                        // Generate one token for this new batch to have the attention past in cache
+                        let new_cached_batch =
+                            prefill(&mut client, new_batch, &mut new_entries, &queue, &shared)
+                                .instrument(span)
+                                .await;
+                        // Reset waiting counter
+                        waiting_tokens = 1;
+                        // Extend current batch with the new batch
+                        if let Some(new_cached_batch) = new_cached_batch {
+                            entries.extend(new_entries);
+                            batches.push(new_cached_batch);
+                        }
+                    }
+                }
+                // Create span for this batch to add context to inference calls
+                let next_batch_size = entries.len();
+                // Each entry generates one token per decode call
+                metrics::histogram!(
+                    "tgi_batch_efficiency",
+                    next_batch_size as f64 / max_batch_size as f64,
+                    "backend" => shared.backend.as_str()
+                );
+                let next_batch_span =
+                    info_span!(parent: None, "batch", batch_size = next_batch_size);
+                entries.iter_mut().for_each(|(_, entry)| {
+                    // Create a new span to link the batch back to this entry
+                    let entry_batch_span = entry.sampled_span(
+                        || info_span!(parent: &entry.span, "infer", batch_size = next_batch_size),
+                    );
+                    // Add relationships
+                    next_batch_span.follows_from(&entry_batch_span);
+                    entry_batch_span.follows_from(&next_batch_span);
+                    // Update entry
+                    entry.temp_span = Some(entry_batch_span);
+                });
+
+                shared.set_running_token_debt(&entries);
+                cached_batch = decode(
+                    &mut client,
+                    batches,
+                    &mut entries,
+                    &queue,
+                    &shared,
+                    previous_decode,
+                )
+                .instrument(next_batch_span)
+                .await;
+                let decode_end = Instant::now();
+                // The inter-token latency includes the prefill of the requests added to the batch
+                if let (Some(controller), Some(previous_decode)) =
+                    (&mut latency_controller, previous_decode)
+                {
+                    controller.record(decode_end - previous_decode);
+                    shared.set_latency_budget(controller.budget());
+                }
+                previous_decode = Some(decode_end);
+                waiting_tokens += 1;
+
+                // Free the backend from the aborted entries, at most once per decode step
+                // The backend may not support cancellation: it is not retried for this batch
+                if cancel_supported {
+                    cancel_supported =
+                        cancel_aborted(&mut client, &mut cached_batch, &mut entries, &shared).await;
+                }
+
+                if last_stale_check.elapsed() >= STALE_ENTRY_INTERVAL {
+                    entries.values().for_each(Entry::log_if_stale);
+                    last_stale_check = Instant::now();
+                }
+            }
+            metrics::gauge!("tgi_batch_current_size", 0.0, "backend" => shared.backend.as_str());
+            shared.set_running_token_debt(&IntMap::default());
+        }
+    }
+}
+
+/// Maximum size of the running batch allowed by the latency controller
+fn allowed_batch_size(controller: Option<&LatencyController>, max_batch_size: usize) -> usize {
+    controller.map_or(max_batch_size, |controller| {
+        controller.max_batch_size(max_batch_size)
+    })
+}
+
+/// Client of the batching task of a connected backend, its shard statistics being shared
+/// The cached batches of a previous task that panicked are cleared
+async fn connected(
+    shared: &Shared,
+    mut client: ShardedClient,
+    faults: Option<FaultConfig>,
+) -> BackendClient {
+    *shared.shard_stats.lock() = Some(client.stats());
+    // The aborted requests stay in the batches of the backends not implementing `Cancel` until
+    // they finish them
+    let cancel = client.info().await.map_or(false, |info| info.cancel);
+    if !cancel {
+        tracing::warn!(
+            "{} backend does not implement Cancel: the aborted requests are generated to the end",
+            shared.backend.as_str()
+        );
+    }
+    shared.cancel.store(cancel, Ordering::SeqCst);
+    let mut client = BackendClient::new(client, faults);
+    let _ = client.clear_cache(None).await;
+    client
+}
+
+/// Mark the backend as ready once it is connected and has loaded its model
+fn set_ready(shared: &Shared) {
+    if !shared.ready.swap(true, Ordering::SeqCst) {
+        tracing::info!("{} backend ready", shared.backend.as_str());
+        // Batch the requests queued while loading
+        shared.batching_task.notify_one();
+    }
+}
+
+/// Speculative decoding loop, replacing `batching_task` when the backend has a draft backend
+/// The requests are generated one at a time
+async fn speculative_task(
+    client: ShardedClient,
+    draft: ShardedClient,
+    draft_tokens: u32,
+    faults: Option<FaultConfig>,
+    queue: Queue,
+    shared: Arc<Shared>,
+) {
+    let mut draft = BackendClient::new(draft, faults.clone());
+    let _ = draft.clear_cache(None).await;
+    let mut client = connected(&shared, client, faults).await;
+    set_ready(&shared);
+
+    loop {
+        // Wait for a notification from the Infer struct
+        shared.batching_task.notified().await;
+
+        while let Some((mut entries, batch, span)) = queue.next_batch(None, 1, None, None).await {
+            speculate(
+                &mut client,
+                &mut draft,
+                batch,
+                &mut entries,
+                &queue,
+                &shared,
+                draft_tokens,
+            )
+            .instrument(span)
+            .await;
+            shared.set_running_token_debt(&IntMap::default());
+        }
+    }
+}
+
+/// Generate the requests of `batch` with the target backend `client`, checking up to
+/// `draft_tokens` tokens of the `draft` backend at each step
+/// The requests are decoded without draft tokens once the draft backend fails
+#[instrument(skip_all)]
+async fn speculate(
+    client: &mut BackendClient,
+    draft: &mut BackendClient,
+    batch: Batch,
+    entries: &mut IntMap<u64, Entry>,
+    queue: &Queue,
+    shared: &Shared,
+    draft_tokens: u32,
+) {
+    let start_time = Instant::now();
+    let batch_id = batch.id;
+    let backend = shared.backend.as_str();
+    entries
+        .values_mut()
+        .for_each(|entry| entry.batch_time = Some(start_time));
+
+    // Both backends prefill the requests, the first token is the one of the target backend
+    let draft_batch = Batch {
+        requests: batch.requests.iter().map(draft_request).collect(),
+        ..batch.clone()
+    };
+    let deadline = batch_deadline(entries);
+    let (target_prefill, draft_prefill) = tokio::join!(
+        client.prefill(batch, deadline),
+        draft.prefill(draft_batch, deadline)
+    );
+    let mut draft_batch = match draft_prefill {
+        Ok((_, draft_batch, _)) => draft_batch,
+        Err(err) => {
+            draft_failed(&err, backend);
+            let _ = draft.clear_cache(Some(batch_id)).await;
+            None
+        }
+    };
+    let (mut cached_batch, mut accepted) = match target_prefill {
+        Ok((generations, next_batch, cache_usage)) => {
+            shared.batch_succeeded();
+            shared.record_cache_usage(cache_usage);
+            let accepted = request_tokens(&generations);
+            send_generations(generations, entries);
+            metrics::histogram!("tgi_batch_inference_duration", start_time.elapsed(), "method" => "prefill", "backend" => backend);
+            metrics::increment_counter!("tgi_batch_inference_success", "method" => "prefill", "backend" => backend);
+            (next_batch, accepted)
+        }
+        Err(err) => {
+            shared.batch_failed(&err);
+            let _ = client.clear_cache(Some(batch_id)).await;
+            send_errors(err, entries, queue, shared);
+            metrics::increment_counter!("tgi_batch_inference_failure", "method" => "prefill", "backend" => backend);
+            (None, vec![])
+        }
+    };
+
+    let (mut proposed_tokens, mut accepted_tokens) = (0, 0);
+    let mut previous_decode = None;
+    let mut cancel_supported = shared.cancel.load(Ordering::SeqCst);
+    while let Some(batch) = cached_batch {
+        shared.set_running_token_debt(entries);
+        // The target backend generates one token after the accepted draft tokens
+        let tokens = entries
+            .values()
+            .map(|entry| {
+                entry
+                    .request
+                    .stopping_parameters
+                    .max_new_tokens
+                    .saturating_sub(entry.handle.generated_tokens() + 1)
+            })
+            .min()
+            .unwrap_or(0)
+            .min(draft_tokens);
+        let drafts = match draft_batch.take() {
+            Some(current) if tokens > 0 => {
+                let accepted = std::mem::take(&mut accepted);
+                match draft
+                    .draft(current, tokens, accepted, batch_deadline(entries))
+                    .await
+                {
+                    Ok((drafts, next_batch)) => {
+                        draft_batch = next_batch;
+                        Some(drafts)
+                    }
+                    Err(err) => {
+                        draft_failed(&err, backend);
+                        let _ = draft.clear_cache(Some(batch_id)).await;
+                        None
+                    }
+                }
+            }
+            // The next token is the last one
+            Some(current) => {
+                let _ = draft.clear_cache(Some(current.id)).await;
+                None
+            }
+            None => None,
+        };
+
+        cached_batch = match drafts {
+            None => decode(client, vec![batch], entries, queue, shared, previous_decode).await,
+            Some(drafts) => {
+                let start_time = Instant::now();
+                let batch_id = batch.id;
+                match client
+                    .verify(batch, drafts.clone(), batch_deadline(entries))
+                    .await
+                {
+                    Ok((generations, next_batch, cache_usage)) => {
+                        let duration = start_time.elapsed();
+                        shared.batch_succeeded();
+                        shared.record_cache_usage(cache_usage);
+                        shared.record_decode(generations.len(), duration);
+                        let proposed: usize = drafts.iter().map(|draft| draft.ids.len()).sum();
+                        let step_accepted = accepted_draft_tokens(&drafts, &generations);
+                        proposed_tokens += proposed;
+                        accepted_tokens += step_accepted;
+                        metrics::counter!("tgi_speculative_draft_tokens", proposed as u64, "backend" => backend);
+                        metrics::counter!("tgi_speculative_accepted_tokens", step_accepted as u64, "backend" => backend);
+                        accepted = request_tokens(&generations);
+                        send_generations(generations, entries);
+                        metrics::histogram!("tgi_batch_inference_duration", duration, "method" => "verify", "backend" => backend);
+                        metrics::increment_counter!("tgi_batch_inference_success", "method" => "verify", "backend" => backend);
+                        next_batch
+                    }
+                    Err(err) => {
+                        shared.batch_failed(&err);
+                        let _ = client.clear_cache(Some(batch_id)).await;
+                        send_errors(err, entries, queue, shared);
+                        metrics::increment_counter!("tgi_batch_inference_failure", "method" => "verify", "backend" => backend);
+                        None
+                    }
+                }
+            }
+        };
+        previous_decode = Some(Instant::now());
+
+        if cancel_supported {
+            cancel_supported = cancel_aborted(client, &mut cached_batch, entries, shared).await;
+        }
+    }
+
+    // The draft backend keeps the requests until the target backend is done with them
+    if let Some(draft_batch) = draft_batch {
+        let _ = draft.clear_cache(Some(draft_batch.id)).await;
+    }
+    if proposed_tokens > 0 {
+        let acceptance_rate = accepted_tokens as f64 / proposed_tokens as f64;
+        tracing::debug!(
+            proposed_tokens,
+            accepted_tokens,
+            acceptance_rate,
+            "Speculative decoding"
+        );
+        metrics::histogram!("tgi_request_speculative_acceptance_rate", acceptance_rate, "backend" => backend);
+    }
+}
+
+/// Request of the draft backend: it never stops before the target backend
+fn draft_request(request: &Request) -> Request {
+    Request {
+        // The target backend stops at `max_new_tokens` at the latest
+        stopping_parameters: request
+            .stopping_parameters
+            .as_ref()
+            .map(|stopping_parameters| StoppingCriteriaParameters {
+                max_new_tokens: stopping_parameters.max_new_tokens,
+                stop_sequences: vec![],
+                ignore_eos_token: true,
+            }),
+        prefix_cache: None,
+        prefill_logprobs: false,
+        ..request.clone()
+    }
+}
+
+/// Token ids of the generations of each request, in order
+fn request_tokens(generations: &[Generation]) -> Vec<RequestTokens> {
+    let mut tokens: Vec<RequestTokens> = Vec::new();
+    for generation in generations {
+        match tokens
+            .iter_mut()
+            .find(|tokens| tokens.request_id == generation.request_id)
+        {
+            Some(tokens) => tokens.ids.push(generation.token_id),
+            None => tokens.push(RequestTokens {
+                request_id: generation.request_id,
+                ids: vec![generation.token_id],
+            }),
+        }
+    }
+    tokens
+}
+
+/// Number of draft tokens equal to the tokens generated at their position
+fn accepted_draft_tokens(drafts: &[RequestTokens], generations: &[Generation]) -> usize {
+    drafts
+        .iter()
+        .map(|draft| {
+            generations
+                .iter()
+                .filter(|generation| generation.request_id == draft.request_id)
+                .zip(&draft.ids)
+                .take_while(|(generation, id)| generation.token_id == **id)
+                .count()
+        })
+        .sum()
+}
+
+/// The requests go on without draft tokens
+fn draft_failed(err: &ClientError, backend: &'static str) {
+    tracing::warn!("Draft backend failed, decoding without draft tokens: {err}");
+    metrics::increment_counter!("tgi_speculative_draft_failure", "backend" => backend);
+}
+
+#[instrument(skip_all)]
+async fn prefill(
+    client: &mut BackendClient,
+    batch: Batch,
+    entries: &mut IntMap<u64, Entry>,
+    queue: &Queue,
+    shared: &Shared,
+) -> Option<Batch> {
+    let start_time = Instant::now();
+    let batch_id = batch.id;
+    let backend = shared.backend.as_str();
+    // Batches added to a running batch are prefilled after the decode step they waited for
+    entries
+        .values_mut()
+        .for_each(|entry| entry.batch_time = Some(start_time));
+
+    match client.prefill(batch, batch_deadline(entries)).await {
+        Ok((generations, next_batch, cache_usage)) => {
+            shared.batch_succeeded();
+            shared.record_cache_usage(cache_usage);
+            send_generations(generations, entries);
+            metrics::histogram!("tgi_batch_inference_duration", start_time.elapsed(), "method" => "prefill", "backend" => backend);
+            metrics::increment_counter!("tgi_batch_inference_success", "method" => "prefill", "backend" => backend);
+            next_batch
+        }
+        // If we have an error, we discard the whole batch
+        Err(err) => {
+            shared.batch_failed(&err);
+            let _ = client.clear_cache(Some(batch_id)).await;
+            send_errors(err, entries, queue, shared);
+            metrics::increment_counter!("tgi_batch_inference_failure", "method" => "prefill", "backend" => backend);
+            None
+        }
+    }
+}
+
+#[instrument(skip_all)]
+/// `previous_decode` is the end of the previous decode step of the running batch, the gap since
+/// then covers the prefills of the added batches and the router overhead
+async fn decode(
+    client: &mut BackendClient,
+    batches: Vec<Batch>,
+    entries: &mut IntMap<u64, Entry>,
+    queue: &Queue,
+    shared: &Shared,
+    previous_decode: Option<Instant>,
+) -> Option<Batch> {
+    let start_time = Instant::now();
+    let backend = shared.backend.as_str();
+    let batch_id = batches.first().map_or(0, |batch| batch.id);
+    let batch_size = entries.len();
+    let bucket = batch_size_bucket(batch_size);
+    let gap = previous_decode.map(|previous_decode| start_time - previous_decode);
+
+    match client.decode(batches, batch_deadline(entries)).await {
+        Ok((generations, next_batch, cache_usage)) => {
+            let decode_duration = start_time.elapsed();
+            shared.batch_succeeded();
+            shared.record_cache_usage(cache_usage);
+            shared.record_decode(generations.len(), decode_duration);
+            let tokens = generations.len();
+            let send_start_time = Instant::now();
+            send_generations(generations, entries);
+            let send_duration = send_start_time.elapsed();
+
+            metrics::histogram!("tgi_batch_decode_wait_duration", decode_duration, "batch_size" => bucket, "backend" => backend);
+            metrics::histogram!("tgi_batch_send_generations_duration", send_duration, "batch_size" => bucket, "backend" => backend);
+            if let Some(gap) = gap {
+                metrics::histogram!("tgi_batch_decode_gap_duration", gap, "batch_size" => bucket, "backend" => backend);
+            }
+            if shared.debug_batching {
+                tracing::info!(
+                    batch_id,
+                    batch_size,
+                    tokens,
+                    decode_ms = decode_duration.as_secs_f64() * 1000.0,
+                    send_generations_ms = send_duration.as_secs_f64() * 1000.0,
+                    gap_ms = gap.map(|gap| gap.as_secs_f64() * 1000.0),
+                    "{backend} decode step"
+                );
+            }
+            metrics::histogram!("tgi_batch_inference_duration", start_time.elapsed(), "method" => "decode", "backend" => backend);
+            metrics::increment_counter!("tgi_batch_inference_success", "method" => "decode", "backend" => backend);
+            next_batch
+        }
+        // If we have an error, we discard the whole batch
+        Err(err) => {
+            shared.batch_failed(&err);
+            send_errors(err, entries, queue, shared);
+            metrics::increment_counter!("tgi_batch_inference_failure", "method" => "decode", "backend" => backend);
+            None
+        }
+    }
+}
+
+/// Remove the aborted entries from the cached batch so that the backend stops generating them
+///
+/// Returns false if the backend could not cancel them: `batch` is then unchanged and the entries
+/// are removed once the backend finishes them
+#[instrument(skip_all)]
+async fn cancel_aborted(
+    client: &mut BackendClient,
+    batch: &mut Option<Batch>,
+    entries: &mut IntMap<u64, Entry>,
+    shared: &Shared,
+) -> bool {
+    let batch_id = match batch {
+        Some(batch) => batch.id,
+        None => return true,
+    };
+    let aborted: Vec<(u64, InferError)> = entries
+        .iter()
+        .filter_map(|(id, entry)| abort_error(entry).map(|err| (*id, err)))
+        .collect();
+    if aborted.is_empty() {
+        return true;
+    }
+    let backend = shared.backend.as_str();
+    let request_ids = aborted.iter().map(|(id, _)| *id).collect();
+
+    match client.cancel(batch_id, request_ids).await {
+        Ok(next_batch) => {
+            metrics::increment_counter!("tgi_batch_cancel_success", "backend" => backend);
+            let throughput = shared.throughput();
+            for (id, err) in aborted {
+                if let Some(entry) = entries.remove(&id) {
+                    // Create and enter a span to link this function back to the entry
+                    let _cancel_span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "cancel").entered();
+                    // The backend would have kept generating it up to `max_new_tokens`
+                    if let Some(throughput) = throughput {
+                        let remaining_tokens = entry
+                            .request
+                            .stopping_parameters
+                            .max_new_tokens
+                            .saturating_sub(entry.handle.generated_tokens());
+                        metrics::histogram!(
+                            "tgi_request_cancel_gpu_seconds_saved",
+                            remaining_tokens as f64 / throughput,
+                            "backend" => backend
+                        );
+                    }
+                    abort(&entry, err);
+                }
+            }
+            *batch = next_batch;
+            true
+        }
+        Err(err) => {
+            tracing::warn!("Could not cancel the aborted requests of batch {batch_id}: {err}");
+            metrics::increment_counter!("tgi_batch_cancel_failure", "backend" => backend);
+            false
+        }
+    }
+}
+
+/// Check that the backend returned one id, logprob and text for each prefill token
+fn check_prefill_tokens(tokens: &PrefillTokens) -> Result<(), InferError> {
+    let ids = tokens.ids.len();
+    if tokens.logprobs.len() != ids || tokens.texts.len() != ids {
+        return Err(InferError::GenerationError(
+            GenerationErrorCode::MalformedResponse,
+            format!(
+                "the backend returned malformed prefill tokens: {ids} ids, {} logprobs and {} texts",
+                tokens.logprobs.len(),
+                tokens.texts.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Position of the first prefill token whose text is not the slice of the inputs at the offsets
+/// of the router tokenization, None if they match or if the router did not keep its tokenization
+///
+/// The offsets of a truncated request are the ones of its inputs before the truncation and are not
+/// compared. Backends decode the tokens one by one: the whitespace around the texts is ignored,
+/// and the special tokens and the tokens that are not valid UTF-8 on their own are skipped
+fn prefill_mismatch(request: &ValidGenerateRequest, tokens: &PrefillTokens) -> Option<usize> {
+    let tokenization = request.tokenization.as_ref()?;
+    let offsets = &tokenization.offsets;
+    if offsets.len() != tokenization.tokens || offsets.len() != tokens.texts.len() {
+        return None;
+    }
+    offsets
+        .iter()
+        .zip(&tokens.texts)
+        .position(|(&(start, end), text)| {
+            if start >= end || text.contains(char::REPLACEMENT_CHARACTER) {
+                return false;
+            }
+            request
+                .inputs
+                .get(start..end)
+                .map_or(true, |expected| expected.trim() != text.trim())
+        })
+}
+
+/// Replace the text of a token by its unmodified tokenizer piece, e.g. `Ġworld`
+fn raw_token_text(tokenizer: &Tokenizer, id: u32, text: &mut String) {
+    if let Some(piece) = tokenizer.id_to_token(id) {
+        *text = piece;
+    }
+}
+
+/// Remove the spaces before punctuation and English contractions, as the
+/// `clean_up_tokenization_spaces` option of the `transformers` tokenizers
+fn clean_up_tokenization_spaces(text: &str) -> String {
+    text.replace(" .", ".")
+        .replace(" ?", "?")
+        .replace(" !", "!")
+        .replace(" ,", ",")
+        .replace(" ' ", "'")
+        .replace(" n't", "n't")
+        .replace(" 'm", "'m")
+        .replace(" 's", "'s")
+        .replace(" 've", "'ve")
+        .replace(" 're", "'re")
+}
+
+/// Label of the decode timing histograms, batch sizes are bucketed by powers of two
+fn batch_size_bucket(batch_size: usize) -> &'static str {
+    match batch_size {
+        0..=1 => "1",
+        2 => "2",
+        3..=4 => "3-4",
+        5..=8 => "5-8",
+        9..=16 => "9-16",
+        17..=32 => "17-32",
+        33..=64 => "33-64",
+        _ => "65+",
+    }
+}
+
+/// Time left before the earliest deadline of `entries`
+fn batch_deadline(entries: &IntMap<u64, Entry>) -> Option<Duration> {
+    let now = Instant::now();
+    entries
+        .values()
+        .filter_map(|entry| entry.deadline)
+        .min()
+        .map(|deadline| deadline.saturating_duration_since(now))
+}
+
+/// Send errors to Infer for all `entries`
+/// On failures of the whole batch, the entries that did not stream any token yet are requeued
+/// instead, up to `MAX_REQUEUES` times
+#[instrument(skip_all)]
+fn send_errors(
+    error: ClientError,
+    entries: &mut IntMap<u64, Entry>,
+    queue: &Queue,
+    shared: &Shared,
+) {
+    let mut requeued = Vec::new();
+    entries.drain().for_each(|(_, entry)| {
+        if batch_failure(&error)
+            && entry.auto_requeue
+            && entry.handle.generated_tokens() == 0
+            && entry.handle.requeues() < MAX_REQUEUES
+            && !entry.response_tx.abandoned(&entry.handle)
+        {
+            metrics::increment_counter!("tgi_request_requeue", "backend" => shared.backend.as_str());
+            transition!(entry.handle, "requeued");
+            requeued.push(entry);
+            return;
+        }
+
+        // Create and enter a span to link this function back to the entry
+        let _send_error_span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_error").entered();
+        let err = InferError::from(error.clone());
+        metrics::increment_counter!("tgi_request_failure", "err" => err.error_code().to_string());
+        tracing::error!("{err}");
+        shared.replay_log.capture(
+            entry.handle.id,
+            shared.backend.as_str(),
+            &entry.request,
+            &err,
+        );
+        transition!(entry.handle, "failed", error_type = err.error_code());
+        entry
+            .handle
+            .finish(RequestStatus::Failed, Some(err.to_string()));
+
+        // unwrap_or is valid here as we don't care if the receiver is gone.
+        entry
+            .response_tx
+            .send(Err(err))
+            .unwrap_or(());
+    });
+
+    if !requeued.is_empty() {
+        tracing::warn!(
+            "Requeuing {} requests of the failed batch: {error}",
+            requeued.len()
+        );
+        requeued.sort_by_key(|entry| entry.queue_time);
+        queue.requeue(requeued);
+    }
+}
+
+/// Send one or multiple `InferStreamResponse` to Infer for all `entries`
+#[instrument(skip_all)]
+fn send_generations(generations: Vec<Generation>, entries: &mut IntMap<u64, Entry>) {
+    generations.into_iter().for_each(|generation| {
+        // Get entry
+        // An unknown id is a bug but it must not take the batching task down
+        let entry = match entries.get_mut(&generation.request_id) {
+            Some(entry) => entry,
+            None => return unknown_request_id(generation.request_id, entries),
+        };
+
+        // Create and enter a span to link this function back to the entry
+        let _generation_span = entry.sampled_span(|| info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_generation", generation = ?generation)).entered();
+
+        // The client cancelled this request or is not waiting for it anymore
+        // We stop forwarding its generations and the batching task cancels it on the backend
+        // after this step. The entry is only removed here if the backend is done with it
+        if let Some(err) = abort_error(entry) {
+            abort(entry, err);
+            if generation.generated_text.is_some() {
+                entries.remove(&generation.request_id);
+            }
+            return;
+        }
+
+        // Backends ignoring `prefill_logprobs` can still return prefill tokens
+        if let Some(mut prefill_tokens) = generation
+            .prefill_tokens
+            .filter(|_| entry.request.prefill_tokens)
+        {
+            // Zipping misaligned arrays would drop or shift the tokens of the details
+            if let Err(err) = check_prefill_tokens(&prefill_tokens) {
+                abort(entry, err);
+                if generation.generated_text.is_some() {
+                    entries.remove(&generation.request_id);
+                }
+                return;
+            }
+            let mismatch = prefill_mismatch(&entry.request, &prefill_tokens);
+            if let Some(position) = mismatch {
+                tracing::warn!(
+                    "The text of the prefill token {position} does not match the inputs at its offsets"
+                );
+                metrics::increment_counter!("tgi_request_prefill_mismatch");
+            }
+            if let Some(tokenizer) = &entry.token_pieces {
+                for (text, id) in prefill_tokens.texts.iter_mut().zip(&prefill_tokens.ids) {
+                    raw_token_text(tokenizer, *id, text);
+                }
+            }
+            // Send message
+            // unwrap_or is valid here as we don't care if the receiver is gone.
+            entry
+                .response_tx
+                .send(Ok(InferStreamResponse::Prefill {
+                    tokens: prefill_tokens,
+                    tokenization: entry.request.tokenization.clone(),
+                    mismatch: mismatch.is_some(),
+                }))
+                .unwrap_or(());
+        }
+
+        // Create last Token
+        // Its text is held back while it could be the start of a stop sequence
+        // The raw text of the token is not held back, the stop buffer only matches stop sequences
+        let released = match generation.generated_text {
+            Some(_) => entry.stop_buffer.finish(&generation.token_text),
+            None => entry.stop_buffer.push(&generation.token_text),
+        };
+        let mut text = released.clone();
+        if let Some(tokenizer) = &entry.token_pieces {
+            text = generation.token_text;
+            raw_token_text(tokenizer, generation.token_id, &mut text);
+        }
+        let token = Token {
+            id: generation.token_id,
+            text,
+            logprob: generation.token_logprob,
+            special: generation.token_is_special,
+        };
+
+        if let Some(mut generated_text) = generation.generated_text {
+            // Remove entry as this is the last message
+            let entry = match entries.remove(&generation.request_id) {
+                Some(entry) => entry,
+                None => return unknown_request_id(generation.request_id, entries),
+            };
+            entry.handle.add_token();
+
+            // Usage is billed on the generated tokens: prefer the count of the tokens sent to
+            // the client if the backend reports another one
+            let sent_tokens = entry.handle.generated_tokens();
+            let token_count_mismatch = sent_tokens != generated_text.generated_tokens;
+            if token_count_mismatch {
+                tracing::error!(
+                    "Request {} sent {sent_tokens} tokens but the backend reports {} generated tokens",
+                    entry.handle.id,
+                    generated_text.generated_tokens
+                );
+                metrics::increment_counter!("tgi_request_token_count_mismatch");
+                generated_text.generated_tokens = sent_tokens;
+            }
+            entry.handle.finish(RequestStatus::Completed, None);
+            transition!(
+                entry.handle,
+                "finished",
+                generated_tokens = generated_text.generated_tokens,
+                finish_reason = ?FinishReason::from(generated_text.finish_reason)
+            );
+            if let Some(session) = &entry.session {
+                session.finish(&entry.request.inputs, &generated_text.text);
+            }
+            entry.handle.set_completed(
+                &entry.request.inputs,
+                &generated_text.text,
+                entry.session.as_ref().map(|session| session.id().to_string()),
+            );
+            // Sessions and continuations keep the text as the backend detokenized it
+            entry.stop_buffer.truncate(&mut generated_text.text);
+            if entry.request.clean_up_tokenization_spaces {
+                generated_text.text = clean_up_tokenization_spaces(&generated_text.text);
+            }
+
+            // The inference time is counted from the queue time if the prefill time is missing
+            let start = entry.batch_time.unwrap_or_else(|| {
+                tracing::error!(
+                    "Request {} has no batch time. This is a bug.",
+                    entry.handle.id
+                );
+                metrics::increment_counter!("tgi_batch_time_missing");
+                entry.queue_time
+            });
+
+            // Send message
+            // unwrap_or is valid here as we don't care if the receiver is gone.
+            entry
+                .response_tx
+                .send(Ok(InferStreamResponse::End {
+                    token,
+                    generated_text,
+                    queued: entry.queue_time,
+                    start,
+                    parameters: ValidParameters {
+                        requested_max_new_tokens: entry.requested_max_new_tokens,
+                        ..entry.request.valid_parameters()
+                    },
+                    matched_stop: entry.stop_buffer.matched().cloned(),
+                    token_count_mismatch,
+                    router_limit: false,
+                }))
+                .unwrap_or(());
+        } else {
+            entry.handle.add_token();
+            let generated_tokens = entry.handle.generated_tokens();
+            if generated_tokens == 1 {
+                transition!(entry.handle, "first_token");
+            } else if generated_tokens % TRACE_TOKEN_INTERVAL == 0 {
+                transition!(entry.handle, "decoding", generated_tokens);
+            }
+            entry.sent_text.push_str(&released);
+            // A backend generating past `max_new_tokens` would keep the stream open forever
+            // The entry is cancelled on the backend with the aborted entries
+            if generated_tokens >= entry.token_limit {
+                end_at_token_limit(entry, token);
+                return;
+            }
+            // Send message
+            // unwrap_or is valid here as we don't care if the receiver is gone.
+            entry
+                .response_tx
+                .send(Ok(InferStreamResponse::Token(token)))
+                .unwrap_or(());
+        }
+    });
+}
+
+/// End the generation of `entry` with `token`, the backend sending more tokens than its limit
+fn end_at_token_limit(entry: &mut Entry, token: Token) {
+    let generated_tokens = entry.handle.generated_tokens();
+    tracing::error!(
+        "Request {} received {generated_tokens} tokens from the backend for `max_new_tokens` {}: the backend does not stop the generation",
+        entry.handle.id,
+        entry.request.stopping_parameters.max_new_tokens
+    );
+    metrics::increment_counter!("tgi_request_router_limit");
+    entry.handle.finish(RequestStatus::Completed, None);
+    transition!(
+        entry.handle,
+        "finished",
+        generated_tokens,
+        finish_reason = ?FinishReason::RouterLimit
+    );
+
+    // unwrap_or is valid here as we don't care if the receiver is gone.
+    entry
+        .response_tx
+        .send(Ok(InferStreamResponse::End {
+            token,
+            generated_text: GeneratedText {
+                text: std::mem::take(&mut entry.sent_text),
+                generated_tokens,
+                finish_reason: text_generation_client::FinishReason::Length as i32,
+                seed: None,
+            },
+            queued: entry.queue_time,
+            start: entry.batch_time.unwrap_or(entry.queue_time),
+            parameters: ValidParameters {
+                requested_max_new_tokens: entry.requested_max_new_tokens,
+                ..entry.request.valid_parameters()
+            },
+            matched_stop: entry.stop_buffer.matched().cloned(),
+            token_count_mismatch: false,
+            router_limit: true,
+        }))
+        .unwrap_or(());
+}
+
+/// Error aborting `entry` if its client is not waiting for it anymore
+fn abort_error(entry: &Entry) -> Option<InferError> {
+    if entry.response_tx.abandoned(&entry.handle) {
+        Some(InferError::Cancelled)
+    } else if entry.handle.generated_tokens() >= entry.token_limit {
+        // Already ended by `end_at_token_limit`, only cancelled on the backend
+        Some(InferError::GenerationError(
+            GenerationErrorCode::ExcessTokens,
+            format!("the backend sent more than {} tokens", entry.token_limit),
+        ))
+    } else if entry
+        .deadline
+        .map_or(false, |deadline| Instant::now() >= deadline)
+    {
+        Some(InferError::DeadlineExceeded)
+    } else {
+        None
+    }
+}
+
+/// Report `entry` as aborted by `err`, only once
+fn abort(entry: &Entry, err: InferError) {
+    let (status, error) = match err {
+        InferError::Cancelled => (RequestStatus::Cancelled, None),
+        _ => (RequestStatus::Failed, Some(err.to_string())),
+    };
+    if entry.handle.finish(status, error) {
+        metrics::increment_counter!("tgi_request_failure", "err" => err.error_code().to_string());
+        tracing::error!("{err}");
+        transition!(entry.handle, "aborted", error_type = err.error_code());
+        // unwrap_or is valid here as we don't care if the receiver is gone.
+        entry.response_tx.send(Err(err)).unwrap_or(());
+    }
+}
+
+/// The failure is not caused by a request of the batch
+fn batch_failure(err: &ClientError) -> bool {
+    matches!(
+        err,
+        ClientError::Connection(_) | ClientError::Unavailable(_) | ClientError::Overloaded(_)
+    )
+}
+
+/// The backend cannot be reached
+fn backend_unreachable(err: &ClientError) -> bool {
+    matches!(
+        err,
+        ClientError::Connection(_) | ClientError::Unavailable(_)
+    )
+}
+
+/// Log and count a generation for a request id that is not in `entries`
+fn unknown_request_id(request_id: u64, entries: &IntMap<u64, Entry>) {
+    let entry_ids: Vec<&u64> = entries.keys().collect();
+    tracing::error!(
+        "Request id {request_id} not found in entries. This is a bug. Current entry ids: {entry_ids:?}"
+    );
+    metrics::increment_counter!("tgi_batch_unknown_request_id");
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum InferStreamResponse {
+    // Heartbeat sent while the request is waiting in the queue
+    Queued,
+    // Sent when the request is added to a batch
+    Started,
+    // Optional first message, with the tokenization of the inputs if it was kept
+    Prefill {
+        tokens: PrefillTokens,
+        tokenization: Option<InputTokenization>,
+        /// The texts of the tokens do not match the inputs at the offsets of the tokenization
+        mismatch: bool,
+    },
+    // Intermediate messages
+    Token(Token),
+    // Last message
+    End {
+        token: Token,
+        generated_text: GeneratedText,
+        start: Instant,
+        queued: Instant,
+        /// Parameters used by the backend
+        parameters: ValidParameters,
+        /// Stop sequence found in the streamed text
+        matched_stop: Option<MatchedStop>,
+        /// The backend reported another number of generated tokens than the number of tokens sent
+        token_count_mismatch: bool,
+        /// The router ended the generation, the backend sending more than `max_new_tokens`
+        router_limit: bool,
+    },
+}
+
+/// Concurrency permit acquired before its request is enqueued, taken by the first attempt of the
+/// request
+#[derive(Clone, Debug)]
+pub(crate) struct ReservedPermit(Arc<Mutex<Option<OwnedSemaphorePermit>>>);
+
+impl ReservedPermit {
+    fn take(&self) -> Option<OwnedSemaphorePermit> {
+        self.0.lock().take()
+    }
+}
+
+/// Request validated by `Infer::dry_run`
+#[derive(Debug)]
+pub(crate) struct DryRun {
+    pub(crate) model: String,
+    pub(crate) backend: &'static str,
+    /// None if the throughput of the backend is not known yet
+    pub(crate) estimated_wait: Option<Duration>,
+    pub(crate) request: ValidGenerateRequest,
+}
+
+#[derive(Debug)]
+pub(crate) struct InferResponse {
+    pub(crate) request_id: u64,
+    /// Time spent in the pre-generation hook
+    pub(crate) hook_time: Option<Duration>,
+    pub(crate) validation_timings: Option<ValidationTimings>,
+    /// Number of tokens of the validated inputs
+    pub(crate) input_length: u32,
+    /// Only returned by the backend for the requests with `details`
+    pub(crate) prefill: Vec<PrefillToken>,
+    /// The texts of `prefill` do not match the inputs at the offsets of the router tokenization
+    pub(crate) prefill_mismatch: bool,
+    /// Only kept for the requests with `decoder_input_details`
+    pub(crate) tokenization: Option<InputTokenization>,
+    pub(crate) tokens: Vec<Token>,
+    pub(crate) generated_text: GeneratedText,
+    pub(crate) queued: Instant,
+    pub(crate) start: Instant,
+    /// Number of generations needed to get a non-empty text
+    pub(crate) attempts: u32,
+    /// Generated tokens summed over all attempts
+    pub(crate) total_generated_tokens: u32,
+    /// Number of times the request was put back in the queue after a batch failure
+    pub(crate) requeues: u32,
+    /// The generated text is empty and retrying would not change it
+    pub(crate) empty: bool,
+    pub(crate) parameters: ValidParameters,
+    /// Stop sequence that ended the generation
+    pub(crate) matched_stop: Option<MatchedStop>,
+    /// `generated_text.generated_tokens` is the number of tokens sent instead of the backend count
+    pub(crate) token_count_mismatch: bool,
+    /// Number of identical requests served by the same generation, this one included
+    pub(crate) coalesced: u32,
+    /// The router ended the generation, the backend sending more than `max_new_tokens`
+    pub(crate) router_limit: bool,
+}
+
+impl InferResponse {
+    pub(crate) fn finish_reason(&self) -> FinishReason {
+        match self.empty {
+            true => FinishReason::EmptyGeneration,
+            false => finish_reason(&self.generated_text, self.router_limit),
+        }
+    }
+}
+
+/// Finish reason of a generation ended by the backend, or by the router if `router_limit` is set
+pub(crate) fn finish_reason(generated_text: &GeneratedText, router_limit: bool) -> FinishReason {
+    match router_limit {
+        true => FinishReason::RouterLimit,
+        false => FinishReason::from(generated_text.finish_reason),
+    }
+}
+
+/// Cause of a `GenerationError`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenerationErrorCode {
+    /// The backend failed the generation
+    Backend,
+    /// The backend returned tokens that do not match the request
+    MalformedResponse,
+    /// The backend sent more tokens than `max_new_tokens`
+    ExcessTokens,
+}
+
+impl GenerationErrorCode {
+    /// Prefix of the message in the display of the error
+    fn prefix(&self) -> &'static str {
+        match self {
+            GenerationErrorCode::Backend => "Server error: ",
+            GenerationErrorCode::MalformedResponse | GenerationErrorCode::ExcessTokens => "",
+        }
+    }
+}
+
+/// Errors of the requests
+///
+/// `error_code` is the `error_type` of the error responses: embedders match the variants, the
+/// clients of the API match the codes
+#[derive(Debug, Clone, Error)]
+pub enum InferError {
+    /// The message is the one of the backend for `GenerationErrorCode::Backend`
+    #[error("Request failed during generation: {}{1}", .0.prefix())]
+    GenerationError(GenerationErrorCode, String),
+    /// All the permits of `max_concurrent_requests` are taken: the queue is full
+    #[error("Model is overloaded: {running} of {limit} concurrent requests are running")]
+    Overloaded { limit: usize, running: usize },
+    #[error("Input validation error: {0}")]
+    ValidationError(#[from] ValidationError),
+    #[error("Incomplete generation")]
+    IncompleteGeneration,
+    #[error("Request was cancelled")]
+    Cancelled,
+    #[error("Backend is overloaded: {0}")]
+    BackendOverloaded(String),
+    #[error("Backend ran out of memory: {0}")]
+    BackendOom(String),
+    #[error("Backend rejected the request: {0}")]
+    BackendInvalidArgument(String),
+    #[error("Backend is unavailable: {0}")]
+    BackendUnavailable(String),
+    #[error("Request deadline exceeded")]
+    DeadlineExceeded,
+    #[error("Response timeout exceeded after {generated_tokens} generated tokens")]
+    ResponseTimeout {
+        input_length: u32,
+        generated_tokens: u32,
+    },
+    #[error("Request blocked: {0}")]
+    Blocked(String),
+    #[error("Generated text rejected: {0}")]
+    ContentFiltered(String),
+    #[error("Model is overloaded: estimated queue wait of {}ms, the limit is {}ms", .estimated_wait.as_millis(), .max_queue_wait.as_millis())]
+    QueueWait {
+        estimated_wait: Duration,
+        max_queue_wait: Duration,
+    },
+    #[error("Backend is unavailable: circuit breaker is open, retry in {}ms", .0.as_millis())]
+    CircuitOpen(Duration),
+    #[error("Model is loading")]
+    ModelLoading,
+    #[error("Model `{model}` is not served. Available models: [{}]", .available.join(", "))]
+    UnknownModel {
+        model: String,
+        available: Vec<String>,
+    },
+    #[error("Too many concurrent streams: {current} of {limit} are open for this {scope}")]
+    ConnectionLimit {
+        scope: &'static str,
+        limit: usize,
+        current: usize,
+    },
+    #[error("Too many dry runs: the limit is {0} per second")]
+    RateLimit(u32),
+}
+
+/// Classify backend errors
+impl From<ClientError> for InferError {
+    fn from(err: ClientError) -> Self {
+        match err {
+            ClientError::Overloaded(message) => InferError::BackendOverloaded(message),
+            ClientError::OutOfMemory(message) => InferError::BackendOom(message),
+            ClientError::InvalidArgument(message) => InferError::BackendInvalidArgument(message),
+            ClientError::Connection(message) | ClientError::Unavailable(message) => {
+                InferError::BackendUnavailable(message)
+            }
+            ClientError::Generation(message) => {
+                InferError::GenerationError(GenerationErrorCode::Backend, message)
+            }
+        }
+    }
+}
+
+impl InferError {
+    /// Stable identifier of the error, the `error_type` of the error responses and the `err`
+    /// label of the failure metrics
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            InferError::GenerationError(..) => "generation",
+            InferError::Overloaded { .. } => "overloaded",
+            InferError::ValidationError(_) => "validation",
+            InferError::IncompleteGeneration => "incomplete_generation",
+            InferError::Cancelled => "cancelled",
+            InferError::BackendOverloaded(_) => "backend_overloaded",
+            InferError::BackendOom(_) => "backend_oom",
+            InferError::BackendInvalidArgument(_) => "backend_invalid_argument",
+            InferError::BackendUnavailable(_) => "backend_unavailable",
+            InferError::DeadlineExceeded => "deadline_exceeded",
+            InferError::ResponseTimeout { .. } => "response_timeout",
+            InferError::Blocked(_) => "blocked",
+            InferError::ContentFiltered(_) => "content_filter",
+            InferError::QueueWait { .. } => "overloaded",
+            InferError::CircuitOpen(_) => "backend_unavailable",
+            InferError::ModelLoading => "model_loading",
+            InferError::UnknownModel { .. } => "unknown_model",
+            InferError::ConnectionLimit { .. } => "connection_limit",
+            InferError::RateLimit(_) => "rate_limit",
+        }
+    }
+
+    /// Cause of the abort of a stream that started, None for the errors rejecting requests
+    /// The router is shutting down if the generation ends incomplete while `draining`
+    pub(crate) fn abort_reason(&self, draining: bool) -> Option<AbortReason> {
+        match self {
+            InferError::IncompleteGeneration if draining => Some(AbortReason::Shutdown),
+            InferError::GenerationError(..)
+            | InferError::IncompleteGeneration
+            | InferError::BackendOverloaded(_)
+            | InferError::BackendOom(_)
+            | InferError::BackendInvalidArgument(_)
+            | InferError::BackendUnavailable(_)
+            | InferError::CircuitOpen(_) => Some(AbortReason::BackendError),
+            InferError::DeadlineExceeded | InferError::ResponseTimeout { .. } => {
+                Some(AbortReason::Deadline)
+            }
+            InferError::ContentFiltered(_) => Some(AbortReason::Moderation),
+            InferError::Cancelled => Some(AbortReason::Cancelled),
+            InferError::Overloaded { .. }
+            | InferError::ValidationError(_)
+            | InferError::Blocked(_)
+            | InferError::QueueWait { .. }
+            | InferError::ModelLoading
+            | InferError::UnknownModel { .. }
+            | InferError::ConnectionLimit { .. }
+            | InferError::RateLimit(_) => None,
+        }
+    }
+
+    /// Cause of the 429 errors
+    pub(crate) fn overload_reason(&self) -> Option<OverloadReason> {
+        match self {
+            InferError::Overloaded { .. } => Some(OverloadReason::Concurrency),
+            InferError::QueueWait { .. } => Some(OverloadReason::Admission),
+            InferError::BackendOverloaded(_) => Some(OverloadReason::Backend),
+            InferError::ConnectionLimit { .. } => Some(OverloadReason::ConnectionLimit),
+            InferError::RateLimit(_) => Some(OverloadReason::RateLimit),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::CircuitState;
+    use crate::health::HealthCheck;
+    use crate::usage::api_key_id;
+    use crate::{default_parameters, InputSource, StopConfig};
+    use std::collections::{HashMap, HashSet};
+    use text_generation_client::{
+        InfoResponse, MockCall, MockConfig, NextTokenChooserParameters, StoppingCriteriaParameters,
+    };
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::Tokenizer;
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    fn test_entry(
+        request_id: u64,
+    ) -> (
+        Entry,
+        UnboundedReceiver<Result<InferStreamResponse, InferError>>,
+    ) {
+        let semaphore = Arc::new(Semaphore::new(1));
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        let permit = semaphore.try_acquire_owned().unwrap();
+
+        let entry = Entry {
+            request: ValidGenerateRequest {
+                inputs: "".to_string(),
+                input_length: 0,
+                parameters: NextTokenChooserParameters {
+                    temperature: 0.0,
+                    top_k: 0,
+                    top_p: 0.0,
+                    typical_p: 0.0,
+                    do_sample: false,
+                    seed: 0,
+                    repetition_penalty: 0.0,
+                    watermark: false,
+                    allowed_tokens: vec![],
+                },
+                stopping_parameters: StoppingCriteriaParameters {
+                    ignore_eos_token: false,
+                    max_new_tokens: 0,
+                    stop_sequences: vec![],
+                },
+                stop_matcher: Arc::default(),
+                prefill_tokens: false,
+                tokenization: None,
+                clean_up_tokenization_spaces: false,
+                raw_token_text: false,
+                normalized_chars: None,
+                input_source: InputSource::Text,
+                timings: ValidationTimings::default(),
+            },
+            response_tx: response_tx.into(),
+            handle: Arc::new(RequestHandle::new(request_id, false)),
+            span: info_span!("entry"),
+            temp_span: Some(info_span!("infer")),
+            queue_time: Instant::now(),
+            batch_time: Some(Instant::now()),
+            deadline: None,
+            heartbeat: false,
+            session: None,
+            stop_buffer: StopBuffer::new(Arc::default()),
+            priority: false,
+            probe: None,
+            latency_sensitive: false,
+            auto_requeue: false,
+            allow_downgrade: false,
+            requested_max_new_tokens: None,
+            api_key_id: None,
+            token_pieces: None,
+            token_limit: u32::MAX,
+            sent_text: String::new(),
+            permit: Permit::new(permit),
+        };
+        (entry, response_rx)
+    }
+
+    fn generation(request_id: u64, generated_text: Option<GeneratedText>) -> Generation {
+        Generation {
+            request_id,
+            prefill_tokens: None,
+            token_id: 0,
+            token_logprob: 0.0,
+            token_text: "test".to_string(),
+            token_is_special: false,
+            generated_text,
+        }
+    }
+
+    #[test]
+    fn test_send_generations_unknown_id() {
+        let mut entries = IntMap::default();
+        let (entry, mut response_rx) = test_entry(0);
+        entries.insert(0, entry);
+
+        let generated_text = GeneratedText {
+            text: "test".to_string(),
+            generated_tokens: 1,
+            finish_reason: 0,
+            seed: None,
+        };
+
+        // The bogus ids must be skipped while the known id is still processed
+        send_generations(
+            vec![
+                generation(42, None),
+                generation(0, None),
+                generation(43, Some(generated_text.clone())),
+            ],
+            &mut entries,
+        );
+        assert!(matches!(
+            response_rx.try_recv(),
+            Ok(Ok(InferStreamResponse::Token(_)))
+        ));
+        assert!(response_rx.try_recv().is_err());
+        assert!(entries.contains_key(&0));
+
+        send_generations(vec![generation(0, Some(generated_text))], &mut entries);
+        assert!(matches!(
+            response_rx.try_recv(),
+            Ok(Ok(InferStreamResponse::End { .. }))
+        ));
+        assert!(entries.is_empty());
+    }
+
+    fn prefill_tokens(ids: Vec<u32>, logprobs: Vec<f32>, texts: &[&str]) -> PrefillTokens {
+        PrefillTokens {
+            ids,
+            logprobs,
+            texts: texts.iter().map(|text| text.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_check_prefill_tokens() {
+        let tokens = prefill_tokens(vec![1, 2], vec![f32::NAN, -0.5], &["Hello", " world"]);
+        assert!(check_prefill_tokens(&tokens).is_ok());
+        let tokens = prefill_tokens(vec![], vec![], &[]);
+        assert!(check_prefill_tokens(&tokens).is_ok());
+
+        let malformed = [
+            prefill_tokens(vec![1, 2], vec![f32::NAN], &["Hello", " world"]),
+            prefill_tokens(vec![1, 2], vec![f32::NAN, -0.5], &["Hello"]),
+            prefill_tokens(vec![1], vec![f32::NAN, -0.5], &["Hello", " world"]),
+        ];
+        for tokens in malformed {
+            let err = check_prefill_tokens(&tokens).unwrap_err();
+            assert!(matches!(err, InferError::GenerationError(..)));
+        }
+        let err = check_prefill_tokens(&malformed_prefill()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Request failed during generation: the backend returned malformed prefill tokens: 3 ids, 3 logprobs and 2 texts"
+        );
+    }
+
+    fn malformed_prefill() -> PrefillTokens {
+        prefill_tokens(vec![1, 2, 3], vec![f32::NAN, -0.5, -1.0], &["a", "b"])
+    }
+
+    #[test]
+    fn test_send_generations_malformed_prefill() {
+        let mut entries = IntMap::default();
+        let (mut entry, mut response_rx) = test_entry(0);
+        entry.request.prefill_tokens = true;
+        entries.insert(0, entry);
+
+        let mut generation = generation(0, None);
+        generation.prefill_tokens = Some(malformed_prefill());
+        send_generations(vec![generation], &mut entries);
+        assert!(matches!(
+            response_rx.try_recv(),
+            Ok(Err(InferError::GenerationError(..)))
+        ));
+        assert!(response_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_prefill_mismatch() {
+        let (entry, _response_rx) = test_entry(0);
+        let mut request = entry.request;
+        request.inputs = "Hello wörld".to_string();
+        request.tokenization = Some(InputTokenization {
+            offsets: vec![(0, 0), (0, 5), (5, 12)],
+            chars: 11,
+            bytes: 12,
+            tokens: 3,
+        });
+
+        // Special tokens and surrounding whitespace are ignored
+        let tokens = prefill_tokens(vec![0, 1, 2], vec![0.0; 3], &["<s>", "Hello", "wörld"]);
+        assert_eq!(prefill_mismatch(&request, &tokens), None);
+        let tokens = prefill_tokens(vec![0, 1, 2], vec![0.0; 3], &["<s>", "Hello", "w\u{FFFD}"]);
+        assert_eq!(prefill_mismatch(&request, &tokens), None);
+
+        // Reordered tokens
+        let tokens = prefill_tokens(vec![0, 1, 2], vec![0.0; 3], &["<s>", " wörld", "Hello"]);
+        assert_eq!(prefill_mismatch(&request, &tokens), Some(1));
+
+        // Not compared if the backend did not tokenize the inputs as the router
+        let tokens = prefill_tokens(vec![1, 2], vec![0.0; 2], &[" wörld", "Hello"]);
+        assert_eq!(prefill_mismatch(&request, &tokens), None);
+        // or if the inputs were truncated
+        let tokens = prefill_tokens(vec![0, 1, 2], vec![0.0; 3], &["<s>", " wörld", "Hello"]);
+        request.tokenization.as_mut().unwrap().tokens = 5;
+        assert_eq!(prefill_mismatch(&request, &tokens), None);
+        request.tokenization = None;
+        assert_eq!(prefill_mismatch(&request, &tokens), None);
+    }
+
+    #[test]
+    fn test_send_generations_missing_batch_time() {
+        let mut entries = IntMap::default();
+        let (mut entry, mut response_rx) = test_entry(0);
+        entry.batch_time = None;
+        let queue_time = entry.queue_time;
+        entries.insert(0, entry);
+
+        let generated_text = GeneratedText {
+            text: "test".to_string(),
+            generated_tokens: 1,
+            finish_reason: 0,
+            seed: None,
+        };
+        send_generations(vec![generation(0, Some(generated_text))], &mut entries);
+        match response_rx.try_recv() {
+            Ok(Ok(InferStreamResponse::End { start, queued, .. })) => {
+                assert_eq!(start, queue_time);
+                assert_eq!(queued, queue_time);
+            }
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+
+    #[test]
+    fn test_send_generations_token_limit() {
+        let mut entries = IntMap::default();
+        let (mut entry, mut response_rx) = test_entry(0);
+        entry.token_limit = 3;
+        entries.insert(0, entry);
+
+        // The backend keeps generating, several tokens per step
+        send_generations(vec![generation(0, None), generation(0, None)], &mut entries);
+        send_generations(vec![generation(0, None), generation(0, None)], &mut entries);
+        for _ in 0..2 {
+            assert!(matches!(
+                response_rx.try_recv(),
+                Ok(Ok(InferStreamResponse::Token(_)))
+            ));
+        }
+        match response_rx.try_recv() {
+            Ok(Ok(InferStreamResponse::End {
+                generated_text,
+                router_limit,
+                ..
+            })) => {
+                assert!(router_limit);
+                assert_eq!(generated_text.text, "testtesttest");
+                assert_eq!(generated_text.generated_tokens, 3);
+                assert!(matches!(
+                    finish_reason(&generated_text, router_limit),
+                    FinishReason::RouterLimit
+                ));
+            }
+            message => panic!("unexpected message {message:?}"),
+        }
+        // The entry is left to be cancelled on the backend, without sending anything else
+        assert!(response_rx.try_recv().is_err());
+        let entry = entries.get(&0).unwrap();
+        assert!(abort_error(entry).is_some());
+        assert_eq!(entry.handle.generated_tokens(), 3);
+
+        send_generations(
+            vec![generation(
+                0,
+                Some(GeneratedText {
+                    text: "test".to_string(),
+                    generated_tokens: 5,
+                    finish_reason: 0,
+                    seed: None,
+                }),
+            )],
+            &mut entries,
+        );
+        assert!(entries.is_empty());
+        assert!(response_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_send_generations_token_limit_not_reached() {
+        let mut entries = IntMap::default();
+        let (mut entry, mut response_rx) = test_entry(0);
+        // Without any margin over `max_new_tokens`
+        entry.token_limit = 3;
+        entries.insert(0, entry);
+
+        let generated_text = GeneratedText {
+            text: "testtesttest".to_string(),
+            generated_tokens: 3,
+            finish_reason: 0,
+            seed: None,
+        };
+        send_generations(vec![generation(0, None), generation(0, None)], &mut entries);
+        send_generations(vec![generation(0, Some(generated_text))], &mut entries);
+        for _ in 0..2 {
+            assert!(matches!(
+                response_rx.try_recv(),
+                Ok(Ok(InferStreamResponse::Token(_)))
+            ));
+        }
+        assert!(matches!(
+            response_rx.try_recv(),
+            Ok(Ok(InferStreamResponse::End {
+                router_limit: false,
+                token_count_mismatch: false,
+                ..
+            }))
+        ));
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn test_send_generations_deadline_exceeded() {
+        let mut entries = IntMap::default();
+        let (mut entry, mut response_rx) = test_entry(0);
+        entry.deadline = Some(Instant::now());
+        let handle = entry.handle.clone();
+        entries.insert(0, entry);
+
+        send_generations(vec![generation(0, None)], &mut entries);
+        send_generations(vec![generation(0, None)], &mut entries);
+        assert!(matches!(
+            response_rx.try_recv(),
+            Ok(Err(InferError::DeadlineExceeded))
+        ));
+        // The error is only sent once
+        assert!(response_rx.try_recv().is_err());
+        assert_eq!(handle.status(), RequestStatus::Failed);
+        // The entry is kept until the backend is done with it
+        assert!(entries.contains_key(&0));
+    }
+
+    #[test]
+    fn test_client_error_classification() {
+        let cases = [
+            (
+                ClientError::Overloaded("".to_string()),
+                "backend_overloaded",
+            ),
+            (ClientError::OutOfMemory("".to_string()), "backend_oom"),
+            (
+                ClientError::InvalidArgument("".to_string()),
+                "backend_invalid_argument",
+            ),
+            (
+                ClientError::Unavailable("".to_string()),
+                "backend_unavailable",
+            ),
+            (
+                ClientError::Connection("".to_string()),
+                "backend_unavailable",
+            ),
+            (ClientError::Generation("".to_string()), "generation"),
+        ];
+
+        for (client_error, error_type) in cases {
+            assert_eq!(InferError::from(client_error).error_code(), error_type);
+        }
+    }
+
+    /// Embedders match the structured data of the errors, the messages are kept as they were
+    #[test]
+    fn test_error_data() {
+        let err = InferError::from(ClientError::Generation("CUDA error".to_string()));
+        assert!(matches!(
+            &err,
+            InferError::GenerationError(GenerationErrorCode::Backend, message) if message == "CUDA error"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Request failed during generation: Server error: CUDA error"
+        );
+
+        let err = InferError::UnknownModel {
+            model: "large".to_string(),
+            available: vec!["bloom".to_string(), "small".to_string()],
+        };
+        assert_eq!(
+            err.to_string(),
+            "Model `large` is not served. Available models: [bloom, small]"
+        );
+        let err = InferError::QueueWait {
+            estimated_wait: Duration::from_secs(2),
+            max_queue_wait: Duration::from_secs(1),
+        };
+        assert_eq!(
+            err.to_string(),
+            "Model is overloaded: estimated queue wait of 2000ms, the limit is 1000ms"
+        );
+    }
+
+    #[test]
+    fn test_backend_message_is_kept() {
+        let err = InferError::from(ClientError::OutOfMemory("CUDA out of memory".to_string()));
+        assert_eq!(
+            err.to_string(),
+            "Backend ran out of memory: CUDA out of memory"
+        );
+    }
+
+    #[test]
+    fn test_batching_policy() {
+        assert_eq!(
+            BatchingPolicy::from_str("hybrid:4"),
+            Ok(BatchingPolicy::Hybrid { max_size: 4 })
+        );
+        assert!(BatchingPolicy::from_str("hybrid").is_err());
+        assert!(BatchingPolicy::from_str("fast").is_err());
+
+        let latency = BatchingPolicy::from_str("latency").unwrap();
+        assert!(latency.allows_prefill(8, false));
+        assert!(!latency.allows_prefill(1, true));
+        assert!(BatchingPolicy::Throughput.allows_prefill(8, true));
+
+        let hybrid = BatchingPolicy::Hybrid { max_size: 4 };
+        assert!(!hybrid.allows_prefill(8, true));
+        assert!(hybrid.allows_prefill(4, true));
+    }
+
+    #[test]
+    fn test_clean_up_tokenization_spaces() {
+        assert_eq!(
+            clean_up_tokenization_spaces("Hello , it 's me . Is n't it ?"),
+            "Hello, it's me. Isn't it?"
+        );
+        assert_eq!(clean_up_tokenization_spaces("No change."), "No change.");
+    }
+
+    #[test]
+    fn test_batch_size_bucket() {
+        assert_eq!(batch_size_bucket(1), "1");
+        assert_eq!(batch_size_bucket(4), "3-4");
+        assert_eq!(batch_size_bucket(5), "5-8");
+        assert_eq!(batch_size_bucket(32), "17-32");
+        assert_eq!(batch_size_bucket(128), "65+");
+    }
+
+    #[test]
+    fn test_cache_budget() {
+        let usage = |blocks_used| {
+            Some(CacheUsage {
+                blocks_used,
+                blocks_total: 100,
+            })
+        };
+        assert_eq!(cache_budget(1000, None, 0.8), 1000);
+        assert_eq!(cache_budget(1000, usage(80), 0.8), 1000);
+        assert_eq!(cache_budget(1000, usage(90), 0.8), 500);
+        assert_eq!(cache_budget(1000, usage(100), 0.8), 0);
+        // Disabled by a threshold of 1
+        assert_eq!(cache_budget(1000, usage(100), 1.0), 1000);
+    }
+
+    /// Infer serving a mock backend
+    /// Queued requests are added to the running batch right away
+    fn mock_infer(config: MockConfig) -> Infer {
+        faulty_mock_infer(config, None, DISABLED_BREAKER)
+    }
+
+    const DISABLED_BREAKER: CircuitBreakerConfig = CircuitBreakerConfig {
+        threshold: 0,
+        probe_interval: Duration::from_secs(5),
+    };
+
+    fn faulty_mock_infer(
+        config: MockConfig,
+        faults: Option<FaultConfig>,
+        circuit_breaker: CircuitBreakerConfig,
+    ) -> Infer {
+        build_mock_infer(
+            ShardedClient::mock(config).into(),
+            faults,
+            circuit_breaker,
+            LoadingPolicy::Reject,
+            None,
+            None,
+        )
+    }
+
+    fn build_mock_infer(
+        client: BackendConnection,
+        faults: Option<FaultConfig>,
+        circuit_breaker: CircuitBreakerConfig,
+        loading_policy: LoadingPolicy,
+        speculation: Option<Speculation>,
+        latency_target: Option<LatencyTarget>,
+    ) -> Infer {
+        Infer::builder(client, mock_validation())
+            .max_batch_size(4)
+            .max_waiting_tokens(1)
+            .min_downgraded_new_tokens(1)
+            .max_concurrent_requests(16)
+            .faults(faults)
+            .circuit_breaker(circuit_breaker)
+            .loading_policy(loading_policy)
+            .speculation(speculation)
+            .latency_target(latency_target)
+            .build()
+    }
+
+    fn mock_validation() -> Validation {
+        // Every input is a single unknown token
+        let vocab = HashMap::from([("[UNK]".to_string(), 0)]);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        Validation::basic(Tokenizer::new(model), 1, 2, 4, 1000, 1512)
+    }
+
+    fn mock_request(max_new_tokens: u32) -> GenerateRequest {
+        GenerateRequest {
+            inputs: "Hello".to_string(),
+            parameters: GenerateParameters {
+                max_new_tokens,
+                ..default_parameters()
+            },
+            template: None,
+            template_vars: None,
+            preset: None,
+            model: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefill_tokens_with_details() {
+        let infer = mock_infer(MockConfig::default());
+        let mut request = mock_request(3);
+        request.inputs = "Hello world".to_string();
+
+        // Without details, the prefill tokens are neither returned by the backend nor sent
+        let response = infer
+            .generate(request.clone(), RequestContext::default())
+            .await
+            .unwrap();
+        assert!(response.prefill.is_empty());
+        assert_eq!(response.input_length, 1);
+
+        request.parameters.details = true;
+        let response = infer
+            .generate(request, RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.prefill.len(), 2);
+        assert_eq!(response.input_length, 1);
+    }
+
+    #[tokio::test]
+    async fn test_decoder_input_details() {
+        let infer = mock_infer(MockConfig::default());
+        let mut request = mock_request(3);
+        request.parameters.details = true;
+        request.parameters.decoder_input_details = true;
+        let response = infer
+            .generate(request.clone(), RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.prefill[0].start, Some(0));
+        assert_eq!(response.prefill[0].end, Some(5));
+        assert!(!response.prefill_mismatch);
+        let tokenization = response.tokenization.unwrap();
+        assert_eq!(
+            (tokenization.chars, tokenization.bytes, tokenization.tokens),
+            (5, 5, 1)
+        );
+
+        // The mock backend tokenizes the inputs per word, unlike the router
+        request.inputs = "Hello wörld".to_string();
+        let response = infer
+            .generate(request, RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.prefill.len(), 2);
+        assert!(response.prefill.iter().all(|token| token.start.is_none()));
+        let tokenization = response.tokenization.unwrap();
+        assert_eq!((tokenization.chars, tokenization.bytes), (11, 12));
+    }
+
+    #[tokio::test]
+    async fn test_mock_generate() {
+        let infer = mock_infer(MockConfig::default());
+
+        let response = infer
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.generated_text.text, " the quick brown");
+        assert_eq!(response.tokens.len(), 3);
+        assert!(matches!(response.finish_reason(), FinishReason::Length));
+        assert!(response.validation_timings.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_models() {
+        // Only the draft backend reports its KV-cache usage
+        let draft = mock_infer(MockConfig {
+            cache_blocks_total: Some(100),
+            ..MockConfig::default()
+        });
+        let infer =
+            mock_infer(MockConfig::default()).with_models("main", [("draft".to_string(), draft)]);
+        assert_eq!(infer.model_names(), vec!["main", "draft"]);
+
+        let mut request = mock_request(3);
+        request.model = Some("main".to_string());
+        let mut context = RequestContext::default();
+        infer.prepare(&mut request, &mut context).unwrap();
+        infer.generate(request, context).await.unwrap();
+        assert!(infer.cache_utilization().is_empty());
+
+        let mut request = mock_request(3);
+        request.model = Some("draft".to_string());
+        let mut context = RequestContext::default();
+        infer.prepare(&mut request, &mut context).unwrap();
+        let response = infer.generate(request, context).await.unwrap();
+        assert_eq!(response.generated_text.text, " the quick brown");
+        assert_eq!(infer.cache_utilization().len(), 1);
+        assert_eq!(infer.queue_status().await.len(), 2);
+
+        let mut request = mock_request(3);
+        request.model = Some("gpt2".to_string());
+        let err = infer
+            .prepare(&mut request, &mut RequestContext::default())
+            .unwrap_err();
+        assert_eq!(err.error_code(), "unknown_model");
+        assert_eq!(
+            err.to_string(),
+            "Model `gpt2` is not served. Available models: [main, draft]"
+        );
+        assert!(infer
+            .generate(request, RequestContext::default())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_force_watermark() {
+        let mut infer = mock_infer(MockConfig::default());
+        let response = infer
+            .generate(mock_request(1), RequestContext::default())
+            .await
+            .unwrap();
+        assert!(!response.parameters.watermark);
+
+        infer.force_watermark = true;
+        let response = infer
+            .generate(mock_request(1), RequestContext::default())
+            .await
+            .unwrap();
+        assert!(response.parameters.watermark);
+    }
+
+    #[tokio::test]
+    async fn test_allowed_tokens() {
+        let infer = mock_infer(MockConfig::default());
+        let mut request = mock_request(3);
+        request.parameters.do_sample = Some(true);
+        request.parameters.seed = Some(3);
+        let response = infer
+            .generate(request.clone(), RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.generated_text.text, " fox jumps over");
+
+        // The mask is sent to the backend, which only generates the allowed tokens
+        request.parameters.allowed_tokens = Some(vec![0, 0]);
+        let response = infer
+            .generate(request.clone(), RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.parameters.allowed_tokens, vec![0]);
+        assert!(response.tokens.iter().all(|token| token.id == 0));
+        assert_eq!(response.generated_text.text, " the the the");
+
+        // The tokenizer of the mock has a single token
+        request.parameters.allowed_tokens = Some(vec![0, 1]);
+        assert!(matches!(
+            infer.generate(request, RequestContext::default()).await,
+            Err(InferError::ValidationError(
+                ValidationError::AllowedTokenId(1, 1)
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_top_k() {
+        // Tokenizer with the vocabulary of the mock backend
+        let vocab: HashMap<String, u32> = [
+            "the", "quick", "brown", "fox", "jumps", "over", "lazy", "[UNK]",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), id as u32))
+        .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let validation = Validation::basic(Tokenizer::new(model), 1, 2, 4, 1000, 1512);
+        let infer = Infer::builder(
+            ShardedClient::mock(MockConfig::default()).into(),
+            validation,
+        )
+        .max_waiting_tokens(1)
+        .build();
+
+        // The values keeping the whole vocabulary are sent as 0, which the backend would reject,
+        // and do not make the request sampled
+        for (top_k, forwarded, sampling) in [
+            (0, 0, false),
+            (1, 1, true),
+            (7, 7, true),
+            (8, 0, false),
+            (i32::MAX, 0, false),
+        ] {
+            let mut request = mock_request(1);
+            request.parameters.top_k = Some(top_k);
+            infer
+                .prepare(&mut request, &mut RequestContext::default())
+                .unwrap();
+            assert_eq!(request.parameters.sampling(), sampling, "top_k {top_k}");
+            let response = infer
+                .generate(request, RequestContext::default())
+                .await
+                .unwrap();
+            assert_eq!(response.parameters.top_k, forwarded, "top_k {top_k}");
+        }
+
+        let mut request = mock_request(1);
+        request.parameters.top_k = Some(-1);
+        assert!(matches!(
+            infer.generate(request, RequestContext::default()).await,
+            Err(InferError::ValidationError(ValidationError::TopK))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dedicated_batching_runtime() {
+        for threads in [1, 2] {
+            let infer = Infer::builder(
+                ShardedClient::mock(MockConfig::default()).into(),
+                mock_validation(),
+            )
+            .max_waiting_tokens(1)
+            .batching_runtime(Some(dedicated_runtime(threads)))
+            .build();
+
+            // The channels and the notifications of the queue cross the runtimes
+            let (first, second) = tokio::join!(
+                infer.generate(mock_request(3), RequestContext::default()),
+                infer.generate(mock_request(5), RequestContext::default())
+            );
+            assert_eq!(first.unwrap().generated_text.generated_tokens, 3);
+            assert_eq!(second.unwrap().generated_text.generated_tokens, 5);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deterministic_batching() {
+        let infer = Infer::builder(
+            ShardedClient::mock(MockConfig {
+                token_delay: Duration::from_millis(10),
+                ..MockConfig::default()
+            })
+            .into(),
+            mock_validation(),
+        )
+        .max_batch_size(2)
+        .max_waiting_tokens(1)
+        .deterministic_batching(true)
+        .build();
+
+        // A batch smaller than `max_batch_size` waits for more requests
+        let alone = infer
+            .generate(mock_request(2), RequestContext::default())
+            .await
+            .unwrap();
+        assert!(alone.start - alone.queued >= DETERMINISTIC_FILL_TIMEOUT);
+
+        // The first two requests fill a batch, the third one is not added to the running batch
+        let (first, second, third) = tokio::join!(
+            infer.generate(mock_request(5), RequestContext::default()),
+            infer.generate(mock_request(5), RequestContext::default()),
+            infer.generate(mock_request(5), RequestContext::default())
+        );
+        let mut starts = [first, second, third].map(|response| response.unwrap().start);
+        starts.sort();
+        assert_eq!(starts[0], starts[1]);
+        assert!(starts[2] - starts[1] >= DETERMINISTIC_FILL_TIMEOUT);
+    }
+
+    #[test]
+    fn test_shard_status() {
+        let uris = ["shard-0", "shard-1", "shard-2"];
+        let stats = ShardStats::new(uris.iter().map(|uri| uri.to_string()).collect(), 2.0);
+        let call = |millis: [u64; 3]| {
+            millis.map(|millis| (Duration::from_millis(millis), Ok::<_, ClientError>(())))
+        };
+        stats.record("decode", &call([10, 12, 30]));
+        // Not compared before each shard has enough samples
+        assert!(stats.snapshot().iter().all(|shard| !shard.slow));
+
+        for _ in 0..10 {
+            stats.record("decode", &call([10, 12, 30]));
+        }
+        stats.record(
+            "decode",
+            &[
+                (Duration::from_millis(10), Ok(())),
+                (
+                    Duration::from_millis(1),
+                    Err(ClientError::Unavailable("gone".to_string())),
+                ),
+                (Duration::from_millis(30), Ok(())),
+            ],
+        );
+        let statuses = shard_status("stable", stats.snapshot());
+        let status = |uri: &str| statuses.iter().find(|status| status.uri == uri).unwrap();
+        assert_eq!(statuses.len(), 3);
+        assert!(!status("shard-0").slow && !status("shard-1").slow);
+        assert!(status("shard-2").slow);
+        assert_eq!(status("shard-2").p50_ms, Some(30));
+        assert_eq!(status("shard-2").p95_ms, Some(30));
+
+        // The failed call is not counted in the response times
+        assert!(!status("shard-1").healthy);
+        assert_eq!(
+            status("shard-1").last_error.as_deref(),
+            Some("Server is unavailable: gone")
+        );
+        assert_eq!(status("shard-1").p50_ms, Some(12));
+        assert!(status("shard-0").healthy);
+
+        // The shard recovers once its recent calls are as fast as the others
+        for _ in 0..100 {
+            stats.record("decode", &call([10, 12, 14]));
+        }
+        assert!(stats.snapshot().iter().all(|shard| !shard.slow));
+        assert!(stats.snapshot()[1].healthy);
+    }
+
+    #[tokio::test]
+    async fn test_throughput_estimate() {
+        let infer = mock_infer(MockConfig::default());
+        let shared = &infer.stable.shared;
+        // The wait cannot be estimated before the first decode
+        assert_eq!(shared.throughput(), None);
+        assert_eq!(infer.stable.estimated_wait().await, None);
+
+        shared.record_decode(100, Duration::from_secs(1));
+        assert_eq!(shared.throughput(), Some(100.0));
+        shared.record_decode(200, Duration::from_secs(1));
+        assert_eq!(shared.throughput(), Some(110.0));
+        shared.record_decode(0, Duration::ZERO);
+        assert_eq!(shared.throughput(), Some(110.0));
+        assert_eq!(infer.stable.estimated_wait().await, Some(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn test_mock_cache_utilization() {
+        let infer = mock_infer(MockConfig::default());
+        infer
+            .generate(mock_request(2), RequestContext::default())
+            .await
+            .unwrap();
+        assert!(infer.cache_utilization().is_empty());
+
+        let infer = mock_infer(MockConfig {
+            cache_blocks_total: Some(100),
+            ..MockConfig::default()
+        });
+        infer
+            .generate(mock_request(2), RequestContext::default())
+            .await
+            .unwrap();
+        let cache_utilization = infer.cache_utilization();
+        assert_eq!(cache_utilization.len(), 1);
+        // The finished batch is no longer cached
+        assert_eq!(cache_utilization[0].blocks_used, 0);
+        assert_eq!(cache_utilization[0].blocks_total, 100);
+        let status = infer.queue_status().await;
+        assert!(status[0].cache_utilization.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_max_queue_wait() {
+        let mut infer = mock_infer(MockConfig::default());
+        infer.max_queue_wait = Some(Duration::from_secs(1));
+        infer
+            .stable
+            .shared
+            .record_decode(10, Duration::from_secs(1));
+        let (mut entry, _response_rx) = test_entry(100);
+        entry.request.stopping_parameters.max_new_tokens = 20;
+        infer.stable.queue.append(entry);
+
+        assert!(matches!(
+            infer.generate(mock_request(1), RequestContext::default()).await,
+            Err(InferError::QueueWait { estimated_wait, .. }) if estimated_wait == Duration::from_secs(2)
+        ));
+
+        let mut request = mock_request(1);
+        request.parameters.force_queue = true;
+        assert!(infer
+            .generate(request, RequestContext::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_running_token_debt() {
+        let infer = mock_infer(MockConfig {
+            token_delay: Duration::from_millis(10),
+            ..MockConfig::default()
+        });
+        let running_infer = infer.clone();
+        let running = tokio::spawn(async move {
+            running_infer
+                .generate(mock_request(20), RequestContext::default())
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(55)).await;
+
+        // The running request counts with the tokens it has left to generate
+        let shared = &infer.stable.shared;
+        let token_debt = shared.running_token_debt.load(Ordering::Relaxed);
+        assert!(token_debt > 0 && token_debt < 20, "{token_debt}");
+        running.await.unwrap().unwrap();
+        // Reset by the batching task once the batch is done
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(shared.running_token_debt.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mock_generate_stream_stop_sequence() {
+        let infer = mock_infer(MockConfig::default());
+        let mut request = mock_request(10);
+        request.parameters.stop = vec![" brown".to_string()];
+
+        let handle = infer.register();
+        let mut stream = infer
+            .generate_stream(request, RequestContext::default(), handle)
+            .await
+            .unwrap();
+        let mut texts = Vec::new();
+        let mut generated_text = None;
+        while let Some(response) = stream.next().await {
+            match response.unwrap() {
+                InferStreamResponse::Token(token) => texts.push(token.text),
+                InferStreamResponse::End {
+                    token,
+                    generated_text: text,
+                    ..
+                } => {
+                    texts.push(token.text);
+                    generated_text = Some(text);
+                }
+                _ => {}
+            }
+        }
+
+        // The stop sequence is not streamed
+        assert_eq!(texts, vec![" the", " quick", ""]);
+        let generated_text = generated_text.unwrap();
+        assert_eq!(generated_text.text, " the quick brown");
+        assert!(matches!(
+            FinishReason::from(generated_text.finish_reason),
+            FinishReason::StopSequence
+        ));
+    }
+
+    fn speculative_mock_infer(draft: MockConfig) -> Infer {
+        build_mock_infer(
+            ShardedClient::mock(MockConfig::default()).into(),
+            None,
+            DISABLED_BREAKER,
+            LoadingPolicy::Reject,
+            Some(Speculation {
+                draft: ShardedClient::mock(draft).into(),
+                tokens: 4,
+            }),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_speculative_decoding() {
+        let expected = mock_infer(MockConfig::default())
+            .generate(mock_request(10), RequestContext::default())
+            .await
+            .unwrap();
+
+        // Every third draft token is rejected
+        let infer = speculative_mock_infer(MockConfig {
+            draft_miss_interval: 3,
+            ..MockConfig::default()
+        });
+        let response = infer
+            .generate(mock_request(10), RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.generated_text.text, expected.generated_text.text);
+        assert_eq!(response.generated_text.generated_tokens, 10);
+        assert_eq!(response.tokens.len(), 10);
+        assert!(matches!(response.finish_reason(), FinishReason::Length));
+
+        // The stop sequence ends the request among the accepted tokens
+        let mut request = mock_request(10);
+        request.parameters.stop = vec![" brown".to_string()];
+        let response = infer
+            .generate(request, RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.generated_text.text, " the quick brown");
+        assert!(matches!(
+            response.finish_reason(),
+            FinishReason::StopSequence
+        ));
+
+        // Without its draft backend, the request is decoded by the target backend alone
+        let infer = speculative_mock_infer(MockConfig {
+            fail_requests: HashSet::from([0]),
+            ..MockConfig::default()
+        });
+        let response = infer
+            .generate(mock_request(10), RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.generated_text.text, expected.generated_text.text);
+    }
+
+    #[tokio::test]
+    async fn test_mock_continuation() {
+        let infer = mock_infer(MockConfig::default());
+        let response = infer
+            .generate(mock_request(2), RequestContext::default())
+            .await
+            .unwrap();
+        let continuation = infer.continuation(response.request_id, None).unwrap();
+        assert_eq!(continuation.prompt, "Hello");
+        assert_eq!(continuation.generated_text, " the quick");
+
+        let mut request = mock_request(1);
+        request.inputs = format!("{}{}", continuation.prompt, continuation.generated_text);
+        let context = RequestContext {
+            continued: Some(continuation),
+            ..RequestContext::default()
+        };
+        let response = infer.generate(request, context).await.unwrap();
+        let continuation = infer.continuation(response.request_id, None).unwrap();
+        assert_eq!(continuation.prompt, "Hello");
+        assert_eq!(continuation.generated_text, " the quick the");
+
+        assert!(infer.continuation(response.request_id + 1, None).is_none());
+
+        // Only the API key of the request can continue it
+        let first_key = api_key_id("first-key");
+        let context = RequestContext {
+            api_key_id: Some(first_key.clone()),
+            ..RequestContext::default()
+        };
+        let response = infer.generate(mock_request(1), context).await.unwrap();
+        assert!(infer
+            .continuation(response.request_id, Some(&first_key))
+            .is_some());
+        let second_key = api_key_id("second-key");
+        assert!(infer
+            .continuation(response.request_id, Some(&second_key))
+            .is_none());
+        assert!(infer.continuation(response.request_id, None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_raw_token_text_refused() {
+        let infer = mock_infer(MockConfig::default());
+        let mut request = mock_request(3);
+        request.parameters.raw_token_text = true;
+        request.parameters.stop_config = vec![StopConfig {
+            sequence: " quick".to_string(),
+            keep_text: true,
+        }];
+        assert!(infer
+            .generate(request.clone(), RequestContext::default())
+            .await
+            .is_ok());
+
+        // The raw text of the tokens would contain the removed stop sequence
+        request.parameters.stop = vec![" brown".to_string()];
+        assert!(matches!(
+            infer
+                .generate(request.clone(), RequestContext::default())
+                .await,
+            Err(InferError::ValidationError(
+                ValidationError::RawTokenTextStop
+            ))
+        ));
+
+        // The post-generation hook would not see the raw text
+        request.parameters.stop = vec![];
+        let infer = Infer::builder(
+            ShardedClient::mock(MockConfig::default()).into(),
+            mock_validation().refuse_raw_token_text(true),
+        )
+        .build();
+        assert!(matches!(
+            infer.generate(request, RequestContext::default()).await,
+            Err(InferError::ValidationError(
+                ValidationError::RawTokenTextFiltered
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_id_refused() {
+        let mut request = mock_request(3);
+        request.parameters.session_id = Some("chat".to_string());
+        let infer = Infer::builder(
+            ShardedClient::mock(MockConfig::default()).into(),
+            mock_validation().refuse_session_id(true),
+        )
+        .session_limits(0, 0)
+        .build();
+        assert!(!infer.sessions_enabled());
+        assert!(matches!(
+            infer
+                .generate(request.clone(), RequestContext::default())
+                .await,
+            Err(InferError::ValidationError(
+                ValidationError::SessionsDisabled
+            ))
+        ));
+
+        // Without a session
+        request.parameters.session_id = None;
+        assert!(infer
+            .generate(request, RequestContext::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_mock_stop_config_keep_text() {
+        let infer = mock_infer(MockConfig::default());
+        let mut request = mock_request(10);
+        request.parameters.stop = vec![" fox".to_string()];
+        request.parameters.stop_config = vec![StopConfig {
+            sequence: " brown".to_string(),
+            keep_text: true,
+        }];
+
+        let response = infer
+            .generate(request, RequestContext::default())
+            .await
+            .unwrap();
+        let texts: Vec<&str> = response
+            .tokens
+            .iter()
+            .map(|token| token.text.as_str())
+            .collect();
+        // The kept stop sequence is sent with the tokens
+        assert_eq!(texts, vec![" the", " quick", " brown"]);
+        assert_eq!(response.generated_text.text, " the quick brown");
+        assert_eq!(
+            response.matched_stop,
+            Some(MatchedStop {
+                sequence: " brown".to_string(),
+                index: 1,
+            })
+        );
+        assert_eq!(response.parameters.stop, vec![" fox", " brown"]);
+    }
+
+    #[tokio::test]
+    async fn test_mock_stop_config_generated_text() {
+        let infer = mock_infer(MockConfig::default());
+        let mut request = mock_request(10);
+        request.parameters.stop_config = vec![StopConfig {
+            sequence: " brown".to_string(),
+            keep_text: false,
+        }];
+
+        let response = infer
+            .generate(request, RequestContext::default())
+            .await
+            .unwrap();
+        let texts: Vec<&str> = response
+            .tokens
+            .iter()
+            .map(|token| token.text.as_str())
+            .collect();
+        assert_eq!(texts, vec![" the", " quick", ""]);
+        // The generated text of the backend ends with the stop sequence, which is not kept
+        assert_eq!(response.generated_text.text, " the quick");
+        assert_eq!(response.generated_text.generated_tokens, 3);
+    }
+
+    #[tokio::test]
+    async fn test_mock_concatenation() {
+        let infer = mock_infer(MockConfig {
+            token_delay: Duration::from_millis(10),
+            ..MockConfig::default()
+        });
+
+        let long_infer = infer.clone();
+        let long = tokio::spawn(async move {
+            long_infer
+                .generate(mock_request(20), RequestContext::default())
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(35)).await;
+
+        // The short request joins the running batch instead of waiting for the long one
+        let short = infer
+            .generate(mock_request(2), RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(short.generated_text.text, " the quick");
+        assert!(!long.is_finished());
+
+        let long = long.await.unwrap().unwrap();
+        assert_eq!(long.generated_text.generated_tokens, 20);
+    }
+
+    #[tokio::test]
+    async fn test_prefill_chunks() {
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let infer = Infer::builder(
+            ShardedClient::mock(MockConfig {
+                token_delay: Duration::from_millis(10),
+                calls: Some(calls.clone()),
+                ..MockConfig::default()
+            })
+            .into(),
+            mock_validation(),
+        )
+        .max_batch_size(8)
+        .max_waiting_tokens(1)
+        .prefill_chunk_tokens(Some(2))
+        .build();
+
+        let running_infer = infer.clone();
+        let running = tokio::spawn(async move {
+            running_infer
+                .generate(mock_request(20), RequestContext::default())
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(35)).await;
+
+        // Five single token prompts are queued while the running batch decodes
+        let queued: Vec<_> = (0..5)
+            .map(|_| {
+                let infer = infer.clone();
+                tokio::spawn(async move {
+                    infer
+                        .generate(mock_request(2), RequestContext::default())
+                        .await
+                })
+            })
+            .collect();
+        for request in queued {
+            request.await.unwrap().unwrap();
+        }
+        running.await.unwrap().unwrap();
+
+        // They are prefilled in chunks of at most 2 tokens, the running batch being decoded
+        // between two chunks
+        let calls = calls.lock().unwrap().clone();
+        let prefills: Vec<(usize, usize)> = calls
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter_map(|(i, call)| match call {
+                MockCall::Prefill(ids) => Some((i, ids.len())),
+                MockCall::Decode(_) => None,
+            })
+            .collect();
+        assert!(prefills.len() >= 3);
+        assert!(prefills.iter().all(|(_, size)| *size <= 2));
+        assert_eq!(prefills.iter().map(|(_, size)| size).sum::<usize>(), 5);
+        for window in prefills.windows(2) {
+            assert!(calls[window[0].0 + 1..window[1].0]
+                .iter()
+                .any(|call| matches!(call, MockCall::Decode(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_latency_policy_batching() {
+        // Calls received by a mock backend while a request arrives during a latency sensitive one
+        let run = |batching_policy: BatchingPolicy| async move {
+            let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let infer = Infer::builder(
+                ShardedClient::mock(MockConfig {
+                    token_delay: Duration::from_millis(10),
+                    calls: Some(calls.clone()),
+                    ..MockConfig::default()
+                })
+                .into(),
+                mock_validation(),
+            )
+            .max_batch_size(4)
+            .max_waiting_tokens(1)
+            .batching_policy(batching_policy)
+            .build();
+
+            let mut sensitive = mock_request(10);
+            sensitive.parameters.latency_sensitive = true;
+            let sensitive_infer = infer.clone();
+            let sensitive = tokio::spawn(async move {
+                sensitive_infer
+                    .generate(sensitive, RequestContext::default())
+                    .await
+            });
+            tokio::time::sleep(Duration::from_millis(35)).await;
+            infer
+                .generate(mock_request(2), RequestContext::default())
+                .await
+                .unwrap();
+            sensitive.await.unwrap().unwrap();
+            calls
+        };
+        // Index of the second prefill, and whether the first request is decoded after it
+        let second_prefill = |calls: &[MockCall]| {
+            let sensitive_ids = match &calls[0] {
+                MockCall::Prefill(ids) => ids.clone(),
+                call => panic!("unexpected first call {call:?}"),
+            };
+            let index = calls
+                .iter()
+                .skip(1)
+                .position(|call| matches!(call, MockCall::Prefill(_)))
+                .unwrap()
+                + 1;
+            let sensitive_running = calls[index..].iter().any(|call| match call {
+                MockCall::Decode(ids) => ids.iter().any(|id| sensitive_ids.contains(id)),
+                MockCall::Prefill(_) => false,
+            });
+            (index, sensitive_running)
+        };
+
+        // The new request waits until the latency sensitive request is finished: its 9 decodes
+        // come before the second prefill
+        let calls = run(BatchingPolicy::Latency).await.lock().unwrap().clone();
+        let (index, sensitive_running) = second_prefill(&calls);
+        assert_eq!(index, 10);
+        assert!(!sensitive_running);
+
+        // It joins the running batch otherwise
+        let calls = run(BatchingPolicy::Throughput)
+            .await
+            .lock()
+            .unwrap()
+            .clone();
+        let (index, sensitive_running) = second_prefill(&calls);
+        assert!(index < 10);
+        assert!(sensitive_running);
+    }
+
+    #[tokio::test]
+    async fn test_mock_zero_generated_tokens() {
+        let infer = mock_infer(MockConfig {
+            zero_generated_tokens: true,
+            ..MockConfig::default()
+        });
+
+        // The tokens sent are counted instead
+        let response = infer
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.generated_text.text, " the quick brown");
+        assert_eq!(response.generated_text.generated_tokens, 3);
+        assert!(response.token_count_mismatch);
+
+        let response = mock_infer(MockConfig::default())
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.generated_text.generated_tokens, 3);
+        assert!(!response.token_count_mismatch);
+
+        assert_eq!(
+            mean_time_per_token(Duration::from_millis(30), 0),
+            Duration::ZERO
+        );
+        assert_eq!(
+            mean_time_per_token(Duration::from_millis(30), 3),
+            Duration::from_millis(10)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_cancellation() {
+        let infer = mock_infer(MockConfig {
+            token_delay: Duration::from_millis(1),
+            ..MockConfig::default()
+        });
+
+        let handle = infer.register();
+        let mut stream = infer
+            .generate_stream(mock_request(100), RequestContext::default(), handle.clone())
+            .await
+            .unwrap();
+        assert!(matches!(
+            stream.next().await,
+            Some(Ok(InferStreamResponse::Prefill { .. }))
+        ));
+
+        infer.cancel(handle.id).await.unwrap();
+        let mut cancelled = false;
+        while let Some(response) = stream.next().await {
+            if let Err(err) = response {
+                cancelled = matches!(err, InferError::Cancelled);
+                assert_eq!(err.abort_reason(false), Some(AbortReason::Cancelled));
+                break;
+            }
+        }
+        assert!(cancelled);
+        assert_eq!(handle.status(), RequestStatus::Cancelled);
+    }
+
+    #[tokio::test]
+    async fn test_mock_disconnect_frees_backend() {
+        let infer = mock_infer(MockConfig {
+            token_delay: Duration::from_millis(10),
+            ..MockConfig::default()
+        });
+
+        let mut stream = infer
+            .generate_stream(
+                mock_request(500),
+                RequestContext::default(),
+                infer.register(),
+            )
+            .await
+            .unwrap();
+        assert!(stream.next().await.is_some());
+        drop(stream);
+
+        // The entry is cancelled on the backend instead of generating its 500 tokens
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while infer.limit_concurrent_requests.available_permits() < 16 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(infer
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .is_ok());
+    }
+
+    /// The aborted requests are generated to the end by the backends not implementing `Cancel`
+    #[tokio::test]
+    async fn test_mock_cancel_unsupported() {
+        let calls = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let infer = mock_infer(MockConfig {
+            token_delay: Duration::from_millis(1),
+            info: Some(InfoResponse {
+                vocab_size: 8,
+                special_tokens: HashMap::new(),
+                speculation: true,
+                prefix_cache: true,
+                cancel: false,
+            }),
+            calls: Some(calls.clone()),
+            ..MockConfig::default()
+        });
+
+        let mut stream = infer
+            .generate_stream(
+                mock_request(20),
+                RequestContext::default(),
+                infer.register(),
+            )
+            .await
+            .unwrap();
+        assert!(stream.next().await.is_some());
+        drop(stream);
+
+        // Its permit is released once the backend finishes it
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while infer.limit_concurrent_requests.available_permits() < 16 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let decodes = calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|call| matches!(call, MockCall::Decode(_)))
+            .count();
+        assert_eq!(decodes, 19);
+    }
+
+    #[tokio::test]
+    async fn test_health_check_under_saturation() {
+        let infer = mock_infer(MockConfig {
+            token_delay: Duration::from_millis(10),
+            ..MockConfig::default()
+        });
+
+        // Long generations take all the permits: 4 of them are running and 12 are queued
+        let mut requests = tokio::task::JoinSet::new();
+        for _ in 0..16 {
+            let infer = infer.clone();
+            requests.spawn(async move {
+                infer
+                    .generate(mock_request(1000), RequestContext::default())
+                    .await
+            });
+        }
+        while infer.limit_concurrent_requests.available_permits() > 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(matches!(
+            infer
+                .generate(mock_request(3), RequestContext::default())
+                .await,
+            Err(InferError::Overloaded { .. })
+        ));
+
+        // The probe takes the reserved permit and joins the full running batch
+        let health = HealthCheck::new(infer.clone(), Duration::ZERO);
+        tokio::time::timeout(Duration::from_secs(1), health.check())
+            .await
+            .expect("health check timed out")
+            .unwrap();
+        assert_eq!(infer.probe_permits.available_permits(), 1);
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_parameters_take_no_permit() {
+        let infer = mock_infer(MockConfig::default());
+        let permits = infer
+            .limit_concurrent_requests
+            .clone()
+            .try_acquire_many_owned(16)
+            .unwrap();
+
+        // Rejected on their parameters instead of being reported as overloaded
+        let burst = (0..32).map(|_| {
+            let mut request = mock_request(3);
+            request.parameters.temperature = Some(0.0);
+            infer.generate(request, RequestContext::default())
+        });
+        for result in join_all(burst).await {
+            assert!(matches!(
+                result,
+                Err(InferError::ValidationError(ValidationError::Temperature))
+            ));
+        }
+        assert!(matches!(
+            infer
+                .generate(mock_request(3), RequestContext::default())
+                .await,
+            Err(InferError::Overloaded { .. })
+        ));
+
+        drop(permits);
+        assert!(infer
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .is_ok());
+    }
+
+    /// `generate_stream` returns the errors before the request is queued instead of sending them
+    /// in the stream, so that the handler answers with their status code
+    #[tokio::test]
+    async fn test_generate_stream_rejected_before_queuing() {
+        let infer = mock_infer(MockConfig::default());
+
+        let mut request = mock_request(3);
+        request.parameters.temperature = Some(0.0);
+        assert!(matches!(
+            infer
+                .generate_stream(request, RequestContext::default(), infer.register())
+                .await,
+            Err(InferError::ValidationError(ValidationError::Temperature))
+        ));
+
+        // The request takes a permit before its inputs are tokenized and found too long
+        assert!(matches!(
+            infer
+                .generate_stream(
+                    mock_request(1512),
+                    RequestContext::default(),
+                    infer.register()
+                )
+                .await,
+            Err(InferError::ValidationError(
+                ValidationError::MaxTotalTokens(1512, 1, 1512)
+            ))
+        ));
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 16);
+
+        let permits = infer
+            .limit_concurrent_requests
+            .clone()
+            .try_acquire_many_owned(16)
+            .unwrap();
+        assert!(matches!(
+            infer
+                .generate_stream(mock_request(3), RequestContext::default(), infer.register())
+                .await,
+            Err(InferError::Overloaded { .. })
+        ));
+        drop(permits);
+
+        let mut stream = infer
+            .generate_stream(mock_request(3), RequestContext::default(), infer.register())
+            .await
+            .unwrap();
+        while let Some(response) = stream.next().await {
+            response.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mock_deadline_overflow() {
+        let infer = mock_infer(MockConfig::default());
+
+        let mut request = mock_request(3);
+        request.parameters.deadline_ms = Some(u64::MAX);
+        let response = infer
+            .generate(request, RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.generated_text.generated_tokens, 3);
+    }
+
+    #[tokio::test]
+    async fn test_mock_response_timeout() {
+        let infer = mock_infer(MockConfig {
+            token_delay: Duration::from_millis(10),
+            ..MockConfig::default()
+        });
+
+        let mut request = mock_request(500);
+        request.parameters.response_timeout_ms = Some(50);
+        match infer.generate(request, RequestContext::default()).await {
+            Err(InferError::ResponseTimeout {
+                generated_tokens, ..
+            }) => assert!(generated_tokens > 0 && generated_tokens < 500),
+            _ => panic!("expected a response timeout"),
+        }
 
-                        // Generate one token for this new batch to have the attention past in cache
-                        let new_cached_batch = prefill(&mut client, new_batch, &mut new_entries)
-                            .instrument(span)
-                            .await;
-                        // Reset waiting counter
-                        waiting_tokens = 1;
-                        // Extend current batch with the new batch
-                        if let Some(new_cached_batch) = new_cached_batch {
-                            entries.extend(new_entries);
-                            batches.push(new_cached_batch);
+        // The permit is released without waiting for the 500 tokens
+        tokio::time::timeout(Duration::from_millis(500), async {
+            while infer.limit_concurrent_requests.available_permits() < 16 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let mut request = mock_request(3);
+        request.parameters.response_timeout_ms = Some(5000);
+        assert!(infer
+            .generate(request, RequestContext::default())
+            .await
+            .is_ok());
+
+        let mut request = mock_request(3);
+        request.parameters.response_timeout_ms = Some(u64::MAX);
+        assert!(infer
+            .generate(request, RequestContext::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_mock_failure() {
+        let infer = mock_infer(MockConfig {
+            fail_requests: HashSet::from([0]),
+            ..MockConfig::default()
+        });
+
+        let err = infer
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, InferError::GenerationError(..)));
+
+        // The backend is still usable
+        assert!(infer
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_fault_injection_recovery() {
+        let infer = faulty_mock_infer(
+            MockConfig::default(),
+            Some(FaultConfig {
+                prefill_failure: 0.3,
+                decode_failure: 0.1,
+                latency_probability: 0.2,
+                latency: Duration::from_millis(1),
+                connection_drop: 0.05,
+            }),
+            DISABLED_BREAKER,
+        );
+
+        let mut requests = tokio::task::JoinSet::new();
+        // As many requests as permits
+        for i in 0..16 {
+            let infer = infer.clone();
+            requests.spawn(async move {
+                match i % 2 {
+                    0 => infer
+                        .generate(mock_request(5), RequestContext::default())
+                        .await
+                        .map(|_| ()),
+                    _ => {
+                        let mut stream = infer
+                            .generate_stream(
+                                mock_request(5),
+                                RequestContext::default(),
+                                infer.register(),
+                            )
+                            .await?;
+                        while let Some(response) = stream.next().await {
+                            response?;
                         }
+                        Ok(())
                     }
                 }
-                // Create span for this batch to add context to inference calls
-                let next_batch_size = entries.len();
-                let next_batch_span =
-                    info_span!(parent: None, "batch", batch_size = next_batch_size);
-                entries.iter_mut().for_each(|(_, entry)| {
-                    // Create a new span to link the batch back to this entry
-                    let entry_batch_span =
-                        info_span!(parent: &entry.span, "infer", batch_size = next_batch_size);
-                    // Add relationships
-                    next_batch_span.follows_from(&entry_batch_span);
-                    entry_batch_span.follows_from(&next_batch_span);
-                    // Update entry
-                    entry.temp_span = Some(entry_batch_span);
-                });
-
-                cached_batch = decode(&mut client, batches, &mut entries)
-                    .instrument(next_batch_span)
-                    .await;
-                waiting_tokens += 1;
+            });
+        }
+        while let Some(result) = requests.join_next().await {
+            // Failed requests are fine, panics are not
+            if let Err(err) = result.unwrap() {
+                assert!(matches!(
+                    err,
+                    InferError::GenerationError(..) | InferError::BackendUnavailable(_)
+                ));
             }
-            metrics::gauge!("tgi_batch_current_size", 0.0);
         }
+
+        // All the permits are released
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 16);
     }
-}
 
-#[instrument(skip_all)]
-async fn prefill(
-    client: &mut ShardedClient,
-    batch: Batch,
-    entries: &mut IntMap<u64, Entry>,
-) -> Option<Batch> {
-    let start_time = Instant::now();
-    let batch_id = batch.id;
+    fn best_of_request() -> GenerateRequest {
+        let mut request = mock_request(3);
+        request.parameters.do_sample = Some(true);
+        request.parameters.best_of = Some(2);
+        request
+    }
 
-    match client.prefill(batch).await {
-        Ok((generations, next_batch)) => {
-            send_generations(generations, entries);
-            metrics::histogram!("tgi_batch_inference_duration", start_time.elapsed(), "method" => "prefill");
-            metrics::increment_counter!("tgi_batch_inference_success", "method" => "prefill");
-            next_batch
+    #[tokio::test]
+    async fn test_limits() {
+        let infer = mock_infer(MockConfig::default());
+        let mut context = RequestContext::default();
+        infer.apply_limits(Some("unknown-key"), None, &mut context);
+        assert_eq!(context.limits, Some(infer.limits(None)));
+        assert!(infer.generate(mock_request(10), context).await.is_ok());
+
+        let context = RequestContext {
+            limits: Some(Limits {
+                max_input_length: 1000,
+                max_new_tokens: Some(5),
+            }),
+            ..RequestContext::default()
+        };
+        assert!(matches!(
+            infer.generate(mock_request(10), context).await,
+            Err(InferError::ValidationError(
+                ValidationError::MaxNewTokensLimit(5, 10)
+            ))
+        ));
+
+        let context = RequestContext {
+            limits: Some(Limits {
+                max_input_length: 0,
+                max_new_tokens: None,
+            }),
+            ..RequestContext::default()
+        };
+        assert!(matches!(
+            infer.generate(mock_request(10), context).await,
+            Err(InferError::ValidationError(ValidationError::InputLength(
+                0,
+                _
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_best_of_permits() {
+        let infer = mock_infer(MockConfig::default());
+        let response = infer
+            .generate_best_of(best_of_request(), RequestContext::default(), 2)
+            .await
+            .unwrap();
+        assert_eq!(response.1.len(), 1);
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 16);
+
+        // A single permit is left: one sequence is generated unless `strict_n` is set
+        let taken = infer
+            .limit_concurrent_requests
+            .clone()
+            .try_acquire_many_owned(15)
+            .unwrap();
+        let response = infer
+            .generate_best_of(best_of_request(), RequestContext::default(), 2)
+            .await
+            .unwrap();
+        assert!(response.1.is_empty());
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 1);
+
+        let mut request = best_of_request();
+        request.parameters.strict_n = true;
+        let err = infer
+            .generate_best_of(request, RequestContext::default(), 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            InferError::Overloaded {
+                limit: 16,
+                running: 15
+            }
+        ));
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 1);
+
+        // A sequence is enqueued with its reserved permit, even when none is left
+        let permit = infer
+            .limit_concurrent_requests
+            .clone()
+            .try_acquire_owned()
+            .unwrap();
+        let context = RequestContext {
+            permit: Some(ReservedPermit(Arc::new(Mutex::new(Some(permit))))),
+            ..RequestContext::default()
+        };
+        assert!(infer.generate(mock_request(3), context).await.is_ok());
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 1);
+        drop(taken);
+
+        // A sequence failing midway fails the request and releases all the permits
+        let infer = mock_infer(MockConfig {
+            fail_requests: HashSet::from([1]),
+            ..MockConfig::default()
+        });
+        let err = infer
+            .generate_best_of(best_of_request(), RequestContext::default(), 2)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, InferError::GenerationError(..)));
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 16);
+    }
+
+    #[tokio::test]
+    async fn test_auto_requeue() {
+        let unavailable = |batches: &[u64]| {
+            mock_infer(MockConfig {
+                unavailable_batches: batches.iter().copied().collect(),
+                ..MockConfig::default()
+            })
+        };
+        let mut request = mock_request(3);
+        request.parameters.auto_requeue = Some(true);
+
+        // The first batch fails: the request is batched again
+        let response = unavailable(&[0])
+            .generate(request.clone(), RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(response.generated_text.text, " the quick brown");
+        assert_eq!(response.requeues, 1);
+
+        // The request fails once it was requeued `MAX_REQUEUES` times
+        let err = unavailable(&[0, 1, 2])
+            .generate(request.clone(), RequestContext::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, InferError::BackendUnavailable(_)));
+
+        request.parameters.auto_requeue = Some(false);
+        let err = unavailable(&[0])
+            .generate(request, RequestContext::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, InferError::BackendUnavailable(_)));
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let infer = faulty_mock_infer(
+            MockConfig::default(),
+            Some(FaultConfig {
+                prefill_failure: 0.0,
+                decode_failure: 0.0,
+                latency_probability: 0.0,
+                latency: Duration::ZERO,
+                connection_drop: 1.0,
+            }),
+            CircuitBreakerConfig {
+                threshold: 2,
+                probe_interval: Duration::from_secs(60),
+            },
+        );
+
+        for _ in 0..2 {
+            let err = infer
+                .generate(mock_request(3), RequestContext::default())
+                .await
+                .unwrap_err();
+            assert!(matches!(err, InferError::BackendUnavailable(_)));
         }
-        // If we have an error, we discard the whole batch
-        Err(err) => {
-            let _ = client.clear_cache(Some(batch_id)).await;
-            send_errors(err, entries);
-            metrics::increment_counter!("tgi_batch_inference_failure", "method" => "prefill");
-            None
+
+        // The circuit is open: the request is rejected without reaching the backend
+        let err = infer
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, InferError::CircuitOpen(_)));
+        assert_eq!(err.error_code(), "backend_unavailable");
+
+        let breakers = infer.circuit_breakers();
+        assert_eq!(breakers.len(), 1);
+        assert_eq!(breakers[0].state, CircuitState::Open);
+        assert_eq!(breakers[0].consecutive_failures, 2);
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 16);
+    }
+
+    #[tokio::test]
+    async fn test_probe_unhealthy_canary() {
+        tokio::time::pause();
+        let infer = Infer::builder(
+            ShardedClient::mock(MockConfig::default()).into(),
+            mock_validation(),
+        )
+        .canary(ShardedClient::mock(MockConfig::default()).into(), 1.0)
+        .circuit_breaker(CircuitBreakerConfig {
+            threshold: 1,
+            probe_interval: Duration::from_secs(60),
+        })
+        .build();
+        while !infer.backend_healthy(Backend::Canary) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
         }
+        let canary = infer.canary.as_ref().unwrap().shared.clone();
+
+        // One request probes the unhealthy canary, the others spill over to the stable backend
+        canary.set_healthy(false);
+        let mut probe = RequestContext::default();
+        assert_eq!(infer.route_request(None, &mut probe), Backend::Canary);
+        assert!(probe.admitted);
+        let mut context = RequestContext::default();
+        assert_eq!(infer.route_request(None, &mut context), Backend::Stable);
+        assert!(!context.admitted);
+        assert_eq!(infer.route(Some("canary")), Backend::Stable);
+
+        // The probe reaching the canary marks it healthy
+        infer.generate(mock_request(1), probe).await.unwrap();
+        assert!(infer.backend_healthy(Backend::Canary));
+
+        // While its circuit is open, the probe makes it half-open and is not rejected by it
+        canary.batch_failed(&ClientError::Connection("down".to_string()));
+        assert_eq!(canary.breaker.state(), CircuitState::Open);
+        let mut context = RequestContext::default();
+        assert_eq!(infer.route_request(None, &mut context), Backend::Stable);
+        tokio::time::advance(Duration::from_secs(60)).await;
+        let mut probe = RequestContext::default();
+        assert_eq!(infer.route_request(None, &mut probe), Backend::Canary);
+        assert_eq!(canary.breaker.state(), CircuitState::HalfOpen);
+        infer.generate(mock_request(1), probe).await.unwrap();
+        assert_eq!(canary.breaker.state(), CircuitState::Closed);
+        assert!(infer.backend_healthy(Backend::Canary));
     }
-}
 
-#[instrument(skip_all)]
-async fn decode(
-    client: &mut ShardedClient,
-    batches: Vec<Batch>,
-    entries: &mut IntMap<u64, Entry>,
-) -> Option<Batch> {
-    let start_time = Instant::now();
+    #[tokio::test]
+    async fn test_supervise_batching_task() {
+        let infer = mock_infer(MockConfig::default());
+        let shared = infer.stable.shared.clone();
+        let runs = Arc::new(AtomicUsize::new(0));
 
-    match client.decode(batches).await {
-        Ok((generations, next_batch)) => {
-            send_generations(generations, entries);
-            metrics::histogram!("tgi_batch_inference_duration", start_time.elapsed(), "method" => "decode");
-            metrics::increment_counter!("tgi_batch_inference_success", "method" => "decode");
-            next_batch
+        // The task panics on its first two runs, then returns as if it was cancelled
+        let task_runs = runs.clone();
+        supervise(async { Some(()) }, shared.clone(), move |()| {
+            let runs = task_runs.clone();
+            async move {
+                if runs.fetch_add(1, Ordering::SeqCst) < 2 {
+                    panic!("batching task panic");
+                }
+            }
+        })
+        .await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        // The backend is reported down once its task stopped
+        assert!(shared.stopped.load(Ordering::SeqCst));
+        assert!(!infer.backend_healthy(Backend::Stable));
+        let err = infer
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, InferError::BackendUnavailable(_)));
+        let health = HealthCheck::new(infer.clone(), Duration::ZERO);
+        assert!(health.check().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_model_loading_reject() {
+        let (client_sender, client_receiver) = oneshot::channel();
+        let infer = build_mock_infer(
+            BackendConnection::Connecting(client_receiver),
+            None,
+            DISABLED_BREAKER,
+            LoadingPolicy::Reject,
+            None,
+            None,
+        );
+
+        let err = infer
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, InferError::ModelLoading));
+        assert_eq!(err.error_code(), "model_loading");
+        assert!(!infer.backend_healthy(Backend::Stable));
+
+        client_sender
+            .send(ShardedClient::mock(MockConfig::default()))
+            .unwrap_or_else(|_| panic!("the batching task is gone"));
+        while !infer.backend_healthy(Backend::Stable) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
         }
-        // If we have an error, we discard the whole batch
-        Err(err) => {
-            send_errors(err, entries);
-            metrics::increment_counter!("tgi_batch_inference_failure", "method" => "decode");
-            None
+        assert!(infer
+            .generate(mock_request(3), RequestContext::default())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_model_loading_queue() {
+        let (client_sender, client_receiver) = oneshot::channel();
+        let infer = build_mock_infer(
+            BackendConnection::Connecting(client_receiver),
+            None,
+            DISABLED_BREAKER,
+            LoadingPolicy::Queue,
+            None,
+            None,
+        );
+
+        let mut requests = tokio::task::JoinSet::new();
+        for max_new_tokens in 1..=3 {
+            let infer = infer.clone();
+            requests.spawn(async move {
+                infer
+                    .generate(mock_request(max_new_tokens), RequestContext::default())
+                    .await
+            });
+        }
+        while infer.limit_concurrent_requests.available_permits() > 13 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // Health probes are rejected while the model loads
+        let probe = RequestContext {
+            probe: true,
+            ..RequestContext::default()
+        };
+        assert!(matches!(
+            infer.generate(mock_request(1), probe).await,
+            Err(InferError::ModelLoading)
+        ));
+
+        client_sender
+            .send(ShardedClient::mock(MockConfig::default()))
+            .unwrap_or_else(|_| panic!("the batching task is gone"));
+        while let Some(response) = requests.join_next().await {
+            assert!(response.unwrap().is_ok());
         }
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 16);
     }
-}
 
-/// Send errors to Infer for all `entries`
-#[instrument(skip_all)]
-fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
-    entries.drain().for_each(|(_, entry)| {
-        // Create and enter a span to link this function back to the entry
-        let _send_error_span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_error").entered();
-        let err = InferError::GenerationError(error.to_string());
-        metrics::increment_counter!("tgi_request_failure", "err" => "generation");
-        tracing::error!("{err}");
+    #[tokio::test]
+    async fn test_dry_run() {
+        let infer = mock_infer(MockConfig::default());
+        let mut request = mock_request(7);
+        request.parameters.seed = Some(42);
+        let dry_run = infer
+            .dry_run(request, RequestContext::default())
+            .await
+            .unwrap();
+        assert_eq!(dry_run.backend, "stable");
+        assert_eq!(dry_run.estimated_wait, None);
+        assert_eq!(dry_run.request.input_length, 1);
+        let parameters = dry_run.request.valid_parameters();
+        assert_eq!(parameters.max_new_tokens, 7);
+        assert_eq!(parameters.seed, 42);
+        assert_eq!(parameters.temperature, 1.0);
 
-        // unwrap_or is valid here as we don't care if the receiver is gone.
-        entry
-            .response_tx
-            .send(Err(err))
-            .unwrap_or(());
-    });
-}
+        // Nothing was queued and no permit was taken
+        assert_eq!(infer.limit_concurrent_requests.available_permits(), 16);
+        assert_eq!(infer.queue_status().await[0].size, 0);
 
-/// Send one or multiple `InferStreamResponse` to Infer for all `entries`
-#[instrument(skip_all)]
-fn send_generations(generations: Vec<Generation>, entries: &mut IntMap<u64, Entry>) {
-    generations.into_iter().for_each(|generation| {
-        // Get entry
-        // We can `expect` here as the request id should always be in the entries
-        let entry = entries
-            .get(&generation.request_id)
-            .expect("ID not found in entries. This is a bug.");
+        // Invalid requests are rejected as they would be by `generate`
+        assert!(matches!(
+            infer
+                .dry_run(mock_request(0), RequestContext::default())
+                .await,
+            Err(InferError::ValidationError(ValidationError::MaxNewTokens))
+        ));
+        let mut request = mock_request(3);
+        request.model = Some("other".to_string());
+        assert!(matches!(
+            infer.dry_run(request, RequestContext::default()).await,
+            Err(InferError::UnknownModel { .. })
+        ));
+    }
 
-        // Create and enter a span to link this function back to the entry
-        let _generation_span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_generation", generation = ?generation).entered();
+    #[tokio::test]
+    async fn test_concatenated_batch_timings() {
+        let infer = mock_infer(MockConfig {
+            token_delay: Duration::from_millis(10),
+            ..MockConfig::default()
+        });
 
-        if let Some(prefill_tokens) = generation.prefill_tokens {
-            // Send message
-            // unwrap_or is valid here as we don't care if the receiver is gone.
-            entry
-                .response_tx
-                .send(Ok(InferStreamResponse::Prefill(prefill_tokens)))
-                .unwrap_or(());
+        let running = {
+            let infer = infer.clone();
+            tokio::spawn(async move {
+                infer
+                    .generate(mock_request(50), RequestContext::default())
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Added to the running batch after at most one decode step
+        let concatenated = infer
+            .generate(mock_request(2), RequestContext::default())
+            .await
+            .unwrap();
+        let running = running.await.unwrap().unwrap();
+        assert!(concatenated.start >= concatenated.queued);
+        assert!(concatenated.start - concatenated.queued < Duration::from_millis(100));
+        assert!(concatenated.start - running.start >= Duration::from_millis(100));
+        assert!(running.start - running.queued < Duration::from_millis(100));
+    }
+
+    /// Clients sending their requests one after the other to a mock backend whose decode steps
+    /// slow down with the size of the batch, the budget sampled every 50ms
+    async fn latency_budgets(latency_target: Option<LatencyTarget>) -> Vec<Option<f64>> {
+        let infer = build_mock_infer(
+            ShardedClient::mock(MockConfig {
+                token_delay: Duration::from_millis(5),
+                request_delay: Duration::from_millis(5),
+                ..MockConfig::default()
+            })
+            .into(),
+            None,
+            DISABLED_BREAKER,
+            LoadingPolicy::Reject,
+            None,
+            latency_target,
+        );
+
+        let mut clients = tokio::task::JoinSet::new();
+        for _ in 0..12 {
+            let infer = infer.clone();
+            clients.spawn(async move {
+                for _ in 0..10 {
+                    assert!(infer
+                        .generate(mock_request(20), RequestContext::default())
+                        .await
+                        .is_ok());
+                }
+            });
+        }
+        let mut budgets = Vec::new();
+        while !clients.is_empty() {
+            tokio::select! {
+                response = clients.join_next() => response.unwrap().unwrap(),
+                _ = tokio::time::sleep(Duration::from_millis(50)) => {
+                    budgets.push(infer.queue_status().await[0].latency_budget);
+                }
+            }
         }
+        budgets
+    }
 
-        // Create last Token
-        let token = Token {
-            id: generation.token_id,
-            text: generation.token_text,
-            logprob: generation.token_logprob,
-            special: generation.token_is_special,
-        };
+    #[tokio::test(start_paused = true)]
+    async fn test_latency_target() {
+        // Static batching
+        let budgets = latency_budgets(None).await;
+        assert!(budgets.iter().all(Option::is_none));
 
-        if let Some(generated_text) = generation.generated_text {
-            // Remove entry as this is the last message
-            // We can `expect` here as the request id should always be in the entries
-            let entry = entries
-                .remove(&generation.request_id)
-                .expect("ID not found in entries. This is a bug.");
+        // A full batch takes 25ms per token, 40ms with the prefill of two new requests
+        let budgets = latency_budgets(Some(LatencyTarget {
+            target: Duration::from_millis(20),
+            quantile: 0.95,
+            proportional_gain: 0.1,
+            integral_gain: 0.02,
+        }))
+        .await;
+        // The budget settles below the full batch
+        let settled: Vec<f64> = budgets[budgets.len() / 2..]
+            .iter()
+            .map(|budget| budget.unwrap())
+            .collect();
+        let min = settled.iter().copied().fold(1.0, f64::min);
+        let max = settled.iter().copied().fold(0.0, f64::max);
+        assert!(max < 1.0, "budget is not limited");
+        assert!(min > 0.0, "budget dropped to {min}");
+        assert!(max - min < 0.5, "budget oscillated between {min} and {max}");
+    }
 
-            // Send message
-            // unwrap_or is valid here as we don't care if the receiver is gone.
-            entry
-                .response_tx
-                .send(Ok(InferStreamResponse::End {
-                    token,
-                    generated_text,
-                    queued: entry.queue_time,
-                    start: entry.batch_time.unwrap(),
-                }))
-                .unwrap_or(());
-        } else {
-            // Send message
-            // unwrap_or is valid here as we don't care if the receiver is gone.
-            entry
-                .response_tx
-                .send(Ok(InferStreamResponse::Token(token)))
-                .unwrap_or(());
+    #[tokio::test]
+    async fn test_permits_random_disconnects() {
+        let infer = mock_infer(MockConfig {
+            token_delay: Duration::from_millis(1),
+            ..MockConfig::default()
+        });
+
+        for _ in 0..20 {
+            let mut requests = tokio::task::JoinSet::new();
+            for _ in 0..16 {
+                let infer = infer.clone();
+                let (max_new_tokens, disconnect_after) = {
+                    let mut rng = rand::thread_rng();
+                    (rng.gen_range(1..=20), rng.gen_range(0..=20))
+                };
+                requests.spawn(async move {
+                    // The permits of the previous clients may not be released yet
+                    let mut stream = match infer
+                        .generate_stream(
+                            mock_request(max_new_tokens),
+                            RequestContext::default(),
+                            infer.register(),
+                        )
+                        .await
+                    {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            assert!(matches!(err, InferError::Overloaded { .. }));
+                            return;
+                        }
+                    };
+                    // The client disconnects after `disconnect_after` responses
+                    for _ in 0..disconnect_after {
+                        if stream.next().await.is_none() {
+                            break;
+                        }
+                    }
+                });
+            }
+            while let Some(result) = requests.join_next().await {
+                result.unwrap();
+            }
         }
-    });
-}
 
-#[derive(Debug)]
-pub(crate) enum InferStreamResponse {
-    // Optional first message
-    Prefill(PrefillTokens),
-    // Intermediate messages
-    Token(Token),
-    // Last message
-    End {
-        token: Token,
-        generated_text: GeneratedText,
-        start: Instant,
-        queued: Instant,
-    },
-}
+        // The entries of the disconnected clients are dropped when their generation is done or
+        // by the next sweep of the queue
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while infer.limit_concurrent_requests.available_permits() < 16 {
+            assert!(Instant::now() < deadline, "permits were leaked");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
 
-#[derive(Debug)]
-pub(crate) struct InferResponse {
-    pub(crate) prefill: Vec<PrefillToken>,
-    pub(crate) tokens: Vec<Token>,
-    pub(crate) generated_text: GeneratedText,
-    pub(crate) queued: Instant,
-    pub(crate) start: Instant,
-}
+    fn test_token(text: &str) -> Token {
+        Token {
+            id: 0,
+            text: text.to_string(),
+            logprob: 0.0,
+            special: false,
+        }
+    }
 
-#[derive(Debug, Error)]
-pub enum InferError {
-    #[error("Request failed during generation: {0}")]
-    GenerationError(String),
-    #[error("Model is overloaded")]
-    Overloaded(#[from] TryAcquireError),
-    #[error("Input validation error: {0}")]
-    ValidationError(#[from] ValidationError),
-    #[error("Incomplete generation")]
-    IncompleteGeneration,
-}
+    /// Feed `messages` to the accumulation of a stream
+    async fn accumulate_messages(
+        messages: Vec<Result<InferStreamResponse, InferError>>,
+    ) -> Result<InferResponse, InferError> {
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        for message in messages {
+            response_tx.send(message).unwrap();
+        }
+        drop(response_tx);
+        accumulate(
+            UnboundedReceiverStream::new(response_rx),
+            &RequestHandle::new(0, false),
+        )
+        .await
+    }
 
-impl InferError {
-    pub(crate) fn error_type(&self) -> &str {
-        match self {
-            InferError::GenerationError(_) => "generation",
-            InferError::Overloaded(_) => "overloaded",
-            InferError::ValidationError(_) => "validation",
-            InferError::IncompleteGeneration => "incomplete_generation",
+    fn test_end(text: &str) -> InferStreamResponse {
+        let (entry, _) = test_entry(0);
+        InferStreamResponse::End {
+            token: test_token(text),
+            generated_text: GeneratedText {
+                text: text.to_string(),
+                generated_tokens: 1,
+                finish_reason: 0,
+                seed: None,
+            },
+            start: Instant::now(),
+            queued: Instant::now(),
+            parameters: entry.request.valid_parameters(),
+            matched_stop: None,
+            token_count_mismatch: false,
+            router_limit: false,
         }
     }
+
+    #[tokio::test]
+    async fn test_accumulate_error_after_end() {
+        let response = accumulate_messages(vec![
+            Ok(InferStreamResponse::Token(test_token("a"))),
+            Ok(test_end("b")),
+            Err(InferError::GenerationError(
+                GenerationErrorCode::Backend,
+                "teardown".to_string(),
+            )),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(response.tokens.len(), 2);
+        assert_eq!(response.generated_text.text, "b");
+    }
+
+    #[tokio::test]
+    async fn test_accumulate_token_after_end() {
+        let response = accumulate_messages(vec![
+            Ok(InferStreamResponse::Token(test_token("a"))),
+            Ok(test_end("b")),
+            Ok(InferStreamResponse::Token(test_token("c"))),
+            Ok(test_end("d")),
+        ])
+        .await
+        .unwrap();
+        let texts: Vec<&str> = response
+            .tokens
+            .iter()
+            .map(|token| token.text.as_str())
+            .collect();
+        assert_eq!(texts, ["a", "b"]);
+        assert_eq!(response.generated_text.text, "b");
+    }
+
+    #[tokio::test]
+    async fn test_accumulate_error_before_end() {
+        let err = accumulate_messages(vec![
+            Ok(InferStreamResponse::Token(test_token("a"))),
+            Err(InferError::GenerationError(
+                GenerationErrorCode::Backend,
+                "failed".to_string(),
+            )),
+            Ok(test_end("b")),
+        ])
+        .await
+        .unwrap_err();
+        assert!(matches!(err, InferError::GenerationError(..)));
+
+        let err = accumulate_messages(vec![Ok(InferStreamResponse::Token(test_token("a")))])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, InferError::IncompleteGeneration));
+    }
 }
@@ -0,0 +1,1037 @@
+/// Store of the asynchronous generation jobs
+use crate::limits::Limits;
+use crate::registry::RequestHandle;
+use crate::{ErrorResponse, GenerateRequest, GenerateResponse, JobStatus, RequestStatus, Token};
+use nohash_hasher::IntMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Interval between two removals of the expired jobs, whose spilled files would stay on disk
+/// until the next request to the job API otherwise
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+const FILE_PREFIX: &str = "job-";
+
+/// Spill of the large job results to disk
+#[derive(Debug, Clone)]
+pub struct SpillConfig {
+    /// Directory of the spilled results, emptied at startup
+    pub dir: PathBuf,
+    /// The completed jobs of at least this size are spilled
+    pub threshold_bytes: usize,
+    /// The oldest spilled jobs are evicted when a new one would exceed this size
+    pub max_bytes: u64,
+}
+
+/// Jobs bounded in number and in bytes
+///
+/// Finished jobs are kept for `ttl`, or until running jobs need their room. Running jobs are never
+/// evicted: new jobs are rejected while the store is full of them
+#[derive(Clone)]
+pub(crate) struct Jobs {
+    /// None if the job API is disabled
+    state: Option<Arc<Mutex<JobsState>>>,
+}
+
+struct JobsState {
+    /// Maximum number of jobs
+    max_jobs: usize,
+    /// Maximum total size of the jobs
+    max_bytes: usize,
+    /// How long finished jobs are kept
+    ttl: Duration,
+    /// Current total size of the jobs
+    bytes: usize,
+    jobs: IntMap<u64, Job>,
+    /// Ids of the finished jobs, oldest first
+    finished: VecDeque<u64>,
+    /// None if the results are kept in memory
+    spill: Option<SpillConfig>,
+    /// Current total size of the spilled files
+    spill_bytes: u64,
+    /// Ids of the spilled jobs, oldest first
+    spilled: VecDeque<u64>,
+}
+
+struct Job {
+    handle: Arc<RequestHandle>,
+    /// API key id of the client that submitted the job, None without API key
+    owner: Option<String>,
+    /// Tokens approved by the post-generation hook so far
+    tokens: Vec<Token>,
+    /// Set once the job is finished, None once its tokens and result are spilled
+    result: Option<Result<GenerateResponse, ErrorResponse>>,
+    spilled: Option<SpilledJob>,
+    finished_at: Option<Instant>,
+    /// Set once the result was sent to the callback URL of the job
+    delivered: Option<bool>,
+    size: usize,
+    /// Sent a message for every new token and when the job finishes
+    updates: watch::Sender<()>,
+}
+
+/// Tokens and response of a completed job written to disk
+#[derive(Debug, Clone)]
+pub(crate) struct SpilledJob {
+    /// JSON of the `GenerateResponse`
+    pub response_path: PathBuf,
+    pub response_bytes: u64,
+    /// JSON of the tokens, read by the streams attached to the job
+    tokens_path: PathBuf,
+    tokens: usize,
+    /// Size of both files
+    bytes: u64,
+}
+
+/// New tokens of a job, read by the streams attached to it
+pub(crate) struct JobUpdate {
+    pub tokens: Vec<Token>,
+    /// Set once the job is finished
+    pub result: Option<Result<GenerateResponse, ErrorResponse>>,
+    /// Set instead of the tokens and result of a spilled job
+    pub spilled: Option<SpilledJob>,
+    /// Changes on the next update of the job
+    pub updates: watch::Receiver<()>,
+}
+
+/// Response of a completed job
+pub(crate) enum JobResponse {
+    Memory(GenerateResponse),
+    Spilled(SpilledJob),
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum JobsError {
+    #[error("The job API is disabled")]
+    Disabled,
+    #[error("The job store is full: {0} jobs are running")]
+    Full(usize),
+}
+
+impl Jobs {
+    /// Create a new store. The job API is disabled if any of the limits is 0
+    pub(crate) fn new(max_jobs: usize, max_bytes: usize, ttl: Duration) -> Self {
+        let state = (max_jobs > 0 && max_bytes > 0).then(|| {
+            Arc::new(Mutex::new(JobsState {
+                max_jobs,
+                max_bytes,
+                ttl,
+                bytes: 0,
+                jobs: IntMap::default(),
+                finished: VecDeque::new(),
+                spill: None,
+                spill_bytes: 0,
+                spilled: VecDeque::new(),
+            }))
+        });
+        Self { state }
+    }
+
+    /// Spill the large results to disk
+    /// The files left by a previous run are removed
+    pub(crate) fn with_spill(self, config: SpillConfig) -> std::io::Result<Self> {
+        let state = match &self.state {
+            None => return Ok(self),
+            Some(state) => state,
+        };
+        std::fs::create_dir_all(&config.dir)?;
+        for path in spill_files(&config.dir) {
+            remove_file(&path);
+        }
+        state.lock().spill = Some(config);
+
+        let jobs = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Some(state) = &jobs.state {
+                    let mut state = state.lock();
+                    state.remove_expired();
+                    state.record();
+                }
+            }
+        });
+        Ok(self)
+    }
+
+    /// Add the job of a request sent with the API key with the id `owner`
+    pub(crate) fn insert(
+        &self,
+        handle: Arc<RequestHandle>,
+        owner: Option<String>,
+    ) -> Result<(), JobsError> {
+        let mut state = self.state.as_ref().ok_or(JobsError::Disabled)?.lock();
+        state.remove_expired();
+
+        let size = std::mem::size_of::<Job>();
+        state.evict(1, size);
+        if state.jobs.len() >= state.max_jobs || state.bytes + size > state.max_bytes {
+            metrics::increment_counter!("tgi_job_rejected");
+            return Err(JobsError::Full(state.jobs.len()));
+        }
+
+        let (updates, _) = watch::channel(());
+        state.bytes += size;
+        state.jobs.insert(
+            handle.id,
+            Job {
+                handle,
+                owner,
+                tokens: vec![],
+                result: None,
+                spilled: None,
+                finished_at: None,
+                delivered: None,
+                size,
+                updates,
+            },
+        );
+        state.record();
+        Ok(())
+    }
+
+    /// Add the tokens approved by the post-generation hook to a running job
+    pub(crate) fn push(&self, id: u64, tokens: Vec<Token>) {
+        if tokens.is_empty() {
+            return;
+        }
+        let mut state = match &self.state {
+            None => return,
+            Some(state) => state.lock(),
+        };
+        let size = tokens.iter().map(token_size).sum::<usize>();
+        if let Some(job) = state.jobs.get_mut(&id) {
+            job.tokens.extend(tokens);
+            job.size += size;
+            job.updates.send_replace(());
+            state.bytes += size;
+            state.evict(0, 0);
+            state.record();
+        }
+    }
+
+    /// Store the last tokens and the result of a job
+    pub(crate) fn finish(
+        &self,
+        id: u64,
+        tokens: Vec<Token>,
+        result: Result<GenerateResponse, ErrorResponse>,
+    ) {
+        let mut state = match &self.state {
+            None => return,
+            Some(state) => state.lock(),
+        };
+        let size = tokens.iter().map(token_size).sum::<usize>()
+            + match &result {
+                Ok(response) => serde_json::to_vec(response).map_or(0, |bytes| bytes.len()),
+                Err(err) => serde_json::to_vec(err).map_or(0, |bytes| bytes.len()),
+            };
+        let spill = match state.jobs.get_mut(&id) {
+            None => return,
+            Some(job) => {
+                let spill = result.is_ok();
+                job.tokens.extend(tokens);
+                job.result = Some(result);
+                job.finished_at = Some(Instant::now());
+                job.size += size;
+                job.updates.send_replace(());
+                spill.then_some(job.size)
+            }
+        };
+        state.bytes += size;
+        state.finished.push_back(id);
+        state.evict(0, 0);
+        state.record();
+
+        let spill = match (&state.spill, spill) {
+            (Some(config), Some(size)) if size >= config.threshold_bytes => config.dir.clone(),
+            _ => return,
+        };
+        drop(state);
+        let jobs = self.clone();
+        tokio::spawn(async move { jobs.spill(id, spill).await });
+    }
+
+    /// Write the tokens and the response of a completed job to disk, and free their memory
+    async fn spill(&self, id: u64, dir: PathBuf) {
+        let state = match &self.state {
+            None => return,
+            Some(state) => state,
+        };
+        let (tokens, response) = match state.lock().jobs.get(&id) {
+            Some(Job {
+                tokens,
+                result: Some(Ok(response)),
+                ..
+            }) => (tokens.clone(), response.clone()),
+            _ => return,
+        };
+
+        let spilled =
+            tokio::task::spawn_blocking(move || write_spilled(&dir, id, &tokens, &response)).await;
+        let spilled = match spilled {
+            Ok(Ok(spilled)) => spilled,
+            Ok(Err(err)) => {
+                tracing::error!("Could not spill job {id}: {err}");
+                metrics::increment_counter!("tgi_job_spill_failure");
+                return;
+            }
+            Err(_) => return,
+        };
+        state.lock().store_spilled(id, spilled);
+    }
+
+    /// The job exists and was submitted with the API key with the id `owner`
+    /// The clients only see their own jobs
+    pub(crate) fn owned_by(&self, id: u64, owner: Option<&str>) -> bool {
+        let state = match &self.state {
+            None => return false,
+            Some(state) => state.lock(),
+        };
+        state
+            .jobs
+            .get(&id)
+            .map_or(false, |job| job.owner.as_deref() == owner)
+    }
+
+    /// Status of a job, with its response once it is finished
+    pub(crate) fn status(&self, id: u64) -> Option<JobStatus> {
+        let mut state = self.state.as_ref()?.lock();
+        state.remove_expired();
+        state.jobs.get(&id).map(Job::status)
+    }
+
+    /// Tokens of a job starting at `from`, and its result if it is finished
+    pub(crate) fn update(&self, id: u64, from: usize) -> Option<JobUpdate> {
+        let state = self.state.as_ref()?.lock();
+        let job = state.jobs.get(&id)?;
+        Some(JobUpdate {
+            tokens: job.tokens.get(from..).unwrap_or_default().to_vec(),
+            result: job.result.clone(),
+            spilled: job.spilled.clone(),
+            updates: job.updates.subscribe(),
+        })
+    }
+
+    /// Response of a completed job
+    pub(crate) fn response(&self, id: u64) -> Option<JobResponse> {
+        let state = self.state.as_ref()?.lock();
+        let job = state.jobs.get(&id)?;
+        match (&job.result, &job.spilled) {
+            (Some(Ok(response)), _) => Some(JobResponse::Memory(response.clone())),
+            (_, Some(spilled)) => Some(JobResponse::Spilled(spilled.clone())),
+            _ => None,
+        }
+    }
+
+    /// Record the outcome of the delivery of a job result to its callback URL
+    pub(crate) fn set_delivered(&self, id: u64, delivered: bool) {
+        if let Some(state) = &self.state {
+            if let Some(job) = state.lock().jobs.get_mut(&id) {
+                job.delivered = Some(delivered);
+            }
+        }
+    }
+
+    /// Remove a finished job
+    /// Returns false if the job is unknown or still running
+    pub(crate) fn remove(&self, id: u64) -> bool {
+        let mut state = match &self.state {
+            None => return false,
+            Some(state) => state.lock(),
+        };
+        let finished = state.jobs.get(&id).map_or(false, Job::finished);
+        if finished {
+            state.finished.retain(|finished| *finished != id);
+            state.remove(id);
+            state.record();
+        }
+        finished
+    }
+}
+
+impl JobsState {
+    fn remove(&mut self, id: u64) {
+        if let Some(job) = self.jobs.remove(&id) {
+            self.bytes -= job.size;
+            if let Some(spilled) = job.spilled {
+                self.spill_bytes -= spilled.bytes;
+                self.spilled.retain(|spilled| *spilled != id);
+                spilled.remove();
+            }
+        }
+    }
+
+    /// Replace the tokens and the result of a job by their spilled files
+    fn store_spilled(&mut self, id: u64, spilled: SpilledJob) {
+        let max_bytes = self.spill.as_ref().map_or(0, |config| config.max_bytes);
+        let spillable = self
+            .jobs
+            .get(&id)
+            .map_or(false, |job| matches!(job.result, Some(Ok(_))));
+        // Results larger than the whole disk budget stay in memory
+        if !spillable || spilled.bytes > max_bytes {
+            spilled.remove();
+            return;
+        }
+        while self.spill_bytes + spilled.bytes > max_bytes {
+            let oldest = match self.spilled.pop_front() {
+                None => break,
+                Some(oldest) => oldest,
+            };
+            self.finished.retain(|finished| *finished != oldest);
+            self.remove(oldest);
+            metrics::increment_counter!("tgi_job_eviction", "reason" => "disk");
+        }
+
+        let job = self
+            .jobs
+            .get_mut(&id)
+            .expect("spilled job was removed. This is a bug.");
+        let size = std::mem::size_of::<Job>();
+        self.bytes -= job.size - size;
+        job.size = size;
+        job.tokens = vec![];
+        job.result = None;
+        self.spill_bytes += spilled.bytes;
+        job.spilled = Some(spilled);
+        self.spilled.push_back(id);
+        self.record();
+    }
+
+    /// Remove the finished jobs older than `ttl`
+    fn remove_expired(&mut self) {
+        while let Some(id) = self.finished.front().copied() {
+            let expired = self.jobs.get(&id).map_or(true, |job| {
+                job.finished_at
+                    .map_or(true, |finished_at| finished_at.elapsed() >= self.ttl)
+            });
+            if !expired {
+                break;
+            }
+            self.finished.pop_front();
+            self.remove(id);
+            metrics::increment_counter!("tgi_job_eviction", "reason" => "expired");
+        }
+    }
+
+    /// Evict the oldest finished jobs until `jobs` more jobs of `bytes` fit in the limits
+    fn evict(&mut self, jobs: usize, bytes: usize) {
+        while self.jobs.len() + jobs > self.max_jobs || self.bytes + bytes > self.max_bytes {
+            let id = match self.finished.pop_front() {
+                None => break,
+                Some(id) => id,
+            };
+            self.remove(id);
+            metrics::increment_counter!("tgi_job_eviction", "reason" => "capacity");
+        }
+    }
+
+    fn record(&self) {
+        metrics::gauge!("tgi_job_count", self.jobs.len() as f64);
+        metrics::gauge!("tgi_job_bytes", self.bytes as f64);
+        metrics::gauge!("tgi_job_spill_bytes", self.spill_bytes as f64);
+    }
+}
+
+impl Job {
+    fn finished(&self) -> bool {
+        self.result.is_some() || self.spilled.is_some()
+    }
+
+    fn status(&self) -> JobStatus {
+        let (status, response, error) = match &self.result {
+            None if self.spilled.is_some() => (RequestStatus::Completed, None, None),
+            Some(Ok(response)) => (RequestStatus::Completed, Some(response.clone()), None),
+            Some(Err(err)) if err.error_type == "cancelled" => {
+                (RequestStatus::Cancelled, None, Some(err.clone()))
+            }
+            Some(Err(err)) => (RequestStatus::Failed, None, Some(err.clone())),
+            // The request can end before its job stores the result
+            None => match self.handle.status() {
+                RequestStatus::Queued => (RequestStatus::Queued, None, None),
+                _ => (RequestStatus::Running, None, None),
+            },
+        };
+        let generated_tokens = match &self.spilled {
+            None => self.tokens.len(),
+            Some(spilled) => spilled.tokens,
+        };
+        JobStatus {
+            id: self.handle.id,
+            status,
+            generated_tokens: generated_tokens as u32,
+            response,
+            error,
+            delivered: self.delivered,
+            spilled: self.spilled.is_some(),
+        }
+    }
+}
+
+impl SpilledJob {
+    /// Read the tokens and the response of the job
+    pub(crate) async fn load(&self) -> std::io::Result<(Vec<Token>, GenerateResponse)> {
+        let (tokens_path, response_path) = (self.tokens_path.clone(), self.response_path.clone());
+        tokio::task::spawn_blocking(move || {
+            let tokens = serde_json::from_slice(&std::fs::read(tokens_path)?)?;
+            let response = serde_json::from_slice(&std::fs::read(response_path)?)?;
+            Ok((tokens, response))
+        })
+        .await?
+    }
+
+    fn remove(&self) {
+        remove_file(&self.response_path);
+        remove_file(&self.tokens_path);
+    }
+}
+
+/// Job whose request is validated but not batched yet, as written to the job log
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct QueuedJob {
+    /// Request of the job, its deadline header and its `force_queue` authorization applied
+    pub request: GenerateRequest,
+    pub callback_url: Option<String>,
+    /// The API key itself is never written
+    pub api_key_id: Option<String>,
+    pub limits: Option<Limits>,
+    /// Unix time in milliseconds when the job was submitted
+    pub submitted_at_ms: u64,
+}
+
+impl QueuedJob {
+    pub(crate) fn new(
+        request: GenerateRequest,
+        callback_url: Option<String>,
+        api_key_id: Option<String>,
+        limits: Option<Limits>,
+    ) -> Self {
+        Self {
+            request,
+            callback_url,
+            api_key_id,
+            limits,
+            submitted_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |timestamp| timestamp.as_millis() as u64),
+        }
+    }
+
+    /// Time since the job was submitted, zero if the clock went back
+    pub(crate) fn age(&self) -> Duration {
+        let submitted_at = UNIX_EPOCH + Duration::from_millis(self.submitted_at_ms);
+        SystemTime::now()
+            .duration_since(submitted_at)
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Line of the job log
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JobLogRecord {
+    Queued {
+        id: u64,
+        job: QueuedJob,
+    },
+    /// The request of the job was batched, or ended before it was
+    Dispatched {
+        id: u64,
+    },
+}
+
+/// Write-ahead log of the jobs whose request is not batched yet, restored on startup with
+/// `--restore-queue`
+///
+/// A job is written and synced to disk before its request is enqueued. A restored job keeps its
+/// id, the ids of the new requests starting after the restored ones, and its deadline runs from
+/// its submission. Only the jobs are logged, the requests of the other routes are lost with the
+/// connection of their client on a restart
+///
+/// The file is written by the blocking threads of the runtime
+#[derive(Clone, Default)]
+pub(crate) struct JobLog {
+    /// None if the jobs are not logged
+    state: Option<Arc<Mutex<JobLogState>>>,
+}
+
+struct JobLogState {
+    file: File,
+    /// Ids of the logged jobs that are not dispatched yet
+    queued: HashSet<u64>,
+}
+
+impl JobLog {
+    /// Open the log at `path`, keeping only its jobs that were not dispatched
+    /// Returns these jobs with their id if `restore` is set, otherwise the log is emptied
+    pub(crate) fn open(
+        path: &Path,
+        restore: bool,
+    ) -> std::io::Result<(Self, Vec<(u64, QueuedJob)>)> {
+        let jobs: Vec<(u64, QueuedJob)> = match (restore, File::open(path)) {
+            (true, Ok(file)) => read_job_log(file)
+                .into_iter()
+                .filter_map(|(id, job)| Some((id, job?)))
+                .collect(),
+            (true, Err(err)) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => vec![],
+        };
+
+        // The log is rewritten with the restored jobs only, then replaced at once
+        let rewritten = path.with_extension("tmp");
+        let mut file = File::create(&rewritten)?;
+        for (id, job) in &jobs {
+            file.write_all(&record_line(&JobLogRecord::Queued {
+                id: *id,
+                job: job.clone(),
+            })?)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&rewritten, path)?;
+
+        let state = JobLogState {
+            file: OpenOptions::new().append(true).open(path)?,
+            queued: jobs.iter().map(|(id, _)| *id).collect(),
+        };
+        let log = Self {
+            state: Some(Arc::new(Mutex::new(state))),
+        };
+        Ok((log, jobs))
+    }
+
+    /// Log the accepted job `id` and sync it to disk, before its request is enqueued
+    /// Returns false if the jobs are not logged
+    pub(crate) async fn queued(&self, id: u64, job: QueuedJob) -> bool {
+        let state = match &self.state {
+            None => return false,
+            Some(state) => state.clone(),
+        };
+        let written = tokio::task::spawn_blocking(move || {
+            let mut state = state.lock();
+            state.queued.insert(id);
+            state.write(&JobLogRecord::Queued { id, job }, true);
+        })
+        .await;
+        if let Err(err) = written {
+            tracing::error!("Could not write the job log: {err}");
+            metrics::increment_counter!("tgi_job_log_failure");
+        }
+        true
+    }
+
+    /// Mark a logged job as dispatched, it is not restored anymore
+    pub(crate) async fn dispatched(&self, id: u64) {
+        let state = match &self.state {
+            None => return,
+            Some(state) => state.clone(),
+        };
+        let written = tokio::task::spawn_blocking(move || {
+            let mut state = state.lock();
+            if !state.queued.remove(&id) {
+                return;
+            }
+            // The log is emptied once all its jobs are dispatched so that it does not grow forever
+            if state.queued.is_empty() {
+                if let Err(err) = state.file.set_len(0) {
+                    tracing::error!("Could not empty the job log: {err}");
+                    metrics::increment_counter!("tgi_job_log_failure");
+                }
+                return;
+            }
+            // A dispatched job that is restored again is generated twice, which a crash before
+            // the record would do anyway: it is not synced
+            state.write(&JobLogRecord::Dispatched { id }, false);
+        })
+        .await;
+        if let Err(err) = written {
+            tracing::error!("Could not write the job log: {err}");
+            metrics::increment_counter!("tgi_job_log_failure");
+        }
+    }
+}
+
+impl JobLogState {
+    fn write(&mut self, record: &JobLogRecord, sync: bool) {
+        let written = record_line(record).and_then(|line| {
+            self.file.write_all(&line)?;
+            if sync {
+                self.file.sync_data()?;
+            }
+            Ok(())
+        });
+        if let Err(err) = written {
+            tracing::error!("Could not write the job log: {err}");
+            metrics::increment_counter!("tgi_job_log_failure");
+        }
+    }
+}
+
+fn record_line(record: &JobLogRecord) -> std::io::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Jobs of a job log in order, None once dispatched
+/// A truncated last line, written while the router stopped, is skipped
+fn read_job_log(file: File) -> Vec<(u64, Option<QueuedJob>)> {
+    let mut jobs: Vec<(u64, Option<QueuedJob>)> = vec![];
+    // Position of the jobs in `jobs`
+    let mut positions = IntMap::default();
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                tracing::warn!("Could not read the job log: {err}");
+                break;
+            }
+        };
+        match serde_json::from_str(&line) {
+            Ok(JobLogRecord::Queued { id, job }) => {
+                positions.insert(id, jobs.len());
+                jobs.push((id, Some(job)));
+            }
+            Ok(JobLogRecord::Dispatched { id }) => {
+                if let Some(position) = positions.get(&id) {
+                    jobs[*position].1 = None;
+                }
+            }
+            Err(err) => tracing::warn!("Skipping an invalid line of the job log: {err}"),
+        }
+    }
+    jobs
+}
+
+fn write_spilled(
+    dir: &Path,
+    id: u64,
+    tokens: &[Token],
+    response: &GenerateResponse,
+) -> std::io::Result<SpilledJob> {
+    let response = serde_json::to_vec(response)?;
+    let tokens_json = serde_json::to_vec(tokens)?;
+    let spilled = SpilledJob {
+        response_path: dir.join(format!("{FILE_PREFIX}{id}.json")),
+        response_bytes: response.len() as u64,
+        tokens_path: dir.join(format!("{FILE_PREFIX}{id}.tokens.json")),
+        tokens: tokens.len(),
+        bytes: (response.len() + tokens_json.len()) as u64,
+    };
+    let written = std::fs::write(&spilled.response_path, response)
+        .and_then(|()| std::fs::write(&spilled.tokens_path, tokens_json));
+    if let Err(err) = written {
+        spilled.remove();
+        return Err(err);
+    }
+    Ok(spilled)
+}
+
+/// Files of `dir` written by the job store
+fn spill_files(dir: &Path) -> Vec<PathBuf> {
+    std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?;
+            (name.starts_with(FILE_PREFIX) && name.ends_with(".json")).then_some(path)
+        })
+        .collect()
+}
+
+fn remove_file(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!("Could not remove {}: {err}", path.display());
+        }
+    }
+}
+
+/// Memory used by a stored token
+fn token_size(token: &Token) -> usize {
+    std::mem::size_of::<Token>() + token.text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::InferError;
+    use crate::registry::Registry;
+
+    fn token(text: &str) -> Token {
+        Token {
+            id: 0,
+            text: text.to_string(),
+            logprob: 0.0,
+            special: false,
+        }
+    }
+
+    fn response(text: &str) -> GenerateResponse {
+        GenerateResponse {
+            generated_text: text.to_string(),
+            details: None,
+            continued_from: None,
+            warning: None,
+        }
+    }
+
+    #[test]
+    fn test_job_lifecycle() {
+        let registry = Registry::new(false);
+        let jobs = Jobs::new(10, 1 << 20, Duration::from_secs(60));
+        let handle = registry.register();
+        jobs.insert(handle.clone(), None).unwrap();
+        assert_eq!(
+            jobs.status(handle.id).unwrap().status,
+            RequestStatus::Queued
+        );
+
+        jobs.push(handle.id, vec![token("a"), token("b")]);
+        let update = jobs.update(handle.id, 1).unwrap();
+        assert_eq!(update.tokens.len(), 1);
+        assert!(update.result.is_none());
+        assert!(!jobs.remove(handle.id));
+
+        jobs.finish(handle.id, vec![token("c")], Ok(response("abc")));
+        assert!(update.updates.has_changed().unwrap());
+        let status = jobs.status(handle.id).unwrap();
+        assert_eq!(status.status, RequestStatus::Completed);
+        assert_eq!(status.generated_tokens, 3);
+        assert_eq!(status.response.unwrap().generated_text, "abc");
+        assert_eq!(status.delivered, None);
+        jobs.set_delivered(handle.id, false);
+        assert_eq!(jobs.status(handle.id).unwrap().delivered, Some(false));
+
+        assert!(jobs.remove(handle.id));
+        assert!(jobs.status(handle.id).is_none());
+    }
+
+    #[test]
+    fn test_owned_by() {
+        let registry = Registry::new(false);
+        let jobs = Jobs::new(10, 1 << 20, Duration::from_secs(60));
+        let handle = registry.register();
+        jobs.insert(handle.clone(), Some("first".to_string()))
+            .unwrap();
+        assert!(jobs.owned_by(handle.id, Some("first")));
+        assert!(!jobs.owned_by(handle.id, Some("second")));
+        assert!(!jobs.owned_by(handle.id, None));
+        assert!(!jobs.owned_by(handle.id + 1, Some("first")));
+
+        let anonymous = registry.register();
+        jobs.insert(anonymous.clone(), None).unwrap();
+        assert!(jobs.owned_by(anonymous.id, None));
+        assert!(!jobs.owned_by(anonymous.id, Some("first")));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let registry = Registry::new(false);
+        let jobs = Jobs::new(2, 1 << 20, Duration::from_secs(60));
+        let first = registry.register();
+        let second = registry.register();
+        jobs.insert(first.clone(), None).unwrap();
+        jobs.insert(second.clone(), None).unwrap();
+
+        // Running jobs are not evicted
+        assert!(matches!(
+            jobs.insert(registry.register(), None),
+            Err(JobsError::Full(2))
+        ));
+
+        // The oldest finished job is evicted
+        jobs.finish(first.id, vec![token("a")], Ok(response("a")));
+        let third = registry.register();
+        jobs.insert(third.clone(), None).unwrap();
+        assert!(jobs.status(first.id).is_none());
+        assert!(jobs.status(second.id).is_some());
+        assert!(jobs.status(third.id).is_some());
+    }
+
+    fn spill_config(name: &str, max_bytes: u64) -> SpillConfig {
+        let dir = std::env::temp_dir().join(format!("tgi-jobs-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).unwrap_or(());
+        SpillConfig {
+            dir,
+            threshold_bytes: 0,
+            max_bytes,
+        }
+    }
+
+    async fn wait_spilled(jobs: &Jobs, id: u64) {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !jobs.status(id).map_or(false, |status| status.spilled) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spill() {
+        let config = spill_config("spill", 1 << 20);
+        // Files of a previous run are removed
+        std::fs::create_dir_all(&config.dir).unwrap();
+        std::fs::write(config.dir.join("job-42.json"), "{}").unwrap();
+        let registry = Registry::new(false);
+        let jobs = Jobs::new(10, 1 << 20, Duration::from_secs(60))
+            .with_spill(config.clone())
+            .unwrap();
+        assert_eq!(spill_files(&config.dir).len(), 0);
+
+        let handle = registry.register();
+        jobs.insert(handle.clone(), None).unwrap();
+        jobs.push(handle.id, vec![token("a")]);
+        jobs.finish(handle.id, vec![token("b")], Ok(response("ab")));
+        wait_spilled(&jobs, handle.id).await;
+
+        let status = jobs.status(handle.id).unwrap();
+        assert_eq!(status.status, RequestStatus::Completed);
+        assert_eq!(status.generated_tokens, 2);
+        assert!(status.response.is_none());
+        let spilled = match jobs.response(handle.id) {
+            Some(JobResponse::Spilled(spilled)) => spilled,
+            _ => panic!("the response is not spilled"),
+        };
+        let (tokens, response) = spilled.load().await.unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(response.generated_text, "ab");
+        assert_eq!(
+            spilled.response_bytes,
+            std::fs::metadata(&spilled.response_path).unwrap().len()
+        );
+
+        assert!(jobs.remove(handle.id));
+        assert_eq!(spill_files(&config.dir).len(), 0);
+        std::fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_spill_capacity() {
+        let registry = Registry::new(false);
+        let size = serde_json::to_vec(&response("a")).unwrap().len()
+            + serde_json::to_vec(&[token("a")]).unwrap().len();
+        let config = spill_config("capacity", size as u64 + 1);
+        let jobs = Jobs::new(10, 1 << 20, Duration::from_secs(60))
+            .with_spill(config.clone())
+            .unwrap();
+
+        let first = registry.register();
+        jobs.insert(first.clone(), None).unwrap();
+        jobs.finish(first.id, vec![token("a")], Ok(response("a")));
+        wait_spilled(&jobs, first.id).await;
+
+        // The oldest spilled job is evicted
+        let second = registry.register();
+        jobs.insert(second.clone(), None).unwrap();
+        jobs.finish(second.id, vec![token("b")], Ok(response("b")));
+        wait_spilled(&jobs, second.id).await;
+        assert!(jobs.status(first.id).is_none());
+        assert_eq!(spill_files(&config.dir).len(), 2);
+        std::fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    fn log_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("tgi-job-log-{}-{name}", std::process::id()));
+        std::fs::remove_file(&path).unwrap_or(());
+        path
+    }
+
+    fn queued_job(inputs: &str) -> QueuedJob {
+        QueuedJob::new(
+            GenerateRequest {
+                inputs: inputs.to_string(),
+                parameters: crate::default_parameters(),
+                template: None,
+                template_vars: None,
+                preset: None,
+                model: None,
+            },
+            Some("https://example.com/jobs".to_string()),
+            Some("9a3e0c5b71d2f4e8".to_string()),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_job_log() {
+        let path = log_path("restore");
+        let (log, restored) = JobLog::open(&path, true).unwrap();
+        assert!(restored.is_empty());
+        assert!(log.queued(7, queued_job("a")).await);
+        // On disk once logged, before the request is enqueued: a crash restores it
+        let crashed = path.with_extension("crashed");
+        std::fs::copy(&path, &crashed).unwrap();
+        let (_, restored) = JobLog::open(&crashed, true).unwrap();
+        assert_eq!(restored.len(), 1);
+        std::fs::remove_file(&crashed).unwrap();
+        assert!(log.queued(3, queued_job("b")).await);
+        assert!(log.queued(5, queued_job("c")).await);
+        log.dispatched(3).await;
+        drop(log);
+        // Written while the router stopped
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(br#"{"event":"queued","id":9,"job":{"req"#)
+            .unwrap();
+
+        // The jobs that were not dispatched are restored in log order, with their id
+        let (log, restored) = JobLog::open(&path, true).unwrap();
+        let restored: Vec<(u64, String)> = restored
+            .into_iter()
+            .map(|(id, job)| (id, job.request.inputs))
+            .collect();
+        assert_eq!(restored, vec![(7, "a".to_string()), (5, "c".to_string())]);
+        drop(log);
+
+        // Without `restore`, the log is emptied
+        let (log, restored) = JobLog::open(&path, false).unwrap();
+        assert!(restored.is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        // The log is emptied once all its jobs are dispatched
+        assert!(log.queued(0, queued_job("e")).await);
+        assert!(std::fs::metadata(&path).unwrap().len() > 0);
+        log.dispatched(0).await;
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+
+        // Not logged
+        assert!(!JobLog::default().queued(0, queued_job("f")).await);
+
+        // The age of a restored job runs from its submission
+        let mut job = queued_job("g");
+        job.submitted_at_ms -= 2000;
+        assert!(job.age() >= Duration::from_secs(2));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_expiration() {
+        let registry = Registry::new(false);
+        let jobs = Jobs::new(10, 1 << 20, Duration::ZERO);
+        let handle = registry.register();
+        jobs.insert(handle.clone(), None).unwrap();
+        jobs.finish(
+            handle.id,
+            vec![],
+            Err(ErrorResponse::from(&InferError::Cancelled)),
+        );
+        assert!(jobs.status(handle.id).is_none());
+
+        assert!(matches!(
+            Jobs::new(0, 1 << 20, Duration::ZERO).insert(registry.register()),
+            Err(JobsError::Disabled)
+        ));
+    }
+}
@@ -0,0 +1,172 @@
+/// Feedback control of the batches from the inter-token latency of their decode steps
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Number of recent decode steps whose latency is measured
+const WINDOW: usize = 32;
+/// The budget does not change until this many decode steps are measured
+const MIN_SAMPLES: usize = 8;
+
+/// Inter-token latency target of the running batches
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyTarget {
+    /// Latency the `quantile` of the recent decode steps should not exceed
+    pub target: Duration,
+    pub quantile: f64,
+    /// Gain of the budget on the relative latency error of the last step
+    pub proportional_gain: f64,
+    /// Gain of the budget on the accumulated relative latency error
+    pub integral_gain: f64,
+}
+
+/// Proportional-integral controller of the fraction of the batch that can be used
+///
+/// The budget shrinks while the measured latency is above the target and grows back while there
+/// is headroom. It starts at 1: the batches are not limited until the target is missed
+#[derive(Debug)]
+pub(crate) struct LatencyController {
+    config: LatencyTarget,
+    /// Latencies of the recent decode steps, oldest first
+    samples: VecDeque<Duration>,
+    integral: f64,
+    budget: f64,
+}
+
+impl LatencyController {
+    pub(crate) fn new(config: LatencyTarget) -> Self {
+        Self {
+            config,
+            samples: VecDeque::with_capacity(WINDOW),
+            integral: 1.0,
+            budget: 1.0,
+        }
+    }
+
+    /// Fraction of the batch size and of the token budget that new requests can use
+    pub(crate) fn budget(&self) -> f64 {
+        self.budget
+    }
+
+    /// Record the latency of a decode step, from the end of the previous one, and update the
+    /// budget
+    pub(crate) fn record(&mut self, latency: Duration) {
+        if self.samples.len() == WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(latency);
+        if self.samples.len() < MIN_SAMPLES {
+            return;
+        }
+
+        let target = self.config.target.as_secs_f64();
+        // Positive while there is headroom
+        let error = (target - self.quantile().as_secs_f64()) / target;
+        self.integral = (self.integral + self.config.integral_gain * error).clamp(0.0, 1.0);
+        self.budget = (self.integral + self.config.proportional_gain * error).clamp(0.0, 1.0);
+    }
+
+    /// Maximum size of the running batch, at least one request so that the queue never stalls
+    pub(crate) fn max_batch_size(&self, max_batch_size: usize) -> usize {
+        ((max_batch_size as f64 * self.budget).round() as usize).clamp(1, max_batch_size)
+    }
+
+    /// Share of `tokens` that the requests added to the running batch can use
+    pub(crate) fn token_budget(&self, tokens: u32) -> u32 {
+        (tokens as f64 * self.budget) as u32
+    }
+
+    fn quantile(&self) -> Duration {
+        let mut samples: Vec<Duration> = self.samples.iter().copied().collect();
+        samples.sort();
+        let index = (self.config.quantile * samples.len() as f64) as usize;
+        samples[index.min(samples.len() - 1)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target() -> LatencyTarget {
+        LatencyTarget {
+            target: Duration::from_millis(80),
+            quantile: 0.95,
+            proportional_gain: 0.1,
+            integral_gain: 0.02,
+        }
+    }
+
+    /// Decode steps of a batch of `max_batch_size` requests taking 20ms plus 10ms per request,
+    /// the prefill of the added requests stalling the step by half as much
+    fn simulate(controller: &mut LatencyController, steps: usize) -> Vec<(f64, Duration)> {
+        let max_batch_size = 16;
+        let mut remaining_tokens: Vec<u32> = vec![];
+        let mut next_length = 0;
+        let mut history = vec![];
+        for _ in 0..steps {
+            let allowed = controller.max_batch_size(max_batch_size);
+            let mut latency = Duration::ZERO;
+            if remaining_tokens.len() < allowed {
+                let added = allowed - remaining_tokens.len();
+                for _ in 0..added {
+                    // Deterministic lengths between 20 and 80 tokens
+                    next_length = (next_length + 37) % 61;
+                    remaining_tokens.push(20 + next_length);
+                }
+                latency += Duration::from_millis(10 + 5 * added as u64);
+            }
+            latency += Duration::from_millis(20 + 10 * remaining_tokens.len() as u64);
+            remaining_tokens.retain_mut(|tokens| {
+                *tokens -= 1;
+                *tokens > 0
+            });
+            controller.record(latency);
+            history.push((controller.budget(), latency));
+        }
+        history
+    }
+
+    #[test]
+    fn test_controller_converges() {
+        let mut controller = LatencyController::new(target());
+        let history = simulate(&mut controller, 2000);
+
+        // The budget settles instead of oscillating between the empty and the full batch
+        let settled = &history[1000..];
+        let min = settled
+            .iter()
+            .map(|(budget, _)| *budget)
+            .fold(1.0, f64::min);
+        let max = settled
+            .iter()
+            .map(|(budget, _)| *budget)
+            .fold(0.0, f64::max);
+        assert!(min > 0.1, "budget dropped to {min}");
+        assert!(
+            max - min < 0.15,
+            "budget oscillated between {min} and {max}"
+        );
+
+        let mut latencies: Vec<Duration> = settled.iter().map(|(_, latency)| *latency).collect();
+        latencies.sort();
+        let p95 = latencies[latencies.len() * 95 / 100];
+        assert!(p95 <= Duration::from_millis(90), "p95 latency is {p95:?}");
+    }
+
+    #[test]
+    fn test_budget_recovers() {
+        let mut controller = LatencyController::new(target());
+        for _ in 0..100 {
+            controller.record(Duration::from_millis(200));
+        }
+        assert_eq!(controller.budget(), 0.0);
+        assert_eq!(controller.max_batch_size(16), 1);
+        assert_eq!(controller.token_budget(1000), 0);
+
+        for _ in 0..300 {
+            controller.record(Duration::from_millis(40));
+        }
+        assert_eq!(controller.budget(), 1.0);
+        assert_eq!(controller.max_batch_size(16), 16);
+    }
+}
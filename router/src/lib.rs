@@ -1,17 +1,82 @@
-/// Text Generation Inference Webserver
+//! Text Generation Inference Webserver
+//!
+//! The router can be embedded in another service: [`server::RouterApp::into_router`] returns its
+//! routes so that they can be merged into another [`axum::Router`], with its own middlewares and
+//! server lifecycle. See `examples/embedded.rs`.
+//!
+//! # Stability
+//!
+//! The following items follow semantic versioning, a breaking change to them bumps the minor
+//! version while the crate is at `0.x`:
+//! - [`server::ServerOptions`], [`server::RouterApp`], [`server::run`] and the configuration
+//!   types re-exported by [`server`]
+//! - [`Infer::builder`], the public methods of [`InferBuilder`] and [`Infer::wait_ready`]
+//! - [`Validation::basic`]
+//! - the request and response types of the HTTP API: [`GenerateParameters`],
+//!   [`GenerateRequest`], [`GenerateResponse`], [`StreamResponse`] and their fields
+//!
+//! New options are added to [`server::ServerOptions`] in patch releases with their default in
+//! [`server::ServerOptions::new`]: build it with `new` and set its fields rather than with a
+//! struct literal. The other public items, such as [`bench`], may change in any release.
+#[macro_use]
+mod trace;
+
+mod access_log;
+mod audit;
+pub mod bench;
+mod breaker;
+mod cache;
+mod callback;
+#[cfg(feature = "client")]
+pub mod client;
+mod coalesce;
+mod config;
+mod connections;
+mod conversation;
+mod drain;
+mod extract;
+mod faults;
+mod health;
+mod hook;
 mod infer;
+mod jobs;
+mod latency;
+mod limits;
+mod models;
+mod normalize;
+mod pacing;
+mod preset;
+mod profile;
+mod progress;
 mod queue;
+mod rate;
+mod registry;
+mod replay;
+mod sampling;
+mod selftest;
 pub mod server;
+mod session;
+mod stop;
+mod template;
+mod usage;
 mod validation;
+mod vocab;
 
-use infer::Infer;
+use conversation::{ConversationTurn, Message};
+use infer::{Backend, ReservedPermit};
+pub use infer::{GenerationErrorCode, Infer, InferBuilder, InferError};
+use limits::Limits;
 use queue::{Entry, Queue};
+use registry::Continuation;
 use serde::{Deserialize, Serialize};
+use session::Session;
+use std::collections::HashMap;
 use utoipa::ToSchema;
-use validation::Validation;
+pub use validation::{Validation, ValidationError};
 
-#[derive(Clone, Debug, Deserialize, ToSchema)]
-pub(crate) struct GenerateParameters {
+/// Parameters of a generation request, see [`GenerateParameters::builder`] to build them
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct GenerateParameters {
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
     pub best_of: Option<usize>,
@@ -31,8 +96,9 @@ pub(crate) struct GenerateParameters {
         example = 1.03
     )]
     pub repetition_penalty: Option<f32>,
+    /// 0, or a value of at least the vocabulary size, disables the top-k filtering
     #[serde(default)]
-    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
+    #[schema(minimum = 0, nullable = true, default = "null", example = 10)]
     pub top_k: Option<i32>,
     #[serde(default)]
     #[schema(
@@ -53,8 +119,8 @@ pub(crate) struct GenerateParameters {
     )]
     pub typical_p: Option<f32>,
     #[serde(default)]
-    #[schema(default = "false", example = true)]
-    pub do_sample: bool,
+    #[schema(nullable = true, default = "null", example = true)]
+    pub do_sample: Option<bool>,
     #[serde(default = "default_max_new_tokens")]
     #[schema(exclusive_minimum = 0, exclusive_maximum = 512, default = "20")]
     pub max_new_tokens: u32,
@@ -64,15 +130,23 @@ pub(crate) struct GenerateParameters {
     #[serde(default)]
     #[schema(inline, max_items = 4, example = json ! (["photographer"]))]
     pub stop: Vec<String>,
+    /// Stop sequences with their own behavior, in addition to `stop`
+    #[serde(default)]
+    pub stop_config: Vec<StopConfig>,
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = "null")]
     pub truncate: Option<usize>,
     #[serde(default)]
-    #[schema(default = "false", example = true)]
-    pub watermark: bool,
+    #[schema(nullable = true, default = "null", example = true)]
+    pub watermark: Option<bool>,
     #[serde(default)]
     #[schema(default = "true")]
     pub details: bool,
+    /// Add the byte offsets of the prompt tokens in `inputs` to the prefill details, and the
+    /// token statistics of the prompt. Only used with `details`, ignored when streaming
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub decoder_input_details: bool,
     #[serde(default)]
     #[schema(
         exclusive_minimum = 0,
@@ -81,12 +155,209 @@ pub(crate) struct GenerateParameters {
         example = "null"
     )]
     pub seed: Option<u64>,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = "null"
+    )]
+    pub deadline_ms: Option<u64>,
+    /// Abort the generation and answer 504 if the response is not ready after this long, e.g. to
+    /// answer before the timeout of a gateway. Only used by `/generate`
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = "null"
+    )]
+    pub response_timeout_ms: Option<u64>,
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub no_cache: bool,
+    #[serde(default = "default_heartbeat")]
+    #[schema(default = "true", example = true)]
+    pub heartbeat: bool,
+    #[serde(default)]
+    #[schema(default = "0", example = 1)]
+    pub retry_on_empty: u8,
+    #[serde(default)]
+    /// Session of the request: the backend can reuse its state of the previous prompt and
+    /// generated text of the session. Rejected unless the router keeps sessions (`max_sessions`)
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub session_id: Option<String>,
+    /// Do not pause the generation of this request to add new requests to its batch, depending on
+    /// the batching policy of the router
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub latency_sensitive: bool,
+    /// Send the text generated so far with each streamed token
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub stream_full_text: bool,
+    /// Queue the request even if its estimated wait is too long
+    /// Ignored unless the request is sent with one of the API keys allowed to force queuing
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub force_queue: bool,
+    /// Fail the request if the router cannot generate the `best_of` sequences at once instead of
+    /// generating fewer sequences
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub strict_n: bool,
+    /// Put the request back in the queue if its batch fails before it streamed any token
+    /// Defaults to the `auto_requeue` setting of the router
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub auto_requeue: Option<bool>,
+    /// Batch the request with fewer new tokens than `max_new_tokens` instead of waiting when the
+    /// batch token budget of the router is short. `details` then report both values
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub allow_downgrade: bool,
+    /// Apply the NFC normalization to `inputs` and strip their invisible characters (zero width
+    /// joiners, BOMs...) before tokenization. Inputs with characters left by a broken decoding
+    /// are rejected. Defaults to the router `--normalize-input` setting
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub normalize_input: Option<bool>,
+    /// Remove the spaces before punctuation and English contractions in `generated_text`
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub clean_up_tokenization_spaces: bool,
+    /// Send the unmodified tokenizer piece of each token as its text, e.g. `Ġworld` or `▁world`
+    /// `generated_text` is still detokenized. Cannot be combined with `stream_full_text`, with
+    /// `stop` or the `stop_config` removed from the text, or with the post-generation patterns
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub raw_token_text: bool,
+    /// Maximum number of tokens per second sent by `generate_stream`. The backend still
+    /// generates at full speed and the last event is sent as soon as the generation ends
+    /// `max_new_tokens` must be sent in less than 10 minutes at this rate
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        nullable = true,
+        default = "null",
+        example = "null"
+    )]
+    pub stream_rate_limit: Option<f32>,
+    /// Send a `progress` event carrying a `StreamProgress` every this many tokens streamed by
+    /// `generate_stream`. The tokens are counted as they are sent, after the `stream_rate_limit`
+    /// pacing
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = "null"
+    )]
+    pub progress_interval_tokens: Option<u32>,
+    /// Only send the `progress` events and the last event, without the token events. Needs
+    /// `progress_interval_tokens`
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub progress_only: bool,
+    /// Only generate these token ids, e.g. digits and punctuation to fill a schema. The
+    /// generation still ends at the stop sequences and after `max_new_tokens`, but only at the
+    /// EOS token if its id is in the list
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub allowed_tokens: Option<Vec<u32>>,
+}
+
+/// State set by the router on a request, carried alongside its [`GenerateRequest`]
+/// It is never received from or sent to the clients
+#[derive(Clone, Debug, Default)]
+pub(crate) struct RequestContext {
+    /// Set when the request is started in its session
+    pub session: Option<Session>,
+    /// Set to pin the request to a backend
+    pub backend: Option<Backend>,
+    /// Set when the request probes an unhealthy backend, the probe being already let through its
+    /// circuit breaker
+    pub admitted: bool,
+    /// Set for the continuations of a previous generation
+    pub continued: Option<Continuation>,
+    /// Set for health probes
+    pub probe: bool,
+    /// Set for the replies of a conversation
+    pub conversation: Option<ConversationTurn>,
+    /// Set from the API key of the request
+    pub limits: Option<Limits>,
+    /// Set to the keyed hash of the API key of the request
+    pub api_key_id: Option<String>,
+    /// Set to the sampling decision of the trace of the request, None outside of an HTTP request
+    pub trace_sampled: Option<bool>,
+    /// Set once the inputs are resolved
+    pub input_source: InputSource,
+    /// Set for the sequences of `best_of`, whose permits are acquired together
+    pub permit: Option<ReservedPermit>,
+}
+
+/// Stop sequence of `stop_config`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub struct StopConfig {
+    #[schema(example = "\n\n")]
+    pub sequence: String,
+    /// Send the text of the stop sequence with the generated tokens, the stop sequences of `stop`
+    /// being removed from them
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub keep_text: bool,
+}
+
+/// Stop sequence that ended the generation
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub struct MatchedStop {
+    #[schema(example = "\n\n")]
+    pub sequence: String,
+    /// Position of the stop sequence in `stop` followed by `stop_config`
+    #[schema(example = 0)]
+    pub index: usize,
+}
+
+/// Source of the inputs sent to the model
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum InputSource {
+    /// `inputs`
+    #[default]
+    Text,
+    /// Server-side `template` rendered with `inputs` as `{input}` and with `template_vars`
+    Template,
+}
+
+/// Estimated cost of a request according to the cost model of the router
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, ToSchema)]
+pub struct EstimatedCost {
+    #[schema(example = 0.0042)]
+    pub amount: f64,
+    #[schema(example = "USD")]
+    pub currency: String,
+}
+
+impl GenerateParameters {
+    /// The backend samples if `do_sample` or any logits warper is set
+    /// `top_k` is normalized by `Infer::prepare`, the values keeping the whole vocabulary being 0
+    pub(crate) fn sampling(&self) -> bool {
+        self.do_sample.unwrap_or(false)
+            || self.temperature.is_some()
+            || self.top_k.map_or(false, |top_k| top_k > 0)
+            || self.top_p.is_some()
+            || self.typical_p.is_some()
+    }
 }
 
 fn default_max_new_tokens() -> u32 {
     20
 }
 
+fn default_heartbeat() -> bool {
+    true
+}
+
 fn default_parameters() -> GenerateParameters {
     GenerateParameters {
         best_of: None,
@@ -95,23 +366,74 @@ fn default_parameters() -> GenerateParameters {
         top_k: None,
         top_p: None,
         typical_p: None,
-        do_sample: false,
+        do_sample: None,
         max_new_tokens: default_max_new_tokens(),
         return_full_text: None,
         stop: Vec::new(),
+        stop_config: Vec::new(),
         truncate: None,
-        watermark: false,
+        watermark: None,
         details: false,
+        decoder_input_details: false,
         seed: None,
+        deadline_ms: None,
+        response_timeout_ms: None,
+        no_cache: false,
+        heartbeat: default_heartbeat(),
+        retry_on_empty: 0,
+        session_id: None,
+        latency_sensitive: false,
+        stream_full_text: false,
+        force_queue: false,
+        strict_n: false,
+        auto_requeue: None,
+        allow_downgrade: false,
+        normalize_input: None,
+        clean_up_tokenization_spaces: false,
+        raw_token_text: false,
+        stream_rate_limit: None,
+        progress_interval_tokens: None,
+        progress_only: false,
+        allowed_tokens: None,
     }
 }
 
-#[derive(Clone, Debug, Deserialize, ToSchema)]
-pub(crate) struct GenerateRequest {
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct GenerateRequest {
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
+    /// Name of a server-side prompt template rendered with `inputs` as `{input}`
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub template: Option<String>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub template_vars: Option<HashMap<String, String>>,
+    /// Name of a server-side parameter preset, the parameters given in the request taking
+    /// precedence
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub preset: Option<String>,
+    /// Name of the served model generating the text, the model of the router if it is not set
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub model: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct JobRequest {
+    #[serde(flatten)]
+    pub request: GenerateRequest,
+    /// URL the final status of the job is POSTed to
+    #[serde(default)]
+    #[schema(
+        nullable = true,
+        default = "null",
+        example = "https://example.com/jobs"
+    )]
+    pub callback_url: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, ToSchema)]
@@ -121,6 +443,14 @@ pub(crate) struct CompatGenerateRequest {
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
     #[serde(default)]
+    pub template: Option<String>,
+    #[serde(default)]
+    pub template_vars: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub preset: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
     #[allow(dead_code)]
     pub stream: bool,
 }
@@ -130,35 +460,49 @@ impl From<CompatGenerateRequest> for GenerateRequest {
         Self {
             inputs: req.inputs,
             parameters: req.parameters,
+            template: req.template,
+            template_vars: req.template_vars,
+            preset: req.preset,
+            model: req.model,
         }
     }
 }
 
-#[derive(Debug, Serialize, ToSchema)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub struct PrefillToken {
     #[schema(example = 0)]
-    id: u32,
+    pub id: u32,
     #[schema(example = "test")]
-    text: String,
+    pub text: String,
     #[schema(nullable = true, example = - 0.34)]
-    logprob: f32,
+    pub logprob: f32,
+    /// Byte offsets of the token in `inputs`, only set with `decoder_input_details`
+    /// Special tokens have empty offsets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0)]
+    pub start: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 4)]
+    pub end: Option<usize>,
 }
 
-#[derive(Debug, Serialize, ToSchema)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub struct Token {
     #[schema(example = 0)]
-    id: u32,
+    pub id: u32,
+    /// Empty while the token could be the start of a stop sequence, the held back text being
+    /// added to the next tokens. The text of a stop sequence is never sent
     #[schema(example = "test")]
-    text: String,
+    pub text: String,
     #[schema(nullable = true, example = - 0.34)]
-    logprob: f32,
+    pub logprob: f32,
     #[schema(example = "false")]
-    special: bool,
+    pub special: bool,
 }
 
-#[derive(Serialize, ToSchema)]
-#[serde(rename_all(serialize = "snake_case"))]
-pub(crate) enum FinishReason {
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
     #[schema(rename = "length")]
     Length,
     #[serde(rename = "eos_token")]
@@ -166,10 +510,17 @@ pub(crate) enum FinishReason {
     EndOfSequenceToken,
     #[schema(rename = "stop_sequence")]
     StopSequence,
+    #[schema(rename = "empty_generation")]
+    EmptyGeneration,
+    #[schema(rename = "content_filter")]
+    ContentFilter,
+    /// The backend kept generating past `max_new_tokens` and the router ended the generation
+    #[schema(rename = "router_limit")]
+    RouterLimit,
 }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct BestOfSequence {
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct BestOfSequence {
     #[schema(example = "test")]
     pub generated_text: String,
     #[schema(example = "length")]
@@ -182,8 +533,47 @@ pub(crate) struct BestOfSequence {
     pub tokens: Vec<Token>,
 }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct Details {
+/// Parameters used by the backend after defaulting and validation
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct ValidParameters {
+    #[schema(example = 0.5)]
+    pub temperature: f32,
+    /// 0 if top-k filtering is disabled
+    #[schema(example = 10)]
+    pub top_k: u32,
+    #[schema(example = 0.95)]
+    pub top_p: f32,
+    #[schema(example = 0.95)]
+    pub typical_p: f32,
+    #[schema(example = 1.03)]
+    pub repetition_penalty: f32,
+    #[schema(example = true)]
+    pub do_sample: bool,
+    #[schema(example = 20)]
+    pub max_new_tokens: u32,
+    #[schema(example = json!(["photographer"]))]
+    pub stop: Vec<String>,
+    /// Seed of the sampling, randomly generated if the request has none
+    #[schema(example = 42)]
+    pub seed: u64,
+    #[schema(example = false)]
+    pub watermark: bool,
+    /// Token ids the generation was restricted to, all the tokens if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schema(example = json!([]))]
+    pub allowed_tokens: Vec<u32>,
+    /// Set by the router when it lowered `max_new_tokens` to fit the request in a batch
+    #[serde(skip)]
+    pub(crate) requested_max_new_tokens: Option<u32>,
+    /// Set by the validation when the normalization changed the inputs
+    #[serde(skip)]
+    pub(crate) normalized_chars: Option<u32>,
+    #[serde(skip)]
+    pub(crate) input_source: InputSource,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct Details {
     #[schema(example = "length")]
     pub finish_reason: FinishReason,
     #[schema(example = 1)]
@@ -194,37 +584,488 @@ pub(crate) struct Details {
     pub tokens: Vec<Token>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub best_of_sequences: Option<Vec<BestOfSequence>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 1)]
+    pub attempts: Option<u32>,
+    /// Number of times the request was put back in the queue after a batch failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0)]
+    pub retries: Option<u32>,
+    /// Only set when the generation ended on a stop sequence
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub matched_stop: Option<MatchedStop>,
+    pub parameters: ValidParameters,
+    /// The backend reported another number of generated tokens: `generated_tokens` is the number
+    /// of tokens sent by the router
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[schema(default = "false", example = "false")]
+    pub token_count_mismatch: bool,
+    /// The texts of the prefill tokens do not match `inputs` at the offsets of the router
+    /// tokenization, only checked with `decoder_input_details`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[schema(default = "false", example = "false")]
+    pub prefill_mismatch: bool,
+    /// Statistics of `inputs` before truncation, only set with `decoder_input_details`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 24)]
+    pub prompt_chars: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 24)]
+    pub prompt_bytes: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 6)]
+    pub prompt_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 4.0)]
+    pub chars_per_token: Option<f32>,
+    /// Only set when the request allowed the router to lower `max_new_tokens` to fit it in a batch,
+    /// and the router did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 2000)]
+    pub requested_max_new_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 300)]
+    pub effective_max_new_tokens: Option<u32>,
+    /// The normalization of `inputs` changed them
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[schema(default = "false", example = "false")]
+    pub normalized: bool,
+    /// Number of characters of `inputs` removed or replaced by the normalization
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub normalized_chars: Option<u32>,
+    /// `template` if the inputs are a rendered template
+    #[serde(default)]
+    #[schema(example = "text")]
+    pub input_source: InputSource,
+    /// Only set if the router has a cost model, over all the sequences and attempts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub estimated_cost: Option<EstimatedCost>,
 }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct GenerateResponse {
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct GenerateResponse {
     #[schema(example = "test")]
     pub generated_text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub details: Option<Details>,
+    /// Id of the continued request, its generated text being prepended to `generated_text`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub continued_from: Option<u64>,
+    /// Set when fewer than `best_of` sequences were generated because the router was overloaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub warning: Option<String>,
 }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct StreamDetails {
+/// Request as the router would generate it, returned by `POST /validate`
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct DryRunResponse {
+    #[schema(example = "bigscience/bloom")]
+    pub model: String,
+    #[schema(example = "stable")]
+    pub backend: &'static str,
+    /// Preset whose parameters filled the ones not given in the request
+    #[schema(nullable = true, example = "null")]
+    pub preset: Option<String>,
+    #[schema(nullable = true, example = "null")]
+    pub template: Option<String>,
+    pub input_source: InputSource,
+    /// Inputs sent to the model, after the rendering of the template, the normalization and the
+    /// truncation
+    #[schema(example = "My name is Olivier and I")]
+    pub inputs: String,
+    #[schema(example = 7)]
+    pub input_length: u32,
+    /// Number of characters changed by the normalization of the inputs
+    #[schema(nullable = true, example = "null")]
+    pub normalized_chars: Option<u32>,
+    /// The seed is the one the request would use if it was sent now
+    pub parameters: ValidParameters,
+    /// Time needed to generate the queued requests of the backend, None if the throughput of the
+    /// backend is not known yet
+    #[schema(nullable = true, example = 1500)]
+    pub estimated_wait_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct ConversationRequest {
+    /// User message appended to the conversation
+    #[schema(example = "What is Deep Learning?")]
+    pub message: String,
+    #[serde(default = "default_parameters")]
+    pub parameters: GenerateParameters,
+    /// Name of a server-side prompt template rendered with the conversation history as `{input}`
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub template: Option<String>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub template_vars: Option<HashMap<String, String>>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub preset: Option<String>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub model: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct ConversationHistory {
+    #[schema(example = "chat-42")]
+    pub conversation_id: String,
+    pub messages: Vec<Message>,
+}
+
+/// Drain status of the router
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct DrainStatus {
+    /// New generation requests are rejected
+    #[schema(example = true)]
+    pub draining: bool,
+    #[schema(example = 0)]
+    pub queued: usize,
+    #[schema(example = 2)]
+    pub running: usize,
+}
+
+/// KV-cache usage of a backend, as reported by its last prefill or decode response
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct CacheUtilization {
+    #[schema(example = "stable")]
+    pub backend: &'static str,
+    #[schema(example = 900)]
+    pub blocks_used: u32,
+    #[schema(example = 1000)]
+    pub blocks_total: u32,
+    #[schema(example = 0.9)]
+    pub utilization: f64,
+}
+
+/// Requests waiting in the queue of a backend
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct QueueStatus {
+    #[schema(example = "stable")]
+    pub backend: &'static str,
+    #[schema(example = 2)]
+    pub size: usize,
+    /// Time spent in the queue by the oldest request
+    #[schema(example = 1500)]
+    pub oldest_age_ms: u64,
+    /// Tokens of the inputs of the queued requests
+    #[schema(example = 24)]
+    pub input_tokens: u64,
+    /// Maximum number of tokens the queued requests will generate
+    #[schema(example = 40)]
+    pub token_debt: u64,
+    /// Health probes, batched before the other requests
+    #[schema(example = 0)]
+    pub priority: usize,
+    /// None if the backend does not report its KV-cache usage
+    pub cache_utilization: Option<CacheUtilization>,
+    /// Fraction of the batch allowed by the inter-token latency controller, None without latency
+    /// target
+    #[schema(nullable = true, example = 0.75)]
+    pub latency_budget: Option<f64>,
+    /// First requests of the queue, in batching order
+    pub requests: Vec<QueuedRequest>,
+}
+
+/// Connection and response times of a shard of a backend
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub(crate) struct ShardStatus {
+    #[schema(example = "stable")]
+    pub backend: &'static str,
+    #[schema(example = "/tmp/text-generation-server-0")]
+    pub uri: String,
+    /// False if the last call to the shard failed
+    pub healthy: bool,
+    #[schema(nullable = true, example = "null")]
+    pub last_error: Option<String>,
+    /// Median response time of the recent prefill and decode calls, None before the first call
+    #[schema(nullable = true, example = 42)]
+    pub p50_ms: Option<u64>,
+    #[schema(nullable = true, example = 60)]
+    pub p95_ms: Option<u64>,
+    /// Set while the shard is slower than the other shards by `slow_shard_factor`
+    pub slow: bool,
+}
+
+/// Queued request, without its inputs
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct QueuedRequest {
+    #[schema(example = 42)]
+    pub id: u64,
+    #[schema(example = 1500)]
+    pub age_ms: u64,
+    #[schema(example = 12)]
+    pub input_length: u32,
+    #[schema(example = 20)]
+    pub max_new_tokens: u32,
+    pub priority: bool,
+    pub latency_sensitive: bool,
+    /// Times the request was put back in the queue after a batch failure
+    #[schema(example = 0)]
+    pub requeues: u32,
+    /// Keyed hash of the API key of the request
+    #[schema(nullable = true, example = "9a3e0c5b71d2f4e8")]
+    pub api_key_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct ContinueRequest {
+    /// Id of a completed request, returned in the `x-request-id` header
+    #[schema(example = 0)]
+    pub request_id: u64,
+    #[serde(default = "default_parameters")]
+    pub parameters: GenerateParameters,
+}
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct StreamDetails {
     #[schema(example = "length")]
     pub finish_reason: FinishReason,
     #[schema(example = 1)]
     pub generated_tokens: u32,
     #[schema(nullable = true, example = 42)]
     pub seed: Option<u64>,
+    /// Only set when the generation ended on a stop sequence
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub matched_stop: Option<MatchedStop>,
+    /// The backend reported another number of generated tokens: `generated_tokens` is the number
+    /// of tokens sent by the router
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[schema(default = "false", example = "false")]
+    pub token_count_mismatch: bool,
+    /// Only set if the router has a cost model
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub estimated_cost: Option<EstimatedCost>,
 }
 
-#[derive(Serialize, ToSchema)]
-pub(crate) struct StreamResponse {
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct StreamResponse {
     pub token: Token,
     #[schema(nullable = true, default = "null", example = "test")]
     pub generated_text: Option<String>,
     #[schema(nullable = true, default = "null")]
     pub details: Option<StreamDetails>,
+    /// Text of all the tokens sent so far, including this one, if `stream_full_text` is set
+    /// Not sent anymore once the text is longer than the limit of the router
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, default = "null", example = "test")]
+    pub generated_text_so_far: Option<String>,
+    /// The optional fields were dropped to keep the event under the size limit of the router
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    #[schema(default = "false", example = "false")]
+    pub truncated: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all(serialize = "snake_case"))]
+pub(crate) enum RequestStatus {
+    #[schema(rename = "queued")]
+    Queued,
+    #[schema(rename = "running")]
+    Running,
+    #[schema(rename = "completed")]
+    Completed,
+    #[schema(rename = "failed")]
+    Failed,
+    #[schema(rename = "cancelled")]
+    Cancelled,
 }
 
 #[derive(Serialize, ToSchema)]
-pub(crate) struct ErrorResponse {
+pub(crate) struct GenerationStatus {
+    #[schema(example = 0)]
+    pub id: u64,
+    #[schema(example = "queued")]
+    pub status: RequestStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 3)]
+    pub queue_position: Option<usize>,
+    #[schema(example = 1)]
+    pub generated_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct JobStatus {
+    #[schema(example = 0)]
+    pub id: u64,
+    #[schema(example = "running")]
+    pub status: RequestStatus,
+    /// Number of tokens stored so far
+    #[schema(example = 1)]
+    pub generated_tokens: u32,
+    /// Set once the job is completed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub response: Option<GenerateResponse>,
+    /// Set if the job failed or was cancelled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub error: Option<ErrorResponse>,
+    /// Set once the result was sent to the `callback_url` of the job, false if all the attempts
+    /// failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub delivered: Option<bool>,
+    /// Set if the response was too large to be kept in memory: it is retrieved with
+    /// `GET /jobs/{id}/result`
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub spilled: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    /// Human readable message, which can change between versions
     pub error: String,
+    /// Stable identifier of the error, to match instead of `error`
+    ///
+    /// Retryable: `overloaded`, `backend_overloaded`, `backend_unavailable` (after `Retry-After` if
+    /// set), `draining` (on another replica), `unavailable`, `incomplete_generation`,
+    /// `model_loading`, `connection_limit` (once another stream of the client IP or API key is
+    /// closed), `rate_limit` (after a second)
+    ///
+    /// Not retryable as is: `validation`, `generation`, `backend_oom`, `backend_invalid_argument`,
+    /// `deadline_exceeded`, `response_timeout`, `cancelled`, `blocked`, `content_filter`, `not_found`,
+    /// `unauthorized`, `serialization`, `unknown_model`
+    ///
+    /// Readiness probe only: `vocab_mismatch` and `selftest_mismatch`, until the deployment is fixed
+    #[schema(example = "overloaded")]
+    pub error_type: String,
+    /// Number of tokens of `inputs`, when they are too long or when the response timed out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub input_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub max_input_length: Option<usize>,
+    /// Estimated time in the queue, when the model is overloaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub estimated_wait_ms: Option<u64>,
+    /// Cause of an `overloaded` or `backend_overloaded` error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub reason: Option<OverloadReason>,
+    /// Limit reached by the request: concurrent requests for `concurrency`, queue wait in
+    /// milliseconds for `admission`, open streams for `connection_limit`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub limit: Option<u64>,
+    /// Current value of `limit`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub current: Option<u64>,
+    /// Number of tokens generated before the response timed out
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub generated_tokens: Option<u32>,
+}
+
+impl ErrorResponse {
+    /// Error without the optional fields
+    pub(crate) fn new(error: String, error_type: &str) -> Self {
+        Self {
+            error,
+            error_type: error_type.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Cause of a 429 Too Many Requests
+/// Clients reduce their concurrency on `concurrency` and `connection_limit`, and back off on
+/// `admission`, `backend` and `rate_limit`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OverloadReason {
+    /// All the concurrent requests of the router are running
+    Concurrency,
+    /// The estimated queue wait of the request is longer than the router accepts
+    Admission,
+    /// The backend rejected the request
+    Backend,
+    /// The client IP or the API key of the request has the maximum number of open streams
+    ConnectionLimit,
+    /// The endpoint was called too often
+    RateLimit,
+}
+
+impl OverloadReason {
+    /// Label of the metrics
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            OverloadReason::Concurrency => "concurrency",
+            OverloadReason::Admission => "admission",
+            OverloadReason::Backend => "backend",
+            OverloadReason::ConnectionLimit => "connection_limit",
+            OverloadReason::RateLimit => "rate_limit",
+        }
+    }
+}
+
+/// Cause of a stream aborted by the server
+/// Clients retry on `shutdown` and `backend_error`, and keep the partial text on `deadline`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AbortReason {
+    /// The router stopped before the generation ended
+    Shutdown,
+    /// The deadline or the response timeout of the request expired
+    Deadline,
+    /// The generated text was rejected by the post-generation hook
+    Moderation,
+    /// The backend failed the generation
+    BackendError,
+    /// The request was cancelled with `DELETE /generation/{id}`
+    Cancelled,
+}
+
+impl AbortReason {
+    /// Label of the metrics
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            AbortReason::Shutdown => "shutdown",
+            AbortReason::Deadline => "deadline",
+            AbortReason::Moderation => "moderation",
+            AbortReason::BackendError => "backend_error",
+            AbortReason::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Payload of the `progress` events of a stream with a `progress_interval_tokens`
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize, ToSchema)]
+pub struct StreamProgress {
+    /// Tokens sent to the client so far
+    #[schema(example = 64)]
+    pub tokens_generated: u32,
+    /// Time since the request was received
+    #[schema(example = 2150)]
+    pub elapsed_ms: u64,
+    /// Rate of the tokens sent since the previous `progress` event
+    #[schema(example = 30.5)]
+    pub tokens_per_second: f64,
+}
+
+/// Payload of the terminal `aborted` event of a stream, sent after its error event
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub struct StreamAborted {
+    pub reason: AbortReason,
+    /// Tokens generated before the abort, including the ones that were held back
+    #[schema(example = 12)]
+    pub generated_tokens: u32,
+    /// Stable identifier of the error, see `ErrorResponse`
+    #[schema(example = "deadline_exceeded")]
     pub error_type: String,
 }
@@ -0,0 +1,283 @@
+/// Token limits of the requests per API key
+use crate::usage::api_key_id;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Limits applied to the requests of an API key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+pub(crate) struct Limits {
+    #[schema(example = 1000)]
+    pub max_input_length: usize,
+    /// None if `max_new_tokens` is only limited by `max_total_tokens`
+    #[schema(nullable = true, example = 512)]
+    pub max_new_tokens: Option<u32>,
+}
+
+/// Limits of a profile, the limits it does not set being taken from the default profile
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    #[serde(default)]
+    max_input_length: Option<usize>,
+    #[serde(default)]
+    max_new_tokens: Option<u32>,
+    /// The generations of the API key are written to the audit log
+    #[serde(default)]
+    audit: bool,
+}
+
+impl Profile {
+    fn apply(&self, limits: Limits) -> Limits {
+        Limits {
+            max_input_length: self.max_input_length.unwrap_or(limits.max_input_length),
+            max_new_tokens: self.max_new_tokens.or(limits.max_new_tokens),
+        }
+    }
+}
+
+/// Content of the limit profiles file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfilesFile {
+    /// Applies to the requests without a known API key, over the limits of the router
+    #[serde(default)]
+    default: Profile,
+    #[serde(default)]
+    api_keys: HashMap<String, Profile>,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum LimitsError {
+    #[error("could not read limit profiles: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse limit profiles: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("`max_input_length` of the {0} profile ({1}) must be < `max_total_tokens` ({2})")]
+    InputLength(String, usize, usize),
+    #[error("`max_new_tokens` of the {0} profile must be > 0")]
+    MaxNewTokens(String),
+    #[error("`audit` can only be set in the profiles of the API keys")]
+    DefaultAudit,
+}
+
+/// Limit profiles loaded from a JSON file
+/// `{"default": {"max_input_length": 1000}, "api_keys": {"<key>": {"max_input_length": 6000}}}`
+/// The API keys of the profiles with `"audit": true` are audited
+#[derive(Clone, Debug)]
+pub(crate) struct LimitProfiles {
+    /// None if all the requests have the limits of the router
+    path: Option<PathBuf>,
+    /// Limits of the router
+    router: Limits,
+    max_total_tokens: usize,
+    profiles: Arc<RwLock<Profiles>>,
+}
+
+#[derive(Debug)]
+struct Profiles {
+    default: Limits,
+    api_keys: HashMap<String, Limits>,
+    audited: HashSet<String>,
+}
+
+impl LimitProfiles {
+    /// All the requests have the limits of the router
+    pub(crate) fn new(max_input_length: usize, max_total_tokens: usize) -> Self {
+        let router = Limits {
+            max_input_length,
+            max_new_tokens: None,
+        };
+        Self {
+            path: None,
+            router,
+            max_total_tokens,
+            profiles: Arc::new(RwLock::new(Profiles {
+                default: router,
+                api_keys: HashMap::new(),
+                audited: HashSet::new(),
+            })),
+        }
+    }
+
+    pub(crate) fn load(
+        path: Option<PathBuf>,
+        max_input_length: usize,
+        max_total_tokens: usize,
+    ) -> Result<Self, LimitsError> {
+        let profiles = Self {
+            path,
+            ..Self::new(max_input_length, max_total_tokens)
+        };
+        profiles.reload()?;
+        Ok(profiles)
+    }
+
+    /// Load the profiles again from the file
+    /// The current profiles are kept if the file is invalid
+    pub(crate) fn reload(&self) -> Result<(), LimitsError> {
+        if let Some(path) = &self.path {
+            let profiles = self.resolve(read_profiles(path)?)?;
+            tracing::info!(
+                "Loaded {} limit profiles, {} of them audited",
+                profiles.api_keys.len(),
+                profiles.audited.len()
+            );
+            *self.profiles.write() = profiles;
+        }
+        Ok(())
+    }
+
+    /// Limits of the requests sent with `api_key`
+    /// The default profile applies to the requests without API key or with an unknown one
+    pub(crate) fn get(&self, api_key: Option<&str>) -> Limits {
+        let profiles = self.profiles.read();
+        api_key
+            .and_then(|api_key| profiles.api_keys.get(api_key))
+            .copied()
+            .unwrap_or(profiles.default)
+    }
+
+    /// The generations of the API key with the id `api_key_id` are written to the audit log
+    pub(crate) fn audited(&self, api_key_id: &str) -> bool {
+        self.profiles.read().audited.contains(api_key_id)
+    }
+
+    /// Number of audited API keys
+    pub(crate) fn audited_count(&self) -> usize {
+        self.profiles.read().audited.len()
+    }
+
+    fn resolve(&self, file: ProfilesFile) -> Result<Profiles, LimitsError> {
+        if file.default.audit {
+            return Err(LimitsError::DefaultAudit);
+        }
+        let default = self.check("default", file.default.apply(self.router))?;
+        let audited = file
+            .api_keys
+            .iter()
+            .filter(|(_, profile)| profile.audit)
+            .map(|(api_key, _)| api_key_id(api_key))
+            .collect();
+        let api_keys = file
+            .api_keys
+            .into_iter()
+            .map(|(api_key, profile)| {
+                // Only the id of the key is logged
+                let name = api_key_id(&api_key);
+                let limits = self.check(&name, profile.apply(default))?;
+                Ok((api_key, limits))
+            })
+            .collect::<Result<_, LimitsError>>()?;
+        Ok(Profiles {
+            default,
+            api_keys,
+            audited,
+        })
+    }
+
+    fn check(&self, name: &str, limits: Limits) -> Result<Limits, LimitsError> {
+        if limits.max_input_length >= self.max_total_tokens {
+            return Err(LimitsError::InputLength(
+                name.to_string(),
+                limits.max_input_length,
+                self.max_total_tokens,
+            ));
+        }
+        if limits.max_new_tokens == Some(0) {
+            return Err(LimitsError::MaxNewTokens(name.to_string()));
+        }
+        Ok(limits)
+    }
+}
+
+fn read_profiles(path: &Path) -> Result<ProfilesFile, LimitsError> {
+    let source = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&source)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles(source: &str) -> Result<LimitProfiles, LimitsError> {
+        let profiles = LimitProfiles::new(1000, 8192);
+        *profiles.profiles.write() = profiles.resolve(serde_json::from_str(source)?)?;
+        Ok(profiles)
+    }
+
+    #[test]
+    fn test_get() {
+        let profiles = profiles(
+            r#"{
+                "default": {"max_new_tokens": 256},
+                "api_keys": {"batch-key": {"max_input_length": 6000, "max_new_tokens": 1024}, "other-key": {}}
+            }"#,
+        )
+        .unwrap();
+        let default = Limits {
+            max_input_length: 1000,
+            max_new_tokens: Some(256),
+        };
+        assert_eq!(profiles.get(None), default);
+        assert_eq!(profiles.get(Some("unknown-key")), default);
+        assert_eq!(profiles.get(Some("other-key")), default);
+        assert_eq!(
+            profiles.get(Some("batch-key")),
+            Limits {
+                max_input_length: 6000,
+                max_new_tokens: Some(1024),
+            }
+        );
+    }
+
+    #[test]
+    fn test_without_profiles() {
+        let profiles = LimitProfiles::new(1000, 8192);
+        assert_eq!(
+            profiles.get(Some("batch-key")),
+            Limits {
+                max_input_length: 1000,
+                max_new_tokens: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_profiles() {
+        assert!(matches!(
+            profiles(r#"{"api_keys": {"batch-key": {"max_input_length": 8192}}}"#),
+            Err(LimitsError::InputLength(name, 8192, 8192)) if name == api_key_id("batch-key")
+        ));
+        assert!(matches!(
+            profiles(r#"{"default": {"max_new_tokens": 0}}"#),
+            Err(LimitsError::MaxNewTokens(_))
+        ));
+        assert!(matches!(
+            profiles(r#"{"default": {"max_input_lenght": 10}}"#),
+            Err(LimitsError::Parse(_))
+        ));
+        assert!(matches!(
+            profiles(r#"{"default": {"audit": true}}"#),
+            Err(LimitsError::DefaultAudit)
+        ));
+    }
+
+    #[test]
+    fn test_audited() {
+        let profiles = profiles(
+            r#"{"api_keys": {"audited-key": {"audit": true}, "other-key": {"audit": false}}}"#,
+        )
+        .unwrap();
+        assert!(profiles.audited(&api_key_id("audited-key")));
+        assert!(!profiles.audited(&api_key_id("other-key")));
+        assert!(!profiles.audited(&api_key_id("unknown-key")));
+        assert_eq!(profiles.audited_count(), 1);
+        // The audit does not change the limits
+        assert_eq!(profiles.get(Some("audited-key")), profiles.get(None));
+    }
+}
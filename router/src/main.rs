@@ -8,15 +8,26 @@ use opentelemetry::sdk::Resource;
 use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::Path;
-use text_generation_client::ShardedClient;
-use text_generation_router::server;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use text_generation_client::{ClientError, MockConfig, ShardedClient};
+use text_generation_router::server::{
+    self, load_models, AuditConfig, BackendConnection, BatchingPolicy, CallbackConfig,
+    CircuitBreakerConfig, CostModel, FaultConfig, LatencyTarget, LoadingPolicy, ModelConfig,
+    ReplayConfig, ServedModel, ServerOptions, SpillConfig, VocabCheck, VocabMismatchPolicy,
+};
 use tokenizers::Tokenizer;
+use tokio::sync::oneshot;
 use tower_http::cors::AllowOrigin;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, Layer};
 
+/// Delay before the second attempt to connect to the shards
+const CONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Maximum delay between two attempts to connect to the shards
+const CONNECT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
 /// App Configuration
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -35,10 +46,37 @@ struct Args {
     max_batch_size: usize,
     #[clap(default_value = "20", long, env)]
     max_waiting_tokens: usize,
+    /// Maximum input tokens of a batch added to a running batch, the rest of the queue being
+    /// prefilled as the next chunk after one decode of the running batch. The queue is split
+    /// between requests: a single prompt longer than this is still prefilled in one call, the
+    /// backends prefilling each request whole
+    #[clap(long, env)]
+    prefill_chunk_tokens: Option<u32>,
+    /// Budget of the inputs and new tokens of the requests of a batch. Unlimited if it is not set
+    #[clap(long, env)]
+    max_batch_total_tokens: Option<u32>,
+    /// Requests sent with `allow_downgrade` are batched with fewer new tokens than requested
+    /// when the budget is short, but never fewer than this
+    #[clap(default_value = "16", long, env)]
+    min_downgraded_new_tokens: u32,
+    /// The budget of the requests added to a running batch shrinks when the KV-cache utilization
+    /// reported by the backend exceeds this fraction. Only used with `max_batch_total_tokens`
+    #[clap(default_value = "0.9", long, env)]
+    cache_utilization_threshold: f64,
+    #[clap(default_value = "throughput", long, env)]
+    batching_policy: BatchingPolicy,
+    #[clap(long, env)]
+    all_latency_sensitive: bool,
+    #[clap(long, env)]
+    force_watermark: bool,
     #[clap(default_value = "3000", long, short, env)]
     port: u16,
     #[clap(default_value = "/tmp/text-generation-0", long, env)]
     master_shard_uds_path: String,
+    #[clap(long, env)]
+    canary_master_shard_uds_path: Option<String>,
+    #[clap(default_value = "0.0", long, env)]
+    canary_ratio: f32,
     #[clap(default_value = "bigscience/bloom", long, env)]
     tokenizer_name: String,
     #[clap(default_value = "2", long, env)]
@@ -49,6 +87,317 @@ struct Args {
     otlp_endpoint: Option<String>,
     #[clap(long, env)]
     cors_allow_origin: Option<Vec<String>>,
+    #[clap(default_value = "0", long, env)]
+    response_cache_entries: usize,
+    #[clap(default_value = "67108864", long, env)]
+    response_cache_bytes: usize,
+    #[clap(long, env)]
+    queue_heartbeat_interval_secs: Option<u64>,
+    #[clap(long, env)]
+    lenient_json: bool,
+    #[clap(long, env)]
+    usage_sink: Option<String>,
+    /// Price of a prompt token in the cost estimates of the usage records, the `details` and the
+    /// `x-estimated-cost` header. Costs are only estimated if one of the prices is set
+    #[clap(long, env)]
+    cost_per_prefill_token: Option<f64>,
+    /// Price of a generated token
+    #[clap(long, env)]
+    cost_per_decode_token: Option<f64>,
+    /// Price of a second of inference, from the prefill to the last token
+    #[clap(long, env)]
+    cost_per_inference_second: Option<f64>,
+    /// Label of the currency of the prices
+    #[clap(default_value = "USD", long, env)]
+    cost_currency: String,
+    #[clap(long, env)]
+    pre_generation_hook_url: Option<String>,
+    #[clap(default_value = "1000", long, env)]
+    pre_generation_hook_timeout_ms: u64,
+    #[clap(long, env)]
+    pre_generation_hook_fail_open: bool,
+    #[clap(long, env)]
+    post_generation_redact_pattern: Option<Vec<String>>,
+    #[clap(long, env)]
+    post_generation_reject_pattern: Option<Vec<String>>,
+    #[clap(default_value = "8", long, env)]
+    post_generation_window: usize,
+    #[clap(long, env)]
+    prompt_templates_dir: Option<String>,
+    /// JSON file mapping preset names to generation parameters
+    #[clap(long, env)]
+    parameter_presets_path: Option<String>,
+    #[clap(default_value = "1000", long, env)]
+    health_check_cache_ms: u64,
+    #[clap(default_value = "10", long, env)]
+    backend_connect_timeout: u64,
+    #[clap(default_value = "30", long, env)]
+    backend_connect_retries: usize,
+    /// A warning names the shards whose median response time is this many times the median of
+    /// the other shards
+    #[clap(default_value = "2.0", long, env)]
+    slow_shard_factor: f64,
+    #[clap(default_value = "1.0", long, env)]
+    access_log_sample_rate: f64,
+    #[clap(default_value = "10000", long, env)]
+    access_log_slow_threshold_ms: u64,
+    #[clap(long, env)]
+    trace_requests: bool,
+    /// Ratio of the traces recorded for the requests without a `traceparent` header. The requests
+    /// with a `traceparent` follow its sampled flag. The spans of the batching task, the
+    /// token-level events and the `trace_requests` transitions are only recorded for the sampled
+    /// requests
+    #[clap(default_value = "1.0", long, env)]
+    trace_sample_ratio: f64,
+    #[clap(long, env)]
+    mock: bool,
+    #[clap(default_value = "20", long, env)]
+    mock_token_delay_ms: u64,
+    /// Longer texts generated so far are not sent anymore to the clients streaming them
+    #[clap(default_value = "16384", long, env)]
+    max_stream_full_text_bytes: usize,
+    /// The optional fields of larger streamed events are dropped
+    #[clap(long, env)]
+    max_stream_event_bytes: Option<usize>,
+    /// Requests are rejected when the queued tokens ahead of them need more time to be generated
+    /// at the recent decode throughput
+    #[clap(long, env)]
+    max_queue_wait_ms: Option<u64>,
+    /// API keys allowed to bypass `max_queue_wait_ms` with the `force_queue` parameter
+    #[clap(long, env)]
+    force_queue_api_key: Option<Vec<String>>,
+    /// The oldest exchanges of the conversations kept by the router are evicted past this number
+    /// of tokens
+    #[clap(default_value = "1000", long, env)]
+    max_conversation_tokens: u32,
+    /// Conversations are forgotten when they are not updated for this long
+    #[clap(default_value = "3600", long, env)]
+    conversation_ttl_secs: u64,
+    /// API key of the admin routes, which are disabled if it is not set
+    #[clap(long, env)]
+    admin_api_key: Option<String>,
+    /// Key of the hash identifying the API keys in the usage records, audit and access logs and
+    /// admin routes. A random key is used if it is not set, so that the ids change on restart
+    #[clap(long, env)]
+    api_key_id_secret: Option<String>,
+    /// Document the admin routes in the OpenAPI documentation
+    #[clap(long, env)]
+    admin_api_doc: bool,
+    /// Serve the API routes under this prefix, such as `/llm/v1`
+    #[clap(long, env)]
+    path_prefix: Option<String>,
+    /// Do not serve the `/` routes, for gateways reserving `/`
+    #[clap(long, env)]
+    disable_root_route: bool,
+    /// Also serve `/health` at the root when `path_prefix` is set, for the load balancers
+    #[clap(long, env)]
+    root_health_route: bool,
+    /// Consecutive batches failing to reach a backend after which its requests are rejected
+    /// immediately, 0 to disable the circuit breaker
+    #[clap(default_value = "5", long, env)]
+    circuit_breaker_threshold: u32,
+    /// Delay between two requests let through to probe a backend whose circuit breaker is open
+    #[clap(default_value = "5000", long, env)]
+    circuit_breaker_probe_interval_ms: u64,
+    /// Put the requests of a batch failing as a whole (e.g. on a shard restart) back in the queue
+    /// if they did not stream any token yet, instead of failing them. Requests can override it
+    /// with the `auto_requeue` parameter
+    #[clap(long, env)]
+    auto_requeue: bool,
+    /// Directory where the requests failing during generation are captured, to be replayed with
+    /// `POST /admin/replay/{capture_id}`. The inputs are not captured if outputs are redacted
+    #[clap(long, env)]
+    replay_capture_dir: Option<String>,
+    /// Number of capture files kept, the oldest one being removed
+    #[clap(default_value = "8", long, env)]
+    replay_max_files: usize,
+    /// Size from which a new capture file is started
+    #[clap(default_value = "16777216", long, env)]
+    replay_max_file_bytes: u64,
+    /// Delay after which the captures expire
+    #[clap(default_value = "86400", long, env)]
+    replay_ttl_secs: u64,
+    /// JSON file overriding `max_input_length` and capping `max_new_tokens` per API key:
+    /// `{"default": {...}, "api_keys": {"<key>": {"max_input_length": 6000}}}`. Reloaded on SIGHUP
+    #[clap(long, env)]
+    limit_profiles_path: Option<String>,
+    /// JSONL file where the token streams, final texts and parameters of the generations of the
+    /// API keys with `"audit": true` in the limit profiles are appended. Needs `admin_api_key`
+    #[clap(long, env)]
+    audit_log_path: Option<String>,
+    /// Size from which the audit log is rotated
+    #[clap(default_value = "104857600", long, env)]
+    audit_log_max_file_bytes: u64,
+    /// Number of rotated audit logs kept, the oldest one being removed
+    #[clap(default_value = "8", long, env)]
+    audit_log_max_files: usize,
+    /// JSON file of a prompt with a known greedy output, generated after startup to confirm that
+    /// the expected model is loaded: `{"inputs": "...", "expected_prefix": "..."}` or
+    /// `"expected_token_ids": [...]`. The result is served at `GET /health/selftest`
+    #[clap(long, env)]
+    golden_prompt_path: Option<String>,
+    /// Fail the readiness probe when the golden prompt does not generate the expected output
+    #[clap(long, env)]
+    golden_prompt_fail_readiness: bool,
+    /// Handling of the requests sent while the shards load the model: `reject` them with a
+    /// `model_loading` error or `queue` them until the model is loaded
+    #[clap(default_value = "reject", long, env)]
+    model_loading_policy: LoadingPolicy,
+    /// Log a line per decode step with its batch, its number of tokens, the time spent waiting
+    /// for the backend and sending the generations, and the gap since the previous step
+    #[clap(long, env)]
+    debug_batching: bool,
+    /// Maximum number of jobs submitted to `POST /jobs` that are kept, 0 disables the job API
+    #[clap(default_value = "1000", long, env)]
+    max_jobs: usize,
+    /// Maximum total size of the tokens and responses of the kept jobs
+    #[clap(default_value = "67108864", long, env)]
+    max_job_bytes: usize,
+    /// Finished jobs are forgotten after this long
+    #[clap(default_value = "600", long, env)]
+    job_ttl_secs: u64,
+    /// Directory where the completed jobs larger than `job_spill_threshold_bytes` are written
+    /// instead of being kept in memory. It is emptied at startup
+    #[clap(long, env)]
+    job_spill_dir: Option<String>,
+    #[clap(default_value = "1048576", long, env)]
+    job_spill_threshold_bytes: usize,
+    /// Maximum total size of the spilled jobs, the oldest ones being evicted
+    #[clap(default_value = "1073741824", long, env)]
+    max_job_spill_bytes: u64,
+    /// Write-ahead log of the jobs whose request is not batched yet. The requests of the other
+    /// routes, e.g. the live SSE streams, are not logged: they end with the connection of their
+    /// client. It is emptied at startup, unless `restore_queue` is set
+    #[clap(long, env)]
+    job_log_path: Option<String>,
+    /// Start again the jobs of `job_log_path` that were not batched before the router stopped.
+    /// They keep their id, their deadline running from their submission, and their result is also
+    /// sent to their callback URL. Set `api_key_id_secret` so that they can still be read with
+    /// the API key that submitted them
+    #[clap(long, env)]
+    restore_queue: bool,
+    /// Secret of the HMAC-SHA256 signature of the job callbacks, sent as
+    /// `x-signature-256: sha256=<hex>`
+    #[clap(long, env)]
+    callback_secret: Option<String>,
+    /// Hosts the `callback_url` of the jobs can point to. Callbacks are disabled if it is not set.
+    /// `*` allows any host that only resolves to public addresses
+    #[clap(long, env)]
+    callback_allowed_host: Option<Vec<String>>,
+    /// Attempts to deliver a job result before marking it as not delivered
+    #[clap(default_value = "5", long, env)]
+    callback_max_attempts: u32,
+    /// Delay before the first retry of a callback, doubled after each attempt
+    #[clap(default_value = "1000", long, env)]
+    callback_initial_backoff_ms: u64,
+    #[clap(default_value = "10000", long, env)]
+    callback_timeout_ms: u64,
+    #[clap(default_value = "16", long, env)]
+    max_concurrent_callbacks: usize,
+    /// Identical deterministic requests running at the same time share a single generation
+    #[clap(long, env)]
+    coalesce_requests: bool,
+    /// Apply the NFC normalization to the inputs and strip their invisible characters, unless the
+    /// requests disable it with the `normalize_input` parameter
+    #[clap(long, env)]
+    normalize_input: bool,
+    /// Code points stripped from the normalized inputs, e.g. `U+200B`. Defaults to the zero width
+    /// space, non-joiner and joiner, the word joiner and the byte order mark
+    #[clap(long, env)]
+    normalize_input_strip_char: Option<Vec<String>>,
+    /// JSON file of the models served next to the model of the router, selected by the `model`
+    /// of the requests: `{"<name>": {"tokenizer_name", "master_shard_uds_path",
+    /// "max_input_length", "max_total_tokens", "max_batch_size", "max_concurrent_requests",
+    /// "max_batch_total_tokens"}}`
+    #[clap(long, env)]
+    models_config: Option<String>,
+    /// Model of `models_config` drafting the tokens of the target model with speculative decoding
+    /// It is not served on its own and must share the tokenizer of the target model
+    #[clap(long, env)]
+    speculative_draft_model: Option<String>,
+    /// Model whose requests are generated with speculative decoding, one request at a time.
+    /// Defaults to the model of the router. The router does not start if the shards of the draft
+    /// or target model do not implement speculative decoding
+    #[clap(long, env)]
+    speculative_target_model: Option<String>,
+    /// Draft tokens checked by each call to the target model
+    #[clap(default_value = "4", long, env)]
+    speculative_tokens: u32,
+    /// Streams a client IP can keep open on the streaming routes, counted before they wait for a
+    /// permit. Unlimited if it is not set
+    #[clap(long, env)]
+    max_stream_connections_per_ip: Option<usize>,
+    /// Streams an API key can keep open on the streaming routes. Unlimited if it is not set
+    #[clap(long, env)]
+    max_stream_connections_per_api_key: Option<usize>,
+    /// Inter-token latency the running batches should not exceed. The requests are added to the
+    /// running batches up to a budget adjusted from the measured latency. The batches are only
+    /// limited by `max_batch_size` and `max_batch_total_tokens` if it is not set
+    #[clap(long, env)]
+    inter_token_latency_target_ms: Option<u64>,
+    /// Quantile of the latency of the recent decode steps compared to the target
+    #[clap(default_value = "0.95", long, env)]
+    inter_token_latency_quantile: f64,
+    /// Change of the budget proportional to the relative latency error
+    #[clap(default_value = "0.1", long, env)]
+    latency_controller_proportional_gain: f64,
+    /// Change of the budget after each decode step proportional to the relative latency error
+    #[clap(default_value = "0.02", long, env)]
+    latency_controller_integral_gain: f64,
+    /// Calls per second to `POST /validate`, which returns how the router would generate a request
+    /// without taking a concurrency permit
+    #[clap(default_value = "10", long, env)]
+    max_dry_runs_per_second: u32,
+    /// Permits usable only by the health and self-test probes, so that `/health` succeeds while
+    /// the other requests use all the `max_concurrent_requests` permits. The admin routes do not
+    /// take any permit
+    #[clap(default_value = "1", long, env)]
+    reserved_probe_permits: usize,
+    /// Tokens a backend can send past the `max_new_tokens` of a request before the router ends
+    /// its generation with the `router_limit` finish reason and cancels it on the backend. Leaves
+    /// room for the backends sending several tokens per decode step
+    #[clap(default_value = "16", long, env)]
+    max_excess_tokens: u32,
+    /// Maximum number of sessions kept for the requests with a `session_id`, the least recently
+    /// used being evicted first. The shards of every backend must implement prefix caching, which
+    /// the Python server does not yet: the router stops otherwise. 0 disables the sessions, the
+    /// requests with a `session_id` being rejected
+    #[clap(default_value = "0", long, env)]
+    max_sessions: usize,
+    /// Maximum total size of the prompts and generated texts kept by the sessions
+    #[clap(default_value = "67108864", long, env)]
+    max_session_bytes: usize,
+    /// Run the batching tasks on a dedicated runtime with this many worker threads, so that the
+    /// decode calls are not delayed by the request handlers under heavy load. 0 keeps them on the
+    /// runtime of the router
+    #[clap(default_value = "0", long, env)]
+    batching_threads: usize,
+    /// Batch the requests strictly in arrival order into batches of `max_batch_size`, for
+    /// evaluation runs expecting the same outputs run after run. A smaller batch is only sent once
+    /// no request was queued for a second. No request is added to a running batch and the latency
+    /// target and prefill chunks are disabled: the throughput is much lower. The outputs can still
+    /// differ with the kernels of the backend, see the README
+    #[clap(long, env)]
+    deterministic_batching: bool,
+    /// Handling of a backend whose tokenizer has another vocabulary size or special token ids
+    /// than the tokenizer of its model in the router, the tokenizer of the router for the stable
+    /// and canary backends: `fail` to stop the router, or `not-ready` to log an error and fail the
+    /// readiness probe
+    #[clap(default_value = "fail", long, env)]
+    vocab_mismatch_policy: VocabMismatchPolicy,
+    /// Inject faults in the calls to the backends, for resilience testing
+    #[clap(long, env, hide = true)]
+    fault_injection: bool,
+    #[clap(default_value = "0.0", long, env, hide = true)]
+    fault_prefill_failure: f64,
+    #[clap(default_value = "0.0", long, env, hide = true)]
+    fault_decode_failure: f64,
+    #[clap(default_value = "0.0", long, env, hide = true)]
+    fault_latency_probability: f64,
+    #[clap(default_value = "1000", long, env, hide = true)]
+    fault_latency_ms: u64,
+    #[clap(default_value = "0.0", long, env, hide = true)]
+    fault_connection_drop: f64,
 }
 
 fn main() -> Result<(), std::io::Error> {
@@ -63,19 +412,174 @@ fn main() -> Result<(), std::io::Error> {
         max_total_tokens,
         max_batch_size,
         max_waiting_tokens,
+        prefill_chunk_tokens,
+        max_batch_total_tokens,
+        min_downgraded_new_tokens,
+        cache_utilization_threshold,
+        batching_policy,
+        all_latency_sensitive,
+        force_watermark,
         port,
         master_shard_uds_path,
+        canary_master_shard_uds_path,
+        canary_ratio,
         tokenizer_name,
         validation_workers,
         json_output,
         otlp_endpoint,
         cors_allow_origin,
+        response_cache_entries,
+        response_cache_bytes,
+        queue_heartbeat_interval_secs,
+        lenient_json,
+        usage_sink,
+        cost_per_prefill_token,
+        cost_per_decode_token,
+        cost_per_inference_second,
+        cost_currency,
+        pre_generation_hook_url,
+        pre_generation_hook_timeout_ms,
+        pre_generation_hook_fail_open,
+        post_generation_redact_pattern,
+        post_generation_reject_pattern,
+        post_generation_window,
+        prompt_templates_dir,
+        parameter_presets_path,
+        health_check_cache_ms,
+        backend_connect_timeout,
+        backend_connect_retries,
+        slow_shard_factor,
+        access_log_sample_rate,
+        access_log_slow_threshold_ms,
+        trace_requests,
+        trace_sample_ratio,
+        mock,
+        mock_token_delay_ms,
+        max_stream_full_text_bytes,
+        max_stream_event_bytes,
+        max_queue_wait_ms,
+        force_queue_api_key,
+        max_conversation_tokens,
+        conversation_ttl_secs,
+        admin_api_key,
+        api_key_id_secret,
+        admin_api_doc,
+        path_prefix,
+        disable_root_route,
+        root_health_route,
+        circuit_breaker_threshold,
+        circuit_breaker_probe_interval_ms,
+        auto_requeue,
+        replay_capture_dir,
+        replay_max_files,
+        replay_max_file_bytes,
+        replay_ttl_secs,
+        limit_profiles_path,
+        audit_log_path,
+        audit_log_max_file_bytes,
+        audit_log_max_files,
+        golden_prompt_path,
+        golden_prompt_fail_readiness,
+        model_loading_policy,
+        debug_batching,
+        max_jobs,
+        max_job_bytes,
+        job_ttl_secs,
+        job_spill_dir,
+        job_spill_threshold_bytes,
+        max_job_spill_bytes,
+        job_log_path,
+        restore_queue,
+        callback_secret,
+        callback_allowed_host,
+        callback_max_attempts,
+        callback_initial_backoff_ms,
+        callback_timeout_ms,
+        max_concurrent_callbacks,
+        coalesce_requests,
+        normalize_input,
+        normalize_input_strip_char,
+        models_config,
+        speculative_draft_model,
+        speculative_target_model,
+        speculative_tokens,
+        max_stream_connections_per_ip,
+        max_stream_connections_per_api_key,
+        inter_token_latency_target_ms,
+        inter_token_latency_quantile,
+        latency_controller_proportional_gain,
+        latency_controller_integral_gain,
+        max_dry_runs_per_second,
+        reserved_probe_permits,
+        max_excess_tokens,
+        max_sessions,
+        max_session_bytes,
+        batching_threads,
+        deterministic_batching,
+        vocab_mismatch_policy,
+        fault_injection,
+        fault_prefill_failure,
+        fault_decode_failure,
+        fault_latency_probability,
+        fault_latency_ms,
+        fault_connection_drop,
     } = args;
 
     if validation_workers == 0 {
         panic!("validation_workers must be > 0");
     }
 
+    if backend_connect_retries == 0 {
+        panic!("backend_connect_retries must be > 0");
+    }
+
+    if slow_shard_factor <= 1.0 {
+        panic!("slow_shard_factor must be > 1");
+    }
+
+    if !(0.0..=1.0).contains(&canary_ratio) {
+        panic!("canary_ratio must be between 0 and 1");
+    }
+
+    if !(0.0..=1.0).contains(&access_log_sample_rate) {
+        panic!("access_log_sample_rate must be between 0 and 1");
+    }
+    if !(0.0..=1.0).contains(&trace_sample_ratio) {
+        panic!("trace_sample_ratio must be between 0 and 1");
+    }
+
+    let faults = fault_injection.then(|| FaultConfig {
+        prefill_failure: fault_prefill_failure,
+        decode_failure: fault_decode_failure,
+        latency_probability: fault_latency_probability,
+        latency: Duration::from_millis(fault_latency_ms),
+        connection_drop: fault_connection_drop,
+    });
+    if let Some(faults) = &faults {
+        for (name, probability) in [
+            ("fault_prefill_failure", faults.prefill_failure),
+            ("fault_decode_failure", faults.decode_failure),
+            ("fault_latency_probability", faults.latency_probability),
+            ("fault_connection_drop", faults.connection_drop),
+        ] {
+            if !(0.0..=1.0).contains(&probability) {
+                panic!("{name} must be between 0 and 1");
+            }
+        }
+    }
+
+    let costs = [
+        cost_per_prefill_token,
+        cost_per_decode_token,
+        cost_per_inference_second,
+    ];
+    let cost_model = costs.iter().any(Option::is_some).then(|| CostModel {
+        prefill_token: cost_per_prefill_token.unwrap_or(0.0),
+        decode_token: cost_per_decode_token.unwrap_or(0.0),
+        inference_second: cost_per_inference_second.unwrap_or(0.0),
+        currency: cost_currency,
+    });
+
     // CORS allowed origins
     // map to go inside the option and then map to parse from String to HeaderValue
     // Finally, convert to AllowOrigin
@@ -89,17 +593,20 @@ fn main() -> Result<(), std::io::Error> {
 
     // Tokenizer instance
     // This will only be used to validate payloads
-    let local_path = Path::new(&tokenizer_name);
-    let tokenizer =
-        if local_path.exists() && local_path.is_dir() && local_path.join("tokenizer.json").exists()
-        {
-            // Load local tokenizer
-            Tokenizer::from_file(local_path.join("tokenizer.json")).unwrap()
-        } else {
-            // Download and instantiate tokenizer
-            // We need to download it outside of the Tokio runtime
-            Tokenizer::from_pretrained(tokenizer_name.clone(), None).unwrap()
-        };
+    let tokenizer = load_tokenizer(&tokenizer_name);
+
+    // Other served models and their tokenizers
+    let models: Vec<(String, ModelConfig, Tokenizer)> = match models_config {
+        None => vec![],
+        Some(path) => load_models(Path::new(&path))
+            .expect("Could not load the models")
+            .into_iter()
+            .map(|(name, config)| {
+                let tokenizer = load_tokenizer(&config.tokenizer_name);
+                (name, config, tokenizer)
+            })
+            .collect(),
+    };
 
     // Launch Tokio runtime
     tokio::runtime::Builder::new_multi_thread()
@@ -107,45 +614,192 @@ fn main() -> Result<(), std::io::Error> {
         .build()
         .unwrap()
         .block_on(async {
-            init_logging(otlp_endpoint, json_output);
+            init_logging(otlp_endpoint, json_output, trace_sample_ratio);
+
+            // Binds on localhost
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+
+            // Answer the health probes while starting up
+            let (connected_sender, connected_receiver) = oneshot::channel();
+            let startup_probes = tokio::spawn(server::run_startup_probes(
+                addr,
+                path_prefix.clone(),
+                connected_receiver,
+            ));
 
             // Get pipeline tag
-            let model_info = reqwest::get(format!(
-                "https://huggingface.co/api/models/{tokenizer_name}"
-            ))
-            .await
-            .expect("Could not connect to hf.co")
-            .text()
-            .await
-            .expect("error when retrieving model info from hf.co");
-            let model_info: serde_json::Value =
-                serde_json::from_str(&model_info).expect("unable to parse model info");
-
-            // if pipeline-tag == text-generation we default to return_full_text = true
-            let compat_return_full_text = match model_info.get("pipeline_tag") {
-                None => {
-                    tracing::warn!("no pipeline tag found for model {tokenizer_name}");
-                    false
+            // The mock backend does not need a connection to hf.co
+            let compat_return_full_text = match mock {
+                true => false,
+                false => {
+                    let model_info = reqwest::get(format!(
+                        "https://huggingface.co/api/models/{tokenizer_name}"
+                    ))
+                    .await
+                    .expect("Could not connect to hf.co")
+                    .text()
+                    .await
+                    .expect("error when retrieving model info from hf.co");
+                    let model_info: serde_json::Value =
+                        serde_json::from_str(&model_info).expect("unable to parse model info");
+
+                    // if pipeline-tag == text-generation we default to return_full_text = true
+                    match model_info.get("pipeline_tag") {
+                        None => {
+                            tracing::warn!("no pipeline tag found for model {tokenizer_name}");
+                            false
+                        }
+                        Some(pipeline_tag) => pipeline_tag.as_str() == Some("text-generation"),
+                    }
                 }
-                Some(pipeline_tag) => pipeline_tag.as_str() == Some("text-generation"),
             };
 
+            connected_sender.send(()).unwrap_or(());
+            startup_probes.await.unwrap();
+
             // Instantiate sharded client from the master unix socket
-            let mut sharded_client = ShardedClient::connect_uds(master_shard_uds_path)
-                .await
-                .expect("Could not connect to server");
-            // Clear the cache; useful if the webserver rebooted
-            sharded_client
-                .clear_cache(None)
-                .await
-                .expect("Unable to clear cache");
-            tracing::info!("Connected");
+            // or a mock backend generating tokens in process
+            // The router serves while the shards load the model, the requests reach the backends
+            // once they are connected
+            let connect_timeout = Duration::from_secs(backend_connect_timeout);
+            let mock_config = MockConfig {
+                token_delay: Duration::from_millis(mock_token_delay_ms),
+                ..MockConfig::default()
+            };
+            let (sharded_client, stable_connection) = match mock {
+                true => {
+                    tracing::warn!("Serving a mock backend");
+                    (ShardedClient::mock(mock_config.clone()).into(), None)
+                }
+                false => {
+                    let (client_sender, client_receiver) = oneshot::channel();
+                    (
+                        BackendConnection::Connecting(client_receiver),
+                        Some((master_shard_uds_path, client_sender)),
+                    )
+                }
+            };
 
-            // Binds on localhost
-            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+            // Instantiate sharded client of the canary backend
+            let (canary_sharded_client, canary_connection) = match canary_master_shard_uds_path {
+                None => (None, None),
+                Some(_) if mock => (Some(ShardedClient::mock(mock_config.clone()).into()), None),
+                Some(canary_master_shard_uds_path) => {
+                    let (client_sender, client_receiver) = oneshot::channel();
+                    (
+                        Some(BackendConnection::Connecting(client_receiver)),
+                        Some((canary_master_shard_uds_path, client_sender)),
+                    )
+                }
+            };
+
+            // Instantiate sharded clients of the other models
+            let mut model_connections = vec![];
+            let models: Vec<ServedModel> = models
+                .into_iter()
+                .map(|(name, config, tokenizer)| {
+                    let client = match mock {
+                        true => ShardedClient::mock(mock_config.clone()).into(),
+                        false => {
+                            let (client_sender, client_receiver) = oneshot::channel();
+                            model_connections.push((
+                                name.clone(),
+                                config.master_shard_uds_path.clone(),
+                                client_sender,
+                                tokenizer.clone(),
+                            ));
+                            BackendConnection::Connecting(client_receiver)
+                        }
+                    };
+                    ServedModel {
+                        name,
+                        config,
+                        tokenizer,
+                        client,
+                    }
+                })
+                .collect();
+
+            // Compared with the vocabulary of each backend once it is connected
+            let vocab_check = VocabCheck::new(tokenizer.clone(), vocab_mismatch_policy);
+            let backend_vocab_check = vocab_check.clone();
+            let canary_tokenizer = tokenizer.clone();
+
+            // The draft and target backends must implement speculative decoding
+            let speculative_models: Vec<String> = match &speculative_draft_model {
+                None => vec![],
+                Some(draft) => vec![
+                    draft.clone(),
+                    speculative_target_model
+                        .clone()
+                        .unwrap_or_else(|| tokenizer_name.clone()),
+                ],
+            };
+            let stable_model = tokenizer_name.clone();
+            // Every backend must reuse the prefix of the sessions
+            let sessions = max_sessions > 0 && max_session_bytes > 0;
+
+            // Not spawned so that a failure to connect stops the router
+            let connect = async move {
+                if let Some((uds_path, client_sender)) = stable_connection {
+                    let mut client = connect_backend(
+                        uds_path,
+                        connect_timeout,
+                        backend_connect_retries,
+                        slow_shard_factor,
+                    )
+                    .await;
+                    tracing::info!("Connected");
+                    // The token counts, stop sequences and truncation would all be wrong
+                    if let Err(err) = backend_vocab_check.check(&mut client).await {
+                        panic!("The router and the backend do not use the same tokenizer: {err}");
+                    }
+                    let speculation = speculative_models.contains(&stable_model);
+                    check_capabilities(&mut client, &stable_model, speculation, sessions).await;
+                    client_sender.send(client).unwrap_or(());
+                }
+                if let Some((uds_path, client_sender)) = canary_connection {
+                    let mut client = connect_backend(
+                        uds_path,
+                        connect_timeout,
+                        backend_connect_retries,
+                        slow_shard_factor,
+                    )
+                    .await;
+                    tracing::info!("Connected to canary");
+                    if let Err(err) = backend_vocab_check
+                        .check_backend("canary", &canary_tokenizer, &mut client)
+                        .await
+                    {
+                        panic!("The router and the canary backend do not use the same tokenizer: {err}");
+                    }
+                    check_capabilities(&mut client, "canary", false, sessions).await;
+                    client_sender.send(client).unwrap_or(());
+                }
+                for (name, uds_path, client_sender, tokenizer) in model_connections {
+                    let mut client = connect_backend(
+                        uds_path,
+                        connect_timeout,
+                        backend_connect_retries,
+                        slow_shard_factor,
+                    )
+                    .await;
+                    tracing::info!("Connected to model {name}");
+                    if let Err(err) = backend_vocab_check
+                        .check_backend(&name, &tokenizer, &mut client)
+                        .await
+                    {
+                        panic!("The model {name} and its backend do not use the same tokenizer: {err}");
+                    }
+                    let speculation = speculative_models.contains(&name);
+                    check_capabilities(&mut client, &name, speculation, sessions).await;
+                    client_sender.send(client).unwrap_or(());
+                }
+                std::future::pending::<()>().await
+            };
 
             // Run server
-            server::run(
+            let options = ServerOptions {
                 compat_return_full_text,
                 max_concurrent_requests,
                 max_best_of,
@@ -154,22 +808,210 @@ fn main() -> Result<(), std::io::Error> {
                 max_total_tokens,
                 max_batch_size,
                 max_waiting_tokens,
-                sharded_client,
+                prefill_chunk_tokens,
+                max_batch_total_tokens,
+                min_downgraded_new_tokens,
+                cache_utilization_threshold,
+                batching_policy,
+                all_latency_sensitive,
+                force_watermark,
+                client: sharded_client,
+                canary_client: canary_sharded_client,
+                canary_ratio,
                 tokenizer,
                 validation_workers,
-                addr,
-                cors_allow_origin,
-            )
-            .await;
+                allow_origin: cors_allow_origin,
+                response_cache_entries,
+                response_cache_bytes,
+                queue_heartbeat_interval: queue_heartbeat_interval_secs.map(Duration::from_secs),
+                lenient_json,
+                model_id: tokenizer_name,
+                usage_sink,
+                cost_model,
+                pre_generation_hook_url,
+                pre_generation_hook_timeout: Duration::from_millis(pre_generation_hook_timeout_ms),
+                pre_generation_hook_fail_open,
+                post_generation_redact_patterns: post_generation_redact_pattern.unwrap_or_default(),
+                post_generation_reject_patterns: post_generation_reject_pattern.unwrap_or_default(),
+                post_generation_window,
+                prompt_templates_dir: prompt_templates_dir.map(PathBuf::from),
+                parameter_presets_path: parameter_presets_path.map(PathBuf::from),
+                health_check_cache: Duration::from_millis(health_check_cache_ms),
+                access_log_sample_rate,
+                access_log_slow_threshold: Duration::from_millis(access_log_slow_threshold_ms),
+                trace_requests,
+                trace_sample_ratio,
+                faults,
+                max_stream_full_text_bytes,
+                max_stream_event_bytes,
+                max_queue_wait: max_queue_wait_ms.map(Duration::from_millis),
+                force_queue_api_keys: force_queue_api_key
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect(),
+                max_conversation_tokens,
+                conversation_ttl: Duration::from_secs(conversation_ttl_secs),
+                admin_api_key,
+                api_key_id_secret,
+                admin_api_doc,
+                path_prefix,
+                disable_root_route,
+                root_health_route,
+                circuit_breaker: CircuitBreakerConfig {
+                    threshold: circuit_breaker_threshold,
+                    probe_interval: Duration::from_millis(circuit_breaker_probe_interval_ms),
+                },
+                auto_requeue,
+                replay: replay_capture_dir.map(|dir| ReplayConfig {
+                    dir: PathBuf::from(dir),
+                    max_files: replay_max_files,
+                    max_file_bytes: replay_max_file_bytes,
+                    ttl: Duration::from_secs(replay_ttl_secs),
+                }),
+                limit_profiles_path: limit_profiles_path.map(PathBuf::from),
+                audit: audit_log_path.map(|path| AuditConfig {
+                    path: PathBuf::from(path),
+                    max_file_bytes: audit_log_max_file_bytes,
+                    max_files: audit_log_max_files,
+                }),
+                golden_prompt_path: golden_prompt_path.map(PathBuf::from),
+                golden_prompt_fail_readiness,
+                model_loading_policy,
+                debug_batching,
+                max_jobs,
+                max_job_bytes,
+                job_ttl: Duration::from_secs(job_ttl_secs),
+                callbacks: CallbackConfig {
+                    secret: callback_secret,
+                    allowed_hosts: callback_allowed_host.unwrap_or_default(),
+                    max_attempts: callback_max_attempts,
+                    initial_backoff: Duration::from_millis(callback_initial_backoff_ms),
+                    timeout: Duration::from_millis(callback_timeout_ms),
+                    max_concurrent: max_concurrent_callbacks,
+                },
+                coalesce_requests,
+                normalize_input,
+                normalize_input_strip_chars: normalize_input_strip_char,
+                models,
+                speculative_draft_model,
+                speculative_target_model,
+                speculative_tokens,
+                max_stream_connections_per_ip,
+                max_stream_connections_per_api_key,
+                job_spill: job_spill_dir.map(|dir| SpillConfig {
+                    dir: PathBuf::from(dir),
+                    threshold_bytes: job_spill_threshold_bytes,
+                    max_bytes: max_job_spill_bytes,
+                }),
+                job_log: job_log_path.map(PathBuf::from),
+                restore_queue,
+                latency_target: inter_token_latency_target_ms.map(|target_ms| LatencyTarget {
+                    target: Duration::from_millis(target_ms),
+                    quantile: inter_token_latency_quantile,
+                    proportional_gain: latency_controller_proportional_gain,
+                    integral_gain: latency_controller_integral_gain,
+                }),
+                max_dry_runs_per_second,
+                reserved_probe_permits,
+                max_excess_tokens,
+                max_sessions,
+                max_session_bytes,
+                batching_threads,
+                deterministic_batching,
+                vocab_check,
+            };
+            let server = server::run(options, addr);
+            tokio::select! {
+                _ = server => {}
+                _ = connect => {}
+            }
             Ok(())
         })
 }
 
+/// Load a tokenizer from a local directory or from the hub
+fn load_tokenizer(name: &str) -> Tokenizer {
+    let local_path = Path::new(name);
+    if local_path.exists() && local_path.is_dir() && local_path.join("tokenizer.json").exists() {
+        // Load local tokenizer
+        Tokenizer::from_file(local_path.join("tokenizer.json")).unwrap()
+    } else {
+        // Download and instantiate tokenizer
+        // We need to download it outside of the Tokio runtime
+        Tokenizer::from_pretrained(name, None).unwrap()
+    }
+}
+
+/// Connect to the shards of a backend
+/// Retry with an exponential backoff as the shards might not be listening yet
+async fn connect_backend(
+    uds_path: String,
+    timeout: Duration,
+    retries: usize,
+    slow_shard_factor: f64,
+) -> ShardedClient {
+    let mut backoff = CONNECT_INITIAL_BACKOFF;
+    for attempt in 1..=retries {
+        let connect = async {
+            let mut sharded_client = ShardedClient::connect_uds(uds_path.clone()).await?;
+            // Clear the cache; useful if the webserver rebooted
+            sharded_client.clear_cache(None).await?;
+            Ok::<_, ClientError>(sharded_client)
+        };
+        match tokio::time::timeout(timeout, connect).await {
+            Ok(Ok(sharded_client)) => {
+                sharded_client.set_slow_shard_factor(slow_shard_factor);
+                return sharded_client;
+            }
+            Ok(Err(err)) => tracing::warn!(
+                "Waiting for shard at uri {uds_path}, attempt {attempt}/{retries}: {err}"
+            ),
+            Err(_) => tracing::warn!(
+                "Waiting for shard at uri {uds_path}, attempt {attempt}/{retries}: timed out"
+            ),
+        }
+        if attempt < retries {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(CONNECT_MAX_BACKOFF);
+        }
+    }
+    panic!("Could not connect to the shard at uri {uds_path} after {retries} attempts");
+}
+
+/// Stop the router if the shards of a model do not implement speculative decoding while
+/// `speculation` is set, or prefix caching while `sessions` is set
+/// They would fail the first `Draft` or `Verify` call of every request, or prefill the whole
+/// inputs of every session
+async fn check_capabilities(
+    client: &mut ShardedClient,
+    name: &str,
+    speculation: bool,
+    sessions: bool,
+) {
+    if !speculation && !sessions {
+        return;
+    }
+    let info = match client.info().await {
+        Ok(info) => info,
+        Err(err) => panic!("Could not check the capabilities of the shards of model {name}: {err}"),
+    };
+    if speculation && !info.speculation {
+        panic!(
+            "The shards of model {name} do not implement speculative decoding, remove `--speculative-draft-model`"
+        );
+    }
+    if sessions && !info.prefix_cache {
+        panic!(
+            "The shards of model {name} do not implement prefix caching, set `--max-sessions 0`"
+        );
+    }
+}
+
 /// Init logging using env variables LOG_LEVEL and LOG_FORMAT:
 ///     - otlp_endpoint is an optional URL to an Open Telemetry collector
 ///     - LOG_LEVEL may be TRACE, DEBUG, INFO, WARN or ERROR (default to INFO)
 ///     - LOG_FORMAT may be TEXT or JSON (default to TEXT)
-fn init_logging(otlp_endpoint: Option<String>, json_output: bool) {
+fn init_logging(otlp_endpoint: Option<String>, json_output: bool, trace_sample_ratio: f64) {
     let mut layers = Vec::new();
 
     // STDOUT/STDERR layer
@@ -200,7 +1042,10 @@ fn init_logging(otlp_endpoint: Option<String>, json_output: bool) {
                         "service.name",
                         "text-generation-inference.router",
                     )]))
-                    .with_sampler(Sampler::AlwaysOn),
+                    // Follow the sampled flag of the `traceparent` of the requests
+                    .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                        trace_sample_ratio,
+                    )))),
             )
             .install_batch(opentelemetry::runtime::Tokio);
 
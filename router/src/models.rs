@@ -0,0 +1,130 @@
+/// Models served by the router next to its own model
+use crate::infer::BackendConnection;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use thiserror::Error;
+use tokenizers::Tokenizer;
+
+/// Model served by its own shards, selected by the `model` of the requests
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelConfig {
+    /// Hub id or local directory of the tokenizer
+    pub tokenizer_name: String,
+    /// Unix socket of the master shard
+    pub master_shard_uds_path: String,
+    pub max_input_length: usize,
+    pub max_total_tokens: usize,
+    pub max_batch_size: usize,
+    pub max_concurrent_requests: usize,
+    /// Unlimited if it is not set
+    #[serde(default)]
+    pub max_batch_total_tokens: Option<u32>,
+}
+
+/// Model served next to the model of the router, with its tokenizer and its shards
+pub struct ServedModel {
+    pub name: String,
+    pub config: ModelConfig,
+    pub tokenizer: Tokenizer,
+    pub client: BackendConnection,
+}
+
+#[derive(Debug, Error)]
+pub enum ModelsError {
+    #[error("could not read the models: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse the models: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("`max_input_length` of model `{0}` ({1}) must be < `max_total_tokens` ({2})")]
+    InputLength(String, usize, usize),
+    #[error("`{1}` of model `{0}` must be > 0")]
+    Zero(String, &'static str),
+    #[error("`max_batch_total_tokens` of model `{0}` ({1}) must be >= `max_total_tokens` ({2})")]
+    BatchTotalTokens(String, u32, usize),
+}
+
+/// Read the models of a JSON file mapping their names to their configuration
+/// `{"draft": {"tokenizer_name": "bigscience/bloom-560m", "master_shard_uds_path": "/tmp/draft-server", ...}}`
+pub fn load_models(path: &Path) -> Result<BTreeMap<String, ModelConfig>, ModelsError> {
+    parse_models(&std::fs::read_to_string(path)?)
+}
+
+fn parse_models(content: &str) -> Result<BTreeMap<String, ModelConfig>, ModelsError> {
+    let models: BTreeMap<String, ModelConfig> = serde_json::from_str(content)?;
+    for (name, model) in &models {
+        model.validate(name)?;
+    }
+    Ok(models)
+}
+
+impl ModelConfig {
+    fn validate(&self, name: &str) -> Result<(), ModelsError> {
+        for (field, value) in [
+            ("max_total_tokens", self.max_total_tokens),
+            ("max_batch_size", self.max_batch_size),
+            ("max_concurrent_requests", self.max_concurrent_requests),
+        ] {
+            if value == 0 {
+                return Err(ModelsError::Zero(name.to_string(), field));
+            }
+        }
+        if self.max_input_length >= self.max_total_tokens {
+            return Err(ModelsError::InputLength(
+                name.to_string(),
+                self.max_input_length,
+                self.max_total_tokens,
+            ));
+        }
+        if let Some(max_batch_total_tokens) = self.max_batch_total_tokens {
+            if (max_batch_total_tokens as usize) < self.max_total_tokens {
+                return Err(ModelsError::BatchTotalTokens(
+                    name.to_string(),
+                    max_batch_total_tokens,
+                    self.max_total_tokens,
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_models() {
+        let models = parse_models(
+            r#"{"draft": {
+                "tokenizer_name": "bigscience/bloom-560m",
+                "master_shard_uds_path": "/tmp/draft-server",
+                "max_input_length": 500,
+                "max_total_tokens": 1000,
+                "max_batch_size": 16,
+                "max_concurrent_requests": 64
+            }}"#,
+        )
+        .unwrap();
+        assert_eq!(models["draft"].max_input_length, 500);
+        assert_eq!(models["draft"].max_batch_total_tokens, None);
+
+        let err = parse_models(
+            r#"{"draft": {
+                "tokenizer_name": "bigscience/bloom-560m",
+                "master_shard_uds_path": "/tmp/draft-server",
+                "max_input_length": 1000,
+                "max_total_tokens": 1000,
+                "max_batch_size": 16,
+                "max_concurrent_requests": 64
+            }}"#,
+        )
+        .unwrap_err();
+        assert!(matches!(err, ModelsError::InputLength(name, 1000, 1000) if name == "draft"));
+        assert!(matches!(
+            parse_models(r#"{"draft": {"tokenizer_name": "bigscience/bloom-560m"}}"#),
+            Err(ModelsError::Parse(_))
+        ));
+    }
+}
@@ -0,0 +1,176 @@
+/// Unicode normalization of the inputs
+use crate::validation::ValidationError;
+use std::collections::HashSet;
+use std::sync::Arc;
+use unicode_normalization::char::{canonical_combining_class, compose};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// Invisible characters stripped by default: zero width space, non-joiner and joiner, word joiner
+/// and byte order mark
+pub(crate) const DEFAULT_STRIPPED_CHARS: [char; 5] =
+    ['\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}'];
+
+/// NFC normalization of the inputs and removal of a set of invisible characters
+///
+/// Disabled unless the server or the request enables it, so that the inputs reach the tokenizer
+/// byte for byte
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InputNormalizer {
+    /// Normalize the inputs of the requests that do not set `normalize_input`
+    enabled: bool,
+    /// Characters removed from the inputs
+    stripped: Arc<HashSet<char>>,
+}
+
+/// Normalized inputs of a request
+#[derive(Debug, PartialEq)]
+pub(crate) struct Normalized {
+    pub inputs: String,
+    /// Number of characters of the original inputs removed or replaced, 0 if they were already
+    /// normalized
+    pub changed_chars: usize,
+}
+
+impl InputNormalizer {
+    pub(crate) fn new(enabled: bool, stripped: impl IntoIterator<Item = char>) -> Self {
+        Self {
+            enabled,
+            stripped: Arc::new(stripped.into_iter().collect()),
+        }
+    }
+
+    /// The inputs of a request with this `normalize_input` parameter are normalized
+    pub(crate) fn enabled(&self, normalize_input: Option<bool>) -> bool {
+        normalize_input.unwrap_or(self.enabled)
+    }
+
+    /// Strip the characters of the set and apply the NFC normalization
+    /// Fails on the noncharacters, which are only found in badly decoded text
+    pub(crate) fn normalize(&self, inputs: &str) -> Result<Normalized, ValidationError> {
+        let mut stripped = String::with_capacity(inputs.len());
+        let mut changed_chars = 0;
+        for (position, c) in inputs.chars().enumerate() {
+            if is_invalid(c) {
+                return Err(ValidationError::InvalidCharacter(c as u32, position));
+            }
+            match self.stripped.contains(&c) {
+                true => changed_chars += 1,
+                false => stripped.push(c),
+            }
+        }
+        if is_nfc(&stripped) {
+            return Ok(Normalized {
+                inputs: stripped,
+                changed_chars,
+            });
+        }
+
+        // Only the characters of the composition segments changed by NFC are counted
+        let mut segment = String::new();
+        let mut last = None;
+        for c in stripped.chars() {
+            let starts_segment = canonical_combining_class(c) == 0
+                && last.map_or(true, |last| compose(last, c).is_none());
+            if starts_segment && !segment.is_empty() {
+                changed_chars += changed_segment_chars(&segment);
+                segment.clear();
+            }
+            segment.push(c);
+            last = Some(c);
+        }
+        changed_chars += changed_segment_chars(&segment);
+
+        let inputs: String = stripped.nfc().collect();
+        Ok(Normalized {
+            // Segments composing across their boundary are rare enough to be undercounted
+            changed_chars: changed_chars.max(1),
+            inputs,
+        })
+    }
+}
+
+/// Noncharacters are left by broken decoders
+/// The replacement character is accepted: it is also found in text quoting a decoding error
+fn is_invalid(c: char) -> bool {
+    let code_point = c as u32;
+    (0xFDD0..=0xFDEF).contains(&code_point) || code_point & 0xFFFE == 0xFFFE
+}
+
+fn changed_segment_chars(segment: &str) -> usize {
+    match segment.nfc().eq(segment.chars()) {
+        true => 0,
+        false => segment.chars().count(),
+    }
+}
+
+/// Parse a code point given as `U+200B` or `200B`
+pub(crate) fn parse_code_point(value: &str) -> Result<char, String> {
+    let hex = value
+        .strip_prefix("U+")
+        .or_else(|| value.strip_prefix("u+"))
+        .unwrap_or(value);
+    u32::from_str_radix(hex, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| format!("`{value}` is not a valid code point"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalizer() -> InputNormalizer {
+        InputNormalizer::new(true, DEFAULT_STRIPPED_CHARS)
+    }
+
+    #[test]
+    fn test_normalize() {
+        let normalized = normalizer()
+            .normalize("\u{FEFF}Cafe\u{301} au\u{200B} lait")
+            .unwrap();
+        assert_eq!(normalized.inputs, "Café au lait");
+        // The BOM, the zero width space and the two characters of the decomposed é
+        assert_eq!(normalized.changed_chars, 4);
+
+        let normalized = normalizer().normalize("Café au lait").unwrap();
+        assert_eq!(normalized.inputs, "Café au lait");
+        assert_eq!(normalized.changed_chars, 0);
+
+        // Hangul jamos compose across the starters
+        let normalized = normalizer().normalize("\u{1100}\u{1161}").unwrap();
+        assert_eq!(normalized.inputs, "\u{AC00}");
+        assert_eq!(normalized.changed_chars, 2);
+    }
+
+    #[test]
+    fn test_invalid_character() {
+        let err = normalizer().normalize("broken \u{FDD0} text").unwrap_err();
+        assert!(matches!(err, ValidationError::InvalidCharacter(0xFDD0, 7)));
+        assert_eq!(
+            err.to_string(),
+            "`inputs` contains the invalid character U+FDD0 at position 7"
+        );
+        assert!(normalizer().normalize("\u{FFFE}").is_err());
+        assert!(normalizer().normalize("\u{10FFFF}").is_err());
+
+        // The replacement character can be legitimate text
+        let normalized = normalizer().normalize("decoded as \u{FFFD}").unwrap();
+        assert_eq!(normalized.inputs, "decoded as \u{FFFD}");
+        assert_eq!(normalized.changed_chars, 0);
+    }
+
+    #[test]
+    fn test_enabled() {
+        assert!(!InputNormalizer::default().enabled(None));
+        assert!(InputNormalizer::default().enabled(Some(true)));
+        assert!(!normalizer().enabled(Some(false)));
+    }
+
+    #[test]
+    fn test_parse_code_point() {
+        assert_eq!(parse_code_point("U+200B"), Ok('\u{200B}'));
+        assert_eq!(parse_code_point("feff"), Ok('\u{FEFF}'));
+        assert!(parse_code_point("U+D800").is_err());
+        assert!(parse_code_point("zero width").is_err());
+    }
+}
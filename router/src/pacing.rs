@@ -0,0 +1,130 @@
+/// Pacing of the streamed tokens at the `stream_rate_limit` of the request
+use crate::infer::{InferError, InferStreamResponse};
+use futures::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+type Message = Result<InferStreamResponse, InferError>;
+
+/// Release the tokens of `responses` at most `rate` times per second
+///
+/// The responses are read as soon as the backend sends them and wait in a buffer, so that the
+/// batch of the request is not slowed down by the client. The status events are not paced, and
+/// once the generation ends the buffered tokens and the last event are sent without waiting
+pub(crate) fn pace<S>(responses: S, rate: Option<f32>) -> impl Stream<Item = Message>
+where
+    S: Stream<Item = Message> + Send + 'static,
+{
+    async_stream::stream! {
+        let mut responses = Box::pin(responses);
+        let interval = match rate {
+            Some(rate) => Duration::from_secs_f32(1.0 / rate),
+            None => {
+                while let Some(response) = responses.next().await {
+                    yield response;
+                }
+                return;
+            }
+        };
+
+        let mut pending: VecDeque<Message> = VecDeque::new();
+        let mut finished = false;
+        let mut release = Instant::now();
+        loop {
+            while let Some(response) = pending.front() {
+                if !finished && matches!(response, Ok(InferStreamResponse::Token(_))) {
+                    break;
+                }
+                yield pending.pop_front().unwrap();
+            }
+            if finished {
+                return;
+            }
+
+            let paced = tokio::select! {
+                response = responses.next() => {
+                    match response {
+                        Some(response) => {
+                            finished = matches!(
+                                response,
+                                Ok(InferStreamResponse::End { .. }) | Err(_)
+                            );
+                            pending.push_back(response);
+                        }
+                        None => finished = true,
+                    }
+                    None
+                }
+                _ = tokio::time::sleep_until(release), if !pending.is_empty() => {
+                    pending.pop_front()
+                }
+            };
+            if let Some(response) = paced {
+                release = Instant::now() + interval;
+                yield response;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::GenerationErrorCode;
+    use crate::Token;
+
+    fn token(id: u32) -> Message {
+        Ok(InferStreamResponse::Token(Token {
+            id,
+            text: id.to_string(),
+            logprob: 0.0,
+            special: false,
+        }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pace() {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut paced = Box::pin(pace(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(receiver),
+            Some(8.0),
+        ));
+        let start = Instant::now();
+
+        sender.send(Ok(InferStreamResponse::Started)).unwrap();
+        for id in 0..5 {
+            sender.send(token(id)).unwrap();
+        }
+        assert!(matches!(
+            paced.next().await,
+            Some(Ok(InferStreamResponse::Started))
+        ));
+
+        // One token every 125ms, the first one at once
+        for id in 0..3 {
+            match paced.next().await {
+                Some(Ok(InferStreamResponse::Token(token))) => assert_eq!(token.id, id),
+                response => panic!("unexpected response {response:?}"),
+            }
+            assert_eq!(start.elapsed(), Duration::from_millis(125 * id as u64));
+        }
+
+        // The tokens left are flushed once the generation ends
+        sender
+            .send(Err(InferError::GenerationError(
+                GenerationErrorCode::Backend,
+                "failed".to_string(),
+            )))
+            .unwrap();
+        for id in 3..5 {
+            match paced.next().await {
+                Some(Ok(InferStreamResponse::Token(token))) => assert_eq!(token.id, id),
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+        assert!(matches!(paced.next().await, Some(Err(_))));
+        assert!(paced.next().await.is_none());
+        assert!(start.elapsed() < Duration::from_millis(375));
+    }
+}
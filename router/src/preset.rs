@@ -0,0 +1,210 @@
+/// Server-side parameter presets
+use crate::validation::ValidationError;
+use crate::{default_max_new_tokens, GenerateParameters};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Subset of the generation parameters
+/// The parameters given in a request take precedence. The parameters that are not nullable in the
+/// requests are only taken from the preset when the request leaves them to their default value
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Preset {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.5)]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 1.03)]
+    pub repetition_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 10)]
+    pub top_k: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.95)]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 0.95)]
+    pub typical_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = true)]
+    pub do_sample: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = 20)]
+    pub max_new_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = json ! (["photographer"]))]
+    pub stop: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub truncate: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = false)]
+    pub watermark: Option<bool>,
+}
+
+impl Preset {
+    /// Fill the parameters not given in a request
+    fn apply(&self, parameters: &mut GenerateParameters) {
+        parameters.temperature = parameters.temperature.or(self.temperature);
+        parameters.repetition_penalty = parameters.repetition_penalty.or(self.repetition_penalty);
+        parameters.top_k = parameters.top_k.or(self.top_k);
+        parameters.top_p = parameters.top_p.or(self.top_p);
+        parameters.typical_p = parameters.typical_p.or(self.typical_p);
+        parameters.truncate = parameters.truncate.or(self.truncate);
+        parameters.do_sample = parameters.do_sample.or(self.do_sample);
+        parameters.watermark = parameters.watermark.or(self.watermark);
+        if let Some(max_new_tokens) = self.max_new_tokens {
+            if parameters.max_new_tokens == default_max_new_tokens() {
+                parameters.max_new_tokens = max_new_tokens;
+            }
+        }
+        if let Some(stop) = &self.stop {
+            if parameters.stop.is_empty() {
+                parameters.stop = stop.clone();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum PresetError {
+    #[error("could not read presets: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse presets: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// Named presets loaded from a JSON file mapping the preset names to their parameters
+#[derive(Clone, Default)]
+pub(crate) struct Presets {
+    /// None if presets are disabled
+    path: Option<PathBuf>,
+    presets: Arc<RwLock<BTreeMap<String, Preset>>>,
+}
+
+impl Presets {
+    pub(crate) fn load(path: Option<PathBuf>) -> Result<Self, PresetError> {
+        let presets = Self {
+            path,
+            presets: Arc::default(),
+        };
+        presets.reload()?;
+        Ok(presets)
+    }
+
+    /// Load the presets again from the file
+    /// The current presets are kept if the file is invalid
+    pub(crate) fn reload(&self) -> Result<(), PresetError> {
+        if let Some(path) = &self.path {
+            let presets = read_presets(path)?;
+            tracing::info!("Loaded {} parameter presets", presets.len());
+            *self.presets.write() = presets;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn list(&self) -> BTreeMap<String, Preset> {
+        self.presets.read().clone()
+    }
+
+    /// Fill the parameters not given in a request with the preset `name`
+    pub(crate) fn apply(
+        &self,
+        name: &str,
+        parameters: &mut GenerateParameters,
+    ) -> Result<(), ValidationError> {
+        let presets = self.presets.read();
+        match presets.get(name) {
+            Some(preset) => {
+                preset.apply(parameters);
+                Ok(())
+            }
+            None => {
+                let available: Vec<&str> = presets.keys().map(String::as_str).collect();
+                Err(ValidationError::UnknownPreset(
+                    name.to_string(),
+                    available.join(", "),
+                ))
+            }
+        }
+    }
+}
+
+fn read_presets(path: &Path) -> Result<BTreeMap<String, Preset>, PresetError> {
+    let source = std::fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&source)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_parameters;
+
+    fn presets() -> Presets {
+        let presets = Presets::default();
+        *presets.presets.write() = serde_json::from_str(
+            r#"{
+                "creative": {"temperature": 1.2, "top_p": 0.95, "do_sample": true, "max_new_tokens": 64},
+                "precise": {"temperature": 0.2, "stop": ["\n"]}
+            }"#,
+        )
+        .unwrap();
+        presets
+    }
+
+    #[test]
+    fn test_apply() {
+        let presets = presets();
+
+        let mut parameters = default_parameters();
+        presets.apply("creative", &mut parameters).unwrap();
+        assert_eq!(parameters.temperature, Some(1.2));
+        assert_eq!(parameters.top_p, Some(0.95));
+        assert_eq!(parameters.do_sample, Some(true));
+        assert_eq!(parameters.max_new_tokens, 64);
+        assert_eq!(parameters.top_k, None);
+
+        // The parameters of the request take precedence
+        let mut parameters = GenerateParameters {
+            temperature: Some(0.7),
+            max_new_tokens: 10,
+            stop: vec!["User:".to_string()],
+            ..default_parameters()
+        };
+        presets.apply("creative", &mut parameters).unwrap();
+        assert_eq!(parameters.temperature, Some(0.7));
+        assert_eq!(parameters.max_new_tokens, 10);
+        presets.apply("precise", &mut parameters).unwrap();
+        assert_eq!(parameters.stop, vec!["User:".to_string()]);
+
+        // A flag of the preset can be turned off by the request
+        let mut parameters = GenerateParameters {
+            do_sample: Some(false),
+            ..default_parameters()
+        };
+        presets.apply("creative", &mut parameters).unwrap();
+        assert_eq!(parameters.do_sample, Some(false));
+    }
+
+    #[test]
+    fn test_unknown_preset() {
+        let presets = presets();
+        assert!(matches!(
+            presets.apply("balanced", &mut default_parameters()),
+            Err(ValidationError::UnknownPreset(name, available)) if name == "balanced" && available == "creative, precise"
+        ));
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        assert!(serde_json::from_str::<BTreeMap<String, Preset>>(
+            r#"{"creative": {"temprature": 1.2}}"#
+        )
+        .is_err());
+    }
+}
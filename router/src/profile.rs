@@ -0,0 +1,252 @@
+/// Serialization profiles of the responses
+use axum::body::{boxed, Bytes, Full, HttpBody, StreamBody};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde_json::Value;
+
+/// Header selecting the profile, as the `fmt` query parameter
+const PROFILE_HEADER: &str = "accept-profile";
+
+/// Naming of the keys of the JSON responses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ResponseProfile {
+    /// Keys of the API documentation, the default
+    Snake,
+    /// camelCase keys, asked with `Accept-Profile: camel` or `?fmt=camel`
+    Camel,
+}
+
+impl ResponseProfile {
+    fn from_request<B>(request: &Request<B>) -> Self {
+        let header = request
+            .headers()
+            .get(PROFILE_HEADER)
+            .and_then(|value| value.to_str().ok());
+        let query = request.uri().query().and_then(|query| {
+            query
+                .split('&')
+                .find_map(|parameter| parameter.strip_prefix("fmt="))
+        });
+        match [header, query]
+            .into_iter()
+            .flatten()
+            .any(|profile| profile.trim().eq_ignore_ascii_case("camel"))
+        {
+            true => ResponseProfile::Camel,
+            false => ResponseProfile::Snake,
+        }
+    }
+}
+
+/// Render the JSON responses and the streamed events with the profile of the request
+/// The responses of the default profile are sent as they are, as the ranged responses: their
+/// byte offsets are those of the default profile
+pub(crate) async fn profile_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    let profile = ResponseProfile::from_request(&request);
+    let response = next.run(request).await;
+    match profile {
+        ResponseProfile::Snake => response,
+        ResponseProfile::Camel => camel_response(response).await,
+    }
+}
+
+async fn camel_response(response: Response) -> Response {
+    let headers = response.headers();
+    if headers.contains_key(header::ACCEPT_RANGES) || headers.contains_key(header::CONTENT_RANGE) {
+        return response;
+    }
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if content_type.starts_with("application/json") {
+        let (mut parts, mut body) = response.into_parts();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(err) => {
+                    tracing::error!("Could not read the response: {err}");
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            }
+        }
+        let data = camel_json(&data).unwrap_or(data);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Response::from_parts(parts, boxed(Full::from(data)))
+    } else if content_type.starts_with("text/event-stream") {
+        let (parts, mut body) = response.into_parts();
+        // Each chunk of the body is a whole event
+        let events = async_stream::stream! {
+            while let Some(chunk) = body.data().await {
+                yield chunk.map(|chunk| camel_event(&chunk));
+            }
+        };
+        Response::from_parts(parts, boxed(StreamBody::new(events)))
+    } else {
+        response
+    }
+}
+
+/// None if `data` is not JSON
+fn camel_json(data: &[u8]) -> Option<Vec<u8>> {
+    let value: Value = serde_json::from_slice(data).ok()?;
+    serde_json::to_vec(&camel_keys(value)).ok()
+}
+
+/// Rename the keys of the JSON data of a server-sent event
+fn camel_event(event: &Bytes) -> Bytes {
+    let event = match std::str::from_utf8(event) {
+        Ok(event) => event,
+        Err(_) => return event.clone(),
+    };
+    let lines: Vec<String> = event
+        .split('\n')
+        .map(|line| match line.strip_prefix("data:") {
+            Some(data) => {
+                let (space, json) = match data.strip_prefix(' ') {
+                    Some(json) => (" ", json),
+                    None => ("", data),
+                };
+                match camel_json(json.as_bytes()).and_then(|json| String::from_utf8(json).ok()) {
+                    Some(json) => format!("data:{space}{json}"),
+                    None => line.to_string(),
+                }
+            }
+            None => line.to_string(),
+        })
+        .collect();
+    Bytes::from(lines.join("\n"))
+}
+
+/// Rename the keys of all the objects of `value` to camelCase, in their order
+/// The values are kept as they are: the enum variants stay in snake_case
+fn camel_keys(value: Value) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .map(|(key, value)| (camel_case(&key), camel_keys(value)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(camel_keys).collect()),
+        value => value,
+    }
+}
+
+fn camel_case(key: &str) -> String {
+    let mut camel = String::with_capacity(key.len());
+    let mut upper = false;
+    for c in key.chars() {
+        match c {
+            '_' => upper = !camel.is_empty(),
+            c if upper => {
+                camel.extend(c.to_uppercase());
+                upper = false;
+            }
+            c => camel.push(c),
+        }
+    }
+    camel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorResponse, GenerateResponse, StreamResponse};
+
+    const GENERATE_RESPONSE: &str = r#"{"generated_text":"test","details":{"finish_reason":"eos_token","generated_tokens":1,"seed":null,"prefill":[{"id":0,"text":"Hello","logprob":-1.5}],"tokens":[{"id":1,"text":"test","logprob":-0.34,"special":false}],"best_of_sequences":[{"generated_text":"other","finish_reason":"length","generated_tokens":1,"seed":42,"prefill":[],"tokens":[]}],"parameters":{"temperature":1.0,"top_k":0,"top_p":1.0,"typical_p":1.0,"repetition_penalty":1.0,"do_sample":false,"max_new_tokens":20,"stop":[],"seed":0,"watermark":false},"input_source":"text"}}"#;
+    const GENERATE_RESPONSE_CAMEL: &str = r#"{"generatedText":"test","details":{"finishReason":"eos_token","generatedTokens":1,"seed":null,"prefill":[{"id":0,"text":"Hello","logprob":-1.5}],"tokens":[{"id":1,"text":"test","logprob":-0.34,"special":false}],"bestOfSequences":[{"generatedText":"other","finishReason":"length","generatedTokens":1,"seed":42,"prefill":[],"tokens":[]}],"parameters":{"temperature":1.0,"topK":0,"topP":1.0,"typicalP":1.0,"repetitionPenalty":1.0,"doSample":false,"maxNewTokens":20,"stop":[],"seed":0,"watermark":false},"inputSource":"text"}}"#;
+    const STREAM_RESPONSE: &str = r#"{"token":{"id":1,"text":"test","logprob":-0.34,"special":false},"generated_text":"test","details":{"finish_reason":"length","generated_tokens":1,"seed":null},"generated_text_so_far":"test"}"#;
+    const STREAM_RESPONSE_CAMEL: &str = r#"{"token":{"id":1,"text":"test","logprob":-0.34,"special":false},"generatedText":"test","details":{"finishReason":"length","generatedTokens":1,"seed":null},"generatedTextSoFar":"test"}"#;
+    const ERROR_RESPONSE: &str = r#"{"error":"Model is overloaded","error_type":"overloaded","estimated_wait_ms":1000,"reason":"admission"}"#;
+    const ERROR_RESPONSE_CAMEL: &str = r#"{"error":"Model is overloaded","errorType":"overloaded","estimatedWaitMs":1000,"reason":"admission"}"#;
+
+    /// The default profile is the serialization of the response types, the camel profile renames
+    /// their keys
+    fn assert_snapshots<T: serde::de::DeserializeOwned + serde::Serialize>(
+        snake: &str,
+        camel: &str,
+    ) {
+        let response: T = serde_json::from_str(snake).unwrap();
+        assert_eq!(serde_json::to_string(&response).unwrap(), snake);
+        assert_eq!(
+            String::from_utf8(camel_json(snake.as_bytes()).unwrap()).unwrap(),
+            camel
+        );
+    }
+
+    #[test]
+    fn test_profile_snapshots() {
+        assert_snapshots::<GenerateResponse>(GENERATE_RESPONSE, GENERATE_RESPONSE_CAMEL);
+        assert_snapshots::<StreamResponse>(STREAM_RESPONSE, STREAM_RESPONSE_CAMEL);
+        assert_snapshots::<ErrorResponse>(ERROR_RESPONSE, ERROR_RESPONSE_CAMEL);
+    }
+
+    #[test]
+    fn test_camel_event() {
+        let event = Bytes::from(format!("data:{STREAM_RESPONSE}\n\n"));
+        assert_eq!(
+            camel_event(&event),
+            Bytes::from(format!("data:{STREAM_RESPONSE_CAMEL}\n\n"))
+        );
+        let event = Bytes::from("event:status\ndata:{\"queue_position\":1}\n\n");
+        assert_eq!(
+            camel_event(&event),
+            Bytes::from("event:status\ndata:{\"queuePosition\":1}\n\n")
+        );
+        // Keep-alive comments are sent as they are
+        let event = Bytes::from(":\n\n");
+        assert_eq!(camel_event(&event), event);
+    }
+
+    #[tokio::test]
+    async fn test_camel_ranged_response() {
+        let response = |ranged: bool| {
+            let mut response = Response::builder().header(header::CONTENT_TYPE, "application/json");
+            if ranged {
+                response = response.header(header::ACCEPT_RANGES, "bytes");
+            }
+            response.body(boxed(Full::from(STREAM_RESPONSE))).unwrap()
+        };
+        async fn body(response: Response) -> Vec<u8> {
+            let mut body = response.into_body();
+            let mut bytes = Vec::new();
+            while let Some(data) = body.data().await {
+                bytes.extend_from_slice(&data.unwrap());
+            }
+            bytes
+        }
+        assert_eq!(
+            body(camel_response(response(false)).await).await,
+            STREAM_RESPONSE_CAMEL.as_bytes()
+        );
+        // The ranges of the client would not match the renamed body
+        assert_eq!(
+            body(camel_response(response(true)).await).await,
+            STREAM_RESPONSE.as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_response_profile() {
+        let request = |uri: &str, profile: Option<&str>| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(profile) = profile {
+                request = request.header(PROFILE_HEADER, profile);
+            }
+            ResponseProfile::from_request(&request.body(()).unwrap())
+        };
+        assert_eq!(request("/generate", None), ResponseProfile::Snake);
+        assert_eq!(request("/generate", Some("camel")), ResponseProfile::Camel);
+        assert_eq!(request("/generate?fmt=camel", None), ResponseProfile::Camel);
+        assert_eq!(
+            request("/generate?a=1&fmt=Camel", None),
+            ResponseProfile::Camel
+        );
+        assert_eq!(request("/generate?fmt=snake", None), ResponseProfile::Snake);
+        assert_eq!(request("/generate", Some("snake")), ResponseProfile::Snake);
+    }
+}
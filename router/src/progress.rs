@@ -0,0 +1,142 @@
+/// Progress events of the streams with a `progress_interval_tokens`
+use crate::StreamProgress;
+use tokio::time::Instant;
+
+/// Counts the tokens sent to the client and rolls them up every `interval` tokens
+///
+/// The tokens are counted when they are sent, after the `stream_rate_limit` pacing and once the
+/// post-generation hook released them: the progress is the one seen by the client
+#[derive(Debug)]
+pub(crate) struct StreamProgressTracker {
+    interval: u32,
+    /// Reception of the request
+    start: Instant,
+    sent: u32,
+    /// Previous progress event, or the start of the stream
+    last: Instant,
+    last_sent: u32,
+}
+
+impl StreamProgressTracker {
+    /// None if the request has no `progress_interval_tokens`
+    pub(crate) fn new(interval: Option<u32>, start: Instant) -> Option<Self> {
+        Some(Self {
+            interval: interval.filter(|interval| *interval > 0)?,
+            start,
+            sent: 0,
+            last: Instant::now(),
+            last_sent: 0,
+        })
+    }
+
+    /// Count a token sent to the client, returns the progress every `interval` tokens
+    pub(crate) fn sent(&mut self) -> Option<StreamProgress> {
+        self.sent += 1;
+        if self.sent % self.interval != 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let secs = now.duration_since(self.last).as_secs_f64();
+        let tokens_per_second = match secs > 0.0 {
+            true => (self.sent - self.last_sent) as f64 / secs,
+            false => 0.0,
+        };
+        self.last = now;
+        self.last_sent = self.sent;
+        Some(StreamProgress {
+            tokens_generated: self.sent,
+            elapsed_ms: now.duration_since(self.start).as_millis() as u64,
+            tokens_per_second,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::{InferError, InferStreamResponse};
+    use crate::pacing::pace;
+    use crate::Token;
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    fn token(id: u32) -> Result<InferStreamResponse, InferError> {
+        Ok(InferStreamResponse::Token(Token {
+            id,
+            text: id.to_string(),
+            logprob: 0.0,
+            special: false,
+        }))
+    }
+
+    #[test]
+    fn test_disabled() {
+        assert!(StreamProgressTracker::new(None, Instant::now()).is_none());
+        assert!(StreamProgressTracker::new(Some(0), Instant::now()).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_interval() {
+        let start = Instant::now();
+        tokio::time::advance(Duration::from_millis(100)).await;
+        let mut progress = StreamProgressTracker::new(Some(3), start).unwrap();
+        for _ in 0..2 {
+            assert!(progress.sent().is_none());
+        }
+        tokio::time::advance(Duration::from_millis(250)).await;
+        assert_eq!(
+            progress.sent(),
+            Some(StreamProgress {
+                tokens_generated: 3,
+                elapsed_ms: 350,
+                tokens_per_second: 12.0,
+            })
+        );
+        // The rate is the one since the previous event
+        tokio::time::advance(Duration::from_millis(125)).await;
+        assert!(progress.sent().is_none());
+        assert!(progress.sent().is_none());
+        let sent = progress.sent().unwrap();
+        assert_eq!(sent.tokens_generated, 6);
+        assert_eq!(sent.elapsed_ms, 475);
+        assert_eq!(sent.tokens_per_second, 24.0);
+    }
+
+    /// The progress counts the paced tokens: its rate is the `stream_rate_limit`
+    #[tokio::test(start_paused = true)]
+    async fn test_paced() {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut paced = Box::pin(pace(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(receiver),
+            Some(8.0),
+        ));
+        for id in 0..5 {
+            sender.send(token(id)).unwrap();
+        }
+        let mut progress = StreamProgressTracker::new(Some(2), Instant::now()).unwrap();
+        let mut events = vec![];
+        for _ in 0..5 {
+            assert!(matches!(
+                paced.next().await,
+                Some(Ok(InferStreamResponse::Token(_)))
+            ));
+            events.extend(progress.sent());
+        }
+        // The first token is sent at once, then one every 125ms
+        assert_eq!(
+            events,
+            vec![
+                StreamProgress {
+                    tokens_generated: 2,
+                    elapsed_ms: 125,
+                    tokens_per_second: 16.0,
+                },
+                StreamProgress {
+                    tokens_generated: 4,
+                    elapsed_ms: 375,
+                    tokens_per_second: 8.0,
+                },
+            ]
+        );
+    }
+}
@@ -1,31 +1,214 @@
+use crate::coalesce::ResponseSender;
 use crate::infer::InferError;
 use crate::infer::InferStreamResponse;
+use crate::registry::RequestHandle;
+use crate::session::Session;
+use crate::stop::StopBuffer;
 use crate::validation::ValidGenerateRequest;
+use crate::{QueueStatus, QueuedRequest, RequestStatus};
 use nohash_hasher::{BuildNoHashHasher, IntMap};
 use std::cmp::min;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use text_generation_client::{Batch, Request};
+use tokenizers::Tokenizer;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit};
 use tokio::time::Instant;
 use tracing::{info_span, instrument, Span};
 
+/// Conservative lower bound of the time needed to prefill or decode one token
+/// Queued entries that cannot generate `max_new_tokens` at this pace before their deadline are
+/// failed instead of being batched
+const MIN_TIME_PER_TOKEN: Duration = Duration::from_millis(5);
+/// Interval between two updates of the oldest entry age when no batch is requested
+/// Queued entries whose client is gone are also dropped at this interval
+const OLDEST_ENTRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Entries holding their permit for longer than this are logged, as they are likely leaked
+pub(crate) const STALE_ENTRY_AGE: Duration = Duration::from_secs(600);
+/// Interval between two checks of the stale entries
+pub(crate) const STALE_ENTRY_INTERVAL: Duration = Duration::from_secs(60);
+/// Maximum number of requests listed by a queue snapshot
+const SNAPSHOT_REQUESTS: usize = 200;
+
 /// Queue entry
 #[derive(Debug)]
 pub(crate) struct Entry {
     /// Request
     pub request: ValidGenerateRequest,
     /// Response sender to communicate between the Infer struct and the batching_task
-    pub response_tx: UnboundedSender<Result<InferStreamResponse, InferError>>,
+    pub response_tx: ResponseSender,
+    /// Registry handle used to report this entry status
+    pub handle: Arc<RequestHandle>,
     /// Span that will live as long as entry
     pub span: Span,
     /// Temporary span used as a guard when logging inference, wait times...
     pub temp_span: Option<Span>,
     /// Instant when this entry was queued
     pub queue_time: Instant,
-    /// Instant when this entry was added to a batch
+    /// Instant when the prefill of this entry began, set by the batching task
     pub batch_time: Option<Instant>,
+    /// Instant after which the client is not waiting for this entry anymore
+    pub deadline: Option<Instant>,
+    /// Notify the client when this entry is added to a batch
+    pub heartbeat: bool,
+    /// Session used to reuse the backend state of the previous request
+    pub session: Option<Session>,
+    /// Holds back the streamed text that could be the start of a stop sequence
+    pub stop_buffer: StopBuffer,
+    /// Batched before the other entries, even if the batch is smaller than `min_size`
+    pub priority: bool,
+    /// Counts the priority entries as queued probes
+    pub probe: Option<QueuedProbe>,
+    /// Adding new requests to the batch of this entry is subject to the batching policy
+    pub latency_sensitive: bool,
+    /// Requeued instead of failed when its batch fails before it streamed any token
+    pub auto_requeue: bool,
+    /// Can be batched with fewer new tokens than requested when the batch token budget is short
+    pub allow_downgrade: bool,
+    /// `max_new_tokens` of the request, set when it was batched with fewer new tokens
+    pub requested_max_new_tokens: Option<u32>,
+    /// End of the API key of the request
+    pub api_key_id: Option<String>,
+    /// Tokenizer sending the pieces of the tokens as their text, if the request asks for them
+    pub token_pieces: Option<Arc<Tokenizer>>,
+    /// Tokens after which the router ends the generation, in case the backend does not stop at
+    /// `max_new_tokens`
+    pub token_limit: u32,
+    /// Text of the tokens sent, the generated text of a generation ended by the router
+    pub sent_text: String,
     /// Permit
-    pub _permit: OwnedSemaphorePermit,
+    pub permit: Permit,
+}
+
+impl Entry {
+    /// Span created by `span`, or a disabled span if the trace of the request is not sampled
+    pub(crate) fn sampled_span(&self, span: impl FnOnce() -> Span) -> Span {
+        match self.handle.sampled() {
+            true => span(),
+            false => Span::none(),
+        }
+    }
+
+    /// Log this entry if it holds its permit for longer than `STALE_ENTRY_AGE`
+    pub(crate) fn log_if_stale(&self) {
+        let age = self.queue_time.elapsed();
+        if age >= STALE_ENTRY_AGE {
+            tracing::warn!(
+                request_id = self.handle.id,
+                state = self.permit.state().as_str(),
+                client_connected = !self.response_tx.is_closed(),
+                "Entry holds its permit since {age:?}"
+            );
+        }
+    }
+}
+
+/// Tokens left for a new batch, the inputs and the new tokens of its entries included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TokenBudget {
+    pub tokens: u32,
+    /// The entries allowing it are batched with the new tokens left in the budget, if there are
+    /// at least this many
+    pub min_new_tokens: u32,
+}
+
+/// State of an entry holding a permit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PermitState {
+    Queued,
+    Running,
+}
+
+impl PermitState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PermitState::Queued => "queued",
+            PermitState::Running => "running",
+        }
+    }
+}
+
+/// Concurrency permit of an entry
+/// Counted by state in `tgi_permits_in_use` until the entry is dropped
+#[derive(Debug)]
+pub(crate) struct Permit {
+    _permit: OwnedSemaphorePermit,
+    state: PermitState,
+}
+
+impl Permit {
+    pub(crate) fn new(permit: OwnedSemaphorePermit) -> Self {
+        metrics::increment_gauge!("tgi_permits_in_use", 1.0, "state" => PermitState::Queued.as_str());
+        Self {
+            _permit: permit,
+            state: PermitState::Queued,
+        }
+    }
+
+    pub(crate) fn state(&self) -> PermitState {
+        self.state
+    }
+
+    fn set_running(&mut self) {
+        self.set_state(PermitState::Running);
+    }
+
+    fn set_queued(&mut self) {
+        self.set_state(PermitState::Queued);
+    }
+
+    fn set_state(&mut self, state: PermitState) {
+        if self.state != state {
+            metrics::decrement_gauge!("tgi_permits_in_use", 1.0, "state" => self.state.as_str());
+            self.state = state;
+            metrics::increment_gauge!("tgi_permits_in_use", 1.0, "state" => self.state.as_str());
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        metrics::decrement_gauge!("tgi_permits_in_use", 1.0, "state" => self.state.as_str());
+    }
+}
+
+/// Counts a probe in the queued probes of its backend while it is queued
+/// The batching task adds the queued probes to a full batch
+#[derive(Debug)]
+pub(crate) struct QueuedProbe {
+    queued_probes: Arc<AtomicUsize>,
+    queued: bool,
+}
+
+impl QueuedProbe {
+    pub(crate) fn new(queued_probes: Arc<AtomicUsize>) -> Self {
+        queued_probes.fetch_add(1, Ordering::SeqCst);
+        Self {
+            queued_probes,
+            queued: true,
+        }
+    }
+
+    fn set_queued(&mut self, queued: bool) {
+        if self.queued != queued {
+            match queued {
+                true => self.queued_probes.fetch_add(1, Ordering::SeqCst),
+                false => self.queued_probes.fetch_sub(1, Ordering::SeqCst),
+            };
+            self.queued = queued;
+        }
+    }
+}
+
+/// The probes leaving the queue without being batched, e.g. cancelled or late, stop being
+/// counted when they are dropped
+impl Drop for QueuedProbe {
+    fn drop(&mut self) {
+        self.set_queued(false);
+    }
 }
 
 /// Request Queue
@@ -36,12 +219,13 @@ pub(crate) struct Queue {
 }
 
 impl Queue {
-    pub(crate) fn new() -> Self {
+    /// `backend` is the label of the queue metrics
+    pub(crate) fn new(backend: &'static str) -> Self {
         // Create channel
         let (queue_sender, queue_receiver) = mpsc::unbounded_channel();
 
         // Launch background queue task
-        tokio::spawn(queue_task(queue_receiver));
+        tokio::spawn(queue_task(queue_receiver, backend));
 
         Self { queue_sender }
     }
@@ -62,6 +246,8 @@ impl Queue {
         &self,
         min_size: Option<usize>,
         max_size: usize,
+        max_tokens: Option<u32>,
+        budget: Option<TokenBudget>,
     ) -> Option<NextBatch> {
         // Create response channel
         let (response_sender, response_receiver) = oneshot::channel();
@@ -71,6 +257,106 @@ impl Queue {
             .send(QueueCommand::NextBatch {
                 min_size,
                 max_size,
+                max_tokens,
+                budget,
+                response_sender,
+                span: Span::current(),
+            })
+            .unwrap();
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Get a batch of the queued probes, the other entries staying queued
+    /// The probes are added to a full running batch: this never hands out another entry
+    #[instrument(skip(self))]
+    pub(crate) async fn next_probes(&self) -> Option<NextBatch> {
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send next probes command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::NextProbes {
+                response_sender,
+                span: Span::current(),
+            })
+            .unwrap();
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Put back entries of a failed batch at the front of the queue
+    /// They keep their queue time
+    #[instrument(skip_all)]
+    pub(crate) fn requeue(&self, entries: Vec<Entry>) {
+        // Send requeue command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::Requeue(entries, Span::current()))
+            .unwrap();
+    }
+
+    /// Get the position of a request in the queue
+    #[instrument(skip(self))]
+    pub(crate) async fn position(&self, request_id: u64) -> Option<usize> {
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send position command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::Position {
+                request_id,
+                response_sender,
+            })
+            .unwrap();
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Get the number of tokens left to generate by the queued requests
+    #[instrument(skip(self))]
+    pub(crate) async fn token_debt(&self) -> u64 {
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send token debt command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::TokenDebt { response_sender })
+            .unwrap();
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Get the metadata of the queued requests
+    /// The queue task only copies them: batching is not delayed by the serialization
+    #[instrument(skip(self))]
+    pub(crate) async fn snapshot(&self) -> QueueStatus {
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send snapshot command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::Snapshot { response_sender })
+            .unwrap();
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Remove a request from the queue if it was not batched yet
+    #[instrument(skip(self))]
+    pub(crate) async fn remove(&self, request_id: u64) -> Option<Entry> {
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send remove command to the background task managing the state
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::Remove {
+                request_id,
                 response_sender,
                 span: Span::current(),
             })
@@ -82,118 +368,539 @@ impl Queue {
 }
 
 // Background task responsible of the queue state
-async fn queue_task(mut receiver: UnboundedReceiver<QueueCommand>) {
-    let mut state = State::new();
+async fn queue_task(mut receiver: UnboundedReceiver<QueueCommand>, backend: &'static str) {
+    let mut state = State::new(backend);
+    // The age of the oldest entry must keep growing if the batching task is stuck
+    let mut oldest_entry_interval = tokio::time::interval(OLDEST_ENTRY_INTERVAL);
+    let mut stale_entry_interval = tokio::time::interval(STALE_ENTRY_INTERVAL);
 
-    while let Some(cmd) = receiver.recv().await {
+    loop {
+        let cmd = tokio::select! {
+            cmd = receiver.recv() => match cmd {
+                Some(cmd) => cmd,
+                None => break,
+            },
+            _ = oldest_entry_interval.tick() => {
+                state.remove_closed_entries();
+                // Deadlines also apply while no batch is requested, e.g. while the model loads
+                state.remove_late_entries();
+                state.update_oldest_entry_age();
+                continue;
+            }
+            _ = stale_entry_interval.tick() => {
+                state.entries.iter().for_each(|(_, entry)| entry.log_if_stale());
+                continue;
+            }
+        };
         match cmd {
             QueueCommand::Append(entry, span) => span.in_scope(|| state.append(entry)),
+            QueueCommand::Requeue(entries, span) => span.in_scope(|| state.requeue(entries)),
             QueueCommand::NextBatch {
                 min_size,
                 max_size,
+                max_tokens,
+                budget,
                 response_sender,
                 span,
             } => span.in_scope(|| {
-                let next_batch = state.next_batch(min_size, max_size);
+                let next_batch = state.next_batch(min_size, max_size, max_tokens, budget);
                 response_sender.send(next_batch).unwrap_or(());
             }),
+            QueueCommand::NextProbes {
+                response_sender,
+                span,
+            } => span.in_scope(|| {
+                response_sender.send(state.next_probes()).unwrap_or(());
+            }),
+            QueueCommand::Position {
+                request_id,
+                response_sender,
+            } => {
+                response_sender
+                    .send(state.position(request_id))
+                    .unwrap_or(());
+            }
+            QueueCommand::TokenDebt { response_sender } => {
+                response_sender.send(state.token_debt()).unwrap_or(());
+            }
+            QueueCommand::Snapshot { response_sender } => {
+                let start_time = Instant::now();
+                let snapshot = state.snapshot(SNAPSHOT_REQUESTS);
+                metrics::histogram!("tgi_queue_snapshot_duration", start_time.elapsed(), "backend" => backend);
+                response_sender.send(snapshot).unwrap_or(());
+            }
+            QueueCommand::Remove {
+                request_id,
+                response_sender,
+                span,
+            } => span.in_scope(|| {
+                // If the receiver is gone, the entry is dropped here which releases its permit
+                response_sender.send(state.remove(request_id)).unwrap_or(());
+            }),
         }
     }
 }
 
+/// Key of the sub-queue of an entry
+/// Batches take the entries of the sub-queues in key order. Each backend has its own queue and
+/// batching task, so the entries of a batch are always for the same backend
+///
+/// The backends do not load adapters: there is no adapter key and no weighted selection across
+/// keys. The priority sub-queue only goes first, its entries are bounded by the reserved probe
+/// permits so the normal entries cannot starve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum QueueKey {
+    /// Batched right away, even if the batch is smaller than `min_size`
+    Priority,
+    Normal,
+}
+
+impl QueueKey {
+    fn of(entry: &Entry) -> Self {
+        match entry.priority {
+            true => QueueKey::Priority,
+            false => QueueKey::Normal,
+        }
+    }
+}
+
+/// Queue entries split in sub-queues by key
+/// Iterated in batch order: the sub-queues in key order, each in FIFO order
+#[derive(Debug, Default)]
+struct Entries {
+    /// Sub-queues organized in VecDeques, as batches are taken from and requeued at their front
+    queues: BTreeMap<QueueKey, VecDeque<(u64, Entry)>>,
+    /// Number of entries of all the sub-queues
+    len: usize,
+}
+
+impl Entries {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Key of the sub-queue of the first entry in batch order
+    fn front_key(&self) -> Option<QueueKey> {
+        self.queues
+            .iter()
+            .find(|(_, queue)| !queue.is_empty())
+            .map(|(key, _)| *key)
+    }
+
+    fn push_back(&mut self, key: QueueKey, entry: (u64, Entry)) {
+        self.queues.entry(key).or_default().push_back(entry);
+        self.len += 1;
+    }
+
+    fn push_front(&mut self, key: QueueKey, entry: (u64, Entry)) {
+        self.queues.entry(key).or_default().push_front(entry);
+        self.len += 1;
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &(u64, Entry)> {
+        self.queues.values().flatten()
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut (u64, Entry)> {
+        self.queues.values_mut().flatten()
+    }
+
+    /// Remove the entry of a request
+    fn remove(&mut self, request_id: u64) -> Option<(u64, Entry)> {
+        for queue in self.queues.values_mut() {
+            if let Some(position) = queue
+                .iter()
+                .position(|(_, entry)| entry.handle.id == request_id)
+            {
+                self.len -= 1;
+                return queue.remove(position);
+            }
+        }
+        None
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&(u64, Entry)) -> bool) {
+        for queue in self.queues.values_mut() {
+            queue.retain(&mut f);
+        }
+        self.len = self.queues.values().map(VecDeque::len).sum();
+    }
+
+    /// Remove all the entries of a sub-queue, in order
+    fn drain_key(&mut self, key: QueueKey) -> Vec<(u64, Entry)> {
+        let entries: Vec<(u64, Entry)> = self
+            .queues
+            .get_mut(&key)
+            .map(|queue| queue.drain(..).collect())
+            .unwrap_or_default();
+        self.len -= entries.len();
+        entries
+    }
+
+    /// Remove the first `count` entries in batch order
+    fn drain_front(&mut self, count: usize) -> Vec<(u64, Entry)> {
+        let mut entries = Vec::with_capacity(count);
+        for queue in self.queues.values_mut() {
+            let drained = min(count - entries.len(), queue.len());
+            entries.extend(queue.drain(..drained));
+        }
+        self.len -= entries.len();
+        entries
+    }
+}
+
 /// Queue State
 #[derive(Debug)]
 struct State {
-    /// Queue entries organized in a Vec
-    entries: Vec<(u64, Entry)>,
+    /// Queue entries, in sub-queues
+    entries: Entries,
 
     /// Id of the next entry
     next_id: u64,
 
     /// Id of the next batch
     next_batch_id: u64,
+
+    /// Label of the metrics
+    backend: &'static str,
 }
 
 impl State {
-    fn new() -> Self {
+    fn new(backend: &'static str) -> Self {
         Self {
-            entries: Vec::with_capacity(128),
+            entries: Entries::default(),
             next_id: 0,
             next_batch_id: 0,
+            backend,
         }
     }
 
     /// Append an entry to the queue
     fn append(&mut self, mut entry: Entry) {
         // Create a span that will live as long as the entry is in the queue waiting to be batched
-        let queue_span = info_span!(parent: &entry.span, "queued");
+        let queue_span = entry.sampled_span(|| info_span!(parent: &entry.span, "queued"));
         entry.temp_span = Some(queue_span);
 
-        // Push entry in the queue
-        self.entries.push((self.next_id, entry));
+        // Push entry at the back of its sub-queue
+        self.entries
+            .push_back(QueueKey::of(&entry), (self.next_id, entry));
         self.next_id += 1;
-        metrics::increment_gauge!("tgi_queue_size", 1.0);
+        metrics::increment_gauge!("tgi_queue_size", 1.0, "backend" => self.backend);
+    }
+
+    /// Insert entries at the front of their sub-queues, in order
+    fn requeue(&mut self, entries: Vec<Entry>) {
+        let count = entries.len();
+        let entries: Vec<(u64, Entry)> = entries
+            .into_iter()
+            .map(|mut entry| {
+                entry.temp_span =
+                    Some(entry.sampled_span(|| info_span!(parent: &entry.span, "queued")));
+                entry.batch_time = None;
+                entry.handle.set_requeued();
+                entry.permit.set_queued();
+                if let Some(probe) = &mut entry.probe {
+                    probe.set_queued(true);
+                }
+                self.next_id += 1;
+                (self.next_id - 1, entry)
+            })
+            .collect();
+        for (id, entry) in entries.into_iter().rev() {
+            self.entries.push_front(QueueKey::of(&entry), (id, entry));
+        }
+        metrics::increment_gauge!("tgi_queue_size", count as f64, "backend" => self.backend);
+    }
+
+    /// Position of a request in the queue, in batch order
+    fn position(&self, request_id: u64) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|(_, entry)| entry.handle.id == request_id)
+    }
+
+    /// Maximum number of tokens the queued requests will generate
+    fn token_debt(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|(_, entry)| entry.request.stopping_parameters.max_new_tokens as u64)
+            .sum()
+    }
+
+    /// Aggregates of the queued entries and metadata of the first `max_requests` ones
+    fn snapshot(&self, max_requests: usize) -> QueueStatus {
+        let now = Instant::now();
+        let mut status = QueueStatus {
+            // Set by Infer
+            backend: "",
+            size: self.entries.len(),
+            oldest_age_ms: 0,
+            input_tokens: 0,
+            token_debt: 0,
+            priority: 0,
+            // Set by Infer
+            cache_utilization: None,
+            latency_budget: None,
+            requests: Vec::with_capacity(min(self.entries.len(), max_requests)),
+        };
+        for (_, entry) in self.entries.iter() {
+            let age_ms = now.saturating_duration_since(entry.queue_time).as_millis() as u64;
+            let max_new_tokens = entry.request.stopping_parameters.max_new_tokens;
+            status.oldest_age_ms = status.oldest_age_ms.max(age_ms);
+            status.input_tokens += entry.request.input_length as u64;
+            status.token_debt += max_new_tokens as u64;
+            status.priority += entry.priority as usize;
+            if status.requests.len() < max_requests {
+                status.requests.push(QueuedRequest {
+                    id: entry.handle.id,
+                    age_ms,
+                    input_length: entry.request.input_length,
+                    max_new_tokens,
+                    priority: entry.priority,
+                    latency_sensitive: entry.latency_sensitive,
+                    requeues: entry.handle.requeues(),
+                    api_key_id: entry.api_key_id.clone(),
+                });
+            }
+        }
+        status
+    }
+
+    /// Remove a request from the queue
+    fn remove(&mut self, request_id: u64) -> Option<Entry> {
+        let (_, entry) = self.entries.remove(request_id)?;
+        metrics::decrement_gauge!("tgi_queue_size", 1.0, "backend" => self.backend);
+        Some(entry)
+    }
+
+    /// Update the gauge of the time spent in the queue by the oldest entry
+    fn update_oldest_entry_age(&self) {
+        let age = self
+            .entries
+            .iter()
+            .map(|(_, entry)| entry.queue_time.elapsed())
+            .max()
+            .unwrap_or_default();
+        metrics::gauge!("tgi_queue_oldest_entry_age", age.as_secs_f64(), "backend" => self.backend);
+    }
+
+    /// Drop the entries whose client is gone, releasing their permits
+    fn remove_closed_entries(&mut self) {
+        let queue_size = self.entries.len();
+
+        self.entries.retain(|(_, entry)| {
+            if !entry.response_tx.is_closed() {
+                return true;
+            }
+            let _closed_span = entry.temp_span.as_ref().map(|span| span.enter());
+            metrics::increment_counter!("tgi_request_failure", "err" => "cancelled");
+            tracing::debug!("Client disconnected while queued");
+            transition!(entry.handle, "cancelled");
+            entry.handle.finish(RequestStatus::Cancelled, None);
+            false
+        });
+
+        if self.entries.len() != queue_size {
+            metrics::gauge!("tgi_queue_size", self.entries.len() as f64, "backend" => self.backend);
+        }
+    }
+
+    /// Fail the entries that cannot meet their deadline anymore
+    fn remove_late_entries(&mut self) {
+        let now = Instant::now();
+        let queue_size = self.entries.len();
+
+        self.entries.retain(|(_, entry)| {
+            let deadline = match entry.deadline {
+                None => return true,
+                Some(deadline) => deadline,
+            };
+            // Prefill + decode of `max_new_tokens`
+            let min_generation_time =
+                MIN_TIME_PER_TOKEN * (entry.request.stopping_parameters.max_new_tokens + 1);
+            if deadline.saturating_duration_since(now) >= min_generation_time {
+                return true;
+            }
+
+            let _late_span = entry.temp_span.as_ref().map(|span| span.enter());
+            let err = InferError::DeadlineExceeded;
+            metrics::increment_counter!("tgi_request_failure", "err" => "deadline_exceeded");
+            tracing::error!("{err}");
+            transition!(entry.handle, "failed", error_type = err.error_code());
+            entry
+                .handle
+                .finish(RequestStatus::Failed, Some(err.to_string()));
+            // unwrap_or is valid here as we don't care if the receiver is gone.
+            entry.response_tx.send(Err(err)).unwrap_or(());
+            false
+        });
+
+        if self.entries.len() != queue_size {
+            metrics::gauge!("tgi_queue_size", self.entries.len() as f64, "backend" => self.backend);
+        }
     }
 
     // Get the next batch
-    fn next_batch(&mut self, min_size: Option<usize>, max_size: usize) -> Option<NextBatch> {
+    // If `max_tokens` is set, the batch only contains the entries whose inputs fit in `max_tokens`
+    // tokens, or the first entry if its inputs are longer
+    fn next_batch(
+        &mut self,
+        min_size: Option<usize>,
+        max_size: usize,
+        max_tokens: Option<u32>,
+        budget: Option<TokenBudget>,
+    ) -> Option<NextBatch> {
+        self.remove_closed_entries();
+        self.remove_late_entries();
+        self.update_oldest_entry_age();
+
         if self.entries.is_empty() {
             return None;
         }
 
         // Check if we have enough entries
+        // Priority entries are batched right away
         if let Some(min_size) = min_size {
-            if self.entries.len() < min_size {
+            if self.entries.len() < min_size && self.entries.front_key() != Some(QueueKey::Priority)
+            {
                 return None;
             }
         }
 
-        let next_batch_size = min(self.entries.len(), max_size);
+        let mut next_batch_size = min(self.entries.len(), max_size);
+        if let Some(max_tokens) = max_tokens {
+            let mut tokens = 0;
+            next_batch_size = self
+                .entries
+                .iter()
+                .take(next_batch_size)
+                .position(|(_, entry)| {
+                    tokens += entry.request.input_length;
+                    tokens > max_tokens
+                })
+                .unwrap_or(next_batch_size)
+                .max(1);
+        }
 
+        // The entries are batched in order while they fit in the token budget
+        // An entry that does not fit holds back the younger entries instead of being skipped by
+        // the shorter ones: a long prompt that fits alone waits at most for the running batch and
+        // for the probes, bounded by their reserved permits
+        if let Some(budget) = budget {
+            let mut tokens = 0;
+            let mut size = 0;
+            for (_, entry) in self.entries.iter_mut().take(next_batch_size) {
+                let request = &entry.request;
+                let entry_tokens =
+                    request.input_length + request.stopping_parameters.max_new_tokens;
+                if tokens + entry_tokens <= budget.tokens {
+                    tokens += entry_tokens;
+                    size += 1;
+                    continue;
+                }
+                // The last entry uses the rest of the budget
+                let max_new_tokens = budget.tokens.saturating_sub(tokens + request.input_length);
+                if entry.allow_downgrade && max_new_tokens >= budget.min_new_tokens {
+                    let stopping_parameters = &mut entry.request.stopping_parameters;
+                    // A requeued entry keeps the `max_new_tokens` of its request
+                    entry
+                        .requested_max_new_tokens
+                        .get_or_insert(stopping_parameters.max_new_tokens);
+                    stopping_parameters.max_new_tokens = max_new_tokens;
+                    metrics::increment_counter!("tgi_request_downgraded");
+                    transition!(entry.handle, "downgraded", max_new_tokens);
+                    size += 1;
+                }
+                break;
+            }
+            if size == 0 {
+                return None;
+            }
+            next_batch_size = size;
+        }
+
+        let entries = self.entries.drain_front(next_batch_size);
+        Some(self.batch(entries))
+    }
+
+    /// Get a batch of the queued probes only
+    fn next_probes(&mut self) -> Option<NextBatch> {
+        self.remove_closed_entries();
+        self.remove_late_entries();
+        self.update_oldest_entry_age();
+
+        let entries = self.entries.drain_key(QueueKey::Priority);
+        if entries.is_empty() {
+            return None;
+        }
+        Some(self.batch(entries))
+    }
+
+    /// Batch entries removed from the queue
+    fn batch(&mut self, entries: Vec<(u64, Entry)>) -> NextBatch {
+        let next_batch_size = entries.len();
         // Create span for this batch to add context to inference calls
         let next_batch_span = info_span!(parent: None, "batch", batch_size = next_batch_size);
         next_batch_span.follows_from(&Span::current());
 
+        let batch_id = self.next_batch_id;
         let mut batch_requests = Vec::with_capacity(next_batch_size);
         let mut batch_entries =
             IntMap::with_capacity_and_hasher(next_batch_size, BuildNoHashHasher::default());
 
-        // Drain next_batch_size entries
-        self.entries
-            .drain(..next_batch_size)
-            .for_each(|(id, mut entry)| {
-                // Create a new span to link the batch back to this entry
-                let entry_batch_span =
-                    info_span!(parent: &entry.span, "infer", batch_size = next_batch_size);
-                // Add relationships
-                next_batch_span.follows_from(&entry_batch_span);
-                entry_batch_span.follows_from(&next_batch_span);
-                // Update entry
-                entry.temp_span = Some(entry_batch_span);
-
-                batch_requests.push(Request {
-                    id,
-                    inputs: entry.request.inputs.clone(),
-                    parameters: Some(entry.request.parameters.clone()),
-                    stopping_parameters: Some(entry.request.stopping_parameters.clone()),
-                });
-                // Set batch_time
-                entry.batch_time = Some(Instant::now());
-                // Insert in batch_entries IntMap
-                batch_entries.insert(id, entry);
+        entries.into_iter().for_each(|(id, mut entry)| {
+            // Create a new span to link the batch back to this entry
+            let entry_batch_span = entry.sampled_span(
+                || info_span!(parent: &entry.span, "infer", batch_size = next_batch_size),
+            );
+            // Add relationships
+            next_batch_span.follows_from(&entry_batch_span);
+            entry_batch_span.follows_from(&next_batch_span);
+            // Update entry
+            entry.temp_span = Some(entry_batch_span);
+
+            batch_requests.push(Request {
+                id,
+                inputs: entry.request.inputs.clone(),
+                parameters: Some(entry.request.parameters.clone()),
+                stopping_parameters: Some(entry.request.stopping_parameters.clone()),
+                prefix_cache: entry.session.as_ref().map(Session::prefix_cache),
+                prefill_logprobs: entry.request.prefill_tokens,
             });
+            metrics::histogram!("tgi_queue_duration", entry.queue_time.elapsed(), "backend" => self.backend);
+            entry.handle.set_running();
+            entry.permit.set_running();
+            if let Some(probe) = &mut entry.probe {
+                probe.set_queued(false);
+            }
+            transition!(entry.handle, "dequeued", batch_id);
+            if entry.heartbeat {
+                // unwrap_or is valid here as we don't care if the receiver is gone.
+                entry
+                    .response_tx
+                    .send(Ok(InferStreamResponse::Started))
+                    .unwrap_or(());
+            }
+            // Insert in batch_entries IntMap
+            batch_entries.insert(id, entry);
+        });
 
         let batch = Batch {
-            id: self.next_batch_id,
+            id: batch_id,
             requests: batch_requests,
             size: next_batch_size as u32,
         };
         // Increment batch id
         self.next_batch_id += 1;
 
-        metrics::gauge!("tgi_queue_size", self.entries.len() as f64);
-        metrics::histogram!("tgi_batch_next_size", batch.size as f64);
-        Some((batch_entries, batch, next_batch_span))
+        metrics::gauge!("tgi_queue_size", self.entries.len() as f64, "backend" => self.backend);
+        metrics::histogram!("tgi_batch_next_size", batch.size as f64, "backend" => self.backend);
+        self.update_oldest_entry_age();
+        (batch_entries, batch, next_batch_span)
     }
 }
 
@@ -202,30 +909,64 @@ type NextBatch = (IntMap<u64, Entry>, Batch, Span);
 #[derive(Debug)]
 enum QueueCommand {
     Append(Entry, Span),
+    Requeue(Vec<Entry>, Span),
     NextBatch {
         min_size: Option<usize>,
         max_size: usize,
+        max_tokens: Option<u32>,
+        budget: Option<TokenBudget>,
         response_sender: oneshot::Sender<Option<NextBatch>>,
         span: Span,
     },
+    NextProbes {
+        response_sender: oneshot::Sender<Option<NextBatch>>,
+        span: Span,
+    },
+    Position {
+        request_id: u64,
+        response_sender: oneshot::Sender<Option<usize>>,
+    },
+    TokenDebt {
+        response_sender: oneshot::Sender<u64>,
+    },
+    Snapshot {
+        response_sender: oneshot::Sender<QueueStatus>,
+    },
+    Remove {
+        request_id: u64,
+        response_sender: oneshot::Sender<Option<Entry>>,
+        span: Span,
+    },
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::sync::Arc;
+    use crate::validation::ValidationTimings;
+    use crate::InputSource;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
     use text_generation_client::{NextTokenChooserParameters, StoppingCriteriaParameters};
     use tokio::sync::{mpsc, Semaphore};
     use tracing::info_span;
 
-    fn default_entry() -> Entry {
+    /// Receivers of the entries of a test, keeping their clients connected until the test ends
+    type Clients = Vec<UnboundedReceiver<Result<InferStreamResponse, InferError>>>;
+
+    fn default_entry(clients: &mut Clients) -> Entry {
+        default_entry_with_handle(0, clients)
+    }
+
+    fn default_entry_with_handle(request_id: u64, clients: &mut Clients) -> Entry {
         let semaphore = Arc::new(Semaphore::new(1));
-        let (response_tx, _) = mpsc::unbounded_channel();
+        let (response_tx, response_rx) = mpsc::unbounded_channel();
+        clients.push(response_rx);
         let permit = semaphore.try_acquire_owned().unwrap();
 
         Entry {
             request: ValidGenerateRequest {
                 inputs: "".to_string(),
+                input_length: 0,
                 parameters: NextTokenChooserParameters {
                     temperature: 0.0,
                     top_k: 0,
@@ -235,26 +976,51 @@ mod tests {
                     seed: 0,
                     repetition_penalty: 0.0,
                     watermark: false,
+                    allowed_tokens: vec![],
                 },
                 stopping_parameters: StoppingCriteriaParameters {
                     ignore_eos_token: false,
                     max_new_tokens: 0,
                     stop_sequences: vec![],
                 },
+                stop_matcher: Arc::default(),
+                prefill_tokens: false,
+                tokenization: None,
+                clean_up_tokenization_spaces: false,
+                raw_token_text: false,
+                normalized_chars: None,
+                input_source: InputSource::Text,
+                timings: ValidationTimings::default(),
             },
-            response_tx,
+            response_tx: response_tx.into(),
+            handle: Arc::new(RequestHandle::new(request_id, false)),
             span: info_span!("entry"),
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
-            _permit: permit,
+            deadline: None,
+            heartbeat: false,
+            session: None,
+            stop_buffer: StopBuffer::new(Arc::default()),
+            priority: false,
+            probe: None,
+            latency_sensitive: false,
+            auto_requeue: false,
+            allow_downgrade: false,
+            requested_max_new_tokens: None,
+            api_key_id: None,
+            token_pieces: None,
+            token_limit: u32::MAX,
+            sent_text: String::new(),
+            permit: Permit::new(permit),
         }
     }
 
     #[test]
     fn test_append() {
-        let mut state = State::new();
-        let entry = default_entry();
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        let entry = default_entry(&mut clients);
 
         assert_eq!(state.next_id, 0);
         assert_eq!(state.entries.len(), 0);
@@ -263,30 +1029,32 @@ mod tests {
 
         assert_eq!(state.next_id, 1);
         assert_eq!(state.entries.len(), 1);
-        let (id, _) = state.entries.remove(0);
-        assert_eq!(id, 0);
+        let (id, _) = state.entries.iter().next().unwrap();
+        assert_eq!(*id, 0);
     }
 
     #[test]
     fn test_next_batch_empty() {
-        let mut state = State::new();
+        let mut state = State::new("stable");
 
-        assert!(state.next_batch(None, 1).is_none());
-        assert!(state.next_batch(Some(1), 1).is_none());
+        assert!(state.next_batch(None, 1, None, None).is_none());
+        assert!(state.next_batch(Some(1), 1, None, None).is_none());
     }
 
     #[test]
     fn test_next_batch_min_size() {
-        let mut state = State::new();
-        state.append(default_entry());
-        state.append(default_entry());
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        state.append(default_entry(&mut clients));
+        state.append(default_entry(&mut clients));
 
-        let (entries, batch, _) = state.next_batch(None, 2).unwrap();
+        let (entries, batch, _) = state.next_batch(None, 2, None, None).unwrap();
         assert_eq!(entries.len(), 2);
         assert!(entries.contains_key(&0));
         assert!(entries.contains_key(&1));
-        assert!(entries.get(&0).unwrap().batch_time.is_some());
-        assert!(entries.get(&1).unwrap().batch_time.is_some());
+        // Set when the prefill of the batch begins
+        assert!(entries.get(&0).unwrap().batch_time.is_none());
+        assert!(entries.get(&1).unwrap().batch_time.is_none());
         assert_eq!(batch.id, 0);
         assert_eq!(batch.size, 2);
 
@@ -294,23 +1062,24 @@ mod tests {
         assert_eq!(state.entries.len(), 0);
         assert_eq!(state.next_batch_id, 1);
 
-        state.append(default_entry());
+        state.append(default_entry(&mut clients));
 
-        assert!(state.next_batch(Some(2), 2).is_none());
+        assert!(state.next_batch(Some(2), 2, None, None).is_none());
 
         assert_eq!(state.next_id, 3);
         assert_eq!(state.entries.len(), 1);
-        let (id, _) = state.entries.remove(0);
-        assert_eq!(id, 2);
+        let (id, _) = state.entries.iter().next().unwrap();
+        assert_eq!(*id, 2);
     }
 
     #[test]
     fn test_next_batch_max_size() {
-        let mut state = State::new();
-        state.append(default_entry());
-        state.append(default_entry());
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        state.append(default_entry(&mut clients));
+        state.append(default_entry(&mut clients));
 
-        let (entries, batch, _) = state.next_batch(None, 1).unwrap();
+        let (entries, batch, _) = state.next_batch(None, 1, None, None).unwrap();
         assert_eq!(entries.len(), 1);
         assert!(entries.contains_key(&0));
         assert_eq!(batch.id, 0);
@@ -320,9 +1089,9 @@ mod tests {
         assert_eq!(state.entries.len(), 1);
         assert_eq!(state.next_batch_id, 1);
 
-        state.append(default_entry());
+        state.append(default_entry(&mut clients));
 
-        let (entries, batch, _) = state.next_batch(None, 3).unwrap();
+        let (entries, batch, _) = state.next_batch(None, 3, None, None).unwrap();
         assert_eq!(entries.len(), 2);
         assert!(entries.contains_key(&1));
         assert!(entries.contains_key(&2));
@@ -336,57 +1105,563 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_append() {
-        let queue = Queue::new();
-        queue.append(default_entry());
+        let mut clients = Clients::new();
+        let queue = Queue::new("stable");
+        queue.append(default_entry(&mut clients));
     }
 
     #[tokio::test]
     async fn test_queue_next_batch_empty() {
-        let queue = Queue::new();
+        let queue = Queue::new("stable");
 
-        assert!(queue.next_batch(None, 1).await.is_none());
-        assert!(queue.next_batch(Some(1), 1).await.is_none());
+        assert!(queue.next_batch(None, 1, None, None).await.is_none());
+        assert!(queue.next_batch(Some(1), 1, None, None).await.is_none());
     }
 
     #[tokio::test]
     async fn test_queue_next_batch_min_size() {
-        let queue = Queue::new();
-        queue.append(default_entry());
-        queue.append(default_entry());
+        let mut clients = Clients::new();
+        let queue = Queue::new("stable");
+        queue.append(default_entry(&mut clients));
+        queue.append(default_entry(&mut clients));
 
-        let (entries, batch, _) = queue.next_batch(None, 2).await.unwrap();
+        let (entries, batch, _) = queue.next_batch(None, 2, None, None).await.unwrap();
         assert_eq!(entries.len(), 2);
         assert!(entries.contains_key(&0));
         assert!(entries.contains_key(&1));
-        assert!(entries.get(&0).unwrap().batch_time.is_some());
-        assert!(entries.get(&1).unwrap().batch_time.is_some());
+        assert!(entries.get(&0).unwrap().batch_time.is_none());
+        assert!(entries.get(&1).unwrap().batch_time.is_none());
         assert_eq!(batch.id, 0);
         assert_eq!(batch.size, 2);
 
-        queue.append(default_entry());
+        queue.append(default_entry(&mut clients));
 
-        assert!(queue.next_batch(Some(2), 2).await.is_none());
+        assert!(queue.next_batch(Some(2), 2, None, None).await.is_none());
     }
 
     #[tokio::test]
     async fn test_queue_next_batch_max_size() {
-        let queue = Queue::new();
-        queue.append(default_entry());
-        queue.append(default_entry());
+        let mut clients = Clients::new();
+        let queue = Queue::new("stable");
+        queue.append(default_entry(&mut clients));
+        queue.append(default_entry(&mut clients));
 
-        let (entries, batch, _) = queue.next_batch(None, 1).await.unwrap();
+        let (entries, batch, _) = queue.next_batch(None, 1, None, None).await.unwrap();
         assert_eq!(entries.len(), 1);
         assert!(entries.contains_key(&0));
         assert_eq!(batch.id, 0);
         assert_eq!(batch.size, 1);
 
-        queue.append(default_entry());
+        queue.append(default_entry(&mut clients));
 
-        let (entries, batch, _) = queue.next_batch(None, 3).await.unwrap();
+        let (entries, batch, _) = queue.next_batch(None, 3, None, None).await.unwrap();
         assert_eq!(entries.len(), 2);
         assert!(entries.contains_key(&1));
         assert!(entries.contains_key(&2));
         assert_eq!(batch.id, 1);
         assert_eq!(batch.size, 2);
     }
+
+    #[test]
+    fn test_snapshot() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        let mut entry = default_entry_with_handle(7, &mut clients);
+        entry.request.input_length = 10;
+        entry.request.stopping_parameters.max_new_tokens = 20;
+        entry.api_key_id = Some("9a3e0c5b71d2f4e8".to_string());
+        state.append(entry);
+        state.append(default_entry_with_handle(8, &mut clients));
+        let mut probe = default_entry_with_handle(9, &mut clients);
+        probe.priority = true;
+        state.append(probe);
+
+        let snapshot = state.snapshot(2);
+        assert_eq!(snapshot.size, 3);
+        assert_eq!(snapshot.input_tokens, 10);
+        assert_eq!(snapshot.token_debt, 20);
+        assert_eq!(snapshot.priority, 1);
+        // In batching order
+        let ids: Vec<u64> = snapshot.requests.iter().map(|request| request.id).collect();
+        assert_eq!(ids, vec![9, 7]);
+        assert_eq!(
+            snapshot.requests[1].api_key_id.as_deref(),
+            Some("9a3e0c5b71d2f4e8")
+        );
+        assert_eq!(snapshot.requests[1].max_new_tokens, 20);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_snapshot_does_not_block_batching() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        for id in 0..10_000 {
+            state.append(default_entry_with_handle(id, &mut clients));
+        }
+        let start_time = Instant::now();
+        let snapshot = state.snapshot(SNAPSHOT_REQUESTS);
+        // Microseconds in release builds
+        assert!(start_time.elapsed() < Duration::from_millis(20));
+        assert_eq!(snapshot.requests.len(), SNAPSHOT_REQUESTS);
+
+        let queue = Queue::new("stable");
+        for _ in 0..10_000 {
+            queue.append(default_entry(&mut clients));
+        }
+        let snapshots = tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                loop {
+                    assert_eq!(queue.snapshot().await.requests.len(), SNAPSHOT_REQUESTS);
+                }
+            }
+        });
+
+        let mut max_wait = Duration::ZERO;
+        for _ in 0..100 {
+            let start_time = Instant::now();
+            assert!(queue.next_batch(None, 1, None, None).await.is_some());
+            max_wait = max_wait.max(start_time.elapsed());
+        }
+        snapshots.abort();
+        assert!(max_wait < Duration::from_millis(100), "{max_wait:?}");
+    }
+
+    #[test]
+    fn test_position_and_remove() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        state.append(default_entry_with_handle(10, &mut clients));
+        state.append(default_entry_with_handle(11, &mut clients));
+
+        assert_eq!(state.position(10), Some(0));
+        assert_eq!(state.position(11), Some(1));
+        assert_eq!(state.position(12), None);
+
+        let entry = state.remove(10).unwrap();
+        assert_eq!(entry.handle.id, 10);
+        assert_eq!(state.entries.len(), 1);
+        assert_eq!(state.position(11), Some(0));
+        assert!(state.remove(10).is_none());
+    }
+
+    #[test]
+    fn test_token_debt() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        assert_eq!(state.token_debt(), 0);
+
+        let mut entry = default_entry_with_handle(10, &mut clients);
+        entry.request.stopping_parameters.max_new_tokens = 10;
+        state.append(entry);
+        let mut entry = default_entry_with_handle(11, &mut clients);
+        entry.request.stopping_parameters.max_new_tokens = 20;
+        state.append(entry);
+        assert_eq!(state.token_debt(), 30);
+
+        state.remove(10);
+        assert_eq!(state.token_debt(), 20);
+    }
+
+    #[test]
+    fn test_next_batch_set_running() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        state.append(default_entry(&mut clients));
+
+        let (entries, _, _) = state.next_batch(None, 1, None, None).unwrap();
+        let entry = entries.get(&0).unwrap();
+        assert_eq!(entry.handle.status(), RequestStatus::Running);
+    }
+
+    #[test]
+    fn test_requeue() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        state.append(default_entry_with_handle(0, &mut clients));
+        let (entries, _, _) = state.next_batch(None, 1, None, None).unwrap();
+        state.append(default_entry_with_handle(1, &mut clients));
+
+        let entry = entries.into_values().next().unwrap();
+        let queue_time = entry.queue_time;
+        state.requeue(vec![entry]);
+
+        // The requeued entry is batched first with its original queue time
+        let (entries, _, _) = state.next_batch(None, 1, None, None).unwrap();
+        let entry = entries.get(&2).unwrap();
+        assert_eq!(entry.handle.id, 0);
+        assert_eq!(entry.handle.requeues(), 1);
+        assert_eq!(entry.queue_time, queue_time);
+        assert_eq!(state.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_next_batch_heartbeat_started() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        let mut entry = default_entry(&mut clients);
+        entry.response_tx = response_tx.into();
+        entry.heartbeat = true;
+        state.append(entry);
+
+        state.next_batch(None, 1, None, None).unwrap();
+        assert!(matches!(
+            response_rx.try_recv(),
+            Ok(Ok(InferStreamResponse::Started))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_queue_remove() {
+        let mut clients = Clients::new();
+        let queue = Queue::new("stable");
+        queue.append(default_entry_with_handle(3, &mut clients));
+
+        assert_eq!(queue.position(3).await, Some(0));
+        assert!(queue.remove(3).await.is_some());
+        assert!(queue.position(3).await.is_none());
+        assert!(queue.next_batch(None, 1, None, None).await.is_none());
+    }
+
+    #[test]
+    fn test_next_batch_late_entries() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        let mut late_entry = default_entry_with_handle(0, &mut clients);
+        late_entry.request.stopping_parameters.max_new_tokens = 10;
+        late_entry.deadline = Some(Instant::now() + Duration::from_millis(1));
+        let handle = late_entry.handle.clone();
+        state.append(late_entry);
+
+        let mut entry = default_entry_with_handle(1, &mut clients);
+        entry.deadline = Some(Instant::now() + Duration::from_secs(60));
+        state.append(entry);
+
+        let (entries, batch, _) = state.next_batch(None, 2, None, None).unwrap();
+        assert_eq!(batch.size, 1);
+        assert!(entries.contains_key(&1));
+        assert_eq!(handle.status(), RequestStatus::Failed);
+        assert!(state.entries.is_empty());
+    }
+
+    #[test]
+    fn test_next_batch_priority() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        state.append(default_entry(&mut clients));
+        let mut probe = default_entry(&mut clients);
+        probe.priority = true;
+        state.append(probe);
+
+        // The priority entry is batched first, even below min_size
+        let (entries, batch, _) = state.next_batch(Some(3), 1, None, None).unwrap();
+        assert_eq!(batch.size, 1);
+        assert!(entries.get(&1).unwrap().priority);
+
+        assert!(state.next_batch(Some(3), 1, None, None).is_none());
+        assert_eq!(state.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_sub_queues() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        for (request_id, priority) in [(0, false), (1, true), (2, false), (3, true)] {
+            let mut entry = default_entry_with_handle(request_id, &mut clients);
+            entry.priority = priority;
+            state.append(entry);
+        }
+
+        // The priority sub-queue comes first, each sub-queue keeping its order
+        let order: Vec<u64> = state.entries.iter().map(|(_, e)| e.handle.id).collect();
+        assert_eq!(order, vec![1, 3, 0, 2]);
+        assert_eq!(state.position(0), Some(2));
+        assert_eq!(state.entries.front_key(), Some(QueueKey::Priority));
+
+        let (entries, _, _) = state.next_batch(None, 3, None, None).unwrap();
+        let mut batched: Vec<u64> = entries.values().map(|entry| entry.handle.id).collect();
+        batched.sort_unstable();
+        assert_eq!(batched, vec![0, 1, 3]);
+        assert_eq!(state.entries.len(), 1);
+        assert_eq!(state.entries.front_key(), Some(QueueKey::Normal));
+
+        // Requeued entries go back to the front of their own sub-queue
+        let mut entries: Vec<Entry> = entries.into_values().collect();
+        entries.sort_by_key(|entry| entry.handle.id);
+        state.requeue(entries);
+        let order: Vec<u64> = state.entries.iter().map(|(_, e)| e.handle.id).collect();
+        assert_eq!(order, vec![1, 3, 0, 2]);
+
+        assert!(state.remove(3).is_some());
+        assert!(state.remove(0).is_some());
+        assert_eq!(state.entries.len(), 2);
+        let order: Vec<u64> = state.entries.iter().map(|(_, e)| e.handle.id).collect();
+        assert_eq!(order, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_next_batch_max_tokens() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        for input_length in [600, 600, 600] {
+            let mut entry = default_entry(&mut clients);
+            entry.request.input_length = input_length;
+            state.append(entry);
+        }
+
+        let (entries, batch, _) = state.next_batch(None, 3, Some(1500), None).unwrap();
+        assert_eq!(batch.size, 2);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(state.entries.len(), 1);
+
+        // An entry longer than the budget is batched alone
+        let (_, batch, _) = state.next_batch(None, 3, Some(500), None).unwrap();
+        assert_eq!(batch.size, 1);
+        assert!(state.entries.is_empty());
+    }
+
+    #[test]
+    fn test_next_batch_token_budget() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        for allow_downgrade in [false, false, true] {
+            let mut entry = default_entry(&mut clients);
+            entry.request.input_length = 100;
+            entry.request.stopping_parameters.max_new_tokens = 400;
+            entry.allow_downgrade = allow_downgrade;
+            state.append(entry);
+        }
+        let budget = |tokens| {
+            Some(TokenBudget {
+                tokens,
+                min_new_tokens: 50,
+            })
+        };
+
+        let (_, batch, _) = state.next_batch(None, 3, None, budget(800)).unwrap();
+        assert_eq!(batch.size, 1);
+        // Entries that do not allow it are not downgraded
+        assert!(state.next_batch(None, 3, None, budget(300)).is_none());
+        let (_, batch, _) = state.next_batch(None, 3, None, budget(500)).unwrap();
+        assert_eq!(batch.size, 1);
+
+        // Never below the floor
+        assert!(state.next_batch(None, 3, None, budget(140)).is_none());
+        let (entries, batch, _) = state.next_batch(None, 3, None, budget(300)).unwrap();
+        assert_eq!(batch.size, 1);
+        let entry = entries.values().next().unwrap();
+        assert_eq!(entry.requested_max_new_tokens, Some(400));
+        assert_eq!(entry.request.stopping_parameters.max_new_tokens, 200);
+        assert_eq!(
+            batch.requests[0]
+                .stopping_parameters
+                .as_ref()
+                .unwrap()
+                .max_new_tokens,
+            200
+        );
+    }
+
+    #[test]
+    fn test_next_batch_closed_entries() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        let mut closed_entry = default_entry_with_handle(0, &mut clients);
+        let (response_tx, _) = mpsc::unbounded_channel();
+        closed_entry.response_tx = response_tx.into();
+        let handle = closed_entry.handle.clone();
+        state.append(closed_entry);
+        state.append(default_entry_with_handle(1, &mut clients));
+
+        let (entries, batch, _) = state.next_batch(None, 2, None, None).unwrap();
+        assert_eq!(batch.size, 1);
+        assert!(entries.contains_key(&1));
+        assert_eq!(handle.status(), RequestStatus::Cancelled);
+        assert!(state.entries.is_empty());
+    }
+
+    #[test]
+    fn test_permit_release() {
+        let mut clients = Clients::new();
+        let semaphore = Arc::new(Semaphore::new(2));
+        let mut state = State::new("stable");
+        for request_id in 0..2 {
+            let mut entry = default_entry_with_handle(request_id, &mut clients);
+            entry.permit = Permit::new(semaphore.clone().try_acquire_owned().unwrap());
+            state.append(entry);
+        }
+        assert_eq!(semaphore.available_permits(), 0);
+
+        let (entries, _, _) = state.next_batch(None, 1, None, None).unwrap();
+        let entry = entries.get(&0).unwrap();
+        assert_eq!(entry.permit.state(), PermitState::Running);
+        let (_, entry) = state.entries.iter().next().unwrap();
+        assert_eq!(entry.permit.state(), PermitState::Queued);
+
+        drop(entries);
+        assert_eq!(semaphore.available_permits(), 1);
+        drop(state);
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn test_queued_probes() {
+        let mut clients = Clients::new();
+        let queued_probes = Arc::new(AtomicUsize::new(0));
+        let mut probe = |request_id| {
+            let mut entry = default_entry_with_handle(request_id, &mut clients);
+            entry.priority = true;
+            entry.probe = Some(QueuedProbe::new(queued_probes.clone()));
+            entry
+        };
+        let count = || queued_probes.load(Ordering::SeqCst);
+        let mut state = State::new("stable");
+
+        // Batched then requeued
+        state.append(probe(0));
+        assert_eq!(count(), 1);
+        let (entries, _, _) = state.next_probes().unwrap();
+        assert_eq!(count(), 0);
+        state.requeue(entries.into_values().collect());
+        assert_eq!(count(), 1);
+
+        // Removed by a cancellation
+        drop(state.remove(0));
+        assert_eq!(count(), 0);
+
+        // Dropped as the client is gone
+        let mut closed_probe = probe(1);
+        let (response_tx, _) = mpsc::unbounded_channel();
+        closed_probe.response_tx = response_tx.into();
+        state.append(closed_probe);
+        // Failed as it is late
+        let mut late_probe = probe(2);
+        late_probe.deadline = Some(Instant::now());
+        state.append(late_probe);
+        assert_eq!(count(), 2);
+        assert!(state.next_probes().is_none());
+        assert_eq!(count(), 0);
+    }
+
+    /// Under continuous load, every queued entry is eventually batched and the probes are
+    /// batched first
+    #[test]
+    fn test_next_batch_no_starvation() {
+        let mut clients = Clients::new();
+        const PROBE_PERMITS: usize = 2;
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut state = State::new("stable");
+            let mut next_request_id = 0;
+            // Arrival step of the queued entries
+            let mut arrivals = IntMap::default();
+            let mut max_wait = 0;
+            for step in 0..200 {
+                let queued_probes = state.entries.iter().filter(|(_, e)| e.priority).count();
+                for _ in 0..rng.gen_range(0..=3) {
+                    let mut entry = default_entry_with_handle(next_request_id, &mut clients);
+                    entry.priority = queued_probes < PROBE_PERMITS && rng.gen_bool(0.3);
+                    arrivals.insert(next_request_id, step);
+                    next_request_id += 1;
+                    state.append(entry);
+                }
+
+                if let Some((entries, _, _)) = state.next_batch(None, 2, None, None) {
+                    let batched_probes = entries.values().filter(|e| e.priority).count();
+                    let queued_probes = state.entries.iter().filter(|(_, e)| e.priority).count();
+                    assert!(batched_probes == entries.len() || queued_probes == 0);
+                    for entry in entries.values() {
+                        let arrival = arrivals.remove(&entry.handle.id).unwrap();
+                        max_wait = max_wait.max(step - arrival);
+                    }
+                }
+            }
+            // The queue drains once the load stops
+            while let Some((entries, _, _)) = state.next_batch(None, 2, None, None) {
+                entries.values().for_each(|entry| {
+                    arrivals.remove(&entry.handle.id);
+                });
+            }
+            assert!(arrivals.is_empty());
+            assert!(max_wait <= 50, "waited {max_wait} steps");
+        }
+    }
+
+    /// A continuous stream of short requests does not starve a long one
+    #[test]
+    fn test_next_batch_long_prompt_not_starved() {
+        let mut clients = Clients::new();
+        const MAX_BATCH_TOTAL_TOKENS: u32 = 2048;
+        const MAX_SHORT_NEW_TOKENS: u32 = 20;
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut state = State::new("stable");
+            // Tokens and decode steps left of the running entries
+            let mut running: Vec<(u32, u32)> = vec![];
+            let long_arrival = rng.gen_range(10..50);
+            let mut long_batched = None;
+            for step in 0..200 {
+                for _ in 0..rng.gen_range(0..=3) {
+                    let mut entry = default_entry_with_handle(0, &mut clients);
+                    entry.request.input_length = rng.gen_range(10..=100);
+                    entry.request.stopping_parameters.max_new_tokens =
+                        rng.gen_range(1..=MAX_SHORT_NEW_TOKENS);
+                    state.append(entry);
+                }
+                if step == long_arrival {
+                    let mut entry = default_entry_with_handle(1, &mut clients);
+                    entry.request.input_length = 1800;
+                    entry.request.stopping_parameters.max_new_tokens = 200;
+                    state.append(entry);
+                }
+
+                let used: u32 = running.iter().map(|(tokens, _)| tokens).sum();
+                let budget = TokenBudget {
+                    tokens: MAX_BATCH_TOTAL_TOKENS - used,
+                    min_new_tokens: 1,
+                };
+                if let Some((entries, _, _)) = state.next_batch(None, 32, None, Some(budget)) {
+                    for entry in entries.values() {
+                        if entry.handle.id == 1 {
+                            long_batched = Some(step);
+                        }
+                        let max_new_tokens = entry.request.stopping_parameters.max_new_tokens;
+                        running.push((entry.request.input_length + max_new_tokens, max_new_tokens));
+                    }
+                }
+                // One decode step
+                running.iter_mut().for_each(|(_, steps)| *steps -= 1);
+                running.retain(|(_, steps)| *steps > 0);
+            }
+
+            // The entries ahead of it are batched once the running entries finish, then it waits
+            // for them to finish
+            let waited = long_batched.expect("the long request was never batched") - long_arrival;
+            assert!(waited <= 2 * MAX_SHORT_NEW_TOKENS, "waited {waited} steps");
+        }
+    }
+
+    /// A full running batch only takes the probes, not the older normal entries
+    #[test]
+    fn test_next_probes() {
+        let mut clients = Clients::new();
+        let mut state = State::new("stable");
+        state.append(default_entry_with_handle(0, &mut clients));
+        state.append(default_entry_with_handle(1, &mut clients));
+
+        assert!(state.next_probes().is_none());
+
+        let mut probe = default_entry_with_handle(2, &mut clients);
+        probe.priority = true;
+        state.append(probe);
+        let (entries, batch, _) = state.next_probes().unwrap();
+        assert_eq!(batch.size, 1);
+        assert!(entries.values().all(|entry| entry.handle.id == 2));
+        assert!(state.next_probes().is_none());
+
+        // The normal entries keep their order
+        let order: Vec<u64> = state.entries.iter().map(|(_, e)| e.handle.id).collect();
+        assert_eq!(order, vec![0, 1]);
+    }
 }
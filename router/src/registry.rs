@@ -0,0 +1,439 @@
+/// Registry of live requests used for status polling and cancellation
+use crate::validation::ValidationTimings;
+use crate::RequestStatus;
+use nohash_hasher::IntMap;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How long finished requests can still be queried and continued
+const RETENTION: Duration = Duration::from_secs(60);
+/// Minimum delay between two sweeps of the finished requests of a shard
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// Shards of the registry, so that concurrent requests rarely wait for the same lock
+const SHARDS: usize = 16;
+
+/// Request Registry
+///
+/// The requests are spread over `SHARDS` shards by id, each behind its own lock
+#[derive(Debug, Clone)]
+pub(crate) struct Registry {
+    /// Registry state, by shard
+    shards: Arc<[Mutex<RegistryState>]>,
+    /// Id of the next request
+    next_id: Arc<AtomicU64>,
+    /// Log the state transitions of the requests at info level
+    trace_requests: bool,
+}
+
+#[derive(Debug)]
+struct RegistryState {
+    /// Live and recently finished requests
+    requests: IntMap<u64, Arc<RequestHandle>>,
+    /// Instant of the last sweep
+    last_sweep: Instant,
+}
+
+impl Registry {
+    pub(crate) fn new(trace_requests: bool) -> Self {
+        Self {
+            shards: (0..SHARDS)
+                .map(|_| {
+                    Mutex::new(RegistryState {
+                        requests: IntMap::default(),
+                        last_sweep: Instant::now(),
+                    })
+                })
+                .collect(),
+            next_id: Arc::new(AtomicU64::new(0)),
+            trace_requests,
+        }
+    }
+
+    /// Register a new request and return its handle
+    pub(crate) fn register(&self) -> Arc<RequestHandle> {
+        self.register_id(self.next_id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    /// Start the ids of the new requests at `next_id` or after, to not reuse restored ids
+    pub(crate) fn reserve(&self, next_id: u64) {
+        self.next_id.fetch_max(next_id, Ordering::SeqCst);
+    }
+
+    /// Register a request under an id reserved with `reserve`
+    pub(crate) fn register_id(&self, id: u64) -> Arc<RequestHandle> {
+        let handle = Arc::new(RequestHandle::new(id, self.trace_requests));
+
+        let mut state = self.shard(id).lock();
+        // Remove finished requests that are past their retention window
+        if state.last_sweep.elapsed() >= SWEEP_INTERVAL {
+            state
+                .requests
+                .retain(|_, handle| !handle.expired(RETENTION));
+            state.last_sweep = Instant::now();
+        }
+        state.requests.insert(id, handle.clone());
+
+        handle
+    }
+
+    /// Get the handle of a live or recently finished request
+    pub(crate) fn get(&self, id: u64) -> Option<Arc<RequestHandle>> {
+        self.shard(id).lock().requests.get(&id).cloned()
+    }
+
+    /// Number of queued and running requests
+    pub(crate) fn counts(&self) -> (usize, usize) {
+        self.shards.iter().fold((0, 0), |counts, shard| {
+            shard
+                .lock()
+                .requests
+                .values()
+                .fold(counts, |(queued, running), handle| match handle.status() {
+                    RequestStatus::Queued => (queued + 1, running),
+                    RequestStatus::Running => (queued, running + 1),
+                    _ => (queued, running),
+                })
+        })
+    }
+
+    fn shard(&self, id: u64) -> &Mutex<RegistryState> {
+        &self.shards[id as usize % SHARDS]
+    }
+}
+
+/// Text of a completed generation, kept to continue it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Continuation {
+    /// Inputs of the first request of the continued generations
+    pub prompt: String,
+    /// Text generated by the request and the requests it continues
+    pub generated_text: String,
+    /// Session of the request, so that the backend can reuse its state
+    pub session_id: Option<String>,
+    /// Model of the request, None for the model of the router
+    pub model: Option<String>,
+}
+
+/// Shared view of a request lifecycle
+///
+/// Written by `Infer` and the batching task, read by the status endpoint
+#[derive(Debug)]
+pub(crate) struct RequestHandle {
+    /// Request id
+    pub id: u64,
+    /// Log the state transitions of this request at info level
+    trace: bool,
+    /// The trace of the request is sampled: its token-level spans and events are recorded
+    sampled: AtomicBool,
+    /// Number of tokens sent to the client so far
+    generated_tokens: AtomicU32,
+    /// Number of tokens of the validated inputs, 0 until the request is validated
+    input_length: AtomicU32,
+    /// Number of times the request was put back in the queue after a batch failure
+    requeues: AtomicU32,
+    /// Set when a client asked to cancel this request
+    cancel_requested: AtomicBool,
+    /// Number of identical requests served by the generation of this request, itself included
+    coalesced: AtomicU32,
+    /// Status
+    state: Mutex<HandleState>,
+}
+
+#[derive(Debug)]
+struct HandleState {
+    status: RequestStatus,
+    error: Option<String>,
+    /// Time spent in the pre-generation hook
+    hook_time: Option<Duration>,
+    /// Time spent in the last validation of the request
+    validation_timings: Option<ValidationTimings>,
+    /// Generation continued by this request
+    continued: Option<Continuation>,
+    /// Set once the request is completed
+    continuation: Option<Continuation>,
+    /// Served model of the request, None for the model of the router
+    model: Option<String>,
+    /// API key id of the client of the request, None without API key
+    owner: Option<String>,
+    /// Instant when the request reached a terminal status
+    finished: Option<Instant>,
+}
+
+impl RequestHandle {
+    pub(crate) fn new(id: u64, trace: bool) -> Self {
+        Self {
+            id,
+            trace,
+            sampled: AtomicBool::new(true),
+            generated_tokens: AtomicU32::new(0),
+            input_length: AtomicU32::new(0),
+            requeues: AtomicU32::new(0),
+            cancel_requested: AtomicBool::new(false),
+            coalesced: AtomicU32::new(1),
+            state: Mutex::new(HandleState {
+                status: RequestStatus::Queued,
+                error: None,
+                hook_time: None,
+                validation_timings: None,
+                continued: None,
+                continuation: None,
+                model: None,
+                owner: None,
+                finished: None,
+            }),
+        }
+    }
+
+    pub(crate) fn status(&self) -> RequestStatus {
+        self.state.lock().status
+    }
+
+    pub(crate) fn error(&self) -> Option<String> {
+        self.state.lock().error.clone()
+    }
+
+    /// The state transitions are only traced if the trace of the request is sampled
+    pub(crate) fn trace(&self) -> bool {
+        self.trace && self.sampled()
+    }
+
+    pub(crate) fn sampled(&self) -> bool {
+        self.sampled.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_sampled(&self, sampled: bool) {
+        self.sampled.store(sampled, Ordering::Relaxed);
+    }
+
+    pub(crate) fn generated_tokens(&self) -> u32 {
+        self.generated_tokens.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn input_length(&self) -> u32 {
+        self.input_length.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_input_length(&self, input_length: u32) {
+        self.input_length.store(input_length, Ordering::Relaxed);
+    }
+
+    pub(crate) fn requeues(&self) -> u32 {
+        self.requeues.load(Ordering::Relaxed)
+    }
+
+    /// Mark the request as put back in the queue
+    pub(crate) fn set_requeued(&self) {
+        self.requeues.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.lock();
+        if state.status == RequestStatus::Running {
+            state.status = RequestStatus::Queued;
+        }
+    }
+
+    /// Mark the request as added to a batch
+    pub(crate) fn set_running(&self) {
+        let mut state = self.state.lock();
+        if state.status == RequestStatus::Queued {
+            state.status = RequestStatus::Running;
+        }
+    }
+
+    pub(crate) fn hook_time(&self) -> Option<Duration> {
+        self.state.lock().hook_time
+    }
+
+    pub(crate) fn set_hook_time(&self, hook_time: Duration) {
+        self.state.lock().hook_time = Some(hook_time);
+    }
+
+    pub(crate) fn validation_timings(&self) -> Option<ValidationTimings> {
+        self.state.lock().validation_timings
+    }
+
+    pub(crate) fn set_validation_timings(&self, timings: ValidationTimings) {
+        self.state.lock().validation_timings = Some(timings);
+    }
+
+    /// Mark the request as the continuation of a previous generation
+    pub(crate) fn set_continued(&self, continued: Continuation) {
+        self.state.lock().continued = Some(continued);
+    }
+
+    pub(crate) fn set_model(&self, model: String) {
+        self.state.lock().model = Some(model);
+    }
+
+    pub(crate) fn owner(&self) -> Option<String> {
+        self.state.lock().owner.clone()
+    }
+
+    pub(crate) fn set_owner(&self, owner: Option<String>) {
+        self.state.lock().owner = owner;
+    }
+
+    /// Keep the text of the completed request so that it can be continued
+    pub(crate) fn set_completed(
+        &self,
+        inputs: &str,
+        generated_text: &str,
+        session_id: Option<String>,
+    ) {
+        let mut state = self.state.lock();
+        let model = state.model.clone();
+        let continuation = match state.continued.take() {
+            Some(continued) => Continuation {
+                prompt: continued.prompt,
+                generated_text: continued.generated_text + generated_text,
+                session_id,
+                model,
+            },
+            None => Continuation {
+                prompt: inputs.to_string(),
+                generated_text: generated_text.to_string(),
+                session_id,
+                model,
+            },
+        };
+        state.continuation = Some(continuation);
+    }
+
+    pub(crate) fn continuation(&self) -> Option<Continuation> {
+        self.state.lock().continuation.clone()
+    }
+
+    /// Count one more token sent to the client
+    pub(crate) fn add_token(&self) {
+        self.generated_tokens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Move the request to a terminal status
+    ///
+    /// Returns false if the request was already finished
+    pub(crate) fn finish(&self, status: RequestStatus, error: Option<String>) -> bool {
+        let mut state = self.state.lock();
+        if state.finished.is_some() {
+            return false;
+        }
+        state.status = status;
+        state.error = error;
+        state.finished = Some(Instant::now());
+        true
+    }
+
+    pub(crate) fn request_cancel(&self) {
+        self.cancel_requested.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn cancel_requested(&self) -> bool {
+        self.cancel_requested.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn coalesced(&self) -> u32 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_coalesced(&self, coalesced: u32) {
+        self.coalesced.store(coalesced, Ordering::Relaxed);
+    }
+
+    fn expired(&self, retention: Duration) -> bool {
+        match self.state.lock().finished {
+            None => false,
+            Some(finished) => finished.elapsed() >= retention,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let registry = Registry::new(false);
+        let first = registry.register();
+        let second = registry.register();
+
+        assert_eq!(first.id, 0);
+        assert_eq!(second.id, 1);
+        assert_eq!(registry.get(1).unwrap().id, 1);
+        assert!(registry.get(2).is_none());
+
+        // Reserved ids are not handed out
+        registry.reserve(5);
+        registry.reserve(3);
+        assert_eq!(registry.register_id(4).id, 4);
+        assert_eq!(registry.register().id, 5);
+
+        // The requests of the same shard are kept apart
+        registry.reserve(2 * SHARDS as u64);
+        registry.register_id(SHARDS as u64);
+        assert_eq!(registry.get(0).unwrap().id, 0);
+        assert_eq!(registry.get(SHARDS as u64).unwrap().id, SHARDS as u64);
+    }
+
+    #[test]
+    fn test_sampled() {
+        let handle = RequestHandle::new(0, true);
+        assert!(handle.sampled());
+        assert!(handle.trace());
+        // The transitions of the requests whose trace is not sampled are not traced
+        handle.set_sampled(false);
+        assert!(!handle.trace());
+        assert!(!RequestHandle::new(1, false).trace());
+    }
+
+    #[test]
+    fn test_counts() {
+        let registry = Registry::new(false);
+        assert_eq!(registry.counts(), (0, 0));
+
+        let first = registry.register();
+        let second = registry.register();
+        registry.register();
+        first.set_running();
+        second.finish(RequestStatus::Completed, None);
+        assert_eq!(registry.counts(), (1, 1));
+    }
+
+    #[test]
+    fn test_handle_lifecycle() {
+        let handle = RequestHandle::new(0, false);
+        assert_eq!(handle.status(), RequestStatus::Queued);
+
+        handle.set_running();
+        handle.add_token();
+        assert_eq!(handle.status(), RequestStatus::Running);
+        assert_eq!(handle.generated_tokens(), 1);
+
+        assert!(handle.finish(RequestStatus::Cancelled, None));
+        assert!(!handle.finish(RequestStatus::Completed, None));
+        assert_eq!(handle.status(), RequestStatus::Cancelled);
+        assert!(handle.expired(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn test_continuation() {
+        let first = RequestHandle::new(0, false);
+        assert_eq!(first.continuation(), None);
+        first.set_completed("Hello", " world", None);
+        let continuation = first.continuation().unwrap();
+
+        // The continuation inputs are the prompt and the generated text
+        let second = RequestHandle::new(1, false);
+        second.set_continued(continuation);
+        second.set_completed("Hello world", ", how", Some("chat".to_string()));
+        assert_eq!(
+            second.continuation(),
+            Some(Continuation {
+                prompt: "Hello".to_string(),
+                generated_text: " world, how".to_string(),
+                session_id: Some("chat".to_string()),
+                model: None,
+            })
+        );
+    }
+}
@@ -0,0 +1,483 @@
+/// Capture of the requests failed by a backend error, to replay them once the backend is fixed
+use crate::infer::InferError;
+use crate::validation::ValidGenerateRequest;
+use crate::{default_parameters, GenerateParameters, GenerateRequest, StopConfig};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// Maximum number of captures waiting to be written
+const CHANNEL_CAPACITY: usize = 256;
+/// Interval between two removals of the expired capture files
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+const FILE_PREFIX: &str = "captures-";
+const FILE_EXTENSION: &str = ".jsonl";
+
+/// Capture ring configuration
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    /// Directory of the JSONL capture files
+    pub dir: PathBuf,
+    /// The oldest file is removed when a new one would exceed this number
+    pub max_files: usize,
+    /// A new file is started when the current one would exceed this size
+    pub max_file_bytes: u64,
+    /// Captures are not replayed and their files are removed after this delay
+    pub ttl: Duration,
+}
+
+/// Request failed by a backend error
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Capture {
+    pub id: String,
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub backend: String,
+    pub error: String,
+    /// Validated inputs, None if the payloads are redacted
+    pub inputs: Option<String>,
+    pub parameters: CapturedParameters,
+}
+
+/// Parameters sent to the backend, the random seed included
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CapturedParameters {
+    pub temperature: f32,
+    pub top_k: u32,
+    pub top_p: f32,
+    pub typical_p: f32,
+    pub repetition_penalty: f32,
+    pub do_sample: bool,
+    pub max_new_tokens: u32,
+    /// Empty if the payloads are redacted
+    pub stop_sequences: Vec<StopConfig>,
+    pub seed: u64,
+    pub watermark: bool,
+    /// Empty if all the tokens were allowed
+    #[serde(default)]
+    pub allowed_tokens: Vec<u32>,
+}
+
+impl Capture {
+    fn new(
+        id: String,
+        backend: &str,
+        request: &ValidGenerateRequest,
+        err: &InferError,
+        redact: bool,
+    ) -> Self {
+        let parameters = &request.parameters;
+        Self {
+            id,
+            timestamp: now_ms(),
+            backend: backend.to_string(),
+            error: err.to_string(),
+            inputs: (!redact).then(|| request.inputs.clone()),
+            parameters: CapturedParameters {
+                temperature: parameters.temperature,
+                top_k: parameters.top_k,
+                top_p: parameters.top_p,
+                typical_p: parameters.typical_p,
+                repetition_penalty: parameters.repetition_penalty,
+                do_sample: parameters.do_sample,
+                max_new_tokens: request.stopping_parameters.max_new_tokens,
+                stop_sequences: match redact {
+                    true => vec![],
+                    false => request.stop_sequences.clone(),
+                },
+                seed: parameters.seed,
+                watermark: parameters.watermark,
+                allowed_tokens: parameters.allowed_tokens.clone(),
+            },
+        }
+    }
+
+    /// Request validated into the captured request, None if its payload is redacted
+    /// The validation defaults are mapped back to unset parameters
+    pub(crate) fn request(&self) -> Option<GenerateRequest> {
+        let parameters = &self.parameters;
+        let inputs = self.inputs.clone()?;
+        Some(GenerateRequest {
+            inputs,
+            parameters: GenerateParameters {
+                temperature: Some(parameters.temperature),
+                repetition_penalty: Some(parameters.repetition_penalty),
+                top_k: (parameters.top_k > 0).then_some(parameters.top_k as i32),
+                top_p: (parameters.top_p < 1.0).then_some(parameters.top_p),
+                typical_p: (parameters.typical_p < 1.0).then_some(parameters.typical_p),
+                do_sample: Some(parameters.do_sample),
+                max_new_tokens: parameters.max_new_tokens,
+                stop_config: parameters.stop_sequences.clone(),
+                seed: Some(parameters.seed),
+                watermark: Some(parameters.watermark),
+                allowed_tokens: (!parameters.allowed_tokens.is_empty())
+                    .then(|| parameters.allowed_tokens.clone()),
+                details: true,
+                // The response of the failed request was never cached
+                no_cache: true,
+                ..default_parameters()
+            },
+            template: None,
+            template_vars: None,
+            preset: None,
+            model: None,
+        })
+    }
+}
+
+/// Sends the captures to a background writer task
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ReplayLog {
+    /// None if the capture is disabled
+    state: Option<Arc<ReplayState>>,
+}
+
+#[derive(Debug)]
+struct ReplayState {
+    config: ReplayConfig,
+    /// Do not capture the inputs and stop sequences
+    redact: bool,
+    sender: mpsc::Sender<Capture>,
+}
+
+impl ReplayLog {
+    pub(crate) fn new(config: Option<ReplayConfig>, redact: bool) -> std::io::Result<Self> {
+        let config = match config {
+            None => return Ok(Self::default()),
+            Some(config) => config,
+        };
+        let ring = Ring::open(config.clone())?;
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        tokio::spawn(writer_task(ring, receiver));
+        Ok(Self {
+            state: Some(Arc::new(ReplayState {
+                config,
+                redact,
+                sender,
+            })),
+        })
+    }
+
+    /// Capture a request failed by a generation error
+    pub(crate) fn capture(
+        &self,
+        request_id: u64,
+        backend: &str,
+        request: &ValidGenerateRequest,
+        err: &InferError,
+    ) {
+        let state = match &self.state {
+            Some(state) if matches!(err, InferError::GenerationError(..)) => state,
+            _ => return,
+        };
+        let id = format!("{}-{request_id}", now_ms());
+        let capture = Capture::new(id, backend, request, err, state.redact);
+        tracing::warn!("Captured failed request {request_id} as {}", capture.id);
+
+        // Never wait on the writer
+        match state.sender.try_send(capture) {
+            Ok(()) => metrics::increment_counter!("tgi_replay_capture"),
+            Err(TrySendError::Full(_)) => {
+                metrics::increment_counter!("tgi_replay_capture_dropped", "reason" => "full")
+            }
+            Err(TrySendError::Closed(_)) => {
+                metrics::increment_counter!("tgi_replay_capture_dropped", "reason" => "closed")
+            }
+        }
+    }
+
+    /// Find a capture that did not expire
+    pub(crate) async fn get(&self, id: String) -> Option<Capture> {
+        let state = self.state.clone()?;
+        tokio::task::spawn_blocking(move || find(&state.config, &id))
+            .await
+            .ok()
+            .flatten()
+    }
+}
+
+async fn writer_task(mut ring: Ring, mut receiver: mpsc::Receiver<Capture>) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        tokio::select! {
+            capture = receiver.recv() => match capture {
+                Some(capture) => ring.write(&capture),
+                // All the logs are dropped
+                None => break,
+            },
+            _ = interval.tick() => ring.remove_old(),
+        }
+    }
+}
+
+/// Capture files bounded in number and size
+#[derive(Debug)]
+struct Ring {
+    config: ReplayConfig,
+    /// Index of the current file
+    index: u64,
+    /// Current file and its size
+    file: Option<(File, u64)>,
+}
+
+impl Ring {
+    fn open(config: ReplayConfig) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        // Never append to the files of a previous run
+        let index = capture_files(&config.dir)
+            .last()
+            .map_or(0, |(index, _)| index + 1);
+        Ok(Self {
+            config,
+            index,
+            file: None,
+        })
+    }
+
+    fn write(&mut self, capture: &Capture) {
+        if let Err(err) = self.try_write(capture) {
+            tracing::error!("Could not write capture {}: {err}", capture.id);
+            metrics::increment_counter!("tgi_replay_capture_dropped", "reason" => "write");
+        }
+    }
+
+    fn try_write(&mut self, capture: &Capture) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(capture)?;
+        line.push(b'\n');
+
+        let full = match &self.file {
+            None => true,
+            Some((_, bytes)) => bytes + line.len() as u64 > self.config.max_file_bytes,
+        };
+        if full {
+            if self.file.take().is_some() {
+                self.index += 1;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file_path(&self.config.dir, self.index))?;
+            self.file = Some((file, 0));
+            self.remove_rotated();
+        }
+
+        let (file, bytes) = self
+            .file
+            .as_mut()
+            .expect("capture file is not open. This is a bug.");
+        file.write_all(&line)?;
+        *bytes += line.len() as u64;
+        Ok(())
+    }
+
+    /// Remove the files beyond `max_files`
+    fn remove_rotated(&self) {
+        for (index, path) in capture_files(&self.config.dir) {
+            if index + self.config.max_files as u64 <= self.index {
+                remove_file(&path);
+            }
+        }
+    }
+
+    /// Remove the files beyond `max_files` and the ones not written for `ttl`
+    fn remove_old(&mut self) {
+        self.remove_rotated();
+        for (index, path) in capture_files(&self.config.dir) {
+            if !expired(&path, self.config.ttl) {
+                continue;
+            }
+            // The next capture starts a new file
+            if index == self.index && self.file.take().is_some() {
+                self.index += 1;
+            }
+            remove_file(&path);
+        }
+    }
+}
+
+/// Find a capture in the files, newest first
+fn find(config: &ReplayConfig, id: &str) -> Option<Capture> {
+    let ttl_ms = config.ttl.as_millis() as u64;
+    capture_files(&config.dir)
+        .into_iter()
+        .rev()
+        .filter_map(|(_, path)| File::open(path).ok())
+        .flat_map(|file| BufReader::new(file).lines().map_while(Result::ok))
+        // Lines being written are skipped
+        .filter_map(|line| serde_json::from_str::<Capture>(&line).ok())
+        .find(|capture| capture.id == id)
+        .filter(|capture| now_ms().saturating_sub(capture.timestamp) < ttl_ms)
+}
+
+/// Capture files of `dir` sorted by index
+fn capture_files(dir: &Path) -> Vec<(u64, PathBuf)> {
+    let mut files: Vec<(u64, PathBuf)> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let index = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix(FILE_PREFIX)?
+                .strip_suffix(FILE_EXTENSION)?
+                .parse()
+                .ok()?;
+            Some((index, path))
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+fn remove_file(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        tracing::error!("Could not remove capture file {}: {err}", path.display());
+    }
+}
+
+fn file_path(dir: &Path, index: u64) -> PathBuf {
+    dir.join(format!("{FILE_PREFIX}{index}{FILE_EXTENSION}"))
+}
+
+/// The file was not written for `ttl`
+fn expired(path: &Path, ttl: Duration) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map_or(false, |age| age >= ttl)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |timestamp| timestamp.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::GenerationErrorCode;
+    use crate::validation::ValidationTimings;
+    use crate::InputSource;
+    use text_generation_client::{NextTokenChooserParameters, StoppingCriteriaParameters};
+
+    fn config(name: &str, max_files: usize, max_file_bytes: u64) -> ReplayConfig {
+        let dir = std::env::temp_dir().join(format!("tgi-replay-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).unwrap_or(());
+        ReplayConfig {
+            dir,
+            max_files,
+            max_file_bytes,
+            ttl: Duration::from_secs(60),
+        }
+    }
+
+    fn request() -> ValidGenerateRequest {
+        ValidGenerateRequest {
+            inputs: "Hello".to_string(),
+            input_length: 1,
+            parameters: NextTokenChooserParameters {
+                temperature: 0.5,
+                top_k: 0,
+                top_p: 0.9,
+                typical_p: 1.0,
+                do_sample: true,
+                seed: 42,
+                repetition_penalty: 1.0,
+                watermark: false,
+                allowed_tokens: vec![],
+            },
+            stopping_parameters: StoppingCriteriaParameters {
+                ignore_eos_token: false,
+                max_new_tokens: 10,
+                stop_sequences: vec!["\n".to_string()],
+            },
+            stop_sequences: vec![StopConfig {
+                sequence: "\n".to_string(),
+                keep_text: true,
+            }],
+            prefill_tokens: false,
+            tokenization: None,
+            clean_up_tokenization_spaces: false,
+            raw_token_text: false,
+            normalized_chars: None,
+            input_source: InputSource::Text,
+            timings: ValidationTimings::default(),
+        }
+    }
+
+    fn capture(id: &str, redact: bool) -> Capture {
+        let err =
+            InferError::GenerationError(GenerationErrorCode::Backend, "CUDA error".to_string());
+        Capture::new(id.to_string(), "stable", &request(), &err, redact)
+    }
+
+    #[test]
+    fn test_capture_request() {
+        let request = capture("0-0", false).request().unwrap();
+        assert_eq!(request.inputs, "Hello");
+        assert_eq!(request.parameters.temperature, Some(0.5));
+        assert_eq!(request.parameters.top_k, None);
+        assert_eq!(request.parameters.top_p, Some(0.9));
+        assert_eq!(request.parameters.typical_p, None);
+        assert_eq!(request.parameters.seed, Some(42));
+        assert_eq!(request.parameters.max_new_tokens, 10);
+        assert_eq!(request.parameters.stop_config, request().stop_sequences);
+        assert_eq!(request.parameters.allowed_tokens, None);
+
+        let redacted = capture("0-0", true);
+        assert!(redacted.parameters.stop_sequences.is_empty());
+        assert!(redacted.request().is_none());
+    }
+
+    #[test]
+    fn test_ring() {
+        let line_bytes = serde_json::to_vec(&capture("0-0", false)).unwrap().len() as u64 + 1;
+        // Two captures per file
+        let config = config("ring", 2, 2 * line_bytes);
+        let mut ring = Ring::open(config.clone()).unwrap();
+        for i in 0..5 {
+            ring.write(&capture(&format!("0-{i}"), false));
+        }
+
+        // The first file was removed
+        let indices: Vec<u64> = capture_files(&config.dir)
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(indices, vec![1, 2]);
+        assert!(find(&config, "0-1").is_none());
+        assert_eq!(find(&config, "0-2").unwrap().id, "0-2");
+        assert_eq!(
+            find(&config, "0-4").unwrap().inputs.as_deref(),
+            Some("Hello")
+        );
+
+        // A new run starts a new file
+        let ring = Ring::open(config.clone()).unwrap();
+        assert_eq!(ring.index, 3);
+        std::fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_expired_captures() {
+        let config = ReplayConfig {
+            ttl: Duration::ZERO,
+            ..config("expired", 2, 1024)
+        };
+        let mut ring = Ring::open(config.clone()).unwrap();
+        ring.write(&capture("0-0", false));
+        assert!(find(&config, "0-0").is_none());
+
+        ring.remove_old();
+        assert!(capture_files(&config.dir).is_empty());
+        std::fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
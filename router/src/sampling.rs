@@ -0,0 +1,226 @@
+/// Sampling of the traces of the requests, following the W3C trace context of the client
+use axum::extract::State;
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use opentelemetry::trace::TraceContextExt;
+use rand::Rng;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+const TRACEPARENT_HEADER: &str = "traceparent";
+const TRACESTATE_HEADER: &str = "tracestate";
+
+/// Trace context of the client, from the `traceparent` and `tracestate` headers
+/// `traceparent: 00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TraceParent {
+    pub trace_id: String,
+    pub parent_id: String,
+    /// The client records the trace
+    pub sampled: bool,
+    pub tracestate: Option<String>,
+}
+
+impl TraceParent {
+    /// None if `traceparent` is invalid, in which case the trace starts at the router
+    fn parse(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next().filter(|version| is_hex(version, 2))?;
+        let trace_id = parts.next().filter(|id| is_hex(id, 32) && !is_zero(id))?;
+        let parent_id = parts.next().filter(|id| is_hex(id, 16) && !is_zero(id))?;
+        let flags = parts.next().filter(|flags| is_hex(flags, 2))?;
+        // Version 00 has exactly four fields, the later versions may add fields
+        if version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(Self {
+            trace_id: trace_id.to_string(),
+            parent_id: parent_id.to_string(),
+            sampled: flags & 1 == 1,
+            tracestate: tracestate
+                .map(str::trim)
+                .filter(|tracestate| !tracestate.is_empty())
+                .map(String::from),
+        })
+    }
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len
+        && value
+            .bytes()
+            .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c))
+}
+
+fn is_zero(value: &str) -> bool {
+    value.bytes().all(|c| c == b'0')
+}
+
+/// Sampler of the traces started by the router, for the requests without a `traceparent`
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TraceSampler {
+    /// Ratio of the traces recorded
+    ratio: f64,
+}
+
+impl TraceSampler {
+    pub(crate) fn new(ratio: f64) -> Self {
+        Self {
+            ratio: ratio.clamp(0.0, 1.0),
+        }
+    }
+
+    fn sample(&self) -> bool {
+        rand::thread_rng().gen_bool(self.ratio)
+    }
+}
+
+/// Trace context of a request, added to its extensions
+#[derive(Clone, Debug)]
+pub(crate) struct TraceContext {
+    /// None if the request has no valid `traceparent`
+    pub parent: Option<TraceParent>,
+    /// The full spans and the token-level events of the request are recorded
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// The sampling decision of the OpenTelemetry span of the request, which follows the
+    /// `traceparent` of the client and else the sampler of the router
+    /// Without an OpenTelemetry exporter, `sampler` decides for the requests without a
+    /// `traceparent`
+    fn new<B>(request: &Request<B>, span: &Span, sampler: TraceSampler) -> Self {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let parent = header(TRACEPARENT_HEADER)
+            .and_then(|traceparent| TraceParent::parse(traceparent, header(TRACESTATE_HEADER)));
+        let span_context = span.context().span().span_context().clone();
+        let sampled = match (&parent, span_context.is_valid()) {
+            (_, true) => span_context.is_sampled(),
+            (Some(parent), false) => parent.sampled,
+            (None, false) => sampler.sample(),
+        };
+        Self { parent, sampled }
+    }
+}
+
+/// Middleware adding a `TraceContext` to the request extensions
+///
+/// The OpenTelemetry layer makes the span of the request a child of the `traceparent` of the
+/// client, the span of its queue entry being the parent of the spans of the batching task. The
+/// requests whose trace is not sampled only record their top-level spans
+pub(crate) async fn trace_context_middleware<B>(
+    State(sampler): State<TraceSampler>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let context = TraceContext::new(&request, &Span::current(), sampler);
+    if let Some(parent) = &context.parent {
+        tracing::debug!(
+            trace_id = parent.trace_id.as_str(),
+            parent_id = parent.parent_id.as_str(),
+            tracestate = parent.tracestate.as_deref(),
+            sampled = context.sampled,
+            "Trace context of the client"
+        );
+    }
+    if !context.sampled {
+        metrics::increment_counter!("tgi_request_trace_unsampled");
+    }
+    request.extensions_mut().insert(context);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    fn request(traceparent: Option<&str>, tracestate: Option<&str>) -> Request<()> {
+        let mut request = Request::builder().uri("/generate");
+        if let Some(traceparent) = traceparent {
+            request = request.header(TRACEPARENT_HEADER, traceparent);
+        }
+        if let Some(tracestate) = tracestate {
+            request = request.header(TRACESTATE_HEADER, tracestate);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            TraceParent::parse(TRACEPARENT, Some("vendor=opaque")),
+            Some(TraceParent {
+                trace_id: "0af7651916cd43dd8448eb211c80319c".to_string(),
+                parent_id: "b7ad6b7169203331".to_string(),
+                sampled: true,
+                tracestate: Some("vendor=opaque".to_string()),
+            })
+        );
+        let unsampled = TraceParent::parse(
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00",
+            None,
+        )
+        .unwrap();
+        assert!(!unsampled.sampled);
+        // Other flags than sampled
+        assert!(
+            TraceParent::parse(
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-03",
+                None
+            )
+            .unwrap()
+            .sampled
+        );
+        // Later versions may add fields
+        assert!(TraceParent::parse(
+            "01-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            None
+        )
+        .is_some());
+
+        for invalid in [
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c8031-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-0g",
+        ] {
+            assert_eq!(TraceParent::parse(invalid, None), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_sampled() {
+        // Without an OpenTelemetry exporter, the spans have no trace context
+        let never = TraceSampler::new(0.0);
+        let always = TraceSampler::new(1.0);
+        let context = TraceContext::new(&request(Some(TRACEPARENT), None), &Span::none(), never);
+        assert!(context.sampled);
+        assert!(context.parent.is_some());
+        let unsampled = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00";
+        let context = TraceContext::new(&request(Some(unsampled), None), &Span::none(), always);
+        assert!(!context.sampled);
+
+        // The sampler of the router decides without a valid `traceparent`
+        for traceparent in [None, Some("invalid")] {
+            let request = request(traceparent, None);
+            assert!(TraceContext::new(&request, &Span::none(), always).sampled);
+            let context = TraceContext::new(&request, &Span::none(), never);
+            assert!(!context.sampled);
+            assert!(context.parent.is_none());
+        }
+    }
+}
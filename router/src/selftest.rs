@@ -0,0 +1,309 @@
+/// Golden prompt check of the loaded model
+use crate::infer::{Backend, InferError};
+use crate::{
+    default_max_new_tokens, default_parameters, GenerateParameters, GenerateRequest, Infer,
+    RequestContext,
+};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+
+/// Prompt with a known greedy output, loaded from a JSON file
+/// `{"inputs": "The capital of France is", "expected_prefix": " Paris"}`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GoldenPrompt {
+    inputs: String,
+    #[serde(default)]
+    expected_prefix: Option<String>,
+    #[serde(default)]
+    expected_token_ids: Option<Vec<u32>>,
+    #[serde(default = "default_max_new_tokens")]
+    max_new_tokens: u32,
+    #[serde(default)]
+    repetition_penalty: Option<f32>,
+}
+
+impl GoldenPrompt {
+    /// Greedy request with a fixed seed so that its output only depends on the model
+    fn request(&self) -> GenerateRequest {
+        GenerateRequest {
+            inputs: self.inputs.clone(),
+            parameters: GenerateParameters {
+                max_new_tokens: self.max_new_tokens,
+                repetition_penalty: self.repetition_penalty,
+                do_sample: Some(false),
+                seed: Some(0),
+                details: true,
+                ..default_parameters()
+            },
+            template: None,
+            template_vars: None,
+            preset: None,
+            model: None,
+        }
+    }
+
+    /// First difference between the generation and the expected output, None if they match
+    fn compare(&self, text: &str, token_ids: &[u32]) -> Option<String> {
+        if let Some(prefix) = &self.expected_prefix {
+            if !text.starts_with(prefix.as_str()) {
+                let position = prefix
+                    .chars()
+                    .zip(text.chars())
+                    .take_while(|(expected, generated)| expected == generated)
+                    .count();
+                return Some(format!(
+                    "expected a text starting with {prefix:?}, generated {text:?} (first difference at character {position})"
+                ));
+            }
+        }
+        if let Some(expected) = &self.expected_token_ids {
+            if token_ids.get(..expected.len()) != Some(expected.as_slice()) {
+                let position = expected
+                    .iter()
+                    .zip(token_ids)
+                    .take_while(|(expected, generated)| expected == generated)
+                    .count();
+                return Some(format!(
+                    "expected token ids starting with {expected:?}, generated {token_ids:?} (first difference at token {position})"
+                ));
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum SelfTestError {
+    #[error("could not read the golden prompt: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse the golden prompt: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("the golden prompt must set `expected_prefix` or `expected_token_ids`")]
+    NoExpectedOutput,
+    #[error("`max_new_tokens` ({0}) of the golden prompt must be >= the number of expected token ids ({1})")]
+    MaxNewTokens(u32, usize),
+}
+
+/// Outcome of a golden prompt check
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct SelfTestResult {
+    pub passed: bool,
+    #[schema(example = " Paris, the largest city of the country")]
+    pub generated_text: String,
+    #[schema(example = json ! ([7653, 11, 262]))]
+    pub token_ids: Vec<u32>,
+    /// First difference with the expected output
+    #[schema(nullable = true, example = "null")]
+    pub diff: Option<String>,
+    /// The golden prompt could not be generated
+    #[schema(nullable = true, example = "null")]
+    pub error: Option<String>,
+    /// Unix time of the check in milliseconds
+    #[schema(example = 1680000000000u64)]
+    pub timestamp: u64,
+}
+
+impl SelfTestResult {
+    fn new(golden: &GoldenPrompt, generation: Result<(String, Vec<u32>), InferError>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        match generation {
+            Ok((generated_text, token_ids)) => {
+                let diff = golden.compare(&generated_text, &token_ids);
+                Self {
+                    passed: diff.is_none(),
+                    generated_text,
+                    token_ids,
+                    diff,
+                    error: None,
+                    timestamp,
+                }
+            }
+            Err(err) => Self {
+                passed: false,
+                generated_text: String::new(),
+                token_ids: vec![],
+                diff: None,
+                error: Some(err.to_string()),
+                timestamp,
+            },
+        }
+    }
+
+    /// The model does not generate the expected output
+    fn mismatch(&self) -> bool {
+        self.diff.is_some()
+    }
+}
+
+/// Generates the golden prompt to confirm that the expected model and weights are loaded
+#[derive(Clone)]
+pub(crate) struct SelfTest {
+    infer: Infer,
+    /// None if no golden prompt is configured
+    golden: Option<Arc<GoldenPrompt>>,
+    /// A mismatch makes the router report it is not ready
+    fail_readiness: bool,
+    /// Result of the last check
+    result: Arc<RwLock<Option<SelfTestResult>>>,
+    /// Concurrent checks wait for the running one
+    running: Arc<Mutex<()>>,
+}
+
+impl SelfTest {
+    pub(crate) fn load(
+        infer: Infer,
+        path: Option<&Path>,
+        fail_readiness: bool,
+    ) -> Result<Self, SelfTestError> {
+        let golden = match path {
+            None => None,
+            Some(path) => Some(Arc::new(read_golden_prompt(path)?)),
+        };
+        Ok(Self {
+            infer,
+            golden,
+            fail_readiness,
+            result: Arc::new(RwLock::new(None)),
+            running: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.golden.is_some()
+    }
+
+    /// Generate the golden prompt and compare its output
+    /// None if no golden prompt is configured
+    pub(crate) async fn run(&self) -> Option<SelfTestResult> {
+        let golden = self.golden.as_ref()?;
+        let _running = self.running.lock().await;
+
+        // Sent to the stable backend with the reserved permits of the health probes
+        let context = RequestContext {
+            backend: Some(Backend::Stable),
+            probe: true,
+            ..RequestContext::default()
+        };
+        let generation = self
+            .infer
+            .generate(golden.request(), context)
+            .await
+            .map(|response| {
+                let token_ids = response.tokens.iter().map(|token| token.id).collect();
+                (response.generated_text.text, token_ids)
+            });
+        let result = SelfTestResult::new(golden, generation);
+        match (&result.diff, &result.error) {
+            (None, None) => {
+                tracing::info!("Self-test passed");
+                metrics::increment_counter!("tgi_selftest", "result" => "passed");
+            }
+            (Some(diff), _) => {
+                tracing::error!("Self-test failed: {diff}");
+                metrics::increment_counter!("tgi_selftest", "result" => "mismatch");
+            }
+            (_, Some(err)) => {
+                tracing::error!("Self-test could not run: {err}");
+                metrics::increment_counter!("tgi_selftest", "result" => "error");
+            }
+        }
+        *self.result.write() = Some(result.clone());
+        Some(result)
+    }
+
+    /// Result of the last check
+    pub(crate) fn result(&self) -> Option<SelfTestResult> {
+        self.result.read().clone()
+    }
+
+    /// False if the last check found a mismatch and mismatches fail the readiness probe
+    pub(crate) fn ready(&self) -> bool {
+        !self.fail_readiness
+            || !self
+                .result
+                .read()
+                .as_ref()
+                .map_or(false, SelfTestResult::mismatch)
+    }
+}
+
+fn read_golden_prompt(path: &Path) -> Result<GoldenPrompt, SelfTestError> {
+    let source = std::fs::read_to_string(path)?;
+    let golden: GoldenPrompt = serde_json::from_str(&source)?;
+    match &golden.expected_token_ids {
+        None if golden.expected_prefix.is_none() => Err(SelfTestError::NoExpectedOutput),
+        Some(expected) if expected.len() > golden.max_new_tokens as usize => Err(
+            SelfTestError::MaxNewTokens(golden.max_new_tokens, expected.len()),
+        ),
+        _ => Ok(golden),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn golden(source: &str) -> GoldenPrompt {
+        serde_json::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn test_compare() {
+        let golden = golden(r#"{"inputs": "Hello", "expected_prefix": " the quick"}"#);
+        assert_eq!(golden.compare(" the quick brown", &[]), None);
+        assert_eq!(
+            golden.compare(" the slow", &[]).unwrap(),
+            "expected a text starting with \" the quick\", generated \" the slow\" (first difference at character 5)"
+        );
+
+        let golden = golden(r#"{"inputs": "Hello", "expected_token_ids": [0, 1]}"#);
+        assert_eq!(golden.compare("", &[0, 1, 2]), None);
+        assert!(golden
+            .compare("", &[0, 2])
+            .unwrap()
+            .ends_with("at token 1)"));
+        assert!(golden.compare("", &[0]).is_some());
+    }
+
+    #[test]
+    fn test_request_is_greedy() {
+        let golden = golden(r#"{"inputs": "Hello", "expected_prefix": " the"}"#);
+        let parameters = golden.request().parameters;
+        assert_eq!(parameters.do_sample, Some(false));
+        assert!(!parameters.sampling());
+        assert_eq!(parameters.seed, Some(0));
+        assert!(parameters.details);
+    }
+
+    #[test]
+    fn test_invalid_golden_prompt() {
+        let dir = std::env::temp_dir().join(format!("tgi-selftest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("golden.json");
+
+        std::fs::write(&path, r#"{"inputs": "Hello"}"#).unwrap();
+        assert!(matches!(
+            read_golden_prompt(&path),
+            Err(SelfTestError::NoExpectedOutput)
+        ));
+        std::fs::write(
+            &path,
+            r#"{"inputs": "Hello", "expected_token_ids": [0, 1], "max_new_tokens": 1}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            read_golden_prompt(&path),
+            Err(SelfTestError::MaxNewTokens(1, 2))
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
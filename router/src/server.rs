@@ -1,39 +1,117 @@
 /// HTTP Server logic
-use crate::infer::{InferError, InferResponse, InferStreamResponse};
+use crate::access_log::{access_log_middleware, AccessLog, RequestLog};
+pub use crate::audit::AuditConfig;
+use crate::audit::{AuditEntry, AuditLog};
+pub use crate::breaker::CircuitBreakerConfig;
+use crate::breaker::{CircuitBreakerStatus, CircuitState};
+use crate::cache::ResponseCache;
+pub use crate::callback::CallbackConfig;
+use crate::callback::Callbacks;
+use crate::config::{elide_credentials, Config, Info, ModelInfo};
+use crate::connections::{ClientConnections, ConnectionStatus, StreamConnections};
+use crate::conversation::{Conversations, MemoryStore, Message, Role, USER_STOP_SEQUENCE};
+use crate::drain::{drain_middleware, Draining};
+use crate::extract::{LenientJson, StrictJson};
+pub use crate::faults::FaultConfig;
+use crate::health::HealthCheck;
+use crate::hook::{InputHook, OutputHook, PostGenerationHook, RegexHook, WebhookHook};
+use crate::infer::{
+    accumulate, dedicated_runtime, finish_reason, limit_min_batch_size, mean_time_per_token,
+    Backend, InferError, InferResponse, InferStreamResponse, Speculation,
+};
+pub use crate::infer::{BackendConnection, BatchingPolicy, LoadingPolicy};
+pub use crate::jobs::SpillConfig;
+use crate::jobs::{JobLog, JobResponse, Jobs, JobsError, QueuedJob};
+pub use crate::latency::LatencyTarget;
+use crate::limits::{LimitProfiles, Limits};
+pub use crate::models::{load_models, ModelConfig, ModelsError, ServedModel};
+use crate::normalize::{parse_code_point, InputNormalizer, DEFAULT_STRIPPED_CHARS};
+use crate::pacing::pace;
+use crate::preset::{Preset, Presets};
+use crate::profile::profile_middleware;
+use crate::progress::StreamProgressTracker;
+use crate::rate::RateLimiter;
+use crate::registry::RequestHandle;
+pub use crate::replay::ReplayConfig;
+use crate::replay::ReplayLog;
+use crate::sampling::{trace_context_middleware, TraceContext, TraceSampler};
+use crate::selftest::{SelfTest, SelfTestResult};
+use crate::template::{TemplateInfo, Templates};
+pub use crate::usage::CostModel;
+use crate::usage::{api_key, api_key_id, set_api_key_id_secret, UsageRecorder};
 use crate::validation::ValidationError;
+use crate::vocab::{Vocab, VocabStatus};
+pub use crate::vocab::{VocabCheck, VocabMismatch, VocabMismatchPolicy};
 use crate::{
-    BestOfSequence, CompatGenerateRequest, Details, ErrorResponse, FinishReason,
-    GenerateParameters, GenerateRequest, GenerateResponse, Infer, PrefillToken, StreamDetails,
-    StreamResponse, Token, Validation,
+    AbortReason, BestOfSequence, CacheUtilization, CompatGenerateRequest, ContinueRequest,
+    ConversationHistory, ConversationRequest, Details, DrainStatus, DryRunResponse, ErrorResponse,
+    EstimatedCost, FinishReason, GenerateParameters, GenerateRequest, GenerateResponse,
+    GenerationStatus, Infer, InputSource, JobRequest, JobStatus, MatchedStop, OverloadReason,
+    PrefillToken, QueueStatus, QueuedRequest, RequestContext, RequestStatus, ShardStatus,
+    StopConfig, StreamAborted, StreamDetails, StreamProgress, StreamResponse, Token,
+    ValidParameters, Validation,
 };
-use axum::extract::Extension;
-use axum::http::{HeaderMap, Method, StatusCode};
+use axum::body::{boxed, Full, StreamBody};
+use axum::extract::{ConnectInfo, Extension, Path};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
-use axum::{http, Json, Router};
+use axum::{http, middleware, Json, Router};
 use axum_tracing_opentelemetry::opentelemetry_tracing_layer;
 use futures::Stream;
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use reqwest::Url;
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
 use std::convert::Infallible;
+use std::io::SeekFrom;
 use std::net::SocketAddr;
-use text_generation_client::ShardedClient;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tokenizers::Tokenizer;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::signal;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 use tokio::time::Instant;
 use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
 use tower_http::cors::{AllowOrigin, CorsLayer};
-use tracing::{info_span, instrument, Instrument};
+use tracing::{instrument, Instrument};
 use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
 /// Compatibility route with api-inference and AzureML
-#[instrument(skip(infer))]
+#[instrument(skip(
+    infer,
+    cache,
+    usage,
+    audit_log,
+    trace_context,
+    output_hook,
+    request_log,
+    request_headers
+))]
 async fn compat_generate(
     default_return_full_text: Extension<bool>,
     infer: Extension<Infer>,
-    req: Json<CompatGenerateRequest>,
-) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    cache: Extension<ResponseCache>,
+    usage: Extension<UsageRecorder>,
+    audit_log: Extension<AuditLog>,
+    trace_context: Extension<TraceContext>,
+    output_hook: Extension<OutputHook>,
+    request_log: Extension<RequestLog>,
+    stream_full_text_limit: Extension<StreamFullTextLimit>,
+    stream_event_limit: Extension<StreamEventLimit>,
+    draining: Extension<Draining>,
+    stream_connections: Extension<StreamConnections>,
+    connect_info: ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
+    req: StrictJson<CompatGenerateRequest>,
+) -> Result<Response, (StatusCode, HeaderMap, Json<ErrorResponse>)> {
     let mut req = req.0;
 
     // default return_full_text given the pipeline_tag
@@ -43,48 +121,95 @@ async fn compat_generate(
 
     // switch on stream
     if req.stream {
-        Ok(generate_stream(infer, Json(req.into()))
-            .await
-            .into_response())
+        Ok(generate_stream(
+            infer,
+            usage,
+            audit_log,
+            trace_context,
+            output_hook,
+            request_log,
+            stream_full_text_limit,
+            stream_event_limit,
+            draining,
+            stream_connections,
+            connect_info,
+            request_headers,
+            RequestContext::default(),
+            StrictJson(req.into()),
+        )
+        .await?
+        .into_response())
     } else {
-        let (headers, generation) = generate(infer, Json(req.into())).await?;
+        let (headers, generation) = generate(
+            infer,
+            cache,
+            usage,
+            audit_log,
+            trace_context,
+            output_hook,
+            request_log,
+            request_headers,
+            RequestContext::default(),
+            StrictJson(req.into()),
+        )
+        .await?;
         // wrap generation inside a Vec to match api-inference
         Ok((headers, Json(vec![generation.0])).into_response())
     }
 }
 
 /// Health check method
-#[instrument(skip(infer))]
-async fn health(infer: Extension<Infer>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+#[instrument(skip(health))]
+async fn health(health: Extension<HealthCheck>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
     // TODO: while this is the best health check we can do, it is a bit on the heavy side and might
     //       be a bit too slow for a health check.
     //       What we should do instead is check if the gRPC channels are still healthy.
+    health.check().await?;
+    Ok(())
+}
 
-    // Send a small inference request
-    infer
-        .generate(GenerateRequest {
-            inputs: "liveness".to_string(),
-            parameters: GenerateParameters {
-                best_of: None,
-                temperature: None,
-                repetition_penalty: None,
-                top_k: None,
-                top_p: None,
-                typical_p: None,
-                do_sample: false,
-                max_new_tokens: 1,
-                return_full_text: None,
-                stop: Vec::new(),
-                truncate: None,
-                watermark: false,
-                details: false,
-                seed: None,
-            },
-        })
-        .await?;
+/// Readiness probe
+/// Also fails while the golden prompt does not generate its expected output, if configured, and
+/// when the backend does not use the tokenizer of the router
+#[instrument(skip(health, selftest, vocab_check))]
+async fn ready(
+    health: Extension<HealthCheck>,
+    selftest: Extension<SelfTest>,
+    vocab_check: Extension<VocabCheck>,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    if let Some(mismatch) = vocab_check.mismatch() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorResponse::new(
+                format!("The backend does not use the tokenizer of the router: {mismatch}"),
+                "vocab_mismatch",
+            )),
+        ));
+    }
+    if !selftest.ready() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ErrorResponse::new(
+                "The golden prompt does not generate its expected output".to_string(),
+                "selftest_mismatch",
+            )),
+        ));
+    }
+    health.check().await?;
     Ok(())
 }
 
+/// Liveness probe: the router is up, even if it cannot serve requests yet
+async fn live() {}
+
+/// Readiness probe answered while the router starts up
+async fn not_ready() -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(ErrorResponse::new("Starting up".to_string(), "unavailable")),
+    )
+}
+
 /// Generate tokens
 #[utoipa::path(
     post,
@@ -95,16 +220,34 @@ async fn health(infer: Extension<Infer>) -> Result<(), (StatusCode, Json<ErrorRe
         (status = 200, description = "Generated Text", body = GenerateResponse),
         (status = 424, description = "Generation Error", body = ErrorResponse,
             example = json ! ({"error": "Request failed during generation"})),
+        (status = 403, description = "Request blocked or generated text rejected", body = ErrorResponse,
+            example = json ! ({"error": "Request blocked"})),
         (status = 429, description = "Model is overloaded", body = ErrorResponse,
             example = json ! ({"error": "Model is overloaded"})),
         (status = 422, description = "Input validation error", body = ErrorResponse,
             example = json ! ({"error": "Input validation error"})),
         (status = 500, description = "Incomplete generation", body = ErrorResponse,
             example = json ! ({"error": "Incomplete generation"})),
+        (status = 503, description = "Backend is unavailable", body = ErrorResponse,
+            example = json ! ({"error": "Backend is unavailable"})),
+        (status = 504, description = "Request deadline or response timeout exceeded", body = ErrorResponse,
+            example = json ! ({"error": "Request deadline exceeded"})),
+        (status = 507, description = "Backend ran out of memory", body = ErrorResponse,
+            example = json ! ({"error": "Backend ran out of memory"})),
     )
 )]
 #[instrument(
-    skip(infer),
+    skip(
+        infer,
+        cache,
+        usage,
+        audit_log,
+        trace_context,
+        output_hook,
+        request_log,
+        request_headers,
+        context
+    ),
     fields(
         total_time,
         validation_time,
@@ -112,14 +255,62 @@ async fn health(infer: Extension<Infer>) -> Result<(), (StatusCode, Json<ErrorRe
         inference_time,
         time_per_token,
         seed,
+        backend,
     )
 )]
 async fn generate(
     infer: Extension<Infer>,
-    req: Json<GenerateRequest>,
-) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
+    cache: Extension<ResponseCache>,
+    usage: Extension<UsageRecorder>,
+    audit_log: Extension<AuditLog>,
+    trace_context: Extension<TraceContext>,
+    output_hook: Extension<OutputHook>,
+    request_log: Extension<RequestLog>,
+    request_headers: HeaderMap,
+    mut context: RequestContext,
+    mut req: StrictJson<GenerateRequest>,
+) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, HeaderMap, Json<ErrorResponse>)> {
     let span = tracing::Span::current();
     let start_time = Instant::now();
+    set_deadline_from_headers(&request_headers, &mut req.0.parameters);
+    let api_key = api_key(&request_headers);
+    infer.authorize_force_queue(api_key.as_deref(), &mut req.0.parameters);
+    infer.apply_limits(api_key.as_deref(), req.0.model.as_deref(), &mut context);
+    context.api_key_id = api_key.as_deref().map(api_key_id);
+    context.trace_sampled = Some(trace_context.sampled);
+    if let Err(err) = infer.prepare(&mut req.0, &mut context) {
+        usage.record(
+            &request_headers,
+            0,
+            0,
+            Duration::ZERO,
+            start_time,
+            Err(&err),
+        );
+        request_log.error(err.error_code());
+        return Err(err.into());
+    }
+    // Written to the audit log when dropped, None if the API key is not audited
+    let mut audit = audit_log.start(context.api_key_id.as_deref(), false);
+
+    let backend = route(&infer, &request_headers, &mut context);
+    span.record("backend", backend.as_str());
+
+    // Deterministic requests can be answered from the response cache of their backend
+    let cache_key = cache.key(&req.0, &context);
+    if let Some(cache_key) = &cache_key {
+        if let Some(response) = cache.get(cache_key) {
+            metrics::increment_counter!("tgi_cache_hit");
+            if let Some(audit) = &mut audit {
+                audit.cached(&response);
+            }
+            let mut headers = HeaderMap::new();
+            headers.insert("x-cache", HeaderValue::from_static("hit"));
+            return Ok((headers, Json(response)));
+        }
+        metrics::increment_counter!("tgi_cache_miss");
+    }
+    infer.start_session(&req.0, &mut context);
 
     let compute_characters = req.0.inputs.chars().count();
     let mut add_prompt = None;
@@ -128,18 +319,107 @@ async fn generate(
     }
 
     let details = req.0.parameters.details;
+    let retry_on_empty = req.0.parameters.retry_on_empty > 0;
+    let conversation = context.conversation.take();
+    let best_of = req.0.parameters.best_of.unwrap_or(1);
 
     // Inference
-    let (response, best_of_responses) = match req.0.parameters.best_of {
-        Some(best_of) if best_of > 1 => {
-            let (response, best_of_responses) = infer.generate_best_of(req.0, best_of).await?;
-            (response, Some(best_of_responses))
+    let inference = match req.0.parameters.best_of {
+        Some(best_of) if best_of > 1 => infer
+            .generate_best_of(req.0, context, best_of)
+            .await
+            .map(|(response, best_of_responses)| (response, Some(best_of_responses))),
+        _ => infer
+            .generate(req.0, context)
+            .await
+            .map(|response| (response, None)),
+    };
+    let (mut response, mut best_of_responses) = match inference {
+        Ok(inference) => inference,
+        Err(err) => {
+            // The tokens generated before the response timed out are accounted for
+            let (prompt_tokens, completion_tokens) = match &err {
+                InferError::ResponseTimeout {
+                    input_length,
+                    generated_tokens,
+                } => (*input_length, *generated_tokens),
+                _ => (0, 0),
+            };
+            usage.record(
+                &request_headers,
+                prompt_tokens,
+                completion_tokens,
+                Duration::ZERO,
+                start_time,
+                Err(&err),
+            );
+            if let Some(audit) = &mut audit {
+                audit.fail(&err);
+            }
+            request_log.error(err.error_code());
+            return Err(err.into());
         }
-        _ => (infer.generate(req.0).await?, None),
     };
 
+    // Fewer sequences than requested were generated
+    let sequences = 1 + best_of_responses.as_ref().map_or(0, Vec::len);
+    let warning = (sequences < best_of).then(|| {
+        format!("Generated {sequences} sequences instead of `best_of` ({best_of}): the model is overloaded")
+    });
+
+    // Usage over all the sequences and attempts
+    let finish_reason = response.finish_reason();
+    let input_length = response.input_length;
+    let coalesced = response.coalesced;
+    // The response of a downgraded request is shorter than the one of the same request batched
+    // with all its new tokens
+    let downgraded = response.parameters.requested_max_new_tokens.is_some();
+    let (prompt_tokens, completion_tokens) = std::iter::once(&response)
+        .chain(best_of_responses.iter().flatten())
+        .fold((0, 0), |(prompt_tokens, completion_tokens), response| {
+            (
+                prompt_tokens + response.input_length * response.attempts,
+                completion_tokens + response.total_generated_tokens,
+            )
+        });
+
+    // Post-generation hook
+    let responses = std::iter::once(&mut response).chain(best_of_responses.iter_mut().flatten());
+    if let Err(err) = check_output(&output_hook, responses).await {
+        usage.record(
+            &request_headers,
+            prompt_tokens,
+            completion_tokens,
+            Duration::ZERO,
+            start_time,
+            Err(&err),
+        );
+        if let Some(audit) = &mut audit {
+            audit.set_request_id(response.request_id);
+            audit.fail(&err);
+        }
+        request_log.error(err.error_code());
+        return Err(err.into());
+    }
+    // The tokens move to the details of the response
+    if let Some(audit) = &mut audit {
+        audit.set_request_id(response.request_id);
+        response.tokens.iter().for_each(|token| audit.push(token));
+    }
+
+    // Store the reply in its conversation
+    if let Some(conversation) = conversation {
+        conversation
+            .finish(
+                &response.generated_text.text,
+                input_length,
+                response.generated_text.generated_tokens,
+            )
+            .await;
+    }
+
     // Token details
-    let details = match details {
+    let mut details = match details {
         true => {
             // convert best_of_responses
             let best_of_sequences = best_of_responses.map(|responses: Vec<InferResponse>| {
@@ -154,9 +434,7 @@ async fn generate(
 
                         BestOfSequence {
                             generated_text: output_text,
-                            finish_reason: FinishReason::from(
-                                response.generated_text.finish_reason,
-                            ),
+                            finish_reason: response.finish_reason(),
                             generated_tokens: response.generated_text.generated_tokens,
                             prefill: response.prefill,
                             tokens: response.tokens,
@@ -166,76 +444,66 @@ async fn generate(
                     .collect()
             });
 
-            Some(Details {
-                finish_reason: FinishReason::from(response.generated_text.finish_reason),
-                generated_tokens: response.generated_text.generated_tokens,
-                prefill: response.prefill,
-                tokens: response.tokens,
-                seed: response.generated_text.seed,
+            Some(response_details(
+                &mut response,
                 best_of_sequences,
-            })
+                retry_on_empty,
+            ))
         }
         false => None,
     };
 
     // Timings
-    let total_time = start_time.elapsed();
-    let validation_time = response.queued - start_time;
-    let queue_time = response.start - response.queued;
-    let inference_time = Instant::now() - response.start;
-    let time_per_token = inference_time / response.generated_text.generated_tokens;
+    let timings = RequestTimings::new(
+        start_time,
+        response.queued,
+        response.start,
+        Instant::now(),
+        response.generated_text.generated_tokens,
+    );
+    let estimated_cost = usage.estimate(prompt_tokens, completion_tokens, timings.inference);
 
     // Headers
     let mut headers = HeaderMap::new();
-    headers.insert("x-compute-type", "gpu+optimized".parse().unwrap());
-    headers.insert(
-        "x-compute-time",
-        total_time.as_millis().to_string().parse().unwrap(),
-    );
+    headers.insert("x-compute-type", HeaderValue::from_static("gpu+optimized"));
+    headers.insert("x-backend", HeaderValue::from_static(backend.as_str()));
+    headers.insert("x-request-id", HeaderValue::from(response.request_id));
     headers.insert(
         "x-compute-characters",
-        compute_characters.to_string().parse().unwrap(),
-    );
-    headers.insert(
-        "x-total-time",
-        total_time.as_millis().to_string().parse().unwrap(),
-    );
-    headers.insert(
-        "x-validation-time",
-        validation_time.as_millis().to_string().parse().unwrap(),
-    );
-    headers.insert(
-        "x-queue-time",
-        queue_time.as_millis().to_string().parse().unwrap(),
-    );
-    headers.insert(
-        "x-inference-time",
-        inference_time.as_millis().to_string().parse().unwrap(),
-    );
-    headers.insert(
-        "x-time-per-token",
-        time_per_token.as_millis().to_string().parse().unwrap(),
+        HeaderValue::from(compute_characters),
     );
+    headers.insert("x-prompt-tokens", input_length.into());
+    timings.insert_headers(&mut headers);
+    if let Some(validation_timings) = response.validation_timings {
+        headers.insert(
+            "x-validation-queue-time",
+            millis_header(validation_timings.queue_time),
+        );
+        headers.insert(
+            "x-tokenization-time",
+            millis_header(validation_timings.tokenization_time),
+        );
+    }
+    if let Some(hook_time) = response.hook_time {
+        headers.insert("x-hook-time", millis_header(hook_time));
+    }
+    if let Some(estimated_cost) = &estimated_cost {
+        if let Ok(value) = HeaderValue::from_str(&format!(
+            "{} {}",
+            estimated_cost.amount, estimated_cost.currency
+        )) {
+            headers.insert("x-estimated-cost", value);
+        }
+    }
 
-    // Tracing metadata
-    span.record("total_time", format!("{total_time:?}"));
-    span.record("validation_time", format!("{validation_time:?}"));
-    span.record("queue_time", format!("{queue_time:?}"));
-    span.record("inference_time", format!("{inference_time:?}"));
-    span.record("time_per_token", format!("{time_per_token:?}"));
+    // Tracing metadata and metrics
+    timings.record(&span);
     span.record("seed", format!("{:?}", response.generated_text.seed));
     tracing::info!("Output: {}", response.generated_text.text);
-
-    // Metrics
-    metrics::increment_counter!("tgi_request_success");
-    metrics::histogram!("tgi_request_duration", total_time);
-    metrics::histogram!("tgi_request_validation_duration", validation_time);
-    metrics::histogram!("tgi_request_queue_duration", queue_time);
-    metrics::histogram!("tgi_request_inference_duration", inference_time);
-    metrics::histogram!("tgi_request_mean_time_per_token_duration", time_per_token);
+    metrics::increment_counter!("tgi_request_success", "backend" => backend.as_str());
     metrics::histogram!(
         "tgi_request_generated_tokens",
-        response.generated_text.generated_tokens as f64
+        response.total_generated_tokens as f64
     );
 
     // Send response
@@ -244,14 +512,524 @@ async fn generate(
         output_text = prompt + &output_text;
     }
 
+    if let Some(details) = &mut details {
+        details.estimated_cost = estimated_cost;
+    }
+    if let Some(audit) = &mut audit {
+        audit.succeed(
+            response.parameters.clone(),
+            output_text.clone(),
+            finish_reason.clone(),
+        );
+    }
+
     let response = GenerateResponse {
         generated_text: output_text,
         details,
+        continued_from: None,
+        warning,
+    };
+
+    if let Some(cache_key) = cache_key.filter(|_| !downgraded) {
+        cache.insert(cache_key, &response);
+        headers.insert("x-cache", HeaderValue::from_static("miss"));
+    }
+    if coalesced > 1 {
+        headers.insert("x-coalesced", coalesced.into());
+    }
+    usage.record(
+        &request_headers,
+        prompt_tokens,
+        completion_tokens,
+        timings.inference,
+        start_time,
+        Ok(finish_reason),
+    );
+    request_log.tokens(prompt_tokens, completion_tokens);
+    Ok((headers, Json(response)))
+}
+
+/// Validate a request and return how the router would generate it, without generating it
+/// The request does not take a concurrency permit and is not moderated by the pre-generation hook
+#[utoipa::path(
+    post,
+    tag = "Text Generation Inference",
+    path = "/validate",
+    request_body = GenerateRequest,
+    responses(
+        (status = 200, description = "Validated request", body = DryRunResponse),
+        (status = 404, description = "Unknown model", body = ErrorResponse,
+            example = json ! ({"error": "Unknown model"})),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+            example = json ! ({"error": "Input validation error"})),
+        (status = 429, description = "Too many dry runs", body = ErrorResponse,
+            example = json ! ({"error": "Too many dry runs: the limit is 10 per second"})),
+    )
+)]
+#[instrument(skip_all)]
+async fn dry_run(
+    infer: Extension<Infer>,
+    dry_runs: Extension<RateLimiter>,
+    request_headers: HeaderMap,
+    mut req: StrictJson<GenerateRequest>,
+) -> Result<Json<DryRunResponse>, (StatusCode, Json<ErrorResponse>)> {
+    if !dry_runs.try_acquire() {
+        metrics::increment_counter!("tgi_request_failure", "err" => "rate_limit");
+        return Err(InferError::RateLimit(dry_runs.per_second()).into());
+    }
+    metrics::increment_counter!("tgi_dry_run_count");
+
+    // Same preparation as `generate`
+    let mut context = RequestContext::default();
+    set_deadline_from_headers(&request_headers, &mut req.0.parameters);
+    let api_key = api_key(&request_headers);
+    infer.authorize_force_queue(api_key.as_deref(), &mut req.0.parameters);
+    infer.apply_limits(api_key.as_deref(), req.0.model.as_deref(), &mut context);
+    context.api_key_id = api_key.as_deref().map(api_key_id);
+    let preset = req.0.preset.clone();
+    let template = req.0.template.clone();
+    infer.prepare(&mut req.0, &mut context)?;
+    // Dry runs do not probe an unhealthy canary backend
+    context.backend = Some(infer.route(requested_backend(&request_headers)));
+
+    let dry_run = infer.dry_run(req.0, context).await?;
+    let request = dry_run.request;
+    Ok(Json(DryRunResponse {
+        model: dry_run.model,
+        backend: dry_run.backend,
+        preset,
+        template,
+        input_source: request.input_source,
+        parameters: request.valid_parameters(),
+        inputs: request.inputs,
+        input_length: request.input_length,
+        normalized_chars: request.normalized_chars,
+        estimated_wait_ms: dry_run
+            .estimated_wait
+            .map(|estimated_wait| estimated_wait.as_millis() as u64),
+    }))
+}
+
+/// Details of the best sequence of a generation, its tokens are moved to the details
+fn response_details(
+    response: &mut InferResponse,
+    best_of_sequences: Option<Vec<BestOfSequence>>,
+    retry_on_empty: bool,
+) -> Details {
+    let tokenization = response.tokenization.as_ref();
+    Details {
+        finish_reason: response.finish_reason(),
+        generated_tokens: response.generated_text.generated_tokens,
+        prefill: std::mem::take(&mut response.prefill),
+        tokens: std::mem::take(&mut response.tokens),
+        seed: response.generated_text.seed,
+        best_of_sequences,
+        attempts: retry_on_empty.then_some(response.attempts),
+        retries: (response.requeues > 0).then_some(response.requeues),
+        matched_stop: response.matched_stop.take(),
+        parameters: response.parameters.clone(),
+        token_count_mismatch: response.token_count_mismatch,
+        prefill_mismatch: response.prefill_mismatch,
+        prompt_chars: tokenization.map(|tokenization| tokenization.chars),
+        prompt_bytes: tokenization.map(|tokenization| tokenization.bytes),
+        prompt_tokens: tokenization.map(|tokenization| tokenization.tokens),
+        chars_per_token: tokenization
+            .filter(|tokenization| tokenization.tokens > 0)
+            .map(|tokenization| tokenization.chars as f32 / tokenization.tokens as f32),
+        requested_max_new_tokens: response.parameters.requested_max_new_tokens,
+        effective_max_new_tokens: response
+            .parameters
+            .requested_max_new_tokens
+            .map(|_| response.parameters.max_new_tokens),
+        normalized: response.parameters.normalized_chars.is_some(),
+        normalized_chars: response.parameters.normalized_chars,
+        input_source: response.parameters.input_source,
+        estimated_cost: None,
+    }
+}
+
+/// Run the post-generation hook on the generated texts
+/// The tokens of a modified text are filtered as well so that details do not leak it
+async fn check_output(
+    output_hook: &OutputHook,
+    responses: impl Iterator<Item = &mut InferResponse>,
+) -> Result<(), InferError> {
+    for response in responses {
+        let text = output_hook
+            .check(response.generated_text.text.clone())
+            .await?;
+        if text != response.generated_text.text {
+            response.generated_text.text = text;
+            response.tokens = output_hook
+                .filter(std::mem::take(&mut response.tokens))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Continue a completed generation without sending its prompt again
+#[utoipa::path(
+    post,
+    tag = "Text Generation Inference",
+    path = "/generate/continue",
+    request_body = ContinueRequest,
+    responses(
+        (status = 200, description = "Generated Text", body = GenerateResponse),
+        (status = 404, description = "Unknown or expired request id", body = ErrorResponse,
+            example = json ! ({"error": "Request 0 is unknown or expired, send the full prompt to /generate instead"})),
+        (status = 424, description = "Generation Error", body = ErrorResponse,
+            example = json ! ({"error": "Request failed during generation"})),
+        (status = 429, description = "Model is overloaded", body = ErrorResponse,
+            example = json ! ({"error": "Model is overloaded"})),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+            example = json ! ({"error": "Input validation error"})),
+    )
+)]
+#[instrument(skip(
+    infer,
+    cache,
+    usage,
+    audit_log,
+    trace_context,
+    output_hook,
+    request_log,
+    request_headers
+))]
+async fn continue_generation(
+    infer: Extension<Infer>,
+    cache: Extension<ResponseCache>,
+    usage: Extension<UsageRecorder>,
+    audit_log: Extension<AuditLog>,
+    trace_context: Extension<TraceContext>,
+    output_hook: Extension<OutputHook>,
+    request_log: Extension<RequestLog>,
+    request_headers: HeaderMap,
+    req: StrictJson<ContinueRequest>,
+) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, HeaderMap, Json<ErrorResponse>)> {
+    let ContinueRequest {
+        request_id,
+        mut parameters,
+    } = req.0;
+    // The generations of the other API keys are unknown
+    let owner = api_key(&request_headers).as_deref().map(api_key_id);
+    let continuation = infer
+        .continuation(request_id, owner.as_deref())
+        .ok_or_else(|| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "not_found");
+            (
+                StatusCode::NOT_FOUND,
+                HeaderMap::new(),
+                Json(ErrorResponse::new(format!(
+                        "Request {request_id} is unknown or expired, send the full prompt to /generate instead"
+                    ), "not_found")),
+            )
+        })?;
+
+    // The session of the continued request lets the backend skip the prefill of its text
+    if parameters.session_id.is_none() && infer.sessions_enabled() {
+        parameters.session_id = continuation.session_id.clone();
+    }
+    let return_full_text = parameters.return_full_text.unwrap_or(false);
+    let request = GenerateRequest {
+        inputs: format!("{}{}", continuation.prompt, continuation.generated_text),
+        parameters,
+        template: None,
+        template_vars: None,
+        preset: None,
+        // Continued on the model of the continued request
+        model: continuation.model.clone(),
+    };
+    let context = RequestContext {
+        continued: Some(continuation.clone()),
+        ..RequestContext::default()
     };
+
+    let (headers, Json(mut response)) = generate(
+        infer,
+        cache,
+        usage,
+        audit_log,
+        trace_context,
+        output_hook,
+        request_log,
+        request_headers,
+        context,
+        StrictJson(request),
+    )
+    .await?;
+    // The full text already starts with the continued text
+    if !return_full_text {
+        response.generated_text = continuation.generated_text + &response.generated_text;
+    }
+    response.continued_from = Some(request_id);
     Ok((headers, Json(response)))
 }
 
+/// Reply to a user message of a conversation kept by the router
+#[utoipa::path(
+    post,
+    tag = "Text Generation Inference",
+    path = "/conversation/{conversation_id}",
+    params(("conversation_id" = String, Path, description = "Conversation id, the conversation being created by its first message")),
+    request_body = ConversationRequest,
+    responses(
+        (status = 200, description = "Generated reply", body = GenerateResponse),
+        (status = 424, description = "Generation Error", body = ErrorResponse,
+            example = json ! ({"error": "Request failed during generation"})),
+        (status = 429, description = "Model is overloaded", body = ErrorResponse,
+            example = json ! ({"error": "Model is overloaded"})),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+            example = json ! ({"error": "Input validation error"})),
+    )
+)]
+#[instrument(skip(
+    infer,
+    cache,
+    usage,
+    audit_log,
+    trace_context,
+    output_hook,
+    request_log,
+    conversations,
+    request_headers
+))]
+#[allow(clippy::too_many_arguments)]
+async fn conversation(
+    infer: Extension<Infer>,
+    cache: Extension<ResponseCache>,
+    usage: Extension<UsageRecorder>,
+    audit_log: Extension<AuditLog>,
+    trace_context: Extension<TraceContext>,
+    output_hook: Extension<OutputHook>,
+    request_log: Extension<RequestLog>,
+    conversations: Extension<Conversations>,
+    Path(conversation_id): Path<String>,
+    request_headers: HeaderMap,
+    req: StrictJson<ConversationRequest>,
+) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, HeaderMap, Json<ErrorResponse>)> {
+    let (request, context) =
+        conversation_request(&conversations, conversation_id, &request_headers, req.0).await?;
+    generate(
+        infer,
+        cache,
+        usage,
+        audit_log,
+        trace_context,
+        output_hook,
+        request_log,
+        request_headers,
+        context,
+        StrictJson(request),
+    )
+    .await
+}
+
+/// Stream the reply to a user message of a conversation kept by the router
+#[utoipa::path(
+    post,
+    tag = "Text Generation Inference",
+    path = "/conversation/{conversation_id}/stream",
+    params(("conversation_id" = String, Path, description = "Conversation id, the conversation being created by its first message")),
+    request_body = ConversationRequest,
+    responses(
+        (status = 200, description = "Generated reply", body = StreamResponse,
+            content_type = "text/event-stream"),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+            example = json ! ({"error": "Input validation error"})),
+    )
+)]
+#[instrument(skip(
+    infer,
+    usage,
+    audit_log,
+    trace_context,
+    output_hook,
+    request_log,
+    conversations,
+    request_headers
+))]
+#[allow(clippy::too_many_arguments)]
+async fn conversation_stream(
+    infer: Extension<Infer>,
+    usage: Extension<UsageRecorder>,
+    audit_log: Extension<AuditLog>,
+    trace_context: Extension<TraceContext>,
+    output_hook: Extension<OutputHook>,
+    request_log: Extension<RequestLog>,
+    stream_full_text_limit: Extension<StreamFullTextLimit>,
+    stream_event_limit: Extension<StreamEventLimit>,
+    draining: Extension<Draining>,
+    stream_connections: Extension<StreamConnections>,
+    connect_info: ConnectInfo<SocketAddr>,
+    conversations: Extension<Conversations>,
+    Path(conversation_id): Path<String>,
+    request_headers: HeaderMap,
+    req: StrictJson<ConversationRequest>,
+) -> Result<Response, (StatusCode, HeaderMap, Json<ErrorResponse>)> {
+    let (request, context) =
+        conversation_request(&conversations, conversation_id, &request_headers, req.0).await?;
+    Ok(generate_stream(
+        infer,
+        usage,
+        audit_log,
+        trace_context,
+        output_hook,
+        request_log,
+        stream_full_text_limit,
+        stream_event_limit,
+        draining,
+        stream_connections,
+        connect_info,
+        request_headers,
+        context,
+        StrictJson(request),
+    )
+    .await?
+    .into_response())
+}
+
+/// Generation request of the reply to a user message, with the context of its conversation turn
+/// The conversations are scoped by API key
+async fn conversation_request(
+    conversations: &Conversations,
+    conversation_id: String,
+    request_headers: &HeaderMap,
+    request: ConversationRequest,
+) -> Result<(GenerateRequest, RequestContext), InferError> {
+    let ConversationRequest {
+        message,
+        mut parameters,
+        template,
+        template_vars,
+        preset,
+        model,
+    } = request;
+    // Only one reply can be added to the history
+    if parameters.best_of.map_or(false, |best_of| best_of > 1) {
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        return Err(ValidationError::BestOfDisabled.into());
+    }
+
+    let api_key_id = api_key(request_headers).as_deref().map(api_key_id);
+    let turn = conversations
+        .start(api_key_id.as_deref(), conversation_id, message)
+        .await;
+    // The backend can reuse its state of the previous turn
+    if parameters.session_id.is_none() && infer.sessions_enabled() {
+        parameters.session_id = Some(format!("conversation:{}", turn.key()));
+    }
+    // Stop before the reply starts the next user message
+    if parameters.stop.is_empty() && parameters.stop_config.is_empty() {
+        parameters.stop_config = vec![StopConfig {
+            sequence: USER_STOP_SEQUENCE.to_string(),
+            keep_text: false,
+        }];
+    }
+    // The reply depends on the stored history
+    parameters.no_cache = true;
+
+    let request = GenerateRequest {
+        inputs: turn.prompt(),
+        parameters,
+        template,
+        template_vars,
+        preset,
+        model,
+    };
+    let context = RequestContext {
+        conversation: Some(turn),
+        ..RequestContext::default()
+    };
+    Ok((request, context))
+}
+
+/// Get the history of a conversation
+#[utoipa::path(
+    get,
+    tag = "Text Generation Inference",
+    path = "/conversation/{conversation_id}",
+    params(("conversation_id" = String, Path, description = "Conversation id")),
+    responses(
+        (status = 200, description = "Conversation history", body = ConversationHistory),
+        (status = 404, description = "Unknown or expired conversation", body = ErrorResponse,
+            example = json ! ({"error": "Conversation not found"})),
+    )
+)]
+#[instrument(skip(conversations, request_headers))]
+async fn conversation_history(
+    conversations: Extension<Conversations>,
+    Path(conversation_id): Path<String>,
+    request_headers: HeaderMap,
+) -> Result<Json<ConversationHistory>, (StatusCode, Json<ErrorResponse>)> {
+    let api_key_id = api_key(&request_headers).as_deref().map(api_key_id);
+    match conversations
+        .history(api_key_id.as_deref(), &conversation_id)
+        .await
+    {
+        Some(messages) => Ok(Json(ConversationHistory {
+            conversation_id,
+            messages,
+        })),
+        None => Err(conversation_not_found()),
+    }
+}
+
+/// Clear the history of a conversation
+#[utoipa::path(
+    delete,
+    tag = "Text Generation Inference",
+    path = "/conversation/{conversation_id}",
+    params(("conversation_id" = String, Path, description = "Conversation id")),
+    responses(
+        (status = 204, description = "Conversation cleared"),
+        (status = 404, description = "Unknown or expired conversation", body = ErrorResponse,
+            example = json ! ({"error": "Conversation not found"})),
+    )
+)]
+#[instrument(skip(conversations, request_headers))]
+async fn clear_conversation(
+    conversations: Extension<Conversations>,
+    Path(conversation_id): Path<String>,
+    request_headers: HeaderMap,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    let api_key_id = api_key(&request_headers).as_deref().map(api_key_id);
+    match conversations
+        .clear(api_key_id.as_deref(), &conversation_id)
+        .await
+    {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(conversation_not_found()),
+    }
+}
+
+fn conversation_not_found() -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse::new(
+            "Conversation not found".to_string(),
+            "not_found",
+        )),
+    )
+}
+
 /// Generate a stream of token using Server-Sent Events
+///
+/// The stream starts once the request is queued. The requests failing before, e.g. on their
+/// validation or because the model is overloaded, get a JSON error with its status code
+///
+/// The request id used by `DELETE /generation/{id}` is sent in the `x-request-id` header with the
+/// start of the stream, and in the `status` events sent while the request is queued
+///
+/// Streams aborted by the server after they started end with their error event, then with an
+/// `aborted` event carrying a `StreamAborted`
+///
+/// The tokens of requests with a `stream_rate_limit` are sent at this rate, the tokens left being
+/// flushed when the generation ends
+///
+/// Requests with a `progress_interval_tokens` also get a `progress` event carrying a
+/// `StreamProgress` every this many tokens sent, counted after the `stream_rate_limit` pacing. With
+/// `progress_only` the token events are not sent, the last event is unchanged
 #[utoipa::path(
     post,
     tag = "Text Generation Inference",
@@ -263,19 +1041,32 @@ async fn generate(
         (status = 424, description = "Generation Error", body = ErrorResponse,
             example = json ! ({"error": "Request failed during generation"}),
             content_type = "text/event-stream"),
-        (status = 429, description = "Model is overloaded", body = ErrorResponse,
-            example = json ! ({"error": "Model is overloaded"}),
-            content_type = "text/event-stream"),
+        (status = 403, description = "Request blocked or generated text rejected", body = ErrorResponse,
+            example = json ! ({"error": "Request blocked"})),
+        (status = 429, description = "Model is overloaded, or too many streams of the client IP or API key are open", body = ErrorResponse,
+            example = json ! ({"error": "Model is overloaded"})),
         (status = 422, description = "Input validation error", body = ErrorResponse,
-            example = json ! ({"error": "Input validation error"}),
-            content_type = "text/event-stream"),
+            example = json ! ({"error": "Input validation error"})),
         (status = 500, description = "Incomplete generation", body = ErrorResponse,
             example = json ! ({"error": "Incomplete generation"}),
             content_type = "text/event-stream"),
     )
 )]
 #[instrument(
-    skip(infer),
+    skip(
+        infer,
+        usage,
+        audit_log,
+        trace_context,
+        output_hook,
+        request_log,
+        stream_full_text_limit,
+        stream_event_limit,
+        draining,
+        stream_connections,
+        request_headers,
+        context
+    ),
     fields(
         total_time,
         validation_time,
@@ -283,283 +1074,2844 @@ async fn generate(
         inference_time,
         time_per_token,
         seed,
+        backend,
     )
 )]
 async fn generate_stream(
     infer: Extension<Infer>,
-    req: Json<GenerateRequest>,
-) -> (
-    HeaderMap,
-    Sse<impl Stream<Item = Result<Event, Infallible>>>,
-) {
+    usage: Extension<UsageRecorder>,
+    audit_log: Extension<AuditLog>,
+    trace_context: Extension<TraceContext>,
+    output_hook: Extension<OutputHook>,
+    request_log: Extension<RequestLog>,
+    stream_full_text_limit: Extension<StreamFullTextLimit>,
+    stream_event_limit: Extension<StreamEventLimit>,
+    draining: Extension<Draining>,
+    stream_connections: Extension<StreamConnections>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
+    mut context: RequestContext,
+    mut req: StrictJson<GenerateRequest>,
+) -> Result<
+    (
+        HeaderMap,
+        Sse<impl Stream<Item = Result<Event, Infallible>>>,
+    ),
+    (StatusCode, HeaderMap, Json<ErrorResponse>),
+> {
     let span = tracing::Span::current();
+    request_log.stream();
     let start_time = Instant::now();
+    set_deadline_from_headers(&request_headers, &mut req.0.parameters);
+    let api_key = api_key(&request_headers);
+    // Counted until the stream is dropped, before the request waits for a permit
+    let connection = stream_connections
+        .acquire(client_addr.ip(), api_key.as_deref())
+        .map_err(|err| {
+            request_log.error(err.error_code());
+            err
+        })?;
+    infer.authorize_force_queue(api_key.as_deref(), &mut req.0.parameters);
+    infer.apply_limits(api_key.as_deref(), req.0.model.as_deref(), &mut context);
+    context.api_key_id = api_key.as_deref().map(api_key_id);
+    context.trace_sampled = Some(trace_context.sampled);
+    if let Err(err) = infer.prepare(&mut req.0, &mut context) {
+        request_log.error(err.error_code());
+        usage.record(
+            &request_headers,
+            0,
+            0,
+            Duration::ZERO,
+            start_time,
+            Err(&err),
+        );
+        return Err(err.into());
+    }
+    if req.0.parameters.best_of.unwrap_or(1) > 1 {
+        let err = InferError::from(ValidationError::BestOfStream);
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        tracing::error!("{err}");
+        request_log.error(err.error_code());
+        usage.record(
+            &request_headers,
+            0,
+            0,
+            Duration::ZERO,
+            start_time,
+            Err(&err),
+        );
+        return Err(err.into());
+    }
+    let mut conversation = context.conversation.take();
 
     let compute_characters = req.0.inputs.chars().count();
+    let handle = infer.register();
+    infer.start_session(&req.0, &mut context);
+    let backend = route(&infer, &request_headers, &mut context);
+    span.record("backend", backend.as_str());
 
     let mut headers = HeaderMap::new();
-    headers.insert("x-compute-type", "gpu+optimized".parse().unwrap());
+    headers.insert("x-compute-type", HeaderValue::from_static("gpu+optimized"));
+    headers.insert("x-backend", HeaderValue::from_static(backend.as_str()));
+    headers.insert("x-request-id", HeaderValue::from(handle.id));
     headers.insert(
         "x-compute-characters",
-        compute_characters.to_string().parse().unwrap(),
+        HeaderValue::from(compute_characters),
     );
 
+    let mut add_prompt = None;
+    if req.0.parameters.return_full_text.unwrap_or(false) {
+        add_prompt = Some(req.0.inputs.clone());
+    }
+    let details = req.0.parameters.details;
+    // Text of the streamed tokens, appended to as they are sent
+    let mut text_so_far = req.0.parameters.stream_full_text.then(String::new);
+    let stream_rate_limit = req.0.parameters.stream_rate_limit;
+    // Counts the tokens sent after the pacing and the post-generation hook
+    let mut progress =
+        StreamProgressTracker::new(req.0.parameters.progress_interval_tokens, start_time);
+    let progress_only = req.0.parameters.progress_only;
+
+    // The stream starts once the request is queued: the requests failing validation or rejected
+    // by the admission checks get their status code instead of an error event
+    let response_stream = match infer.generate_stream(req.0, context, handle.clone()).await {
+        Ok(response_stream) => response_stream,
+        Err(err) => {
+            request_log.error(err.error_code());
+            usage.record(
+                &request_headers,
+                0,
+                0,
+                Duration::ZERO,
+                start_time,
+                Err(&err),
+            );
+            return Err(err.into());
+        }
+    };
+    // The request is validated once it is queued
+    let prompt_tokens = handle.input_length();
+    // Written to the audit log when dropped with the stream, None if the API key is not audited
+    let mut audit = audit_log.start(api_key.as_deref().map(api_key_id).as_deref(), true);
+    if let Some(audit) = &mut audit {
+        audit.set_request_id(handle.id);
+    }
+
     let stream = async_stream::stream! {
+        let _connection = connection;
         // Inference
         let mut end_reached = false;
         let mut error = false;
+        // Holds back the last tokens until the post-generation hook approves them
+        let mut window = output_hook.stream();
+        let mut response_stream = Box::pin(pace(response_stream, stream_rate_limit));
 
-        let mut add_prompt = None;
-        if req.0.parameters.return_full_text.unwrap_or(false) {
-            add_prompt = Some(req.0.inputs.clone());
-        }
-        let details = req.0.parameters.details;
-
-        let best_of = req.0.parameters.best_of.unwrap_or(1);
-        if best_of == 1 {
-            match infer.generate_stream(req.0).instrument(info_span!(parent: &span, "async_stream")).await {
-                Ok(mut response_stream) => {
-                    // Server-Sent Event stream
-                    while let Some(response) = response_stream.next().await {
-                        match response {
-                            Ok(response) => {
-                                match response {
-                                    // Prefill is ignored
-                                    InferStreamResponse::Prefill(_) => {}
-                                    // Yield event for every new token
-                                    InferStreamResponse::Token(token) => {
-                                        // StreamResponse
-                                        let stream_token = StreamResponse {
-                                            token,
-                                            generated_text: None,
-                                            details: None,
-                                        };
-
-                                        yield Ok(Event::default().json_data(stream_token).unwrap())
+        // Server-Sent Event stream
+        while let Some(response) = response_stream.next().await {
+            match response {
+                Ok(response) => {
+                    match response {
+                        // Queue notifications use their own event name so that
+                        // clients only listening for tokens are not affected
+                        InferStreamResponse::Queued => {
+                            yield Ok(status_event("queued", handle.id))
+                        }
+                        InferStreamResponse::Started => {
+                            yield Ok(status_event("started", handle.id))
+                        }
+                        // Prefill tokens are not streamed
+                        InferStreamResponse::Prefill { .. } => {}
+                        // Yield event for every new token
+                        InferStreamResponse::Token(token) => match window.push(token).await {
+                            Ok(tokens) => {
+                                for token in tokens {
+                                    if let Some(audit) = &mut audit {
+                                        audit.push(&token);
                                     }
-                                    // Yield event for last token and compute timings
-                                    InferStreamResponse::End {
+                                    // StreamResponse
+                                    let stream_token = StreamResponse {
+                                        generated_text_so_far: append_text(&mut text_so_far, &token, stream_full_text_limit.0),
                                         token,
-                                        generated_text,
-                                        start,
-                                        queued,
-                                    } => {
-                                        // Token details
-                                        let details = match details {
-                                            true => Some(StreamDetails {
-                                                finish_reason: FinishReason::from(generated_text.finish_reason),
-                                                generated_tokens: generated_text.generated_tokens,
-                                                seed: generated_text.seed,
-                                            }),
-                                            false => None,
-                                        };
-
-                                        // Timings
-                                        let total_time = start_time.elapsed();
-                                        let validation_time = queued - start_time;
-                                        let queue_time = start - queued;
-                                        let inference_time = Instant::now() - start;
-                                        let time_per_token = inference_time / generated_text.generated_tokens;
-
-                                        // Tracing metadata
-                                        span.record("total_time", format!("{total_time:?}"));
-                                        span.record("validation_time", format!("{validation_time:?}"));
-                                        span.record("queue_time", format!("{queue_time:?}"));
-                                        span.record("inference_time", format!("{inference_time:?}"));
-                                        span.record("time_per_token", format!("{time_per_token:?}"));
-                                        span.record("seed", format!("{:?}", generated_text.seed));
-                                        tracing::info!(parent: &span, "Output: {}", generated_text.text);
-
-                                        // Metrics
-                                        metrics::increment_counter!("tgi_request_success");
-                                        metrics::histogram!("tgi_request_duration", total_time);
-                                        metrics::histogram!("tgi_request_validation_duration", validation_time);
-                                        metrics::histogram!("tgi_request_queue_duration", queue_time);
-                                        metrics::histogram!("tgi_request_inference_duration", inference_time);
-                                        metrics::histogram!("tgi_request_mean_time_per_token_duration", time_per_token);
-                                        metrics::histogram!("tgi_request_generated_tokens", generated_text.generated_tokens as f64);
-
-                                        // StreamResponse
-                                        end_reached = true;
-
-                                        let mut output_text = generated_text.text;
-                                        if let Some(prompt) = add_prompt {
-                                            output_text = prompt + &output_text;
-                                        }
-
-                                        let stream_token = StreamResponse {
-                                            token,
-                                            generated_text: Some(output_text),
-                                            details
-                                        };
-
-                                        yield Ok(Event::default().json_data(stream_token).unwrap());
-                                        break;
+                                        generated_text: None,
+                                        details: None,
+                                        truncated: false,
+                                    };
+
+                                    if !progress_only {
+                                        yield Ok(stream_event(stream_token, stream_event_limit.0))
+                                    }
+                                    if let Some(progress) = progress.as_mut().and_then(StreamProgressTracker::sent) {
+                                        yield Ok(progress_event(progress))
                                     }
                                 }
                             }
-                            // yield error
                             Err(err) => {
                                 error = true;
-                                yield Ok(Event::from(err));
+                                request_log.error(err.error_code());
+                                usage.record(&request_headers, prompt_tokens, handle.generated_tokens(), Duration::ZERO, start_time, Err(&err));
+                                if let Some(audit) = &mut audit {
+                                    audit.fail(&err);
+                                }
+                                for event in abort_events(err, handle.generated_tokens(), &draining) {
+                                    yield Ok(event);
+                                }
                                 break;
                             }
+                        },
+                        // Yield event for last token and compute timings
+                        InferStreamResponse::End {
+                            token,
+                            generated_text,
+                            start,
+                            queued,
+                            parameters,
+                            matched_stop,
+                            token_count_mismatch,
+                            router_limit,
+                        } => {
+                            // Post-generation hook on the held back tokens and the full text
+                            let filtered = match window.finish(token).await {
+                                Ok(tokens) => output_hook.check(generated_text.text.clone()).await.map(|text| (tokens, text)),
+                                Err(err) => Err(err),
+                            };
+                            let (mut tokens, text) = match filtered {
+                                Ok(filtered) => filtered,
+                                Err(err) => {
+                                    error = true;
+                                    request_log.error(err.error_code());
+                                    usage.record(&request_headers, prompt_tokens, generated_text.generated_tokens, Duration::ZERO, start_time, Err(&err));
+                                    if let Some(audit) = &mut audit {
+                                        audit.fail(&err);
+                                    }
+                                    for event in abort_events(err, generated_text.generated_tokens, &draining) {
+                                        yield Ok(event);
+                                    }
+                                    break;
+                                }
+                            };
+                            if let Some(conversation) = conversation.take() {
+                                conversation.finish(&text, prompt_tokens, generated_text.generated_tokens).await;
+                            }
+                            let token = tokens.pop().expect("window is empty. This is a bug.");
+                            for token in tokens {
+                                if let Some(audit) = &mut audit {
+                                    audit.push(&token);
+                                }
+                                let stream_token = StreamResponse {
+                                    generated_text_so_far: append_text(&mut text_so_far, &token, stream_full_text_limit.0),
+                                    token,
+                                    generated_text: None,
+                                    details: None,
+                                    truncated: false,
+                                };
+
+                                if !progress_only {
+                                    yield Ok(stream_event(stream_token, stream_event_limit.0))
+                                }
+                                if let Some(progress) = progress.as_mut().and_then(StreamProgressTracker::sent) {
+                                    yield Ok(progress_event(progress))
+                                }
+                            }
+
+                            // Timings
+                            let timings = RequestTimings::new(start_time, queued, start, Instant::now(), generated_text.generated_tokens);
+                            let finish_reason = finish_reason(&generated_text, router_limit);
+
+                            // Token details
+                            let details = match details {
+                                true => Some(StreamDetails {
+                                    finish_reason: finish_reason.clone(),
+                                    generated_tokens: generated_text.generated_tokens,
+                                    seed: generated_text.seed,
+                                    matched_stop,
+                                    token_count_mismatch,
+                                    estimated_cost: usage.estimate(prompt_tokens, generated_text.generated_tokens, timings.inference),
+                                }),
+                                false => None,
+                            };
+
+                            // Tracing metadata and metrics
+                            timings.record(&span);
+                            span.record("seed", format!("{:?}", generated_text.seed));
+                            tracing::info!(parent: &span, "Output: {}", generated_text.text);
+                            metrics::increment_counter!("tgi_request_success", "backend" => backend.as_str());
+                            metrics::histogram!("tgi_request_generated_tokens", generated_text.generated_tokens as f64);
+                            usage.record(
+                                &request_headers,
+                                prompt_tokens,
+                                generated_text.generated_tokens,
+                                timings.inference,
+                                start_time,
+                                Ok(finish_reason.clone()),
+                            );
+                            request_log.tokens(prompt_tokens, generated_text.generated_tokens);
+                            request_log.stream_end();
+
+                            // StreamResponse
+                            end_reached = true;
+
+                            let mut output_text = text;
+                            if let Some(prompt) = add_prompt {
+                                output_text = prompt + &output_text;
+                            }
+                            if let Some(audit) = &mut audit {
+                                audit.push(&token);
+                                audit.succeed(parameters, output_text.clone(), finish_reason);
+                            }
+
+                            let stream_token = StreamResponse {
+                                generated_text_so_far: append_text(&mut text_so_far, &token, stream_full_text_limit.0),
+                                token,
+                                generated_text: Some(output_text),
+                                details,
+                                truncated: false,
+                            };
+
+                            yield Ok(stream_event(stream_token, stream_event_limit.0));
+                            break;
                         }
                     }
-                },
+                }
                 // yield error
                 Err(err) => {
                     error = true;
-                    yield Ok(Event::from(err));
+                    request_log.error(err.error_code());
+                    usage.record(&request_headers, prompt_tokens, handle.generated_tokens(), Duration::ZERO, start_time, Err(&err));
+                    if let Some(audit) = &mut audit {
+                        audit.fail(&err);
+                    }
+                    for event in abort_events(err, handle.generated_tokens(), &draining) {
+                        yield Ok(event);
+                    }
+                    break;
                 }
             }
-            // Check if generation reached the end
-            // Skip if we already sent an error
-            if !end_reached && !error {
-                let err = InferError::IncompleteGeneration;
-                metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
-                tracing::error!("{err}");
-                yield Ok(Event::from(err));
-            }
-        } else {
-            let err = InferError::from(ValidationError::BestOfStream);
-            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        }
+        // Check if generation reached the end
+        // Skip if we already sent an error
+        if !end_reached && !error {
+            let err = InferError::IncompleteGeneration;
+            metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
             tracing::error!("{err}");
-            yield Ok(Event::from(err));
+            request_log.error(err.error_code());
+            usage.record(&request_headers, prompt_tokens, handle.generated_tokens(), Duration::ZERO, start_time, Err(&err));
+            if let Some(audit) = &mut audit {
+                audit.fail(&err);
+            }
+            for event in abort_events(err, handle.generated_tokens(), &draining) {
+                yield Ok(event);
+            }
         }
     };
 
-    (headers, Sse::new(stream).keep_alive(KeepAlive::default()))
+    Ok((headers, Sse::new(stream).keep_alive(KeepAlive::default())))
 }
 
-/// Prometheus metrics scrape endpoint
-#[utoipa::path(
-    get,
-    tag = "Text Generation Inference",
-    path = "/metrics",
-    responses((status = 200, description = "Prometheus Metrics", body = String))
-)]
-async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
-    prom_handle.render()
+/// Maximum length in bytes of the `generated_text_so_far` of the streamed responses
+#[derive(Clone, Copy, Debug)]
+struct StreamFullTextLimit(usize);
+
+/// Maximum size in bytes of the streamed events
+#[derive(Clone, Copy, Debug)]
+struct StreamEventLimit(Option<usize>);
+
+/// Server-sent event of a streamed response
+fn stream_event(response: StreamResponse, limit: Option<usize>) -> Event {
+    match stream_event_data(response, limit) {
+        Ok(data) => Event::default().data(data),
+        Err(err) => {
+            tracing::error!("Could not serialize streamed response: {err}");
+            metrics::increment_counter!("tgi_request_failure", "err" => "serialization");
+            Event::from(ErrorResponse::new(
+                format!("Could not serialize streamed response: {err}"),
+                "serialization",
+            ))
+        }
+    }
 }
 
-/// Serving method
-#[allow(clippy::too_many_arguments)]
-pub async fn run(
-    compat_return_full_text: bool,
-    max_concurrent_requests: usize,
-    max_best_of: usize,
-    max_stop_sequences: usize,
-    max_input_length: usize,
-    max_total_tokens: usize,
-    max_batch_size: usize,
-    max_waiting_tokens: usize,
-    client: ShardedClient,
-    tokenizer: Tokenizer,
-    validation_workers: usize,
-    addr: SocketAddr,
-    allow_origin: Option<AllowOrigin>,
-) {
-    // OpenAPI documentation
-    #[derive(OpenApi)]
-    #[openapi(
-        paths(
-            generate,
-            generate_stream,
-            metrics,
-        ),
-        components(
-            schemas(
-                GenerateRequest,
-                GenerateParameters,
-                PrefillToken,
-                Token,
+/// Serialize a streamed response
+/// The optional fields of the responses larger than `limit` bytes are dropped: some proxies only
+/// buffer a limited size per event
+fn stream_event_data(
+    mut response: StreamResponse,
+    limit: Option<usize>,
+) -> Result<String, serde_json::Error> {
+    let data = serde_json::to_string(&response)?;
+    match limit {
+        Some(limit) if data.len() > limit => {
+            tracing::warn!(
+                "Streamed event of {} bytes is larger than {limit} bytes, dropping its optional fields",
+                data.len()
+            );
+            metrics::increment_counter!("tgi_stream_event_truncated");
+            response.generated_text_so_far = None;
+            response.truncated = true;
+            serde_json::to_string(&response)
+        }
+        _ => Ok(data),
+    }
+}
+
+/// Server-sent events of an error ending a started stream
+/// The error event is followed by an `aborted` event if the server aborted the stream
+fn abort_events(err: InferError, generated_tokens: u32, draining: &Draining) -> Vec<Event> {
+    let aborted = stream_aborted(&err, generated_tokens, draining.get());
+    let mut events = vec![Event::from(err)];
+    if let Some(aborted) = aborted {
+        metrics::increment_counter!("tgi_stream_aborted", "reason" => aborted.reason.as_str());
+        events.push(
+            Event::default()
+                .event("aborted")
+                .data(json!(aborted).to_string()),
+        );
+    }
+    events
+}
+
+fn stream_aborted(
+    err: &InferError,
+    generated_tokens: u32,
+    draining: bool,
+) -> Option<StreamAborted> {
+    err.abort_reason(draining).map(|reason| StreamAborted {
+        reason,
+        generated_tokens,
+        error_type: err.error_code().to_string(),
+    })
+}
+
+/// Server-sent event of a queue notification, with the request id to cancel the request
+fn status_event(status: &str, id: u64) -> Event {
+    Event::default()
+        .event("status")
+        .data(json!({ "status": status, "id": id }).to_string())
+}
+
+/// Server-sent event of the progress of a stream with a `progress_interval_tokens`
+fn progress_event(progress: StreamProgress) -> Event {
+    Event::default()
+        .event("progress")
+        .data(json!(progress).to_string())
+}
+
+/// Append the text of a streamed token to the text sent so far
+/// Returns the text to send with the token, or None once it is longer than `limit`: the whole text
+/// is sent with each token, so long generations would need quadratic bandwidth
+fn append_text(text_so_far: &mut Option<String>, token: &Token, limit: usize) -> Option<String> {
+    let text = text_so_far.as_mut()?;
+    text.push_str(&token.text);
+    if text.len() > limit {
+        *text_so_far = None;
+        return None;
+    }
+    Some(text.clone())
+}
+
+/// Pick the backend of a request, using the `x-backend` header as an override
+/// An unhealthy canary backend can be probed by the request
+fn route(infer: &Infer, request_headers: &HeaderMap, context: &mut RequestContext) -> Backend {
+    infer.route_request(requested_backend(request_headers), context)
+}
+
+fn requested_backend(request_headers: &HeaderMap) -> Option<&str> {
+    request_headers
+        .get("x-backend")
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Durations of the stages of a generated request
+///
+/// The instants are taken by the handler, the queue and the batching task, which do not always
+/// agree on their order, e.g. for an entry requeued after a failed batch: the durations saturate
+/// at zero instead of panicking.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct RequestTimings {
+    total: Duration,
+    validation: Duration,
+    queue: Duration,
+    inference: Duration,
+    per_token: Duration,
+}
+
+impl RequestTimings {
+    /// Timings of a request received at `start_time`, queued after its validation and started in
+    /// a batch at `start`, whose generation ended at `now`
+    fn new(
+        start_time: Instant,
+        queued: Instant,
+        start: Instant,
+        now: Instant,
+        generated_tokens: u32,
+    ) -> Self {
+        let inference = now.saturating_duration_since(start);
+        Self {
+            total: now.saturating_duration_since(start_time),
+            validation: queued.saturating_duration_since(start_time),
+            queue: start.saturating_duration_since(queued),
+            inference,
+            per_token: mean_time_per_token(inference, generated_tokens),
+        }
+    }
+
+    /// Timing headers of `/generate`, in integer milliseconds
+    fn insert_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("x-compute-time", millis_header(self.total));
+        headers.insert("x-total-time", millis_header(self.total));
+        headers.insert("x-validation-time", millis_header(self.validation));
+        headers.insert("x-queue-time", millis_header(self.queue));
+        headers.insert("x-inference-time", millis_header(self.inference));
+        headers.insert("x-time-per-token", millis_header(self.per_token));
+    }
+
+    /// Record the timings in the span of the request and in the duration histograms
+    fn record(&self, span: &tracing::Span) {
+        span.record("total_time", format!("{:?}", self.total));
+        span.record("validation_time", format!("{:?}", self.validation));
+        span.record("queue_time", format!("{:?}", self.queue));
+        span.record("inference_time", format!("{:?}", self.inference));
+        span.record("time_per_token", format!("{:?}", self.per_token));
+        metrics::histogram!("tgi_request_duration", self.total);
+        metrics::histogram!("tgi_request_validation_duration", self.validation);
+        metrics::histogram!("tgi_request_queue_duration", self.queue);
+        metrics::histogram!("tgi_request_inference_duration", self.inference);
+        metrics::histogram!("tgi_request_mean_time_per_token_duration", self.per_token);
+    }
+}
+
+/// Header value of a duration in milliseconds
+fn millis_header(duration: Duration) -> HeaderValue {
+    HeaderValue::from(u64::try_from(duration.as_millis()).unwrap_or(u64::MAX))
+}
+
+/// Use the `x-request-deadline-ms` header as `deadline_ms` if the parameter is not set
+fn set_deadline_from_headers(headers: &HeaderMap, parameters: &mut GenerateParameters) {
+    if parameters.deadline_ms.is_none() {
+        parameters.deadline_ms = headers
+            .get("x-request-deadline-ms")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+    }
+}
+
+/// Get the status of a generation
+#[utoipa::path(
+    get,
+    tag = "Text Generation Inference",
+    path = "/generation/{id}",
+    params(("id" = u64, Path, description = "Request id returned in the `x-request-id` header")),
+    responses(
+        (status = 200, description = "Generation status", body = GenerationStatus),
+        (status = 404, description = "Unknown request id", body = ErrorResponse,
+            example = json ! ({"error": "Request not found"})),
+    )
+)]
+#[instrument(skip(infer))]
+async fn generation_status(
+    infer: Extension<Infer>,
+    Path(id): Path<u64>,
+) -> Result<Json<GenerationStatus>, (StatusCode, Json<ErrorResponse>)> {
+    infer
+        .status(id)
+        .await
+        .map(Json)
+        .ok_or_else(request_not_found)
+}
+
+/// Cancel a generation
+#[utoipa::path(
+    delete,
+    tag = "Text Generation Inference",
+    path = "/generation/{id}",
+    params(("id" = u64, Path, description = "Request id returned in the `x-request-id` header")),
+    responses(
+        (status = 200, description = "Generation status after cancellation", body = GenerationStatus),
+        (status = 404, description = "Unknown request id", body = ErrorResponse,
+            example = json ! ({"error": "Request not found"})),
+    )
+)]
+#[instrument(skip(infer))]
+async fn cancel_generation(
+    infer: Extension<Infer>,
+    Path(id): Path<u64>,
+) -> Result<Json<GenerationStatus>, (StatusCode, Json<ErrorResponse>)> {
+    infer
+        .cancel(id)
+        .await
+        .map(Json)
+        .ok_or_else(request_not_found)
+}
+
+fn request_not_found() -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse::new(
+            "Request not found".to_string(),
+            "not_found",
+        )),
+    )
+}
+
+/// Submit a generation job and return without waiting for its generation
+///
+/// The job takes a concurrency permit while it is queued or running. Its final status is POSTed to
+/// its `callback_url` if it is set
+///
+/// With `--job-log-path`, a job that is not batched yet survives a router restart with
+/// `--restore-queue`, under the same id. The requests of the other routes are not restored
+#[utoipa::path(
+    post,
+    tag = "Text Generation Inference",
+    path = "/jobs",
+    request_body = JobRequest,
+    responses(
+        (status = 202, description = "Job submitted", body = JobStatus),
+        (status = 404, description = "The job API is disabled", body = ErrorResponse,
+            example = json ! ({"error": "The job API is disabled"})),
+        (status = 403, description = "Request blocked", body = ErrorResponse,
+            example = json ! ({"error": "Request blocked"})),
+        (status = 429, description = "Model is overloaded or the job store is full", body = ErrorResponse,
+            example = json ! ({"error": "Model is overloaded"})),
+        (status = 422, description = "Input validation error", body = ErrorResponse,
+            example = json ! ({"error": "Input validation error"})),
+        (status = 503, description = "Backend is unavailable", body = ErrorResponse,
+            example = json ! ({"error": "Backend is unavailable"})),
+    )
+)]
+#[instrument(
+    skip(
+        infer,
+        jobs,
+        callbacks,
+        usage,
+        audit_log,
+        output_hook,
+        job_log,
+        request_log,
+        request_headers
+    ),
+    fields(backend)
+)]
+#[allow(clippy::too_many_arguments)]
+async fn submit_job(
+    infer: Extension<Infer>,
+    jobs: Extension<Jobs>,
+    callbacks: Extension<Callbacks>,
+    usage: Extension<UsageRecorder>,
+    audit_log: Extension<AuditLog>,
+    output_hook: Extension<OutputHook>,
+    job_log: Extension<JobLog>,
+    request_log: Extension<RequestLog>,
+    request_headers: HeaderMap,
+    req: StrictJson<JobRequest>,
+) -> Result<(StatusCode, HeaderMap, Json<JobStatus>), (StatusCode, HeaderMap, Json<ErrorResponse>)>
+{
+    let start_time = Instant::now();
+    let JobRequest {
+        request: mut req,
+        callback_url,
+    } = req.0;
+    let mut context = RequestContext::default();
+    set_deadline_from_headers(&request_headers, &mut req.parameters);
+    let api_key = api_key(&request_headers);
+    infer.authorize_force_queue(api_key.as_deref(), &mut req.parameters);
+    infer.apply_limits(api_key.as_deref(), req.model.as_deref(), &mut context);
+    context.api_key_id = api_key.as_deref().map(api_key_id);
+    let job = QueuedJob::new(
+        req,
+        callback_url,
+        context.api_key_id.clone(),
+        context.limits,
+    );
+
+    let started = start_job(
+        &infer,
+        &jobs,
+        &callbacks,
+        &usage,
+        &audit_log,
+        &output_hook,
+        &job_log,
+        job,
+        None,
+        context,
+        requested_backend(&request_headers),
+        start_time,
+    )
+    .await;
+    match started {
+        Ok((status, backend)) => {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-backend", HeaderValue::from_static(backend.as_str()));
+            headers.insert("x-request-id", HeaderValue::from(status.id));
+            Ok((StatusCode::ACCEPTED, headers, Json(status)))
+        }
+        Err(StartJobError::Infer(err)) => {
+            request_log.error(err.error_code());
+            Err(err.into())
+        }
+        Err(StartJobError::Store(err)) => {
+            tracing::warn!("{err}");
+            request_log.error("overloaded");
+            Err(jobs_error(err))
+        }
+    }
+}
+
+/// Reason a job was not started
+enum StartJobError {
+    /// Validation and admission errors
+    Infer(InferError),
+    Store(JobsError),
+}
+
+/// Validate and enqueue the request of a job, then spawn the task generating it
+/// The accepted job is written to the job log before its request is enqueued, except if it is
+/// restored from the log with the id `restored`. The jobs of the audited API keys are audited like their
+/// generations of the other routes
+#[allow(clippy::too_many_arguments)]
+async fn start_job(
+    infer: &Infer,
+    jobs: &Jobs,
+    callbacks: &Callbacks,
+    usage: &UsageRecorder,
+    audit_log: &AuditLog,
+    output_hook: &OutputHook,
+    job_log: &JobLog,
+    job: QueuedJob,
+    restored: Option<u64>,
+    mut context: RequestContext,
+    requested_backend: Option<&str>,
+    start_time: Instant,
+) -> Result<(JobStatus, Backend), StartJobError> {
+    let span = tracing::Span::current();
+    let api_key_id = job.api_key_id.clone();
+    let mut req = job.request.clone();
+    let mut prepared = infer.prepare(&mut req, &mut context);
+    if prepared.is_ok() && req.parameters.best_of.unwrap_or(1) > 1 {
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        prepared = Err(InferError::from(ValidationError::BestOfJob));
+    }
+    let mut callback = None;
+    if let Some(url) = job.callback_url.as_deref().filter(|_| prepared.is_ok()) {
+        match callbacks.check(url) {
+            Ok(url) => callback = Some((callbacks.clone(), url)),
+            Err(err) => {
+                metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                prepared = Err(InferError::from(err));
+            }
+        }
+    }
+    if let Err(err) = prepared {
+        usage.record_key(api_key_id, 0, 0, Duration::ZERO, start_time, Err(&err));
+        return Err(StartJobError::Infer(err));
+    }
+    // Heartbeats are only useful to streaming clients
+    req.parameters.heartbeat = false;
+
+    let mut add_prompt = None;
+    if req.parameters.return_full_text.unwrap_or(false) {
+        add_prompt = Some(req.inputs.clone());
+    }
+    let details = req.parameters.details;
+
+    let handle = match restored {
+        Some(id) => infer.register_id(id),
+        None => infer.register(),
+    };
+    infer.start_session(&req, &mut context);
+    let backend = infer.route_request(requested_backend, &mut context);
+    span.record("backend", backend.as_str());
+    // Written to the audit log when the job ends, None if the API key is not audited
+    let mut audit = audit_log.start(api_key_id.as_deref(), false);
+    if let Some(audit) = &mut audit {
+        audit.set_request_id(handle.id);
+    }
+
+    // A crash after the request is enqueued restores the job
+    let logged = restored.is_none() && job_log.queued(handle.id, job).await;
+    // Validation and admission errors are returned right away
+    let stream = match infer.generate_stream(req, context, handle.clone()).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            if logged {
+                job_log.dispatched(handle.id).await;
+            }
+            usage.record_key(api_key_id, 0, 0, Duration::ZERO, start_time, Err(&err));
+            if let Some(audit) = &mut audit {
+                audit.fail(&err);
+            }
+            return Err(StartJobError::Infer(err));
+        }
+    };
+    // Dropping the stream cancels the generation
+    if let Err(err) = jobs.insert(handle.clone(), api_key_id.clone()) {
+        if logged {
+            job_log.dispatched(handle.id).await;
+        }
+        return Err(StartJobError::Store(err));
+    }
+    let status = jobs
+        .status(handle.id)
+        .expect("job was just inserted. This is a bug.");
+
+    let job = run_job(
+        jobs.clone(),
+        output_hook.clone(),
+        usage.clone(),
+        api_key_id,
+        audit,
+        handle,
+        stream,
+        add_prompt,
+        details,
+        callback,
+        start_time,
+        backend,
+        job_log.clone(),
+        logged || restored.is_some(),
+    );
+    tokio::spawn(job.instrument(span));
+    Ok((status, backend))
+}
+
+/// Delay before starting again a restored job rejected because the router is full
+const RESTORE_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Start the jobs of the job log that were not dispatched before the router stopped, in log
+/// order once the backend is ready, with their id reserved by `Infer::reserve_ids`
+/// Their deadline and their duration run from their submission. The jobs rejected because the
+/// router or the job store is full are retried
+#[allow(clippy::too_many_arguments)]
+async fn restore_jobs(
+    infer: Infer,
+    jobs: Jobs,
+    callbacks: Callbacks,
+    usage: UsageRecorder,
+    audit_log: AuditLog,
+    output_hook: OutputHook,
+    job_log: JobLog,
+    restored: Vec<(u64, QueuedJob)>,
+) {
+    infer.wait_ready().await;
+    for (id, job) in restored {
+        let span = tracing::info_span!("restore_job", id, backend = tracing::field::Empty);
+        loop {
+            let age = job.age();
+            let mut job = job.clone();
+            // A passed deadline fails the job once batched, and tells its callback
+            job.request.parameters.deadline_ms = job
+                .request
+                .parameters
+                .deadline_ms
+                .map(|deadline_ms| deadline_ms.saturating_sub(age.as_millis() as u64).max(1));
+            let context = RequestContext {
+                api_key_id: job.api_key_id.clone(),
+                limits: job.limits,
+                ..RequestContext::default()
+            };
+            let started = start_job(
+                &infer,
+                &jobs,
+                &callbacks,
+                &usage,
+                &audit_log,
+                &output_hook,
+                &job_log,
+                job,
+                Some(id),
+                context,
+                None,
+                Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+            )
+            .instrument(span.clone())
+            .await;
+            match started {
+                Ok(_) => {
+                    tracing::info!("Restored job {id} of the job log");
+                    break;
+                }
+                Err(StartJobError::Infer(InferError::Overloaded { .. }))
+                | Err(StartJobError::Store(JobsError::Full(_))) => {
+                    tokio::time::sleep(RESTORE_RETRY_INTERVAL).await;
+                }
+                Err(StartJobError::Infer(err)) => {
+                    tracing::warn!("Could not restore job {id} of the job log: {err}");
+                    job_log.dispatched(id).await;
+                    break;
+                }
+                Err(StartJobError::Store(err)) => {
+                    tracing::warn!("Could not restore job {id} of the job log: {err}");
+                    job_log.dispatched(id).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Generate the tokens of a job and store them as the post-generation hook approves them
+#[allow(clippy::too_many_arguments)]
+async fn run_job(
+    jobs: Jobs,
+    output_hook: OutputHook,
+    usage: UsageRecorder,
+    api_key_id: Option<String>,
+    mut audit: Option<AuditEntry>,
+    handle: Arc<RequestHandle>,
+    mut stream: impl Stream<Item = Result<InferStreamResponse, InferError>> + Unpin,
+    add_prompt: Option<String>,
+    details: bool,
+    callback: Option<(Callbacks, Url)>,
+    start_time: Instant,
+    backend: Backend,
+    job_log: JobLog,
+    mut logged: bool,
+) {
+    let mut window = output_hook.stream();
+    // Stored with the result so that attached streams send them with the generated text
+    let mut last_tokens = vec![];
+
+    let mut responses = vec![];
+    let generation = async {
+        while let Some(response) = stream.next().await {
+            // The job is not restored anymore once its request is batched
+            if logged && !matches!(response, Ok(InferStreamResponse::Queued)) {
+                logged = false;
+                job_log.dispatched(handle.id).await;
+            }
+            // The audit records the tokens approved by the post-generation hook, as stored
+            match &response {
+                Ok(InferStreamResponse::Token(token)) => {
+                    let tokens = window.push(token.clone()).await?;
+                    if let Some(audit) = &mut audit {
+                        tokens.iter().for_each(|token| audit.push(token));
+                    }
+                    jobs.push(handle.id, tokens)
+                }
+                Ok(InferStreamResponse::End { token, .. }) => {
+                    last_tokens = window.finish(token.clone()).await?;
+                    if let Some(audit) = &mut audit {
+                        last_tokens.iter().for_each(|token| audit.push(token));
+                    }
+                }
+                _ => {}
+            }
+            let end = matches!(response, Ok(InferStreamResponse::End { .. }));
+            responses.push(response);
+            if end {
+                break;
+            }
+        }
+        let mut response = accumulate(tokio_stream::iter(responses), &handle).await?;
+        check_output(&output_hook, std::iter::once(&mut response)).await?;
+        Ok::<_, InferError>(response)
+    };
+
+    let result = match generation.await {
+        Ok(mut response) => {
+            let total_time = start_time.elapsed();
+            metrics::increment_counter!("tgi_request_success", "backend" => backend.as_str());
+            metrics::histogram!("tgi_request_duration", total_time);
+            metrics::histogram!(
+                "tgi_request_generated_tokens",
+                response.total_generated_tokens as f64
+            );
+            let inference_time = response.start.elapsed();
+            usage.record_key(
+                api_key_id.clone(),
+                response.input_length,
+                response.total_generated_tokens,
+                inference_time,
+                start_time,
+                Ok(response.finish_reason()),
+            );
+
+            let details = details.then(|| Details {
+                estimated_cost: usage.estimate(
+                    response.input_length,
+                    response.total_generated_tokens,
+                    inference_time,
+                ),
+                ..response_details(&mut response, None, false)
+            });
+            let finish_reason = response.finish_reason();
+            let mut output_text = response.generated_text.text;
+            if let Some(prompt) = add_prompt {
+                output_text = prompt + &output_text;
+            }
+            if let Some(audit) = &mut audit {
+                audit.succeed(response.parameters, output_text.clone(), finish_reason);
+            }
+            Ok(GenerateResponse {
+                generated_text: output_text,
+                details,
+                continued_from: None,
+                warning: None,
+            })
+        }
+        Err(err) => {
+            usage.record_key(
+                api_key_id.clone(),
+                handle.input_length(),
+                handle.generated_tokens(),
+                Duration::ZERO,
+                start_time,
+                Err(&err),
+            );
+            if let Some(audit) = &mut audit {
+                audit.fail(&err);
+            }
+            Err(ErrorResponse::from(&err))
+        }
+    };
+    if logged {
+        job_log.dispatched(handle.id).await;
+    }
+    // The audit record is written before the result can be read
+    drop(audit);
+    jobs.finish(handle.id, last_tokens, result);
+
+    if let Some((callbacks, url)) = callback {
+        if let Some(status) = jobs.status(handle.id) {
+            callbacks.send(url, &status);
+        }
+    }
+}
+
+/// Get the status of a job, with its response once it is completed
+#[utoipa::path(
+    get,
+    tag = "Text Generation Inference",
+    path = "/jobs/{id}",
+    params(("id" = u64, Path, description = "Job id returned by `POST /jobs`")),
+    responses(
+        (status = 200, description = "Job status", body = JobStatus),
+        (status = 404, description = "Unknown or expired job id", body = ErrorResponse,
+            example = json ! ({"error": "Job not found"})),
+    )
+)]
+#[instrument(skip(jobs, request_headers))]
+async fn job_status(
+    jobs: Extension<Jobs>,
+    Path(id): Path<u64>,
+    request_headers: HeaderMap,
+) -> Result<Json<JobStatus>, (StatusCode, Json<ErrorResponse>)> {
+    check_job_owner(&jobs, id, &request_headers)?;
+    jobs.status(id).map(Json).ok_or_else(job_not_found)
+}
+
+/// Get the response of a completed job
+///
+/// Supports single `Range` requests so that the downloads of large responses can be resumed
+#[utoipa::path(
+    get,
+    tag = "Text Generation Inference",
+    path = "/jobs/{id}/result",
+    params(
+        ("id" = u64, Path, description = "Job id returned by `POST /jobs`"),
+        ("Range" = Option<String>, Header, description = "Bytes of the response to send, e.g. `bytes=1048576-`"),
+    ),
+    responses(
+        (status = 200, description = "Response of the job", body = GenerateResponse),
+        (status = 206, description = "Requested bytes of the response of the job", body = GenerateResponse),
+        (status = 404, description = "Unknown or expired job id", body = ErrorResponse,
+            example = json ! ({"error": "Job not found"})),
+        (status = 409, description = "The job is not completed", body = ErrorResponse,
+            example = json ! ({"error": "Job is not completed"})),
+        (status = 416, description = "The range is outside of the response"),
+    )
+)]
+#[instrument(skip(jobs, request_headers))]
+async fn job_result(
+    jobs: Extension<Jobs>,
+    Path(id): Path<u64>,
+    request_headers: HeaderMap,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    check_job_owner(&jobs, id, &request_headers)?;
+    let status = jobs.status(id).ok_or_else(job_not_found)?;
+    if status.status != RequestStatus::Completed {
+        let (_, Json(mut err)) = job_not_found();
+        err.error = "Job is not completed".to_string();
+        err.error_type = "not_completed".to_string();
+        return Err((StatusCode::CONFLICT, Json(err)));
+    }
+    let response = jobs.response(id).ok_or_else(job_not_found)?;
+
+    let (json, spilled) = match response {
+        JobResponse::Memory(response) => (serde_json::to_vec(&response).unwrap_or_default(), None),
+        JobResponse::Spilled(spilled) => (vec![], Some(spilled)),
+    };
+    let len = spilled
+        .as_ref()
+        .map_or(json.len() as u64, |spilled| spilled.response_bytes);
+    let range = request_headers
+        .get(http::header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| byte_range(range, len));
+    let (status_code, range) = match range {
+        None => (StatusCode::OK, 0..len),
+        Some(Ok(range)) => (StatusCode::PARTIAL_CONTENT, range),
+        Some(Err(())) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                http::header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes */{len}")).unwrap(),
+            );
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+        }
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    headers.insert(
+        http::header::ACCEPT_RANGES,
+        HeaderValue::from_static("bytes"),
+    );
+    headers.insert(
+        http::header::CONTENT_LENGTH,
+        HeaderValue::from(range.end - range.start),
+    );
+    if status_code == StatusCode::PARTIAL_CONTENT {
+        headers.insert(
+            http::header::CONTENT_RANGE,
+            HeaderValue::from_str(&format!("bytes {}-{}/{len}", range.start, range.end - 1))
+                .unwrap(),
+        );
+    }
+
+    let body = match spilled {
+        None => boxed(Full::from(
+            json[range.start as usize..range.end as usize].to_vec(),
+        )),
+        // Streamed from disk instead of being read in memory
+        Some(spilled) => {
+            let mut file = tokio::fs::File::open(&spilled.response_path)
+                .await
+                .map_err(|_| job_not_found())?;
+            file.seek(SeekFrom::Start(range.start))
+                .await
+                .map_err(|_| job_not_found())?;
+            boxed(StreamBody::new(ReaderStream::new(
+                file.take(range.end - range.start),
+            )))
+        }
+    };
+    Ok((status_code, headers, body).into_response())
+}
+
+/// Range of a `Range: bytes=<start>-<end>` header in a body of `len` bytes
+/// None if the header is ignored: the multipart ranges are not supported and an invalid range,
+/// e.g. ending before its start, is ignored as RFC 9110 requires
+fn byte_range(range: &str, len: u64) -> Option<Result<Range<u64>, ()>> {
+    let (start, end) = range.strip_prefix("bytes=")?.trim().split_once('-')?;
+    if end.contains(',') {
+        return None;
+    }
+    let range = match (start.is_empty(), end.is_empty()) {
+        // Last bytes
+        (true, false) => {
+            let suffix: u64 = end.parse().ok()?;
+            len.saturating_sub(suffix)..len
+        }
+        (false, _) => {
+            let start: u64 = start.parse().ok()?;
+            let end = match end.is_empty() {
+                true => len,
+                false => {
+                    let end: u64 = end.parse().ok()?;
+                    if end < start {
+                        return None;
+                    }
+                    end.saturating_add(1).min(len)
+                }
+            };
+            start..end
+        }
+        (true, true) => return None,
+    };
+    match range.start < range.end {
+        true => Some(Ok(range)),
+        false => Some(Err(())),
+    }
+}
+
+/// Stream the tokens of a job using Server-Sent Events
+///
+/// The tokens generated before the stream is attached are sent first
+#[utoipa::path(
+    get,
+    tag = "Text Generation Inference",
+    path = "/jobs/{id}/stream",
+    params(("id" = u64, Path, description = "Job id returned by `POST /jobs`")),
+    responses(
+        (status = 200, description = "Generated Text", body = StreamResponse,
+            content_type = "text/event-stream"),
+        (status = 404, description = "Unknown or expired job id", body = ErrorResponse,
+            example = json ! ({"error": "Job not found"})),
+    )
+)]
+#[instrument(skip(jobs, stream_event_limit, request_headers))]
+async fn stream_job(
+    jobs: Extension<Jobs>,
+    stream_event_limit: Extension<StreamEventLimit>,
+    Path(id): Path<u64>,
+    request_headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, Json<ErrorResponse>)> {
+    check_job_owner(&jobs, id, &request_headers)?;
+    let mut update = jobs.update(id, 0).ok_or_else(job_not_found)?;
+
+    let stream = async_stream::stream! {
+        let mut sent = 0;
+        loop {
+            if let Some(spilled) = update.spilled.take() {
+                match spilled.load().await {
+                    Ok((tokens, response)) => {
+                        update.tokens = tokens.into_iter().skip(sent).collect();
+                        update.result = Some(Ok(response));
+                    }
+                    // The job was evicted
+                    Err(_) => {
+                        let (_, Json(err)) = job_not_found();
+                        yield Ok(Event::from(err));
+                        break;
+                    }
+                }
+            }
+            let mut tokens = std::mem::take(&mut update.tokens);
+            sent += tokens.len();
+            let last = match &update.result {
+                Some(Ok(_)) => tokens.pop(),
+                _ => None,
+            };
+            for token in tokens {
+                yield Ok(stream_event(job_token(token, None), stream_event_limit.0));
+            }
+            match update.result.take() {
+                None => {}
+                Some(Ok(response)) => {
+                    if let Some(token) = last {
+                        yield Ok(stream_event(job_token(token, Some(response)), stream_event_limit.0));
+                    }
+                    break;
+                }
+                Some(Err(err)) => {
+                    yield Ok(Event::from(err));
+                    break;
+                }
+            }
+
+            // The sender is dropped if the job is evicted
+            let _ = update.updates.changed().await;
+            update = match jobs.update(id, sent) {
+                Some(update) => update,
+                None => {
+                    let (_, Json(err)) = job_not_found();
+                    yield Ok(Event::from(err));
+                    break;
+                }
+            };
+        }
+    };
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Streamed response of a job token, the last one has the response of the job
+fn job_token(token: Token, response: Option<GenerateResponse>) -> StreamResponse {
+    let (generated_text, details) = match response {
+        None => (None, None),
+        Some(response) => (
+            Some(response.generated_text),
+            response.details.map(|details| StreamDetails {
+                finish_reason: details.finish_reason,
+                generated_tokens: details.generated_tokens,
+                seed: details.seed,
+                matched_stop: details.matched_stop,
+                token_count_mismatch: details.token_count_mismatch,
+                estimated_cost: details.estimated_cost,
+            }),
+        ),
+    };
+    StreamResponse {
+        token,
+        generated_text,
+        details,
+        generated_text_so_far: None,
+        truncated: false,
+    }
+}
+
+/// Cancel a queued or running job, or delete a finished job
+///
+/// Running jobs are cancelled after the current decode step
+#[utoipa::path(
+    delete,
+    tag = "Text Generation Inference",
+    path = "/jobs/{id}",
+    params(("id" = u64, Path, description = "Job id returned by `POST /jobs`")),
+    responses(
+        (status = 200, description = "Job status before its deletion or after its cancellation", body = JobStatus),
+        (status = 404, description = "Unknown or expired job id", body = ErrorResponse,
+            example = json ! ({"error": "Job not found"})),
+    )
+)]
+#[instrument(skip(infer, jobs, request_headers))]
+async fn delete_job(
+    infer: Extension<Infer>,
+    jobs: Extension<Jobs>,
+    Path(id): Path<u64>,
+    request_headers: HeaderMap,
+) -> Result<Json<JobStatus>, (StatusCode, Json<ErrorResponse>)> {
+    check_job_owner(&jobs, id, &request_headers)?;
+    let status = jobs.status(id).ok_or_else(job_not_found)?;
+    match status.status {
+        RequestStatus::Queued | RequestStatus::Running => {
+            infer.cancel(id).await;
+            jobs.status(id).map(Json).ok_or_else(job_not_found)
+        }
+        _ => {
+            jobs.remove(id);
+            Ok(Json(status))
+        }
+    }
+}
+
+/// The jobs submitted with another API key than the one of the request are unknown
+fn check_job_owner(
+    jobs: &Jobs,
+    id: u64,
+    request_headers: &HeaderMap,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    let owner = api_key(request_headers).as_deref().map(api_key_id);
+    match jobs.owned_by(id, owner.as_deref()) {
+        true => Ok(()),
+        false => Err(job_not_found()),
+    }
+}
+
+fn job_not_found() -> (StatusCode, Json<ErrorResponse>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(ErrorResponse::new("Job not found".to_string(), "not_found")),
+    )
+}
+
+fn jobs_error(err: JobsError) -> (StatusCode, HeaderMap, Json<ErrorResponse>) {
+    let (status_code, error_type) = match err {
+        JobsError::Disabled => (StatusCode::NOT_FOUND, "not_found"),
+        JobsError::Full(_) => (StatusCode::TOO_MANY_REQUESTS, "overloaded"),
+    };
+    (
+        status_code,
+        HeaderMap::new(),
+        Json(ErrorResponse::new(err.to_string(), error_type)),
+    )
+}
+
+/// Get the drain status of the router
+#[utoipa::path(
+    get,
+    tag = "Admin",
+    path = "/admin/drain",
+    responses(
+        (status = 200, description = "Drain status", body = DrainStatus),
+        (status = 401, description = "Missing or invalid admin API key", body = ErrorResponse,
+            example = json ! ({"error": "Unauthorized"})),
+    )
+)]
+#[instrument(skip_all)]
+async fn drain_status(
+    infer: Extension<Infer>,
+    draining: Extension<Draining>,
+    admin_api_key: Extension<AdminApiKey>,
+    request_headers: HeaderMap,
+) -> Result<Json<DrainStatus>, (StatusCode, Json<ErrorResponse>)> {
+    authorize_admin(&admin_api_key, &request_headers)?;
+    Ok(Json(current_drain_status(&infer, &draining)))
+}
+
+/// Stop accepting new generation requests
+/// The deploy tooling can poll `GET /admin/drain` until no request is queued or running
+#[utoipa::path(
+    post,
+    tag = "Admin",
+    path = "/admin/drain",
+    responses(
+        (status = 200, description = "Drain status", body = DrainStatus),
+        (status = 401, description = "Missing or invalid admin API key", body = ErrorResponse,
+            example = json ! ({"error": "Unauthorized"})),
+    )
+)]
+#[instrument(skip_all)]
+async fn drain(
+    infer: Extension<Infer>,
+    draining: Extension<Draining>,
+    admin_api_key: Extension<AdminApiKey>,
+    request_headers: HeaderMap,
+) -> Result<Json<DrainStatus>, (StatusCode, Json<ErrorResponse>)> {
+    authorize_admin(&admin_api_key, &request_headers)?;
+    if draining.start() {
+        tracing::warn!("Draining: new generation requests are rejected");
+    }
+    Ok(Json(current_drain_status(&infer, &draining)))
+}
+
+/// Accept new generation requests again
+#[utoipa::path(
+    post,
+    tag = "Admin",
+    path = "/admin/undrain",
+    responses(
+        (status = 200, description = "Drain status", body = DrainStatus),
+        (status = 401, description = "Missing or invalid admin API key", body = ErrorResponse,
+            example = json ! ({"error": "Unauthorized"})),
+    )
+)]
+#[instrument(skip_all)]
+async fn undrain(
+    infer: Extension<Infer>,
+    draining: Extension<Draining>,
+    admin_api_key: Extension<AdminApiKey>,
+    request_headers: HeaderMap,
+) -> Result<Json<DrainStatus>, (StatusCode, Json<ErrorResponse>)> {
+    authorize_admin(&admin_api_key, &request_headers)?;
+    if draining.stop() {
+        tracing::warn!("Drain cancelled: new generation requests are accepted");
+    }
+    Ok(Json(current_drain_status(&infer, &draining)))
+}
+
+/// Re-submit a request captured when it failed during generation
+/// The captured request keeps its seed and is generated with details
+#[utoipa::path(
+    post,
+    tag = "Admin",
+    path = "/admin/replay/{capture_id}",
+    params(("capture_id" = String, Path, description = "Capture id, logged when the request failed")),
+    responses(
+        (status = 200, description = "Generated Text", body = GenerateResponse),
+        (status = 401, description = "Missing or invalid admin API key", body = ErrorResponse,
+            example = json ! ({"error": "Unauthorized"})),
+        (status = 404, description = "Unknown, expired or redacted capture", body = ErrorResponse,
+            example = json ! ({"error": "Capture 1680000000000-0 is unknown, expired or redacted"})),
+        (status = 424, description = "Generation Error", body = ErrorResponse,
+            example = json ! ({"error": "Request failed during generation"})),
+    )
+)]
+#[instrument(skip(
+    infer,
+    cache,
+    usage,
+    audit_log,
+    trace_context,
+    output_hook,
+    request_log,
+    replay_log,
+    admin_api_key,
+    request_headers
+))]
+#[allow(clippy::too_many_arguments)]
+async fn replay(
+    infer: Extension<Infer>,
+    cache: Extension<ResponseCache>,
+    usage: Extension<UsageRecorder>,
+    audit_log: Extension<AuditLog>,
+    trace_context: Extension<TraceContext>,
+    output_hook: Extension<OutputHook>,
+    request_log: Extension<RequestLog>,
+    replay_log: Extension<ReplayLog>,
+    admin_api_key: Extension<AdminApiKey>,
+    Path(capture_id): Path<String>,
+    request_headers: HeaderMap,
+) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, HeaderMap, Json<ErrorResponse>)> {
+    authorize_admin(&admin_api_key, &request_headers)
+        .map_err(|(status, error)| (status, HeaderMap::new(), error))?;
+    let capture = replay_log.get(capture_id.clone()).await;
+    let request = capture.as_ref().and_then(|capture| capture.request());
+    let request = request.ok_or_else(|| {
+        metrics::increment_counter!("tgi_request_failure", "err" => "not_found");
+        (
+            StatusCode::NOT_FOUND,
+            HeaderMap::new(),
+            Json(ErrorResponse::new(
+                format!("Capture {capture_id} is unknown, expired or redacted"),
+                "not_found",
+            )),
+        )
+    })?;
+    metrics::increment_counter!("tgi_replay_request");
+    tracing::info!("Replaying capture {capture_id}");
+
+    generate(
+        infer,
+        cache,
+        usage,
+        audit_log,
+        trace_context,
+        output_hook,
+        request_log,
+        request_headers,
+        RequestContext::default(),
+        StrictJson(request),
+    )
+    .await
+}
+
+/// Snapshot of the queued requests of each backend, without their inputs
+/// Only the first requests of each queue are listed
+#[utoipa::path(
+    get,
+    tag = "Admin",
+    path = "/admin/queue",
+    responses(
+        (status = 200, description = "Queue snapshot", body = [QueueStatus]),
+        (status = 401, description = "Missing or invalid admin API key", body = ErrorResponse,
+            example = json ! ({"error": "Unauthorized"})),
+    )
+)]
+#[instrument(skip_all)]
+async fn queue_status(
+    infer: Extension<Infer>,
+    admin_api_key: Extension<AdminApiKey>,
+    request_headers: HeaderMap,
+) -> Result<Json<Vec<QueueStatus>>, (StatusCode, Json<ErrorResponse>)> {
+    authorize_admin(&admin_api_key, &request_headers)?;
+    Ok(Json(infer.queue_status().await))
+}
+
+/// Streams open on the router, and the clients with the most open streams
+#[utoipa::path(
+    get,
+    tag = "Admin",
+    path = "/admin/connections",
+    responses(
+        (status = 200, description = "Open streams", body = ConnectionStatus),
+        (status = 401, description = "Missing or invalid admin API key", body = ErrorResponse,
+            example = json ! ({"error": "Unauthorized"})),
+    )
+)]
+#[instrument(skip_all)]
+async fn connection_status(
+    stream_connections: Extension<StreamConnections>,
+    admin_api_key: Extension<AdminApiKey>,
+    request_headers: HeaderMap,
+) -> Result<Json<ConnectionStatus>, (StatusCode, Json<ErrorResponse>)> {
+    authorize_admin(&admin_api_key, &request_headers)?;
+    Ok(Json(stream_connections.status()))
+}
+
+/// Shards of each connected backend with their recent response times
+/// A shard slower than the others by `slow_shard_factor` is marked `slow`
+#[utoipa::path(
+    get,
+    tag = "Admin",
+    path = "/admin/shards",
+    responses(
+        (status = 200, description = "Shard statuses", body = [ShardStatus]),
+        (status = 401, description = "Missing or invalid admin API key", body = ErrorResponse,
+            example = json ! ({"error": "Unauthorized"})),
+    )
+)]
+#[instrument(skip_all)]
+async fn shard_status(
+    infer: Extension<Infer>,
+    admin_api_key: Extension<AdminApiKey>,
+    request_headers: HeaderMap,
+) -> Result<Json<Vec<ShardStatus>>, (StatusCode, Json<ErrorResponse>)> {
+    authorize_admin(&admin_api_key, &request_headers)?;
+    Ok(Json(infer.shard_status()))
+}
+
+/// Result of the last golden prompt check
+#[utoipa::path(
+    get,
+    tag = "Text Generation Inference",
+    path = "/health/selftest",
+    responses(
+        (status = 200, description = "The golden prompt generated its expected output", body = SelfTestResult),
+        (status = 404, description = "No golden prompt is configured", body = ErrorResponse,
+            example = json ! ({"error": "No golden prompt is configured"})),
+        (status = 500, description = "The golden prompt did not generate its expected output", body = SelfTestResult),
+        (status = 503, description = "The golden prompt was not checked yet", body = ErrorResponse,
+            example = json ! ({"error": "The golden prompt was not checked yet"})),
+    )
+)]
+#[instrument(skip_all)]
+async fn selftest_status(selftest: Extension<SelfTest>) -> Response {
+    selftest_response(&selftest, selftest.result())
+}
+
+/// Generate the golden prompt again and compare its output
+#[utoipa::path(
+    post,
+    tag = "Admin",
+    path = "/admin/selftest",
+    responses(
+        (status = 200, description = "The golden prompt generated its expected output", body = SelfTestResult),
+        (status = 401, description = "Missing or invalid admin API key", body = ErrorResponse,
+            example = json ! ({"error": "Unauthorized"})),
+        (status = 404, description = "No golden prompt is configured", body = ErrorResponse,
+            example = json ! ({"error": "No golden prompt is configured"})),
+        (status = 500, description = "The golden prompt did not generate its expected output", body = SelfTestResult),
+    )
+)]
+#[instrument(skip_all)]
+async fn run_selftest(
+    selftest: Extension<SelfTest>,
+    admin_api_key: Extension<AdminApiKey>,
+    request_headers: HeaderMap,
+) -> Response {
+    if let Err(err) = authorize_admin(&admin_api_key, &request_headers) {
+        return err.into_response();
+    }
+    selftest_response(&selftest, selftest.run().await)
+}
+
+fn selftest_response(selftest: &SelfTest, result: Option<SelfTestResult>) -> Response {
+    match result {
+        Some(result) if result.passed => Json(result).into_response(),
+        Some(result) => (StatusCode::INTERNAL_SERVER_ERROR, Json(result)).into_response(),
+        None => {
+            let (status, error, error_type) = match selftest.enabled() {
+                true => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "The golden prompt was not checked yet",
+                    "unavailable",
+                ),
+                false => (
+                    StatusCode::NOT_FOUND,
+                    "No golden prompt is configured",
+                    "not_found",
+                ),
+            };
+            let error = ErrorResponse::new(error.to_string(), error_type);
+            (status, Json(error)).into_response()
+        }
+    }
+}
+
+fn current_drain_status(infer: &Infer, draining: &Draining) -> DrainStatus {
+    let (queued, running) = infer.request_counts();
+    DrainStatus {
+        draining: draining.get(),
+        queued,
+        running,
+    }
+}
+
+/// API key of the admin routes, which are disabled if it is not set
+#[derive(Clone, Debug)]
+struct AdminApiKey(Option<String>);
+
+fn authorize_admin(
+    admin_api_key: &AdminApiKey,
+    request_headers: &HeaderMap,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    match (&admin_api_key.0, api_key(request_headers)) {
+        (Some(admin_api_key), Some(api_key)) if *admin_api_key == api_key => Ok(()),
+        _ => {
+            metrics::increment_counter!("tgi_admin_unauthorized");
+            Err((
+                StatusCode::UNAUTHORIZED,
+                Json(ErrorResponse::new(
+                    "Unauthorized".to_string(),
+                    "unauthorized",
+                )),
+            ))
+        }
+    }
+}
+
+/// List the prompt templates
+#[utoipa::path(
+    get,
+    tag = "Text Generation Inference",
+    path = "/templates",
+    responses((status = 200, description = "Prompt templates", body = [TemplateInfo]))
+)]
+async fn templates(templates: Extension<Templates>) -> Json<Vec<TemplateInfo>> {
+    Json(templates.list())
+}
+
+/// List the parameter presets
+#[utoipa::path(
+    get,
+    tag = "Text Generation Inference",
+    path = "/presets",
+    responses((status = 200, description = "Parameter presets", body = BTreeMap<String, Preset>))
+)]
+async fn presets(presets: Extension<Presets>) -> Json<BTreeMap<String, Preset>> {
+    Json(presets.list())
+}
+
+/// Router information and effective configuration
+#[utoipa::path(
+    get,
+    tag = "Text Generation Inference",
+    path = "/info",
+    responses((status = 200, description = "Router information", body = Info))
+)]
+async fn info(
+    info: Extension<Info>,
+    infer: Extension<Infer>,
+    vocab_check: Extension<VocabCheck>,
+    request_headers: HeaderMap,
+) -> Json<Info> {
+    Json(Info {
+        limits: infer.limits(api_key(&request_headers).as_deref()),
+        circuit_breakers: infer.circuit_breakers(),
+        cache_utilization: infer.cache_utilization(),
+        vocab: vocab_check.status(),
+        ..info.0
+    })
+}
+
+/// Prometheus metrics scrape endpoint
+#[utoipa::path(
+    get,
+    tag = "Text Generation Inference",
+    path = "/metrics",
+    responses((status = 200, description = "Prometheus Metrics", body = String))
+)]
+async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
+    prom_handle.render()
+}
+
+/// Options of the router, with the defaults of the `text-generation-router` command line
+pub struct ServerOptions {
+    /// Default `return_full_text` of the requests sent to `/`
+    pub compat_return_full_text: bool,
+    pub max_concurrent_requests: usize,
+    pub max_best_of: usize,
+    pub max_stop_sequences: usize,
+    pub max_input_length: usize,
+    pub max_total_tokens: usize,
+    pub max_batch_size: usize,
+    pub max_waiting_tokens: usize,
+    pub prefill_chunk_tokens: Option<u32>,
+    pub max_batch_total_tokens: Option<u32>,
+    pub min_downgraded_new_tokens: u32,
+    pub cache_utilization_threshold: f64,
+    pub batching_policy: BatchingPolicy,
+    pub all_latency_sensitive: bool,
+    pub force_watermark: bool,
+    /// Backend of `model_id`
+    pub client: BackendConnection,
+    /// Backend receiving `canary_ratio` of the requests of `model_id`
+    pub canary_client: Option<BackendConnection>,
+    pub canary_ratio: f32,
+    /// Tokenizer of `model_id`
+    pub tokenizer: Tokenizer,
+    pub validation_workers: usize,
+    /// CORS allowed origins, any origin if None
+    pub allow_origin: Option<AllowOrigin>,
+    pub response_cache_entries: usize,
+    pub response_cache_bytes: usize,
+    pub queue_heartbeat_interval: Option<Duration>,
+    pub lenient_json: bool,
+    /// Name of the default model, in `/info` and the usage records
+    pub model_id: String,
+    pub usage_sink: Option<String>,
+    pub cost_model: Option<CostModel>,
+    pub pre_generation_hook_url: Option<String>,
+    pub pre_generation_hook_timeout: Duration,
+    pub pre_generation_hook_fail_open: bool,
+    pub post_generation_redact_patterns: Vec<String>,
+    pub post_generation_reject_patterns: Vec<String>,
+    pub post_generation_window: usize,
+    pub prompt_templates_dir: Option<PathBuf>,
+    pub parameter_presets_path: Option<PathBuf>,
+    pub health_check_cache: Duration,
+    pub access_log_sample_rate: f64,
+    pub access_log_slow_threshold: Duration,
+    pub trace_requests: bool,
+    /// Ratio of the requests without a `traceparent` whose token-level spans are recorded
+    pub trace_sample_ratio: f64,
+    pub faults: Option<FaultConfig>,
+    pub max_stream_full_text_bytes: usize,
+    pub max_stream_event_bytes: Option<usize>,
+    pub max_queue_wait: Option<Duration>,
+    pub force_queue_api_keys: HashSet<String>,
+    pub max_conversation_tokens: u32,
+    pub conversation_ttl: Duration,
+    /// The `/admin` routes are disabled if None
+    pub admin_api_key: Option<String>,
+    /// Key of the hash giving the API key ids, random if None so that the ids change on restart
+    pub api_key_id_secret: Option<String>,
+    /// Document the `/admin` routes in the OpenAPI documentation
+    pub admin_api_doc: bool,
+    /// The API routes are nested under this prefix, such as `/llm/v1`
+    pub path_prefix: Option<String>,
+    /// The `/` routes are not served
+    pub disable_root_route: bool,
+    /// `/health` is also served at the root when `path_prefix` is set
+    pub root_health_route: bool,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub auto_requeue: bool,
+    pub replay: Option<ReplayConfig>,
+    pub limit_profiles_path: Option<PathBuf>,
+    /// The generations of the audited API keys are not written if None. Needs `admin_api_key`
+    pub audit: Option<AuditConfig>,
+    pub golden_prompt_path: Option<PathBuf>,
+    pub golden_prompt_fail_readiness: bool,
+    pub model_loading_policy: LoadingPolicy,
+    pub debug_batching: bool,
+    pub max_jobs: usize,
+    pub max_job_bytes: usize,
+    pub job_ttl: Duration,
+    pub callbacks: CallbackConfig,
+    pub coalesce_requests: bool,
+    pub normalize_input: bool,
+    pub normalize_input_strip_chars: Option<Vec<String>>,
+    /// Other served models, selected by the `model` of the requests
+    pub models: Vec<ServedModel>,
+    pub speculative_draft_model: Option<String>,
+    pub speculative_target_model: Option<String>,
+    pub speculative_tokens: u32,
+    pub max_stream_connections_per_ip: Option<usize>,
+    pub max_stream_connections_per_api_key: Option<usize>,
+    pub job_spill: Option<SpillConfig>,
+    /// Write-ahead log of the jobs whose request is not batched yet
+    pub job_log: Option<PathBuf>,
+    /// Start again the jobs of `job_log` that were not batched before the router stopped
+    pub restore_queue: bool,
+    pub latency_target: Option<LatencyTarget>,
+    pub max_dry_runs_per_second: u32,
+    pub reserved_probe_permits: usize,
+    pub max_excess_tokens: u32,
+    /// Limits of the sessions of the requests with a `session_id`, 0 disables them
+    pub max_sessions: usize,
+    pub max_session_bytes: usize,
+    /// Worker threads of a dedicated runtime running the batching tasks, which share the runtime
+    /// of the router if 0
+    pub batching_threads: usize,
+    /// Batch the requests in arrival order into batches of `max_batch_size`, for reproducible
+    /// outputs at the cost of throughput
+    pub deterministic_batching: bool,
+    /// Vocabulary of `tokenizer`, compared by the caller with the one of the backend once it is
+    /// connected, see `VocabCheck::check`
+    pub vocab_check: VocabCheck,
+}
+
+impl ServerOptions {
+    /// Options serving `model_id` with `tokenizer` and the backend of `client`
+    pub fn new(model_id: String, tokenizer: Tokenizer, client: BackendConnection) -> Self {
+        let vocab_check = VocabCheck::new(tokenizer.clone(), VocabMismatchPolicy::Fail);
+        Self {
+            compat_return_full_text: false,
+            max_concurrent_requests: 128,
+            max_best_of: 2,
+            max_stop_sequences: 4,
+            max_input_length: 1000,
+            max_total_tokens: 1512,
+            max_batch_size: 32,
+            max_waiting_tokens: 20,
+            prefill_chunk_tokens: None,
+            max_batch_total_tokens: None,
+            min_downgraded_new_tokens: 16,
+            cache_utilization_threshold: 0.9,
+            batching_policy: BatchingPolicy::Throughput,
+            all_latency_sensitive: false,
+            force_watermark: false,
+            client,
+            canary_client: None,
+            canary_ratio: 0.0,
+            tokenizer,
+            validation_workers: 2,
+            allow_origin: None,
+            response_cache_entries: 0,
+            response_cache_bytes: 64 * 1024 * 1024,
+            queue_heartbeat_interval: None,
+            lenient_json: false,
+            model_id,
+            usage_sink: None,
+            cost_model: None,
+            pre_generation_hook_url: None,
+            pre_generation_hook_timeout: Duration::from_secs(1),
+            pre_generation_hook_fail_open: false,
+            post_generation_redact_patterns: vec![],
+            post_generation_reject_patterns: vec![],
+            post_generation_window: 8,
+            prompt_templates_dir: None,
+            parameter_presets_path: None,
+            health_check_cache: Duration::from_secs(1),
+            access_log_sample_rate: 1.0,
+            access_log_slow_threshold: Duration::from_secs(10),
+            trace_requests: false,
+            trace_sample_ratio: 1.0,
+            faults: None,
+            max_stream_full_text_bytes: 16384,
+            max_stream_event_bytes: None,
+            max_queue_wait: None,
+            force_queue_api_keys: HashSet::new(),
+            max_conversation_tokens: 1000,
+            conversation_ttl: Duration::from_secs(3600),
+            admin_api_key: None,
+            api_key_id_secret: None,
+            admin_api_doc: false,
+            path_prefix: None,
+            disable_root_route: false,
+            root_health_route: false,
+            circuit_breaker: CircuitBreakerConfig {
+                threshold: 5,
+                probe_interval: Duration::from_secs(5),
+            },
+            auto_requeue: false,
+            replay: None,
+            limit_profiles_path: None,
+            audit: None,
+            golden_prompt_path: None,
+            golden_prompt_fail_readiness: false,
+            model_loading_policy: LoadingPolicy::Reject,
+            debug_batching: false,
+            max_jobs: 1000,
+            max_job_bytes: 64 * 1024 * 1024,
+            job_ttl: Duration::from_secs(600),
+            callbacks: CallbackConfig {
+                secret: None,
+                allowed_hosts: vec![],
+                max_attempts: 5,
+                initial_backoff: Duration::from_secs(1),
+                timeout: Duration::from_secs(10),
+                max_concurrent: 16,
+            },
+            coalesce_requests: false,
+            normalize_input: false,
+            normalize_input_strip_chars: None,
+            models: vec![],
+            speculative_draft_model: None,
+            speculative_target_model: None,
+            speculative_tokens: 4,
+            max_stream_connections_per_ip: None,
+            max_stream_connections_per_api_key: None,
+            job_spill: None,
+            job_log: None,
+            restore_queue: false,
+            latency_target: None,
+            max_dry_runs_per_second: 10,
+            reserved_probe_permits: 1,
+            max_excess_tokens: 16,
+            max_sessions: 0,
+            max_session_bytes: 64 * 1024 * 1024,
+            batching_threads: 0,
+            deterministic_batching: false,
+            vocab_check,
+        }
+    }
+}
+
+/// Routes of the router with their state
+/// The router is not bound, so that it can be merged into another [`Router`]
+pub struct RouterApp {
+    router: Router,
+    draining: Draining,
+    usage_writer: Option<JoinHandle<()>>,
+}
+
+impl RouterApp {
+    /// Start the batching and background tasks of the router and build its routes
+    /// Must be called from a Tokio runtime. Installs the global Prometheus recorder, so panics if a
+    /// recorder is already installed
+    pub fn new(options: ServerOptions) -> Self {
+        let ServerOptions {
+            compat_return_full_text,
+            max_concurrent_requests,
+            max_best_of,
+            max_stop_sequences,
+            max_input_length,
+            max_total_tokens,
+            max_batch_size,
+            max_waiting_tokens,
+            prefill_chunk_tokens,
+            max_batch_total_tokens,
+            min_downgraded_new_tokens,
+            cache_utilization_threshold,
+            batching_policy,
+            all_latency_sensitive,
+            force_watermark,
+            client,
+            canary_client,
+            canary_ratio,
+            tokenizer,
+            validation_workers,
+            allow_origin,
+            response_cache_entries,
+            response_cache_bytes,
+            queue_heartbeat_interval,
+            lenient_json,
+            model_id,
+            usage_sink,
+            cost_model,
+            pre_generation_hook_url,
+            pre_generation_hook_timeout,
+            pre_generation_hook_fail_open,
+            post_generation_redact_patterns,
+            post_generation_reject_patterns,
+            post_generation_window,
+            prompt_templates_dir,
+            parameter_presets_path,
+            health_check_cache,
+            access_log_sample_rate,
+            access_log_slow_threshold,
+            trace_requests,
+            trace_sample_ratio,
+            faults,
+            max_stream_full_text_bytes,
+            max_stream_event_bytes,
+            max_queue_wait,
+            force_queue_api_keys,
+            max_conversation_tokens,
+            conversation_ttl,
+            admin_api_key,
+            api_key_id_secret,
+            admin_api_doc,
+            path_prefix,
+            disable_root_route,
+            root_health_route,
+            circuit_breaker,
+            auto_requeue,
+            replay,
+            limit_profiles_path,
+            audit,
+            golden_prompt_path,
+            golden_prompt_fail_readiness,
+            model_loading_policy,
+            debug_batching,
+            max_jobs,
+            max_job_bytes,
+            job_ttl,
+            callbacks,
+            coalesce_requests,
+            normalize_input,
+            normalize_input_strip_chars,
+            models,
+            speculative_draft_model,
+            speculative_target_model,
+            speculative_tokens,
+            max_stream_connections_per_ip,
+            max_stream_connections_per_api_key,
+            job_spill,
+            job_log,
+            restore_queue,
+            latency_target,
+            max_dry_runs_per_second,
+            reserved_probe_permits,
+            max_excess_tokens,
+            max_sessions,
+            max_session_bytes,
+            batching_threads,
+            deterministic_batching,
+            vocab_check,
+        } = options;
+        // OpenAPI documentation
+        #[derive(OpenApi)]
+        #[openapi(
+        paths(
+            generate,
+            dry_run,
+            continue_generation,
+            generate_stream,
+            conversation,
+            conversation_stream,
+            conversation_history,
+            clear_conversation,
+            generation_status,
+            cancel_generation,
+            submit_job,
+            job_status,
+            job_result,
+            stream_job,
+            delete_job,
+            selftest_status,
+            templates,
+            presets,
+            info,
+            metrics,
+        ),
+        components(
+            schemas(
+                GenerateRequest,
+                GenerateParameters,
+                StopConfig,
+                PrefillToken,
+                Token,
                 GenerateResponse,
+                DryRunResponse,
+                ContinueRequest,
+                ConversationRequest,
+                ConversationHistory,
+                Message,
+                Role,
                 BestOfSequence,
                 Details,
+                MatchedStop,
+                EstimatedCost,
+                InputSource,
+                ValidParameters,
                 FinishReason,
                 StreamResponse,
                 StreamDetails,
+                GenerationStatus,
+                JobRequest,
+                JobStatus,
+                RequestStatus,
+                TemplateInfo,
+                Preset,
+                Info,
+                ModelInfo,
+                Config,
+                BatchingPolicy,
+                LoadingPolicy,
+                VocabMismatchPolicy,
+                VocabStatus,
+                Vocab,
+                Limits,
+                CircuitBreakerStatus,
+                CircuitState,
+                CacheUtilization,
+                SelfTestResult,
                 ErrorResponse,
+                OverloadReason,
+                AbortReason,
+                StreamAborted,
+                StreamProgress,
+            )
+        ),
+        tags(
+            (name = "Text Generation Inference", description = "Hugging Face Text Generation Inference API")
+        ),
+        info(
+            title = "Text Generation Inference",
+            license(
+                name = "Apache 2.0",
+                url = "https://www.apache.org/licenses/LICENSE-2.0"
+            )
+        )
+    )]
+        struct ApiDoc;
+
+        // Admin routes, only documented when `admin_api_doc` is set
+        #[derive(OpenApi)]
+        #[openapi(
+        paths(
+            drain_status,
+            drain,
+            undrain,
+            queue_status,
+            connection_status,
+            shard_status,
+            replay,
+            run_selftest
+        ),
+        components(schemas(
+            DrainStatus,
+            QueueStatus,
+            ConnectionStatus,
+            ShardStatus,
+            ClientConnections,
+            QueuedRequest,
+            CacheUtilization,
+            ErrorResponse,
+            GenerateResponse,
+            SelfTestResult
+        )),
+        tags((name = "Admin", description = "Router administration, requires the admin API key"))
+    )]
+        struct AdminApiDoc;
+
+        // Invisible characters stripped from the normalized inputs, checked with the
+        // configuration
+        let normalize_input_strip_chars = normalize_input_strip_chars.unwrap_or_else(|| {
+            DEFAULT_STRIPPED_CHARS
+                .iter()
+                .map(|c| format!("U+{:04X}", *c as u32))
+                .collect()
+        });
+
+        // Effective configuration
+        let config = Config {
+            model_id: model_id.clone(),
+            compat_return_full_text,
+            max_concurrent_requests,
+            max_best_of,
+            max_stop_sequences,
+            max_input_length,
+            max_total_tokens,
+            max_batch_size,
+            max_waiting_tokens,
+            prefill_chunk_tokens,
+            max_batch_total_tokens,
+            min_downgraded_new_tokens,
+            cache_utilization_threshold,
+            batching_policy,
+            all_latency_sensitive,
+            force_watermark,
+            limit_min_batch_size: limit_min_batch_size(max_batch_size),
+            canary: canary_client.is_some(),
+            canary_ratio,
+            validation_workers,
+            response_cache_entries,
+            response_cache_bytes,
+            queue_heartbeat_interval_ms: queue_heartbeat_interval
+                .map(|interval| interval.as_millis() as u64),
+            lenient_json,
+            usage_sink: usage_sink.clone(),
+            cost_per_prefill_token: cost_model
+                .as_ref()
+                .map(|cost_model| cost_model.prefill_token),
+            cost_per_decode_token: cost_model
+                .as_ref()
+                .map(|cost_model| cost_model.decode_token),
+            cost_per_inference_second: cost_model
+                .as_ref()
+                .map(|cost_model| cost_model.inference_second),
+            cost_currency: cost_model
+                .as_ref()
+                .map(|cost_model| cost_model.currency.clone()),
+            pre_generation_hook_url: pre_generation_hook_url.as_deref().map(elide_credentials),
+            pre_generation_hook_timeout_ms: pre_generation_hook_timeout.as_millis() as u64,
+            pre_generation_hook_fail_open,
+            post_generation_redact_patterns: post_generation_redact_patterns.clone(),
+            post_generation_reject_patterns: post_generation_reject_patterns.clone(),
+            post_generation_window,
+            prompt_templates_dir: prompt_templates_dir
+                .as_ref()
+                .map(|dir| dir.display().to_string()),
+            parameter_presets_path: parameter_presets_path
+                .as_ref()
+                .map(|dir| dir.display().to_string()),
+            health_check_cache_ms: health_check_cache.as_millis() as u64,
+            access_log_sample_rate,
+            access_log_slow_threshold_ms: access_log_slow_threshold.as_millis() as u64,
+            trace_requests,
+            trace_sample_ratio,
+            fault_injection: faults.is_some(),
+            max_stream_full_text_bytes,
+            max_stream_event_bytes,
+            max_queue_wait_ms: max_queue_wait
+                .map(|max_queue_wait| max_queue_wait.as_millis() as u64),
+            max_conversation_tokens,
+            conversation_ttl_secs: conversation_ttl.as_secs(),
+            admin_api: admin_api_key.is_some(),
+            path_prefix: path_prefix.clone(),
+            disable_root_route,
+            root_health_route,
+            circuit_breaker_threshold: circuit_breaker.threshold,
+            circuit_breaker_probe_interval_ms: circuit_breaker.probe_interval.as_millis() as u64,
+            auto_requeue,
+            replay_capture_dir: replay
+                .as_ref()
+                .map(|replay| replay.dir.display().to_string()),
+            replay_max_files: replay.as_ref().map_or(0, |replay| replay.max_files),
+            replay_max_file_bytes: replay.as_ref().map_or(0, |replay| replay.max_file_bytes),
+            replay_ttl_secs: replay.as_ref().map_or(0, |replay| replay.ttl.as_secs()),
+            limit_profiles_path: limit_profiles_path
+                .as_ref()
+                .map(|path| path.display().to_string()),
+            audit_log_path: audit.as_ref().map(|audit| audit.path.display().to_string()),
+            audit_log_max_file_bytes: audit.as_ref().map_or(0, |audit| audit.max_file_bytes),
+            audit_log_max_files: audit.as_ref().map_or(0, |audit| audit.max_files),
+            golden_prompt_path: golden_prompt_path
+                .as_ref()
+                .map(|path| path.display().to_string()),
+            golden_prompt_fail_readiness,
+            model_loading_policy,
+            debug_batching,
+            max_jobs,
+            max_job_bytes,
+            job_ttl_secs: job_ttl.as_secs(),
+            job_spill_dir: job_spill
+                .as_ref()
+                .map(|job_spill| job_spill.dir.display().to_string()),
+            job_spill_threshold_bytes: job_spill
+                .as_ref()
+                .map_or(0, |job_spill| job_spill.threshold_bytes),
+            max_job_spill_bytes: job_spill
+                .as_ref()
+                .map_or(0, |job_spill| job_spill.max_bytes),
+            job_log_path: job_log.as_ref().map(|path| path.display().to_string()),
+            restore_queue,
+            callback_signature: callbacks.secret.is_some(),
+            callback_allowed_hosts: callbacks.allowed_hosts.clone(),
+            callback_max_attempts: callbacks.max_attempts,
+            callback_initial_backoff_ms: callbacks.initial_backoff.as_millis() as u64,
+            callback_timeout_ms: callbacks.timeout.as_millis() as u64,
+            max_concurrent_callbacks: callbacks.max_concurrent,
+            coalesce_requests,
+            normalize_input,
+            normalize_input_strip_chars,
+            models: models.iter().map(|model| model.name.clone()).collect(),
+            speculative_target_model: speculative_draft_model
+                .as_ref()
+                .map(|_| speculative_target_model.unwrap_or_else(|| model_id.clone())),
+            speculative_draft_model,
+            speculative_tokens,
+            max_stream_connections_per_ip,
+            max_stream_connections_per_api_key,
+            inter_token_latency_target_ms: latency_target
+                .as_ref()
+                .map(|latency_target| latency_target.target.as_millis() as u64),
+            inter_token_latency_quantile: latency_target
+                .as_ref()
+                .map_or(0.0, |latency_target| latency_target.quantile),
+            latency_controller_proportional_gain: latency_target
+                .as_ref()
+                .map_or(0.0, |latency_target| latency_target.proportional_gain),
+            latency_controller_integral_gain: latency_target
+                .as_ref()
+                .map_or(0.0, |latency_target| latency_target.integral_gain),
+            max_dry_runs_per_second,
+            reserved_probe_permits,
+            max_excess_tokens,
+            max_sessions,
+            max_session_bytes,
+            batching_threads,
+            deterministic_batching,
+            vocab_mismatch_policy: vocab_check.policy(),
+        };
+        if let Err(err) = config.validate() {
+            panic!("Invalid configuration: {err}");
+        }
+        let normalize_input_strip_chars: Vec<char> = config
+            .normalize_input_strip_chars
+            .iter()
+            .filter_map(|code_point| parse_code_point(code_point).ok())
+            .collect();
+        tracing::info!("{config:?}");
+
+        // Speculative decoding, the draft model is not served on its own
+        let (draft, models): (Vec<ServedModel>, Vec<ServedModel>) = models
+            .into_iter()
+            .partition(|model| Some(&model.name) == config.speculative_draft_model.as_ref());
+        let speculative_target_model = config.speculative_target_model.clone();
+        let mut speculation = draft.into_iter().next().map(|draft| {
+        let target_tokenizer = models
+            .iter()
+            .find(|model| Some(&model.name) == speculative_target_model.as_ref())
+            .map_or(&tokenizer, |model| &model.tokenizer);
+        if draft.tokenizer.get_vocab_size(true) != target_tokenizer.get_vocab_size(true) {
+            panic!(
+                "Invalid configuration: the draft model `{}` does not have the vocabulary of the target model",
+                draft.name
+            );
+        }
+        Speculation {
+            draft: draft.client,
+            tokens: speculative_tokens,
+        }
+    });
+
+        // The API key ids of the records, logs and admin routes
+        if let Some(secret) = api_key_id_secret {
+            if !set_api_key_id_secret(&secret) {
+                tracing::warn!("The API key ids were already computed with another secret");
+            }
+        }
+        // Limit profiles
+        let limit_profiles =
+            LimitProfiles::load(limit_profiles_path, max_input_length, max_total_tokens)
+                .expect("Could not load the limit profiles");
+        // Audit of the API keys of the limit profiles
+        let audit_log =
+            AuditLog::new(audit, limit_profiles.clone()).expect("Could not open the audit log");
+        // OpenAPI documentation, with the paths under which the routes are served
+        let mut api_doc = ApiDoc::openapi();
+        if admin_api_doc {
+            api_doc.merge(AdminApiDoc::openapi());
+        }
+        let api_doc = prefix_paths(api_doc, path_prefix.as_deref(), root_health_route);
+
+        let router_info = Info {
+            model_id: model_id.clone(),
+            version: env!("CARGO_PKG_VERSION"),
+            config,
+            limits: limit_profiles.get(None),
+            circuit_breakers: vec![],
+            cache_utilization: vec![],
+            vocab: vocab_check.status(),
+            models: [ModelInfo {
+                name: model_id.clone(),
+                max_input_length,
+                max_total_tokens,
+                max_batch_size,
+                max_concurrent_requests,
+            }]
+            .into_iter()
+            .chain(models.iter().map(|model| ModelInfo {
+                name: model.name.clone(),
+                max_input_length: model.config.max_input_length,
+                max_total_tokens: model.config.max_total_tokens,
+                max_batch_size: model.config.max_batch_size,
+                max_concurrent_requests: model.config.max_concurrent_requests,
+            }))
+            .collect(),
+            paths: api_doc.paths.paths.keys().cloned().collect(),
+        };
+
+        // Prompt templates
+        let prompt_templates =
+            Templates::load(prompt_templates_dir).expect("Could not load the prompt templates");
+        // Parameter presets
+        let parameter_presets =
+            Presets::load(parameter_presets_path).expect("Could not load the parameter presets");
+        #[cfg(unix)]
+        tokio::spawn(reload_on_hangup(
+            prompt_templates.clone(),
+            parameter_presets.clone(),
+            limit_profiles.clone(),
+        ));
+
+        // Create state
+        let normalizer = InputNormalizer::new(normalize_input, normalize_input_strip_chars);
+        // The post-generation hook cannot filter the raw text of the tokens
+        let output_filtered = !post_generation_redact_patterns.is_empty()
+            || !post_generation_reject_patterns.is_empty();
+        let sessions_enabled = max_sessions > 0 && max_session_bytes > 0;
+        let validation = Validation::new(
+            validation_workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequences,
+            max_input_length,
+            max_total_tokens,
+            prompt_templates.clone(),
+            parameter_presets.clone(),
+            limit_profiles.clone(),
+            normalizer.clone(),
+        )
+        .refuse_raw_token_text(output_filtered)
+        .refuse_session_id(!sessions_enabled);
+        // Pre-generation hook
+        let input_hook = pre_generation_hook_url.map(|url| {
+            InputHook::new(
+                Arc::new(WebhookHook::new(url)),
+                pre_generation_hook_timeout,
+                pre_generation_hook_fail_open,
             )
-        ),
-        tags(
-            (name = "Text Generation Inference", description = "Hugging Face Text Generation Inference API")
-        ),
-        info(
-            title = "Text Generation Inference",
-            license(
-                name = "Apache 2.0",
-                url = "https://www.apache.org/licenses/LICENSE-2.0"
+        });
+
+        // Capture of the failed requests, without their payloads if the outputs are redacted
+        let replay_log = ReplayLog::new(replay, !post_generation_redact_patterns.is_empty())
+            .expect("Could not open the replay capture directory");
+
+        // Runtime shared by the batching tasks of all the backends
+        let batching_runtime = (batching_threads > 0).then(|| dedicated_runtime(batching_threads));
+
+        // Other served models, each with its own tokenizer, limits and batching task
+        let models: Vec<(String, Infer)> = models
+            .into_iter()
+            .map(|model| {
+                let config = model.config;
+                let validation = Validation::new(
+                    validation_workers,
+                    model.tokenizer,
+                    max_best_of,
+                    max_stop_sequences,
+                    config.max_input_length,
+                    config.max_total_tokens,
+                    prompt_templates.clone(),
+                    parameter_presets.clone(),
+                    LimitProfiles::new(config.max_input_length, config.max_total_tokens),
+                    normalizer.clone(),
+                )
+                .refuse_raw_token_text(output_filtered)
+                .refuse_session_id(!sessions_enabled);
+                // Label of the metrics of the model, allocated once at startup
+                let backend = Backend::Model(Box::leak(model.name.clone().into_boxed_str()));
+                let infer = Infer::builder(model.client, validation)
+                    .backend(backend)
+                    .max_batch_size(config.max_batch_size)
+                    .max_waiting_tokens(max_waiting_tokens)
+                    .prefill_chunk_tokens(prefill_chunk_tokens)
+                    .max_batch_total_tokens(config.max_batch_total_tokens)
+                    .min_downgraded_new_tokens(min_downgraded_new_tokens)
+                    .cache_utilization_threshold(cache_utilization_threshold)
+                    .batching_policy(batching_policy)
+                    .all_latency_sensitive(all_latency_sensitive)
+                    .force_watermark(force_watermark)
+                    .max_concurrent_requests(config.max_concurrent_requests)
+                    .heartbeat_interval(queue_heartbeat_interval)
+                    .input_hook(input_hook.clone())
+                    .trace_requests(trace_requests)
+                    .faults(faults.clone())
+                    .max_queue_wait(max_queue_wait)
+                    .force_queue_api_keys(force_queue_api_keys.clone())
+                    .circuit_breaker(circuit_breaker)
+                    .auto_requeue(auto_requeue)
+                    .replay_log(replay_log.clone())
+                    .loading_policy(model_loading_policy)
+                    .debug_batching(debug_batching)
+                    .coalesce_requests(coalesce_requests)
+                    .speculation(
+                        match Some(&model.name) == speculative_target_model.as_ref() {
+                            true => speculation.take(),
+                            false => None,
+                        },
+                    )
+                    .latency_target(latency_target)
+                    .reserved_probe_permits(reserved_probe_permits)
+                    .max_excess_tokens(max_excess_tokens)
+                    .session_limits(max_sessions, max_session_bytes)
+                    .batching_runtime(batching_runtime.clone())
+                    .deterministic_batching(deterministic_batching)
+                    .build();
+                (model.name, infer)
+            })
+            .collect();
+
+        let mut builder = Infer::builder(client, validation)
+            .max_batch_size(max_batch_size)
+            .max_waiting_tokens(max_waiting_tokens)
+            .prefill_chunk_tokens(prefill_chunk_tokens)
+            .max_batch_total_tokens(max_batch_total_tokens)
+            .min_downgraded_new_tokens(min_downgraded_new_tokens)
+            .cache_utilization_threshold(cache_utilization_threshold)
+            .batching_policy(batching_policy)
+            .all_latency_sensitive(all_latency_sensitive)
+            .force_watermark(force_watermark)
+            .max_concurrent_requests(max_concurrent_requests)
+            .heartbeat_interval(queue_heartbeat_interval)
+            .input_hook(input_hook)
+            .trace_requests(trace_requests)
+            .faults(faults)
+            .max_queue_wait(max_queue_wait)
+            .force_queue_api_keys(force_queue_api_keys)
+            .circuit_breaker(circuit_breaker)
+            .auto_requeue(auto_requeue)
+            .replay_log(replay_log.clone())
+            .loading_policy(model_loading_policy)
+            .debug_batching(debug_batching)
+            .coalesce_requests(coalesce_requests)
+            .speculation(speculation)
+            .latency_target(latency_target)
+            .reserved_probe_permits(reserved_probe_permits)
+            .max_excess_tokens(max_excess_tokens)
+            .session_limits(max_sessions, max_session_bytes)
+            .batching_runtime(batching_runtime)
+            .deterministic_batching(deterministic_batching);
+        if let Some(canary_client) = canary_client {
+            builder = builder.canary(canary_client, canary_ratio);
+        }
+        let infer = builder.build().with_models(&model_id, models);
+
+        // Post-generation hook
+        let mut output_hook: Option<Arc<dyn PostGenerationHook>> = None;
+        if !post_generation_redact_patterns.is_empty()
+            || !post_generation_reject_patterns.is_empty()
+        {
+            let hook = RegexHook::new(
+                &post_generation_redact_patterns,
+                &post_generation_reject_patterns,
             )
+            .expect("Invalid post-generation pattern");
+            output_hook = Some(Arc::new(hook));
+        }
+        let output_hook = OutputHook::new(output_hook, post_generation_window);
+
+        // Health check
+        let health_check = HealthCheck::new(infer.clone(), health_check_cache);
+
+        // Golden prompt check, run once the router is up and the model is loaded
+        let selftest = SelfTest::load(
+            infer.clone(),
+            golden_prompt_path.as_deref(),
+            golden_prompt_fail_readiness,
         )
-    )]
-    struct ApiDoc;
-
-    // Create state
-    let validation = Validation::new(
-        validation_workers,
-        tokenizer,
-        max_best_of,
-        max_stop_sequences,
-        max_input_length,
-        max_total_tokens,
-    );
-    let infer = Infer::new(
-        client,
-        validation,
-        max_batch_size,
-        max_waiting_tokens,
-        max_concurrent_requests,
-    );
+        .expect("Could not load the golden prompt");
+        if selftest.enabled() {
+            let (infer, selftest) = (infer.clone(), selftest.clone());
+            tokio::spawn(async move {
+                infer.wait_ready().await;
+                selftest.run().await
+            });
+        }
+
+        // Response cache
+        let cache = ResponseCache::new(response_cache_entries, response_cache_bytes);
+
+        // Asynchronous jobs
+        let mut jobs = Jobs::new(max_jobs, max_job_bytes, job_ttl);
+        if let Some(job_spill) = job_spill {
+            jobs = jobs
+                .with_spill(job_spill)
+                .expect("Could not open the job spill directory");
+        }
+        let callbacks = Callbacks::new(callbacks, jobs.clone());
+
+        // Conversation histories
+        let conversations = Conversations::new(
+            Arc::new(MemoryStore::new(conversation_ttl)),
+            max_conversation_tokens,
+        );
+
+        // Usage records
+        let (usage, usage_writer) =
+            UsageRecorder::new(usage_sink, model_id.clone(), cost_model.clone())
+                .expect("Could not open the usage sink");
+
+        // Write-ahead log of the queued jobs
+        let (job_log, restored_jobs) = match job_log {
+            None => (JobLog::default(), vec![]),
+            Some(path) => JobLog::open(&path, restore_queue).expect("Could not open the job log"),
+        };
+        if !restored_jobs.is_empty() {
+            tracing::info!("Restoring {} jobs of the job log", restored_jobs.len());
+            let next_id = restored_jobs.iter().map(|(id, _)| id + 1).max();
+            infer.reserve_ids(next_id.unwrap_or(0));
+            tokio::spawn(restore_jobs(
+                infer.clone(),
+                jobs.clone(),
+                callbacks.clone(),
+                usage.clone(),
+                audit_log.clone(),
+                output_hook.clone(),
+                job_log.clone(),
+                restored_jobs,
+            ));
+        }
+
+        // Prometheus handler
+        let builder = PrometheusBuilder::new();
+        let prom_handle = builder
+            .install_recorder()
+            .expect("failed to install metrics recorder");
+
+        // CORS layer
+        let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
+        let cors_layer = CorsLayer::new()
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([http::header::CONTENT_TYPE])
+            .allow_origin(allow_origin);
 
-    // Prometheus handler
-    let builder = PrometheusBuilder::new();
-    let prom_handle = builder
-        .install_recorder()
-        .expect("failed to install metrics recorder");
-
-    // CORS layer
-    let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
-    let cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers([http::header::CONTENT_TYPE])
-        .allow_origin(allow_origin);
-
-    // Create router
-    let app = Router::new()
-        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", ApiDoc::openapi()))
-        // Base routes
-        .route("/", post(compat_generate))
-        .route("/generate", post(generate))
-        .route("/generate_stream", post(generate_stream))
-        // AWS Sagemaker route
-        .route("/invocations", post(compat_generate))
-        // Base Health route
-        .route("/health", get(health))
+        // Draining flag, also set on graceful shutdown
+        let draining = Draining::default();
+
+        // Create router
+        let mut app = Router::new();
+        if !disable_root_route {
+            app = app.route("/", post(compat_generate));
+        }
+        let mut app = app
+            // Base routes
+            .route("/generate", post(generate))
+            .route("/generate/continue", post(continue_generation))
+            .route("/generate_stream", post(generate_stream))
+            .route("/validate", post(dry_run))
+            .route(
+                "/conversation/:conversation_id",
+                post(conversation)
+                    .get(conversation_history)
+                    .delete(clear_conversation),
+            )
+            .route(
+                "/conversation/:conversation_id/stream",
+                post(conversation_stream),
+            )
+            .route(
+                "/generation/:id",
+                get(generation_status).delete(cancel_generation),
+            )
+            .route("/jobs", post(submit_job))
+            .route("/jobs/:id", get(job_status).delete(delete_job))
+            .route("/jobs/:id/stream", get(stream_job))
+            .route("/jobs/:id/result", get(job_result))
+            // AWS Sagemaker route
+            .route("/invocations", post(compat_generate))
+            // The responses of the routes above can be rendered with camelCase keys
+            .route_layer(middleware::from_fn(profile_middleware))
+            // Base Health route
+            .route("/health", get(health))
+            // Kubernetes probes
+            .route("/health/live", get(live))
+            .route("/health/ready", get(ready))
+            .route("/health/selftest", get(selftest_status))
+            // AWS Sagemaker health route
+            .route("/ping", get(health))
+            // Prompt templates route
+            .route("/templates", get(templates))
+            // Parameter presets route
+            .route("/presets", get(presets))
+            // Router information route
+            .route("/info", get(info))
+            // Prometheus metrics route
+            .route("/metrics", get(metrics))
+            // Admin routes
+            .route("/admin/drain", get(drain_status).post(drain))
+            .route("/admin/undrain", post(undrain))
+            .route("/admin/queue", get(queue_status))
+            .route("/admin/connections", get(connection_status))
+            .route("/admin/shards", get(shard_status))
+            .route("/admin/replay/:capture_id", post(replay))
+            .route("/admin/selftest", post(run_selftest));
         // Inference API health route
-        .route("/", get(health))
-        // AWS Sagemaker health route
-        .route("/ping", get(health))
-        // Prometheus metrics route
-        .route("/metrics", get(metrics))
-        .layer(Extension(compat_return_full_text))
-        .layer(Extension(infer))
-        .layer(Extension(prom_handle))
-        .layer(opentelemetry_tracing_layer())
-        .layer(cors_layer);
-
-    // Run server
+        if !disable_root_route {
+            app = app.route("/", get(health));
+        }
+        if let Some(path_prefix) = &path_prefix {
+            app = Router::new().nest(path_prefix, app);
+            // Load balancers probing the root
+            if root_health_route {
+                app = app.route("/health", get(health));
+            }
+        }
+        let prefixed = |path: &str| format!("{}{path}", path_prefix.as_deref().unwrap_or(""));
+        let app = app
+            .merge(
+                SwaggerUi::new(prefixed("/docs")).url(prefixed("/api-doc/openapi.json"), api_doc),
+            )
+            .layer(Extension(compat_return_full_text))
+            .layer(Extension(infer))
+            .layer(Extension(health_check))
+            .layer(Extension(selftest))
+            .layer(Extension(vocab_check))
+            .layer(Extension(cache))
+            .layer(Extension(jobs))
+            .layer(Extension(job_log))
+            .layer(Extension(callbacks))
+            .layer(Extension(usage))
+            .layer(Extension(replay_log))
+            .layer(Extension(audit_log))
+            .layer(Extension(output_hook))
+            .layer(Extension(prompt_templates))
+            .layer(Extension(parameter_presets))
+            .layer(Extension(conversations))
+            .layer(Extension(LenientJson(lenient_json)))
+            .layer(Extension(StreamFullTextLimit(max_stream_full_text_bytes)))
+            .layer(Extension(StreamEventLimit(max_stream_event_bytes)))
+            .layer(Extension(router_info))
+            .layer(Extension(prom_handle))
+            .layer(Extension(draining.clone()))
+            .layer(Extension(RateLimiter::new(max_dry_runs_per_second)))
+            .layer(Extension(StreamConnections::new(
+                max_stream_connections_per_ip,
+                max_stream_connections_per_api_key,
+            )))
+            .layer(Extension(AdminApiKey(admin_api_key)))
+            .layer(middleware::from_fn_with_state(
+                draining.clone(),
+                drain_middleware,
+            ))
+            .layer(middleware::from_fn_with_state(
+                AccessLog::new(access_log_sample_rate, access_log_slow_threshold, model_id),
+                access_log_middleware,
+            ))
+            .layer(middleware::from_fn_with_state(
+                TraceSampler::new(trace_sample_ratio),
+                trace_context_middleware,
+            ))
+            .layer(opentelemetry_tracing_layer())
+            .layer(cors_layer);
+
+        Self {
+            router: app,
+            draining,
+            usage_writer,
+        }
+    }
+
+    /// Routes of the router, without its graceful shutdown
+    /// The connection limits need the client addresses: the router must be served with
+    /// `into_make_service_with_connect_info::<SocketAddr>()`
+    pub fn into_router(self) -> Router {
+        self.router
+    }
+
+    /// Serve the router on `addr` until SIGINT or SIGTERM, then drain the open requests
+    pub async fn serve(self, addr: SocketAddr) {
+        axum::Server::bind(&addr)
+            // The client addresses are needed by the connection limits
+            .serve(
+                self.router
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            // Wait until all requests are finished to shut down
+            .with_graceful_shutdown(shutdown_signal(self.draining))
+            .await
+            .unwrap();
+
+        // The server and its usage recorders are dropped: flush the remaining usage records
+        if let Some(usage_writer) = self.usage_writer {
+            usage_writer.await.unwrap_or(());
+        }
+    }
+}
+
+/// Paths of the OpenAPI documentation under the `path_prefix`, with `/health` at the root if
+/// `root_health_route` is set
+fn prefix_paths(
+    mut api_doc: utoipa::openapi::OpenApi,
+    path_prefix: Option<&str>,
+    root_health_route: bool,
+) -> utoipa::openapi::OpenApi {
+    let path_prefix = match path_prefix {
+        Some(path_prefix) => path_prefix,
+        None => return api_doc,
+    };
+    let paths = std::mem::take(&mut api_doc.paths.paths);
+    let root_health = paths.get("/health").filter(|_| root_health_route).cloned();
+    api_doc.paths.paths = paths
+        .into_iter()
+        .map(|(path, item)| (format!("{path_prefix}{path}"), item))
+        .chain(root_health.map(|item| ("/health".to_string(), item)))
+        .collect();
+    api_doc
+}
+
+/// Serving method
+pub async fn run(options: ServerOptions, addr: SocketAddr) {
+    RouterApp::new(options).serve(addr).await
+}
+
+/// Serve the health probes until `connected` resolves, while the router starts up
+/// The probes are served at the root and under the `path_prefix` of the router
+pub async fn run_startup_probes(
+    addr: SocketAddr,
+    path_prefix: Option<String>,
+    connected: oneshot::Receiver<()>,
+) {
+    let probes = Router::new()
+        .route("/health/live", get(live))
+        .route("/health/ready", get(not_ready))
+        .route("/health", get(not_ready));
+    let app = match path_prefix {
+        Some(path_prefix) => probes.clone().nest(&path_prefix, probes),
+        None => probes,
+    };
+
     axum::Server::bind(&addr)
         .serve(app.into_make_service())
-        // Wait until all requests are finished to shut down
-        .with_graceful_shutdown(shutdown_signal())
+        .with_graceful_shutdown(async {
+            connected.await.unwrap_or(());
+        })
         .await
         .unwrap();
 }
 
+/// Reload the prompt templates and the parameter presets on SIGHUP
+#[cfg(unix)]
+async fn reload_on_hangup(templates: Templates, presets: Presets, limits: LimitProfiles) {
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("failed to install signal handler");
+    while hangup.recv().await.is_some() {
+        if let Err(err) = templates.reload() {
+            tracing::error!("Could not reload the prompt templates: {err}");
+        }
+        if let Err(err) = presets.reload() {
+            tracing::error!("Could not reload the parameter presets: {err}");
+        }
+        if let Err(err) = limits.reload() {
+            tracing::error!("Could not reload the limit profiles: {err}");
+        }
+    }
+}
+
 /// Shutdown signal handler
-async fn shutdown_signal() {
+/// The router drains so that the requests of the open connections are rejected
+async fn shutdown_signal(draining: Draining) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -583,6 +3935,7 @@ async fn shutdown_signal() {
     }
 
     tracing::info!("signal received, starting graceful shutdown");
+    draining.start();
     opentelemetry::global::shutdown_tracer_provider();
 }
 
@@ -601,29 +3954,1104 @@ impl From<i32> for FinishReason {
 impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
     fn from(err: InferError) -> Self {
         let status_code = match err {
-            InferError::GenerationError(_) => StatusCode::FAILED_DEPENDENCY,
-            InferError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::GenerationError(..) => StatusCode::FAILED_DEPENDENCY,
+            InferError::Overloaded { .. }
+            | InferError::QueueWait { .. }
+            | InferError::ConnectionLimit { .. }
+            | InferError::RateLimit(_) => StatusCode::TOO_MANY_REQUESTS,
             InferError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             InferError::IncompleteGeneration => StatusCode::INTERNAL_SERVER_ERROR,
+            // 499 Client Closed Request
+            InferError::Cancelled => StatusCode::from_u16(499).unwrap(),
+            InferError::BackendOverloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::BackendOom(_) => StatusCode::INSUFFICIENT_STORAGE,
+            InferError::BackendInvalidArgument(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            InferError::BackendUnavailable(_)
+            | InferError::CircuitOpen(_)
+            | InferError::ModelLoading => StatusCode::SERVICE_UNAVAILABLE,
+            InferError::DeadlineExceeded | InferError::ResponseTimeout { .. } => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            InferError::Blocked(_) | InferError::ContentFiltered(_) => StatusCode::FORBIDDEN,
+            InferError::UnknownModel { .. } => StatusCode::NOT_FOUND,
         };
 
-        (
-            status_code,
-            Json(ErrorResponse {
-                error: err.to_string(),
-                error_type: err.error_type().to_string(),
-            }),
-        )
+        (status_code, Json(ErrorResponse::from(&err)))
+    }
+}
+
+/// Errors of the generate routes
+/// The `x-prompt-tokens` header tells the length of the inputs when they are too long
+/// The `Retry-After` header tells when the next probe is sent to a backend whose circuit is open
+impl From<InferError> for (StatusCode, HeaderMap, Json<ErrorResponse>) {
+    fn from(err: InferError) -> Self {
+        let mut headers = HeaderMap::new();
+        match &err {
+            InferError::ValidationError(ValidationError::InputLength(_, input_length)) => {
+                headers.insert("x-prompt-tokens", (*input_length).into());
+            }
+            InferError::CircuitOpen(retry_after) => {
+                let seconds = (retry_after.as_millis() as u64 + 999) / 1000;
+                headers.insert(http::header::RETRY_AFTER, seconds.max(1).into());
+            }
+            _ => {}
+        }
+        let (status_code, json) = err.into();
+        (status_code, headers, json)
+    }
+}
+
+impl From<&InferError> for ErrorResponse {
+    fn from(err: &InferError) -> Self {
+        let (input_length, max_input_length) = match err {
+            InferError::ValidationError(ValidationError::InputLength(
+                max_input_length,
+                input_length,
+            )) => (Some(*input_length), Some(*max_input_length)),
+            InferError::ResponseTimeout { input_length, .. } => {
+                (Some(*input_length as usize), None)
+            }
+            _ => (None, None),
+        };
+        let generated_tokens = match err {
+            InferError::ResponseTimeout {
+                generated_tokens, ..
+            } => Some(*generated_tokens),
+            _ => None,
+        };
+        let estimated_wait_ms = match err {
+            InferError::QueueWait { estimated_wait, .. } => Some(estimated_wait.as_millis() as u64),
+            _ => None,
+        };
+        let (limit, current) = match err {
+            InferError::Overloaded { limit, running } => {
+                (Some(*limit as u64), Some(*running as u64))
+            }
+            InferError::QueueWait {
+                estimated_wait,
+                max_queue_wait,
+            } => (
+                Some(max_queue_wait.as_millis() as u64),
+                Some(estimated_wait.as_millis() as u64),
+            ),
+            InferError::ConnectionLimit { limit, current, .. } => {
+                (Some(*limit as u64), Some(*current as u64))
+            }
+            InferError::RateLimit(limit) => (Some(*limit as u64), None),
+            _ => (None, None),
+        };
+        ErrorResponse {
+            error: err.to_string(),
+            error_type: err.error_code().to_string(),
+            input_length,
+            max_input_length,
+            estimated_wait_ms,
+            reason: err.overload_reason(),
+            limit,
+            current,
+            generated_tokens,
+        }
     }
 }
 
 impl From<InferError> for Event {
     fn from(err: InferError) -> Self {
-        Event::default()
-            .json_data(ErrorResponse {
-                error: err.to_string(),
-                error_type: err.error_type().to_string(),
-            })
+        Event::from(ErrorResponse::from(&err))
+    }
+}
+
+impl From<ErrorResponse> for Event {
+    fn from(err: ErrorResponse) -> Self {
+        match serde_json::to_string(&err) {
+            Ok(data) => Event::default().data(data),
+            // Only the error message is lost
+            Err(_) => Event::default().data(
+                json!({ "error": "Could not serialize error", "error_type": err.error_type })
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infer::GenerationErrorCode;
+    use axum::body::{Body, HttpBody};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use text_generation_client::{MockCall, MockConfig, ShardedClient};
+    use tokenizers::models::wordlevel::WordLevel;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_input_length_error() {
+        let err = InferError::from(ValidationError::InputLength(1000, 1200));
+        let (status_code, headers, Json(response)) =
+            <(StatusCode, HeaderMap, Json<ErrorResponse>)>::from(err);
+
+        assert_eq!(status_code, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(headers.get("x-prompt-tokens").unwrap(), "1200");
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"error":"Input validation error: `inputs` must have less than 1000 tokens. Given: 1200. Set `truncate` to keep only the last tokens of `inputs`","error_type":"validation","input_length":1200,"max_input_length":1000}"#
+        );
+    }
+
+    #[test]
+    fn test_error_without_input_length() {
+        let (_, headers, Json(response)) =
+            <(StatusCode, HeaderMap, Json<ErrorResponse>)>::from(InferError::Cancelled);
+
+        assert!(headers.get("x-prompt-tokens").is_none());
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"error":"Request was cancelled","error_type":"cancelled"}"#
+        );
+    }
+
+    /// Clients match `error_type` to decide whether to retry: changing the mapping breaks them
+    #[test]
+    fn test_error_types() {
+        let cases = [
+            (
+                InferError::GenerationError(GenerationErrorCode::Backend, String::new()),
+                "generation",
+                StatusCode::FAILED_DEPENDENCY,
+            ),
+            (
+                InferError::GenerationError(GenerationErrorCode::MalformedResponse, String::new()),
+                "generation",
+                StatusCode::FAILED_DEPENDENCY,
+            ),
+            (
+                InferError::GenerationError(GenerationErrorCode::ExcessTokens, String::new()),
+                "generation",
+                StatusCode::FAILED_DEPENDENCY,
+            ),
+            (
+                InferError::Overloaded {
+                    limit: 128,
+                    running: 128,
+                },
+                "overloaded",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                InferError::ValidationError(ValidationError::Temperature),
+                "validation",
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                InferError::IncompleteGeneration,
+                "incomplete_generation",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                InferError::Cancelled,
+                "cancelled",
+                StatusCode::from_u16(499).unwrap(),
+            ),
+            (
+                InferError::BackendOverloaded(String::new()),
+                "backend_overloaded",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                InferError::BackendOom(String::new()),
+                "backend_oom",
+                StatusCode::INSUFFICIENT_STORAGE,
+            ),
+            (
+                InferError::BackendInvalidArgument(String::new()),
+                "backend_invalid_argument",
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                InferError::BackendUnavailable(String::new()),
+                "backend_unavailable",
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                InferError::DeadlineExceeded,
+                "deadline_exceeded",
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+            (
+                InferError::Blocked(String::new()),
+                "blocked",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                InferError::ContentFiltered(String::new()),
+                "content_filter",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                InferError::QueueWait {
+                    estimated_wait: Duration::from_secs(1),
+                    max_queue_wait: Duration::from_millis(500),
+                },
+                "overloaded",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                InferError::CircuitOpen(Duration::from_secs(1)),
+                "backend_unavailable",
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                InferError::ModelLoading,
+                "model_loading",
+                StatusCode::SERVICE_UNAVAILABLE,
+            ),
+            (
+                InferError::UnknownModel {
+                    model: String::new(),
+                    available: vec![],
+                },
+                "unknown_model",
+                StatusCode::NOT_FOUND,
+            ),
+            (
+                InferError::ConnectionLimit {
+                    scope: "client IP",
+                    limit: 8,
+                    current: 8,
+                },
+                "connection_limit",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                InferError::RateLimit(10),
+                "rate_limit",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                InferError::ResponseTimeout {
+                    input_length: 5,
+                    generated_tokens: 3,
+                },
+                "response_timeout",
+                StatusCode::GATEWAY_TIMEOUT,
+            ),
+        ];
+
+        // Does not compile when a variant is added without its cases
+        let variant = |err: &InferError| match err {
+            InferError::GenerationError(..) => 0,
+            InferError::Overloaded { .. } => 1,
+            InferError::ValidationError(_) => 2,
+            InferError::IncompleteGeneration => 3,
+            InferError::Cancelled => 4,
+            InferError::BackendOverloaded(_) => 5,
+            InferError::BackendOom(_) => 6,
+            InferError::BackendInvalidArgument(_) => 7,
+            InferError::BackendUnavailable(_) => 8,
+            InferError::DeadlineExceeded => 9,
+            InferError::ResponseTimeout { .. } => 10,
+            InferError::Blocked(_) => 11,
+            InferError::ContentFiltered(_) => 12,
+            InferError::QueueWait { .. } => 13,
+            InferError::CircuitOpen(_) => 14,
+            InferError::ModelLoading => 15,
+            InferError::UnknownModel { .. } => 16,
+            InferError::ConnectionLimit { .. } => 17,
+            InferError::RateLimit(_) => 18,
+        };
+        let variants: HashSet<usize> = cases.iter().map(|(err, ..)| variant(err)).collect();
+        assert_eq!(variants, (0..19).collect());
+
+        for (err, error_type, status_code) in cases {
+            assert_eq!(err.error_code(), error_type);
+            let (status, Json(response)) = <(StatusCode, Json<ErrorResponse>)>::from(err);
+            assert_eq!(response.error_type, error_type);
+            assert_eq!(status, status_code, "{error_type}");
+        }
+    }
+
+    /// Each 429 tells its cause so that clients back off or reduce their concurrency
+    #[test]
+    fn test_overload_reasons() {
+        let cases = [
+            (
+                InferError::Overloaded {
+                    limit: 128,
+                    running: 127,
+                },
+                r#"{"error":"Model is overloaded: 127 of 128 concurrent requests are running","error_type":"overloaded","reason":"concurrency","limit":128,"current":127}"#,
+            ),
+            (
+                InferError::QueueWait {
+                    estimated_wait: Duration::from_secs(2),
+                    max_queue_wait: Duration::from_secs(1),
+                },
+                r#"{"error":"Model is overloaded: estimated queue wait of 2000ms, the limit is 1000ms","error_type":"overloaded","estimated_wait_ms":2000,"reason":"admission","limit":1000,"current":2000}"#,
+            ),
+            (
+                InferError::BackendOverloaded("queue is full".to_string()),
+                r#"{"error":"Backend is overloaded: queue is full","error_type":"backend_overloaded","reason":"backend"}"#,
+            ),
+            (
+                InferError::ConnectionLimit {
+                    scope: "client IP",
+                    limit: 8,
+                    current: 8,
+                },
+                r#"{"error":"Too many concurrent streams: 8 of 8 are open for this client IP","error_type":"connection_limit","reason":"connection_limit","limit":8,"current":8}"#,
+            ),
+            (
+                InferError::RateLimit(10),
+                r#"{"error":"Too many dry runs: the limit is 10 per second","error_type":"rate_limit","reason":"rate_limit","limit":10}"#,
+            ),
+        ];
+
+        for (err, expected) in cases {
+            let (status, Json(response)) = <(StatusCode, Json<ErrorResponse>)>::from(err);
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(serde_json::to_string(&response).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_stream_aborted() {
+        let aborted = stream_aborted(&InferError::DeadlineExceeded, 12, false).unwrap();
+        assert_eq!(
+            serde_json::to_string(&aborted).unwrap(),
+            r#"{"reason":"deadline","generated_tokens":12,"error_type":"deadline_exceeded"}"#
+        );
+
+        // Generations interrupted while the server drains are aborted by its shutdown
+        let aborted = stream_aborted(&InferError::IncompleteGeneration, 3, true).unwrap();
+        assert_eq!(aborted.reason, AbortReason::Shutdown);
+        let aborted = stream_aborted(&InferError::IncompleteGeneration, 3, false).unwrap();
+        assert_eq!(aborted.reason, AbortReason::BackendError);
+        let aborted =
+            stream_aborted(&InferError::ContentFiltered("toxic".to_string()), 0, false).unwrap();
+        assert_eq!(aborted.reason, AbortReason::Moderation);
+
+        // Rejected requests are not aborted
+        assert!(stream_aborted(
+            &InferError::Overloaded {
+                limit: 128,
+                running: 128
+            },
+            0,
+            false
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_byte_range() {
+        assert_eq!(byte_range("bytes=0-99", 1000), Some(Ok(0..100)));
+        assert_eq!(byte_range("bytes=900-", 1000), Some(Ok(900..1000)));
+        assert_eq!(byte_range("bytes=-100", 1000), Some(Ok(900..1000)));
+        // The end is clamped to the length of the body
+        assert_eq!(byte_range("bytes=900-2000", 1000), Some(Ok(900..1000)));
+        assert_eq!(byte_range("bytes=1000-", 1000), Some(Err(())));
+        // Ignored ranges
+        assert_eq!(byte_range("bytes=0-1,5-9", 1000), None);
+        assert_eq!(byte_range("items=0-1", 1000), None);
+        assert_eq!(byte_range("bytes=-", 1000), None);
+        assert_eq!(byte_range("bytes=500-100", 1000), None);
+        assert_eq!(byte_range("bytes=5000-100", 1000), None);
+    }
+
+    #[test]
+    fn test_circuit_open_retry_after() {
+        let (status_code, headers, _) = <(StatusCode, HeaderMap, Json<ErrorResponse>)>::from(
+            InferError::CircuitOpen(Duration::from_millis(1500)),
+        );
+        assert_eq!(status_code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(headers.get(http::header::RETRY_AFTER).unwrap(), "2");
+    }
+
+    #[test]
+    fn test_append_text() {
+        let token = |text: &str| Token {
+            id: 0,
+            text: text.to_string(),
+            logprob: 0.0,
+            special: false,
+        };
+
+        let mut text_so_far = None;
+        assert_eq!(append_text(&mut text_so_far, &token("Hello"), 8), None);
+
+        let mut text_so_far = Some(String::new());
+        assert_eq!(
+            append_text(&mut text_so_far, &token("Hello"), 8).as_deref(),
+            Some("Hello")
+        );
+        // Held back tokens have an empty text
+        assert_eq!(
+            append_text(&mut text_so_far, &token(""), 8).as_deref(),
+            Some("Hello")
+        );
+        assert_eq!(append_text(&mut text_so_far, &token(" world"), 8), None);
+        assert_eq!(append_text(&mut text_so_far, &token("!"), 8), None);
+    }
+
+    #[test]
+    fn test_stream_event_data() {
+        let response = || StreamResponse {
+            token: Token {
+                id: 0,
+                text: " world".to_string(),
+                logprob: 0.0,
+                special: false,
+            },
+            generated_text: None,
+            details: None,
+            generated_text_so_far: Some("Hello world".repeat(10)),
+            truncated: false,
+        };
+
+        let data = stream_event_data(response(), None).unwrap();
+        assert!(data.contains("generated_text_so_far"));
+        assert!(!data.contains("truncated"));
+        assert_eq!(
+            stream_event_data(response(), Some(data.len())).unwrap(),
+            data
+        );
+
+        let truncated = stream_event_data(response(), Some(data.len() - 1)).unwrap();
+        assert!(!truncated.contains("generated_text_so_far"));
+        assert!(truncated.contains(r#""truncated":true"#));
+        assert!(truncated.contains(r#""text":" world""#));
+    }
+
+    #[test]
+    fn test_request_timings() {
+        let start_time = Instant::now();
+        let at = |millis| start_time + Duration::from_millis(millis);
+
+        let timings = RequestTimings::new(start_time, at(10), at(30), at(130), 4);
+        assert_eq!(timings.total, Duration::from_millis(130));
+        assert_eq!(timings.validation, Duration::from_millis(10));
+        assert_eq!(timings.queue, Duration::from_millis(20));
+        assert_eq!(timings.inference, Duration::from_millis(100));
+        assert_eq!(timings.per_token, Duration::from_millis(25));
+
+        let mut headers = HeaderMap::new();
+        timings.insert_headers(&mut headers);
+        assert_eq!(headers.get("x-compute-time").unwrap(), "130");
+        assert_eq!(headers.get("x-total-time").unwrap(), "130");
+        assert_eq!(headers.get("x-validation-time").unwrap(), "10");
+        assert_eq!(headers.get("x-queue-time").unwrap(), "20");
+        assert_eq!(headers.get("x-inference-time").unwrap(), "100");
+        assert_eq!(headers.get("x-time-per-token").unwrap(), "25");
+    }
+
+    #[test]
+    fn test_request_timings_out_of_order() {
+        let start_time = Instant::now();
+        let at = |millis| start_time + Duration::from_millis(millis);
+
+        // Queued before it was received, started before it was queued
+        let timings = RequestTimings::new(at(50), at(40), at(20), at(60), 0);
+        assert_eq!(timings.total, Duration::from_millis(10));
+        assert_eq!(timings.validation, Duration::ZERO);
+        assert_eq!(timings.queue, Duration::ZERO);
+        assert_eq!(timings.inference, Duration::from_millis(40));
+        assert_eq!(timings.per_token, Duration::ZERO);
+
+        // Ended before everything else
+        let timings = RequestTimings::new(at(10), at(20), at(30), start_time, 3);
+        assert_eq!(timings.total, Duration::ZERO);
+        assert_eq!(timings.inference, Duration::ZERO);
+        assert_eq!(timings.per_token, Duration::ZERO);
+
+        let mut headers = HeaderMap::new();
+        timings.insert_headers(&mut headers);
+        for name in [
+            "x-compute-time",
+            "x-total-time",
+            "x-inference-time",
+            "x-time-per-token",
+        ] {
+            assert_eq!(headers.get(name).unwrap(), "0");
+        }
+        assert_eq!(headers.get("x-validation-time").unwrap(), "10");
+        assert_eq!(headers.get("x-queue-time").unwrap(), "10");
+    }
+
+    #[test]
+    fn test_prefix_paths() {
+        use utoipa::openapi::path::{Operation, PathItem, PathItemType, PathsBuilder};
+        use utoipa::openapi::OpenApiBuilder;
+
+        let api_doc = || {
+            let item = || PathItem::new(PathItemType::Get, Operation::new());
+            OpenApiBuilder::new()
+                .paths(
+                    PathsBuilder::new()
+                        .path("/generate", item())
+                        .path("/health", item()),
+                )
+                .build()
+        };
+        let paths = |api_doc: utoipa::openapi::OpenApi| {
+            api_doc.paths.paths.into_keys().collect::<Vec<String>>()
+        };
+        assert_eq!(
+            paths(prefix_paths(api_doc(), None, true)),
+            vec!["/generate", "/health"]
+        );
+        assert_eq!(
+            paths(prefix_paths(api_doc(), Some("/llm/v1"), false)),
+            vec!["/llm/v1/generate", "/llm/v1/health"]
+        );
+        assert_eq!(
+            paths(prefix_paths(api_doc(), Some("/llm/v1"), true)),
+            vec!["/health", "/llm/v1/generate", "/llm/v1/health"]
+        );
+    }
+
+    /// The jobs of the job log that were not dispatched before a restart are generated, and
+    /// audited like the jobs of the audited API keys
+    #[tokio::test]
+    async fn test_restore_jobs() {
+        let path = std::env::temp_dir().join(format!("tgi-restore-{}", std::process::id()));
+        let job = |inputs: &str, api_key_id: Option<String>| {
+            let request = GenerateRequest {
+                inputs: inputs.to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: 3,
+                    ..crate::default_parameters()
+                },
+                template: None,
+                template_vars: None,
+                preset: None,
+                model: None,
+            };
+            QueuedJob::new(request, None, api_key_id, None)
+        };
+        let (job_log, _) = JobLog::open(&path, false).unwrap();
+        assert!(job_log.queued(2, job("dispatched", None)).await);
+        assert!(
+            job_log
+                .queued(4, job("queued", Some(api_key_id("audited-key"))))
+                .await
+        );
+        job_log.dispatched(2).await;
+        drop(job_log);
+
+        // Restart
+        let (job_log, restored) = JobLog::open(&path, true).unwrap();
+        assert_eq!(restored.len(), 1);
+        let vocab = HashMap::from([("[UNK]".to_string(), 0)]);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let validation = Validation::basic(Tokenizer::new(model), 1, 2, 4, 1000, 1512);
+        let infer = Infer::builder(
+            ShardedClient::mock(MockConfig::default()).into(),
+            validation,
+        )
+        .build();
+        infer.reserve_ids(5);
+        let jobs = Jobs::new(10, 1 << 20, Duration::from_secs(60));
+        let callbacks = Callbacks::new(
+            CallbackConfig {
+                secret: None,
+                allowed_hosts: vec![],
+                max_attempts: 1,
+                initial_backoff: Duration::ZERO,
+                timeout: Duration::from_secs(1),
+                max_concurrent: 1,
+            },
+            jobs.clone(),
+        );
+        let (usage, _) = UsageRecorder::new(None, "mock".to_string(), None).unwrap();
+        let profiles_path = path.with_extension("profiles.json");
+        std::fs::write(
+            &profiles_path,
+            r#"{"api_keys": {"audited-key": {"audit": true}}}"#,
+        )
+        .unwrap();
+        let audit_path = path.with_extension("audit.jsonl");
+        let audit_log = AuditLog::new(
+            Some(AuditConfig {
+                path: audit_path.clone(),
+                max_file_bytes: 1 << 20,
+                max_files: 1,
+            }),
+            LimitProfiles::load(Some(profiles_path.clone()), 1000, 1512).unwrap(),
+        )
+        .unwrap();
+        restore_jobs(
+            infer.clone(),
+            jobs.clone(),
+            callbacks,
+            usage,
+            audit_log,
+            OutputHook::new(None, 0),
+            job_log.clone(),
+            restored,
+        )
+        .await;
+
+        // The restored job keeps its id, the new requests do not reuse it
+        assert_eq!(infer.register().id, 5);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while jobs.status(4).map(|status| status.status) != Some(RequestStatus::Completed) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(jobs.status(4).unwrap().generated_tokens, 3);
+        // Written once the job stored its result
+        let audit = std::fs::read_to_string(&audit_path).unwrap();
+        let records: Vec<serde_json::Value> = audit
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["request_id"], 4);
+        assert_eq!(records[0]["api_key_id"], api_key_id("audited-key"));
+        assert_eq!(records[0]["tokens"].as_array().unwrap().len(), 3);
+        assert_eq!(
+            records[0]["generated_text"],
+            jobs.status(4).unwrap().response.unwrap().generated_text
+        );
+
+        // It is not restored again
+        drop(job_log);
+        let (_, restored) = JobLog::open(&path, true).unwrap();
+        assert!(restored.is_empty());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&profiles_path).unwrap();
+        std::fs::remove_file(&audit_path).unwrap();
+    }
+
+    /// Router serving the mock backend, every input being a single unknown token
+    /// It runs up to 4 requests at once. The `Fail` inputs fail on the backend, the `Panic` inputs crash its batching task and the
+    /// texts of more than 24 tokens are rejected by the post-generation hook
+    fn mock_router(calls: Arc<Mutex<Vec<MockCall>>>) -> Router {
+        let vocab = HashMap::from([("[UNK]".to_string(), 0)]);
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let client = ShardedClient::mock(MockConfig {
+            token_delay: Duration::from_millis(10),
+            fail_inputs: HashSet::from(["Fail".to_string()]),
+            panic_inputs: HashSet::from(["Panic".to_string()]),
+            calls: Some(calls),
+            ..MockConfig::default()
+        });
+        let mut options =
+            ServerOptions::new("mock".to_string(), Tokenizer::new(model), client.into());
+        options.max_waiting_tokens = 1;
+        options.max_concurrent_requests = 4;
+        options.admin_api_key = Some("admin-key".to_string());
+        options.post_generation_reject_patterns = vec![r"^( \S+){25}".to_string()];
+        RouterApp::new(options).into_router()
+    }
+
+    /// Send `request` to `router` from a local client
+    async fn send(router: &Router, mut request: http::Request<Body>) -> Response {
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 3000))));
+        router.clone().oneshot(request).await.unwrap()
+    }
+
+    async fn post_json(router: &Router, path: &str, body: serde_json::Value) -> Response {
+        let request = http::Request::post(path)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        send(router, request).await
+    }
+
+    /// Send `body` with the API key `api_key`
+    async fn post_json_with_key(
+        router: &Router,
+        path: &str,
+        api_key: &str,
+        body: serde_json::Value,
+    ) -> Response {
+        let request = http::Request::post(path)
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .header("x-api-key", api_key)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        send(router, request).await
+    }
+
+    /// Send a request without body with the API key `api_key`
+    async fn request_with_key(
+        router: &Router,
+        method: Method,
+        path: &str,
+        api_key: &str,
+    ) -> Response {
+        let request = http::Request::builder()
+            .method(method)
+            .uri(path)
+            .header("x-api-key", api_key)
+            .body(Body::empty())
+            .unwrap();
+        send(router, request).await
+    }
+
+    async fn get_json(router: &Router, path: &str) -> serde_json::Value {
+        let request = http::Request::get(path).body(Body::empty()).unwrap();
+        let response = send(router, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        serde_json::from_slice(&read_body(response).await).unwrap()
+    }
+
+    async fn read_body(response: Response) -> Vec<u8> {
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(data) = body.data().await {
+            bytes.extend_from_slice(&data.unwrap());
+        }
+        bytes
+    }
+
+    /// Data of the server-sent events of `body`
+    fn event_data(body: &[u8]) -> Vec<serde_json::Value> {
+        String::from_utf8_lossy(body)
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect()
+    }
+
+    /// Data of the error and `aborted` events ending the server-sent events of `body`
+    fn abort_events(body: &[u8]) -> (serde_json::Value, serde_json::Value) {
+        let body = String::from_utf8_lossy(body);
+        let mut lines = body.lines().filter(|line| !line.is_empty()).rev();
+        let aborted = lines.next().and_then(|line| line.strip_prefix("data:"));
+        let event = lines.next().and_then(|line| line.strip_prefix("event:"));
+        assert_eq!(event.map(str::trim), Some("aborted"), "{body}");
+        let error = lines.next().and_then(|line| line.strip_prefix("data:"));
+        (
+            serde_json::from_str(error.unwrap()).unwrap(),
+            serde_json::from_str(aborted.unwrap()).unwrap(),
+        )
+    }
+
+    fn request_id(response: &Response) -> u64 {
+        response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    /// A single test as the router installs the global metrics recorder
+    #[tokio::test]
+    async fn test_routes() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let router = mock_router(calls.clone());
+
+        // Generate
+        let response = post_json(
+            &router,
+            "/generate",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 3}}),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(body["generated_text"], " the quick brown");
+
+        // Generate stream
+        let response = post_json(
+            &router,
+            "/generate_stream",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 3}}),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let events = event_data(&read_body(response).await);
+        let tokens: Vec<_> = events.iter().map(|event| &event["token"]["text"]).collect();
+        assert_eq!(tokens, [" the", " quick", " brown"]);
+        assert!(events[..2]
+            .iter()
+            .all(|event| event["generated_text"].is_null()));
+        assert_eq!(events[2]["generated_text"], " the quick brown");
+
+        // Concatenation: a request sent while a batch decodes joins it and finishes first
+        calls.lock().unwrap().clear();
+        let running_router = router.clone();
+        let running = tokio::spawn(async move {
+            let response = post_json(
+                &running_router,
+                "/generate",
+                json!({"inputs": "Hello", "parameters": {"max_new_tokens": 20, "details": true}}),
+            )
+            .await;
+            (Instant::now(), response)
+        });
+        tokio::time::sleep(Duration::from_millis(35)).await;
+        let response = post_json(
+            &router,
+            "/generate",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 2}}),
+        )
+        .await;
+        let finished = Instant::now();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(body["generated_text"], " the quick");
+
+        let (running_finished, response) = running.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(body["details"]["generated_tokens"], 20);
+        assert!(finished < running_finished);
+        assert!(calls
+            .lock()
             .unwrap()
+            .iter()
+            .any(|call| matches!(call, MockCall::Decode(ids) if ids.len() == 2)));
+
+        // Cancellation: closing the stream cancels its request
+        let response = post_json(
+            &router,
+            "/generate_stream",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 100}}),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let id = request_id(&response);
+        let mut body = response.into_body();
+        let first = body.data().await.unwrap().unwrap();
+        assert_eq!(event_data(&first)[0]["token"]["text"], " the");
+        drop(body);
+
+        let path = format!("/generation/{id}");
+        let mut status = get_json(&router, &path).await;
+        for _ in 0..100 {
+            if status["status"] != "running" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            status = get_json(&router, &path).await;
+        }
+        assert_eq!(status["status"], "cancelled");
+        assert!(status["generated_tokens"].as_u64().unwrap() < 100);
+
+        // Aborts: the streams ended by the server get an aborted event after their error event
+        let response = post_json(
+            &router,
+            "/generate_stream",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 100, "deadline_ms": 50}}),
+        )
+        .await;
+        let (error, aborted) = abort_events(&read_body(response).await);
+        assert_eq!(error["error_type"], "deadline_exceeded");
+        assert_eq!(aborted["reason"], "deadline");
+        assert_eq!(aborted["error_type"], "deadline_exceeded");
+        assert!(aborted["generated_tokens"].as_u64().unwrap() < 100);
+
+        let response = post_json(
+            &router,
+            "/generate_stream",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 30}}),
+        )
+        .await;
+        let (error, aborted) = abort_events(&read_body(response).await);
+        assert_eq!(error["error_type"], "content_filter");
+        assert_eq!(aborted["reason"], "moderation");
+        assert_eq!(aborted["generated_tokens"], 30);
+
+        let response = post_json(
+            &router,
+            "/generate_stream",
+            json!({"inputs": "Fail", "parameters": {"max_new_tokens": 3}}),
+        )
+        .await;
+        let (error, aborted) = abort_events(&read_body(response).await);
+        assert_eq!(error["error_type"], "generation");
+        assert_eq!(aborted["reason"], "backend_error");
+        assert_eq!(aborted["generated_tokens"], 0);
+
+        let response = post_json(
+            &router,
+            "/generate_stream",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 100}}),
+        )
+        .await;
+        let id = request_id(&response);
+        let mut body = response.into_body();
+        let mut events = body.data().await.unwrap().unwrap().to_vec();
+        let path = format!("/generation/{id}");
+        let cancel = http::Request::delete(&path).body(Body::empty()).unwrap();
+        assert_eq!(send(&router, cancel).await.status(), StatusCode::OK);
+        while let Some(data) = body.data().await {
+            events.extend_from_slice(&data.unwrap());
+        }
+        let (error, aborted) = abort_events(&events);
+        assert_eq!(error["error_type"], "cancelled");
+        assert_eq!(aborted["reason"], "cancelled");
+        assert!(aborted["generated_tokens"].as_u64().unwrap() < 100);
+
+        // The generation crashing while the router drains is aborted by the shutdown
+        let response = post_json(
+            &router,
+            "/generate_stream",
+            json!({"inputs": "Panic", "parameters": {"max_new_tokens": 100}}),
+        )
+        .await;
+        let drain = request_with_key(&router, Method::POST, "/admin/drain", "admin-key").await;
+        assert_eq!(drain.status(), StatusCode::OK);
+        let (error, aborted) = abort_events(&read_body(response).await);
+        assert_eq!(error["error_type"], "incomplete_generation");
+        assert_eq!(aborted["reason"], "shutdown");
+        let undrain = request_with_key(&router, Method::POST, "/admin/undrain", "admin-key").await;
+        assert_eq!(undrain.status(), StatusCode::OK);
+
+        // Continuation: only the API key of the request can continue it
+        let response = post_json_with_key(
+            &router,
+            "/generate",
+            "first-key",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 2}}),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let id = request_id(&response);
+        let continue_request = json!({"request_id": id, "parameters": {"max_new_tokens": 1}});
+        let response = post_json_with_key(
+            &router,
+            "/generate/continue",
+            "second-key",
+            continue_request.clone(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = post_json(&router, "/generate/continue", continue_request.clone()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response =
+            post_json_with_key(&router, "/generate/continue", "first-key", continue_request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(body["generated_text"], " the quick the");
+
+        // Jobs: only the API key of a job can read, stream or delete it
+        let response = post_json_with_key(
+            &router,
+            "/jobs",
+            "first-key",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 2}}),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let id = request_id(&response);
+        for (method, path) in [
+            (Method::GET, format!("/jobs/{id}")),
+            (Method::GET, format!("/jobs/{id}/result")),
+            (Method::GET, format!("/jobs/{id}/stream")),
+            (Method::DELETE, format!("/jobs/{id}")),
+        ] {
+            let response = request_with_key(&router, method.clone(), &path, "second-key").await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{method} {path}");
+        }
+        let path = format!("/jobs/{id}");
+        let response = send(
+            &router,
+            http::Request::get(&path).body(Body::empty()).unwrap(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = request_with_key(&router, Method::GET, &path, "first-key").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = request_with_key(&router, Method::DELETE, &path, "first-key").await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Unmatched requests are counted under a single route
+        let request = http::Request::get("/no-such-route/42")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&router, request).await.status(), StatusCode::NOT_FOUND);
+        let request = http::Request::get("/metrics").body(Body::empty()).unwrap();
+        let metrics = read_body(send(&router, request).await).await;
+        let metrics = String::from_utf8(metrics).unwrap();
+        assert!(metrics.contains(r#"route="unmatched""#));
+        assert!(!metrics.contains("no-such-route"));
+
+        // Stream rejections: the requests failing before they are queued get a JSON error with
+        // its status code instead of a stream
+        for parameters in [
+            json!({"temperature": 0.0}),
+            json!({"best_of": 2, "do_sample": true}),
+        ] {
+            let response = post_json(
+                &router,
+                "/generate_stream",
+                json!({"inputs": "Hello", "parameters": parameters}),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(
+                response.headers()[http::header::CONTENT_TYPE],
+                "application/json"
+            );
+            let body: serde_json::Value =
+                serde_json::from_slice(&read_body(response).await).unwrap();
+            assert_eq!(body["error_type"], "validation");
+        }
+
+        // A request failing its validation once it holds the last permit releases it
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            let response = post_json(
+                &router,
+                "/generate_stream",
+                json!({"inputs": "Hello", "parameters": {"max_new_tokens": 20}}),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            streams.push(response);
+        }
+        let response = post_json(
+            &router,
+            "/generate_stream",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 1512}}),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(body["error_type"], "validation");
+        let response = post_json(
+            &router,
+            "/generate_stream",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 20}}),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        streams.push(response);
+
+        // All the permits are taken
+        let response = post_json(
+            &router,
+            "/generate_stream",
+            json!({"inputs": "Hello", "parameters": {"max_new_tokens": 20}}),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers()[http::header::CONTENT_TYPE],
+            "application/json"
+        );
+        let body: serde_json::Value = serde_json::from_slice(&read_body(response).await).unwrap();
+        assert_eq!(body["error_type"], "overloaded");
+        for response in streams {
+            let events = event_data(&read_body(response).await);
+            assert_eq!(events.len(), 20);
+            assert!(events[19]["generated_text"].is_string());
+        }
     }
 }
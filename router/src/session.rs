@@ -0,0 +1,268 @@
+/// Session affinity used to reuse the backend state of a previous request
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use text_generation_client::PrefixCache;
+use tokio::time::Instant;
+
+/// How long a session is kept after its last request
+const SESSION_TTL: Duration = Duration::from_secs(300);
+
+/// Sessions store bounded in number and in bytes
+///
+/// The session ids are picked by the clients: the least recently updated sessions are evicted
+/// when a new one would exceed the limits
+#[derive(Debug, Clone)]
+pub(crate) struct Sessions {
+    state: Arc<Mutex<SessionsState>>,
+}
+
+#[derive(Debug)]
+struct SessionsState {
+    /// Maximum number of sessions, 0 disables the sessions
+    max_sessions: usize,
+    /// Maximum total size of the texts of the sessions
+    max_bytes: usize,
+    /// Current total size of the texts and ids of the sessions
+    bytes: usize,
+    /// Session id -> previous request
+    sessions: HashMap<String, SessionState>,
+    /// Sequence number of the last update -> session id, least recently updated first
+    updates: BTreeMap<u64, String>,
+    /// Sequence number of the next update
+    next_update: u64,
+}
+
+#[derive(Debug)]
+struct SessionState {
+    /// Inputs and generated text of the previous request
+    text: String,
+    /// Instant of the last update
+    updated: Instant,
+    /// Sequence number of the last update
+    update: u64,
+}
+
+impl SessionState {
+    fn size(&self, id: &str) -> usize {
+        id.len() + self.text.len()
+    }
+}
+
+impl SessionsState {
+    fn remove(&mut self, id: &str) {
+        if let Some(session) = self.sessions.remove(id) {
+            self.bytes -= session.size(id);
+            self.updates.remove(&session.update);
+        }
+    }
+
+    /// Remove the expired sessions, the least recently updated being the first to expire
+    fn remove_expired(&mut self) {
+        while let Some((_, id)) = self.updates.iter().next() {
+            if self.sessions[id].updated.elapsed() < SESSION_TTL {
+                break;
+            }
+            let id = id.clone();
+            self.remove(&id);
+        }
+    }
+
+    /// Evict the least recently updated sessions until a new session of `size` bytes fits
+    fn evict(&mut self, size: usize) {
+        while !self.sessions.is_empty()
+            && (self.sessions.len() >= self.max_sessions || self.bytes + size > self.max_bytes)
+        {
+            let id = match self.updates.iter().next() {
+                Some((_, id)) => id.clone(),
+                None => break,
+            };
+            self.remove(&id);
+            metrics::increment_counter!("tgi_session_evicted");
+        }
+    }
+}
+
+impl Sessions {
+    /// Create a new store. The sessions are disabled if any of the limits is 0
+    pub(crate) fn new(max_sessions: usize, max_bytes: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SessionsState {
+                max_sessions,
+                max_bytes,
+                bytes: 0,
+                sessions: HashMap::new(),
+                updates: BTreeMap::new(),
+                next_update: 0,
+            })),
+        }
+    }
+
+    /// False if any of the limits is 0
+    pub(crate) fn enabled(&self) -> bool {
+        let state = self.state.lock();
+        state.max_sessions > 0 && state.max_bytes > 0
+    }
+
+    /// Start a new request in a session
+    ///
+    /// The prefix length is 0 if the session expired or if `inputs` does not start with the
+    /// previous request inputs and generated text
+    pub(crate) fn start(&self, id: String, inputs: &str) -> Session {
+        let mut state = self.state.lock();
+        state.remove_expired();
+
+        let prefix_length = match state.sessions.get(&id) {
+            Some(session) if inputs.starts_with(&session.text) => session.text.len() as u32,
+            _ => 0,
+        };
+
+        Session {
+            sessions: self.clone(),
+            id,
+            prefix_length,
+        }
+    }
+
+    /// The session is not kept if its text alone exceeds the size limit
+    fn update(&self, id: String, text: String) {
+        let mut state = self.state.lock();
+        state.remove(&id);
+        let size = id.len() + text.len();
+        if state.max_sessions == 0 || size > state.max_bytes {
+            return;
+        }
+        state.evict(size);
+
+        let update = state.next_update;
+        state.next_update += 1;
+        state.bytes += size;
+        state.updates.insert(update, id.clone());
+        state.sessions.insert(
+            id,
+            SessionState {
+                text,
+                updated: Instant::now(),
+                update,
+            },
+        );
+        metrics::gauge!("tgi_session_count", state.sessions.len() as f64);
+        metrics::gauge!("tgi_session_bytes", state.bytes as f64);
+    }
+}
+
+/// Session of a request
+#[derive(Debug, Clone)]
+pub(crate) struct Session {
+    sessions: Sessions,
+    id: String,
+    /// Length in bytes of the inputs prefix already known by the backend
+    prefix_length: u32,
+}
+
+impl Session {
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The backend state of the previous request can be reused
+    pub(crate) fn hit(&self) -> bool {
+        self.prefix_length > 0
+    }
+
+    /// The inputs were truncated so they do not share a prefix with the previous request anymore
+    pub(crate) fn truncated(&mut self) {
+        self.prefix_length = 0;
+    }
+
+    pub(crate) fn prefix_cache(&self) -> PrefixCache {
+        PrefixCache {
+            session_id: self.id.clone(),
+            prefix_length: self.prefix_length,
+        }
+    }
+
+    /// Remember the inputs and generated text of a finished request
+    pub(crate) fn finish(&self, inputs: &str, generated_text: &str) {
+        self.sessions
+            .update(self.id.clone(), format!("{inputs}{generated_text}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_prefix() {
+        let sessions = Sessions::new(16, 1024);
+        let session = sessions.start("chat".to_string(), "Hello");
+        assert!(!session.hit());
+        session.finish("Hello", " world");
+
+        let session = sessions.start("chat".to_string(), "Hello world, how are you?");
+        assert!(session.hit());
+        assert_eq!(
+            session.prefix_cache().prefix_length,
+            "Hello world".len() as u32
+        );
+
+        assert!(!sessions.start("chat".to_string(), "Hi").hit());
+        assert!(!sessions.start("other".to_string(), "Hello world").hit());
+    }
+
+    #[test]
+    fn test_session_limits() {
+        let finish = |sessions: &Sessions, id: &str, text: &str| {
+            sessions.start(id.to_string(), "").finish(text, "");
+        };
+        let hit =
+            |sessions: &Sessions, id: &str, text: &str| sessions.start(id.to_string(), text).hit();
+
+        // The least recently updated session is evicted by the third one
+        let sessions = Sessions::new(2, 1024);
+        finish(&sessions, "a", "Hello");
+        finish(&sessions, "b", "Hello");
+        finish(&sessions, "a", "Hello world");
+        finish(&sessions, "c", "Hello");
+        assert!(hit(&sessions, "a", "Hello world"));
+        assert!(!hit(&sessions, "b", "Hello"));
+        assert!(hit(&sessions, "c", "Hello"));
+
+        // Sessions are evicted until the new text fits, the ids being counted
+        let sessions = Sessions::new(16, 12);
+        finish(&sessions, "a", "Hello");
+        finish(&sessions, "b", "Hello");
+        assert_eq!(sessions.state.lock().bytes, 12);
+        finish(&sessions, "c", "Hi");
+        assert!(!hit(&sessions, "a", "Hello"));
+        assert!(hit(&sessions, "b", "Hello"));
+        assert!(hit(&sessions, "c", "Hi"));
+        assert_eq!(sessions.state.lock().bytes, 9);
+
+        // A text larger than the limit is not kept, and does not evict the other sessions
+        finish(&sessions, "b", "Hello world, how are you?");
+        assert!(!hit(&sessions, "b", "Hello"));
+        assert!(hit(&sessions, "c", "Hi"));
+        assert_eq!(sessions.state.lock().bytes, 3);
+
+        // Disabled
+        let sessions = Sessions::new(0, 1024);
+        finish(&sessions, "a", "Hello");
+        assert!(!hit(&sessions, "a", "Hello"));
+    }
+
+    #[test]
+    fn test_session_truncated() {
+        let sessions = Sessions::new(16, 1024);
+        sessions
+            .start("chat".to_string(), "")
+            .finish("Hello", " world");
+
+        let mut session = sessions.start("chat".to_string(), "Hello world!");
+        session.truncated();
+        assert!(!session.hit());
+        assert_eq!(session.prefix_cache().prefix_length, 0);
+    }
+}
@@ -0,0 +1,475 @@
+use crate::{MatchedStop, StopConfig};
+use std::sync::Arc;
+
+/// Aho-Corasick automaton of the stop sequences of a request
+///
+/// Built once by the validation: it has one state per byte of the stop sequences, which are at
+/// most `max_stop_sequences`. Each generated byte is then matched in amortized constant time
+#[derive(Debug)]
+pub(crate) struct StopMatcher {
+    /// Stop sequences with their index in the request
+    stop_sequences: Vec<(usize, StopConfig)>,
+    /// States of the automaton, the root first
+    states: Vec<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Transitions sorted by byte
+    next: Vec<(u8, u32)>,
+    /// Longest proper suffix of the text of the state that is a state
+    fail: u32,
+    /// Length of the text of the state
+    depth: usize,
+    /// Stop sequence ending at this state, by position in `stop_sequences`
+    output: Option<u32>,
+    /// Nearest state with an output following the failure links
+    dict: Option<u32>,
+}
+
+impl StopMatcher {
+    pub(crate) fn new(stop_sequences: &[StopConfig]) -> Self {
+        let stop_sequences: Vec<(usize, StopConfig)> = stop_sequences
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(_, stop)| !stop.sequence.is_empty())
+            .collect();
+
+        // Trie of the stop sequences
+        let mut states = vec![State::default()];
+        for (position, (_, stop)) in stop_sequences.iter().enumerate() {
+            let mut state = 0;
+            for byte in stop.sequence.bytes() {
+                state = match transition(&states[state], byte) {
+                    Some(next) => next as usize,
+                    None => {
+                        let next = states.len();
+                        states.push(State {
+                            depth: states[state].depth + 1,
+                            ..State::default()
+                        });
+                        let transitions = &mut states[state].next;
+                        let at = transitions.partition_point(|(b, _)| *b < byte);
+                        transitions.insert(at, (byte, next as u32));
+                        next
+                    }
+                };
+            }
+            // The first of duplicated stop sequences is the one matched
+            states[state].output.get_or_insert(position as u32);
+        }
+
+        // Failure links, breadth first so that the links of the shorter states are set
+        let mut queue = std::collections::VecDeque::from([0]);
+        while let Some(state) = queue.pop_front() {
+            for index in 0..states[state].next.len() {
+                let (byte, next) = states[state].next[index];
+                let next = next as usize;
+                let fail = match state {
+                    0 => 0,
+                    _ => {
+                        let mut fail = states[state].fail as usize;
+                        loop {
+                            if let Some(target) = transition(&states[fail], byte) {
+                                break target;
+                            }
+                            if fail == 0 {
+                                break 0;
+                            }
+                            fail = states[fail].fail as usize;
+                        }
+                    }
+                };
+                states[next].fail = fail;
+                states[next].dict = match states[fail as usize].output {
+                    Some(_) => Some(fail),
+                    None => states[fail as usize].dict,
+                };
+                queue.push_back(next);
+            }
+        }
+
+        Self {
+            stop_sequences,
+            states,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.stop_sequences.is_empty()
+    }
+
+    fn step(&self, mut state: u32, byte: u8) -> u32 {
+        loop {
+            let current = &self.states[state as usize];
+            if let Some(next) = transition(current, byte) {
+                return next;
+            }
+            if state == 0 {
+                return 0;
+            }
+            state = current.fail;
+        }
+    }
+
+    /// Stop sequences ending at a state, by position in `stop_sequences`
+    fn outputs(&self, state: u32) -> impl Iterator<Item = usize> + '_ {
+        let state = &self.states[state as usize];
+        let first = match state.output {
+            Some(_) => Some(state),
+            None => state.dict.map(|dict| &self.states[dict as usize]),
+        };
+        std::iter::successors(first, |state| {
+            state.dict.map(|dict| &self.states[dict as usize])
+        })
+        .filter_map(|state| state.output.map(|output| output as usize))
+    }
+}
+
+impl Default for StopMatcher {
+    fn default() -> Self {
+        Self::new(&[])
+    }
+}
+
+fn transition(state: &State, byte: u8) -> Option<u32> {
+    state
+        .next
+        .binary_search_by_key(&byte, |(b, _)| *b)
+        .ok()
+        .map(|index| state.next[index].1)
+}
+
+/// Buffer of the generated text that can still become a stop sequence
+///
+/// Text is only released once it can no longer be the prefix of a stop sequence, and the text of
+/// a completed stop sequence is only released if it is kept. The text of each token is only
+/// scanned once: the state of the automaton is the longest suffix of the text that is the prefix
+/// of a stop sequence, which is the text held back
+#[derive(Debug)]
+pub(crate) struct StopBuffer {
+    matcher: Arc<StopMatcher>,
+    /// State of the automaton after the generated text
+    state: u32,
+    /// Text held back
+    pending: String,
+    /// The stop sequence that was generated
+    matched: Option<MatchedStop>,
+}
+
+impl StopBuffer {
+    pub(crate) fn new(matcher: Arc<StopMatcher>) -> Self {
+        Self {
+            matcher,
+            state: 0,
+            pending: String::new(),
+            matched: None,
+        }
+    }
+
+    /// Stop sequence found in the generated text
+    pub(crate) fn matched(&self) -> Option<&MatchedStop> {
+        self.matched.as_ref()
+    }
+
+    /// Cut `text`, the whole generated text, like the released text at the matched stop sequence
+    pub(crate) fn truncate(&self, text: &mut String) {
+        let matched = match &self.matched {
+            Some(matched) => matched,
+            None => return,
+        };
+        let keep_text = self
+            .matcher
+            .stop_sequences
+            .iter()
+            .find(|(index, _)| *index == matched.index)
+            .map_or(false, |(_, stop)| stop.keep_text);
+        if let Some(start) = text.find(matched.sequence.as_str()) {
+            let end = match keep_text {
+                true => start + matched.sequence.len(),
+                false => start,
+            };
+            text.truncate(end);
+        }
+    }
+
+    /// Add the text of a new token and return the text that can be released
+    pub(crate) fn push(&mut self, text: &str) -> String {
+        if self.matched.is_some() {
+            return String::new();
+        }
+        if self.matcher.is_empty() {
+            return text.to_string();
+        }
+        let offset = self.pending.len();
+        self.pending.push_str(text);
+
+        // The stop sequences ending in the token, the first one of the text wins
+        let mut first: Option<(usize, usize)> = None;
+        for (i, byte) in text.bytes().enumerate() {
+            self.state = self.matcher.step(self.state, byte);
+            let end = offset + i + 1;
+            for position in self.matcher.outputs(self.state) {
+                let start = end - self.matcher.stop_sequences[position].1.sequence.len();
+                if first.map_or(true, |first| (start, position) < first) {
+                    first = Some((start, position));
+                }
+            }
+        }
+
+        // Release the text before the first stop sequence, or up to its end if it is kept, and
+        // discard the rest
+        if let Some((start, position)) = first {
+            let (index, stop) = &self.matcher.stop_sequences[position];
+            let end = match stop.keep_text {
+                true => start + stop.sequence.len(),
+                false => start,
+            };
+            self.matched = Some(MatchedStop {
+                sequence: stop.sequence.clone(),
+                index: *index,
+            });
+            self.pending.truncate(end);
+            return std::mem::take(&mut self.pending);
+        }
+
+        // Hold back the longest suffix that is the prefix of a stop sequence
+        let depth = self.matcher.states[self.state as usize].depth;
+        let held = self
+            .pending
+            .split_off(self.pending.len().saturating_sub(depth));
+        std::mem::replace(&mut self.pending, held)
+    }
+
+    /// Add the text of the last token and return all the text that can be released
+    pub(crate) fn finish(&mut self, text: &str) -> String {
+        let mut released = self.push(text);
+        released.push_str(&std::mem::take(&mut self.pending));
+        released
+    }
+}
+
+/// Reference implementation of `StopBuffer` rescanning the held back text for each token, for
+/// the tests and the benchmark
+#[derive(Debug)]
+pub(crate) struct NaiveStopBuffer {
+    /// Stop sequences with their index in the request
+    stop_sequences: Vec<(usize, StopConfig)>,
+    /// Text held back
+    pending: String,
+    /// The stop sequence that was generated
+    matched: Option<MatchedStop>,
+}
+
+impl NaiveStopBuffer {
+    pub(crate) fn new(stop_sequences: &[StopConfig]) -> Self {
+        Self {
+            stop_sequences: stop_sequences
+                .iter()
+                .cloned()
+                .enumerate()
+                .filter(|(_, stop)| !stop.sequence.is_empty())
+                .collect(),
+            pending: String::new(),
+            matched: None,
+        }
+    }
+
+    pub(crate) fn matched(&self) -> Option<&MatchedStop> {
+        self.matched.as_ref()
+    }
+
+    pub(crate) fn push(&mut self, text: &str) -> String {
+        if self.matched.is_some() {
+            return String::new();
+        }
+        if self.stop_sequences.is_empty() {
+            return text.to_string();
+        }
+        self.pending.push_str(text);
+
+        let stop = self
+            .stop_sequences
+            .iter()
+            .filter_map(|(index, stop)| {
+                Some((self.pending.find(stop.sequence.as_str())?, *index, stop))
+            })
+            .min_by_key(|(start, index, _)| (*start, *index));
+        if let Some((start, index, stop)) = stop {
+            let end = match stop.keep_text {
+                true => start + stop.sequence.len(),
+                false => start,
+            };
+            self.matched = Some(MatchedStop {
+                sequence: stop.sequence.clone(),
+                index,
+            });
+            self.pending.truncate(end);
+            return std::mem::take(&mut self.pending);
+        }
+
+        let held = self
+            .pending
+            .char_indices()
+            .map(|(start, _)| start)
+            .find(|start| {
+                let suffix = &self.pending[*start..];
+                self.stop_sequences
+                    .iter()
+                    .any(|(_, stop)| stop.sequence.starts_with(suffix))
+            })
+            .unwrap_or(self.pending.len());
+        let held = self.pending.split_off(held);
+        std::mem::replace(&mut self.pending, held)
+    }
+
+    pub(crate) fn finish(&mut self, text: &str) -> String {
+        let mut released = self.push(text);
+        released.push_str(&std::mem::take(&mut self.pending));
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+
+    fn stop_buffer(stop_sequences: &[StopConfig]) -> StopBuffer {
+        StopBuffer::new(Arc::new(StopMatcher::new(stop_sequences)))
+    }
+
+    fn stops(sequences: &[&str]) -> Vec<StopConfig> {
+        sequences
+            .iter()
+            .map(|sequence| StopConfig {
+                sequence: sequence.to_string(),
+                keep_text: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_no_stop_sequences() {
+        let mut buffer = StopBuffer::new(Arc::default());
+        assert_eq!(buffer.push("Hello"), "Hello");
+        assert_eq!(buffer.finish(" world"), " world");
+    }
+
+    #[test]
+    fn test_hold_back() {
+        let mut buffer = stop_buffer(&stops(&["\nUser:"]));
+        assert_eq!(buffer.push("Hello"), "Hello");
+        assert_eq!(buffer.push("!\n"), "!");
+        assert_eq!(buffer.push("Us"), "");
+        // Not a stop sequence anymore
+        assert_eq!(buffer.push("a"), "\nUsa");
+        assert_eq!(buffer.push("\n"), "");
+        assert_eq!(buffer.finish("User:"), "");
+    }
+
+    #[test]
+    fn test_stop_inside_token() {
+        let mut buffer = stop_buffer(&stops(&["###", "END"]));
+        assert_eq!(buffer.push("a#"), "a");
+        assert_eq!(buffer.push("b ##"), "#b ");
+        assert_eq!(buffer.push("#c"), "");
+        assert_eq!(buffer.push("d"), "");
+        assert_eq!(
+            buffer.matched(),
+            Some(&MatchedStop {
+                sequence: "###".to_string(),
+                index: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_finish_flushes() {
+        let mut buffer = stop_buffer(&stops(&["END"]));
+        assert_eq!(buffer.push("The E"), "The ");
+        assert_eq!(buffer.finish("N"), "EN");
+    }
+
+    #[test]
+    fn test_keep_text() {
+        let mut stop_sequences = stops(&["END", "\n\n"]);
+        stop_sequences[1].keep_text = true;
+        let mut buffer = stop_buffer(&stop_sequences);
+        assert_eq!(buffer.push("Title\n"), "Title");
+        // The kept stop sequence is released, the text after it is discarded
+        assert_eq!(buffer.push("\nBody"), "\n\n");
+        assert_eq!(buffer.finish("END"), "");
+        assert_eq!(
+            buffer.matched(),
+            Some(&MatchedStop {
+                sequence: "\n\n".to_string(),
+                index: 1,
+            })
+        );
+
+        // The first stop sequence of the text wins
+        let mut buffer = stop_buffer(&stop_sequences);
+        assert_eq!(buffer.finish("a END\n\n"), "a ");
+        assert_eq!(buffer.matched().map(|stop| stop.index), Some(0));
+    }
+
+    #[test]
+    fn test_overlapping_stop_sequences() {
+        // The stop sequence starting first wins, even if a shorter one ends before it
+        let mut buffer = stop_buffer(&stops(&["b c", "a b c d"]));
+        assert_eq!(buffer.push("x a b"), "x ");
+        assert_eq!(buffer.push(" c d"), "");
+        assert_eq!(buffer.matched().map(|stop| stop.index), Some(1));
+
+        // A failed prefix of a stop sequence can start another one
+        let mut buffer = stop_buffer(&stops(&["aab", "ab!"]));
+        assert_eq!(buffer.push("aa"), "");
+        assert_eq!(buffer.push("a"), "a");
+        assert_eq!(buffer.push("b!"), "");
+        assert_eq!(buffer.matched().map(|stop| stop.index), Some(0));
+    }
+
+    /// The automaton releases the same text and matches the same stop sequences as the naive
+    /// scan, for random stop sequences, texts and tokenizations
+    #[test]
+    fn test_same_as_naive() {
+        fn random_text(rng: &mut StdRng, max_len: usize) -> String {
+            let alphabet = ["a", "b", "\n", "#", "é", "🦀"];
+            (0..rng.gen_range(0..=max_len))
+                .map(|_| *alphabet.choose(rng).unwrap())
+                .collect()
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..5000 {
+            let stop_sequences: Vec<StopConfig> = (0..rng.gen_range(0..=4))
+                .map(|_| StopConfig {
+                    sequence: random_text(&mut rng, 4),
+                    keep_text: rng.gen_bool(0.3),
+                })
+                .collect();
+            let tokens: Vec<String> = (0..rng.gen_range(1..=20))
+                .map(|_| random_text(&mut rng, 3))
+                .collect();
+
+            let mut buffer = stop_buffer(&stop_sequences);
+            let mut naive = NaiveStopBuffer::new(&stop_sequences);
+            for (i, token) in tokens.iter().enumerate() {
+                let (released, expected) = match i + 1 == tokens.len() {
+                    true => (buffer.finish(token), naive.finish(token)),
+                    false => (buffer.push(token), naive.push(token)),
+                };
+                assert_eq!(released, expected, "{stop_sequences:?} {tokens:?}");
+            }
+            assert_eq!(
+                buffer.matched(),
+                naive.matched(),
+                "{stop_sequences:?} {tokens:?}"
+            );
+        }
+    }
+}
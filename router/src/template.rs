@@ -0,0 +1,404 @@
+/// Server-side prompt templates
+use crate::validation::ValidationError;
+use crate::{GenerateRequest, InputSource};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Variable replaced by the request `inputs`
+const INPUT_VARIABLE: &str = "input";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Variable(String),
+}
+
+/// Prompt template using `{variable}` placeholders, `{{` and `}}` being literal braces
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    fn parse(source: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) if c.is_alphanumeric() || c == '_' => name.push(c),
+                            _ => return Err(TemplateError::Placeholder(name)),
+                        }
+                    }
+                    if name.is_empty() {
+                        return Err(TemplateError::Placeholder(name));
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Variable(name));
+                }
+                '}' => return Err(TemplateError::Brace),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self { parts })
+    }
+
+    /// The template renders `inputs`
+    fn uses_input(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Variable(name) if name == INPUT_VARIABLE))
+    }
+
+    /// Variables that must be given in `template_vars`
+    fn variables(&self) -> BTreeSet<&str> {
+        self.parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Variable(name) if name != INPUT_VARIABLE => Some(name.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn render(
+        &self,
+        inputs: &str,
+        variables: &HashMap<String, String>,
+    ) -> Result<String, ValidationError> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => rendered.push_str(text),
+                Part::Variable(name) if name == INPUT_VARIABLE => rendered.push_str(inputs),
+                Part::Variable(name) => match variables.get(name) {
+                    Some(value) => rendered.push_str(value),
+                    None => return Err(ValidationError::TemplateVariable(name.clone())),
+                },
+            }
+        }
+        Ok(rendered)
+    }
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum TemplateError {
+    #[error("could not read templates: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid placeholder `{{{0}`")]
+    Placeholder(String),
+    #[error("unmatched `}}`, use `}}}}` for a literal brace")]
+    Brace,
+    #[error("template `{0}`: {1}")]
+    Template(String, Box<TemplateError>),
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct TemplateInfo {
+    #[schema(example = "chat")]
+    name: String,
+    /// Variables required in `template_vars`
+    #[schema(example = json!(["system"]))]
+    variables: Vec<String>,
+}
+
+/// Named templates loaded from a directory, one template per file named after the file stem
+#[derive(Clone, Default)]
+pub(crate) struct Templates {
+    /// None if templates are disabled
+    directory: Option<PathBuf>,
+    templates: Arc<RwLock<BTreeMap<String, Template>>>,
+}
+
+impl Templates {
+    pub(crate) fn load(directory: Option<PathBuf>) -> Result<Self, TemplateError> {
+        let templates = Self {
+            directory,
+            templates: Arc::default(),
+        };
+        templates.reload()?;
+        Ok(templates)
+    }
+
+    /// Load the templates again from the directory
+    /// The current templates are kept if any of the templates is invalid
+    pub(crate) fn reload(&self) -> Result<(), TemplateError> {
+        if let Some(directory) = &self.directory {
+            let templates = read_templates(directory)?;
+            tracing::info!("Loaded {} prompt templates", templates.len());
+            *self.templates.write() = templates;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn list(&self) -> Vec<TemplateInfo> {
+        self.templates
+            .read()
+            .iter()
+            .map(|(name, template)| TemplateInfo {
+                name: name.clone(),
+                variables: template.variables().into_iter().map(String::from).collect(),
+            })
+            .collect()
+    }
+
+    /// Resolve the source of the inputs of a request, replacing its inputs by its rendered
+    /// template if it has one
+    ///
+    /// `inputs` is rendered as the `{input}` of the template. The fields that would be ignored
+    /// are rejected, before the template is looked up
+    pub(crate) fn resolve(
+        &self,
+        request: &mut GenerateRequest,
+    ) -> Result<InputSource, ValidationError> {
+        let name = match request.template.take() {
+            None if request.template_vars.is_some() => {
+                return Err(ValidationError::TemplateVarsWithoutTemplate)
+            }
+            None => return Ok(InputSource::Text),
+            Some(name) => name,
+        };
+        let variables = request.template_vars.take().unwrap_or_default();
+        if variables.contains_key(INPUT_VARIABLE) {
+            return Err(ValidationError::TemplateVarsInput);
+        }
+
+        let templates = self.templates.read();
+        let template = match templates.get(&name) {
+            Some(template) => template,
+            None => {
+                let available: Vec<&str> = templates.keys().map(String::as_str).collect();
+                return Err(ValidationError::UnknownTemplate(name, available.join(", ")));
+            }
+        };
+        if !request.inputs.is_empty() && !template.uses_input() {
+            return Err(ValidationError::TemplateIgnoresInputs(name));
+        }
+        request.inputs = template.render(&request.inputs, &variables)?;
+        Ok(InputSource::Template)
+    }
+}
+
+fn read_templates(directory: &Path) -> Result<BTreeMap<String, Template>, TemplateError> {
+    let mut templates = BTreeMap::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        let name = match path.file_stem().and_then(|name| name.to_str()) {
+            Some(name) if path.is_file() && !name.starts_with('.') => name.to_string(),
+            _ => continue,
+        };
+        let source = std::fs::read_to_string(&path)?;
+        let template = Template::parse(&source)
+            .map_err(|err| TemplateError::Template(name.clone(), Box::new(err)))?;
+        templates.insert(name, template);
+    }
+    Ok(templates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_parameters;
+
+    #[test]
+    fn test_parse() {
+        let template = Template::parse("{system}\n\nUser: {input} {{json}}").unwrap();
+        assert_eq!(
+            template.parts,
+            vec![
+                Part::Variable("system".to_string()),
+                Part::Text("\n\nUser: ".to_string()),
+                Part::Variable("input".to_string()),
+                Part::Text(" {json}".to_string()),
+            ]
+        );
+        assert_eq!(template.variables(), BTreeSet::from(["system"]));
+
+        assert!(matches!(
+            Template::parse("{input"),
+            Err(TemplateError::Placeholder(_))
+        ));
+        assert!(matches!(
+            Template::parse("{}"),
+            Err(TemplateError::Placeholder(_))
+        ));
+        assert!(matches!(
+            Template::parse("input}"),
+            Err(TemplateError::Brace)
+        ));
+    }
+
+    #[test]
+    fn test_render() {
+        let template = Template::parse("{system} User: {input}").unwrap();
+        let mut variables = HashMap::new();
+        assert!(matches!(
+            template.render("Hello", &variables),
+            Err(ValidationError::TemplateVariable(name)) if name == "system"
+        ));
+
+        variables.insert("system".to_string(), "Be nice.".to_string());
+        assert_eq!(
+            template.render("Hello", &variables).unwrap(),
+            "Be nice. User: Hello"
+        );
+    }
+
+    #[test]
+    fn test_unknown_template() {
+        let templates = Templates::default();
+        templates
+            .templates
+            .write()
+            .insert("chat".to_string(), Template::parse("{input}").unwrap());
+
+        let mut request = request("Hello", Some("chat"), None);
+        assert_eq!(
+            templates.resolve(&mut request).unwrap(),
+            InputSource::Template
+        );
+        assert_eq!(request.inputs, "Hello");
+        assert!(matches!(
+            templates.resolve(&mut request("Hello", Some("summary"), None)),
+            Err(ValidationError::UnknownTemplate(name, available)) if name == "summary" && available == "chat"
+        ));
+    }
+
+    fn request(
+        inputs: &str,
+        template: Option<&str>,
+        template_vars: Option<&[(&str, &str)]>,
+    ) -> GenerateRequest {
+        GenerateRequest {
+            inputs: inputs.to_string(),
+            parameters: default_parameters(),
+            template: template.map(String::from),
+            template_vars: template_vars.map(|variables| {
+                variables
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect()
+            }),
+            preset: None,
+            model: None,
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let templates = Templates::default();
+        templates.templates.write().extend([
+            (
+                "chat".to_string(),
+                Template::parse("{system} User: {input}").unwrap(),
+            ),
+            (
+                "greeting".to_string(),
+                Template::parse("Hello {name}!").unwrap(),
+            ),
+        ]);
+        let variables: &[(&str, &str)] = &[("system", "Be nice."), ("name", "Ann")];
+        let input: &[(&str, &str)] = &[("input", "Hi")];
+
+        // Every combination of `inputs`, `template` and `template_vars`, with the resolved source
+        // and inputs or the error
+        let matrix: [(
+            &str,
+            Option<&str>,
+            Option<&[(&str, &str)]>,
+            Result<&str, &str>,
+        ); 24] = [
+            ("", None, None, Ok("")),
+            (
+                "",
+                None,
+                Some(variables),
+                Err("TemplateVarsWithoutTemplate"),
+            ),
+            ("", None, Some(input), Err("TemplateVarsWithoutTemplate")),
+            ("", Some("chat"), None, Err("TemplateVariable")),
+            ("", Some("chat"), Some(variables), Ok("Be nice. User: ")),
+            ("", Some("chat"), Some(input), Err("TemplateVarsInput")),
+            ("", Some("greeting"), None, Err("TemplateVariable")),
+            ("", Some("greeting"), Some(variables), Ok("Hello Ann!")),
+            ("", Some("greeting"), Some(input), Err("TemplateVarsInput")),
+            ("", Some("unknown"), None, Err("UnknownTemplate")),
+            ("", Some("unknown"), Some(variables), Err("UnknownTemplate")),
+            ("", Some("unknown"), Some(input), Err("TemplateVarsInput")),
+            ("Hi", None, None, Ok("Hi")),
+            (
+                "Hi",
+                None,
+                Some(variables),
+                Err("TemplateVarsWithoutTemplate"),
+            ),
+            ("Hi", None, Some(input), Err("TemplateVarsWithoutTemplate")),
+            ("Hi", Some("chat"), None, Err("TemplateVariable")),
+            ("Hi", Some("chat"), Some(variables), Ok("Be nice. User: Hi")),
+            ("Hi", Some("chat"), Some(input), Err("TemplateVarsInput")),
+            ("Hi", Some("greeting"), None, Err("TemplateIgnoresInputs")),
+            (
+                "Hi",
+                Some("greeting"),
+                Some(variables),
+                Err("TemplateIgnoresInputs"),
+            ),
+            (
+                "Hi",
+                Some("greeting"),
+                Some(input),
+                Err("TemplateVarsInput"),
+            ),
+            ("Hi", Some("unknown"), None, Err("UnknownTemplate")),
+            (
+                "Hi",
+                Some("unknown"),
+                Some(variables),
+                Err("UnknownTemplate"),
+            ),
+            ("Hi", Some("unknown"), Some(input), Err("TemplateVarsInput")),
+        ];
+        for (inputs, template, template_vars, expected) in matrix {
+            let mut request = request(inputs, template, template_vars);
+            let case = format!("{inputs:?} {template:?} {template_vars:?}");
+            match (templates.resolve(&mut request), expected) {
+                (Ok(source), Ok(resolved)) => {
+                    let expected_source = match template {
+                        None => InputSource::Text,
+                        Some(_) => InputSource::Template,
+                    };
+                    assert_eq!(source, expected_source, "{case}");
+                    assert_eq!(request.inputs, resolved, "{case}");
+                }
+                (Err(err), Err(variant)) => {
+                    assert!(format!("{err:?}").starts_with(variant), "{case}: {err:?}")
+                }
+                (result, _) => panic!("{case}: unexpected {result:?}"),
+            }
+        }
+    }
+}
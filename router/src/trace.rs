@@ -0,0 +1,23 @@
+/// Log a state transition of a request
+///
+/// Transitions are logged at debug level, or at info level when requests are traced
+macro_rules! transition {
+    ($handle:expr, $state:literal $(, $($fields:tt)+)?) => {{
+        let handle: &crate::registry::RequestHandle = &$handle;
+        if handle.trace() {
+            tracing::info!(
+                target: "request_transition",
+                request_id = handle.id,
+                state = $state
+                $(, $($fields)+)?
+            );
+        } else {
+            tracing::debug!(
+                target: "request_transition",
+                request_id = handle.id,
+                state = $state
+                $(, $($fields)+)?
+            );
+        }
+    }};
+}
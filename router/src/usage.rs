@@ -0,0 +1,392 @@
+/// Per-request usage records for billing
+use crate::infer::InferError;
+use crate::{EstimatedCost, FinishReason};
+use axum::http::HeaderMap;
+use hmac::{Hmac, Mac};
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use serde::Serialize;
+use sha2::Sha256;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+/// Maximum number of records waiting to be written
+const CHANNEL_CAPACITY: usize = 4096;
+/// Interval between two flushes of the sink
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Key of the hash giving the API key ids, random unless set at startup
+static API_KEY_ID_SECRET: OnceCell<Vec<u8>> = OnceCell::new();
+
+/// Destination of the usage records
+#[derive(Debug, Clone)]
+enum UsageSink {
+    Stdout,
+    /// JSONL file
+    File(String),
+}
+
+impl From<String> for UsageSink {
+    fn from(sink: String) -> Self {
+        match sink.as_str() {
+            "stdout" => UsageSink::Stdout,
+            _ => UsageSink::File(sink),
+        }
+    }
+}
+
+/// Prices of the requests, used to estimate their cost for chargeback
+#[derive(Debug, Clone)]
+pub struct CostModel {
+    pub prefill_token: f64,
+    pub decode_token: f64,
+    /// Price of a second of inference, from the prefill to the last token
+    pub inference_second: f64,
+    /// Label of the currency of the prices, e.g. `USD`
+    pub currency: String,
+}
+
+impl CostModel {
+    pub(crate) fn estimate(
+        &self,
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        inference_time: Duration,
+    ) -> EstimatedCost {
+        EstimatedCost {
+            amount: self.prefill_token * prompt_tokens as f64
+                + self.decode_token * completion_tokens as f64
+                + self.inference_second * inference_time.as_secs_f64(),
+            currency: self.currency.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct UsageRecord {
+    /// `api_key_id` of the API key of the request, the key itself is never written
+    api_key_id: Option<String>,
+    model_id: String,
+    prompt_tokens: u32,
+    completion_tokens: u32,
+    latency_ms: u64,
+    finish_reason: Option<FinishReason>,
+    error_type: Option<String>,
+    /// Milliseconds since the Unix epoch
+    timestamp: u64,
+    /// Only set if a cost model is configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimated_cost: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<String>,
+}
+
+/// Sends usage records to a background writer task
+#[derive(Clone)]
+pub(crate) struct UsageRecorder {
+    model_id: String,
+    /// None if the costs are not estimated
+    cost_model: Option<CostModel>,
+    /// None if usage records are disabled
+    sender: Option<mpsc::Sender<UsageRecord>>,
+}
+
+impl UsageRecorder {
+    /// Create a new recorder writing to `sink` ("stdout" or a file path)
+    ///
+    /// The returned task flushes the sink and exits once all the recorders are dropped
+    pub(crate) fn new(
+        sink: Option<String>,
+        model_id: String,
+        cost_model: Option<CostModel>,
+    ) -> std::io::Result<(Self, Option<JoinHandle<()>>)> {
+        let sink = match sink {
+            None => {
+                return Ok((
+                    Self {
+                        model_id,
+                        cost_model,
+                        sender: None,
+                    },
+                    None,
+                ))
+            }
+            Some(sink) => UsageSink::from(sink),
+        };
+
+        let writer: Box<dyn Write + Send> = match sink {
+            UsageSink::Stdout => Box::new(std::io::stdout()),
+            UsageSink::File(path) => {
+                Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+        };
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let task = tokio::spawn(writer_task(BufWriter::new(writer), receiver));
+
+        Ok((
+            Self {
+                model_id,
+                cost_model,
+                sender: Some(sender),
+            },
+            Some(task),
+        ))
+    }
+
+    /// Estimated cost of a request, None if no cost model is configured
+    pub(crate) fn estimate(
+        &self,
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        inference_time: Duration,
+    ) -> Option<EstimatedCost> {
+        self.cost_model
+            .as_ref()
+            .map(|cost_model| cost_model.estimate(prompt_tokens, completion_tokens, inference_time))
+    }
+
+    /// Record the usage of a finished request
+    /// `outcome` is the finish reason of a completed request or its error
+    /// The failed requests are only charged for their tokens: `inference_time` is 0
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn record(
+        &self,
+        request_headers: &HeaderMap,
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        inference_time: Duration,
+        start_time: Instant,
+        outcome: Result<FinishReason, &InferError>,
+    ) {
+        self.record_key(
+            api_key(request_headers).as_deref().map(api_key_id),
+            prompt_tokens,
+            completion_tokens,
+            inference_time,
+            start_time,
+            outcome,
+        )
+    }
+
+    /// Record the usage of a finished request of the API key with the id `api_key_id`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn record_key(
+        &self,
+        api_key_id: Option<String>,
+        prompt_tokens: u32,
+        completion_tokens: u32,
+        inference_time: Duration,
+        start_time: Instant,
+        outcome: Result<FinishReason, &InferError>,
+    ) {
+        let sender = match &self.sender {
+            None => return,
+            Some(sender) => sender,
+        };
+
+        let (finish_reason, error_type) = match outcome {
+            Ok(finish_reason) => (Some(finish_reason), None),
+            // The generation finished but its text was rejected
+            Err(err @ InferError::ContentFiltered(_)) => (
+                Some(FinishReason::ContentFilter),
+                Some(err.error_code().to_string()),
+            ),
+            Err(err) => (None, Some(err.error_code().to_string())),
+        };
+        let estimated_cost = self.estimate(prompt_tokens, completion_tokens, inference_time);
+        let record = UsageRecord {
+            api_key_id,
+            model_id: self.model_id.clone(),
+            prompt_tokens,
+            completion_tokens,
+            latency_ms: start_time.elapsed().as_millis() as u64,
+            finish_reason,
+            error_type,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |timestamp| timestamp.as_millis() as u64),
+            estimated_cost: estimated_cost.as_ref().map(|cost| cost.amount),
+            currency: estimated_cost.map(|cost| cost.currency),
+        };
+
+        // Never wait on the writer
+        if let Err(err) = sender.try_send(record) {
+            match err {
+                TrySendError::Full(_) => {
+                    metrics::increment_counter!("tgi_usage_record_dropped", "reason" => "full")
+                }
+                TrySendError::Closed(_) => {
+                    metrics::increment_counter!("tgi_usage_record_dropped", "reason" => "closed")
+                }
+            }
+        }
+    }
+}
+
+/// API key from the `Authorization: Bearer` or `x-api-key` headers
+pub(crate) fn api_key(request_headers: &HeaderMap) -> Option<String> {
+    let bearer = request_headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let api_key = bearer.or_else(|| {
+        request_headers
+            .get("x-api-key")
+            .and_then(|value| value.to_str().ok())
+    });
+    api_key.map(|api_key| api_key.trim().to_string())
+}
+
+/// Set the key of the hash giving the API key ids, so that the ids are the same across restarts
+///
+/// Returns false if the ids were already computed with another key
+pub(crate) fn set_api_key_id_secret(secret: &str) -> bool {
+    API_KEY_ID_SECRET.set(secret.as_bytes().to_vec()).is_ok()
+}
+
+/// Keyed hash of an API key, to tell the keys apart in logs, records and admin routes without
+/// exposing them, even for short keys
+pub(crate) fn api_key_id(api_key: &str) -> String {
+    let secret = API_KEY_ID_SECRET.get_or_init(|| rand::thread_rng().gen::<[u8; 32]>().to_vec());
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(api_key.as_bytes());
+    mac.finalize().into_bytes()[..8]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+async fn writer_task(
+    mut writer: BufWriter<Box<dyn Write + Send>>,
+    mut receiver: mpsc::Receiver<UsageRecord>,
+) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            record = receiver.recv() => match record {
+                Some(record) => write_record(&mut writer, &record),
+                // All recorders are dropped
+                None => break,
+            },
+            _ = interval.tick() => flush(&mut writer),
+        }
+    }
+    flush(&mut writer);
+}
+
+fn write_record(writer: &mut impl Write, record: &UsageRecord) {
+    let result = serde_json::to_writer(&mut *writer, record)
+        .map_err(std::io::Error::from)
+        .and_then(|_| writer.write_all(b"\n"));
+    if let Err(err) = result {
+        tracing::error!("Could not write usage record: {err}");
+        metrics::increment_counter!("tgi_usage_record_dropped", "reason" => "write");
+    }
+}
+
+fn flush(writer: &mut impl Write) {
+    if let Err(err) = writer.flush() {
+        tracing::error!("Could not flush usage records: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_key() {
+        let mut headers = HeaderMap::new();
+        assert!(api_key(&headers).is_none());
+
+        headers.insert("x-api-key", "key".parse().unwrap());
+        assert_eq!(api_key(&headers).unwrap(), "key");
+
+        headers.insert("authorization", "Bearer token".parse().unwrap());
+        assert_eq!(api_key(&headers).unwrap(), "token");
+    }
+
+    #[test]
+    fn test_api_key_id() {
+        let id = api_key_id("key");
+        assert_eq!(id, api_key_id("key"));
+        assert_ne!(id, api_key_id("other"));
+        assert!(!id.contains("key"));
+        assert_eq!(id.len(), 16);
+        assert_eq!(api_key_id("clé-é").len(), 16);
+    }
+
+    #[test]
+    fn test_write_record() {
+        let record = UsageRecord {
+            api_key_id: None,
+            model_id: "bigscience/bloom".to_string(),
+            prompt_tokens: 3,
+            completion_tokens: 5,
+            latency_ms: 10,
+            finish_reason: Some(FinishReason::Length),
+            error_type: None,
+            timestamp: 0,
+            estimated_cost: None,
+            currency: None,
+        };
+        let mut buffer = Vec::new();
+        write_record(&mut buffer, &record);
+
+        let line = String::from_utf8(buffer).unwrap();
+        assert!(line.ends_with('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["prompt_tokens"], 3);
+        assert_eq!(value["finish_reason"], "length");
+        // The cost fields are absent without a cost model
+        assert!(value.get("estimated_cost").is_none());
+    }
+
+    #[test]
+    fn test_record_api_key_id() {
+        let (sender, mut receiver) = mpsc::channel(1);
+        let recorder = UsageRecorder {
+            model_id: "bigscience/bloom".to_string(),
+            cost_model: None,
+            sender: Some(sender),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer secret-key".parse().unwrap());
+        recorder.record(
+            &headers,
+            3,
+            5,
+            Duration::ZERO,
+            Instant::now(),
+            Ok(FinishReason::Length),
+        );
+
+        let mut buffer = Vec::new();
+        write_record(&mut buffer, &receiver.try_recv().unwrap());
+        let line = String::from_utf8(buffer).unwrap();
+        assert!(!line.contains("secret"));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["api_key_id"], api_key_id("secret-key"));
+    }
+
+    #[test]
+    fn test_estimate() {
+        let cost_model = CostModel {
+            prefill_token: 0.5,
+            decode_token: 2.0,
+            inference_second: 10.0,
+            currency: "credits".to_string(),
+        };
+        let cost = cost_model.estimate(4, 3, Duration::from_millis(1500));
+        assert_eq!(cost.amount, 23.0);
+        assert_eq!(cost.currency, "credits");
+
+        let (recorder, _) = UsageRecorder::new(None, "bigscience/bloom".to_string(), None).unwrap();
+        assert!(recorder.estimate(4, 3, Duration::ZERO).is_none());
+    }
+}
@@ -1,8 +1,17 @@
-use crate::validation::ValidationError::{BestOfSampling, BestOfSeed, EmptyInput};
 /// Payload validation logic
-use crate::{GenerateParameters, GenerateRequest};
+use crate::limits::{LimitProfiles, Limits};
+use crate::normalize::InputNormalizer;
+use crate::preset::Presets;
+use crate::stop::StopMatcher;
+use crate::template::Templates;
+use crate::validation::ValidationError::{BestOfSampling, BestOfSeed, EmptyInput};
+use crate::{
+    GenerateParameters, GenerateRequest, InputSource, RequestContext, StopConfig, ValidParameters,
+};
 use rand::rngs::ThreadRng;
 use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use text_generation_client::{NextTokenChooserParameters, StoppingCriteriaParameters};
 use thiserror::Error;
 use tokenizers::tokenizer::Tokenizer;
@@ -10,17 +19,62 @@ use tokenizers::TruncationDirection;
 use tokio::sync::{mpsc, oneshot};
 use tracing::{instrument, Span};
 
+/// Longest time that `max_new_tokens` can take to be sent at the `stream_rate_limit` of a request
+const MAX_PACED_STREAM_SECS: f32 = 600.0;
+/// Maximum number of token ids in the `allowed_tokens` of a request
+const MAX_ALLOWED_TOKENS: usize = 4096;
+
 /// Validation
 #[derive(Debug, Clone)]
 pub struct Validation {
     /// maximum value for the best_of parameter
     #[allow(dead_code)]
     max_best_of: usize,
+    /// maximum number of stop sequences
+    max_stop_sequences: usize,
+    /// Prompt templates
+    templates: Templates,
+    /// Parameter presets
+    presets: Presets,
+    /// Limits of the requests per API key
+    limits: LimitProfiles,
+    /// Maps the generated token ids to their tokenizer pieces
+    tokenizer: Arc<Tokenizer>,
+    /// `raw_token_text` is refused
+    refuse_raw_token_text: bool,
+    /// `session_id` is refused
+    refuse_session_id: bool,
     /// Channel to communicate with the background validation task
     sender: mpsc::UnboundedSender<ValidationRequest>,
 }
 
 impl Validation {
+    /// Validation without prompt templates, parameter presets or input normalization, with the
+    /// same limits for all the API keys
+    /// Must be called from a Tokio runtime
+    pub fn basic(
+        tokenizer: Tokenizer,
+        workers: usize,
+        max_best_of: usize,
+        max_stop_sequences: usize,
+        max_input_length: usize,
+        max_total_tokens: usize,
+    ) -> Self {
+        Self::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequences,
+            max_input_length,
+            max_total_tokens,
+            Templates::default(),
+            Presets::default(),
+            LimitProfiles::new(max_input_length, max_total_tokens),
+            InputNormalizer::default(),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         workers: usize,
         tokenizer: Tokenizer,
@@ -28,44 +82,158 @@ impl Validation {
         max_stop_sequences: usize,
         max_input_length: usize,
         max_total_tokens: usize,
+        templates: Templates,
+        presets: Presets,
+        limits: LimitProfiles,
+        normalizer: InputNormalizer,
     ) -> Self {
         // Create channel
         let (validation_sender, validation_receiver) = mpsc::unbounded_channel();
 
         // Launch background validation task
+        let pieces = Arc::new(tokenizer.clone());
         tokio::spawn(validation_task(
             workers,
             tokenizer,
-            max_stop_sequences,
             max_input_length,
             max_total_tokens,
+            normalizer,
             validation_receiver,
         ));
 
         Self {
             max_best_of,
+            max_stop_sequences,
+            templates,
+            presets,
+            limits,
+            tokenizer: pieces,
+            refuse_raw_token_text: false,
+            refuse_session_id: false,
             sender: validation_sender,
         }
     }
 
-    /// Validate a payload and get the number of tokens in the input
+    /// Refuse `raw_token_text`, e.g. when the post-generation hook filters the generated text: it
+    /// only sees the detokenized text
+    pub(crate) fn refuse_raw_token_text(mut self, refuse_raw_token_text: bool) -> Self {
+        self.refuse_raw_token_text = refuse_raw_token_text;
+        self
+    }
+
+    /// Refuse `session_id` when the sessions are disabled, rather than ignoring it
+    pub(crate) fn refuse_session_id(mut self, refuse_session_id: bool) -> Self {
+        self.refuse_session_id = refuse_session_id;
+        self
+    }
+
+    /// Tokenizer used to send the raw text of the generated tokens
+    pub(crate) fn tokenizer(&self) -> Arc<Tokenizer> {
+        self.tokenizer.clone()
+    }
+
+    /// Check the parameters of a payload without tokenizing its inputs
+    /// Cheap enough to run before the request takes a concurrency permit
+    #[instrument(skip_all)]
+    pub(crate) fn validate_params(
+        &self,
+        request: &mut GenerateRequest,
+        context: &mut RequestContext,
+    ) -> Result<(), ValidationError> {
+        // The requests without the limits of their API key have the default limits
+        let limits = *context.limits.get_or_insert_with(|| self.limits.get(None));
+        let checked = if request.parameters.raw_token_text && self.refuse_raw_token_text {
+            Err(ValidationError::RawTokenTextFiltered)
+        } else if request.parameters.session_id.is_some() && self.refuse_session_id {
+            Err(ValidationError::SessionsDisabled)
+        } else {
+            check_request(request, &limits, self.max_stop_sequences)
+        };
+        checked.map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            err
+        })
+    }
+
+    /// Get the number of tokens in the input of a payload checked by `validate_params`, and
+    /// check the limits depending on it
     #[instrument(skip_all)]
-    pub(crate) async fn validate(
+    pub(crate) async fn validate_input(
         &self,
         request: GenerateRequest,
+        context: &RequestContext,
     ) -> Result<ValidGenerateRequest, ValidationError> {
         // Create response channel
         let (sender, receiver) = oneshot::channel();
         // Send request to the background validation task
         // Unwrap is safe here
         self.sender
-            .send((request, sender, Span::current()))
+            .send((
+                request,
+                context.limits,
+                context.input_source,
+                sender,
+                Span::current(),
+                Instant::now(),
+            ))
             .unwrap();
         // Await on response channel
         // Unwrap is safe here
         receiver.await.unwrap()
     }
 
+    /// Resolve the source of the inputs of a request, rendering its template if it has one
+    /// Conflicting sources are rejected
+    #[instrument(skip_all)]
+    pub(crate) fn resolve_inputs(
+        &self,
+        request: &mut GenerateRequest,
+        context: &mut RequestContext,
+    ) -> Result<(), ValidationError> {
+        context.input_source = self.templates.resolve(request).map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            err
+        })?;
+        Ok(())
+    }
+
+    /// Fill the parameters not given in a request using a preset with the preset parameters
+    #[instrument(skip_all)]
+    pub(crate) fn resolve_preset(
+        &self,
+        request: &mut GenerateRequest,
+    ) -> Result<(), ValidationError> {
+        let name = match request.preset.take() {
+            None => return Ok(()),
+            Some(name) => name,
+        };
+        self.presets
+            .apply(&name, &mut request.parameters)
+            .map_err(|err| {
+                metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                tracing::error!("{err}");
+                err
+            })
+    }
+
+    /// Send a top_k keeping the whole vocabulary as 0: it does not filter any token, and the
+    /// request is not sampled because of it
+    pub(crate) fn normalize_top_k(&self, parameters: &mut GenerateParameters) {
+        let vocab_size = self.tokenizer.get_vocab_size(true);
+        if let Some(top_k) = &mut parameters.top_k {
+            if *top_k > 0 && *top_k as usize >= vocab_size {
+                *top_k = 0;
+            }
+        }
+    }
+
+    /// Limits of the requests sent with `api_key`
+    pub(crate) fn limits(&self, api_key: Option<&str>) -> Limits {
+        self.limits.get(api_key)
+    }
+
     /// Validate the best_of parameter
     #[instrument(skip_all)]
     pub(crate) fn validate_best_of(&self, best_of: usize) -> Result<usize, ValidationError> {
@@ -86,9 +254,9 @@ impl Validation {
 async fn validation_task(
     workers: usize,
     tokenizer: Tokenizer,
-    max_stop_sequences: usize,
     max_input_length: usize,
     max_total_tokens: usize,
+    normalizer: InputNormalizer,
     mut receiver: mpsc::UnboundedReceiver<ValidationRequest>,
 ) {
     let mut workers_senders = Vec::with_capacity(workers);
@@ -96,6 +264,7 @@ async fn validation_task(
     // Create workers
     for _ in 0..workers {
         let tokenizer_clone: Tokenizer = tokenizer.clone().into();
+        let normalizer = normalizer.clone();
         // Create channel to communicate with worker
         let (worker_sender, worker_receiver) = mpsc::channel(workers);
         workers_senders.push(worker_sender);
@@ -104,9 +273,9 @@ async fn validation_task(
         tokio::task::spawn_blocking(move || {
             validation_worker(
                 tokenizer_clone,
-                max_stop_sequences,
                 max_input_length,
                 max_total_tokens,
+                normalizer,
                 worker_receiver,
             )
         });
@@ -124,29 +293,36 @@ async fn validation_task(
     }
 }
 
-/// Check the parameters inside the payload and get the number of tokens inside the input using
-/// the tokenizer
+/// Get the number of tokens inside the input using the tokenizer
 fn validation_worker(
     tokenizer: Tokenizer,
-    max_stop_sequences: usize,
     max_input_length: usize,
     max_total_tokens: usize,
+    normalizer: InputNormalizer,
     mut receiver: mpsc::Receiver<ValidationRequest>,
 ) {
     // Seed rng
     let mut rng = rand::thread_rng();
 
     // Loop over requests
-    while let Some((request, response_tx, parent_span)) = receiver.blocking_recv() {
+    while let Some((request, limits, input_source, response_tx, parent_span, sent)) =
+        receiver.blocking_recv()
+    {
         parent_span.in_scope(|| {
+            // Time spent waiting for this worker
+            let queue_time = sent.elapsed();
+            metrics::histogram!("tgi_request_validation_queue_duration", queue_time);
             response_tx
                 .send(
                     validate(
                         request,
+                        limits,
+                        input_source,
                         &tokenizer,
-                        max_stop_sequences,
                         max_input_length,
                         max_total_tokens,
+                        &normalizer,
+                        queue_time,
                         &mut rng,
                     )
                     .map_err(|err| {
@@ -160,16 +336,19 @@ fn validation_worker(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn validate(
-    request: GenerateRequest,
+    mut request: GenerateRequest,
+    limits: Option<Limits>,
+    input_source: InputSource,
     tokenizer: &Tokenizer,
-    max_stop_sequences: usize,
     max_input_length: usize,
     max_total_tokens: usize,
+    normalizer: &InputNormalizer,
+    queue_time: Duration,
     rng: &mut ThreadRng,
 ) -> Result<ValidGenerateRequest, ValidationError> {
     let GenerateParameters {
-        best_of,
         temperature,
         repetition_penalty,
         top_k,
@@ -177,105 +356,75 @@ fn validate(
         typical_p,
         do_sample,
         max_new_tokens,
-        stop: stop_sequences,
+        stop,
+        stop_config,
         truncate,
         seed,
         watermark,
+        details,
+        decoder_input_details,
+        clean_up_tokenization_spaces,
+        raw_token_text,
+        normalize_input,
+        allowed_tokens,
         ..
     } = request.parameters;
-
-    // sampling must be true when best_of > 1
-    let best_of = best_of.unwrap_or(1);
-    let sampling = do_sample
-        || temperature.is_some()
-        || top_k.is_some()
-        || top_p.is_some()
-        || typical_p.is_some();
-
-    if best_of > 1 && !sampling {
-        return Err(BestOfSampling);
-    }
+    let max_input_length = limits.map_or(max_input_length, |limits| limits.max_input_length);
 
     let temperature = temperature.unwrap_or(1.0);
-    if temperature <= 0.0 {
-        return Err(ValidationError::Temperature);
-    }
-
     let repetition_penalty = repetition_penalty.unwrap_or(1.0);
-    if repetition_penalty <= 0.0 {
-        return Err(ValidationError::RepetitionPenalty);
-    }
-
     // Different because the proto default value is not a valid value
     // for the user
-    let top_p = top_p
-        .map(|value| {
-            if value <= 0.0 || value >= 1.0 {
-                return Err(ValidationError::TopP);
-            }
-            Ok(value)
-        })
-        .unwrap_or(Ok(1.0))?;
-
-    let typical_p = typical_p
-        .map(|value| {
-            if value <= 0.0 || value >= 1.0 {
-                return Err(ValidationError::TypicalP);
-            }
-            Ok(value)
-        })
-        .unwrap_or(Ok(1.0))?;
-
-    let top_k: u32 = top_k
-        .map(|value| {
-            if value <= 0 {
-                return Err(ValidationError::TopK);
-            }
-            Ok(value as u32)
+    let top_p = top_p.unwrap_or(1.0);
+    let typical_p = typical_p.unwrap_or(1.0);
+    // Keeping the whole vocabulary or more does not filter any token: disabled as with 0
+    let vocab_size = tokenizer.get_vocab_size(true);
+    let top_k = top_k
+        .filter(|value| *value > 0 && (*value as usize) < vocab_size)
+        .map_or(0, |value| value as u32);
+
+    // The stop sequences of `stop` are removed from the streamed text
+    let stop_sequences: Vec<StopConfig> = stop
+        .into_iter()
+        .map(|sequence| StopConfig {
+            sequence,
+            keep_text: false,
         })
-        .unwrap_or(Ok(0))?;
-
-    if max_new_tokens == 0 {
-        return Err(ValidationError::MaxNewTokens);
-    }
-
-    if stop_sequences.len() > max_stop_sequences {
-        return Err(ValidationError::StopSequence(
-            max_stop_sequences,
-            stop_sequences.len(),
-        ));
-    }
+        .chain(stop_config)
+        .collect();
 
     // If seed is None, assign a random one
-    let seed = match seed {
-        None => rng.gen(),
-        Some(seed) => {
-            if best_of > 1 {
-                return Err(BestOfSeed);
-            }
-            seed
+    let seed = seed.unwrap_or_else(|| rng.gen());
+
+    // Normalize before the empty check as the inputs can be made only of stripped characters
+    let mut normalized_chars = None;
+    if normalizer.enabled(normalize_input) {
+        let normalized = normalizer.normalize(&request.inputs)?;
+        if normalized.changed_chars > 0 {
+            metrics::increment_counter!("tgi_request_normalized");
+            normalized_chars = Some(normalized.changed_chars as u32);
         }
-    };
+        request.inputs = normalized.inputs;
+    }
 
-    // Check if inputs is empty
+    // Check again after the normalization and the pre-generation hook
     if request.inputs.is_empty() {
         return Err(EmptyInput);
     }
 
-    // Check if truncate is strictly positive and less than max_input_length
-    let truncate = truncate
-        .map(|value| {
-            if value == 0 || value > max_input_length {
-                return Err(ValidationError::Truncate(max_input_length, value));
-            }
-            Ok(Some(value))
-        })
-        .unwrap_or(Ok(None))?;
-
     // Get the number of tokens in the input
+    let tokenization_start = Instant::now();
     let mut encoding = tokenizer
         .encode(request.inputs.clone(), true)
         .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+    // Statistics of the inputs before truncation
+    let statistics = (details && decoder_input_details).then(|| {
+        (
+            request.inputs.chars().count(),
+            request.inputs.len(),
+            encoding.len(),
+        )
+    });
 
     let (inputs, input_length) = if let Some(truncate) = truncate {
         // truncate encoding and decode new inputs
@@ -287,6 +436,14 @@ fn validate(
     } else {
         (request.inputs, encoding.len())
     };
+    let tokenization = statistics.map(|(chars, bytes, tokens)| InputTokenization {
+        offsets: encoding.get_offsets().to_vec(),
+        chars,
+        bytes,
+        tokens,
+    });
+    let tokenization_time = tokenization_start.elapsed();
+    metrics::histogram!("tgi_request_tokenization_duration", tokenization_time);
 
     if input_length > max_input_length {
         return Err(ValidationError::InputLength(max_input_length, input_length));
@@ -301,6 +458,15 @@ fn validate(
         ));
     }
 
+    let mut allowed_tokens = allowed_tokens.unwrap_or_default();
+    allowed_tokens.sort_unstable();
+    allowed_tokens.dedup();
+    if let Some(&id) = allowed_tokens.last() {
+        if id as usize >= vocab_size {
+            return Err(ValidationError::AllowedTokenId(id, vocab_size));
+        }
+    }
+
     // Return ValidGenerateRequest
     let parameters = NextTokenChooserParameters {
         temperature,
@@ -308,40 +474,256 @@ fn validate(
         top_k,
         top_p,
         typical_p,
-        do_sample,
+        do_sample: do_sample.unwrap_or(false),
         seed,
-        watermark,
+        watermark: watermark.unwrap_or(false),
+        allowed_tokens,
     };
     let stopping_parameters = StoppingCriteriaParameters {
         max_new_tokens,
-        stop_sequences,
+        stop_sequences: stop_sequences
+            .iter()
+            .map(|stop| stop.sequence.clone())
+            .collect(),
         ignore_eos_token: false,
     };
+    // Built once so that the streamed text is matched incrementally
+    let stop_matcher = Arc::new(StopMatcher::new(&stop_sequences));
 
     metrics::histogram!("tgi_request_input_length", input_length as f64);
     metrics::histogram!("tgi_request_max_new_tokens", max_new_tokens as f64);
 
     Ok(ValidGenerateRequest {
         inputs,
+        input_length: input_length as u32,
         parameters,
         stopping_parameters,
+        stop_matcher,
+        prefill_tokens: details,
+        tokenization,
+        clean_up_tokenization_spaces,
+        raw_token_text,
+        normalized_chars,
+        input_source,
+        timings: ValidationTimings {
+            queue_time,
+            tokenization_time,
+        },
     })
 }
 
+/// Checks of a payload that do not need its tokenized inputs
+fn check_request(
+    request: &GenerateRequest,
+    limits: &Limits,
+    max_stop_sequences: usize,
+) -> Result<(), ValidationError> {
+    let parameters = &request.parameters;
+    check_parameters(parameters)?;
+
+    if let Some(limit) = limits.max_new_tokens {
+        if parameters.max_new_tokens > limit {
+            return Err(ValidationError::MaxNewTokensLimit(
+                limit,
+                parameters.max_new_tokens,
+            ));
+        }
+    }
+
+    let stop_sequences = parameters.stop.len() + parameters.stop_config.len();
+    if stop_sequences > max_stop_sequences {
+        return Err(ValidationError::StopSequence(
+            max_stop_sequences,
+            stop_sequences,
+        ));
+    }
+
+    // Check if inputs is empty
+    if request.inputs.is_empty() {
+        return Err(EmptyInput);
+    }
+
+    // Check if truncate is strictly positive and less than max_input_length
+    if let Some(truncate) = parameters.truncate {
+        if truncate == 0 || truncate > limits.max_input_length {
+            return Err(ValidationError::Truncate(limits.max_input_length, truncate));
+        }
+    }
+    Ok(())
+}
+
+/// Check the parameters independently of the router configuration
+/// Shared with the parameter builder of the client so that it fails as the router would
+pub(crate) fn check_parameters(parameters: &GenerateParameters) -> Result<(), ValidationError> {
+    // sampling must be true when best_of > 1
+    let best_of = parameters.best_of.unwrap_or(1);
+    if best_of > 1 && !parameters.sampling() {
+        return Err(BestOfSampling);
+    }
+    if best_of > 1 && parameters.seed.is_some() {
+        return Err(BestOfSeed);
+    }
+
+    if parameters.temperature.map_or(false, |value| value <= 0.0) {
+        return Err(ValidationError::Temperature);
+    }
+    if parameters
+        .repetition_penalty
+        .map_or(false, |value| value <= 0.0)
+    {
+        return Err(ValidationError::RepetitionPenalty);
+    }
+    if parameters
+        .top_p
+        .map_or(false, |value| value <= 0.0 || value >= 1.0)
+    {
+        return Err(ValidationError::TopP);
+    }
+    if parameters
+        .typical_p
+        .map_or(false, |value| value <= 0.0 || value >= 1.0)
+    {
+        return Err(ValidationError::TypicalP);
+    }
+    if parameters.top_k.map_or(false, |value| value < 0) {
+        return Err(ValidationError::TopK);
+    }
+    if parameters.max_new_tokens == 0 {
+        return Err(ValidationError::MaxNewTokens);
+    }
+    if parameters.deadline_ms == Some(0) {
+        return Err(ValidationError::DeadlineMs);
+    }
+    if parameters.response_timeout_ms == Some(0) {
+        return Err(ValidationError::ResponseTimeoutMs);
+    }
+    // The full text would mix tokenizer pieces with detokenized text
+    if parameters.raw_token_text && parameters.stream_full_text {
+        return Err(ValidationError::RawTokenTextFullText);
+    }
+    // The raw text of the tokens is not held back by the stop sequences
+    let removed_stop =
+        !parameters.stop.is_empty() || parameters.stop_config.iter().any(|stop| !stop.keep_text);
+    if parameters.raw_token_text && removed_stop {
+        return Err(ValidationError::RawTokenTextStop);
+    }
+    // Slow rates would keep the streams open for hours
+    if let Some(rate) = parameters.stream_rate_limit {
+        let min_rate = parameters.max_new_tokens as f32 / MAX_PACED_STREAM_SECS;
+        if rate.is_nan() || rate < min_rate {
+            return Err(ValidationError::StreamRateLimit(min_rate));
+        }
+    }
+    if parameters.progress_interval_tokens == Some(0) {
+        return Err(ValidationError::ProgressInterval);
+    }
+    // The stream would only send its last event
+    if parameters.progress_only && parameters.progress_interval_tokens.is_none() {
+        return Err(ValidationError::ProgressOnly);
+    }
+    if let Some(allowed_tokens) = &parameters.allowed_tokens {
+        if allowed_tokens.is_empty() || allowed_tokens.len() > MAX_ALLOWED_TOKENS {
+            return Err(ValidationError::AllowedTokens(
+                MAX_ALLOWED_TOKENS,
+                allowed_tokens.len(),
+            ));
+        }
+    }
+
+    for (i, config) in parameters.stop_config.iter().enumerate() {
+        if config.sequence.is_empty() {
+            return Err(ValidationError::EmptyStopConfig);
+        }
+        let duplicate = parameters.stop.contains(&config.sequence)
+            || parameters.stop_config[..i]
+                .iter()
+                .any(|stop| stop.sequence == config.sequence);
+        if duplicate {
+            return Err(ValidationError::DuplicateStopSequence(
+                config.sequence.clone(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Request, its limits and input source, response channel, span and instant when the request was
+/// sent to the validation task
 type ValidationRequest = (
     GenerateRequest,
+    Option<Limits>,
+    InputSource,
     oneshot::Sender<Result<ValidGenerateRequest, ValidationError>>,
     Span,
+    Instant,
 );
 
+/// Time spent in the validation of a request
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ValidationTimings {
+    /// Waiting for a validation worker
+    pub queue_time: Duration,
+    /// Encoding the inputs, and decoding them again if they are truncated
+    pub tokenization_time: Duration,
+}
+
 #[derive(Debug)]
 pub(crate) struct ValidGenerateRequest {
     pub inputs: String,
+    /// Number of tokens of `inputs`
+    pub input_length: u32,
     pub parameters: NextTokenChooserParameters,
     pub stopping_parameters: StoppingCriteriaParameters,
+    /// Automaton of the stop sequences of `stop` followed by `stop_config`
+    pub stop_matcher: Arc<StopMatcher>,
+    /// The backend returns the prefill tokens, only needed for the details of the response
+    pub prefill_tokens: bool,
+    /// Only kept for the requests with `decoder_input_details`
+    pub tokenization: Option<InputTokenization>,
+    /// Clean up the spaces of the generated text
+    pub clean_up_tokenization_spaces: bool,
+    /// The tokens are sent with the text of their tokenizer piece
+    pub raw_token_text: bool,
+    /// Number of characters changed by the normalization of the inputs, None if it left them as is
+    pub normalized_chars: Option<u32>,
+    pub input_source: InputSource,
+    pub timings: ValidationTimings,
+}
+
+/// Tokenization of the inputs of a request
+#[derive(Debug, Clone)]
+pub(crate) struct InputTokenization {
+    /// Byte offsets in the inputs of the tokens sent to the backend
+    pub offsets: Vec<(usize, usize)>,
+    /// Number of characters, bytes and tokens of the inputs before truncation
+    pub chars: usize,
+    pub bytes: usize,
+    pub tokens: usize,
+}
+
+impl ValidGenerateRequest {
+    /// Parameters sent to the backend
+    pub(crate) fn valid_parameters(&self) -> ValidParameters {
+        ValidParameters {
+            temperature: self.parameters.temperature,
+            top_k: self.parameters.top_k,
+            top_p: self.parameters.top_p,
+            typical_p: self.parameters.typical_p,
+            repetition_penalty: self.parameters.repetition_penalty,
+            do_sample: self.parameters.do_sample,
+            max_new_tokens: self.stopping_parameters.max_new_tokens,
+            stop: self.stopping_parameters.stop_sequences.clone(),
+            seed: self.parameters.seed,
+            watermark: self.parameters.watermark,
+            allowed_tokens: self.parameters.allowed_tokens.clone(),
+            requested_max_new_tokens: None,
+            normalized_chars: self.normalized_chars,
+            input_source: self.input_source,
+        }
+    }
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum ValidationError {
     #[error("`best_of` must be > 0 and <= {0}. Given: {1}")]
     BestOf(usize, usize),
@@ -353,13 +735,17 @@ pub enum ValidationError {
     BestOfSeed,
     #[error("`best_of` != 1 is not supported when streaming tokens")]
     BestOfStream,
+    #[error("`best_of` != 1 is not supported by jobs")]
+    BestOfJob,
+    #[error("`callback_url` {0}")]
+    CallbackUrl(String),
     #[error("`temperature` must be strictly positive")]
     Temperature,
     #[error("`repetition_penalty` must be strictly positive")]
     RepetitionPenalty,
     #[error("`top_p` must be > 0.0 and < 1.0")]
     TopP,
-    #[error("`top_k` must be strictly positive")]
+    #[error("`top_k` must be positive, or 0 to disable it")]
     TopK,
     #[error("`truncate` must be strictly positive and less than {0}. Given: {1}")]
     Truncate(usize, usize),
@@ -367,14 +753,56 @@ pub enum ValidationError {
     TypicalP,
     #[error("`max_new_tokens` must be strictly positive")]
     MaxNewTokens,
+    #[error("`max_new_tokens` must be <= {0} for this API key. Given: {1}")]
+    MaxNewTokensLimit(u32, u32),
+    #[error("`deadline_ms` must be strictly positive")]
+    DeadlineMs,
+    #[error("`response_timeout_ms` must be strictly positive")]
+    ResponseTimeoutMs,
+    #[error("`raw_token_text` cannot be combined with `stream_full_text`")]
+    RawTokenTextFullText,
+    #[error("`raw_token_text` cannot be combined with stop sequences removed from the text")]
+    RawTokenTextStop,
+    #[error("`raw_token_text` is not supported: the generated text is filtered")]
+    RawTokenTextFiltered,
+    #[error("`session_id` is not supported: the sessions are disabled")]
+    SessionsDisabled,
+    #[error("`stream_rate_limit` must be >= {0} to send `max_new_tokens` in less than 10 minutes")]
+    StreamRateLimit(f32),
+    #[error("`progress_interval_tokens` must be strictly positive")]
+    ProgressInterval,
+    #[error("`progress_only` needs `progress_interval_tokens`")]
+    ProgressOnly,
+    #[error("`allowed_tokens` must contain between 1 and {0} token ids. Given: {1}")]
+    AllowedTokens(usize, usize),
+    #[error("`allowed_tokens` contains the token id {0}, outside of the vocabulary of {1} tokens")]
+    AllowedTokenId(u32, usize),
     #[error("`inputs` tokens + `max_new_tokens` must be <= {0}. Given: {1} `inputs` tokens and {2} `max_new_tokens`")]
     MaxTotalTokens(usize, usize, u32),
-    #[error("`inputs` must have less than {0} tokens. Given: {1}")]
+    #[error("`inputs` must have less than {0} tokens. Given: {1}. Set `truncate` to keep only the last tokens of `inputs`")]
     InputLength(usize, usize),
     #[error("`inputs` cannot be empty")]
     EmptyInput,
-    #[error("`stop` supports up to {0} stop sequences. Given: {1}")]
+    #[error("`inputs` contains the invalid character U+{0:04X} at position {1}")]
+    InvalidCharacter(u32, usize),
+    #[error("`stop` and `stop_config` support up to {0} stop sequences. Given: {1}")]
     StopSequence(usize, usize),
+    #[error("`stop_config` sequences must not be empty")]
+    EmptyStopConfig,
+    #[error("stop sequence `{0}` is given more than once")]
+    DuplicateStopSequence(String),
     #[error("tokenizer error {0}")]
     Tokenizer(String),
+    #[error("template `{0}` does not exist. Available templates: [{1}]")]
+    UnknownTemplate(String, String),
+    #[error("template variable `{0}` is missing from `template_vars`")]
+    TemplateVariable(String),
+    #[error("`template_vars` is only used with `template`")]
+    TemplateVarsWithoutTemplate,
+    #[error("`template_vars` cannot set `input`, which is given by `inputs`")]
+    TemplateVarsInput,
+    #[error("`inputs` must be empty: template `{0}` has no `{{input}}` placeholder")]
+    TemplateIgnoresInputs(String),
+    #[error("preset `{0}` does not exist. Available presets: [{1}]")]
+    UnknownPreset(String, String),
 }
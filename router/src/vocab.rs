@@ -0,0 +1,352 @@
+/// Check of the tokenizer of the router against the tokenizer of the backend
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use text_generation_client::{InfoResponse, ShardedClient};
+use thiserror::Error;
+use tokenizers::Tokenizer;
+use utoipa::ToSchema;
+
+/// Handling of a backend whose tokenizer differs from the tokenizer of the router
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum VocabMismatchPolicy {
+    /// The router does not start
+    Fail,
+    /// The router logs an error and fails its readiness probe
+    NotReady,
+}
+
+impl FromStr for VocabMismatchPolicy {
+    type Err = String;
+
+    /// Parse `fail` or `not-ready`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "fail" => Ok(VocabMismatchPolicy::Fail),
+            "not-ready" | "not_ready" => Ok(VocabMismatchPolicy::NotReady),
+            _ => Err(format!(
+                "unknown vocabulary mismatch policy `{value}`, expected `fail` or `not-ready`"
+            )),
+        }
+    }
+}
+
+/// Vocabulary of a tokenizer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, ToSchema)]
+pub(crate) struct Vocab {
+    /// Number of tokens, with the added tokens
+    #[schema(example = 250680)]
+    pub vocab_size: u32,
+    /// Ids of the special tokens, by content
+    pub special_tokens: BTreeMap<String, u32>,
+}
+
+impl Vocab {
+    fn new(tokenizer: &Tokenizer) -> Self {
+        Self {
+            vocab_size: tokenizer.get_vocab_size(true) as u32,
+            special_tokens: special_tokens(tokenizer),
+        }
+    }
+}
+
+impl From<InfoResponse> for Vocab {
+    fn from(info: InfoResponse) -> Self {
+        Self {
+            vocab_size: info.vocab_size,
+            special_tokens: info.special_tokens.into_iter().collect(),
+        }
+    }
+}
+
+/// Vocabularies of the router and of the backend, in `/info`
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct VocabStatus {
+    pub router: Vocab,
+    /// None until the backend is connected, or if it does not report its vocabulary
+    pub backend: Option<Vocab>,
+    /// Difference between the vocabularies, the router is not ready if set
+    #[schema(nullable = true, example = "null")]
+    pub mismatch: Option<String>,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum VocabMismatch {
+    #[error(
+        "the tokenizer of the router has {0} tokens, the tokenizer of the backend has {1} tokens"
+    )]
+    VocabSize(u32, u32),
+    #[error("the special token {0:?} has the id {2} in the backend, {1:?} in the router")]
+    SpecialToken(String, Option<u32>, u32),
+}
+
+/// Compares the vocabulary of the router with the one reported by the backend once it is
+/// connected
+#[derive(Clone)]
+pub struct VocabCheck {
+    tokenizer: Tokenizer,
+    policy: VocabMismatchPolicy,
+    router: Vocab,
+    state: Arc<RwLock<VocabState>>,
+}
+
+/// Set once the backend is connected
+#[derive(Debug, Default)]
+struct VocabState {
+    backend: Option<Vocab>,
+    mismatch: Option<VocabMismatch>,
+}
+
+impl VocabCheck {
+    pub fn new(tokenizer: Tokenizer, policy: VocabMismatchPolicy) -> Self {
+        Self {
+            router: Vocab::new(&tokenizer),
+            tokenizer,
+            policy,
+            state: Arc::default(),
+        }
+    }
+
+    pub fn policy(&self) -> VocabMismatchPolicy {
+        self.policy
+    }
+
+    /// Compare the vocabulary reported by the stable backend with the tokenizer of the router
+    ///
+    /// Returns the mismatch with the `fail` policy. With the `not-ready` policy the mismatch is
+    /// only logged and fails the readiness probe
+    pub async fn check(&self, client: &mut ShardedClient) -> Result<(), VocabMismatch> {
+        let backend = match backend_vocab("stable", client).await {
+            Some(backend) => backend,
+            None => return Ok(()),
+        };
+        let mismatch = compare(&self.tokenizer, &backend).err();
+        self.state.write().backend = Some(backend);
+        self.apply_policy("stable", mismatch)
+    }
+
+    /// Compare the vocabulary reported by another backend with the tokenizer of its model: the
+    /// tokenizer of the router for the canary, the one of its model for a backend of `--models`,
+    /// the draft backend included
+    ///
+    /// The status only shows the vocabulary of the stable backend, the mismatches of the other
+    /// backends follow the same policy
+    pub async fn check_backend(
+        &self,
+        name: &str,
+        tokenizer: &Tokenizer,
+        client: &mut ShardedClient,
+    ) -> Result<(), VocabMismatch> {
+        let backend = match backend_vocab(name, client).await {
+            Some(backend) => backend,
+            None => return Ok(()),
+        };
+        self.apply_policy(name, compare(tokenizer, &backend).err())
+    }
+
+    /// The first mismatch is kept to fail the readiness probe
+    fn apply_policy(
+        &self,
+        name: &str,
+        mismatch: Option<VocabMismatch>,
+    ) -> Result<(), VocabMismatch> {
+        let mismatch = match mismatch {
+            None => return Ok(()),
+            Some(mismatch) => mismatch,
+        };
+        metrics::increment_counter!("tgi_vocab_mismatch", "backend" => name.to_string());
+        self.state.write().mismatch.get_or_insert(mismatch.clone());
+        match self.policy {
+            VocabMismatchPolicy::Fail => Err(mismatch),
+            VocabMismatchPolicy::NotReady => {
+                tracing::error!(
+                    "The router and the {name} backend do not use the same tokenizer, \
+                    the token counts, stop sequences and truncation are wrong: {mismatch}"
+                );
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn mismatch(&self) -> Option<VocabMismatch> {
+        self.state.read().mismatch.clone()
+    }
+
+    pub(crate) fn status(&self) -> VocabStatus {
+        let state = self.state.read();
+        VocabStatus {
+            router: self.router.clone(),
+            backend: state.backend.clone(),
+            mismatch: state.mismatch.as_ref().map(VocabMismatch::to_string),
+        }
+    }
+}
+
+/// Vocabulary reported by the backend, None if it does not report it
+async fn backend_vocab(name: &str, client: &mut ShardedClient) -> Option<Vocab> {
+    match client.info().await {
+        Ok(info) => Some(info.into()),
+        Err(err) => {
+            tracing::warn!(
+                "The {name} backend does not report its vocabulary, not checking it: {err}"
+            );
+            None
+        }
+    }
+}
+
+/// The special tokens of the backend must have the same ids in the router
+fn compare(tokenizer: &Tokenizer, backend: &Vocab) -> Result<(), VocabMismatch> {
+    let vocab_size = tokenizer.get_vocab_size(true) as u32;
+    if vocab_size != backend.vocab_size {
+        return Err(VocabMismatch::VocabSize(vocab_size, backend.vocab_size));
+    }
+    for (content, id) in &backend.special_tokens {
+        let router_id = tokenizer.token_to_id(content);
+        if router_id != Some(*id) {
+            return Err(VocabMismatch::SpecialToken(content.clone(), router_id, *id));
+        }
+    }
+    Ok(())
+}
+
+/// Special added tokens of the serialized tokenizer
+fn special_tokens(tokenizer: &Tokenizer) -> BTreeMap<String, u32> {
+    #[derive(Deserialize)]
+    struct AddedToken {
+        id: u32,
+        content: String,
+        special: bool,
+    }
+    #[derive(Deserialize)]
+    struct Serialized {
+        added_tokens: Vec<AddedToken>,
+    }
+    let serialized = tokenizer
+        .to_string(false)
+        .ok()
+        .and_then(|json| serde_json::from_str::<Serialized>(&json).ok());
+    serialized
+        .map(|serialized| {
+            serialized
+                .added_tokens
+                .into_iter()
+                .filter(|token| token.special)
+                .map(|token| (token.content, token.id))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use text_generation_client::MockConfig;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::AddedToken;
+
+    /// Tokenizer of the mock vocabulary with a `</s>` special token
+    fn tokenizer() -> Tokenizer {
+        let vocab: HashMap<String, u32> = [
+            "the", "quick", "brown", "fox", "jumps", "over", "lazy", "[UNK]",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), id as u32))
+        .collect();
+        let model = WordLevel::builder()
+            .vocab(vocab)
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(model);
+        tokenizer.add_special_tokens(&[AddedToken::from("</s>", true)]);
+        tokenizer
+    }
+
+    fn client(vocab_size: u32, special_tokens: &[(&str, u32)]) -> ShardedClient {
+        ShardedClient::mock(MockConfig {
+            info: Some(InfoResponse {
+                vocab_size,
+                special_tokens: special_tokens
+                    .iter()
+                    .map(|(content, id)| (content.to_string(), *id))
+                    .collect(),
+                speculation: true,
+                prefix_cache: true,
+                cancel: true,
+            }),
+            ..MockConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_router_vocab() {
+        let status = VocabCheck::new(tokenizer(), VocabMismatchPolicy::Fail).status();
+        assert_eq!(status.router.vocab_size, 9);
+        assert_eq!(
+            status.router.special_tokens,
+            BTreeMap::from([("</s>".to_string(), 8)])
+        );
+        assert!(status.backend.is_none());
+        assert!(status.mismatch.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let check = VocabCheck::new(tokenizer(), VocabMismatchPolicy::Fail);
+        check.check(&mut client(9, &[("</s>", 8)])).await.unwrap();
+        assert!(check.mismatch().is_none());
+        assert_eq!(check.status().backend, Some(check.status().router));
+    }
+
+    #[tokio::test]
+    async fn test_mismatch() {
+        let check = VocabCheck::new(tokenizer(), VocabMismatchPolicy::Fail);
+        assert_eq!(
+            check.check(&mut client(10, &[("</s>", 8)])).await,
+            Err(VocabMismatch::VocabSize(9, 10))
+        );
+        assert_eq!(
+            check.check(&mut client(9, &[("</s>", 2)])).await,
+            Err(VocabMismatch::SpecialToken("</s>".to_string(), Some(8), 2))
+        );
+        assert_eq!(
+            check.check(&mut client(9, &[("<pad>", 8)])).await,
+            Err(VocabMismatch::SpecialToken("<pad>".to_string(), None, 8))
+        );
+
+        // Only logged, the router is not ready
+        let check = VocabCheck::new(tokenizer(), VocabMismatchPolicy::NotReady);
+        check.check(&mut client(10, &[])).await.unwrap();
+        assert_eq!(check.mismatch(), Some(VocabMismatch::VocabSize(9, 10)));
+        let status = check.status();
+        assert_eq!(status.backend.unwrap().vocab_size, 10);
+        assert!(status.mismatch.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_check_backend() {
+        let check = VocabCheck::new(tokenizer(), VocabMismatchPolicy::Fail);
+        assert_eq!(
+            check
+                .check_backend("model", &tokenizer(), &mut client(9, &[("</s>", 2)]))
+                .await,
+            Err(VocabMismatch::SpecialToken("</s>".to_string(), Some(8), 2))
+        );
+
+        // The mismatch of the canary fails the readiness probe, the status shows the stable backend
+        let check = VocabCheck::new(tokenizer(), VocabMismatchPolicy::NotReady);
+        check.check(&mut client(9, &[("</s>", 8)])).await.unwrap();
+        check
+            .check_backend("canary", &tokenizer(), &mut client(10, &[]))
+            .await
+            .unwrap();
+        assert_eq!(check.mismatch(), Some(VocabMismatch::VocabSize(9, 10)));
+        assert_eq!(check.status().backend, Some(check.status().router));
+    }
+}
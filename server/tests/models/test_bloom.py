@@ -26,6 +26,7 @@ def default_pb_request(default_pb_parameters, default_pb_stop_parameters):
         inputs="Test",
         parameters=default_pb_parameters,
         stopping_parameters=default_pb_stop_parameters,
+        prefill_logprobs=True,
     )
 
 
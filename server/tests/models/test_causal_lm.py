@@ -27,6 +27,7 @@ def default_pb_request(default_pb_parameters, default_pb_stop_parameters):
         inputs="Test",
         parameters=default_pb_parameters,
         stopping_parameters=default_pb_stop_parameters,
+        prefill_logprobs=True,
     )
 
 
@@ -122,6 +123,21 @@ def test_causal_lm_generate_token(default_causal_lm, default_causal_lm_batch):
     assert generations[0].request_id == 0
 
 
+def test_causal_lm_generate_token_no_prefill_logprobs(
+    default_causal_lm, default_pb_request, gpt2_tokenizer
+):
+    default_pb_request.prefill_logprobs = False
+    batch = CausalLMBatch.from_pb(
+        generate_pb2.Batch(id=0, requests=[default_pb_request], size=1),
+        gpt2_tokenizer,
+        torch.device("cpu"),
+    )
+    generations, _ = default_causal_lm.generate_token(batch)
+
+    assert all([generation.prefill_tokens is None for generation in generations])
+    assert all([generation.token_id.item() == 13 for generation in generations])
+
+
 def test_causal_lm_generate_token_completion(
     default_causal_lm, default_causal_lm_batch
 ):
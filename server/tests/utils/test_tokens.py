@@ -1,4 +1,7 @@
+import torch
+
 from text_generation_server.utils.tokens import (
+    NextTokenChooser,
     StopSequenceCriteria,
     StoppingCriteria,
     FinishReason,
@@ -33,3 +36,17 @@ def test_stopping_criteria_max():
     assert criteria(1, "") == (False, None)
     assert criteria(1, "") == (False, None)
     assert criteria(1, "") == (True, FinishReason.FINISH_REASON_LENGTH)
+
+
+def test_next_token_chooser_allowed_tokens():
+    chooser = NextTokenChooser(allowed_tokens=[1, 3])
+    scores = torch.tensor([[0.0, 1.0, 5.0, 2.0]])
+    next_id, logprobs = chooser(torch.tensor([[0]]), scores)
+    assert next_id.item() == 3
+    assert logprobs[0, 0] == float("-inf")
+    assert logprobs[0, 2] == float("-inf")
+
+    # All the tokens can be generated without allowed tokens
+    chooser = NextTokenChooser()
+    next_id, _ = chooser(torch.tensor([[0]]), torch.tensor([[0.0, 1.0, 5.0, 2.0]]))
+    assert next_id.item() == 2
@@ -1,4 +1,5 @@
 import grpc
+import torch
 
 from google.rpc import status_pb2, code_pb2
 from grpc_status import rpc_status
@@ -24,6 +25,15 @@ class ExceptionInterceptor(AsyncServerInterceptor):
 
             await context.abort_with_status(
                 rpc_status.to_status(
-                    status_pb2.Status(code=code_pb2.INTERNAL, message=str(err))
+                    status_pb2.Status(code=error_code(err), message=str(err))
                 )
             )
+
+
+def error_code(err: Exception) -> int:
+    """Map an exception to the gRPC status code expected by the router"""
+    if isinstance(err, torch.cuda.OutOfMemoryError):
+        return code_pb2.RESOURCE_EXHAUSTED
+    if isinstance(err, ValueError):
+        return code_pb2.INVALID_ARGUMENT
+    return code_pb2.INTERNAL
@@ -423,8 +423,8 @@ class CausalLM(Model):
                     next_batch_max_input_length, new_input_length
                 )
 
-            # Prefill
-            if stopping_criteria.current_tokens == 1:
+            # Prefill, only decoded for the requests returning the prefill tokens
+            if stopping_criteria.current_tokens == 1 and request.prefill_logprobs:
                 # Remove generated token to only have prefill and add nan for first prompt token
                 prefill_logprobs = [float("nan")] + logprobs.gather(
                     1, all_input_ids[1:]
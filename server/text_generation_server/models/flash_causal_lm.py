@@ -378,8 +378,8 @@ class FlashCausalLM(Model):
                 next_batch_all_input_ids_tensor.append(all_input_ids_tensor)
                 next_batch_max_seqlen = max(next_batch_max_seqlen, new_input_length)
 
-            # Prefill
-            if stopping_criteria.current_tokens == 1:
+            # Prefill, only decoded for the requests returning the prefill tokens
+            if stopping_criteria.current_tokens == 1 and request.prefill_logprobs:
                 # Remove generated token to only have prefill and add nan for first prompt token
                 prefill_logprobs = [float("nan")] + logprobs.gather(
                     1, all_input_ids_tensor[1:input_length].unsqueeze(1)
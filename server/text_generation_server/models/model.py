@@ -14,6 +14,12 @@ class Model(ABC):
         self.tokenizer = tokenizer
         self.all_special_ids = set(tokenizer.all_special_ids)
         self.device = device
+        # Vocabulary reported to the router, without the token added below
+        self.vocab_size = len(tokenizer)
+        self.special_tokens = {
+            token: tokenizer.convert_tokens_to_ids(token)
+            for token in tokenizer.all_special_tokens
+        }
 
         # see `decode_token` method
         self.tokenizer.add_special_tokens(
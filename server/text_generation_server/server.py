@@ -29,6 +29,12 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
     async def ServiceDiscovery(self, request, context):
         return generate_pb2.ServiceDiscoveryResponse(urls=self.server_urls)
 
+    async def Info(self, request, context):
+        return generate_pb2.InfoResponse(
+            vocab_size=self.model.vocab_size,
+            special_tokens=self.model.special_tokens,
+        )
+
     async def ClearCache(self, request, context):
         if request.HasField("id"):
             self.cache.delete(request.id)
@@ -2,6 +2,7 @@ import re
 import torch
 
 from transformers import (
+    LogitsProcessor,
     LogitsProcessorList,
     TemperatureLogitsWarper,
     TopKLogitsWarper,
@@ -34,6 +35,18 @@ class Greedy:
         return logits.argmax()
 
 
+class AllowedTokensLogitsProcessor(LogitsProcessor):
+    """Only let the tokens of `allowed_tokens` be generated"""
+
+    def __init__(self, allowed_tokens: List[int], device: str = "cpu"):
+        self.allowed_tokens = torch.tensor(allowed_tokens, dtype=torch.long, device=device)
+
+    def __call__(self, input_ids, scores):
+        mask = torch.full_like(scores, float("-inf"))
+        mask[:, self.allowed_tokens] = 0
+        return scores + mask
+
+
 class NextTokenChooser:
     def __init__(
         self,
@@ -45,6 +58,7 @@ class NextTokenChooser:
         typical_p=None,
         do_sample=False,
         seed=0,
+        allowed_tokens=None,
         device="cpu",
     ):
         warpers = LogitsProcessorList()
@@ -52,6 +66,9 @@ class NextTokenChooser:
         # all samplers can be found in `generation_utils_samplers.py`
         sampling = do_sample
 
+        # The other warpers only see the allowed tokens
+        if allowed_tokens:
+            warpers.append(AllowedTokensLogitsProcessor(allowed_tokens, device=device))
         if watermark:
             warpers.append(WatermarkLogitsProcessor(device=device))
         if repetition_penalty is not None and repetition_penalty != 1.0:
@@ -104,6 +121,7 @@ class NextTokenChooser:
             typical_p=pb.typical_p,
             do_sample=pb.do_sample,
             seed=pb.seed,
+            allowed_tokens=list(pb.allowed_tokens),
             device=device,
         )
 